@@ -0,0 +1,320 @@
+// Accounting: how much each client has sent, in rows, bytes, and batches, so it's known who the data comes from. A
+// client is its API key (when keys are configured), or else the source its `X-Source-Id` names, or else its address.
+// `GET /sources` reports every client's totals. With quotas (`[quotas]`), a client that has used up its rows, bytes,
+// or batches for the period is turned away with a `429` until the next period starts. Counts are kept in memory, so
+// they start over when the service restarts.
+
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    http::{header, HeaderValue, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::KeyId,
+    config::QuotaConfig,
+    dataset::AppState,
+    error::AppError,
+    provenance,
+    types::{SourceAccount, SourceUsage, SourcesResponse, Status},
+};
+
+// Once this many clients are known, the ones after them are counted together
+const MAX_SOURCES: usize = 10_000;
+
+// What they're counted as
+const OTHER_SOURCES: &str = "(other)";
+
+// Routes (or ends of routes, for named datasets) that ingest payloads, which a client over its quota is turned away
+// from. `/write` covers `/api/v1/write` and `/api/v2/write` too.
+const INGEST_ROUTES: &[&str] = &["/collate", "/collate/batch", "/collate_wide", "/upsert", "/aggregate", "/write"];
+
+tokio::task_local! {
+    // What the request being handled has ingested so far
+    static CHARGED: Cell<Usage>;
+}
+
+// The client a request is charged to, for the handlers that ingest outside of it (WebSocket messages)
+#[derive(Debug, Clone)]
+pub struct Client(pub String);
+
+// The most a client may send in a period. A limit that's left out doesn't apply, and a limit of 0 turns every batch
+// away.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Quota {
+    pub rows: Option<u64>,
+    pub bytes: Option<u64>,
+    pub batches: Option<u64>,
+}
+
+impl Quota {
+    fn is_unlimited(&self) -> bool {
+        self.rows.is_none() && self.bytes.is_none() && self.batches.is_none()
+    }
+
+    // The first limit `used` has reached, and what it limits
+    fn reached(&self, used: &Usage) -> Option<(u64, &'static str)> {
+        [(self.rows, used.rows, "rows"), (self.bytes, used.bytes, "bytes"), (self.batches, used.batches, "batches")]
+            .into_iter()
+            .find_map(|(limit, used, what)| limit.filter(|limit| used >= *limit).map(|limit| (limit, what)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Usage {
+    rows: u64,
+    bytes: u64,
+    batches: u64,
+}
+
+impl Usage {
+    fn add(&mut self, other: Usage) {
+        self.rows += other.rows;
+        self.bytes += other.bytes;
+        self.batches += other.batches;
+    }
+}
+
+impl From<Usage> for SourceUsage {
+    fn from(usage: Usage) -> Self {
+        SourceUsage {
+            rows: usage.rows,
+            bytes: usage.bytes,
+            batches: usage.batches,
+        }
+    }
+}
+
+// What a client has sent
+#[derive(Debug)]
+struct Account {
+    total: Usage,
+    // The period `used` counts, as the number of periods since the Unix epoch
+    period: u64,
+    used: Usage,
+    // Batches turned away because a quota was used up
+    rejected: u64,
+    first_seen: DateTime<Utc>,
+    last_seen: DateTime<Utc>,
+}
+
+impl Account {
+    fn new(now: DateTime<Utc>, period: u64) -> Self {
+        Account {
+            total: Usage::default(),
+            period,
+            used: Usage::default(),
+            rejected: 0,
+            first_seen: now,
+            last_seen: now,
+        }
+    }
+
+    // Start counting a new period, if it's begun since the last batch
+    fn roll(&mut self, period: u64) {
+        if self.period != period {
+            self.period = period;
+            self.used = Usage::default();
+        }
+    }
+}
+
+// A client's quota is used up: why, and how many seconds until the next period
+#[derive(Debug)]
+struct Exceeded {
+    message: String,
+    retry_after: u64,
+}
+
+// Every client's account
+#[derive(Debug)]
+pub struct Accounting {
+    since: DateTime<Utc>,
+    accounts: Mutex<HashMap<String, Account>>,
+}
+
+impl Default for Accounting {
+    fn default() -> Self {
+        Accounting {
+            since: Utc::now(),
+            accounts: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+// The account a client's batches go in: its own, or the one clients past `MAX_SOURCES` share
+fn account_name<'a>(accounts: &HashMap<String, Account>, client: &'a str) -> &'a str {
+    if accounts.contains_key(client) || accounts.len() < MAX_SOURCES { client } else { OTHER_SOURCES }
+}
+
+// The period a time is in, as the number of periods since the Unix epoch (so daily periods start at midnight UTC)
+fn period_of(config: &QuotaConfig, now: DateTime<Utc>) -> u64 {
+    now.timestamp().max(0) as u64 / config.period_secs.max(1)
+}
+
+impl Accounting {
+    // Turn a client away if it has used up its quota for the period
+    fn admit(&self, config: &QuotaConfig, client: &str) -> Result<(), Exceeded> {
+        let quota = config.quota(client);
+        if quota.is_unlimited() {
+            return Ok(());
+        }
+        let now = Utc::now();
+        let period = period_of(config, now);
+
+        let mut accounts = self.accounts.lock().unwrap();
+        let name = account_name(&accounts, client);
+        let account = accounts.entry(name.to_string()).or_insert_with(|| Account::new(now, period));
+        account.roll(period);
+        let Some((limit, what)) = quota.reached(&account.used) else {
+            return Ok(());
+        };
+        account.rejected += 1;
+
+        let period_secs = config.period_secs.max(1);
+        let retry_after = ((period + 1) * period_secs).saturating_sub(now.timestamp().max(0) as u64).max(1);
+        Err(Exceeded {
+            message: format!(
+                "{} has used up its quota of {} {} per {} s. Retry in {} s, when the next period starts.",
+                client, limit, what, period_secs, retry_after
+            ),
+            retry_after,
+        })
+    }
+
+    // Add what a client has ingested to its account
+    fn charge(&self, config: &QuotaConfig, client: &str, usage: Usage) {
+        let now = Utc::now();
+        let period = period_of(config, now);
+
+        let mut accounts = self.accounts.lock().unwrap();
+        let name = account_name(&accounts, client);
+        let account = accounts.entry(name.to_string()).or_insert_with(|| Account::new(now, period));
+        account.roll(period);
+        account.total.add(usage);
+        account.used.add(usage);
+        account.last_seen = now;
+    }
+
+    // Every client's account, the ones that have sent the most bytes first
+    pub fn report(&self, config: &QuotaConfig) -> SourcesResponse {
+        let now = Utc::now();
+        let period = period_of(config, now);
+        let quotas = config.enabled();
+
+        let accounts = self.accounts.lock().unwrap();
+        let bytes: u64 = accounts.values().map(|account| account.total.bytes).sum();
+        let mut sources: Vec<SourceAccount> = accounts
+            .iter()
+            .map(|(name, account)| {
+                // What was used in an earlier period doesn't count toward this one's
+                let used = if account.period == period { account.used } else { Usage::default() };
+                SourceAccount {
+                    source: name.clone(),
+                    rows: account.total.rows,
+                    bytes: account.total.bytes,
+                    batches: account.total.batches,
+                    share: if bytes == 0 { 0.0 } else { account.total.bytes as f64 / bytes as f64 },
+                    rejected: account.rejected,
+                    first_seen: account.first_seen.to_rfc3339(),
+                    last_seen: account.last_seen.to_rfc3339(),
+                    used: quotas.then(|| used.into()),
+                    quota: quotas.then(|| config.quota(name)),
+                }
+            })
+            .collect();
+        drop(accounts);
+        sources.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.source.cmp(&b.source)));
+
+        let period_started = DateTime::from_timestamp((period * config.period_secs.max(1)) as i64, 0);
+        SourcesResponse {
+            status: Status::Success,
+            since: self.since.to_rfc3339(),
+            period_secs: quotas.then_some(config.period_secs),
+            period_started: period_started.filter(|_| quotas).map(|at| at.to_rfc3339()),
+            sources,
+        }
+    }
+}
+
+// Count a batch ingested while handling a request toward its client's account. Does nothing outside of one.
+pub(crate) fn count(rows: usize, bytes: usize) {
+    let _ = CHARGED.try_with(|charged| {
+        let mut usage = charged.get();
+        usage.add(Usage {
+            rows: rows as u64,
+            bytes: bytes as u64,
+            batches: 1,
+        });
+        charged.set(usage);
+    });
+}
+
+// Who a request is charged to: its API key, when it was sent with one the service knows (as `key:` and the key's ID,
+// which the audit log has too), or else the source it came from, as the provenance columns name it
+fn client(state: &AppState, request: &Request) -> String {
+    if let Some(key) = request.extensions().get::<KeyId>() {
+        return format!("key:{}", key.0);
+    }
+    let peer = request.extensions().get::<ConnectInfo<SocketAddr>>().map(|info| info.0);
+    provenance::source(state, request.headers(), peer)
+}
+
+// Middleware charging every request to its client for what it ingests. Requests to the routes that ingest are turned
+// away first if the client has used up its quota, so a client over its quota can still read.
+pub(crate) async fn account(State(state): State<Arc<AppState>>, mut request: Request, next: Next) -> Response {
+    let client = client(&state, &request);
+    let route = request.extensions().get::<MatchedPath>().map_or("", |path| path.as_str());
+    let ingest = request.method() == Method::POST && INGEST_ROUTES.iter().any(|suffix| route.ends_with(suffix));
+    if ingest && let Err(exceeded) = state.accounting.admit(&state.config.quotas, &client) {
+        let mut response = AppError::TooManyRequests(exceeded.message).into_response();
+        response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(exceeded.retry_after));
+        return response;
+    }
+
+    request.extensions_mut().insert(Client(client.clone()));
+    let handled = async {
+        let response = next.run(request).await;
+        (response, CHARGED.with(Cell::get))
+    };
+    let (response, usage) = CHARGED.scope(Cell::new(Usage::default()), handled).await;
+    if usage.batches > 0 {
+        state.accounting.charge(&state.config.quotas, &client, usage);
+    }
+
+    response
+}
+
+// Run an ingest that isn't a request of its own (a WebSocket message) on behalf of a client, the way `account` runs a
+// request
+pub(crate) async fn charged<T>(
+    state: &AppState,
+    client: &str,
+    ingest: impl Future<Output = Result<T, AppError>>,
+) -> Result<T, AppError> {
+    let admitted = state.accounting.admit(&state.config.quotas, client);
+    admitted.map_err(|exceeded| AppError::TooManyRequests(exceeded.message))?;
+
+    let handled = async {
+        let result = ingest.await;
+        (result, CHARGED.with(Cell::get))
+    };
+    let (result, usage) = CHARGED.scope(Cell::new(Usage::default()), handled).await;
+    if usage.batches > 0 {
+        state.accounting.charge(&state.config.quotas, client, usage);
+    }
+
+    result
+}
@@ -0,0 +1,958 @@
+use std::{collections::HashMap, io::Cursor, str::FromStr};
+
+use axum::http::{header, HeaderMap};
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::AppError,
+    filter::Filter,
+    sketch::{HyperLogLog, TDigest},
+    types::AggregateRequest,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", rename_all = "lowercase")]
+pub enum AggregateOperation {
+    #[default]
+    Sum,
+    Mean,
+    Min,
+    Max,
+    Count,
+    Median,
+    Std,
+    P90,
+    P95,
+    P99,
+    // The number of distinct (non-null) values
+    Distinct,
+}
+
+impl FromStr for AggregateOperation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "sum" => Ok(AggregateOperation::Sum),
+            "mean" => Ok(AggregateOperation::Mean),
+            "min" => Ok(AggregateOperation::Min),
+            "max" => Ok(AggregateOperation::Max),
+            "count" => Ok(AggregateOperation::Count),
+            "median" | "p50" => Ok(AggregateOperation::Median),
+            "std" => Ok(AggregateOperation::Std),
+            "p90" => Ok(AggregateOperation::P90),
+            "p95" => Ok(AggregateOperation::P95),
+            "p99" => Ok(AggregateOperation::P99),
+            "distinct" => Ok(AggregateOperation::Distinct),
+            other => Err(format!(
+                "Unsupported aggregate operation {:?} (expected one of: sum, mean, min, max, count, median, std, p50, \
+                 p90, p95, p99, distinct)",
+                other
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for AggregateOperation {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl AggregateOperation {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AggregateOperation::Sum => "sum",
+            AggregateOperation::Mean => "mean",
+            AggregateOperation::Min => "min",
+            AggregateOperation::Max => "max",
+            AggregateOperation::Count => "count",
+            AggregateOperation::Median => "median",
+            AggregateOperation::Std => "std",
+            AggregateOperation::P90 => "p90",
+            AggregateOperation::P95 => "p95",
+            AggregateOperation::P99 => "p99",
+            AggregateOperation::Distinct => "distinct",
+        }
+    }
+
+    // The quantile a percentile operation (or median) picks out
+    pub fn quantile(&self) -> Option<f64> {
+        match self {
+            AggregateOperation::Median => Some(0.5),
+            AggregateOperation::P90 => Some(0.9),
+            AggregateOperation::P95 => Some(0.95),
+            AggregateOperation::P99 => Some(0.99),
+            _ => None,
+        }
+    }
+
+    // Apply the operation to an expression (used inside a group-by `agg`)
+    pub fn apply(&self, expr: Expr) -> Expr {
+        match self {
+            AggregateOperation::Sum => expr.sum(),
+            AggregateOperation::Mean => expr.mean(),
+            AggregateOperation::Min => expr.min(),
+            AggregateOperation::Max => expr.max(),
+            AggregateOperation::Count => expr.count(),
+            AggregateOperation::Median => expr.median(),
+            // Sample standard deviation (same default as pandas)
+            AggregateOperation::Std => expr.std(1),
+            AggregateOperation::P90 | AggregateOperation::P95 | AggregateOperation::P99 => {
+                let quantile = self.quantile().expect("percentiles have a quantile");
+                expr.quantile(lit(quantile), QuantileMethod::Linear)
+            }
+            AggregateOperation::Distinct => expr.drop_nulls().n_unique(),
+        }
+    }
+}
+
+// Describes how `/aggregate` reduces a DataFrame: the group-by keys (and time windows, if any) plus the operation used
+// for each other column
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AggregateSpec {
+    // Write-ahead log records from before multi-key group-bys have a single `key`
+    #[serde(alias = "key", deserialize_with = "one_or_many")]
+    pub keys: Vec<String>,
+    // Operation for any column not listed in `ops`
+    pub default_op: AggregateOperation,
+    pub ops: HashMap<String, AggregateOperation>,
+    // Also group rows into time windows, within each key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window: Option<TimeWindow>,
+}
+
+// Windows of a timestamp column `/aggregate` groups rows into: tumbling (back to back) when `every` and `period` are
+// the same, or sliding (overlapping) when windows start more often than they last. Durations use Polars' syntax, e.g.
+// `30s`, `5m`, `1h`, or `1d`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeWindow {
+    pub column: String,
+    // How long each window lasts
+    pub period: String,
+    // How far apart windows start
+    pub every: String,
+}
+
+impl TimeWindow {
+    // A window from the `window` and `slide` parameters. `window` may carry the slide itself, as in `5m,slide=1m`.
+    pub fn new(column: &str, window: &str, slide: Option<&str>) -> Result<Self, String> {
+        let (period, inline_slide) = match window.split_once(',') {
+            Some((period, rest)) => match rest.trim().strip_prefix("slide=") {
+                Some(slide) => (period.trim(), Some(slide.trim())),
+                None => return Err(format!("Invalid window {:?} (expected e.g. 5m or 5m,slide=1m)", window)),
+            },
+            None => (window.trim(), None),
+        };
+        let every = match (inline_slide, slide) {
+            (Some(_), Some(_)) => return Err(String::from("Give the slide either in `window` or as `slide`, not both")),
+            (Some(slide), None) | (None, Some(slide)) => slide.trim(),
+            (None, None) => period,
+        };
+
+        let window = TimeWindow {
+            column: column.to_string(),
+            period: period.to_string(),
+            every: every.to_string(),
+        };
+        window.options()?;
+        Ok(window)
+    }
+
+    fn options(&self) -> Result<DynamicGroupOptions, String> {
+        Ok(DynamicGroupOptions {
+            index_column: self.column.as_str().into(),
+            every: parse_duration(&self.every)?,
+            period: parse_duration(&self.period)?,
+            offset: Duration::parse("0ns"),
+            // Each window is labelled with its start, and holds the rows from its start up to (not including) its end
+            label: Label::Left,
+            closed_window: ClosedWindow::Left,
+            start_by: StartBy::WindowBound,
+            include_boundaries: false,
+        })
+    }
+}
+
+impl AggregateSpec {
+    // Make sure every column the spec refers to exists in the DataFrame
+    pub fn validate(&self, df: &DataFrame) -> Result<(), String> {
+        let names = df.get_column_names_str();
+
+        if self.keys.is_empty() && self.window.is_none() {
+            return Err(String::from("At least one key column is required"));
+        }
+        for key in &self.keys {
+            if !names.contains(&key.as_str()) {
+                return Err(format!("Key column {:?} is not present in the payload", key));
+            }
+        }
+        if let Some(window) = &self.window {
+            if !names.contains(&window.column.as_str()) {
+                return Err(format!("Time column {:?} is not present in the payload", window.column));
+            }
+            if self.keys.contains(&window.column) || self.ops.contains_key(&window.column) {
+                return Err(format!("Time column {:?} can't also be a key or aggregated", window.column));
+            }
+        }
+
+        for column in self.ops.keys() {
+            if self.keys.contains(column) {
+                return Err(format!("Key column {:?} cannot also be aggregated", column));
+            }
+            if !names.contains(&column.as_str()) {
+                return Err(format!("Column {:?} in ops is not present in the payload", column));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Every non-key (and non-time) column of a DataFrame, in order, with the operation the spec applies to it
+    pub fn column_ops(&self, df: &DataFrame) -> Vec<(String, AggregateOperation)> {
+        df.get_column_names_str()
+            .into_iter()
+            .filter(|name| !self.keys.iter().any(|key| key == name))
+            .filter(|name| self.window.as_ref().is_none_or(|window| window.column != *name))
+            .map(|name| {
+                let operation = self.ops.get(name).copied().unwrap_or(self.default_op);
+                (name.to_string(), operation)
+            })
+            .collect()
+    }
+
+    // Make sure the spec only uses operations a running aggregate can keep up in `mode` (incremental or approximate)
+    pub fn check_running(&self, df: &DataFrame, mode: AggregateMode) -> Result<(), String> {
+        if self.window.is_some() {
+            return Err(format!("Time windows can't be aggregated in {} mode (use full mode)", mode.name()));
+        }
+        if mode == AggregateMode::Approximate {
+            return Ok(());
+        }
+        for (column, operation) in self.column_ops(df) {
+            if partials(operation).iter().any(Partial::is_sketch) {
+                return Err(format!(
+                    "Column {:?} uses {}, which can't be aggregated incrementally (use sum, mean, min, max, count or \
+                     std, or approximate mode)",
+                    column,
+                    operation.name()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    // The rows of an aggregate's result for the groups a payload has rows in, i.e. the ones it just changed
+    pub fn changed_groups(&self, result: &DataFrame, payload: &DataFrame) -> PolarsResult<DataFrame> {
+        let keys: Vec<Expr> = self.group_columns().iter().map(|column| col(column.as_str())).collect();
+        // Without keys there's only the one group
+        if keys.is_empty() {
+            return Ok(result.clone());
+        }
+
+        let groups = group_by_spec(payload, self)?.lazy().select(keys.clone());
+        result.clone().lazy().join(groups, keys.clone(), keys, JoinArgs::new(JoinType::Semi)).collect()
+    }
+
+    // The columns that tell the groups of the result apart: the keys, then the window's start
+    pub fn group_columns(&self) -> Vec<String> {
+        let mut columns = self.keys.clone();
+        columns.extend(self.window.iter().map(|window| window.column.clone()));
+        columns
+    }
+
+    fn key_exprs(&self) -> Vec<Expr> {
+        self.keys.iter().map(|key| col(key.as_str())).collect()
+    }
+}
+
+// Group a DataFrame by the spec's keys and reduce every other column with its operation. Keys keep the order they were first seen in.
+pub fn group_by_spec(df: &DataFrame, spec: &AggregateSpec) -> PolarsResult<DataFrame> {
+    let aggs: Vec<Expr> = spec
+        .column_ops(df)
+        .into_iter()
+        .map(|(name, operation)| operation.apply(col(name)))
+        .collect();
+
+    let Some(window) = &spec.window else {
+        return df.clone().lazy().group_by_stable(spec.key_exprs()).agg(aggs).collect();
+    };
+
+    // One row per key and window, sorted by key and then time. Windows with no rows are left out, and so are rows
+    // without a timestamp.
+    let options = window.options().map_err(|message| polars_err!(ComputeError: message))?;
+    let df = parse_timestamps(df, &window.column)?;
+    let mut order = spec.key_exprs();
+    order.push(col(window.column.as_str()));
+
+    df.lazy()
+        .filter(col(window.column.as_str()).is_not_null())
+        .sort_by_exprs(order, SortMultipleOptions::default().with_maintain_order(true))
+        .group_by_dynamic(col(window.column.as_str()), spec.key_exprs(), options)
+        .agg(aggs)
+        .collect()
+}
+
+// `df` with its time column ready to be windowed. CSV timestamps arrive as text, so they're parsed (as RFC 3339);
+// columns that already hold dates, times, or integers are left as they are.
+pub fn parse_timestamps(df: &DataFrame, column: &str) -> PolarsResult<DataFrame> {
+    let mut df = df.clone();
+    let time = df.column(column)?;
+    if time.dtype() == &DataType::String {
+        let parsed = time.strict_cast(&DataType::Datetime(TimeUnit::Microseconds, None)).map_err(|_| {
+            polars_err!(
+                ComputeError: "time column {:?} has values that aren't timestamps like 2025-03-01T12:00:00Z",
+                column
+            )
+        })?;
+        df.with_column(parsed)?;
+    }
+
+    Ok(df)
+}
+
+// Parse a positive duration in Polars' syntax, e.g. `30s`, `5m`, or `100i`
+pub fn parse_duration(duration: &str) -> Result<Duration, String> {
+    match Duration::try_parse(duration) {
+        Ok(parsed) if parsed.is_zero() || parsed.negative() => Err(format!("Duration {:?} must be positive", duration)),
+        Ok(parsed) => Ok(parsed),
+        Err(_) => Err(format!("Invalid duration {:?} (expected e.g. 30s, 5m, 1h, or 1d)", duration)),
+    }
+}
+
+// How `/aggregate` keeps a dataset's aggregate up to date
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", rename_all = "lowercase")]
+pub enum AggregateMode {
+    // Keep every raw row and re-group all of them on each request. Supports every operation, but each request costs
+    // O(total rows).
+    #[default]
+    Full,
+    // Keep running partial aggregates per key instead of raw rows, so each request only costs O(payload rows + keys).
+    // Median, percentiles and distinct counts aren't supported.
+    Incremental,
+    // Like incremental, with median and percentiles kept as t-digests and distinct counts as HyperLogLogs (see
+    // `sketch`), which are estimates
+    Approximate,
+}
+
+impl AggregateMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AggregateMode::Full => "full",
+            AggregateMode::Incremental => "incremental",
+            AggregateMode::Approximate => "approximate",
+        }
+    }
+}
+
+impl FromStr for AggregateMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "full" => Ok(AggregateMode::Full),
+            "incremental" => Ok(AggregateMode::Incremental),
+            "approximate" => Ok(AggregateMode::Approximate),
+            other => Err(format!(
+                "Unsupported aggregate mode {:?} (expected full, incremental or approximate)",
+                other
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for AggregateMode {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+// What a dataset's aggregate is recomputed from
+#[derive(Debug, Clone)]
+pub enum AggregateState {
+    // Every raw row received so far (full mode)
+    History(DataFrame),
+    // Partial aggregates (and sketches) per key (incremental and approximate mode)
+    Running(RunningAggregate),
+}
+
+// The pieces a column's result is rebuilt from. Each one can be combined across payloads on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Partial {
+    Sum,
+    Count,
+    Min,
+    Max,
+    SumOfSquares,
+    // Sketches, kept as bytes. Polars only collects a group's values (or earlier sketches) for them, and
+    // `RunningAggregate::sketched` does the rest.
+    Digest,
+    Distinct,
+}
+
+impl Partial {
+    // Reduce a raw column to this partial
+    fn of_rows(&self, column: Expr) -> Expr {
+        match self {
+            Partial::Sum => column.sum(),
+            Partial::Count => column.count().cast(DataType::Int64),
+            Partial::Min => column.min(),
+            Partial::Max => column.max(),
+            Partial::SumOfSquares => {
+                let value = column.strict_cast(DataType::Float64);
+                (value.clone() * value).sum()
+            }
+            Partial::Digest => column.strict_cast(DataType::Float64),
+            // Values are told apart by their text, whatever the dtype
+            Partial::Distinct => column.cast(DataType::String),
+        }
+    }
+
+    // Reduce several partials (from earlier payloads and a new one) to one
+    fn combine(&self, partial: Expr) -> Expr {
+        match self {
+            Partial::Sum | Partial::Count | Partial::SumOfSquares => partial.sum(),
+            Partial::Min => partial.min(),
+            Partial::Max => partial.max(),
+            Partial::Digest | Partial::Distinct => partial,
+        }
+    }
+
+    fn is_sketch(&self) -> bool {
+        matches!(self, Partial::Digest | Partial::Distinct)
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Partial::Sum => "sum",
+            Partial::Count => "count",
+            Partial::Min => "min",
+            Partial::Max => "max",
+            Partial::SumOfSquares => "sum_sq",
+            Partial::Digest => "digest",
+            Partial::Distinct => "distinct",
+        }
+    }
+}
+
+fn partials(operation: AggregateOperation) -> &'static [Partial] {
+    match operation {
+        AggregateOperation::Sum => &[Partial::Sum],
+        AggregateOperation::Count => &[Partial::Count],
+        AggregateOperation::Min => &[Partial::Min],
+        AggregateOperation::Max => &[Partial::Max],
+        AggregateOperation::Mean => &[Partial::Sum, Partial::Count],
+        AggregateOperation::Std => &[Partial::Sum, Partial::SumOfSquares, Partial::Count],
+        // Only in approximate mode (see `AggregateSpec::check_running`)
+        AggregateOperation::Median | AggregateOperation::P90 | AggregateOperation::P95 | AggregateOperation::P99 => {
+            &[Partial::Digest]
+        }
+        AggregateOperation::Distinct => &[Partial::Distinct],
+    }
+}
+
+// Running sums/counts/minimums/maximums (and in approximate mode, sketches) per key, one row per key. Adding a payload
+// only groups the payload and merges it with this table, so the raw rows never need to be kept around.
+#[derive(Debug, Clone)]
+pub struct RunningAggregate {
+    keys: Vec<String>,
+    columns: Vec<(String, AggregateOperation)>,
+    table: DataFrame,
+}
+
+// Everything about a running aggregate except its table
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunningLayout {
+    pub keys: Vec<String>,
+    pub columns: Vec<(String, AggregateOperation)>,
+}
+
+impl RunningAggregate {
+    // Start from raw rows
+    pub fn new(df: &DataFrame, spec: &AggregateSpec, mode: AggregateMode) -> PolarsResult<Self> {
+        if let Err(message) = spec.check_running(df, mode) {
+            polars_bail!(ComputeError: message);
+        }
+
+        let running = RunningAggregate {
+            keys: spec.keys.clone(),
+            columns: spec.column_ops(df),
+            table: DataFrame::empty(),
+        };
+        let table = running.partials_of(df)?;
+
+        Ok(RunningAggregate { table, ..running })
+    }
+
+    // The running aggregate with a payload merged in. Nothing is changed until the caller stores it.
+    pub fn updated(&self, df: &DataFrame, spec: &AggregateSpec) -> PolarsResult<Self> {
+        if spec.keys != self.keys || spec.column_ops(df) != self.columns {
+            polars_bail!(
+                ComputeError: "incremental aggregation needs the same keys, columns and operations on every request \
+                (expected keys {:?} and columns {:?})",
+                self.keys,
+                self.columns.iter().map(|(name, _)| name).collect::<Vec<_>>()
+            );
+        }
+
+        let df = self.cast_like_table(df)?;
+        let args = UnionArgs {
+            rechunk: false,
+            to_supertypes: true,
+            ..Default::default()
+        };
+        let combined = concat([self.table.clone().lazy(), self.partials_of(&df)?.lazy()], args)?;
+
+        let mut aggs = Vec::new();
+        for (index, (_, operation)) in self.columns.iter().enumerate() {
+            for partial in partials(*operation) {
+                let name = partial_name(index, *partial);
+                aggs.push(partial.combine(col(name.as_str())).alias(name));
+            }
+        }
+        let table = combined.group_by_stable(self.key_exprs()).agg(aggs).collect()?;
+        let table = self.sketched(table, false)?;
+
+        Ok(RunningAggregate {
+            table,
+            ..self.clone()
+        })
+    }
+
+    // The aggregate itself, in the same shape (and with the same dtypes) `group_by_spec` produces. Sketches are
+    // replaced by their estimates first.
+    pub fn finish(&self) -> PolarsResult<DataFrame> {
+        let mut table = self.table.clone();
+        for (index, (_, operation)) in self.columns.iter().enumerate() {
+            let digest = partial_name(index, Partial::Digest);
+            let distinct = partial_name(index, Partial::Distinct);
+            if let Some(quantile) = operation.quantile() {
+                let estimates = sketches(&table, &digest)?
+                    .map(|sketch| Ok(TDigest::from_bytes(sketch).map_err(to_compute_error)?.quantile(quantile)))
+                    .collect::<PolarsResult<Float64Chunked>>()?;
+                table.with_column(estimates.with_name(digest.into()).into_column())?;
+            } else if *operation == AggregateOperation::Distinct {
+                let estimates = sketches(&table, &distinct)?
+                    .map(|sketch| Ok(HyperLogLog::from_bytes(sketch).map_err(to_compute_error)?.estimate() as IdxSize))
+                    .collect::<PolarsResult<Vec<_>>>()?;
+                table.with_column(IdxCa::from_vec(distinct.into(), estimates).into_column())?;
+            }
+        }
+
+        let mut exprs = self.key_exprs();
+        for (index, (name, operation)) in self.columns.iter().enumerate() {
+            let partial = |partial| col(partial_name(index, partial).as_str());
+            let count = || partial(Partial::Count).cast(DataType::Float64);
+
+            let expr = match operation {
+                AggregateOperation::Sum => partial(Partial::Sum),
+                AggregateOperation::Count => partial(Partial::Count).cast(IDX_DTYPE),
+                AggregateOperation::Min => partial(Partial::Min),
+                AggregateOperation::Max => partial(Partial::Max),
+                AggregateOperation::Mean => when(count().gt(lit(0)))
+                    .then(partial(Partial::Sum).cast(DataType::Float64) / count())
+                    .otherwise(lit(NULL)),
+                AggregateOperation::Std => {
+                    let sum = partial(Partial::Sum).cast(DataType::Float64);
+                    let variance = (partial(Partial::SumOfSquares) - sum.clone() * sum / count()) / (count() - lit(1));
+                    // Rounding can leave the variance of identical values slightly below zero
+                    let variance = when(variance.clone().lt(lit(0.0))).then(lit(0.0)).otherwise(variance);
+                    when(count().gt(lit(1))).then(variance.sqrt()).otherwise(lit(NULL))
+                }
+                // Estimated above
+                AggregateOperation::Distinct => partial(Partial::Distinct),
+                _ => partial(Partial::Digest),
+            };
+            exprs.push(expr.alias(name.as_str()));
+        }
+
+        table.lazy().select(exprs).collect()
+    }
+
+    // The running aggregate without the keys matching `filter`, which may only refer to key columns
+    pub fn retain(&self, filter: &Filter) -> Result<Self, String> {
+        if let Some(column) = filter.columns().into_iter().find(|column| !self.keys.iter().any(|key| key == column)) {
+            return Err(format!(
+                "Rows of an incrementally aggregated dataset can only be deleted by key columns ({:?} is not one of {:?})",
+                column, self.keys
+            ));
+        }
+
+        let expr = filter.to_expr(self.table.schema())?;
+        let table = self.table.clone().lazy().filter(expr.not()).collect().map_err(|e| e.to_string())?;
+
+        Ok(RunningAggregate {
+            table,
+            ..self.clone()
+        })
+    }
+
+    // The aggregate without the groups of `rows` (rows of its result)
+    pub fn without_groups(&self, rows: &DataFrame) -> Result<Self, String> {
+        // Without keys there's only the one group
+        let table = if self.keys.is_empty() {
+            self.table.clear()
+        } else {
+            let keys = self.key_exprs();
+            let groups = rows.clone().lazy().select(keys.clone());
+            self.table
+                .clone()
+                .lazy()
+                .join(groups, keys.clone(), keys, JoinArgs::new(JoinType::Anti))
+                .collect()
+                .map_err(|e| e.to_string())?
+        };
+
+        Ok(RunningAggregate {
+            table,
+            ..self.clone()
+        })
+    }
+
+    // The keys and columns the partials belong to, for storing the aggregate outside of memory (see `snapshot`)
+    pub fn layout(&self) -> RunningLayout {
+        RunningLayout {
+            keys: self.keys.clone(),
+            columns: self.columns.clone(),
+        }
+    }
+
+    // The partials themselves, one row per key
+    pub fn table(&self) -> &DataFrame {
+        &self.table
+    }
+
+    // Put a stored running aggregate back together
+    pub fn from_parts(layout: RunningLayout, table: DataFrame) -> Self {
+        RunningAggregate {
+            keys: layout.keys,
+            columns: layout.columns,
+            table,
+        }
+    }
+
+    // A payload with its value columns cast to the dtypes of the rows aggregated before it, which a sum, minimum, or
+    // maximum partial keeps
+    fn cast_like_table(&self, df: &DataFrame) -> PolarsResult<DataFrame> {
+        let kept = [Partial::Sum, Partial::Min, Partial::Max];
+        let mut dtypes = Vec::new();
+        for (index, (name, operation)) in self.columns.iter().enumerate() {
+            if let Some(partial) = partials(*operation).iter().find(|partial| kept.contains(partial)) {
+                let dtype = self.table.column(&partial_name(index, *partial))?.dtype();
+                dtypes.push((name.as_str(), dtype));
+            }
+        }
+
+        cast_like(df, dtypes)
+    }
+
+    // Group raw rows into partials
+    fn partials_of(&self, df: &DataFrame) -> PolarsResult<DataFrame> {
+        let mut aggs = Vec::new();
+        for (index, (name, operation)) in self.columns.iter().enumerate() {
+            for partial in partials(*operation) {
+                aggs.push(partial.of_rows(col(name.as_str())).alias(partial_name(index, *partial)));
+            }
+        }
+
+        let table = df.clone().lazy().group_by_stable(self.key_exprs()).agg(aggs).collect()?;
+        self.sketched(table, true)
+    }
+
+    // Turn the lists a group-by leaves in the sketch partials' columns into one sketch per key: of the group's values
+    // when they're `raw`, or else merging the group's sketches
+    fn sketched(&self, mut table: DataFrame, raw: bool) -> PolarsResult<DataFrame> {
+        for (index, (_, operation)) in self.columns.iter().enumerate() {
+            for partial in partials(*operation).iter().filter(|partial| partial.is_sketch()) {
+                let name = partial_name(index, *partial);
+                let groups = table.column(&name)?.list()?.clone();
+                let mut sketches = Vec::with_capacity(groups.len());
+                for group in groups.into_iter() {
+                    let group = group.unwrap_or_else(|| Series::new_empty(PlSmallStr::EMPTY, &DataType::Null));
+                    let sketch = match (partial, raw) {
+                        (Partial::Digest, true) => TDigest::of_values(group.f64()?.into_iter().flatten()).to_bytes(),
+                        (Partial::Digest, false) => {
+                            let merged = TDigest::merged(group.binary()?.into_iter().flatten());
+                            merged.map_err(to_compute_error)?.to_bytes()
+                        }
+                        (_, true) => {
+                            let values = group.str()?.into_iter().flatten().map(str::as_bytes);
+                            HyperLogLog::of_values(values).to_bytes()
+                        }
+                        (_, false) => {
+                            let merged = HyperLogLog::merged(group.binary()?.into_iter().flatten());
+                            merged.map_err(to_compute_error)?.to_bytes()
+                        }
+                    };
+                    sketches.push(sketch);
+                }
+                table.with_column(BinaryChunked::from_slice(name.into(), &sketches).into_column())?;
+            }
+        }
+
+        Ok(table)
+    }
+
+    fn key_exprs(&self) -> Vec<Expr> {
+        self.keys.iter().map(|key| col(key.as_str())).collect()
+    }
+}
+
+// A payload with its columns cast (strictly) to the dtypes of the rows already aggregated, so incremental and full
+// mode turn away the same payloads instead of one of them nulling out what it can't cast
+pub fn cast_like<'a>(
+    df: &DataFrame,
+    dtypes: impl IntoIterator<Item = (&'a str, &'a DataType)>,
+) -> PolarsResult<DataFrame> {
+    let mut df = df.clone();
+    for (name, dtype) in dtypes {
+        let Ok(column) = df.column(name) else {
+            continue;
+        };
+        if *dtype == DataType::Null || column.dtype() == dtype {
+            continue;
+        }
+
+        let cast = column.strict_cast(dtype).map_err(|_| {
+            polars_err!(
+                SchemaMismatch: "column {:?} is {} in the payload, which can't be cast to the {} of the rows \
+                aggregated before it",
+                name,
+                column.dtype(),
+                dtype
+            )
+        })?;
+        df.with_column(cast)?;
+    }
+
+    Ok(df)
+}
+
+// Partials are named by column position rather than column name, so they can't clash with a key column
+fn partial_name(index: usize, partial: Partial) -> String {
+    format!("__partial_{}_{}", index, partial.name())
+}
+
+// The sketches of a binary partial column, one per key
+fn sketches<'a>(table: &'a DataFrame, name: &str) -> PolarsResult<impl Iterator<Item = &'a [u8]>> {
+    Ok(table.column(name)?.binary()?.into_iter().map(|sketch| sketch.unwrap_or_default()))
+}
+
+fn to_compute_error(message: String) -> PolarsError {
+    polars_err!(ComputeError: message)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AggregateParams {
+    // Aggregate operation to use (takes precedence over the `X-Aggregate-Op` header)
+    pub op: Option<String>,
+    // Comma-separated group-by columns, e.g. `job_id,rank` (defaults to the first column of the CSV, or with a window,
+    // to none)
+    pub keys: Option<String>,
+    // Group rows into windows of this length, e.g. `5m` (see `TimeWindow`)
+    pub window: Option<String>,
+    // Start a window this often, for sliding windows, e.g. `1m`
+    pub slide: Option<String>,
+    // The timestamp column windows are taken over (`timestamp` by default)
+    pub time: Option<String>,
+    // Only aggregate the payload's rows that match this filter (see `filter::Filter`)
+    pub filter: Option<String>,
+    // Left-join the returned rows against this lookup table (see `lookup::LookupTable`)
+    pub lookup: Option<String>,
+}
+
+// Accept either a single string or a list of strings
+pub fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(one) => vec![one],
+        OneOrMany::Many(many) => many,
+    })
+}
+
+// Time column windows are taken over when the request doesn't name one
+pub const DEFAULT_TIME_COLUMN: &str = "timestamp";
+
+// Pick the aggregate operation from the query string, then the `X-Aggregate-Op` header, then the configured default
+fn requested_operation(
+    params: &AggregateParams,
+    headers: &HeaderMap,
+    default_op: AggregateOperation,
+) -> Result<AggregateOperation, String> {
+    if let Some(op) = &params.op {
+        return op.parse();
+    }
+
+    match headers.get("x-aggregate-op") {
+        Some(value) => value
+            .to_str()
+            .map_err(|_| String::from("The X-Aggregate-Op header must be valid ASCII"))?
+            .parse(),
+        None => Ok(default_op),
+    }
+}
+
+// Check if the request body was sent as JSON
+pub(crate) fn is_json_body(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"))
+}
+
+// Read an `/aggregate` body (either raw CSV or a JSON `AggregateRequest`) into the payload DataFrame and its spec
+pub fn parse_aggregate_body(
+    params: &AggregateParams,
+    headers: &HeaderMap,
+    body: &str,
+    configured_op: AggregateOperation,
+) -> Result<(DataFrame, AggregateSpec), AppError> {
+    let mut default_op = requested_operation(params, headers, configured_op).map_err(AppError::BadRequest)?;
+
+    let (csv, keys, ops) = if is_json_body(headers) {
+        let request: AggregateRequest = serde_json::from_str(body)
+            .map_err(|e| AppError::BadRequest(format!("Invalid aggregate request: {}", e)))?;
+
+        if let Some(op) = &request.op {
+            default_op = op.parse().map_err(AppError::BadRequest)?;
+        }
+
+        let mut ops = HashMap::new();
+        for (column, op) in request.ops {
+            ops.insert(column, op.parse().map_err(AppError::BadRequest)?);
+        }
+
+        let keys = match (request.key, request.keys) {
+            (Some(_), Some(_)) => return Err(AppError::BadRequest(String::from("Use either key or keys, not both"))),
+            (Some(key), None) => Some(vec![key]),
+            (None, keys) => keys,
+        };
+
+        (request.csv, keys, ops)
+    } else {
+        (body.to_string(), None, HashMap::new())
+    };
+    let keys = keys.or_else(|| {
+        params
+            .keys
+            .as_ref()
+            .map(|keys| keys.split(',').map(str::trim).filter(|key| !key.is_empty()).map(String::from).collect())
+    });
+
+    // Use Polars to read the CSV
+    let df = CsvReader::new(Cursor::new(csv.as_bytes()))
+        .finish()
+        .map_err(|e| AppError::BadRequest(format!("Error parsing CSV: {}", e)))?;
+
+    let window = match &params.window {
+        Some(window) => Some(
+            TimeWindow::new(params.time.as_deref().unwrap_or(DEFAULT_TIME_COLUMN), window, params.slide.as_deref())
+                .map_err(AppError::BadRequest)?,
+        ),
+        None if params.slide.is_some() || params.time.is_some() => {
+            return Err(AppError::BadRequest(String::from("`slide` and `time` only apply with a `window`")));
+        }
+        None => None,
+    };
+
+    // Group on the first column header unless told otherwise (windows can be aggregated without keys)
+    let keys = match keys {
+        Some(keys) => keys,
+        None if window.is_some() => Vec::new(),
+        None => match df.get_columns().first() {
+            Some(column) => vec![column.name().to_string()],
+            None => return Err(AppError::BadRequest(String::from("The CSV payload has no columns"))),
+        },
+    };
+
+    let spec = AggregateSpec {
+        keys,
+        default_op,
+        ops,
+        window,
+    };
+    spec.validate(&df).map_err(AppError::SchemaMismatch)?;
+
+    Ok((df, spec))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dataset::Dataset;
+
+    fn spec() -> AggregateSpec {
+        let ops = [
+            ("sum", AggregateOperation::Sum),
+            ("mean", AggregateOperation::Mean),
+            ("min", AggregateOperation::Min),
+            ("max", AggregateOperation::Max),
+            ("count", AggregateOperation::Count),
+        ];
+        AggregateSpec {
+            keys: vec![String::from("host")],
+            default_op: AggregateOperation::Sum,
+            ops: ops.into_iter().map(|(column, op)| (column.to_string(), op)).collect(),
+            window: None,
+        }
+    }
+
+    fn payload(hosts: &[&str], values: Column) -> DataFrame {
+        let mut columns = vec![Column::new("host".into(), hosts)];
+        for name in ["sum", "mean", "min", "max", "count"] {
+            columns.push(values.clone().with_name(name.into()));
+        }
+        DataFrame::new(columns).unwrap()
+    }
+
+    // Run payloads through a dataset aggregated in `mode`, returning whether each was accepted and the final result
+    fn aggregate_all(mode: AggregateMode, payloads: &[DataFrame]) -> (Vec<bool>, DataFrame) {
+        let spec = spec();
+        let mut dataset = Dataset::default();
+        let mut accepted = Vec::new();
+        for payload in payloads {
+            match dataset.aggregated(payload, &spec, mode) {
+                Ok((state, df)) => {
+                    dataset.aggregate_state = Some(state);
+                    dataset.df = Some(df);
+                    accepted.push(true);
+                }
+                Err(_) => accepted.push(false),
+            }
+        }
+        (accepted, dataset.df.unwrap())
+    }
+
+    #[test]
+    fn incremental_matches_full() {
+        let payloads = [
+            payload(&["a", "b", "a"], Column::new("".into(), [1i64, 2, 4])),
+            // Not a number, so it can't be cast to the integers aggregated before it
+            payload(&["a"], Column::new("".into(), ["abc"])),
+            payload(&["b", "c"], Column::new("".into(), [Some(8i64), None])),
+            payload(&["c"], Column::new("".into(), [6i64])),
+        ];
+
+        let (full_accepted, full) = aggregate_all(AggregateMode::Full, &payloads);
+        let (incremental_accepted, incremental) = aggregate_all(AggregateMode::Incremental, &payloads);
+        assert_eq!(full_accepted, [true, false, true, true]);
+        assert_eq!(incremental_accepted, full_accepted);
+        assert!(incremental.equals_missing(&full), "incremental:\n{}\nfull:\n{}", incremental, full);
+
+        let sum = full.column("sum").unwrap().i64().unwrap();
+        assert_eq!(sum.into_iter().collect::<Vec<_>>(), [Some(5), Some(10), Some(6)]);
+    }
+}
@@ -0,0 +1,3494 @@
+// The HTTP API: every endpoint `serve` answers, on top of a `Collator`
+
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use axum::{
+    body::{Body, Bytes}, extract::{ConnectInfo, RawQuery, State}, http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode}, middleware, response::{sse::{self, KeepAlive, Sse}, Html, IntoResponse, Response}, routing::{get, post}, Json, Router
+};
+use indexmap::IndexMap;
+use serde::Deserialize;
+use serde_json::Value;
+use log::{error, info, trace, warn};
+use polars::prelude::*;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    accounting, audit, auth, barrier, batch, completeness, compression, dashboard, describe, health, history, idempotency, line_protocol, logging, openapi, partition, persist, rank, rate_limit,
+    remote_write, replication, resample, reshape, rolling, sharding, snapshot, stats, ws,
+};
+use crate::accounting::Client;
+use crate::audit::AuditQuery;
+use crate::auth::Scope;
+use crate::aggregate::{parse_aggregate_body, AggregateOperation, AggregateParams};
+use crate::batch::BatchResult;
+use crate::collator::{
+    aggregate_rows, collate_points, ingest, locked, log_payload, persist_result, prepared, read_window, requested_lookup,
+    Collator, Merge, ReadOptions,
+};
+use crate::completeness::Expectation;
+use crate::computed::ComputedColumns;
+use crate::dataset::{validate_dataset_name, AppState, ConcatMode, Dataset, KeepDuplicate, SharedDataset, DEFAULT_DATASET};
+use crate::error::{AppError, Path, Query};
+use crate::events::{Change, Event};
+use crate::filter::Filter;
+use crate::line_protocol::Precision;
+use crate::lookup::LookupTable;
+use crate::metrics::{dataset_gauges, track_requests};
+use crate::nulls::FillSpec;
+use crate::outliers::{OutlierSpec, OUTLIER_COLUMN};
+use crate::payload::{content_type_headers, read_payload, sniff_content_type, write_df, FileFormat};
+use crate::persist::WriteMode;
+use crate::provenance::Origin;
+use crate::resample::ResampleSpec;
+use crate::reshape::{MeltSpec, PivotSpec};
+use crate::rolling::RollingSpec;
+use crate::schema::DatasetSchema;
+use crate::serialize::{df_response, df_to_csv, df_to_json_records, negotiate, ResponseFormat};
+use crate::snapshot::validate_snapshot_id;
+use crate::stream::csv_body;
+use crate::types::{
+    AuditResponse, AwaitResponse, BatchResponse, ChangeEvent, CollateResponse, ColumnSummary, CompletenessResponse, ComputedResponse, DataResponse, DatasetDeletedResponse, DiffResponse, DistinctCount,
+    DistinctResponse,
+    DatasetResponse, DatasetsResponse, DeleteResponse, DescribeResponse, FilledResponse, FlushResponse, IngestCounts,
+    Lagged, LogLevelRequest, LogLevelResponse, LookupDeletedResponse, LookupLoadedResponse, LookupResponse, LookupsResponse, NullCount, NullsResponse,
+    OutliersResponse, ProbeResponse, QuarantineClearedResponse, QuarantineResponse, RemovedResponse, ResetResponse, RestoreResponse,
+    ReplicationAck, ReplicationStatusResponse, RolledBackBatch, RollbackResponse, RowsResponse, S3ExportResponse, SchemaResponse, SchemaSetResponse, SnapshotResponse, SnapshotsResponse,
+    SocketMessage, SourcesResponse, StatsResponse, Status, StatusResponse, TopResponse, ValueCountsResponse, VersionsResponse,
+};
+use crate::upload::{Payload, Upload};
+use crate::wal::Operation;
+use crate::ws::{Incoming, Socket, SocketError, NORMAL_CLOSURE};
+
+// The prefix of the API's current version, whose bodies only change by gaining fields (see `types`)
+pub const VERSION_PREFIX: &str = "/v1";
+
+// The routes of the API, answered from a collator's datasets. Can be nested into a larger application's router.
+pub fn router(collator: &Collator) -> Router {
+    let state = &collator.state;
+    // A sharding front-end has no datasets of its own, so it only answers what it can send on to its shards
+    let routes = if state.config.sharding.is_front_end() { front_end_routes() } else { routes() };
+    Router::new()
+        // Every route is served under `/v1`, and without a prefix for the clients that predate it
+        .nest(VERSION_PREFIX, routes.clone())
+        .merge(routes)
+        // Charge what each request ingests to its client, after turning it away if the client's quota is used up
+        // (when configured). Inside `audit`, so the audit file records the requests turned away.
+        .route_layer(middleware::from_fn_with_state(state.clone(), accounting::account))
+        // Record the requests that change a dataset in the audit file (when enabled). Inside `idempotency`, so a
+        // replayed response isn't a change of its own.
+        .route_layer(middleware::from_fn_with_state(state.clone(), audit::record_changes))
+        // Replay the response to a retried `/collate`, `/collate/batch`, `/collate_wide`, `/upsert`, or `/aggregate`
+        // (one with a known `Idempotency-Key`)
+        .route_layer(middleware::from_fn_with_state(state.clone(), idempotency::deduplicate))
+        // Check API keys (when configured) before any handler runs
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::require_key))
+        // Limit how fast each client can send requests (when configured), before spending any time on them
+        .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit::limit_rate))
+        // Count and time every request, including rejected ones (route_layer, so only requests that matched a route get a route label)
+        .route_layer(middleware::from_fn_with_state(state.clone(), track_requests))
+        // Compress what the other layers return (so idempotent replays are cached uncompressed)
+        .route_layer(middleware::from_fn(compression::compress_responses))
+        // Give every request an id and log a line once it's handled (layer, so unmatched routes and the responses of
+        // every layer above are logged too)
+        .layer(middleware::from_fn_with_state(state.clone(), logging::trace_requests))
+        // Add the app state to the router
+        .with_state(state.clone())
+}
+
+// A matched route without the version prefix, e.g. `/collate` for `/v1/collate`, so middleware can tell routes apart
+// whichever way they were reached
+pub(crate) fn unversioned(route: &str) -> &str {
+    match route.strip_prefix(VERSION_PREFIX) {
+        Some("") => "/",
+        Some(rest) if rest.starts_with('/') => rest,
+        _ => route,
+    }
+}
+
+// Every route of the API, without a version prefix
+fn routes() -> Router<Arc<AppState>> {
+    Router::new()
+        // `GET /` goes to `root`
+        .route("/", get(root))
+        // Probes: `GET /healthz` answers while the process is up, `GET /livez` while it can still make progress (the
+        // dataset registry isn't stuck, the writer is running), and `GET /readyz` while it can take traffic (flushes
+        // succeed, output directories take writes, sinks are reachable). The last two are `503` when a check fails.
+        .route("/healthz", get(healthz))
+        .route("/livez", get(livez))
+        .route("/readyz", get(readyz))
+        // `POST /collate` goes to `collate` (on the default dataset)
+        .route("/collate", post(collate))
+        // `POST /collate/batch` collates every payload of an NDJSON envelope into the default dataset, or none of them
+        .route("/collate/batch", post(collate_batch))
+        // `POST /write?db=...` collates InfluxDB line protocol into the dataset `db` names, for Telegraf and other
+        // agents that write to InfluxDB. `/api/v2/write?bucket=...` is the same, where InfluxDB 2.x clients expect it.
+        .route("/write", post(write))
+        .route("/api/v2/write", post(write))
+        // `POST /api/v1/write` collates the samples of Prometheus remote write requests into the default dataset
+        .route("/api/v1/write", post(remote_write))
+        // `POST /aggregate` goes to `aggregate` (on the default dataset)
+        .route("/aggregate", post(aggregate))
+        // `POST /upsert?keys=...` replaces the default dataset's rows that have the same keys as the payload's
+        .route("/upsert", post(upsert))
+        // `POST /collate_wide?key=...` joins the payload's columns onto the default dataset's rows with the same keys
+        .route("/collate_wide", post(collate_wide))
+        // `GET /data` reads the default dataset without modifying it, `DELETE /data?filter=...` deletes matching rows
+        .route("/data", get(data).delete(delete_data))
+        // `POST /reset` empties the default dataset
+        .route("/reset", post(reset))
+        // `POST /dedup` drops the default dataset's duplicate rows
+        .route("/dedup", post(dedup))
+        // `POST /fill_nulls` fills in the default dataset's missing values, `POST /drop_nulls` drops the rows with
+        // any, and `GET /nulls` counts them per column
+        .route("/fill_nulls", post(fill_nulls))
+        .route("/drop_nulls", post(drop_nulls))
+        .route("/nulls", get(nulls))
+        // `GET /outliers` returns the default dataset's outlying rows, `POST /outliers` flags every row in an
+        // `is_outlier` column
+        .route("/outliers", get(outliers).post(flag_outliers))
+        // `GET /describe` summarizes the default dataset's numeric columns
+        .route("/describe", get(describe))
+        // `GET /value_counts?column=...` counts the default dataset's most frequent values, `GET /top?by=...` finds
+        // its rows (or groups) with the highest values of a metric
+        .route("/value_counts", get(value_counts))
+        .route("/top", get(top))
+        // `GET /distinct?columns=...` counts the distinct values of the default dataset's columns (or combinations of
+        // them)
+        .route("/distinct", get(distinct))
+        // `POST /pivot` and `POST /melt` return the default dataset reshaped long to wide and back
+        .route("/pivot", post(pivot))
+        .route("/melt", post(melt))
+        // `POST /resample` returns the default dataset on a uniform time grid
+        .route("/resample", post(resample))
+        // `POST /rolling` returns the default dataset with rolling means (or other statistics) added
+        .route("/rolling", post(rolling))
+        // `GET /export` downloads the default dataset as a file
+        .route("/export", get(export))
+        // `POST /export/s3` copies the default dataset to the object store (when configured)
+        .route("/export/s3", post(export_s3))
+        // `GET /ws` opens a WebSocket that collates (or aggregates) every message into the default dataset, and sends
+        // back what changes in it
+        .route("/ws", get(socket))
+        // `GET /events` streams every change to the datasets (and every flush) as Server-Sent Events
+        .route("/events", get(events))
+        // `PUT /lookup/{name}` loads a reference table `/data` and `/aggregate` can join against (`?lookup=...`),
+        // `GET` returns it, and `DELETE` removes it. `GET /lookups` lists them.
+        .route("/lookup/{name}", get(get_lookup).put(put_lookup).delete(delete_lookup))
+        .route("/lookups", get(list_lookups))
+        // `GET /datasets` lists the named datasets
+        .route("/datasets", get(list_datasets))
+        // `GET /datasets/{name}` returns a dataset, `DELETE /datasets/{name}` drops it
+        .route("/datasets/{name}", get(get_dataset).delete(delete_dataset))
+        // `POST /datasets/{name}/collate` and `POST /datasets/{name}/aggregate` work on a named dataset
+        .route("/datasets/{name}/collate", post(collate_dataset))
+        .route("/datasets/{name}/collate/batch", post(collate_batch_dataset))
+        .route("/datasets/{name}/aggregate", post(aggregate_dataset))
+        .route("/datasets/{name}/upsert", post(upsert_dataset))
+        .route("/datasets/{name}/collate_wide", post(collate_wide_dataset))
+        .route("/datasets/{name}/api/v1/write", post(remote_write_dataset))
+        .route("/datasets/{name}/data", get(dataset_data).delete(delete_dataset_data))
+        .route("/datasets/{name}/describe", get(describe_named_dataset))
+        .route("/datasets/{name}/value_counts", get(dataset_value_counts))
+        .route("/datasets/{name}/top", get(dataset_top))
+        .route("/datasets/{name}/distinct", get(dataset_distinct))
+        .route("/datasets/{name}/pivot", post(dataset_pivot))
+        .route("/datasets/{name}/melt", post(dataset_melt))
+        .route("/datasets/{name}/resample", post(dataset_resample))
+        .route("/datasets/{name}/rolling", post(dataset_rolling))
+        .route("/datasets/{name}/reset", post(reset_named_dataset))
+        .route("/datasets/{name}/dedup", post(dedup_named_dataset))
+        .route("/datasets/{name}/fill_nulls", post(fill_nulls_named_dataset))
+        .route("/datasets/{name}/drop_nulls", post(drop_nulls_named_dataset))
+        .route("/datasets/{name}/nulls", get(dataset_nulls))
+        .route("/datasets/{name}/outliers", get(dataset_outliers).post(flag_dataset_outliers))
+        // `GET /quarantine` returns the rows that failed validation, `DELETE /quarantine` clears them
+        .route("/quarantine", get(quarantine).delete(clear_quarantine))
+        .route("/datasets/{name}/quarantine", get(dataset_quarantine).delete(clear_dataset_quarantine))
+        // `POST /snapshots` saves the default dataset's state, `GET /snapshots` lists what's been saved, and
+        // `POST /snapshots/{id}/restore` rolls back to one
+        .route("/snapshots", get(list_snapshots).post(create_snapshot))
+        .route("/snapshots/{id}/restore", post(restore_snapshot))
+        .route("/datasets/{name}/snapshots", get(list_dataset_snapshots).post(create_dataset_snapshot))
+        .route("/datasets/{name}/snapshots/{id}/restore", post(restore_dataset_snapshot))
+        // `GET /versions` lists the dataset's versions (rebuilt from the write-ahead log), and `GET /diff` compares two
+        .route("/versions", get(versions))
+        .route("/diff", get(diff))
+        .route("/datasets/{name}/versions", get(dataset_versions))
+        .route("/datasets/{name}/diff", get(dataset_diff))
+        .route("/datasets/{name}/export", get(dataset_export))
+        .route("/datasets/{name}/export/s3", post(dataset_export_s3))
+        .route("/datasets/{name}/ws", get(dataset_socket))
+        // `GET /schema` describes the dataset's columns, `PUT /schema` declares the columns and dtypes `/collate`
+        // accepts, and `DELETE /schema` removes that declaration
+        .route("/schema", get(get_schema).put(put_schema).delete(delete_schema))
+        .route(
+            "/datasets/{name}/schema",
+            get(get_dataset_schema).put(put_dataset_schema).delete(delete_dataset_schema),
+        )
+        // `GET /columns/computed` lists the columns worked out from others on ingest, `PUT /columns/computed` sets
+        // them, and `DELETE /columns/computed` removes them
+        .route("/columns/computed", get(get_computed).put(put_computed).delete(delete_computed))
+        .route(
+            "/datasets/{name}/columns/computed",
+            get(get_dataset_computed).put(put_dataset_computed).delete(delete_dataset_computed),
+        )
+        // `GET /expected` reports which of the reporters the dataset expects rows from haven't sent any yet, `PUT
+        // /expected` declares them, and `DELETE /expected` stops expecting them
+        .route("/expected", get(get_expected).put(put_expected).delete(delete_expected))
+        .route(
+            "/datasets/{name}/expected",
+            get(get_dataset_expected).put(put_dataset_expected).delete(delete_dataset_expected),
+        )
+        // `POST /await?expect=...` holds the request until the default dataset has collated that many batches (or has
+        // that many distinct values of `key`), then returns its rows
+        .route("/await", post(await_barrier))
+        .route("/datasets/{name}/await", post(dataset_await))
+        // `GET /flush` reports the background writer's status, `POST /flush` forces pending writes to disk
+        .route("/flush", get(flush_status).post(flush))
+        // `GET /metrics` reports counters and gauges in the Prometheus text format
+        .route("/metrics", get(metrics))
+        // `GET /sources` reports how much each client has sent (and how much of its quota it has used)
+        .route("/sources", get(sources))
+        // `GET /openapi.json` describes every route above, and `GET /docs` browses that description with Swagger UI
+        .route("/openapi.json", get(openapi_spec))
+        .route("/docs", get(docs))
+        // `GET /ui` is a dashboard of the datasets, over the routes above
+        .route("/ui", get(ui))
+        // `GET /audit` reads back the audit file: every request that changed a dataset, oldest first
+        .route("/audit", get(audit_entries))
+        // `GET /admin/stats` reports the datasets' memory use, the recent ingest rate, lock waits, and the last flush
+        .route("/admin/stats", get(admin_stats))
+        // `GET /admin/log_level` reports the filter log lines go through, `PUT /admin/log_level` changes it until the
+        // next restart (or `SIGHUP`)
+        .route("/admin/log_level", get(get_log_level).put(put_log_level))
+        // `POST /admin/rollback` undoes a dataset's latest ingests
+        .route("/admin/rollback", post(rollback))
+        // `GET /replication/status` reports how far behind the replicas are (or on a replica, how far behind its
+        // primary it is), `POST /replication/apply` takes a batch of a primary's changes, and `POST
+        // /replication/promote` makes a replica take changes from clients
+        .route("/replication/status", get(replication_status))
+        .route("/replication/apply", post(replication_apply))
+        .route("/replication/promote", post(replication_promote))
+}
+
+// The routes of a sharding front-end: `/collate` sends each row to the shard that owns its key, `/aggregate` each
+// group's rows (answering with every shard's groups), and `/data` reads from every shard. The probes check the shards
+// are reachable.
+fn front_end_routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/", get(root))
+        .route("/healthz", get(healthz))
+        .route("/livez", get(livez))
+        .route("/readyz", get(readyz))
+        .route("/collate", post(sharded_collate))
+        .route("/aggregate", post(sharded_aggregate))
+        .route("/data", get(sharded_data))
+        .route("/datasets/{name}/collate", post(sharded_collate_dataset))
+        .route("/datasets/{name}/aggregate", post(sharded_aggregate_dataset))
+        .route("/datasets/{name}/data", get(sharded_dataset_data))
+        .route("/metrics", get(metrics))
+        .route("/sources", get(sources))
+}
+
+// handler that collates a payload on a sharding front-end, each row into the shard that owns its key (in the default
+// dataset)
+#[axum_macros::debug_handler]
+async fn sharded_collate(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(params): Query<CollateParams>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    body: Payload,
+) -> Result<Response, AppError> {
+    sharded_collate_into(&state, DEFAULT_DATASET, params, query, headers, peer, body).await
+}
+
+// Same as `sharded_collate`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn sharded_collate_dataset(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    Query(params): Query<CollateParams>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    body: Payload,
+) -> Result<Response, AppError> {
+    validate_dataset_name(&name).map_err(AppError::BadRequest)?;
+
+    sharded_collate_into(&state, &name, params, query, headers, peer, body).await
+}
+
+// The response has the payload's rows (as the shards took them in, before validation and the like), rather than the
+// dataset's, which only the shards have
+async fn sharded_collate_into(
+    state: &AppState,
+    name: &str,
+    params: CollateParams,
+    query: Option<String>,
+    headers: HeaderMap,
+    peer: SocketAddr,
+    body: Payload,
+) -> Result<Response, AppError> {
+    // Checked here, so a bad one is turned away before any shard gets rows
+    requested_concat(&params, &headers, state.config.collate.concat)?;
+    let format = negotiate(&headers, ResponseFormat::Json)?;
+    let minimal = prefers_minimal(&headers);
+    let origin = Origin::of(state, &headers, peer);
+    let size = body.size();
+    let df = body.rows(state, &headers).await?;
+
+    let sharded = sharding::collate(state, name, query.as_deref(), &headers, &origin, df).await?;
+    state.metrics.record_ingest(name, "collate", sharded.ingested, size);
+
+    ingest_response(sharded.rows, format, minimal, sharded.wrote_to_file, sharded.counts)
+}
+
+// handler that aggregates a payload on a sharding front-end, each group's rows on the shard that owns the group (in
+// the default dataset)
+#[axum_macros::debug_handler]
+async fn sharded_aggregate(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(params): Query<AggregateParams>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    body: Upload,
+) -> Result<Response, AppError> {
+    sharded_aggregate_into(&state, DEFAULT_DATASET, params, query, headers, peer, body).await
+}
+
+// Same as `sharded_aggregate`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn sharded_aggregate_dataset(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    Query(params): Query<AggregateParams>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    body: Upload,
+) -> Result<Response, AppError> {
+    validate_dataset_name(&name).map_err(AppError::BadRequest)?;
+
+    sharded_aggregate_into(&state, &name, params, query, headers, peer, body).await
+}
+
+async fn sharded_aggregate_into(
+    state: &AppState,
+    name: &str,
+    params: AggregateParams,
+    query: Option<String>,
+    headers: HeaderMap,
+    peer: SocketAddr,
+    body: Upload,
+) -> Result<Response, AppError> {
+    let format = negotiate(&headers, ResponseFormat::Json)?;
+    let minimal = prefers_minimal(&headers);
+    let origin = Origin::of(state, &headers, peer);
+    let body = body.into_bytes().await?;
+    let size = body.len();
+
+    let sharded = sharding::aggregate(state, name, params, query.as_deref(), &headers, &origin, body).await?;
+    state.metrics.record_ingest(name, "aggregate", sharded.ingested, size);
+
+    ingest_response(sharded.rows, format, minimal, sharded.wrote_to_file, sharded.counts)
+}
+
+// handler that returns (part of) the default dataset on a sharding front-end, gathered from every shard
+#[axum_macros::debug_handler]
+async fn sharded_data(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DataParams>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    sharded_read(&state, DEFAULT_DATASET, params, &headers).await
+}
+
+// Same as `sharded_data`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn sharded_dataset_data(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(params): Query<DataParams>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    validate_dataset_name(&name).map_err(AppError::BadRequest)?;
+
+    sharded_read(&state, &name, params, &headers).await
+}
+
+async fn sharded_read(
+    state: &AppState,
+    name: &str,
+    params: DataParams,
+    headers: &HeaderMap,
+) -> Result<Response, AppError> {
+    let (format, options) = read_request(params, headers)?;
+    let (page, total_rows) = sharding::read(state, name, &options).await?;
+
+    data_response(page, total_rows, format, &options)
+}
+
+// Health check, essentially (the probes below tell more)
+async fn root() -> Json<StatusResponse> {
+    trace!("Root endpoint (GET /) called. Returning operational status.");
+    
+    Json(StatusResponse {
+        status: Status::Operational,
+    })
+}
+
+async fn healthz() -> Json<StatusResponse> {
+    Json(StatusResponse {
+        status: Status::Operational,
+    })
+}
+
+async fn livez(State(state): State<Arc<AppState>>) -> (StatusCode, Json<ProbeResponse>) {
+    probe_response(health::liveness(&state).await)
+}
+
+async fn readyz(State(state): State<Arc<AppState>>) -> (StatusCode, Json<ProbeResponse>) {
+    probe_response(health::readiness(&state).await)
+}
+
+// A probe's checks, `503` when any failed
+fn probe_response(probe: ProbeResponse) -> (StatusCode, Json<ProbeResponse>) {
+    if probe.status == Status::Operational {
+        return (StatusCode::OK, Json(probe));
+    }
+
+    let failed: Vec<&str> = probe.checks.iter().filter(|check| !check.ok).map(|check| check.message.as_str()).collect();
+    warn!("Probe failed: {}", failed.join("; "));
+    (StatusCode::SERVICE_UNAVAILABLE, Json(probe))
+}
+
+// The API's OpenAPI description
+async fn openapi_spec() -> Json<Value> {
+    Json(openapi::spec())
+}
+
+// Swagger UI, over `/openapi.json`
+async fn docs() -> Html<&'static str> {
+    Html(openapi::SWAGGER_UI)
+}
+
+// The operators' dashboard
+async fn ui() -> Html<&'static str> {
+    Html(dashboard::DASHBOARD)
+}
+
+// handler that accepts a POST request with a CSV payload and returns a JSON response
+#[axum_macros::debug_handler]
+async fn collate(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(params): Query<CollateParams>,
+    headers: HeaderMap,
+    body: Payload,
+) -> Result<Response, AppError> {
+    let origin = Origin::of(&state, &headers, peer);
+
+    collate_into(&state, DEFAULT_DATASET, params, Merge::Append, headers, origin, body).await
+}
+
+// Same as `collate`, but for a named dataset (created on first use)
+#[axum_macros::debug_handler]
+async fn collate_dataset(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    Query(params): Query<CollateParams>,
+    headers: HeaderMap,
+    body: Payload,
+) -> Result<Response, AppError> {
+    validate_dataset_name(&name).map_err(AppError::BadRequest)?;
+    let origin = Origin::of(&state, &headers, peer);
+
+    collate_into(&state, &name, params, Merge::Append, headers, origin, body).await
+}
+
+// handler that accepts a POST request with an NDJSON envelope of payloads, and collates all of them into the default
+// dataset or none of them
+#[axum_macros::debug_handler]
+async fn collate_batch(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(params): Query<CollateParams>,
+    headers: HeaderMap,
+    body: Upload,
+) -> Result<Response, AppError> {
+    let origin = Origin::of(&state, &headers, peer);
+
+    collate_batch_into(&state, DEFAULT_DATASET, params, headers, origin, body).await
+}
+
+// Same as `collate_batch`, but for a named dataset (created on first use)
+#[axum_macros::debug_handler]
+async fn collate_batch_dataset(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    Query(params): Query<CollateParams>,
+    headers: HeaderMap,
+    body: Upload,
+) -> Result<Response, AppError> {
+    validate_dataset_name(&name).map_err(AppError::BadRequest)?;
+    let origin = Origin::of(&state, &headers, peer);
+
+    collate_batch_into(&state, &name, params, headers, origin, body).await
+}
+
+// handler that accepts a POST request with a payload whose rows replace the default dataset's rows with the same keys
+#[axum_macros::debug_handler]
+async fn upsert(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(params): Query<UpsertParams>,
+    headers: HeaderMap,
+    body: Payload,
+) -> Result<Response, AppError> {
+    let keys = params.keys()?;
+    let origin = Origin::of(&state, &headers, peer);
+
+    collate_into(&state, DEFAULT_DATASET, params.collate, Merge::Upsert(keys), headers, origin, body).await
+}
+
+// Same as `upsert`, but for a named dataset (created on first use)
+#[axum_macros::debug_handler]
+async fn upsert_dataset(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    Query(params): Query<UpsertParams>,
+    headers: HeaderMap,
+    body: Payload,
+) -> Result<Response, AppError> {
+    validate_dataset_name(&name).map_err(AppError::BadRequest)?;
+    let keys = params.keys()?;
+    let origin = Origin::of(&state, &headers, peer);
+
+    collate_into(&state, &name, params.collate, Merge::Upsert(keys), headers, origin, body).await
+}
+
+// handler that accepts a POST request with a payload whose columns are joined onto the default dataset's rows with the
+// same keys
+#[axum_macros::debug_handler]
+async fn collate_wide(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(params): Query<UpsertParams>,
+    headers: HeaderMap,
+    body: Payload,
+) -> Result<Response, AppError> {
+    let keys = params.keys()?;
+    let origin = Origin::of(&state, &headers, peer);
+
+    collate_into(&state, DEFAULT_DATASET, params.collate, Merge::Wide(keys), headers, origin, body).await
+}
+
+// Same as `collate_wide`, but for a named dataset (created on first use)
+#[axum_macros::debug_handler]
+async fn collate_wide_dataset(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    Query(params): Query<UpsertParams>,
+    headers: HeaderMap,
+    body: Payload,
+) -> Result<Response, AppError> {
+    validate_dataset_name(&name).map_err(AppError::BadRequest)?;
+    let keys = params.keys()?;
+    let origin = Origin::of(&state, &headers, peer);
+
+    collate_into(&state, &name, params.collate, Merge::Wide(keys), headers, origin, body).await
+}
+
+// Query parameters of `/write` and `/api/v2/write`, as InfluxDB 1.x and 2.x take them
+#[derive(Debug, Deserialize)]
+struct WriteParams {
+    // The database (1.x) or bucket (2.x) written to, which names the dataset
+    db: Option<String>,
+    bucket: Option<String>,
+    // What the timestamps count in: `ns` (the default), `us`, `ms`, `s`, `m`, or `h`
+    precision: Option<String>,
+}
+
+// handler that accepts a POST request with InfluxDB line protocol, and collates its points into the dataset named by
+// `db` (or `bucket`), or the default dataset. Answers with a bare `204`, as InfluxDB does, since that's what its
+// clients check for.
+#[axum_macros::debug_handler]
+async fn write(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(params): Query<WriteParams>,
+    headers: HeaderMap,
+    body: Upload,
+) -> Result<axum::http::StatusCode, AppError> {
+    let name = params.db.or(params.bucket).filter(|name| !name.is_empty());
+    let name = name.as_deref().unwrap_or(DEFAULT_DATASET);
+    validate_dataset_name(name).map_err(AppError::BadRequest)?;
+    audit::note_dataset(name);
+    let precision: Precision =
+        params.precision.as_deref().map(str::parse).transpose().map_err(AppError::BadRequest)?.unwrap_or_default();
+    let origin = Origin::of(&state, &headers, peer);
+
+    let size = body.size();
+    let body = body.into_bytes().await?;
+    let body = std::str::from_utf8(&body).map_err(|_| AppError::BadRequest(String::from("Line protocol must be UTF-8")))?;
+    let now = origin.received_at.timestamp_nanos_opt().unwrap_or_default();
+    let df = line_protocol::parse(body, precision, now).map_err(AppError::BadRequest)?;
+    collate_points(&state, name, "write", &origin, df, size).await?;
+
+    Ok(axum::http::StatusCode::NO_CONTENT)
+}
+
+// handler that accepts a Prometheus remote write request, and collates its samples into the default dataset
+#[axum_macros::debug_handler]
+async fn remote_write(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Upload,
+) -> Result<axum::http::StatusCode, AppError> {
+    let origin = Origin::of(&state, &headers, peer);
+
+    remote_write_into(&state, DEFAULT_DATASET, &headers, origin, body).await
+}
+
+// Same as `remote_write`, but for a named dataset (created on first use)
+#[axum_macros::debug_handler]
+async fn remote_write_dataset(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    body: Upload,
+) -> Result<axum::http::StatusCode, AppError> {
+    validate_dataset_name(&name).map_err(AppError::BadRequest)?;
+    let origin = Origin::of(&state, &headers, peer);
+
+    remote_write_into(&state, &name, &headers, origin, body).await
+}
+
+// The body has already been decompressed (`Content-Encoding: snappy`) by the time it gets here. Answers with a bare
+// `204`, as Prometheus expects.
+async fn remote_write_into(
+    state: &AppState,
+    name: &str,
+    headers: &HeaderMap,
+    origin: Origin,
+    body: Upload,
+) -> Result<axum::http::StatusCode, AppError> {
+    // Remote write 2.0 says so in the content type; anything else is 1.0
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or_default();
+    if content_type.contains("io.prometheus.write.v2") {
+        return Err(AppError::UnsupportedMediaType(String::from(
+            "Only remote write 1.0 is supported (set `protobuf_message: prometheus.WriteRequest`)",
+        )));
+    }
+
+    let size = body.size();
+    let body = body.into_bytes().await?;
+    let df = remote_write::parse(&body).map_err(|e| AppError::BadRequest(format!("Invalid remote write request: {}", e)))?;
+    if let Some(df) = df {
+        collate_points(state, name, "remote_write", &origin, df, size).await?;
+    }
+
+    Ok(axum::http::StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+struct CollateParams {
+    // `strict` or `union` (takes precedence over the `X-Concat-Mode` header)
+    concat: Option<String>,
+}
+
+// Query parameters of `/upsert` and `/collate_wide`
+#[derive(Debug, Deserialize)]
+struct UpsertParams {
+    // Comma-separated key columns, e.g. `run_id` or `run_id,host`
+    #[serde(alias = "key")]
+    keys: Option<String>,
+    #[serde(flatten)]
+    collate: CollateParams,
+}
+
+impl UpsertParams {
+    fn keys(&self) -> Result<Vec<String>, AppError> {
+        let keys: Vec<String> = self
+            .keys
+            .as_deref()
+            .unwrap_or("")
+            .split(',')
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(String::from)
+            .collect();
+        if keys.is_empty() {
+            return Err(AppError::BadRequest(String::from(
+                "Pass the columns that identify a row as ?keys=... (e.g. ?keys=run_id)",
+            )));
+        }
+
+        Ok(keys)
+    }
+}
+
+// Pick the concat mode from the query string, then the `X-Concat-Mode` header, then the configured default
+fn requested_concat(params: &CollateParams, headers: &HeaderMap, default: ConcatMode) -> Result<ConcatMode, AppError> {
+    let requested = match &params.concat {
+        Some(concat) => Some(concat.as_str()),
+        None => headers
+            .get("x-concat-mode")
+            .map(|value| value.to_str())
+            .transpose()
+            .map_err(|_| AppError::BadRequest(String::from("The X-Concat-Mode header must be valid ASCII")))?,
+    };
+
+    match requested {
+        Some(concat) => concat.parse().map_err(AppError::BadRequest),
+        None => Ok(default),
+    }
+}
+
+// Concatenate a payload onto a dataset, upsert it (replacing the rows with the same keys), or join its columns onto
+// the rows with the same keys
+async fn collate_into(
+    state: &AppState,
+    name: &str,
+    params: CollateParams,
+    merge: Merge,
+    headers: HeaderMap,
+    origin: Origin,
+    body: Payload,
+) -> Result<Response, AppError> {
+    let concat = requested_concat(&params, &headers, state.config.collate.concat)?;
+    let format = negotiate(&headers, ResponseFormat::Json)?;
+    let minimal = prefers_minimal(&headers);
+    let size = body.size();
+    let df = body.rows(state, &headers).await?;
+
+    let dataset = state.dataset(name).await;
+    let mut counts = IngestCounts::default();
+    // Acquire a lock on the dataset within a scope
+    let (result, wrote_to_file, rows) = {
+        let mut dataset = locked(state, name, &dataset).await;
+        let payload = prepared(state, name, &dataset, &origin, df)?;
+        ingest(state, name, &mut dataset, payload, merge, concat, &mut counts).await?
+    };
+    let endpoint = match (counts.replaced, counts.joined) {
+        (Some(_), _) => "upsert",
+        (_, Some(_)) => "collate_wide",
+        _ => "collate",
+    };
+    state.metrics.record_ingest(name, endpoint, rows, size);
+
+    ingest_response(result, format, minimal, wrote_to_file, counts)
+}
+
+// Collate every payload of a batch into a dataset as one ingest. Each payload is parsed and prepared on its own (with
+// its own batch number), and if any of them fails, the response says which and nothing is changed.
+async fn collate_batch_into(
+    state: &AppState,
+    name: &str,
+    params: CollateParams,
+    headers: HeaderMap,
+    origin: Origin,
+    body: Upload,
+) -> Result<Response, AppError> {
+    let concat = requested_concat(&params, &headers, state.config.collate.concat)?;
+    let size = body.size();
+    let body = body.into_bytes().await?;
+    // Parsing doesn't need the lock
+    let (entries, parsed) = state
+        .compute
+        .run(move || {
+            tracing::info_span!("parse").in_scope(|| {
+                let entries = batch::parse_envelope(&body)?;
+                let parsed: Vec<Result<DataFrame, AppError>> =
+                    entries.iter().map(|entry| entry.read().map_err(AppError::BadRequest)).collect();
+                Ok((entries, parsed))
+            })
+        })
+        .await?
+        .map_err(AppError::BadRequest)?;
+
+    let dataset = state.dataset(name).await;
+    let mut counts = IngestCounts::default();
+    let (wrote_to_file, rows, results) = {
+        let mut dataset = locked(state, name, &dataset).await;
+
+        // The payloads are put together the way `/collate` would add them to the dataset one after another. Starting
+        // from the dataset's columns (but none of its rows) checks each payload against them as well.
+        let mut combined = Dataset {
+            df: dataset.df.as_ref().map(DataFrame::clear),
+            ..Default::default()
+        };
+        let mut quarantine = Dataset::default();
+        let mut results = Vec::with_capacity(entries.len());
+        let mut failed = None;
+        for (index, (entry, df)) in entries.into_iter().zip(parsed).enumerate() {
+            let mut result = BatchResult {
+                index,
+                id: entry.id,
+                status: Status::Skipped,
+                rows: None,
+                quarantined: None,
+                error: None,
+            };
+            if failed.is_some() {
+                results.push(result);
+                continue;
+            }
+
+            let prepared = df.and_then(|df| prepared(state, name, &dataset, &origin, df)).and_then(|(df, rejected)| {
+                let new = combined.collated(&df, concat).map_err(|e| {
+                    AppError::SchemaMismatch(format!("The payload doesn't match the dataset or the payloads before it: {}", e))
+                })?;
+                let quarantined = rejected.as_ref().map_or(0, DataFrame::height);
+                let rejected = rejected.map(|rejected| quarantine.quarantined(&rejected)).transpose().map_err(|e| {
+                    AppError::Internal(format!("Can't quarantine the rejected rows: {}", e))
+                })?;
+                Ok((df.height(), quarantined, new, rejected))
+            });
+            match prepared {
+                Ok((rows, quarantined, new, rejected)) => {
+                    result.status = Status::Success;
+                    result.rows = Some(rows);
+                    result.quarantined = state.config.validation.contains_key(name).then_some(quarantined);
+                    combined.df = Some(new);
+                    if rejected.is_some() {
+                        quarantine.quarantine = rejected;
+                    }
+                }
+                Err(e) => {
+                    result.status = Status::Error;
+                    result.error = Some(e.message().to_string());
+                    failed = Some(e);
+                }
+            }
+            results.push(result);
+        }
+        if let Some(e) = failed {
+            return Ok(batch_error(e, results));
+        }
+
+        // Everything prepared, so the batch goes in as if it were a single payload
+        let payload = (combined.df.unwrap_or_default(), quarantine.quarantine);
+        let (_, wrote_to_file, rows) =
+            ingest(state, name, &mut dataset, payload, Merge::Append, concat, &mut counts).await?;
+        (wrote_to_file, rows, results)
+    };
+    state.metrics.record_ingest(name, "collate_batch", rows, size);
+
+    Ok(Json(BatchResponse {
+        status: Status::Success,
+        wrote_to_file: Some(wrote_to_file),
+        rows: Some(rows),
+        error: None,
+        message: None,
+        batches: results,
+        counts,
+    })
+    .into_response())
+}
+
+// The response to a batch with a payload that failed: the failed payload's error, with every payload's result
+fn batch_error(error: AppError, results: Vec<BatchResult>) -> Response {
+    let body = BatchResponse {
+        status: Status::Error,
+        wrote_to_file: None,
+        rows: None,
+        error: Some(error.kind().to_string()),
+        message: Some(format!("Nothing was collated, because a payload of the batch failed: {}", error.message())),
+        batches: results,
+        counts: IngestCounts::default(),
+    };
+    (error.status(), Json(body)).into_response()
+}
+
+// handler that accepts a POST request with a CSV payload, updates the state according to keys, and returns the updated DataFrame as a CSV string
+#[axum_macros::debug_handler]
+async fn aggregate(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<AggregateParams>,
+    headers: HeaderMap,
+    body: Upload,
+) -> Result<Response, AppError> {
+    aggregate_into(&state, DEFAULT_DATASET, params, headers, body).await
+}
+
+// Same as `aggregate`, but for a named dataset (created on first use)
+#[axum_macros::debug_handler]
+async fn aggregate_dataset(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(params): Query<AggregateParams>,
+    headers: HeaderMap,
+    body: Upload,
+) -> Result<Response, AppError> {
+    validate_dataset_name(&name).map_err(AppError::BadRequest)?;
+
+    aggregate_into(&state, &name, params, headers, body).await
+}
+
+// Aggregate a payload into a dataset
+async fn aggregate_into(
+    state: &AppState,
+    name: &str,
+    params: AggregateParams,
+    headers: HeaderMap,
+    body: Upload,
+) -> Result<Response, AppError> {
+    let format = negotiate(&headers, ResponseFormat::Json)?;
+    let minimal = prefers_minimal(&headers);
+    let mut counts = IngestCounts::default();
+
+    // Aggregate bodies can be JSON with the CSV embedded in them, so they're always parsed from memory
+    let body = body.into_bytes().await?;
+    let size = body.len();
+    let lookup = requested_lookup(state, params.lookup.as_deref()).await?;
+    let (result, wrote_to_file, rows) =
+        aggregate_payload(state, name, params, headers, body, lookup.as_deref(), &mut counts).await?;
+    state.metrics.record_ingest(name, "aggregate", rows, size);
+
+    // Only the response is enriched, not the dataset
+    let result = match &lookup {
+        Some(lookup) => lookup.enrich(&result).map_err(AppError::Internal)?,
+        None => result,
+    };
+    ingest_response(result, format, minimal, wrote_to_file, counts)
+}
+
+// Aggregate a payload (an `/aggregate` body) into a dataset. Returns the dataset's new state, where it's being
+// persisted, and how many of the payload's rows were aggregated.
+async fn aggregate_payload(
+    state: &AppState,
+    name: &str,
+    params: AggregateParams,
+    headers: HeaderMap,
+    body: Bytes,
+    lookup: Option<&LookupTable>,
+    counts: &mut IngestCounts,
+) -> Result<(DataFrame, String, usize), AppError> {
+    let filter = params.filter.clone();
+    let op = state.config.aggregate.op;
+    let (df, spec) = state
+        .compute
+        .run(move || {
+            let body = std::str::from_utf8(&body)
+                .map_err(|e| AppError::BadRequest(format!("The request body is not valid UTF-8: {}", e)))?;
+            tracing::info_span!("parse").in_scope(|| parse_aggregate_body(&params, &headers, body, op))
+        })
+        .await??;
+
+    aggregate_rows(state, name, df, spec, filter.as_deref(), lookup, counts).await
+}
+
+#[derive(Debug, Deserialize)]
+struct DataParams {
+    // Comma-separated list of columns to return (defaults to all of them)
+    columns: Option<String>,
+    // Index of the first row to return
+    #[serde(default)]
+    offset: usize,
+    // Maximum number of rows to return (defaults to all remaining rows)
+    limit: Option<usize>,
+    // Only return rows matching this filter, e.g. `latency_ms>100,status=ok` (see `filter::Filter`)
+    filter: Option<String>,
+    // Comma-separated columns to sort by before `offset` and `limit` apply, each optionally followed by `:asc` (the
+    // default) or `:desc`, e.g. `latency_ms:desc,host`
+    sort: Option<String>,
+    // Left-join the rows against this lookup table after filtering, so its columns can be sorted on and selected
+    lookup: Option<String>,
+    // `csv`, `json`, `ndjson`, or `arrow` (defaults to the `Accept` header, then CSV)
+    format: Option<String>,
+    // Return the rows the dataset had at this time (RFC 3339), e.g. `2024-06-01T12:00:00Z`
+    as_of: Option<String>,
+}
+
+// handler that returns (part of) the default dataset
+#[axum_macros::debug_handler]
+async fn data(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DataParams>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    read_from(&state, DEFAULT_DATASET, state.dataset(DEFAULT_DATASET).await, params, &headers).await
+}
+
+// Same as `data`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn dataset_data(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(params): Query<DataParams>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let dataset = state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+
+    read_from(&state, &name, dataset, params, &headers).await
+}
+
+// Return a window of a dataset as CSV, JSON records, NDJSON, or Arrow
+async fn read_from(
+    state: &AppState,
+    name: &str,
+    dataset: SharedDataset,
+    params: DataParams,
+    headers: &HeaderMap,
+) -> Result<Response, AppError> {
+    let (format, options) = read_request(params, headers)?;
+    let (page, total_rows) = read_window(state, name, dataset, &options).await?;
+
+    data_response(page, total_rows, format, &options)
+}
+
+// The format a `/data` request wants (the `format` parameter wins over `Accept`), and which rows
+fn read_request(params: DataParams, headers: &HeaderMap) -> Result<(ResponseFormat, ReadOptions), AppError> {
+    let format = match params.format.as_deref() {
+        Some(format) => format.parse().map_err(AppError::BadRequest)?,
+        None => negotiate(headers, ResponseFormat::Csv)?,
+    };
+    let options = ReadOptions {
+        columns: params.columns.as_deref().map(|columns| {
+            columns.split(',').map(str::trim).filter(|c| !c.is_empty()).map(String::from).collect()
+        }),
+        filter: params.filter,
+        sort: params.sort,
+        lookup: params.lookup,
+        offset: params.offset,
+        limit: params.limit,
+        as_of: params.as_of,
+    };
+    Ok((format, options))
+}
+
+fn data_response(
+    page: DataFrame,
+    total_rows: usize,
+    format: ResponseFormat,
+    options: &ReadOptions,
+) -> Result<Response, AppError> {
+    if format == ResponseFormat::Json {
+        return Ok(Json(DataResponse {
+            status: Status::Success,
+            total_rows,
+            offset: options.offset,
+            rows: df_to_json_records(&page),
+        })
+        .into_response());
+    }
+
+    df_response(page, format, vec![(HeaderName::from_static("x-total-rows"), HeaderValue::from(total_rows))])
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportParams {
+    // `csv` (the default), `arrow`, `feather`, or `parquet`
+    format: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DescribeParams {
+    // Comma-separated quantiles to report, e.g. `0.1,0.5,0.9` (`describe.quantiles` if not set)
+    quantiles: Option<String>,
+}
+
+// handler that returns summary statistics of the default dataset's numeric columns
+#[axum_macros::debug_handler]
+async fn describe(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DescribeParams>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    describe_dataset(&state, state.dataset(DEFAULT_DATASET).await, params, &headers).await
+}
+
+// Same as `describe`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn describe_named_dataset(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(params): Query<DescribeParams>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let dataset = state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+
+    describe_dataset(&state, dataset, params, &headers).await
+}
+
+// Every row of a dataset, taken (cheaply) under its read lock, and then with any spilled rows read back from disk
+async fn all_rows(dataset: &SharedDataset) -> Result<DataFrame, AppError> {
+    let rows = dataset.read().await.rows();
+    rows.collect().map_err(AppError::Internal)
+}
+
+// Compute something from every row of a dataset on the compute pool. Errors from `job` are the request's.
+async fn computed_from<T, F>(state: &AppState, dataset: &SharedDataset, job: F) -> Result<T, AppError>
+where
+    T: Send + 'static,
+    F: FnOnce(&DataFrame) -> Result<T, String> + Send + 'static,
+{
+    let rows = dataset.read().await.rows();
+    state
+        .compute
+        .run(move || job(&rows.collect().map_err(AppError::Internal)?).map_err(AppError::BadRequest))
+        .await?
+}
+
+// By default a JSON object of statistics per column; in the other formats, the description as a table with a row per
+// statistic
+async fn describe_dataset(
+    state: &AppState,
+    dataset: SharedDataset,
+    params: DescribeParams,
+    headers: &HeaderMap,
+) -> Result<Response, AppError> {
+    let format = negotiate(headers, ResponseFormat::Json)?;
+    let quantiles = match &params.quantiles {
+        Some(quantiles) => describe::parse_quantiles(quantiles).map_err(AppError::BadRequest)?,
+        None => state.config.describe.quantiles.clone(),
+    };
+
+    // Cheap to clone, so the statistics are computed after the lock is released (on the compute pool)
+    let rows = dataset.read().await.rows();
+    let height = rows.height();
+    let plan = rows.lazy().map_err(AppError::Internal)?;
+    let description = state
+        .compute
+        .run(move || {
+            let description = describe::describe(plan, &quantiles);
+            drop(rows);
+            description
+        })
+        .await?
+        .map_err(|e| AppError::Internal(format!("Error describing the dataset: {}", e)))?;
+
+    if format != ResponseFormat::Json {
+        return df_response(description, format, Vec::new());
+    }
+
+    let statistics = description.column(describe::STATISTIC_COLUMN).and_then(|column| column.str()).map_err(|e| {
+        AppError::Internal(format!("Error describing the dataset: {}", e))
+    })?;
+    let mut columns = IndexMap::new();
+    for column in description.get_columns().iter().skip(1) {
+        let values = column.f64().map_err(|e| AppError::Internal(format!("Error describing the dataset: {}", e)))?;
+        let summary = statistics.into_no_null_iter().map(String::from).zip(values).collect();
+        columns.insert(column.name().to_string(), summary);
+    }
+
+    Ok(Json(DescribeResponse {
+        status: Status::Success,
+        rows: height,
+        columns,
+    })
+    .into_response())
+}
+
+// Number of values (or rows) `/value_counts` and `/top` return when the request doesn't say
+const DEFAULT_K: usize = 10;
+
+#[derive(Debug, Deserialize)]
+struct ValueCountsParams {
+    column: Option<String>,
+    // How many values to return, most frequent first
+    k: Option<usize>,
+}
+
+// handler that returns the most frequent values of one of the default dataset's columns
+#[axum_macros::debug_handler]
+async fn value_counts(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<ValueCountsParams>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    value_counts_of(&state, state.dataset(DEFAULT_DATASET).await, params, &headers).await
+}
+
+// Same as `value_counts`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn dataset_value_counts(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(params): Query<ValueCountsParams>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let dataset = state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+
+    value_counts_of(&state, dataset, params, &headers).await
+}
+
+async fn value_counts_of(
+    state: &AppState,
+    dataset: SharedDataset,
+    params: ValueCountsParams,
+    headers: &HeaderMap,
+) -> Result<Response, AppError> {
+    let format = negotiate(headers, ResponseFormat::Json)?;
+    let column = params
+        .column
+        .ok_or_else(|| AppError::BadRequest(String::from("A `column` parameter is required")))?;
+
+    let rows = dataset.read().await.rows();
+    let plan = rows.lazy().map_err(AppError::Internal)?;
+    let (counted, k) = (column.clone(), params.k.unwrap_or(DEFAULT_K));
+    let counts = state
+        .compute
+        .run(move || {
+            let counts = rank::value_counts(plan, &counted, k);
+            drop(rows);
+            counts
+        })
+        .await?
+        .map_err(AppError::BadRequest)?;
+
+    if format != ResponseFormat::Json {
+        return df_response(counts, format, Vec::new());
+    }
+
+    Ok(Json(ValueCountsResponse {
+        status: Status::Success,
+        column,
+        values: df_to_json_records(&counts),
+    })
+    .into_response())
+}
+
+#[derive(Debug, Deserialize)]
+struct TopParams {
+    // The metric column to rank by
+    by: Option<String>,
+    k: Option<usize>,
+    // Rank the values of this column instead of single rows, by their `by` values reduced with `op`
+    group: Option<String>,
+    // `sum` (the default), `mean`, `min`, `max`, `count`, `median`, `std`, `p90`, `p95`, `p99`, or `distinct`
+    op: Option<String>,
+    // `desc` (the default, highest first) or `asc`
+    order: Option<String>,
+}
+
+// handler that returns the default dataset's rows (or groups) with the highest values of a metric
+#[axum_macros::debug_handler]
+async fn top(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<TopParams>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    top_of(&state, state.dataset(DEFAULT_DATASET).await, params, &headers).await
+}
+
+// Same as `top`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn dataset_top(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(params): Query<TopParams>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let dataset = state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+
+    top_of(&state, dataset, params, &headers).await
+}
+
+async fn top_of(
+    state: &AppState,
+    dataset: SharedDataset,
+    params: TopParams,
+    headers: &HeaderMap,
+) -> Result<Response, AppError> {
+    let format = negotiate(headers, ResponseFormat::Json)?;
+    let by = params
+        .by
+        .ok_or_else(|| AppError::BadRequest(String::from("A `by` parameter naming the metric column is required")))?;
+    let descending = match params.order.as_deref().map(str::to_ascii_lowercase).as_deref() {
+        None | Some("desc") => true,
+        Some("asc") => false,
+        Some(order) => return Err(AppError::BadRequest(format!("Unsupported order {:?} (expected desc or asc)", order))),
+    };
+    let k = params.k.unwrap_or(DEFAULT_K);
+    let operation: Option<AggregateOperation> = match (&params.group, &params.op) {
+        (Some(_), Some(op)) => Some(op.parse().map_err(AppError::BadRequest)?),
+        (Some(_), None) => Some(AggregateOperation::Sum),
+        (None, Some(_)) => return Err(AppError::BadRequest(String::from("`op` only applies with a `group` column"))),
+        (None, None) => None,
+    };
+
+    let rows = dataset.read().await.rows();
+    let df = rows.lazy().map_err(AppError::Internal)?;
+    let (group, ranked_by) = (params.group, by.clone());
+    let top = state
+        .compute
+        .run(move || {
+            let top = match (&group, operation) {
+                (Some(group), Some(operation)) => rank::top_groups(df, group, &ranked_by, operation, k, descending),
+                _ => rank::top_rows(df, &ranked_by, k, descending),
+            };
+            drop(rows);
+            top
+        })
+        .await?
+        .map_err(AppError::BadRequest)?;
+
+    if format != ResponseFormat::Json {
+        return df_response(top, format, Vec::new());
+    }
+
+    Ok(Json(TopResponse {
+        status: Status::Success,
+        by,
+        rows: df_to_json_records(&top),
+    })
+    .into_response())
+}
+
+// Number of values `/distinct` returns per column when the request doesn't say
+const DEFAULT_DISTINCT_VALUES: usize = 1000;
+
+#[derive(Debug, Deserialize)]
+struct DistinctParams {
+    // Comma-separated columns to count the distinct values of
+    columns: Option<String>,
+    // Count the combinations of the columns' values, rather than each column's values
+    #[serde(default)]
+    combined: bool,
+    // Also return the distinct values, in order
+    #[serde(default)]
+    values: bool,
+    // How many values to return per column
+    limit: Option<usize>,
+}
+
+// handler that counts the distinct values of the default dataset's columns
+#[axum_macros::debug_handler]
+async fn distinct(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DistinctParams>,
+) -> Result<Json<DistinctResponse>, AppError> {
+    distinct_of(&state, state.dataset(DEFAULT_DATASET).await, params).await
+}
+
+// Same as `distinct`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn dataset_distinct(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(params): Query<DistinctParams>,
+) -> Result<Json<DistinctResponse>, AppError> {
+    let dataset = state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+
+    distinct_of(&state, dataset, params).await
+}
+
+async fn distinct_of(
+    state: &AppState,
+    dataset: SharedDataset,
+    params: DistinctParams,
+) -> Result<Json<DistinctResponse>, AppError> {
+    let mut columns: Vec<String> = Vec::new();
+    for column in params.columns.iter().flat_map(|columns| columns.split(',')).map(str::trim) {
+        if !column.is_empty() && !columns.iter().any(|listed| listed == column) {
+            columns.push(column.to_string());
+        }
+    }
+    if columns.is_empty() {
+        return Err(AppError::BadRequest(String::from("A `columns` parameter is required")));
+    }
+    // Either one entry per column, or a single one for all of them
+    let counted: Vec<Vec<String>> = match params.combined {
+        true => vec![columns],
+        false => columns.into_iter().map(|column| vec![column]).collect(),
+    };
+    let values = params.values.then(|| params.limit.unwrap_or(DEFAULT_DISTINCT_VALUES));
+
+    let rows = dataset.read().await.rows();
+    let height = rows.height();
+    let plan = rows.lazy().map_err(AppError::Internal)?;
+    let distinct = state
+        .compute
+        .run(move || {
+            let distinct = counted
+                .into_iter()
+                .map(|columns| {
+                    let (count, values) = rank::distinct(plan.clone(), &columns, values)?;
+                    Ok(DistinctCount {
+                        columns,
+                        count,
+                        values: values.as_ref().map(df_to_json_records),
+                    })
+                })
+                .collect::<Result<Vec<_>, String>>();
+            drop(rows);
+            distinct
+        })
+        .await?
+        .map_err(AppError::BadRequest)?;
+
+    Ok(Json(DistinctResponse {
+        status: Status::Success,
+        rows: height,
+        distinct,
+    }))
+}
+
+// handler that pivots the default dataset long to wide (without modifying it)
+#[axum_macros::debug_handler]
+async fn pivot(State(state): State<Arc<AppState>>, headers: HeaderMap, body: Upload) -> Result<Response, AppError> {
+    let body = body.into_bytes().await?;
+    pivot_of(&state, state.dataset(DEFAULT_DATASET).await, &headers, &body).await
+}
+
+// Same as `pivot`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn dataset_pivot(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    body: Upload,
+) -> Result<Response, AppError> {
+    let dataset = state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+    let body = body.into_bytes().await?;
+
+    pivot_of(&state, dataset, &headers, &body).await
+}
+
+async fn pivot_of(
+    state: &AppState,
+    dataset: SharedDataset,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<Response, AppError> {
+    let format = negotiate(headers, ResponseFormat::Json)?;
+    let spec: PivotSpec =
+        serde_json::from_slice(body).map_err(|e| AppError::BadRequest(format!("Invalid pivot: {}", e)))?;
+
+    let pivoted = computed_from(state, &dataset, move |df| reshape::pivot(df, &spec)).await?;
+
+    reshaped_response(pivoted, format)
+}
+
+// handler that melts the default dataset wide to long (without modifying it)
+#[axum_macros::debug_handler]
+async fn melt(State(state): State<Arc<AppState>>, headers: HeaderMap, body: Upload) -> Result<Response, AppError> {
+    let body = body.into_bytes().await?;
+    melt_of(&state, state.dataset(DEFAULT_DATASET).await, &headers, &body).await
+}
+
+// Same as `melt`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn dataset_melt(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    body: Upload,
+) -> Result<Response, AppError> {
+    let dataset = state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+    let body = body.into_bytes().await?;
+
+    melt_of(&state, dataset, &headers, &body).await
+}
+
+async fn melt_of(
+    state: &AppState,
+    dataset: SharedDataset,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<Response, AppError> {
+    let format = negotiate(headers, ResponseFormat::Json)?;
+    let spec: MeltSpec = serde_json::from_slice(body).map_err(|e| AppError::BadRequest(format!("Invalid melt: {}", e)))?;
+
+    let melted = computed_from(state, &dataset, move |df| reshape::melt(df, &spec)).await?;
+
+    reshaped_response(melted, format)
+}
+
+// handler that puts the default dataset on a uniform time grid (without modifying it)
+#[axum_macros::debug_handler]
+async fn resample(State(state): State<Arc<AppState>>, headers: HeaderMap, body: Upload) -> Result<Response, AppError> {
+    let body = body.into_bytes().await?;
+    resample_of(&state, state.dataset(DEFAULT_DATASET).await, &headers, &body).await
+}
+
+// Same as `resample`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn dataset_resample(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    body: Upload,
+) -> Result<Response, AppError> {
+    let dataset = state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+    let body = body.into_bytes().await?;
+
+    resample_of(&state, dataset, &headers, &body).await
+}
+
+async fn resample_of(
+    state: &AppState,
+    dataset: SharedDataset,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<Response, AppError> {
+    let format = negotiate(headers, ResponseFormat::Json)?;
+    let spec: ResampleSpec =
+        serde_json::from_slice(body).map_err(|e| AppError::BadRequest(format!("Invalid resample: {}", e)))?;
+
+    let resampled = computed_from(state, &dataset, move |df| resample::resample(df, &spec)).await?;
+
+    reshaped_response(resampled, format)
+}
+
+// handler that returns the default dataset with rolling statistics added (without modifying it)
+#[axum_macros::debug_handler]
+async fn rolling(State(state): State<Arc<AppState>>, headers: HeaderMap, body: Upload) -> Result<Response, AppError> {
+    let body = body.into_bytes().await?;
+    rolling_of(&state, state.dataset(DEFAULT_DATASET).await, &headers, &body).await
+}
+
+// Same as `rolling`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn dataset_rolling(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    body: Upload,
+) -> Result<Response, AppError> {
+    let dataset = state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+    let body = body.into_bytes().await?;
+
+    rolling_of(&state, dataset, &headers, &body).await
+}
+
+async fn rolling_of(
+    state: &AppState,
+    dataset: SharedDataset,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<Response, AppError> {
+    let format = negotiate(headers, ResponseFormat::Json)?;
+    let spec: RollingSpec =
+        serde_json::from_slice(body).map_err(|e| AppError::BadRequest(format!("Invalid rolling window: {}", e)))?;
+
+    let rolled = computed_from(state, &dataset, move |df| rolling::rolling(df, &spec)).await?;
+
+    reshaped_response(rolled, format)
+}
+
+fn reshaped_response(df: DataFrame, format: ResponseFormat) -> Result<Response, AppError> {
+    if format != ResponseFormat::Json {
+        return df_response(df, format, Vec::new());
+    }
+
+    Ok(Json(RowsResponse {
+        status: Status::Success,
+        columns: df.get_column_names_str().into_iter().map(String::from).collect(),
+        rows: df_to_json_records(&df),
+    })
+    .into_response())
+}
+
+// handler that downloads the whole default dataset as a file
+#[axum_macros::debug_handler]
+async fn export(State(state): State<Arc<AppState>>, Query(params): Query<ExportParams>) -> Result<Response, AppError> {
+    export_from(state.dataset(DEFAULT_DATASET).await, DEFAULT_DATASET, params).await
+}
+
+// Same as `export`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn dataset_export(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(params): Query<ExportParams>,
+) -> Result<Response, AppError> {
+    let dataset = state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+
+    export_from(dataset, &name, params).await
+}
+
+// Serialize an entire dataset as a downloadable file
+async fn export_from(dataset: SharedDataset, name: &str, params: ExportParams) -> Result<Response, AppError> {
+    let format = params
+        .format
+        .as_deref()
+        .map(str::parse)
+        .unwrap_or(Ok(FileFormat::Csv))
+        .map_err(AppError::BadRequest)?;
+
+    let mut df = all_rows(&dataset).await?;
+
+    // CSV is streamed in chunks, so large datasets don't have to be serialized in memory all at once
+    let body = match format {
+        FileFormat::Csv => csv_body(df),
+        _ => match write_df(&mut df, format) {
+            Ok(body) => Body::from(body),
+            // Formats this build can't write are the client's choice, not a server failure
+            Err(message) if format == FileFormat::Parquet => return Err(AppError::BadRequest(message)),
+            Err(message) => return Err(AppError::Internal(format!("Error exporting DataFrame: {}", message))),
+        },
+    };
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, String::from(format.content_type())),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.{}\"", name, format.extension())),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+// handler that copies the default dataset to the object store
+#[axum_macros::debug_handler]
+async fn export_s3(State(state): State<Arc<AppState>>) -> Result<Json<S3ExportResponse>, AppError> {
+    export_to_s3(&state, DEFAULT_DATASET, state.dataset(DEFAULT_DATASET).await).await
+}
+
+// Same as `export_s3`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn dataset_export_s3(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<S3ExportResponse>, AppError> {
+    let dataset = state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+
+    export_to_s3(&state, &name, dataset).await
+}
+
+// Upload a dataset's rows to the object store, as the schedule would
+async fn export_to_s3(
+    state: &AppState,
+    name: &str,
+    dataset: SharedDataset,
+) -> Result<Json<S3ExportResponse>, AppError> {
+    let exporter = state.s3.as_ref().ok_or_else(|| {
+        AppError::BadRequest(String::from(
+            "S3 exports are disabled; set `s3.endpoint` (or --s3-endpoint) to enable them",
+        ))
+    })?;
+    if !exporter.exports(name) {
+        return Err(AppError::BadRequest(format!("Dataset {:?} isn't one of s3.datasets", name)));
+    }
+
+    // Only the rows are needed, so the lock is released before anything is uploaded
+    let df = all_rows(&dataset).await?;
+    let objects = exporter.export(name, &df).await.map_err(AppError::Internal)?;
+
+    Ok(Json(S3ExportResponse {
+        status: Status::Success,
+        dataset: name.to_string(),
+        rows: df.height(),
+        objects,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct SocketParams {
+    // What's done with each message: `collate` (the default) or `aggregate`
+    #[serde(default)]
+    ingest: SocketIngest,
+    // Whether to send the dataset's updates (default true)
+    updates: Option<bool>,
+    // How an `aggregate` socket aggregates each message, as `/aggregate` takes them in the query string
+    op: Option<String>,
+    keys: Option<String>,
+    window: Option<String>,
+    slide: Option<String>,
+    time: Option<String>,
+    filter: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SocketIngest {
+    #[default]
+    Collate,
+    Aggregate,
+}
+
+impl SocketParams {
+    fn aggregate(&self) -> AggregateParams {
+        AggregateParams {
+            op: self.op.clone(),
+            keys: self.keys.clone(),
+            window: self.window.clone(),
+            slide: self.slide.clone(),
+            time: self.time.clone(),
+            filter: self.filter.clone(),
+            lookup: None,
+        }
+    }
+}
+
+// What a socket's task wakes up for
+enum SocketEvent {
+    Incoming(Incoming),
+    Update(Result<Arc<Event>, RecvError>),
+}
+
+// handler that upgrades to a WebSocket on the default dataset
+#[axum_macros::debug_handler]
+async fn socket(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(params): Query<SocketParams>,
+    request: axum::extract::Request,
+) -> Result<Response, AppError> {
+    open_socket(state, DEFAULT_DATASET.to_string(), peer, params, request)
+}
+
+// Same as `socket`, but for a named dataset (created on first use)
+#[axum_macros::debug_handler]
+async fn dataset_socket(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    Query(params): Query<SocketParams>,
+    request: axum::extract::Request,
+) -> Result<Response, AppError> {
+    validate_dataset_name(&name).map_err(AppError::BadRequest)?;
+
+    open_socket(state, name, peer, params, request)
+}
+
+// Agree to upgrade the connection, and hand it to a task of its own once it's been switched over
+fn open_socket(
+    state: Arc<AppState>,
+    name: String,
+    peer: SocketAddr,
+    params: SocketParams,
+    mut request: axum::extract::Request,
+) -> Result<Response, AppError> {
+    let accept = ws::accept_key(request.headers()).map_err(AppError::BadRequest)?;
+    // Messages change the dataset, so they need a key with write access (when keys are configured at all)
+    let writable = request.extensions().get::<Scope>().is_none_or(|scope| *scope == Scope::Write);
+    let source = Origin::of(&state, request.headers(), peer).source;
+    let client = request.extensions().get::<Client>().map_or_else(|| source.clone(), |client| client.0.clone());
+
+    let upgrade = hyper::upgrade::on(&mut request);
+    tokio::spawn(async move {
+        match upgrade.await {
+            Ok(upgraded) => {
+                let socket = Socket::new(hyper_util::rt::TokioIo::new(upgraded), state.config.server.max_body_bytes);
+                serve_socket(&state, &name, &params, &source, &client, writable, socket).await;
+            }
+            Err(e) => error!("Can't upgrade the connection from {} to a WebSocket: {}", peer, e),
+        }
+    });
+
+    let response = Response::builder()
+        .status(axum::http::StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::UPGRADE, "websocket")
+        .header(header::CONNECTION, "upgrade")
+        .header(header::SEC_WEBSOCKET_ACCEPT, accept)
+        .body(Body::empty())
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    Ok(response)
+}
+
+// Ingest every message a socket receives, answering each with an acknowledgement (or error) in order, and pass on
+// the dataset's updates in between, until either side closes the connection
+async fn serve_socket<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin>(
+    state: &AppState,
+    name: &str,
+    params: &SocketParams,
+    source: &str,
+    client: &str,
+    writable: bool,
+    mut socket: Socket<S>,
+) {
+    let mut updates = params.updates.unwrap_or(true).then(|| state.events.subscribe());
+    let mut messages = 0u64;
+
+    let result: Result<(), SocketError> = async {
+        loop {
+            let event = tokio::select! {
+                incoming = socket.receive() => SocketEvent::Incoming(incoming?),
+                update = async {
+                    match updates.as_mut() {
+                        Some(updates) => updates.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => SocketEvent::Update(update),
+            };
+
+            match event {
+                SocketEvent::Incoming(Incoming::Message(data)) => {
+                    messages += 1;
+                    let origin = Origin {
+                        received_at: chrono::Utc::now(),
+                        source: source.to_string(),
+                    };
+                    let result = if writable {
+                        // Charged to the client that opened the socket, as a request of its own would be
+                        accounting::charged(state, client, socket_ingest(state, name, params, &origin, &data)).await
+                    } else {
+                        Err(AppError::Forbidden(String::from(
+                            "This API key can only read; sending messages needs a key with write access",
+                        )))
+                    };
+                    let reply = match result {
+                        Ok((rows, wrote_to_file, counts)) => SocketMessage::Ack {
+                            seq: messages,
+                            status: Status::Success,
+                            rows,
+                            wrote_to_file,
+                            counts,
+                        },
+                        Err(e) => SocketMessage::Error {
+                            seq: messages,
+                            status: Status::Error,
+                            error: e.kind().to_string(),
+                            message: e.message().to_string(),
+                        },
+                    };
+                    socket.send_text(&to_json(&reply)).await?;
+                }
+                SocketEvent::Incoming(Incoming::Ping(payload)) => socket.send_pong(&payload).await?,
+                SocketEvent::Incoming(Incoming::Close) => {
+                    // The client may already be gone, having said what it had to
+                    let _ = socket.close(NORMAL_CLOSURE, "").await;
+                    return Ok(());
+                }
+                SocketEvent::Update(Ok(event)) => {
+                    if let Event::Changed(change) = event.as_ref()
+                        && change.dataset == name
+                    {
+                        let update = SocketMessage::Update(change_event(change, true));
+                        socket.send_text(&to_json(&update)).await?;
+                    }
+                }
+                // The connection isn't reading as fast as the datasets change; the oldest updates were skipped
+                SocketEvent::Update(Err(RecvError::Lagged(missed))) => {
+                    socket.send_text(&to_json(&SocketMessage::Lagged(Lagged { missed }))).await?;
+                }
+                SocketEvent::Update(Err(RecvError::Closed)) => updates = None,
+            }
+        }
+    }
+    .await;
+
+    match result {
+        Ok(()) => trace!("WebSocket from {} on {:?} closed after {} messages", source, name, messages),
+        Err(SocketError::Lost(e)) => trace!("Lost the WebSocket from {} on {:?}: {}", source, name, e),
+        Err(SocketError::Violation(code, reason)) => {
+            info!("Closing the WebSocket from {} on {:?} ({}): {}", source, name, code, reason);
+            let _ = socket.close(code, &reason).await;
+        }
+    }
+}
+
+// Collate or aggregate a message the way `/collate` or `/aggregate` would a request body. Its content type is worked
+// out from its first bytes, since messages don't have headers. Returns how many rows it added, where they're being
+// persisted, and the other counts, for the acknowledgement.
+async fn socket_ingest(
+    state: &AppState,
+    name: &str,
+    params: &SocketParams,
+    origin: &Origin,
+    data: &[u8],
+) -> Result<(usize, String, IngestCounts), AppError> {
+    let headers = content_type_headers(sniff_content_type(data)).map_err(AppError::Internal)?;
+    let mut counts = IngestCounts::default();
+    let (wrote_to_file, rows) = match params.ingest {
+        SocketIngest::Collate => {
+            let (headers, data) = (headers.clone(), data.to_vec());
+            let df = state
+                .compute
+                .run(move || read_payload(&headers, std::io::Cursor::new(data.as_slice())))
+                .await?
+                .map_err(AppError::BadRequest)?;
+            let dataset = state.dataset(name).await;
+            let mut dataset = dataset.write().await;
+            let payload = prepared(state, name, &dataset, origin, df)?;
+            let concat = state.config.collate.concat;
+            let (_, wrote_to_file, rows) =
+                ingest(state, name, &mut dataset, payload, Merge::Append, concat, &mut counts).await?;
+            (wrote_to_file, rows)
+        }
+        SocketIngest::Aggregate => {
+            let data = Bytes::copy_from_slice(data);
+            let (_, wrote_to_file, rows) =
+                aggregate_payload(state, name, params.aggregate(), headers, data, None, &mut counts).await?;
+            (wrote_to_file, rows)
+        }
+    };
+    state.metrics.record_ingest(name, "ws", rows, data.len());
+
+    Ok((rows, wrote_to_file, counts))
+}
+
+#[derive(Debug, Deserialize)]
+struct EventParams {
+    // Only send this dataset's changes (flushes are sent either way)
+    dataset: Option<String>,
+    // Whether changes include the rows themselves (default true), or only how many there are
+    rows: Option<bool>,
+}
+
+// handler that streams state changes as Server-Sent Events, for dashboards that would otherwise poll
+#[axum_macros::debug_handler]
+async fn events(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<EventParams>,
+) -> Result<Sse<impl futures::Stream<Item = Result<sse::Event, Infallible>>>, AppError> {
+    if let Some(name) = &params.dataset {
+        validate_dataset_name(name).map_err(AppError::BadRequest)?;
+    }
+
+    let stream = futures::stream::unfold((state.events.subscribe(), params), |(mut rx, params)| async move {
+        loop {
+            let event = match rx.recv().await {
+                Ok(event) => sse_event(&event, &params),
+                // The client isn't reading as fast as the datasets change; the oldest events were skipped
+                Err(RecvError::Lagged(missed)) => {
+                    Some(sse::Event::default().event("lagged").data(to_json(&Lagged { missed })))
+                }
+                Err(RecvError::Closed) => return None,
+            };
+            if let Some(event) = event {
+                return Some((Ok(event), (rx, params)));
+            }
+        }
+    });
+
+    // Comments every 15 seconds keep proxies from closing a quiet stream
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+// An event as it's sent to `/events` clients, unless they asked not to get it
+fn sse_event(event: &Event, params: &EventParams) -> Option<sse::Event> {
+    let (kind, data) = match event {
+        Event::Changed(change) => {
+            if params.dataset.as_ref().is_some_and(|name| *name != change.dataset) {
+                return None;
+            }
+            ("change", to_json(&change_event(change, params.rows.unwrap_or(true))))
+        }
+        Event::Flushed(flush) => ("flush", to_json(flush)),
+    };
+
+    Some(sse::Event::default().event(kind).data(data))
+}
+
+// A change as `/ws` and `/events` send it, with or without the rows themselves
+fn change_event(change: &Change, rows: bool) -> ChangeEvent {
+    ChangeEvent {
+        dataset: change.dataset.clone(),
+        operation: change.operation.to_string(),
+        rows: rows.then(|| df_to_json_records(&change.rows)),
+        changed_rows: change.rows.height(),
+        total_rows: change.total_rows,
+    }
+}
+
+// A message for a socket or event stream. Only maps with non-string keys can fail to serialize, and none are sent.
+fn to_json<T: serde::Serialize>(message: &T) -> String {
+    serde_json::to_string(message).expect("messages serialize")
+}
+
+// handler that describes the default dataset's columns, so clients can check their payloads before posting
+#[axum_macros::debug_handler]
+async fn get_schema(State(state): State<Arc<AppState>>) -> Result<Json<SchemaResponse>, AppError> {
+    describe_schema(DEFAULT_DATASET, state.dataset(DEFAULT_DATASET).await).await
+}
+
+// Same as `get_schema`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn get_dataset_schema(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<SchemaResponse>, AppError> {
+    let dataset = state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+
+    describe_schema(&name, dataset).await
+}
+
+// The columns a dataset currently has (with dtypes and null counts), plus its declared schema if there is one
+async fn describe_schema(name: &str, dataset: SharedDataset) -> Result<Json<SchemaResponse>, AppError> {
+    let (df, declared) = {
+        let dataset = dataset.read().await;
+        (dataset.df.is_some().then(|| dataset.rows()), dataset.schema.clone())
+    };
+    let df = df.map(|rows| rows.collect()).transpose().map_err(AppError::Internal)?;
+
+    let (rows, columns) = match &df {
+        Some(df) => {
+            let columns = df
+                .get_columns()
+                .iter()
+                .map(|column| ColumnSummary {
+                    name: column.name().to_string(),
+                    dtype: column.dtype().to_string(),
+                    null_count: column.null_count(),
+                })
+                .collect();
+            (df.height(), columns)
+        }
+        None => (0, Vec::new()),
+    };
+
+    Ok(Json(SchemaResponse {
+        status: Status::Success,
+        dataset: name.to_string(),
+        rows,
+        columns,
+        declared,
+    }))
+}
+
+// handler that declares the default dataset's schema
+#[axum_macros::debug_handler]
+async fn put_schema(State(state): State<Arc<AppState>>, body: Upload) -> Result<Json<SchemaSetResponse>, AppError> {
+    let body = body.into_bytes().await?;
+    set_schema(state.dataset(DEFAULT_DATASET).await, &body).await
+}
+
+// Same as `put_schema`, but for a named dataset (created on first use)
+#[axum_macros::debug_handler]
+async fn put_dataset_schema(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    body: Upload,
+) -> Result<Json<SchemaSetResponse>, AppError> {
+    validate_dataset_name(&name).map_err(AppError::BadRequest)?;
+
+    let body = body.into_bytes().await?;
+    set_schema(state.dataset(&name).await, &body).await
+}
+
+// Declare a dataset's schema. Data it already holds must fit the schema too (and is cast to it in coerce mode).
+async fn set_schema(dataset: SharedDataset, body: &[u8]) -> Result<Json<SchemaSetResponse>, AppError> {
+    let schema: DatasetSchema =
+        serde_json::from_slice(body).map_err(|e| AppError::BadRequest(format!("Invalid schema: {}", e)))?;
+    if schema.columns.is_empty() {
+        return Err(AppError::BadRequest(String::from("The schema doesn't declare any columns")));
+    }
+
+    let mut dataset = dataset.write().await;
+    // Every row has to fit, spilled ones too
+    dataset.unspill().map_err(AppError::Internal)?;
+    if let Some(df) = &dataset.df {
+        let conformed = schema
+            .enforce(df)
+            .map_err(|e| AppError::SchemaMismatch(format!("The dataset's current data doesn't fit the schema: {}", e)))?;
+        dataset.df = Some(conformed);
+        dataset.ledger.clear();
+    }
+    dataset.schema = Some(schema.clone());
+
+    Ok(Json(SchemaSetResponse {
+        status: Status::Success,
+        schema,
+    }))
+}
+
+// handler that removes the default dataset's schema, so any payload is accepted again
+#[axum_macros::debug_handler]
+async fn delete_schema(State(state): State<Arc<AppState>>) -> Json<StatusResponse> {
+    state.dataset(DEFAULT_DATASET).await.write().await.schema = None;
+
+    Json(StatusResponse {
+        status: Status::Success,
+    })
+}
+
+// Same as `delete_schema`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn delete_dataset_schema(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<StatusResponse>, AppError> {
+    let dataset = state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+    dataset.write().await.schema = None;
+
+    Ok(Json(StatusResponse {
+        status: Status::Success,
+    }))
+}
+
+// handler that lists the default dataset's computed columns
+#[axum_macros::debug_handler]
+async fn get_computed(State(state): State<Arc<AppState>>) -> Json<ComputedResponse> {
+    describe_computed(state.dataset(DEFAULT_DATASET).await).await
+}
+
+// Same as `get_computed`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn get_dataset_computed(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<ComputedResponse>, AppError> {
+    let dataset = state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+
+    Ok(describe_computed(dataset).await)
+}
+
+async fn describe_computed(dataset: SharedDataset) -> Json<ComputedResponse> {
+    Json(ComputedResponse {
+        status: Status::Success,
+        computed: dataset.read().await.computed.clone().unwrap_or_default(),
+    })
+}
+
+// handler that sets the default dataset's computed columns
+#[axum_macros::debug_handler]
+async fn put_computed(State(state): State<Arc<AppState>>, body: Upload) -> Result<Json<ComputedResponse>, AppError> {
+    let body = body.into_bytes().await?;
+    let computed = parse_computed(&body)?;
+    set_computed(&state, DEFAULT_DATASET, computed).await
+}
+
+// Same as `put_computed`, but for a named dataset (created on first use)
+#[axum_macros::debug_handler]
+async fn put_dataset_computed(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    body: Upload,
+) -> Result<Json<ComputedResponse>, AppError> {
+    validate_dataset_name(&name).map_err(AppError::BadRequest)?;
+
+    let body = body.into_bytes().await?;
+    let computed = parse_computed(&body)?;
+    set_computed(&state, &name, computed).await
+}
+
+fn parse_computed(body: &[u8]) -> Result<ComputedColumns, AppError> {
+    let computed: ComputedColumns =
+        serde_json::from_slice(body).map_err(|e| AppError::BadRequest(format!("Invalid computed columns: {}", e)))?;
+    if computed.is_empty() {
+        return Err(AppError::BadRequest(String::from(
+            "No computed columns were given (use DELETE to remove them)",
+        )));
+    }
+
+    Ok(computed)
+}
+
+// handler that removes the default dataset's computed columns
+#[axum_macros::debug_handler]
+async fn delete_computed(State(state): State<Arc<AppState>>) -> Result<Json<ComputedResponse>, AppError> {
+    set_computed(&state, DEFAULT_DATASET, ComputedColumns::default()).await
+}
+
+// Same as `delete_computed`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn delete_dataset_computed(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<ComputedResponse>, AppError> {
+    state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+
+    set_computed(&state, &name, ComputedColumns::default()).await
+}
+
+// Replace a dataset's computed columns. They're logged like payloads, so they survive a restart; rows the dataset
+// already holds are left as they are.
+async fn set_computed(
+    state: &AppState,
+    name: &str,
+    computed: ComputedColumns,
+) -> Result<Json<ComputedResponse>, AppError> {
+    let dataset = state.dataset(name).await;
+    let mut dataset = dataset.write().await;
+
+    let operation = Operation::Computed {
+        columns: computed.clone(),
+    };
+    log_payload(state, name, &operation, &DataFrame::empty()).await?;
+    dataset.computed = Some(computed.clone());
+
+    Ok(Json(ComputedResponse {
+        status: Status::Success,
+        computed,
+    }))
+}
+
+// handler that reports which of the default dataset's expected reporters haven't sent rows yet
+#[axum_macros::debug_handler]
+async fn get_expected(State(state): State<Arc<AppState>>) -> Result<Json<CompletenessResponse>, AppError> {
+    report_expected(&state, DEFAULT_DATASET).await
+}
+
+// Same as `get_expected`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn get_dataset_expected(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<CompletenessResponse>, AppError> {
+    report_expected(&state, &name).await
+}
+
+async fn report_expected(state: &AppState, name: &str) -> Result<Json<CompletenessResponse>, AppError> {
+    let report = completeness::report(state, name, true).await?;
+    report
+        .map(Json)
+        .ok_or_else(|| AppError::NotFound(format!("Dataset {:?} doesn't expect any reporters", name)))
+}
+
+// handler that declares the reporters the default dataset expects rows from
+#[axum_macros::debug_handler]
+async fn put_expected(
+    State(state): State<Arc<AppState>>,
+    body: Upload,
+) -> Result<Json<CompletenessResponse>, AppError> {
+    let body = body.into_bytes().await?;
+    declare_expected(&state, DEFAULT_DATASET, &body).await
+}
+
+// Same as `put_expected`, but for a named dataset (which doesn't have to exist yet)
+#[axum_macros::debug_handler]
+async fn put_dataset_expected(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    body: Upload,
+) -> Result<Json<CompletenessResponse>, AppError> {
+    validate_dataset_name(&name).map_err(AppError::BadRequest)?;
+
+    let body = body.into_bytes().await?;
+    declare_expected(&state, &name, &body).await
+}
+
+// Replace a dataset's expected reporters, and report on them. Like a schema set with `PUT /schema`, they last until
+// the service restarts.
+async fn declare_expected(state: &AppState, name: &str, body: &[u8]) -> Result<Json<CompletenessResponse>, AppError> {
+    let expectation: Expectation = serde_json::from_slice(body)
+        .map_err(|e| AppError::BadRequest(format!("Invalid expected reporters: {}", e)))?;
+    expectation
+        .validate()
+        .map_err(|e| AppError::BadRequest(format!("Invalid expected reporters: {}", e)))?;
+
+    state.completeness.declare(name, expectation).await;
+    report_expected(state, name).await
+}
+
+// handler that stops the default dataset expecting reporters
+#[axum_macros::debug_handler]
+async fn delete_expected(State(state): State<Arc<AppState>>) -> Result<Json<StatusResponse>, AppError> {
+    remove_expected(&state, DEFAULT_DATASET).await
+}
+
+// Same as `delete_expected`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn delete_dataset_expected(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<StatusResponse>, AppError> {
+    remove_expected(&state, &name).await
+}
+
+async fn remove_expected(state: &AppState, name: &str) -> Result<Json<StatusResponse>, AppError> {
+    if !state.completeness.remove(name).await {
+        return Err(AppError::NotFound(format!("Dataset {:?} doesn't expect any reporters", name)));
+    }
+
+    Ok(Json(StatusResponse {
+        status: Status::Success,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct AwaitParams {
+    // How many batches to wait for, or with `key`, how many distinct values
+    expect: usize,
+    // Wait for distinct values of this column instead of batches
+    key: Option<String>,
+    // How long to wait before giving up with a `408`, e.g. `300s` or `5m` (defaults to a minute, at most an hour)
+    timeout: Option<String>,
+    // `csv`, `json`, `ndjson`, or `arrow` (defaults to the `Accept` header, then CSV)
+    format: Option<String>,
+}
+
+// handler that waits for the default dataset to reach a barrier
+#[axum_macros::debug_handler]
+async fn await_barrier(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<AwaitParams>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    await_dataset(&state, DEFAULT_DATASET, params, &headers).await
+}
+
+// Same as `await_barrier`, but for a named dataset (which doesn't have to exist yet)
+#[axum_macros::debug_handler]
+async fn dataset_await(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(params): Query<AwaitParams>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    validate_dataset_name(&name).map_err(AppError::BadRequest)?;
+
+    await_dataset(&state, &name, params, &headers).await
+}
+
+// Wait for a dataset to reach the barrier, then return its rows as CSV, JSON, NDJSON, or Arrow
+async fn await_dataset(
+    state: &AppState,
+    name: &str,
+    params: AwaitParams,
+    headers: &HeaderMap,
+) -> Result<Response, AppError> {
+    let format = match params.format.as_deref() {
+        Some(format) => format.parse().map_err(AppError::BadRequest)?,
+        None => negotiate(headers, ResponseFormat::Csv)?,
+    };
+    if params.expect == 0 {
+        return Err(AppError::BadRequest(String::from("expect must be above 0")));
+    }
+    let target = match params.key {
+        Some(column) if column.is_empty() => return Err(AppError::BadRequest(String::from("key can't be empty"))),
+        Some(column) => barrier::Target::Distinct {
+            column,
+            count: params.expect,
+        },
+        None => barrier::Target::Batches(params.expect as u64),
+    };
+    let timeout = match params.timeout.as_deref() {
+        Some(timeout) => barrier::parse_timeout(timeout).map_err(AppError::BadRequest)?,
+        None => barrier::DEFAULT_TIMEOUT,
+    };
+
+    let started = std::time::Instant::now();
+    let reached = barrier::wait(state, name, &target, timeout).await?;
+    let waited_ms = started.elapsed().as_millis() as u64;
+
+    let dataset = state.dataset(name).await;
+    let (rows, total_rows) = read_window(state, name, dataset, &ReadOptions::default()).await?;
+    if format == ResponseFormat::Json {
+        return Ok(Json(AwaitResponse {
+            status: Status::Success,
+            dataset: name.to_string(),
+            reached,
+            waited_ms,
+            total_rows,
+            rows: df_to_json_records(&rows),
+        })
+        .into_response());
+    }
+
+    df_response(
+        rows,
+        format,
+        vec![
+            (HeaderName::from_static("x-total-rows"), HeaderValue::from(total_rows)),
+            (HeaderName::from_static("x-reached"), HeaderValue::from(reached)),
+        ],
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct LookupParams {
+    // Comma-separated key columns to join on (defaults to the table's first column)
+    key: Option<String>,
+}
+
+// handler that loads (or replaces) a lookup table from a CSV or JSON payload
+#[axum_macros::debug_handler]
+async fn put_lookup(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(params): Query<LookupParams>,
+    headers: HeaderMap,
+    body: Upload,
+) -> Result<Json<LookupLoadedResponse>, AppError> {
+    let df = state.compute.run(move || body.read(&headers)).await?.map_err(AppError::BadRequest)?;
+    let keys: Vec<String> = match params.key.as_deref() {
+        Some(key) => key.split(',').map(str::trim).filter(|key| !key.is_empty()).map(String::from).collect(),
+        None => df.get_column_names_str().first().map(|key| key.to_string()).into_iter().collect(),
+    };
+    let table = LookupTable::new(df, keys).map_err(AppError::BadRequest)?;
+    let (rows, keys) = (table.df.height(), table.keys.clone());
+    let replaced = state.lookups.insert(&name, table).await;
+
+    Ok(Json(LookupLoadedResponse {
+        status: Status::Success,
+        name,
+        keys,
+        rows,
+        replaced,
+    }))
+}
+
+// handler that returns a lookup table
+#[axum_macros::debug_handler]
+async fn get_lookup(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let format = negotiate(&headers, ResponseFormat::Csv)?;
+    let table = state.lookups.get(&name).await.ok_or_else(|| AppError::lookup_not_found(&name))?;
+
+    if format == ResponseFormat::Json {
+        return Ok(Json(LookupResponse {
+            status: Status::Success,
+            name,
+            keys: table.keys.clone(),
+            rows: df_to_json_records(&table.df),
+        })
+        .into_response());
+    }
+
+    df_response(table.df.clone(), format, Vec::new())
+}
+
+// handler that removes a lookup table
+#[axum_macros::debug_handler]
+async fn delete_lookup(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<LookupDeletedResponse>, AppError> {
+    state.lookups.remove(&name).await.ok_or_else(|| AppError::lookup_not_found(&name))?;
+
+    Ok(Json(LookupDeletedResponse {
+        status: Status::Success,
+        name,
+    }))
+}
+
+// List the names of every lookup table
+async fn list_lookups(State(state): State<Arc<AppState>>) -> Json<LookupsResponse> {
+    Json(LookupsResponse {
+        status: Status::Success,
+        lookups: state.lookups.names().await,
+    })
+}
+
+// List the names of every dataset
+async fn list_datasets(State(state): State<Arc<AppState>>) -> Json<DatasetsResponse> {
+    Json(DatasetsResponse {
+        status: Status::Success,
+        datasets: state.dataset_names().await,
+    })
+}
+
+// Return the current contents of a dataset without modifying it
+#[axum_macros::debug_handler]
+async fn get_dataset(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<DatasetResponse>, AppError> {
+    let dataset = state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+
+    // Clone the (cheap, reference-counted) frame so the CSV is written without holding the lock
+    let (output_file, df) = {
+        let dataset = dataset.read().await;
+        (dataset.output_file.clone(), dataset.df.is_some().then(|| dataset.rows()))
+    };
+    let df = df.map(|rows| rows.collect()).transpose().map_err(AppError::Internal)?;
+    let (rows, csv_string) = match df {
+        Some(mut df) => (df.height(), df_to_csv(&mut df, true)),
+        None => (0, String::new()),
+    };
+
+    Ok(Json(DatasetResponse {
+        status: Status::Success,
+        name,
+        rows,
+        output_file,
+        csv_string,
+    }))
+}
+
+// Drop a dataset from memory (its output file, if any, is kept)
+#[axum_macros::debug_handler]
+async fn delete_dataset(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<DatasetDeletedResponse>, AppError> {
+    let dataset = state.remove_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+    let mut dataset = dataset.write().await;
+    audit::note_removed(dataset.df.as_ref().map_or(0, DataFrame::height) + dataset.spilled.rows());
+    // Spill files otherwise outlast the dataset, for a restart to pick up
+    dataset.spilled.clear();
+
+    Ok(Json(DatasetDeletedResponse {
+        status: Status::Success,
+        deleted: name,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct ResetParams {
+    // Keep the old output file (renamed with a timestamp) instead of removing it
+    #[serde(default)]
+    rotate: bool,
+}
+
+// handler that empties the default dataset
+#[axum_macros::debug_handler]
+async fn reset(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<ResetParams>,
+) -> Result<Json<ResetResponse>, AppError> {
+    reset_dataset(&state, DEFAULT_DATASET, state.dataset(DEFAULT_DATASET).await, params).await
+}
+
+// Same as `reset`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn reset_named_dataset(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(params): Query<ResetParams>,
+) -> Result<Json<ResetResponse>, AppError> {
+    let dataset = state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+
+    reset_dataset(&state, &name, dataset, params).await
+}
+
+// Forget every row of a dataset (its schema stays), and start its output file (and partitions) over. The old ones are
+// removed, or with `rotate` kept as `<file>.<timestamp>`.
+async fn reset_dataset(
+    state: &AppState,
+    name: &str,
+    dataset: SharedDataset,
+    params: ResetParams,
+) -> Result<Json<ResetResponse>, AppError> {
+    let mut dataset = dataset.write().await;
+
+    log_payload(state, name, &Operation::Reset, &DataFrame::empty()).await?;
+    audit::note_removed(dataset.df.as_ref().map_or(0, DataFrame::height) + dataset.spilled.rows());
+    dataset.reset();
+    dataset.ledger.clear();
+    state.events.changed(name, "reset", &DataFrame::empty(), 0);
+
+    let mut rotated_to = None;
+    if let Some(output_file) = dataset.output_file.clone() {
+        // Anything still queued for the old file has to land in it before it's moved or removed
+        state.writer.flush().await.map_err(AppError::Internal)?;
+
+        let result = if params.rotate {
+            persist::rotate_output(&output_file).map(|rotated| rotated_to = rotated)
+        } else {
+            persist::remove_output(&output_file)
+        };
+        result.map_err(|e| {
+            AppError::Internal(format!("The dataset was reset, but its output file {:?} couldn't be: {}", output_file, e))
+        })?;
+    }
+    let mut partitions_rotated_to = None;
+    if let Some(partitions) = &dataset.partitions {
+        state.writer.flush().await.map_err(AppError::Internal)?;
+
+        let result = if params.rotate {
+            persist::rotate_output(&partitions.dir).map(|rotated| partitions_rotated_to = rotated)
+        } else {
+            partition::remove(&partitions.dir)
+        };
+        result.map_err(|e| {
+            AppError::Internal(format!("The dataset was reset, but its partitions {:?} couldn't be: {}", partitions.dir, e))
+        })?;
+    }
+
+    Ok(Json(ResetResponse {
+        status: Status::Success,
+        dataset: name.to_string(),
+        rotated_to,
+        partitions_rotated_to,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct DeleteParams {
+    // Which rows to delete, e.g. `host=node3,latency_ms>250` (see `filter::Filter`)
+    filter: Option<String>,
+}
+
+// handler that deletes the default dataset's rows matching a filter
+#[axum_macros::debug_handler]
+async fn delete_data(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DeleteParams>,
+) -> Result<Json<DeleteResponse>, AppError> {
+    delete_rows(&state, DEFAULT_DATASET, state.dataset(DEFAULT_DATASET).await, params).await
+}
+
+// Same as `delete_data`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn delete_dataset_data(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(params): Query<DeleteParams>,
+) -> Result<Json<DeleteResponse>, AppError> {
+    let dataset = state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+
+    delete_rows(&state, &name, dataset, params).await
+}
+
+// Remove the rows matching a filter from a dataset. In `append` and `snapshot` mode the output file is rewritten with
+// what's left; in `overwrite` mode it only ever holds the latest payload, so it's left alone.
+async fn delete_rows(
+    state: &AppState,
+    name: &str,
+    dataset: SharedDataset,
+    params: DeleteParams,
+) -> Result<Json<DeleteResponse>, AppError> {
+    let source = params.filter.ok_or_else(|| {
+        AppError::BadRequest(String::from("A `filter` parameter is required (use `POST /reset` to delete every row)"))
+    })?;
+    let filter: Filter = source.parse().map_err(AppError::BadRequest)?;
+
+    let mut dataset = dataset.write().await;
+    // Spilled rows may match too, so they're read back in first
+    dataset.unspill().map_err(AppError::Internal)?;
+    let (aggregate_state, df, deleted) = dataset.without_rows(&filter).map_err(AppError::BadRequest)?;
+    audit::note_removed(deleted);
+
+    log_payload(state, name, &Operation::Delete { filter: source }, &DataFrame::empty()).await?;
+    dataset.aggregate_state = aggregate_state;
+    dataset.df = df;
+    dataset.ledger.clear();
+    let rows = dataset.df.as_ref().map_or(0, DataFrame::height);
+    state.events.changed(name, "delete", &DataFrame::empty(), rows);
+
+    if deleted > 0 && state.config.storage.write_mode != WriteMode::Overwrite {
+        let state_df = dataset.df.clone().unwrap_or_default();
+        persist_result(&state.writer, &dataset, WriteMode::Snapshot, state_df).await?;
+    }
+
+    Ok(Json(DeleteResponse {
+        status: Status::Success,
+        deleted,
+        rows,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct DedupParams {
+    // Comma-separated columns to compare (every column if not set)
+    subset: Option<String>,
+    // `first` (the default), `last`, or `none`
+    keep: Option<String>,
+}
+
+// handler that drops the default dataset's duplicate rows
+#[axum_macros::debug_handler]
+async fn dedup(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DedupParams>,
+) -> Result<Json<RemovedResponse>, AppError> {
+    dedup_dataset(&state, DEFAULT_DATASET, state.dataset(DEFAULT_DATASET).await, params).await
+}
+
+// Same as `dedup`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn dedup_named_dataset(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(params): Query<DedupParams>,
+) -> Result<Json<RemovedResponse>, AppError> {
+    let dataset = state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+
+    dedup_dataset(&state, &name, dataset, params).await
+}
+
+// Remove duplicate rows from a dataset. Like deletes, the output file is rewritten with what's left, except in
+// `overwrite` mode.
+async fn dedup_dataset(
+    state: &AppState,
+    name: &str,
+    dataset: SharedDataset,
+    params: DedupParams,
+) -> Result<Json<RemovedResponse>, AppError> {
+    let subset: Option<Vec<String>> = params
+        .subset
+        .map(|subset| subset.split(',').map(str::trim).filter(|column| !column.is_empty()).map(String::from).collect())
+        .filter(|subset: &Vec<String>| !subset.is_empty());
+    let keep: KeepDuplicate = match params.keep {
+        Some(keep) => keep.parse().map_err(AppError::BadRequest)?,
+        None => KeepDuplicate::default(),
+    };
+
+    let mut dataset = dataset.write().await;
+    dataset.unspill().map_err(AppError::Internal)?;
+    let (df, removed) = dataset.deduplicated(subset.as_deref(), keep).map_err(AppError::BadRequest)?;
+    audit::note_removed(removed);
+
+    log_payload(state, name, &Operation::Dedup { subset, keep }, &DataFrame::empty()).await?;
+    dataset.df = df;
+    dataset.ledger.clear();
+    let rows = dataset.df.as_ref().map_or(0, DataFrame::height);
+    state.events.changed(name, "dedup", &DataFrame::empty(), rows);
+
+    if removed > 0 && state.config.storage.write_mode != WriteMode::Overwrite {
+        let state_df = dataset.df.clone().unwrap_or_default();
+        persist_result(&state.writer, &dataset, WriteMode::Snapshot, state_df).await?;
+    }
+
+    Ok(Json(RemovedResponse {
+        status: Status::Success,
+        removed,
+        rows,
+    }))
+}
+
+// handler that fills in the default dataset's nulls
+#[axum_macros::debug_handler]
+async fn fill_nulls(State(state): State<Arc<AppState>>, body: Upload) -> Result<Json<FilledResponse>, AppError> {
+    let body = body.into_bytes().await?;
+    fill_nulls_of(&state, DEFAULT_DATASET, state.dataset(DEFAULT_DATASET).await, &body).await
+}
+
+// Same as `fill_nulls`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn fill_nulls_named_dataset(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    body: Upload,
+) -> Result<Json<FilledResponse>, AppError> {
+    let dataset = state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+
+    let body = body.into_bytes().await?;
+    fill_nulls_of(&state, &name, dataset, &body).await
+}
+
+// Fill in a dataset's nulls. Like dedups, the output file is rewritten with the result, except in `overwrite` mode.
+async fn fill_nulls_of(
+    state: &AppState,
+    name: &str,
+    dataset: SharedDataset,
+    body: &[u8],
+) -> Result<Json<FilledResponse>, AppError> {
+    let spec: FillSpec =
+        serde_json::from_slice(body).map_err(|e| AppError::BadRequest(format!("Invalid fill: {}", e)))?;
+
+    let mut dataset = dataset.write().await;
+    dataset.unspill().map_err(AppError::Internal)?;
+    let (df, filled) = dataset.nulls_filled(&spec).map_err(AppError::BadRequest)?;
+
+    log_payload(state, name, &Operation::FillNulls { spec }, &DataFrame::empty()).await?;
+    dataset.df = df;
+    dataset.ledger.clear();
+    let rows = dataset.df.as_ref().map_or(0, DataFrame::height);
+    state.events.changed(name, "fill_nulls", &DataFrame::empty(), rows);
+
+    if filled > 0 && state.config.storage.write_mode != WriteMode::Overwrite {
+        let state_df = dataset.df.clone().unwrap_or_default();
+        persist_result(&state.writer, &dataset, WriteMode::Snapshot, state_df).await?;
+    }
+
+    Ok(Json(FilledResponse {
+        status: Status::Success,
+        filled,
+        rows,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct DropNullsParams {
+    // Comma-separated columns to check for nulls (every column if not set)
+    subset: Option<String>,
+}
+
+// handler that drops the default dataset's rows with nulls
+#[axum_macros::debug_handler]
+async fn drop_nulls(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DropNullsParams>,
+) -> Result<Json<RemovedResponse>, AppError> {
+    drop_nulls_of(&state, DEFAULT_DATASET, state.dataset(DEFAULT_DATASET).await, params).await
+}
+
+// Same as `drop_nulls`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn drop_nulls_named_dataset(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(params): Query<DropNullsParams>,
+) -> Result<Json<RemovedResponse>, AppError> {
+    let dataset = state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+
+    drop_nulls_of(&state, &name, dataset, params).await
+}
+
+// Remove a dataset's rows with nulls, rewriting the output file like dedups do
+async fn drop_nulls_of(
+    state: &AppState,
+    name: &str,
+    dataset: SharedDataset,
+    params: DropNullsParams,
+) -> Result<Json<RemovedResponse>, AppError> {
+    let subset: Option<Vec<String>> = params
+        .subset
+        .map(|subset| subset.split(',').map(str::trim).filter(|column| !column.is_empty()).map(String::from).collect())
+        .filter(|subset: &Vec<String>| !subset.is_empty());
+
+    let mut dataset = dataset.write().await;
+    dataset.unspill().map_err(AppError::Internal)?;
+    let (df, removed) = dataset.without_nulls(subset.as_deref()).map_err(AppError::BadRequest)?;
+    audit::note_removed(removed);
+
+    log_payload(state, name, &Operation::DropNulls { subset }, &DataFrame::empty()).await?;
+    dataset.df = df;
+    dataset.ledger.clear();
+    let rows = dataset.df.as_ref().map_or(0, DataFrame::height);
+    state.events.changed(name, "drop_nulls", &DataFrame::empty(), rows);
+
+    if removed > 0 && state.config.storage.write_mode != WriteMode::Overwrite {
+        let state_df = dataset.df.clone().unwrap_or_default();
+        persist_result(&state.writer, &dataset, WriteMode::Snapshot, state_df).await?;
+    }
+
+    Ok(Json(RemovedResponse {
+        status: Status::Success,
+        removed,
+        rows,
+    }))
+}
+
+// handler that reports the default dataset's nulls per column
+#[axum_macros::debug_handler]
+async fn nulls(State(state): State<Arc<AppState>>) -> Result<Json<NullsResponse>, AppError> {
+    nulls_of(state.dataset(DEFAULT_DATASET).await).await
+}
+
+// Same as `nulls`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn dataset_nulls(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<NullsResponse>, AppError> {
+    let dataset = state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+
+    nulls_of(dataset).await
+}
+
+// How many values each column is missing, and how many rows are missing at least one
+async fn nulls_of(dataset: SharedDataset) -> Result<Json<NullsResponse>, AppError> {
+    let df = all_rows(&dataset).await?;
+    let rows = df.height();
+
+    let columns = df
+        .get_columns()
+        .iter()
+        .map(|column| {
+            let null_count = column.null_count();
+            NullCount {
+                name: column.name().to_string(),
+                null_count,
+                null_fraction: if rows == 0 { 0.0 } else { null_count as f64 / rows as f64 },
+            }
+        })
+        .collect();
+    let incomplete = rows - df.drop_nulls::<String>(None).map_or(rows, |complete| complete.height());
+
+    Ok(Json(NullsResponse {
+        status: Status::Success,
+        rows,
+        rows_with_nulls: incomplete,
+        columns,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct OutlierParams {
+    // Comma-separated columns to check (every numeric column other than the keys by default)
+    columns: Option<String>,
+    // Comma-separated columns whose values each get their own median
+    keys: Option<String>,
+    // `mad` (the default) or `std`
+    method: Option<String>,
+    // Deviations from the median a value can be before its row is an outlier (3 by default)
+    threshold: Option<f64>,
+}
+
+// The dataset's `[outliers.<name>]` settings (or the defaults), with the request's parameters on top
+fn requested_outliers(state: &AppState, name: &str, params: OutlierParams) -> Result<OutlierSpec, AppError> {
+    let split = |list: String| -> Vec<String> {
+        list.split(',').map(str::trim).filter(|column| !column.is_empty()).map(String::from).collect()
+    };
+
+    let mut spec = state.config.outliers.get(name).cloned().unwrap_or_default();
+    if let Some(columns) = params.columns {
+        spec.columns = split(columns);
+    }
+    if let Some(keys) = params.keys {
+        spec.keys = split(keys);
+    }
+    if let Some(method) = params.method {
+        spec.method = method.parse().map_err(AppError::BadRequest)?;
+    }
+    if let Some(threshold) = params.threshold {
+        spec.threshold = threshold;
+    }
+
+    Ok(spec)
+}
+
+// handler that returns the default dataset's outlying rows (without modifying it)
+#[axum_macros::debug_handler]
+async fn outliers(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<OutlierParams>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let spec = requested_outliers(&state, DEFAULT_DATASET, params)?;
+    outliers_of(state.dataset(DEFAULT_DATASET).await, &spec, &headers).await
+}
+
+// Same as `outliers`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn dataset_outliers(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(params): Query<OutlierParams>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let dataset = state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+    let spec = requested_outliers(&state, &name, params)?;
+
+    outliers_of(dataset, &spec, &headers).await
+}
+
+// The rows that are outliers now, measured against the whole dataset (so rows flagged on ingest may no longer be, and
+// the other way around)
+async fn outliers_of(dataset: SharedDataset, spec: &OutlierSpec, headers: &HeaderMap) -> Result<Response, AppError> {
+    let format = negotiate(headers, ResponseFormat::Json)?;
+    let df = all_rows(&dataset).await?;
+    if df.width() == 0 {
+        return reshaped_response(df, format);
+    }
+
+    let outliers = spec
+        .flagged(&df)
+        .and_then(|flagged| {
+            flagged.lazy().filter(col(OUTLIER_COLUMN)).collect().map_err(|e| e.to_string())
+        })
+        .map_err(AppError::BadRequest)?;
+
+    reshaped_response(outliers, format)
+}
+
+// handler that flags the default dataset's rows as outliers or not
+#[axum_macros::debug_handler]
+async fn flag_outliers(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<OutlierParams>,
+) -> Result<Json<OutliersResponse>, AppError> {
+    let spec = requested_outliers(&state, DEFAULT_DATASET, params)?;
+    flag_outliers_of(&state, DEFAULT_DATASET, state.dataset(DEFAULT_DATASET).await, spec).await
+}
+
+// Same as `flag_outliers`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn flag_dataset_outliers(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(params): Query<OutlierParams>,
+) -> Result<Json<OutliersResponse>, AppError> {
+    let dataset = state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+    let spec = requested_outliers(&state, &name, params)?;
+
+    flag_outliers_of(&state, &name, dataset, spec).await
+}
+
+// Write (or refresh) a dataset's `is_outlier` column, measuring every row against all the others. The output file is
+// rewritten like after a dedup, except in `overwrite` mode.
+async fn flag_outliers_of(
+    state: &AppState,
+    name: &str,
+    dataset: SharedDataset,
+    spec: OutlierSpec,
+) -> Result<Json<OutliersResponse>, AppError> {
+    let mut dataset = dataset.write().await;
+    dataset.unspill().map_err(AppError::Internal)?;
+    let df = dataset.outliers_flagged(&spec).map_err(AppError::BadRequest)?;
+    let outliers = match &df {
+        Some(df) => df
+            .column(OUTLIER_COLUMN)
+            .and_then(|column| column.bool().map(|flags| flags.sum().unwrap_or(0) as usize))
+            .map_err(|e| AppError::Internal(e.to_string()))?,
+        None => 0,
+    };
+
+    log_payload(state, name, &Operation::FlagOutliers { spec }, &DataFrame::empty()).await?;
+    dataset.df = df;
+    dataset.ledger.clear();
+    let rows = dataset.df.as_ref().map_or(0, DataFrame::height);
+    state.events.changed(name, "flag_outliers", &DataFrame::empty(), rows);
+
+    if rows > 0 && state.config.storage.write_mode != WriteMode::Overwrite {
+        let state_df = dataset.df.clone().unwrap_or_default();
+        persist_result(&state.writer, &dataset, WriteMode::Snapshot, state_df).await?;
+    }
+
+    Ok(Json(OutliersResponse {
+        status: Status::Success,
+        outliers,
+        rows,
+    }))
+}
+
+// handler that returns the default dataset's quarantined rows
+#[axum_macros::debug_handler]
+async fn quarantine(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Result<Response, AppError> {
+    quarantine_of(state.dataset(DEFAULT_DATASET).await, &headers).await
+}
+
+// Same as `quarantine`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn dataset_quarantine(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let dataset = state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+
+    quarantine_of(dataset, &headers).await
+}
+
+// Return the rows that failed a dataset's validation rules, as CSV (the default), JSON records, NDJSON, or Arrow
+async fn quarantine_of(dataset: SharedDataset, headers: &HeaderMap) -> Result<Response, AppError> {
+    let format = negotiate(headers, ResponseFormat::Csv)?;
+    let rows = dataset.read().await.quarantine.clone().unwrap_or_default();
+
+    if format == ResponseFormat::Json {
+        return Ok(Json(QuarantineResponse {
+            status: Status::Success,
+            rows: rows.height(),
+            quarantine: df_to_json_records(&rows),
+        })
+        .into_response());
+    }
+
+    let total = HeaderValue::from(rows.height());
+    df_response(rows, format, vec![(HeaderName::from_static("x-total-rows"), total)])
+}
+
+// handler that empties the default dataset's quarantine
+#[axum_macros::debug_handler]
+async fn clear_quarantine(State(state): State<Arc<AppState>>) -> Result<Json<QuarantineClearedResponse>, AppError> {
+    clear_quarantine_of(&state, DEFAULT_DATASET, state.dataset(DEFAULT_DATASET).await).await
+}
+
+// Same as `clear_quarantine`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn clear_dataset_quarantine(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<QuarantineClearedResponse>, AppError> {
+    let dataset = state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+
+    clear_quarantine_of(&state, &name, dataset).await
+}
+
+async fn clear_quarantine_of(
+    state: &AppState,
+    name: &str,
+    dataset: SharedDataset,
+) -> Result<Json<QuarantineClearedResponse>, AppError> {
+    let mut dataset = dataset.write().await;
+
+    log_payload(state, name, &Operation::ClearQuarantine, &DataFrame::empty()).await?;
+    let deleted = dataset.quarantine.take().map_or(0, |rows| rows.height());
+
+    Ok(Json(QuarantineClearedResponse {
+        status: Status::Success,
+        deleted,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct SnapshotParams {
+    // Label to add to the snapshot's id, e.g. `before-rerun`
+    name: Option<String>,
+}
+
+// handler that snapshots the default dataset
+#[axum_macros::debug_handler]
+async fn create_snapshot(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<SnapshotParams>,
+) -> Result<Json<SnapshotResponse>, AppError> {
+    snapshot_dataset(&state, DEFAULT_DATASET, state.dataset(DEFAULT_DATASET).await, params).await
+}
+
+// Same as `create_snapshot`, but for a named dataset
+#[axum_macros::debug_handler]
+async fn create_dataset_snapshot(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(params): Query<SnapshotParams>,
+) -> Result<Json<SnapshotResponse>, AppError> {
+    let dataset = state.existing_dataset(&name).await.ok_or_else(|| AppError::dataset_not_found(&name))?;
+
+    snapshot_dataset(&state, &name, dataset, params).await
+}
+
+// Write a dataset's current state to a new snapshot
+async fn snapshot_dataset(
+    state: &AppState,
+    name: &str,
+    dataset: SharedDataset,
+    params: SnapshotParams,
+) -> Result<Json<SnapshotResponse>, AppError> {
+    let dir = snapshots_dir(state)?;
+    if let Some(label) = &params.name {
+        validate_snapshot_id(label).map_err(AppError::BadRequest)?;
+    }
+
+    // A read lock is enough: the snapshot only has to see one consistent state, not stop readers
+    let dataset = dataset.read().await;
+    let info = snapshot::write_snapshot(dir, name, &dataset, params.name).map_err(AppError::Internal)?;
+
+    Ok(Json(SnapshotResponse {
+        status: Status::Success,
+        snapshot: info,
+    }))
+}
+
+// handler that lists the default dataset's snapshots
+async fn list_snapshots(State(state): State<Arc<AppState>>) -> Result<Json<SnapshotsResponse>, AppError> {
+    snapshots_of(&state, DEFAULT_DATASET)
+}
+
+// Same as `list_snapshots`, but for a named dataset. Its snapshots are listed even if the dataset was deleted since.
+async fn list_dataset_snapshots(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<SnapshotsResponse>, AppError> {
+    validate_dataset_name(&name).map_err(AppError::BadRequest)?;
+
+    snapshots_of(&state, &name)
+}
+
+fn snapshots_of(state: &AppState, name: &str) -> Result<Json<SnapshotsResponse>, AppError> {
+    let snapshots = snapshot::list_snapshots(snapshots_dir(state)?, name).map_err(AppError::Internal)?;
+
+    Ok(Json(SnapshotsResponse {
+        status: Status::Success,
+        snapshots,
+    }))
+}
+
+// handler that rolls the default dataset back to one of its snapshots
+#[axum_macros::debug_handler]
+async fn restore_snapshot(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<RestoreResponse>, AppError> {
+    restore_from(&state, DEFAULT_DATASET, id).await
+}
+
+// Same as `restore_snapshot`, but for a named dataset (which is recreated if it was deleted)
+#[axum_macros::debug_handler]
+async fn restore_dataset_snapshot(
+    State(state): State<Arc<AppState>>,
+    Path((name, id)): Path<(String, String)>,
+) -> Result<Json<RestoreResponse>, AppError> {
+    validate_dataset_name(&name).map_err(AppError::BadRequest)?;
+
+    restore_from(&state, &name, id).await
+}
+
+// Replace a dataset's rows, aggregate state, and schema with a snapshot's. In `append` and `snapshot` mode the output
+// file is rewritten to match; in `overwrite` mode it's left alone.
+async fn restore_from(state: &AppState, name: &str, id: String) -> Result<Json<RestoreResponse>, AppError> {
+    let dir = snapshots_dir(state)?;
+    validate_snapshot_id(&id).map_err(AppError::BadRequest)?;
+
+    let (info, restored) = snapshot::read_snapshot(dir, name, &id)
+        .map_err(AppError::Internal)?
+        .ok_or_else(|| AppError::NotFound(format!("Dataset {:?} has no snapshot {:?}", name, id)))?;
+
+    let dataset = state.dataset(name).await;
+    let mut dataset = dataset.write().await;
+
+    log_payload(state, name, &Operation::Restore { snapshot: id }, &DataFrame::empty()).await?;
+    dataset.restore(restored);
+    dataset.ledger.clear();
+    state.events.changed(name, "restore", &DataFrame::empty(), dataset.df.as_ref().map_or(0, DataFrame::height));
+
+    if state.config.storage.write_mode != WriteMode::Overwrite {
+        let df = dataset.df.clone().unwrap_or_default();
+        persist_result(&state.writer, &dataset, WriteMode::Snapshot, df).await?;
+    }
+
+    Ok(Json(RestoreResponse {
+        status: Status::Success,
+        restored: info,
+    }))
+}
+
+// handler that lists the default dataset's versions
+async fn versions(State(state): State<Arc<AppState>>) -> Result<Json<VersionsResponse>, AppError> {
+    Ok(Json(history::versions(&state, DEFAULT_DATASET).await?))
+}
+
+// Same as `versions`, but for a named dataset. Its versions are listed even if it was deleted since.
+async fn dataset_versions(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<VersionsResponse>, AppError> {
+    validate_dataset_name(&name).map_err(AppError::BadRequest)?;
+
+    Ok(Json(history::versions(&state, &name).await?))
+}
+
+#[derive(Debug, Deserialize)]
+struct DiffParams {
+    // Versions, as `v3` or `3`
+    from: Option<String>,
+    to: Option<String>,
+}
+
+// handler that compares two versions of the default dataset
+async fn diff(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DiffParams>,
+) -> Result<Json<DiffResponse>, AppError> {
+    diff_of(&state, DEFAULT_DATASET, params).await
+}
+
+// Same as `diff`, but for a named dataset
+async fn dataset_diff(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(params): Query<DiffParams>,
+) -> Result<Json<DiffResponse>, AppError> {
+    validate_dataset_name(&name).map_err(AppError::BadRequest)?;
+
+    diff_of(&state, &name, params).await
+}
+
+async fn diff_of(state: &AppState, name: &str, params: DiffParams) -> Result<Json<DiffResponse>, AppError> {
+    let version = |version: Option<String>| version.as_deref().map(history::parse_version).transpose();
+    let from = version(params.from).map_err(AppError::BadRequest)?;
+    let to = version(params.to).map_err(AppError::BadRequest)?;
+
+    Ok(Json(history::diff(state, name, from, to).await?))
+}
+
+fn snapshots_dir(state: &AppState) -> Result<&std::path::Path, AppError> {
+    state.config.storage.snapshots_dir.as_deref().ok_or_else(|| {
+        AppError::BadRequest(String::from(
+            "Snapshots are disabled; set `storage.snapshots_dir` (or --snapshots-dir) to enable them",
+        ))
+    })
+}
+
+// The response to an accepted `/collate`, `/collate_wide`, `/upsert`, or `/aggregate` payload: by default a JSON
+// object with the dataset's new state as a CSV string, or just the new state in the negotiated format (with
+// `x-wrote-to-file` saying where it's being persisted). The counts that apply are added as JSON fields or headers.
+// With `minimal`, the state is left out, but for its columns.
+fn ingest_response(
+    mut result: DataFrame,
+    format: ResponseFormat,
+    minimal: bool,
+    wrote_to_file: String,
+    counts: IngestCounts,
+) -> Result<Response, AppError> {
+    if minimal {
+        result = result.clear();
+    }
+    let applied = minimal
+        .then(|| (HeaderName::from_static("preference-applied"), HeaderValue::from_static("return=minimal")));
+    if format == ResponseFormat::Json {
+        let body = Json(CollateResponse {
+            status: Status::Success,
+            wrote_to_file,
+            csv_string: df_to_csv(&mut result, true),
+            counts,
+        });
+        return Ok((HeaderMap::from_iter(applied), body).into_response());
+    }
+
+    let wrote_to_file = HeaderValue::from_str(&wrote_to_file)
+        .unwrap_or_else(|_| HeaderValue::from_static("yes"));
+    let mut headers = vec![(HeaderName::from_static("x-wrote-to-file"), wrote_to_file)];
+    for (_, header, count) in counts.fields() {
+        headers.push((HeaderName::from_static(header), HeaderValue::from(count)));
+    }
+    headers.extend(applied);
+    df_response(result, format, headers)
+}
+
+// Whether the request asks (with `Prefer: return=minimal`) for an ingest's response to leave the dataset's rows out,
+// as a client that only needs to know the payload went in would
+fn prefers_minimal(headers: &HeaderMap) -> bool {
+    headers
+        .get_all("prefer")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|preference| preference.trim().eq_ignore_ascii_case("return=minimal"))
+}
+
+// handler that reports what the background writer has (and hasn't yet) written to disk
+async fn flush_status(State(state): State<Arc<AppState>>) -> Json<FlushResponse> {
+    Json(FlushResponse {
+        status: Status::Success,
+        flush: state.writer.status(),
+    })
+}
+
+// handler that writes out everything queued so far and waits for it to finish
+async fn flush(State(state): State<Arc<AppState>>) -> Result<Json<FlushResponse>, AppError> {
+    let status = state.writer.flush().await.map_err(AppError::Internal)?;
+
+    Ok(Json(FlushResponse {
+        status: Status::Success,
+        flush: status,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct AuditParams {
+    // Only entries for this dataset, or this operation (`collate`, `delete`, ...)
+    dataset: Option<String>,
+    operation: Option<String>,
+    // Index of the first matching entry to return
+    #[serde(default)]
+    offset: usize,
+    // Maximum number of entries to return (defaults to all remaining ones)
+    limit: Option<usize>,
+}
+
+async fn audit_entries(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<AuditParams>,
+) -> Result<Json<AuditResponse>, AppError> {
+    if state.audit.is_none() {
+        return Err(AppError::BadRequest(String::from(
+            "The audit log is disabled; set `audit.file` (or --audit-file) to enable it",
+        )));
+    }
+    let query = AuditQuery {
+        dataset: params.dataset,
+        operation: params.operation,
+        offset: params.offset,
+        limit: params.limit,
+    };
+    // The file can be large, so it's read on the blocking thread pool
+    let read = move || state.audit.as_ref().map_or(Ok((Vec::new(), 0)), |audit| audit.read(&query));
+    let (entries, total) = tokio::task::spawn_blocking(read)
+        .await
+        .map_err(|e| AppError::Internal(format!("Reading the audit file failed: {}", e)))?
+        .map_err(AppError::Internal)?;
+
+    Ok(Json(AuditResponse {
+        status: Status::Success,
+        total,
+        entries,
+    }))
+}
+
+async fn admin_stats(State(state): State<Arc<AppState>>) -> Json<StatsResponse> {
+    Json(stats::stats(&state).await)
+}
+
+async fn get_log_level() -> Json<LogLevelResponse> {
+    Json(LogLevelResponse {
+        status: Status::Success,
+        filter: logging::current_log_level(),
+        previous: None,
+    })
+}
+
+async fn put_log_level(body: Upload) -> Result<Json<LogLevelResponse>, AppError> {
+    let body = body.into_bytes().await?;
+    let request: LogLevelRequest =
+        serde_json::from_slice(&body).map_err(|e| AppError::BadRequest(format!("Invalid log level: {}", e)))?;
+    let previous = logging::set_log_level(&request.filter).map_err(AppError::BadRequest)?;
+    warn!("The log level was changed from {:?} to {:?}", previous, request.filter);
+
+    Ok(Json(LogLevelResponse {
+        status: Status::Success,
+        filter: logging::current_log_level(),
+        previous: Some(previous),
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct RollbackParams {
+    // How many of the latest ingests to undo (1 if not set)
+    batches: Option<usize>,
+    // The dataset to undo them in (the default one if not set)
+    dataset: Option<String>,
+}
+
+// Put a dataset back as it was before its latest ingests, to undo a bad payload. As with `delete_rows`, the output file
+// is rewritten in `append` and `snapshot` mode, and left alone in `overwrite` mode.
+async fn rollback(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<RollbackParams>,
+) -> Result<Json<RollbackResponse>, AppError> {
+    let name = params.dataset.as_deref().unwrap_or(DEFAULT_DATASET);
+    audit::note_dataset(name);
+    let dataset = state.existing_dataset(name).await.ok_or_else(|| AppError::dataset_not_found(name))?;
+    let count = params.batches.unwrap_or(1);
+
+    let mut dataset = dataset.write().await;
+    dataset.check_rollback(count).map_err(AppError::BadRequest)?;
+    let before = dataset.df.as_ref().map_or(0, DataFrame::height) + dataset.spilled.rows();
+
+    log_payload(&state, name, &Operation::Rollback { batches: count }, &DataFrame::empty()).await?;
+    // Spilling forgets the ingests before it, so the rows it spilled are still the dataset's after this
+    let batches = dataset.roll_back(count, Some(chrono::Utc::now()));
+    let rows = dataset.df.as_ref().map_or(0, DataFrame::height) + dataset.spilled.rows();
+    audit::note_removed(before.saturating_sub(rows));
+    state.events.changed(name, "rollback", &DataFrame::empty(), rows);
+    warn!("Rolled back the latest {} ingests into {:?}, which has {} rows left", batches.len(), name, rows);
+
+    if state.config.storage.write_mode != WriteMode::Overwrite {
+        dataset.unspill().map_err(AppError::Internal)?;
+        let state_df = dataset.df.clone().unwrap_or_default();
+        persist_result(&state.writer, &dataset, WriteMode::Snapshot, state_df).await?;
+    }
+
+    Ok(Json(RollbackResponse {
+        status: Status::Success,
+        dataset: name.to_string(),
+        rolled_back: batches
+            .iter()
+            .map(|batch| RolledBackBatch {
+                operation: batch.operation.to_string(),
+                rows: batch.rows,
+            })
+            .collect(),
+        rows,
+    }))
+}
+
+// handler that reports how far behind the replicas are, or on a replica, how far behind its primary it is
+#[axum_macros::debug_handler]
+async fn replication_status(State(state): State<Arc<AppState>>) -> Json<ReplicationStatusResponse> {
+    Json(state.replication.status().await)
+}
+
+// handler that applies a batch of a primary's changes on a replica, returning how far it has got
+#[axum_macros::debug_handler]
+async fn replication_apply(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: Upload,
+) -> Result<Json<ReplicationAck>, AppError> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok()).unwrap_or_default().to_string();
+    let latest = header(replication::LATEST_HEADER).parse().unwrap_or(0);
+    let body = body.into_bytes().await?;
+
+    replication::apply(&state, &header(replication::STREAM_HEADER), latest, &body).await.map(Json)
+}
+
+// handler that makes a replica take changes from clients, as the primary
+#[axum_macros::debug_handler]
+async fn replication_promote(State(state): State<Arc<AppState>>) -> Json<ReplicationStatusResponse> {
+    state.replication.promote();
+    Json(state.replication.status().await)
+}
+
+// handler that reports request, ingest, dataset and flush metrics in the Prometheus text format
+async fn metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let datasets = dataset_gauges(&state).await;
+    let body = state.metrics.render(&datasets, state.writer.status().pending_rows);
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")], body)
+}
+
+// handler that reports what each client has sent since the service started, the ones that sent the most bytes first
+async fn sources(State(state): State<Arc<AppState>>) -> Json<SourcesResponse> {
+    Json(state.accounting.report(&state.config.quotas))
+}
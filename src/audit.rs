@@ -0,0 +1,222 @@
+// The audit log (`[audit]`): an append-only file with a line for every request that changed a dataset (collates,
+// aggregates, deletes, resets, ...), saying when, who sent it (their address and API key), what it did to how many
+// rows, and the SHA-256 of the payload, so every change to a dataset can be traced back to the request behind it.
+// Requests that were turned down are recorded too, with their status. `GET /audit` reads it back.
+
+use std::{
+    cell::RefCell,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{SecondsFormat, Utc};
+use log::error;
+
+use crate::{
+    api::unversioned,
+    auth::KeyId,
+    dataset::{AppState, DEFAULT_DATASET},
+    logging::{self, RequestId},
+    rate_limit::client_ip,
+    types::AuditEntry,
+};
+
+// The requests that change a dataset, by method and route (the route as it's served on the default dataset), and the
+// operation they're recorded as
+const AUDITED: &[(&str, &str, &str)] = &[
+    ("POST", "/collate", "collate"),
+    ("POST", "/collate/batch", "collate_batch"),
+    ("POST", "/write", "write"),
+    ("POST", "/api/v2/write", "write"),
+    ("POST", "/api/v1/write", "remote_write"),
+    ("POST", "/aggregate", "aggregate"),
+    ("POST", "/upsert", "upsert"),
+    ("POST", "/collate_wide", "collate_wide"),
+    ("DELETE", "/data", "delete"),
+    ("POST", "/reset", "reset"),
+    ("POST", "/dedup", "dedup"),
+    ("POST", "/fill_nulls", "fill_nulls"),
+    ("POST", "/drop_nulls", "drop_nulls"),
+    ("POST", "/outliers", "flag_outliers"),
+    ("DELETE", "/quarantine", "clear_quarantine"),
+    ("POST", "/snapshots/{id}/restore", "restore"),
+    ("PUT", "/schema", "set_schema"),
+    ("DELETE", "/schema", "remove_schema"),
+    ("PUT", "/columns/computed", "set_computed"),
+    ("DELETE", "/columns/computed", "remove_computed"),
+    // `DELETE /datasets/{name}`
+    ("DELETE", "", "delete_dataset"),
+    ("POST", "/admin/rollback", "rollback"),
+];
+
+// Where the routes of a named dataset are served
+const DATASET_PREFIX: &str = "/datasets/{name}";
+
+tokio::task_local! {
+    // What the request being audited has done so far, noted by the code doing it
+    static NOTES: RefCell<Notes>;
+}
+
+#[derive(Debug, Default)]
+struct Notes {
+    // The dataset, when it isn't in the route (`/write?db=...`)
+    dataset: Option<String>,
+    rows_removed: Option<usize>,
+    payload: Option<(usize, String)>,
+}
+
+// The audit file, opened for appending
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+// Which entries `AuditLog::read` returns
+#[derive(Debug, Default)]
+pub struct AuditQuery {
+    pub dataset: Option<String>,
+    pub operation: Option<String>,
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).map_err(|e| format!("Can't create {:?}: {}", dir, e))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Can't open the audit file {:?}: {}", path, e))?;
+
+        Ok(AuditLog {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+
+    // Add an entry. Each is written with a single `write` on a file opened for appending, so lines never interleave.
+    fn append(&self, entry: &AuditEntry) -> Result<(), String> {
+        let mut line = serde_json::to_vec(entry).map_err(|e| format!("Can't serialize an audit entry: {}", e))?;
+        line.push(b'\n');
+        self.file
+            .lock()
+            .unwrap()
+            .write_all(&line)
+            .map_err(|e| format!("Can't write to the audit file {:?}: {}", self.path, e))
+    }
+
+    // The entries matching the query, oldest first, and how many matched before `offset` and `limit` were applied
+    pub fn read(&self, query: &AuditQuery) -> Result<(Vec<AuditEntry>, usize), String> {
+        let file = File::open(&self.path).map_err(|e| format!("Can't read the audit file {:?}: {}", self.path, e))?;
+
+        let mut entries = Vec::new();
+        let mut total = 0;
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| format!("Can't read the audit file {:?}: {}", self.path, e))?;
+            // A line cut short by a crash while it was written is the last one, and is skipped
+            let Ok(entry) = serde_json::from_str::<AuditEntry>(&line) else {
+                error!("Skipping line {} of the audit file {:?}, which isn't an entry", number + 1, self.path);
+                continue;
+            };
+            if query.dataset.as_ref().is_some_and(|dataset| *dataset != entry.dataset)
+                || query.operation.as_ref().is_some_and(|operation| *operation != entry.operation)
+            {
+                continue;
+            }
+
+            if total >= query.offset && query.limit.is_none_or(|limit| entries.len() < limit) {
+                entries.push(entry);
+            }
+            total += 1;
+        }
+
+        Ok((entries, total))
+    }
+}
+
+// Note the dataset a request changed, when its route doesn't say which
+pub(crate) fn note_dataset(dataset: &str) {
+    let _ = NOTES.try_with(|notes| notes.borrow_mut().dataset = Some(dataset.to_string()));
+}
+
+// Note how many rows a request removed from the dataset
+pub(crate) fn note_removed(rows: usize) {
+    let _ = NOTES.try_with(|notes| notes.borrow_mut().rows_removed = Some(rows));
+}
+
+// Whether the request being handled is audited, so its payload needs hashing
+pub(crate) fn wants_payload() -> bool {
+    NOTES.try_with(|_| ()).is_ok()
+}
+
+// Note the size and SHA-256 (hex) of the request body, as it was received
+pub(crate) fn note_payload(bytes: usize, sha256: String) {
+    let _ = NOTES.try_with(|notes| notes.borrow_mut().payload = Some((bytes, sha256)));
+}
+
+// Middleware recording the requests in `AUDITED` once they're handled, when there's an audit file
+pub(crate) async fn record_changes(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    let Some(audit) = &state.audit else {
+        return next.run(request).await;
+    };
+    let route = request.extensions().get::<MatchedPath>().map_or("", |path| path.as_str()).to_string();
+    let unprefixed = unversioned(&route);
+    let (named, plain) = match unprefixed.strip_prefix(DATASET_PREFIX) {
+        Some(rest) => (true, rest),
+        None => (false, unprefixed),
+    };
+    let method = request.method().to_string();
+    let Some(&(_, _, operation)) = AUDITED.iter().find(|(m, r, _)| *m == method && *r == plain) else {
+        return next.run(request).await;
+    };
+
+    // The dataset's name is the path segment where the route has `{name}`
+    let dataset = named.then(|| {
+        let depth = route.len() - unprefixed.len();
+        request.uri().path()[depth..].split('/').nth(2).unwrap_or_default().to_string()
+    });
+    let peer = request.extensions().get::<ConnectInfo<SocketAddr>>().map(|info| info.0);
+    let client = client_ip(&state, request.headers(), peer).map_or(String::from("unknown"), |ip| ip.to_string());
+    let key = request.extensions().get::<KeyId>().map(|key| key.0.clone());
+    let request_id = request.extensions().get::<RequestId>().map(|id| id.0.clone());
+
+    let handled = async {
+        let response = next.run(request).await;
+        (response, NOTES.with(|notes| std::mem::take(&mut *notes.borrow_mut())))
+    };
+    let (response, notes) = NOTES.scope(RefCell::new(Notes::default()), handled).await;
+
+    let ingested = logging::ingested();
+    let entry = AuditEntry {
+        timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        request_id,
+        operation: operation.to_string(),
+        dataset: dataset.or(notes.dataset).unwrap_or_else(|| DEFAULT_DATASET.to_string()),
+        method,
+        route,
+        status: response.status().as_u16(),
+        client,
+        key,
+        rows_ingested: (ingested > 0).then_some(ingested),
+        rows_removed: notes.rows_removed,
+        payload_bytes: notes.payload.as_ref().map(|(bytes, _)| *bytes),
+        payload_sha256: notes.payload.map(|(_, sha256)| sha256),
+    };
+    if let Err(message) = audit.append(&entry) {
+        error!("{} (the {} request {:?} isn't audited)", message, entry.operation, entry.request_id);
+    }
+
+    response
+}
@@ -0,0 +1,123 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, HeaderMap, Method},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::{
+    api::unversioned,
+    config::AuthConfig,
+    dataset::AppState,
+    digest::{hex, sha256},
+    error::AppError,
+};
+
+// Routes that stay open without a key: `GET /` and the probes, so load balancers and orchestrators can check on the
+// service, and the API's description and the dashboard, which hold nothing a key would protect (the dashboard asks for
+// one to read the datasets with). Under `/v1` too.
+pub const PUBLIC_PATHS: &[&str] = &["/", "/healthz", "/livez", "/readyz", "/openapi.json", "/docs", "/ui"];
+
+// What a key is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+    // Read datasets, schemas, and status (`GET` requests)
+    Read,
+    // Everything a read key can do, plus changing datasets (`POST`, `PUT`, `DELETE`)
+    Write,
+}
+
+// Which API key a request was sent with, without the key itself: the first 16 hex digits of its SHA-256, for the audit
+// log
+#[derive(Debug, Clone)]
+pub struct KeyId(pub String);
+
+impl KeyId {
+    fn of(key: &str) -> Self {
+        KeyId(hex(&sha256(key.as_bytes())[..8]))
+    }
+}
+
+impl AuthConfig {
+    // Authentication is only enforced once at least one key is configured
+    pub fn enabled(&self) -> bool {
+        !self.read_keys.is_empty() || !self.write_keys.is_empty()
+    }
+
+    // The scope a key grants, if it's a known key
+    pub fn scope_of(&self, key: &str) -> Option<Scope> {
+        // Check every key, so how long this takes doesn't depend on which one (if any) matched
+        let write = self.write_keys.iter().fold(false, |found, known| constant_time_eq(known, key) | found);
+        let read = self.read_keys.iter().fold(false, |found, known| constant_time_eq(known, key) | found);
+
+        if write {
+            Some(Scope::Write)
+        } else if read {
+            Some(Scope::Read)
+        } else {
+            None
+        }
+    }
+}
+
+// The scope a request needs: reads for `GET`/`HEAD`, writes for anything else
+fn required_scope(method: &Method) -> Scope {
+    if method == Method::GET || method == Method::HEAD {
+        Scope::Read
+    } else {
+        Scope::Write
+    }
+}
+
+// Middleware checking `Authorization: Bearer <key>` against the configured keys, except on `PUBLIC_PATHS`
+pub async fn require_key(State(state): State<Arc<AppState>>, mut request: Request, next: Next) -> Result<Response, AppError> {
+    let auth = &state.config.auth;
+    let public =
+        request.extensions().get::<MatchedPath>().is_some_and(|path| PUBLIC_PATHS.contains(&unversioned(path.as_str())));
+    if !auth.enabled() || public {
+        return Ok(next.run(request).await);
+    }
+
+    let key = request_key(request.headers())
+        .ok_or_else(|| AppError::Unauthorized(String::from("An `Authorization: Bearer <key>` header is required")))?;
+
+    let scope = auth
+        .scope_of(key)
+        .ok_or_else(|| AppError::Unauthorized(String::from("The API key is not valid")))?;
+
+    let key_id = KeyId::of(key);
+    let required = required_scope(request.method());
+    if scope < required {
+        return Err(AppError::Forbidden(format!(
+            "This API key can only read; {} requests need a key with write access",
+            request.method()
+        )));
+    }
+    // A WebSocket is opened with a `GET`, so it checks for itself whether the key lets it change the dataset
+    request.extensions_mut().insert(scope);
+    request.extensions_mut().insert(key_id);
+
+    Ok(next.run(request).await)
+}
+
+// The API key a request was sent with (`Authorization: Bearer <key>`), whether or not it's a known one
+pub fn request_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        // InfluxDB clients send `Token <key>` instead
+        .and_then(|value| value.strip_prefix("Bearer ").or_else(|| value.strip_prefix("Token ")))
+        .map(str::trim)
+}
+
+// Compare two strings without returning early at the first difference
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,121 @@
+// Barriers (`POST /await`): a request that's held open until a dataset has collated a given number of batches, or
+// holds rows from a given number of distinct values of a key column (ranks, hosts, ...), so a driver script can wait
+// for the stragglers of a collection round without polling in a sleep loop.
+
+use std::{collections::HashSet, time::Duration};
+
+use polars::prelude::*;
+use tokio::{
+    sync::broadcast::error::RecvError,
+    time::{sleep, timeout_at, Instant},
+};
+
+use crate::{completeness, dataset::AppState, error::AppError, events::Event};
+
+// How long a barrier waits when the request doesn't say, and how long it may ask for
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+// How often the batch count is checked
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// What a barrier waits for
+#[derive(Debug, Clone)]
+pub enum Target {
+    // This many payloads collated into the dataset from when the barrier was set
+    Batches(u64),
+    // Rows from this many distinct values of a column, counting the ones the dataset already holds
+    Distinct { column: String, count: usize },
+}
+
+// Parse a timeout like `300s`, `5m`, `1h`, `500ms`, or a number of seconds
+pub fn parse_timeout(timeout: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid timeout {:?} (expected e.g. 30s, 5m, or 1h)", timeout);
+    let timeout = timeout.trim();
+    let split = timeout.find(|c: char| !c.is_ascii_digit()).unwrap_or(timeout.len());
+    let (amount, unit) = timeout.split_at(split);
+    let amount: u64 = amount.parse().map_err(|_| invalid())?;
+    let parsed = match unit {
+        "ms" => Duration::from_millis(amount),
+        "" | "s" => Duration::from_secs(amount),
+        "m" => Duration::from_secs(amount.saturating_mul(60)),
+        "h" => Duration::from_secs(amount.saturating_mul(60 * 60)),
+        _ => return Err(invalid()),
+    };
+    if parsed.is_zero() || parsed > MAX_TIMEOUT {
+        return Err(format!("The timeout must be above 0, and at most {}s", MAX_TIMEOUT.as_secs()));
+    }
+    Ok(parsed)
+}
+
+// Wait until the dataset reaches the target, returning how far it got: the batches collated since the barrier was set,
+// or the distinct values. Gives up with a `408` after `timeout`.
+pub async fn wait(state: &AppState, name: &str, target: &Target, timeout: Duration) -> Result<usize, AppError> {
+    let deadline = Instant::now() + timeout;
+    let reached = match target {
+        Target::Batches(expect) => timeout_at(deadline, batches(state, name, *expect)).await,
+        Target::Distinct { column, count } => timeout_at(deadline, distinct(state, name, column, *count)).await,
+    };
+    reached.unwrap_or_else(|_| {
+        Err(AppError::RequestTimeout(format!(
+            "Dataset {:?} didn't reach {} within {}s",
+            name,
+            describe(target),
+            timeout.as_secs_f64()
+        )))
+    })
+}
+
+fn describe(target: &Target) -> String {
+    match target {
+        Target::Batches(expect) => format!("{} batches", expect),
+        Target::Distinct { column, count } => format!("{} distinct values of {:?}", count, column),
+    }
+}
+
+// Payloads are counted as they're accepted, after their rows are in the dataset
+async fn batches(state: &AppState, name: &str, expect: u64) -> Result<usize, AppError> {
+    let start = state.metrics.payloads(name);
+    loop {
+        let collated = state.metrics.payloads(name).saturating_sub(start);
+        if collated >= expect {
+            return Ok(collated as usize);
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+// Values are counted as text, once from the dataset's rows, and then from the rows of each change it gets. A change that
+// removes rows (or one that's missed) has them counted from the dataset again.
+async fn distinct(state: &AppState, name: &str, column: &str, count: usize) -> Result<usize, AppError> {
+    // Following the changes before counting, so none are missed in between
+    let mut events = state.events.subscribe();
+    let mut seen = completeness::reported(state, name, column, true).await?;
+    while seen.len() < count {
+        let recount = match events.recv().await {
+            Ok(event) => match &*event {
+                Event::Changed(change) if change.dataset == name && change.rows.height() == 0 => true,
+                Event::Changed(change) if change.dataset == name => {
+                    extend(&mut seen, &change.rows, column);
+                    false
+                }
+                _ => false,
+            },
+            Err(RecvError::Lagged(_)) => true,
+            Err(RecvError::Closed) => return Err(AppError::Internal(String::from("The service is shutting down"))),
+        };
+        if recount {
+            seen = completeness::reported(state, name, column, false).await?;
+        }
+    }
+    Ok(seen.len())
+}
+
+fn extend(seen: &mut HashSet<String>, rows: &DataFrame, column: &str) {
+    let values = rows.column(column).and_then(|values| values.cast(&DataType::String));
+    if let Ok(values) = values
+        && let Ok(values) = values.str()
+    {
+        seen.extend(values.into_iter().flatten().map(String::from));
+    }
+}
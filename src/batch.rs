@@ -0,0 +1,73 @@
+use std::io::Cursor;
+
+use axum::http::{header, HeaderMap, HeaderValue};
+use polars::prelude::DataFrame;
+use serde::{Deserialize, Serialize};
+
+use crate::payload::{read_payload, ARROW_FILE_CONTENT_TYPE, ARROW_STREAM_CONTENT_TYPE};
+use crate::types::Status;
+
+// One payload of a `/collate/batch` envelope: a line of its NDJSON body
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchEntry {
+    // Echoed back in the batch's result, to tell the batches apart
+    #[serde(default)]
+    pub id: Option<String>,
+    // The payload as it'd be sent to `/collate`, e.g. a CSV file's contents
+    pub payload: String,
+    // How `payload` is formatted (CSV by default), like a `/collate` request's `Content-Type`
+    #[serde(default)]
+    pub content_type: Option<String>,
+}
+
+impl BatchEntry {
+    pub fn read(&self) -> Result<DataFrame, String> {
+        let mut headers = HeaderMap::new();
+        if let Some(content_type) = &self.content_type {
+            if matches!(content_type.trim(), ARROW_STREAM_CONTENT_TYPE | ARROW_FILE_CONTENT_TYPE) {
+                return Err(String::from("Arrow payloads are binary, so they can't be sent in a batch"));
+            }
+            let value =
+                HeaderValue::from_str(content_type).map_err(|_| format!("Bad content_type {:?}", content_type))?;
+            headers.insert(header::CONTENT_TYPE, value);
+        }
+
+        read_payload(&headers, Cursor::new(self.payload.as_bytes()))
+    }
+}
+
+// What happened to one payload of a batch
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchResult {
+    // Position of the payload in the envelope, from 0
+    pub index: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    // `success`, `error`, or `skipped` (not checked, because an earlier payload already failed)
+    pub status: Status,
+    // Rows of the payload that passed validation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rows: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quarantined: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// The payloads of an NDJSON envelope, in order. Blank lines are ignored.
+pub fn parse_envelope(body: &[u8]) -> Result<Vec<BatchEntry>, String> {
+    let mut entries = Vec::new();
+    for (i, line) in body.split(|byte| *byte == b'\n').enumerate() {
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        let entry = serde_json::from_slice(line).map_err(|e| format!("Error parsing envelope line {}: {}", i + 1, e))?;
+        entries.push(entry);
+    }
+    if entries.is_empty() {
+        return Err(String::from("The envelope has no payloads"));
+    }
+
+    Ok(entries)
+}
@@ -0,0 +1,239 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use chrono::Utc;
+use log::{error, warn};
+use polars::prelude::*;
+use tokio::{sync::mpsc, time::Instant};
+
+use crate::{
+    config::ClickHouseConfig,
+    http::{self, percent_encode, Endpoint},
+    metrics::Metrics,
+    payload::{write_df, FileFormat},
+    sink::{backoff, QUEUE_CAPACITY},
+};
+
+// Rows a dataset just accepted, to be inserted into its table
+#[derive(Debug)]
+struct Mirror {
+    dataset: String,
+    df: DataFrame,
+}
+
+// Handle to the background task that streams every batch of rows the datasets accept into ClickHouse tables, over
+// its HTTP interface. Rows are buffered and sent as one insert per table, since ClickHouse much prefers few big
+// inserts to many small ones. Delivery is at least once: an insert that fails for a reason that could pass (the
+// server is down, overloaded, ...) is retried until it's acknowledged.
+#[derive(Debug, Clone)]
+pub struct ClickHouseSink {
+    tx: mpsc::Sender<Mirror>,
+    // Where the server is, so `/readyz` can check it's reachable
+    server: (String, u16),
+    // Datasets that are streamed (every one, if empty)
+    datasets: Vec<String>,
+    metrics: Arc<Metrics>,
+}
+
+impl ClickHouseSink {
+    // Start the sink task, if a ClickHouse URL is configured. Must be called from within the Tokio runtime.
+    pub fn spawn(config: &ClickHouseConfig, metrics: Arc<Metrics>) -> Option<Self> {
+        let endpoint = Endpoint::parse(config.url.as_deref()?).expect("the URL is checked by Config::validate");
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        let server = (endpoint.host.clone(), endpoint.port);
+
+        tokio::spawn(run(rx, endpoint, config.clone(), metrics.clone()));
+
+        Some(ClickHouseSink {
+            tx,
+            server,
+            datasets: config.datasets.clone(),
+            metrics,
+        })
+    }
+
+    // The server's host and port
+    pub fn server(&self) -> (&str, u16) {
+        (&self.server.0, self.server.1)
+    }
+
+    // Queue a dataset's new rows to be inserted. If the queue is full (ClickHouse has been unreachable for a while),
+    // they're dropped rather than holding up the request.
+    pub fn submit(&self, dataset: &str, df: &DataFrame) {
+        if df.height() == 0 || !(self.datasets.is_empty() || self.datasets.iter().any(|name| name == dataset)) {
+            return;
+        }
+
+        let mirror = Mirror {
+            dataset: dataset.to_string(),
+            df: df.clone(),
+        };
+        if let Err(e) = self.tx.try_send(mirror) {
+            let rows = match &e {
+                mpsc::error::TrySendError::Full(mirror) | mpsc::error::TrySendError::Closed(mirror) => mirror.df.height(),
+            };
+            error!("Dropping {} rows of {:?}: the ClickHouse sink is {} batches behind", rows, dataset, QUEUE_CAPACITY);
+            self.metrics.record_sink("clickhouse", 0, rows);
+        }
+    }
+}
+
+#[derive(Debug)]
+enum InsertError {
+    // Worth trying again: the connection failed, or the server had a problem of its own (a `5xx`, or too many
+    // queries at once)
+    Transient(String),
+    // The insert is wrong (a missing table or column, a value that doesn't parse, bad credentials) and would fail the
+    // same way every time
+    Rejected(String),
+}
+
+async fn run(mut rx: mpsc::Receiver<Mirror>, endpoint: Endpoint, config: ClickHouseConfig, metrics: Arc<Metrics>) {
+    let interval = Duration::from_millis(config.flush_interval_ms);
+    // Batches waiting to be sent, by dataset
+    let mut buffer: BTreeMap<String, Vec<DataFrame>> = BTreeMap::new();
+    let mut buffered_rows = 0;
+    // When what's buffered is sent, at the latest
+    let mut deadline = Instant::now();
+    // Makes each insert's deduplication token unique to this run
+    let run_id = Utc::now().format("%Y%m%dT%H%M%S%.3fZ").to_string();
+    let mut inserts = 0u64;
+
+    loop {
+        // Wait for more rows, or until the oldest buffered ones have waited `flush_interval_ms`
+        let received = if buffer.is_empty() {
+            match rx.recv().await {
+                Some(mirror) => Some(mirror),
+                None => break,
+            }
+        } else {
+            // Once the interval is up (or the service is shutting down), what's buffered is sent
+            tokio::time::timeout_at(deadline, rx.recv()).await.ok().flatten()
+        };
+
+        if let Some(mirror) = received {
+            if buffer.is_empty() {
+                deadline = Instant::now() + interval;
+            }
+            buffered_rows += mirror.df.height();
+            buffer.entry(mirror.dataset).or_default().push(mirror.df);
+            if buffered_rows < config.flush_rows {
+                continue;
+            }
+        }
+
+        for (dataset, frames) in std::mem::take(&mut buffer) {
+            inserts += 1;
+            let token = format!("data_collator-{}-{}", run_id, inserts);
+            insert_with_retries(&endpoint, &config, &metrics, &dataset, frames, &token).await;
+        }
+        buffered_rows = 0;
+    }
+}
+
+// Insert a dataset's buffered batches as one insert, retrying for as long as the failure could pass. Every attempt
+// carries the same deduplication token, so a table that deduplicates inserts keeps one copy even if an attempt that
+// looked failed actually went through.
+async fn insert_with_retries(
+    endpoint: &Endpoint,
+    config: &ClickHouseConfig,
+    metrics: &Metrics,
+    dataset: &str,
+    frames: Vec<DataFrame>,
+    token: &str,
+) {
+    let rows = frames.iter().map(DataFrame::height).sum::<usize>();
+    let body = match insert_body(config, frames) {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Dropping {} rows of {:?} that can't be sent to ClickHouse: {}", rows, dataset, e);
+            metrics.record_sink("clickhouse", 0, rows);
+            return;
+        }
+    };
+
+    let mut attempt = 0;
+    loop {
+        match insert(endpoint, config, dataset, &body, token).await {
+            Ok(()) => {
+                metrics.record_sink("clickhouse", rows, 0);
+                return;
+            }
+            Err(InsertError::Transient(e)) => {
+                let delay = backoff(config.retry_backoff_ms, attempt);
+                attempt += 1;
+                warn!(
+                    "Can't insert {} rows of {:?} into ClickHouse ({}), retrying in {:?} (attempt {})",
+                    rows, dataset, e, delay, attempt
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(InsertError::Rejected(e)) => {
+                error!("Dropping {} rows of {:?} that ClickHouse rejected: {}", rows, dataset, e);
+                metrics.record_sink("clickhouse", 0, rows);
+                return;
+            }
+        }
+    }
+}
+
+async fn insert(
+    endpoint: &Endpoint,
+    config: &ClickHouseConfig,
+    dataset: &str,
+    body: &[u8],
+    token: &str,
+) -> Result<(), InsertError> {
+    let table = config.table.replace("{dataset}", dataset);
+    let query = format!("INSERT INTO {}.{} FORMAT CSVWithNames", quote_identifier(&config.database), quote_identifier(&table));
+    // Dates and times in whatever format the rows have them, rather than only `YYYY-MM-DD hh:mm:ss`
+    let target = format!(
+        "{}/?query={}&date_time_input_format=best_effort&insert_deduplication_token={}",
+        endpoint.path,
+        percent_encode(&query),
+        percent_encode(token)
+    );
+
+    let mut headers = vec![("Content-Type", String::from("text/csv"))];
+    if let Some(user) = &config.user {
+        headers.push(("X-ClickHouse-User", user.clone()));
+    }
+    if let Some(password) = &config.password {
+        headers.push(("X-ClickHouse-Key", password.clone()));
+    }
+
+    let response = http::send(endpoint, "POST", &target, &headers, body).await.map_err(InsertError::Transient)?;
+    if response.is_success() {
+        return Ok(());
+    }
+
+    let message = format!("{} {}", response.status, String::from_utf8_lossy(&response.body).trim());
+    if response.status >= 500 || matches!(response.status, 408 | 429) {
+        Err(InsertError::Transient(message))
+    } else {
+        Err(InsertError::Rejected(message))
+    }
+}
+
+// The rows of every buffered batch as one CSV file with a header, the columns renamed to the table's
+fn insert_body(config: &ClickHouseConfig, frames: Vec<DataFrame>) -> Result<Vec<u8>, String> {
+    let args = UnionArgs {
+        rechunk: true,
+        to_supertypes: true,
+        ..Default::default()
+    };
+    let mut df = concat_lf_diagonal(frames.into_iter().map(DataFrame::lazy).collect::<Vec<_>>(), args)
+        .and_then(|lf| lf.collect())
+        .map_err(|e| e.to_string())?;
+
+    for (from, to) in &config.columns {
+        if df.column(from).is_ok() {
+            df.rename(from, to.as_str().into()).map_err(|e| e.to_string())?;
+        }
+    }
+
+    write_df(&mut df, FileFormat::Csv)
+}
+
+fn quote_identifier(name: &str) -> String {
+    format!("`{}`", name.replace('\\', "\\\\").replace('`', "\\`"))
+}
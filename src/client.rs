@@ -0,0 +1,262 @@
+// A client for the HTTP API, for Rust services that send rows to a running collator (or read them back) rather than
+// embed one. DataFrames go both ways as Arrow IPC streams, so their dtypes survive the trip. Requests that fail in a
+// way that's worth retrying are sent again after a growing wait, and every ingest carries an `Idempotency-Key` kept
+// across its retries, so one whose first attempt got through after all isn't collated twice.
+
+use std::{fmt, io::Cursor, time::Duration};
+
+use polars::prelude::*;
+
+use crate::{
+    aggregate::AggregateSpec,
+    api::VERSION_PREFIX,
+    collator::ReadOptions,
+    dataset::DEFAULT_DATASET,
+    http::{self, percent_encode, Endpoint},
+    payload::{write_df, FileFormat, ARROW_STREAM_CONTENT_TYPE},
+    types::{AggregateRequest, ErrorResponse},
+};
+
+// Statuses that mean the same request may well succeed later: rate limited, or the service (or a proxy in front of
+// it) is restarting or overloaded
+const RETRYABLE_STATUSES: &[u16] = &[429, 502, 503, 504];
+
+// How many times a request is retried unless `with_retries` says otherwise
+const DEFAULT_RETRIES: u32 = 3;
+
+// The wait before the first retry, doubled for every one after it
+const FIRST_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// The longest wait between retries, whatever `Retry-After` asks for
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+// Why a request failed
+#[derive(Debug)]
+pub enum ClientError {
+    // The service couldn't be reached, or what it sent back isn't a response of the API's
+    Transport(String),
+    // The service turned the request down: the HTTP status, and the `error` kind and `message` of the error body
+    Rejected { status: u16, kind: String, message: String },
+}
+
+impl ClientError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            ClientError::Transport(_) => true,
+            ClientError::Rejected { status, .. } => RETRYABLE_STATUSES.contains(status),
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Transport(message) => f.write_str(message),
+            ClientError::Rejected { status, message, .. } => write!(f, "The collator responded {}: {}", status, message),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+// What became of the rows a collate or aggregate sent
+#[derive(Debug)]
+pub struct Ingested {
+    // The dataset's rows once they were added
+    pub dataset: DataFrame,
+    // Where they're being persisted (`no` when nowhere)
+    pub wrote_to_file: String,
+    // Rows that broke the dataset's validation rules, and were quarantined instead
+    pub quarantined: Option<usize>,
+    // Rows left out as duplicates (with `collate.skip_duplicates`)
+    pub skipped: Option<usize>,
+    // Whether the service had already accepted them, and this is the first attempt's response replayed
+    pub replayed: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    endpoint: Endpoint,
+    api_key: Option<String>,
+    retries: u32,
+}
+
+impl Client {
+    // A client for the service at `url`, e.g. `http://collator.example.edu:3000`
+    pub fn new(url: &str) -> Result<Self, ClientError> {
+        Ok(Client {
+            endpoint: Endpoint::parse(url).map_err(|e| ClientError::Transport(format!("Invalid URL {:?}: {}", url, e)))?,
+            api_key: None,
+            retries: DEFAULT_RETRIES,
+        })
+    }
+
+    // Send this API key with every request
+    pub fn with_api_key(mut self, key: &str) -> Self {
+        self.api_key = Some(key.to_string());
+        self
+    }
+
+    // How many times a request that fails (in a way worth retrying) is sent again; 0 never retries
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    // Add rows to a dataset (created on first use), as `POST /collate`
+    pub async fn collate(&self, dataset: &str, df: &mut DataFrame) -> Result<Ingested, ClientError> {
+        let body = write_df(df, FileFormat::Arrow).map_err(ClientError::Transport)?;
+        self.ingest(&route(dataset, "collate"), ARROW_STREAM_CONTENT_TYPE, body).await
+    }
+
+    // Add rows that are already CSV (with a header) to a dataset
+    pub async fn collate_csv(&self, dataset: &str, csv: &str) -> Result<Ingested, ClientError> {
+        self.ingest(&route(dataset, "collate"), "text/csv", csv.as_bytes().to_vec()).await
+    }
+
+    // Aggregate rows into a dataset (created on first use), as `POST /aggregate`. The rows go as CSV, which is all
+    // aggregates take.
+    pub async fn aggregate(&self, dataset: &str, df: &mut DataFrame, spec: &AggregateSpec) -> Result<Ingested, ClientError> {
+        let csv = write_df(df, FileFormat::Csv).map_err(ClientError::Transport)?;
+        let body = AggregateRequest {
+            key: None,
+            keys: Some(spec.keys.clone()),
+            op: Some(spec.default_op.name().to_string()),
+            ops: spec.ops.iter().map(|(column, op)| (column.clone(), op.name().to_string())).collect(),
+            csv: String::from_utf8_lossy(&csv).into_owned(),
+        };
+        let body = serde_json::to_vec(&body).map_err(|e| ClientError::Transport(e.to_string()))?;
+
+        let mut target = route(dataset, "aggregate");
+        if let Some(window) = &spec.window {
+            target.push_str(&query(&[
+                ("window", Some(window.period.clone())),
+                ("slide", Some(window.every.clone())),
+                ("time", Some(window.column.clone())),
+            ]));
+        }
+        self.ingest(&target, "application/json", body).await
+    }
+
+    // Read (part of) a dataset, as `GET /data`
+    pub async fn query(&self, dataset: &str, options: &ReadOptions) -> Result<DataFrame, ClientError> {
+        let target = route(dataset, "data")
+            + &query(&[
+                ("columns", options.columns.as_ref().map(|columns| columns.join(","))),
+                ("filter", options.filter.clone()),
+                ("sort", options.sort.clone()),
+                ("lookup", options.lookup.clone()),
+                ("offset", Some(options.offset.to_string())),
+                ("limit", options.limit.map(|limit| limit.to_string())),
+                ("as_of", options.as_of.clone()),
+                ("format", Some(String::from(FileFormat::Arrow.name()))),
+            ]);
+        let response = self.send("GET", &target, Vec::new(), &[]).await?;
+
+        read_arrow(&response.body)
+    }
+
+    // A whole dataset as a file in the given format, as `GET /export`
+    pub async fn export(&self, dataset: &str, format: FileFormat) -> Result<Vec<u8>, ClientError> {
+        let target = route(dataset, "export") + &query(&[("format", Some(String::from(format.name())))]);
+        let response = self.send("GET", &target, Vec::new(), &[]).await?;
+
+        Ok(response.body)
+    }
+
+    // Send a payload to an ingest route, asking for the dataset's new rows back as Arrow
+    async fn ingest(&self, target: &str, content_type: &str, body: Vec<u8>) -> Result<Ingested, ClientError> {
+        let headers = vec![
+            ("Content-Type", content_type.to_string()),
+            ("Accept", ARROW_STREAM_CONTENT_TYPE.to_string()),
+            // The same for every attempt, so the service can tell a retry from a new payload
+            ("Idempotency-Key", format!("{:032x}", rand::random::<u128>())),
+        ];
+        let response = self.send("POST", target, headers, &body).await?;
+
+        let count = |header: &str| response.header(header).and_then(|value| value.parse().ok());
+        Ok(Ingested {
+            dataset: read_arrow(&response.body)?,
+            wrote_to_file: response.header("x-wrote-to-file").unwrap_or("no").to_string(),
+            quarantined: count("x-quarantined-rows"),
+            skipped: count("x-skipped-rows"),
+            replayed: response.header("idempotent-replayed") == Some("true"),
+        })
+    }
+
+    // Send a request, retrying while it fails in a way worth retrying. A `Retry-After` from the service lengthens the
+    // wait before the next attempt.
+    async fn send(
+        &self,
+        method: &str,
+        target: &str,
+        mut headers: Vec<(&str, String)>,
+        body: &[u8],
+    ) -> Result<http::Response, ClientError> {
+        if let Some(key) = &self.api_key {
+            headers.push(("Authorization", format!("Bearer {}", key)));
+        }
+
+        let mut delay = FIRST_RETRY_DELAY;
+        let mut attempt = 0;
+        loop {
+            let (error, retry_after) = match http::send(&self.endpoint, method, target, &headers, body).await {
+                Ok(response) if response.is_success() => return Ok(response),
+                Ok(response) => {
+                    let retry_after = response.header("retry-after").and_then(|value| value.parse().ok());
+                    (rejection(&response), retry_after.map(Duration::from_secs))
+                }
+                Err(message) => (ClientError::Transport(message), None),
+            };
+            if attempt >= self.retries || !error.is_retryable() {
+                return Err(error);
+            }
+
+            tokio::time::sleep(retry_after.unwrap_or(delay).max(delay).min(MAX_RETRY_DELAY)).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+            attempt += 1;
+        }
+    }
+}
+
+// The path of a route on a dataset, in the API version the client was written against: `/v1/<route>` for the
+// default dataset, `/v1/datasets/<name>/<route>` for the others
+fn route(dataset: &str, route: &str) -> String {
+    if dataset == DEFAULT_DATASET {
+        format!("{}/{}", VERSION_PREFIX, route)
+    } else {
+        format!("{}/datasets/{}/{}", VERSION_PREFIX, percent_encode(dataset), route)
+    }
+}
+
+// A query string of the parameters that are set (empty if none are)
+fn query(params: &[(&str, Option<String>)]) -> String {
+    let params: Vec<String> = params
+        .iter()
+        .filter_map(|(name, value)| value.as_ref().map(|value| format!("{}={}", name, percent_encode(value))))
+        .collect();
+    if params.is_empty() { String::new() } else { format!("?{}", params.join("&")) }
+}
+
+// The error a failed response describes. Error bodies are JSON, but a proxy's (or a route that doesn't exist) may not
+// be.
+fn rejection(response: &http::Response) -> ClientError {
+    match serde_json::from_slice::<ErrorResponse>(&response.body) {
+        Ok(body) => ClientError::Rejected {
+            status: response.status,
+            kind: body.error,
+            message: body.message,
+        },
+        Err(_) => ClientError::Rejected {
+            status: response.status,
+            kind: String::new(),
+            message: String::from_utf8_lossy(&response.body).trim().to_string(),
+        },
+    }
+}
+
+fn read_arrow(body: &[u8]) -> Result<DataFrame, ClientError> {
+    IpcStreamReader::new(Cursor::new(body))
+        .finish()
+        .map_err(|e| ClientError::Transport(format!("The collator sent rows that can't be read: {}", e)))
+}
@@ -0,0 +1,762 @@
+// The collation engine on its own: what happens to rows on their way into a dataset (provenance, computed columns,
+// the schema, validation, the write-ahead log, persistence, ...), whether they arrive over HTTP, from a background
+// source, or from a service embedding `Collator`.
+
+use std::{collections::HashMap, path::Path, sync::Arc, time::Instant};
+
+use log::{trace, warn};
+use polars::prelude::*;
+use tokio::sync::RwLockWriteGuard;
+use tracing::Instrument;
+
+use crate::{
+    aggregate::{AggregateMode, AggregateSpec},
+    audit::AuditLog,
+    config::Config,
+    dataset::{validate_dataset_name, AppState, ConcatMode, Dataset, SharedDataset},
+    error::AppError,
+    filter::Filter,
+    history,
+    line_protocol,
+    load::{initial_datasets, load_initial_state},
+    lookup::LookupTable,
+    persist::WriteMode,
+    provenance::{self, Origin},
+    replication::Resumed,
+    sources,
+    spill::{self, Checkpoint},
+    types::IngestCounts,
+    wal::{self, Operation, Replicated, Wal},
+    writer::{FlushStatus, Write, Writer},
+};
+
+// Where the rows a `Collator` is given came from, for provenance
+const EMBEDDED_SOURCE: &str = "embedded";
+
+// A set of datasets, and everything `serve` does to the rows added to them. Cheap to clone: clones share the
+// datasets. Must be used from within the Tokio runtime, since persisting (and mirroring, ...) happens in background
+// tasks.
+#[derive(Clone)]
+pub struct Collator {
+    pub(crate) state: Arc<AppState>,
+}
+
+// Which of a dataset's rows `Collator::query` returns, the same as `/data`'s parameters
+#[derive(Debug, Clone, Default)]
+pub struct ReadOptions {
+    // Columns to return (all of them, if `None`)
+    pub columns: Option<Vec<String>>,
+    // Only return rows matching this filter, e.g. `latency_ms>100,status=ok`
+    pub filter: Option<String>,
+    // Columns to sort by before `offset` and `limit` apply, e.g. `latency_ms:desc,host`
+    pub sort: Option<String>,
+    // Left-join the rows against this lookup table after filtering
+    pub lookup: Option<String>,
+    // Index of the first row to return
+    pub offset: usize,
+    // Maximum number of rows to return (all remaining rows, if `None`)
+    pub limit: Option<usize>,
+    // Read the rows the dataset had at this time (RFC 3339) instead of the ones it has now
+    pub as_of: Option<String>,
+}
+
+impl Collator {
+    // Check the config, and load the datasets it starts out with: the input file, output files from the last run, and
+    // the write-ahead log replayed on top. Nothing is ingested from the background sources the config sets up (watched
+    // directories, subscriptions, ...) until `start_sources`.
+    pub fn open(config: Config) -> Result<Self, String> {
+        config.validate()?;
+
+        // Directory where named datasets are persisted
+        if let Some(dir) = &config.storage.datasets_dir {
+            std::fs::create_dir_all(dir).map_err(|e| format!("Can't create {:?}: {}", dir, e))?;
+        }
+
+        // Seed the default dataset from the input file, if one was given
+        let initial_df = config.storage.input.as_deref().map(load_initial_state).transpose()?;
+
+        // Pick up where the last run left off
+        let mut initial = initial_datasets(&config.storage, initial_df)?;
+
+        // Replay the write-ahead log on top of the input file
+        let (wal, resumed) = match config.storage.wal.as_deref() {
+            Some(path) => resume_log(&config, path, &mut initial).map(|(wal, resumed)| (Some(wal), resumed))?,
+            None => (None, Resumed::default()),
+        };
+
+        let audit = config.audit.file.as_deref().map(AuditLog::open).transpose()?;
+
+        let state = AppState::new(config, initial, wal, audit);
+        state.replication.resume(resumed);
+        Ok(Collator { state: Arc::new(state) })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.state.config
+    }
+
+    // Start collating from the background sources the config sets up: a watched directory, tailed files (or
+    // standard input), MQTT and NATS subscriptions, and statsd, along with scheduled exports
+    pub async fn start_sources(&self) -> Result<(), String> {
+        sources::start(&self.state).await
+    }
+
+    // Add rows to a dataset (created on first use), as `/collate` would. Returns how many were added.
+    pub async fn ingest(&self, name: &str, df: DataFrame) -> Result<usize, AppError> {
+        validate_dataset_name(name).map_err(AppError::BadRequest)?;
+        let size = df.estimated_size();
+        collate_local(&self.state, name, "collate", &origin(), df, size).await
+    }
+
+    // Aggregate rows into a dataset (created on first use), as `/aggregate` would. Returns the dataset's new rows.
+    pub async fn aggregate(&self, name: &str, df: DataFrame, spec: AggregateSpec) -> Result<DataFrame, AppError> {
+        validate_dataset_name(name).map_err(AppError::BadRequest)?;
+        let size = df.estimated_size();
+        let (result, _, rows) = aggregate_rows(&self.state, name, df, spec, None, None, &mut IngestCounts::default()).await?;
+        self.state.metrics.record_ingest(name, "aggregate", rows, size);
+        Ok(result)
+    }
+
+    // Read (part of) a dataset, as `/data` would
+    pub async fn query(&self, name: &str, options: &ReadOptions) -> Result<DataFrame, AppError> {
+        let dataset = self.state.existing_dataset(name).await.ok_or_else(|| AppError::dataset_not_found(name))?;
+        read_window(&self.state, name, dataset, options).await.map(|(page, _)| page)
+    }
+
+    // The names of the datasets there are
+    pub async fn datasets(&self) -> Vec<String> {
+        self.state.all_datasets().await.into_iter().map(|(name, _)| name).collect()
+    }
+
+    // Write everything accepted so far to the output files, waiting until it's on disk
+    pub async fn persist(&self) -> Result<FlushStatus, String> {
+        let status = self.state.writer.flush().await?;
+        match &status.last_error {
+            Some(error) => Err(error.clone()),
+            None => Ok(status),
+        }
+    }
+}
+
+// Open the write-ahead log and replay it onto the datasets the service starts out with. The ones the last run spilled
+// pick up from their spill files instead, so only the records after their checkpoints are replayed (or even decoded).
+fn resume_log(config: &Config, path: &Path, initial: &mut HashMap<String, Dataset>) -> Result<(Wal, Resumed), String> {
+    let storage = &config.storage;
+    let mut checkpoints = match (storage.memory_budget_bytes, &storage.spill_dir) {
+        (Some(_), Some(dir)) => spill::checkpoints(dir),
+        _ => HashMap::new(),
+    };
+    let checkpointed = |checkpoints: &HashMap<String, Checkpoint>, record: (&str, u64)| {
+        checkpoints.get(record.0).is_some_and(|checkpoint| record.1 <= checkpoint.seq)
+    };
+    let (mut wal, mut records) = Wal::open(path, &|dataset, seq| checkpointed(&checkpoints, (dataset, seq)))?;
+
+    // A checkpoint past the end of the log was taken against some other log, so the log is read again without it
+    let last_seq = wal.last_seq();
+    let stale: Vec<String> =
+        checkpoints.iter().filter(|(_, checkpoint)| checkpoint.seq > last_seq).map(|(name, _)| name.clone()).collect();
+    if !stale.is_empty() {
+        for name in stale {
+            warn!("The spill checkpoint of {:?} is ahead of the write-ahead log, so it's rebuilt from the log", name);
+            if let Some(mut checkpoint) = checkpoints.remove(&name) {
+                checkpoint.dataset.spilled.clear();
+            }
+        }
+        (wal, records) = Wal::open(path, &|dataset, seq| checkpointed(&checkpoints, (dataset, seq)))?;
+    }
+
+    let resumed = Resumed::of(&records);
+    records.retain(|record| !checkpointed(&checkpoints, (&record.dataset, record.seq)));
+    for (name, checkpoint) in checkpoints {
+        initial.insert(name, checkpoint.dataset);
+    }
+    wal::replay(records, initial, storage.snapshots_dir.as_deref(), storage.rollback_batches);
+    Ok((wal, resumed))
+}
+
+fn origin() -> Origin {
+    Origin {
+        received_at: chrono::Utc::now(),
+        source: String::from(EMBEDDED_SOURCE),
+    }
+}
+
+// Collate a DataFrame that didn't come from a request (a watched file, tailed lines, or a message) into a dataset, as
+// if it had been sent to `/collate`. Returns how many rows were added.
+pub(crate) async fn collate_local(
+    state: &AppState,
+    name: &str,
+    endpoint: &'static str,
+    origin: &Origin,
+    df: DataFrame,
+    size: usize,
+) -> Result<usize, AppError> {
+    let dataset = state.dataset(name).await;
+    let mut counts = IngestCounts::default();
+    let (_, _, rows) = {
+        let mut dataset = locked(state, name, &dataset).await;
+        let payload = prepared(state, name, &dataset, origin, df)?;
+        let concat = state.config.collate.concat;
+        ingest(state, name, &mut dataset, payload, Merge::Append, concat, &mut counts).await?
+    };
+    state.metrics.record_ingest(name, endpoint, rows, size);
+
+    Ok(rows)
+}
+
+// Collate telemetry (line protocol points or remote write samples) into a dataset. Each measurement or metric has its
+// own tags and labels, so columns are always lined up by name.
+pub(crate) async fn collate_points(
+    state: &AppState,
+    name: &str,
+    endpoint: &'static str,
+    origin: &Origin,
+    df: DataFrame,
+    size: usize,
+) -> Result<(), AppError> {
+    let dataset = state.dataset(name).await;
+    let mut counts = IngestCounts::default();
+    let (_, _, rows) = {
+        let mut dataset = locked(state, name, &dataset).await;
+        line_protocol::restore_time(name, &mut dataset);
+        let payload = prepared(state, name, &dataset, origin, df)?;
+        ingest(state, name, &mut dataset, payload, Merge::Append, ConcatMode::Union, &mut counts).await?
+    };
+    state.metrics.record_ingest(name, endpoint, rows, size);
+
+    Ok(())
+}
+
+// How `collate_into` combines a payload with a dataset's rows
+pub(crate) enum Merge {
+    // Add the payload's rows after the dataset's (`/collate`)
+    Append,
+    // Replace the rows with the same keys (`/upsert`)
+    Upsert(Vec<String>),
+    // Join the payload's columns onto the rows with the same keys (`/collate_wide`)
+    Wide(Vec<String>),
+}
+
+// Take a dataset's write lock to ingest into it, counting how long that took (see `/admin/stats`)
+pub(crate) async fn locked<'a>(state: &AppState, name: &str, dataset: &'a SharedDataset) -> RwLockWriteGuard<'a, Dataset> {
+    let started = Instant::now();
+    let dataset = dataset.write().instrument(tracing::info_span!("lock", dataset = name)).await;
+    state.metrics.record_lock_wait(name, started.elapsed());
+    dataset
+}
+
+// A parsed payload made ready to merge into a dataset: provenance and computed columns added, held to the schema, and
+// split into the rows to ingest and the rows to quarantine
+#[tracing::instrument(name = "prepare", skip_all)]
+pub(crate) fn prepared(
+    state: &AppState,
+    name: &str,
+    dataset: &Dataset,
+    origin: &Origin,
+    df: DataFrame,
+) -> Result<(DataFrame, Option<DataFrame>), AppError> {
+    // Note where the rows came from, before the schema check so a schema can declare the provenance columns too
+    let df = if state.config.provenance.enabled {
+        provenance::annotate(&state.config.provenance, &state.batches, origin, df).map_err(AppError::BadRequest)?
+    } else {
+        df
+    };
+
+    // Work out the computed columns (the same goes for them)
+    let df = match &dataset.computed {
+        Some(computed) => computed.apply(&df).map_err(AppError::SchemaMismatch)?,
+        None => df,
+    };
+
+    // Hold the payload to the dataset's declared schema, if it has one
+    let df = match &dataset.schema {
+        Some(schema) => schema.enforce(&df).map_err(AppError::SchemaMismatch)?,
+        None => df,
+    };
+
+    // Rows that break the dataset's validation rules go to its quarantine instead
+    validated(state, name, df)
+}
+
+// Merge a prepared payload into a dataset (with the lock held), log it, and persist the change. Returns the dataset's
+// new state, where it's being written, and how many rows the payload added.
+pub(crate) async fn ingest(
+    state: &AppState,
+    name: &str,
+    dataset: &mut Dataset,
+    (df, rejected): (DataFrame, Option<DataFrame>),
+    merge: Merge,
+    concat: ConcatMode,
+    counts: &mut IngestCounts,
+) -> Result<(DataFrame, String, usize), AppError> {
+    // Retried uploads shouldn't add the same rows twice. Provenance columns differ between retries, so they're
+    // left out of the comparison.
+    let df = if state.config.collate.skip_duplicates && matches!(merge, Merge::Append) {
+        let provenance = &state.config.provenance;
+        let ignore = if provenance.enabled {
+            vec![
+                provenance.received_at_column.as_str(),
+                provenance.source_column.as_str(),
+                provenance.batch_column.as_str(),
+            ]
+        } else {
+            Vec::new()
+        };
+        let new = dataset
+            .without_duplicates(&df, concat, &ignore)
+            .map_err(|e| AppError::SchemaMismatch(format!("The payload doesn't match the dataset: {}", e)))?;
+        counts.skipped = Some(df.height() - new.height());
+        new
+    } else {
+        df
+    };
+
+    // Flag outlying rows, measured against the rows the dataset already has as well as the payload's
+    let df = match state.config.outliers.get(name) {
+        Some(spec) => spec.flagged_payload(dataset.df.as_ref(), &df).map_err(|e| {
+            AppError::SchemaMismatch(format!("The payload can't be checked for outliers: {}", e))
+        })?,
+        None => df,
+    };
+
+    // Concatenate the current state with the new DataFrame (or replace the rows it has new versions of, or add
+    // columns to them)
+    let merging = tracing::info_span!("merge").entered();
+    let (new_df, operation) = match merge {
+        Merge::Upsert(keys) => {
+            dataset.check_upsert(&df, &keys).map_err(AppError::BadRequest)?;
+            // The rows it replaces may have been spilled, so every row is read back in first
+            dataset.unspill().map_err(AppError::Internal)?;
+            let (new_df, count) = dataset
+                .upserted(&df, &keys, concat)
+                .map_err(|e| AppError::SchemaMismatch(format!("The payload doesn't match the dataset: {}", e)))?;
+            counts.replaced = Some(count);
+            (new_df, Operation::Upsert { keys, concat })
+        }
+        Merge::Wide(keys) => {
+            dataset.check_upsert(&df, &keys).map_err(AppError::BadRequest)?;
+            dataset.unspill().map_err(AppError::Internal)?;
+            let (new_df, count) = dataset
+                .widened(&df, &keys)
+                .map_err(|e| AppError::SchemaMismatch(format!("The payload doesn't match the dataset: {}", e)))?;
+            counts.joined = Some(count);
+            (new_df, Operation::CollateWide { keys })
+        }
+        Merge::Append => {
+            let collated = |dataset: &Dataset| {
+                dataset
+                    .collated(&df, concat)
+                    .map_err(|e| AppError::SchemaMismatch(format!("The payload doesn't match the dataset: {}", e)))
+            };
+            let mut new_df = collated(dataset)?;
+            // A payload that reshapes the dataset has the output file rewritten, so that needs the spilled rows too
+            let reshaped = dataset.df.as_ref().is_some_and(|previous| previous.schema() != new_df.schema());
+            if reshaped && !dataset.spilled.is_empty() {
+                dataset.unspill().map_err(AppError::Internal)?;
+                new_df = collated(dataset)?;
+            }
+            (new_df, Operation::Collate { concat })
+        }
+    };
+    drop(merging);
+
+    counts.quarantined = quarantine_rows(state, name, dataset, rejected).await?;
+    log_payload(state, name, &operation, &df).await?;
+    let operation_name = operation.ingest_name().unwrap_or("collate");
+    let payload_rows = df.height();
+    let payload = df.clone();
+
+    // A union can reshape the dataset. The payload's rows are the tail of the new state, so take them from there
+    // to write them in the output file's column layout. If columns were added (or widened), or an upsert replaced
+    // rows (or a wide collate changed them), the file is out of date and has to be rewritten instead of appended
+    // to.
+    let replaced = counts.replaced.unwrap_or(0);
+    let joined = counts.joined.unwrap_or(0);
+    let kept = dataset.df.as_ref().map_or(0, |previous| previous.height() - replaced);
+    let reshaped = dataset.df.as_ref().is_some_and(|previous| previous.schema() != new_df.schema());
+    let write_mode = match state.config.storage.write_mode {
+        WriteMode::Append if reshaped || replaced > 0 || joined > 0 => WriteMode::Snapshot,
+        write_mode => write_mode,
+    };
+    let df = new_df.slice(kept as i64, new_df.height() - kept);
+    let rows = df.height();
+
+    // Update the app state, keeping what it was in case the payload is rolled back
+    let before = dataset.before_batch();
+    dataset.df = Some(new_df);
+    let rollback_batches = state.config.storage.rollback_batches;
+    dataset.record_batch(operation_name, payload_rows, before, Some(chrono::Utc::now()), rollback_batches);
+
+    // Cheap to clone, so the response is serialized after the lock is released
+    let result = dataset.df.clone().unwrap();
+
+    // Only the shape: the rows themselves don't belong in the logs
+    trace!("Concatted. The dataset now has {} rows and {} columns", result.height(), result.width());
+
+    // Followers get the rows that were added (or replaced)
+    state.events.changed(name, operation_name, &df, result.height());
+
+    // Appended rows are mirrored as they are; replaced and joined ones have no equivalent in an insert-only table
+    if let Some(postgres) = &state.postgres
+        && matches!(operation, Operation::Collate { .. })
+    {
+        postgres.submit(name, &df);
+    }
+    if let Some(clickhouse) = &state.clickhouse
+        && matches!(operation, Operation::Collate { .. })
+    {
+        clickhouse.submit(name, &df);
+    }
+    // The parent gets appended rows in the dataset's column layout, and upserted (or joined) ones as they were sent, to
+    // merge them by the same keys
+    if let Some(forward) = &state.forward {
+        let rows = if matches!(operation, Operation::Collate { .. }) { &df } else { &payload };
+        forward.submit(name, &operation, rows);
+    }
+
+    let wrote_to_file = persist_result(&state.writer, dataset, write_mode, df).await?;
+
+    Ok((result, wrote_to_file, rows))
+}
+
+// Split a payload by the dataset's validation rules, if it has any, into the rows to ingest and the rows to quarantine
+pub(crate) fn validated(state: &AppState, name: &str, df: DataFrame) -> Result<(DataFrame, Option<DataFrame>), AppError> {
+    match state.config.validation.get(name) {
+        Some(rules) => rules
+            .split(&df)
+            .map_err(|e| AppError::Internal(format!("Error validating the payload: {}", e))),
+        None => Ok((df, None)),
+    }
+}
+
+// Log and add rows to a dataset's quarantine, returning how many there were (`None` if the dataset has no rules)
+pub(crate) async fn quarantine_rows(
+    state: &AppState,
+    name: &str,
+    dataset: &mut Dataset,
+    rows: Option<DataFrame>,
+) -> Result<Option<usize>, AppError> {
+    if !state.config.validation.contains_key(name) {
+        return Ok(None);
+    }
+    let Some(rows) = rows else {
+        return Ok(Some(0));
+    };
+
+    let quarantine = dataset
+        .quarantined(&rows)
+        .map_err(|e| AppError::Internal(format!("Can't quarantine the rejected rows: {}", e)))?;
+    log_payload(state, name, &Operation::Quarantine, &rows).await?;
+    dataset.quarantine = Some(quarantine);
+    trace!("Quarantined {} rows of a payload for {:?}", rows.height(), name);
+
+    Ok(Some(rows.height()))
+}
+
+// Record a payload in the write-ahead log (if enabled) before it's applied to the dataset, and send it to the replicas
+// (if any). A replica only takes changes from its primary, and a forwarded dataset only ones its parent can follow, so
+// this is where the others are turned away.
+pub(crate) async fn log_payload(state: &AppState, name: &str, operation: &Operation, df: &DataFrame) -> Result<(), AppError> {
+    if state.replication.is_replica() {
+        return Err(AppError::Forbidden(String::from(
+            "This collator is a replica, which only takes changes from its primary; send them to the primary, or \
+             promote this one with `POST /replication/promote`",
+        )));
+    }
+    if let Some(forward) = &state.forward {
+        forward.check(name, operation).map_err(AppError::Forbidden)?;
+    }
+    log_record(state, name, operation, df, None).await
+}
+
+// `log_payload`, for a change from the primary as well (`replicated` says which)
+pub(crate) async fn log_record(
+    state: &AppState,
+    name: &str,
+    operation: &Operation,
+    df: &DataFrame,
+    replicated: Option<&Replicated>,
+) -> Result<(), AppError> {
+    let logged = match &state.wal {
+        Some(wal) => {
+            let (seq, record) = wal
+                .append(name, operation, df, replicated)
+                .instrument(tracing::info_span!("wal"))
+                .await
+                .map_err(|message| AppError::Internal(format!("The data was not accepted: {}", message)))?;
+            trace!("Logged payload for {:?} as record {}", name, seq);
+            Some((seq, record))
+        }
+        None => None,
+    };
+    state.replication.submit(logged, name, operation, df);
+
+    Ok(())
+}
+
+// Hand a request's result to the background writer, returning the `wrote_to_file` value for the response. This
+// happens while the dataset is still locked, so writes reach the output file in the same order they were applied.
+#[tracing::instrument(name = "persist", skip_all)]
+pub(crate) async fn persist_result(
+    writer: &Writer,
+    dataset: &Dataset,
+    mode: WriteMode,
+    batch: DataFrame,
+) -> Result<String, AppError> {
+    // A partitioned dataset is written to its partitions as well as its output file (if it has one)
+    if let Some(partitions) = &dataset.partitions {
+        let write = Write {
+            output_file: partitions.dir.clone(),
+            partitions: Some(partitions.spec.clone()),
+            mode,
+            batch: batch.clone(),
+            state: dataset.df.clone().unwrap_or_default(),
+        };
+        writer.submit(write).await.map_err(|e| {
+            AppError::Internal(format!("The data was accepted, but writing to its partitions failed: {}", e))
+        })?;
+    }
+
+    let Some(output_file) = dataset.output_file.clone() else {
+        return Ok(match &dataset.partitions {
+            Some(partitions) => format!("queued: {:?}", partitions.dir),
+            None => String::from("no"),
+        });
+    };
+
+    let write = Write {
+        output_file: output_file.clone(),
+        partitions: None,
+        mode,
+        batch,
+        state: dataset.df.clone().unwrap_or_default(),
+    };
+    writer.submit(write).await.map_err(|e| {
+        AppError::Internal(format!("The data was accepted, but writing to the output file failed: {}", e))
+    })?;
+
+    Ok(format!("queued: {:?}", output_file))
+}
+
+// The lookup table a `lookup` parameter names, if there is one
+pub(crate) async fn requested_lookup(state: &AppState, name: Option<&str>) -> Result<Option<Arc<LookupTable>>, AppError> {
+    match name {
+        Some(name) => state.lookups.get(name).await.map(Some).ok_or_else(|| AppError::lookup_not_found(name)),
+        None => Ok(None),
+    }
+}
+
+// Aggregate rows into a dataset, only those that match the filter if there is one. The rows are checked against the
+// lookup the response is going to be enriched with, if any. Returns the dataset's new state, where it's being
+// persisted, and how many rows were aggregated.
+pub(crate) async fn aggregate_rows(
+    state: &AppState,
+    name: &str,
+    df: DataFrame,
+    spec: AggregateSpec,
+    filter: Option<&str>,
+    lookup: Option<&LookupTable>,
+    counts: &mut IngestCounts,
+) -> Result<(DataFrame, String, usize), AppError> {
+    // Computed columns are aggregated like any other, and can be filtered on. A new dataset would get them from the
+    // config, so the dataset isn't created until the payload has been checked.
+    let computed = match state.existing_dataset(name).await {
+        Some(dataset) => dataset.read().await.computed.clone(),
+        None => state.config.computed.get(name).cloned(),
+    };
+    let df = match computed {
+        Some(computed) => computed.apply(&df).map_err(AppError::SchemaMismatch)?,
+        None => df,
+    };
+    // The aggregated rows have the payload's columns, so a lookup that can't be joined is caught before anything
+    // changes
+    if let Some(lookup) = lookup {
+        lookup.check_keys(df.schema()).map_err(AppError::BadRequest)?;
+    }
+
+    // Only the payload's rows that match the filter are aggregated
+    let df = match filter {
+        Some(filter) => {
+            let filter: Filter = filter.parse().map_err(AppError::BadRequest)?;
+            let matched = filter.apply(&df).map_err(AppError::BadRequest)?;
+            counts.filtered = Some(df.height() - matched.height());
+            matched
+        }
+        None => df,
+    };
+    let mode = state.config.aggregate.mode;
+    if mode != AggregateMode::Full {
+        spec.check_running(&df, mode).map_err(AppError::BadRequest)?;
+    }
+    // Rows that break the dataset's validation rules go to its quarantine instead
+    let (df, rejected) = validated(state, name, df)?;
+    let rows = df.height();
+
+    // Acquire a lock on the dataset within a scope
+    let dataset = state.dataset(name).await;
+    let write_mode = state.config.storage.write_mode;
+    let writer = &state.writer;
+    let result;
+    let wrote_to_file;
+    {
+        let mut dataset = locked(state, name, &dataset).await;
+
+        // A payload whose rows were all filtered out or quarantined has nothing left to aggregate
+        if rows == 0 && (rejected.is_some() || counts.filtered.is_some_and(|filtered| filtered > 0)) {
+            counts.quarantined = quarantine_rows(state, name, &mut dataset, rejected).await?;
+            result = dataset.df.clone().unwrap_or_default();
+            wrote_to_file = String::from("no");
+        } else {
+            // Aggregated datasets aren't spilled, but rows collated before may have been
+            dataset.unspill().map_err(AppError::Internal)?;
+            // Update the DataFrame according to the aggregate spec, grouping on the key columns. The grouping runs on
+            // the compute pool, over a (cheap) copy of what it needs from the dataset.
+            let current = Dataset {
+                df: dataset.df.clone(),
+                aggregate_state: dataset.aggregate_state.clone(),
+                ..Default::default()
+            };
+            let (payload, grouped_by, followed) = (df.clone(), spec.clone(), state.events.followed());
+            let (aggregate_state, updated_df, changed) = state
+                .compute
+                .wait(move || {
+                    tracing::info_span!("group_by").in_scope(|| {
+                        let (aggregate_state, updated_df) = current.aggregated(&payload, &grouped_by, mode)?;
+                        // The groups followers are sent (all of them, if the payload's can't be picked out)
+                        let changed = followed.then(|| {
+                            grouped_by.changed_groups(&updated_df, &payload).unwrap_or_else(|_| updated_df.clone())
+                        });
+                        PolarsResult::Ok((aggregate_state, updated_df, changed))
+                    })
+                })
+                .await?
+                .map_err(|e| {
+                    AppError::SchemaMismatch(format!("The payload can't be aggregated into the dataset: {}", e))
+                })?;
+
+            counts.quarantined = quarantine_rows(state, name, &mut dataset, rejected).await?;
+            let group_columns = spec.group_columns();
+            let operation = Operation::Aggregate { spec, mode };
+            log_payload(state, name, &operation, &df).await?;
+
+            // Update the app state, keeping what it was in case the payload is rolled back
+            let before = dataset.before_batch();
+            dataset.aggregate_state = Some(aggregate_state);
+            dataset.df = Some(updated_df);
+            let rollback_batches = state.config.storage.rollback_batches;
+            dataset.record_batch("aggregate", rows, before, Some(chrono::Utc::now()), rollback_batches);
+            if let Some(forward) = &state.forward
+                && let Some(groups) = &dataset.df
+            {
+                forward.submit_groups(name, groups, group_columns);
+            }
+            if let Some(changed) = changed {
+                state.events.changed(name, "aggregate", &changed, dataset.df.as_ref().map_or(0, DataFrame::height));
+            }
+
+            result = dataset.df.clone().unwrap();
+
+            trace!("Aggregated ({:?}). The dataset now has {} rows", operation, result.height());
+
+            wrote_to_file = persist_result(writer, &dataset, write_mode, df).await?;
+        }
+    }
+
+    Ok((result, wrote_to_file, rows))
+}
+
+// A window of a dataset, and how many rows there are to take it from. With a filter, the window (and the total) only
+// counts the rows that match it. The read is planned as one lazy query (filter, lookup, sort, columns, window), so
+// filters and column picks are pushed down to where the rows come from, and only the rows returned are materialized:
+// a filtered or sorted read of a large dataset doesn't copy it first. The total is counted by a second query over
+// just the columns the filter needs.
+pub(crate) async fn read_window(
+    state: &AppState,
+    name: &str,
+    dataset: SharedDataset,
+    options: &ReadOptions,
+) -> Result<(DataFrame, usize), AppError> {
+    let filter: Option<Filter> = options.filter.as_deref().map(str::parse).transpose().map_err(AppError::BadRequest)?;
+    let lookup = requested_lookup(state, options.lookup.as_deref()).await?;
+    let as_of = options.as_of.as_deref().map(history::parse_as_of).transpose().map_err(AppError::BadRequest)?;
+
+    // Only hold the lock long enough to clone the (cheap) state; the query runs after it's released. `rows` keeps any
+    // spill files the query scans until it's done.
+    let (rows, mut plan) = match as_of {
+        Some(at) => (None, history::rows_as_of(state, name, &dataset, at).await?.lazy()),
+        None => {
+            let rows = dataset.read().await.rows();
+            let plan = rows.lazy().map_err(AppError::Internal)?;
+            (Some(rows), plan)
+        }
+    };
+    let mut schema = plan.collect_schema().map_err(|e| AppError::Internal(e.to_string()))?;
+
+    if let Some(filter) = &filter {
+        plan = plan.filter(filter.to_expr(&schema).map_err(AppError::BadRequest)?);
+    }
+    // Lookup keys are unique, so enriching doesn't change how many rows there are, and the count can leave it out
+    let count = plan.clone().select([len()]);
+    if let Some(lookup) = &lookup {
+        plan = lookup.enriched(plan, &schema).map_err(AppError::BadRequest)?;
+        schema = plan.collect_schema().map_err(|e| AppError::BadRequest(e.to_string()))?;
+    }
+
+    let sort = options.sort.as_deref().map(|sort| parse_sort(sort, &schema)).transpose().map_err(AppError::BadRequest)?;
+    if let Some((columns, descending)) = sort {
+        let sort_options = SortMultipleOptions::default()
+            .with_order_descending_multi(descending)
+            .with_nulls_last(true)
+            .with_maintain_order(true);
+        plan = plan.sort_by_exprs(columns, sort_options);
+    }
+    // Columns are picked after filtering and sorting, since either may refer to columns that aren't returned
+    if let Some(columns) = &options.columns {
+        if let Some(missing) = columns.iter().find(|column| schema.get(column).is_none()) {
+            return Err(AppError::BadRequest(format!("Column {:?} doesn't exist", missing)));
+        }
+        plan = plan.select(columns.iter().map(|column| col(column.as_str())).collect::<Vec<_>>());
+    }
+    let length = options.limit.unwrap_or(usize::MAX).min(IdxSize::MAX as usize) as IdxSize;
+    let plan = plan.slice(options.offset.min(i64::MAX as usize) as i64, length);
+    // Both queries run on the compute pool
+    let (count, page) = state
+        .compute
+        .run(move || {
+            let collected = count.collect().and_then(|count| Ok((count, plan.collect()?)));
+            drop(rows);
+            collected
+        })
+        .await?
+        .map_err(|e| AppError::BadRequest(e.to_string()))?;
+    let total_rows = count.get_columns()[0].get(0).ok().and_then(|count| count.extract::<usize>()).unwrap_or(0);
+
+    Ok((page, total_rows))
+}
+
+// Parse a `sort` parameter into the columns to sort by and whether each one is descending
+pub(crate) fn parse_sort(sort: &str, schema: &Schema) -> Result<(Vec<Expr>, Vec<bool>), String> {
+    let mut columns = Vec::new();
+    let mut descending = Vec::new();
+
+    for key in sort.split(',').map(str::trim).filter(|key| !key.is_empty()) {
+        let (column, order) = match key.rsplit_once(':') {
+            Some((column, order)) => (column.trim(), order.trim().to_ascii_lowercase()),
+            None => (key, String::from("asc")),
+        };
+        if schema.get(column).is_none() {
+            return Err(format!("Column {:?} in the sort doesn't exist", column));
+        }
+        descending.push(match order.as_str() {
+            "asc" => false,
+            "desc" => true,
+            other => return Err(format!("Unsupported sort order {:?} for column {:?} (expected asc or desc)", other, column)),
+        });
+        columns.push(col(column));
+    }
+    if columns.is_empty() {
+        return Err(String::from("The sort is empty"));
+    }
+
+    Ok((columns, descending))
+}
@@ -0,0 +1,332 @@
+// Completeness (`[expected.<dataset>]`, or `PUT /expected`): the reporters a dataset expects rows from during a
+// collection campaign (ranks 0 to 511, a list of hosts, ...), by the key column that names them. `GET /expected`
+// says which haven't sent anything yet. While anything is expected, a background task follows the datasets' changes
+// to check reporters off as their rows arrive, and when a set completes, posts the report to its webhook (if it has
+// one). A set that loses rows (a reset, a delete, ...) is counted again, and can complete again.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::{broadcast::error::RecvError, Mutex, Notify};
+
+use crate::{
+    dataset::AppState,
+    error::AppError,
+    events::{Change, Event},
+    http::Endpoint,
+    types::{CompletenessResponse, Status},
+    webhooks,
+};
+
+// Ranges are expanded into a set of reporters, so they're kept to a size that's cheap to hold and list
+const MAX_REPORTERS: usize = 1_000_000;
+
+// The reporters a dataset expects rows from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Expectation {
+    // The column that names a row's reporter, e.g. `rank` or `host`
+    pub column: String,
+    // Every reporter, as strings or numbers
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub values: Vec<Value>,
+    // Or a range of integers, from the first up to (not including) the second, e.g. `[0, 512]`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range: Option<[i64; 2]>,
+    // `http://` URL the report is posted to when every reporter has sent rows
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<String>,
+}
+
+impl Expectation {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.column.is_empty() {
+            return Err(String::from("A `column` naming each row's reporter is required"));
+        }
+        match (&self.values[..], self.range) {
+            ([], None) => return Err(String::from("Give the expected reporters as `values` or a `range`")),
+            ([_, ..], Some(_)) => return Err(String::from("Give either `values` or a `range`, not both")),
+            (_, Some([start, end])) if start >= end => {
+                return Err(format!("The range [{}, {}] is empty (it ends before the second number)", start, end));
+            }
+            (_, Some([start, end])) if end.abs_diff(start) > MAX_REPORTERS as u64 => {
+                return Err(format!("A range can hold up to {} reporters", MAX_REPORTERS));
+            }
+            (values, None) if values.len() > MAX_REPORTERS => {
+                return Err(format!("Up to {} reporters can be expected", MAX_REPORTERS));
+            }
+            _ => {}
+        }
+        for value in &self.values {
+            text(value)?;
+        }
+        if let Some(webhook) = &self.webhook {
+            Endpoint::parse(webhook).map_err(|e| format!("Invalid webhook {:?}: {}", webhook, e))?;
+        }
+        Ok(())
+    }
+
+    // The expected reporters in order, each with the text a row's value is matched by. Repeats are left out.
+    fn reporters(&self) -> Vec<(String, Value)> {
+        let values: Box<dyn Iterator<Item = Value>> = match self.range {
+            Some([start, end]) => Box::new((start..end).map(Value::from)),
+            None => Box::new(self.values.iter().cloned()),
+        };
+        let mut seen = HashSet::new();
+        values
+            .filter_map(|value| text(&value).ok().map(|text| (text, value)))
+            .filter(|(text, _)| seen.insert(text.clone()))
+            .collect()
+    }
+}
+
+// A reporter's value as text, as a column's values are compared once they're cast to text
+fn text(value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(text) => Ok(text.clone()),
+        Value::Number(number) => Ok(number.to_string()),
+        other => Err(format!("Expected reporters are strings or numbers, not {}", other)),
+    }
+}
+
+// What's known about a dataset's expected reporters
+#[derive(Debug)]
+struct Tracker {
+    expectation: Expectation,
+    reporters: Vec<(String, Value)>,
+    // The reporters that hadn't sent rows as of the last count, less the ones seen since. `None` when rows may have
+    // been removed since, so it has to be counted again.
+    missing: Option<HashSet<String>>,
+    // When the set completed, while it's complete
+    completed_at: Option<DateTime<Utc>>,
+}
+
+impl Tracker {
+    fn new(expectation: Expectation) -> Self {
+        Tracker {
+            reporters: expectation.reporters(),
+            expectation,
+            missing: None,
+            completed_at: None,
+        }
+    }
+}
+
+// Every dataset's expected reporters
+#[derive(Debug, Default)]
+pub struct Completeness {
+    trackers: Mutex<HashMap<String, Tracker>>,
+    // Woken when an expectation is declared or removed
+    declared: Notify,
+}
+
+impl Completeness {
+    pub fn new(config: &HashMap<String, Expectation>) -> Self {
+        let trackers = config.iter().map(|(name, expectation)| (name.clone(), Tracker::new(expectation.clone())));
+        Completeness {
+            trackers: Mutex::new(trackers.collect()),
+            declared: Notify::new(),
+        }
+    }
+
+    // Expect the reporters of `expectation` in a dataset, instead of any it expected before
+    pub async fn declare(&self, name: &str, expectation: Expectation) {
+        self.trackers.lock().await.insert(name.to_string(), Tracker::new(expectation));
+        self.declared.notify_one();
+    }
+
+    // Returns whether the dataset expected anything
+    pub async fn remove(&self, name: &str) -> bool {
+        let removed = self.trackers.lock().await.remove(name).is_some();
+        self.declared.notify_one();
+        removed
+    }
+
+    async fn is_empty(&self) -> bool {
+        self.trackers.lock().await.is_empty()
+    }
+
+    async fn names(&self) -> Vec<String> {
+        self.trackers.lock().await.keys().cloned().collect()
+    }
+
+    // Check off the reporters a change brought rows from. Returns whether the dataset needs counting: when the change
+    // may have removed rows, or seems to have completed the set.
+    async fn saw(&self, change: &Change) -> bool {
+        let mut trackers = self.trackers.lock().await;
+        let Some(tracker) = trackers.get_mut(&change.dataset) else {
+            return false;
+        };
+        // Operations that remove or rewrite rows don't say which
+        if change.rows.height() == 0 {
+            tracker.missing = None;
+            return true;
+        }
+        let Some(missing) = tracker.missing.as_mut() else {
+            return true;
+        };
+        if missing.is_empty() {
+            return false;
+        }
+
+        let seen = change.rows.column(&tracker.expectation.column).and_then(|column| column.cast(&DataType::String));
+        if let Ok(seen) = seen
+            && let Ok(seen) = seen.str()
+        {
+            for value in seen.into_iter().flatten() {
+                missing.remove(value);
+            }
+        }
+        missing.is_empty()
+    }
+
+    // Count every dataset again, after changes were missed
+    async fn forget_counts(&self) {
+        for tracker in self.trackers.lock().await.values_mut() {
+            tracker.missing = None;
+        }
+    }
+}
+
+// Which of a dataset's expected reporters have sent rows, or `None` if it doesn't expect any. Counting notes a
+// completion (and sends the webhook) as well. Requests are turned away when the compute pool is full; the background
+// task waits for a turn.
+pub async fn report(state: &AppState, name: &str, request: bool) -> Result<Option<CompletenessResponse>, AppError> {
+    let Some(expectation) = state.completeness.trackers.lock().await.get(name).map(|tracker| tracker.expectation.clone())
+    else {
+        return Ok(None);
+    };
+    let reported = reported(state, name, &expectation.column, request).await?;
+
+    let mut trackers = state.completeness.trackers.lock().await;
+    // Removed (or replaced) while the rows were counted
+    let Some(tracker) = trackers.get_mut(name).filter(|tracker| tracker.expectation.column == expectation.column)
+    else {
+        return Ok(None);
+    };
+    let missing: Vec<&(String, Value)> =
+        tracker.reporters.iter().filter(|(text, _)| !reported.contains(text)).collect();
+    let expected: HashSet<&str> = tracker.reporters.iter().map(|(text, _)| text.as_str()).collect();
+    let unexpected = reported.iter().filter(|text| !expected.contains(text.as_str())).count();
+    tracker.missing = Some(missing.iter().map(|(text, _)| text.clone()).collect());
+
+    let complete = missing.is_empty();
+    let completed = complete && tracker.completed_at.is_none();
+    match complete {
+        true => tracker.completed_at = tracker.completed_at.or_else(|| Some(Utc::now())),
+        false => tracker.completed_at = None,
+    }
+    let response = CompletenessResponse {
+        status: Status::Success,
+        dataset: name.to_string(),
+        column: tracker.expectation.column.clone(),
+        expected: tracker.reporters.len(),
+        reported: tracker.reporters.len() - missing.len(),
+        unexpected,
+        complete,
+        completed_at: tracker.completed_at.map(|at| at.to_rfc3339()),
+        missing: missing.iter().map(|(_, value)| value.clone()).collect(),
+        webhook: tracker.expectation.webhook.clone(),
+    };
+
+    if completed {
+        info!("Every one of the {} reporters {:?} expects has sent rows", response.expected, name);
+        // On a replica, the primary posts it
+        if let Some(webhook) = tracker.expectation.webhook.clone()
+            && !state.replication.is_replica()
+        {
+            let body = serde_json::to_vec(&response).expect("reports serialize");
+            tokio::spawn(webhooks::post(webhook, body, format!("the completion of {:?}", name)));
+        }
+    }
+    Ok(Some(response))
+}
+
+// The distinct values (as text) of a dataset's reporter column. A dataset that doesn't exist yet, or doesn't have the
+// column yet, has no reporters.
+pub(crate) async fn reported(state: &AppState, name: &str, column: &str, request: bool) -> Result<HashSet<String>, AppError> {
+    let Some(dataset) = state.existing_dataset(name).await else {
+        return Ok(HashSet::new());
+    };
+    let rows = dataset.read().await.rows();
+    let mut plan = rows.lazy().map_err(AppError::Internal)?;
+    let column = column.to_string();
+    let job = move || {
+        let schema = plan.collect_schema().map_err(|e| e.to_string())?;
+        if !schema.contains(&column) {
+            return Ok(HashSet::new());
+        }
+        let values = plan
+            .select([col(column.as_str()).cast(DataType::String)])
+            .unique(None, UniqueKeepStrategy::Any)
+            .collect()
+            .map_err(|e| e.to_string())?;
+        drop(rows);
+        let values = values.get_columns()[0].str().map_err(|e| e.to_string())?;
+        Ok(values.into_iter().flatten().map(String::from).collect())
+    };
+
+    let reported = match request {
+        true => state.compute.run(job).await?,
+        false => state.compute.wait(job).await?,
+    };
+    reported.map_err(AppError::Internal)
+}
+
+// Follow the datasets' changes while any reporters are expected, counting a dataset again when it needs it. Nothing
+// is followed otherwise, since following changes makes every ingest work out what it changed.
+pub(crate) async fn follow(state: Arc<AppState>) {
+    loop {
+        while state.completeness.is_empty().await {
+            state.completeness.declared.notified().await;
+        }
+
+        let mut events = state.events.subscribe();
+        // Whatever was declared before the changes were followed
+        check(&state, state.completeness.names().await).await;
+        while !state.completeness.is_empty().await {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => {
+                        if let Event::Changed(change) = &*event
+                            && state.completeness.saw(change).await
+                        {
+                            check(&state, vec![change.dataset.clone()]).await;
+                        }
+                    }
+                    Err(RecvError::Lagged(_)) => {
+                        state.completeness.forget_counts().await;
+                        check(&state, state.completeness.names().await).await;
+                    }
+                    Err(RecvError::Closed) => return,
+                },
+                // A new declaration is counted right away, and after a removal there may be nothing left to follow
+                () = state.completeness.declared.notified() => {
+                    check(&state, state.completeness.names().await).await;
+                }
+            }
+        }
+    }
+}
+
+// Count the datasets whose counts are out of date
+async fn check(state: &AppState, names: Vec<String>) {
+    for name in names {
+        let stale = {
+            let trackers = state.completeness.trackers.lock().await;
+            trackers.get(&name).is_some_and(|tracker| {
+                tracker.missing.as_ref().is_none_or(|missing| missing.is_empty() && tracker.completed_at.is_none())
+            })
+        };
+        if stale && let Err(e) = report(state, &name, false).await {
+            error!("Can't count the reporters of {:?}: {}", name, e.message());
+        }
+    }
+}
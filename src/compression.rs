@@ -0,0 +1,206 @@
+mod gzip;
+mod snappy;
+
+use std::io::{self, Read, Write};
+
+use axum::{
+    body::{Body, HttpBody},
+    extract::Request,
+    http::{header, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use futures::{stream, StreamExt};
+use log::error;
+
+use crate::error::AppError;
+
+// Responses whose size is known and below this aren't worth compressing
+const MIN_COMPRESSED_BYTES: u64 = 1024;
+
+// The zstd level responses are compressed with: fast, while still shrinking CSV several times over
+const ZSTD_LEVEL: i32 = 3;
+
+// A `Content-Encoding` the service can decode requests and encode responses with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Zstd,
+    // Only decoded, for Prometheus remote write
+    Snappy,
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Zstd => "zstd",
+            Encoding::Snappy => "snappy",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "zstd" => Some(Encoding::Zstd),
+            "snappy" => Some(Encoding::Snappy),
+            _ => None,
+        }
+    }
+}
+
+// How the request body is compressed, from its `Content-Encoding` (`None` for an uncompressed body)
+pub fn request_encoding(headers: &HeaderMap) -> Result<Option<Encoding>, AppError> {
+    let Some(value) = headers.get(header::CONTENT_ENCODING) else {
+        return Ok(None);
+    };
+    let value = value.to_str().unwrap_or_default().trim();
+    if value.is_empty() || value.eq_ignore_ascii_case("identity") {
+        return Ok(None);
+    }
+
+    Encoding::parse(value).map(Some).ok_or_else(|| {
+        AppError::UnsupportedMediaType(format!(
+            "Content-Encoding {:?} isn't supported (use gzip, zstd, or snappy, or send the body uncompressed)",
+            value
+        ))
+    })
+}
+
+// The encoding to compress a response with, from the request's `Accept-Encoding`: the one with the highest q-value,
+// preferring zstd on a tie. `None` when the client accepts neither.
+pub fn response_encoding(headers: &HeaderMap) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = None;
+    for value in headers.get_all(header::ACCEPT_ENCODING) {
+        for item in value.to_str().unwrap_or_default().split(',') {
+            let mut parts = item.split(';');
+            let name = parts.next().unwrap_or_default().trim();
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q=").and_then(|q| q.trim().parse::<f32>().ok()))
+                .next()
+                .unwrap_or(1.0);
+            if quality <= 0.0 {
+                continue;
+            }
+
+            // `*` stands for every encoding not listed, which only matters when neither is
+            let encoding = match name {
+                "*" => Encoding::Zstd,
+                name => match Encoding::parse(name) {
+                    Some(Encoding::Snappy) | None => continue,
+                    Some(encoding) => encoding,
+                },
+            };
+            let better = match best {
+                None => true,
+                Some((current, q)) => {
+                    quality > q || (quality == q && encoding == Encoding::Zstd && current != Encoding::Zstd)
+                }
+            };
+            if better {
+                best = Some((encoding, quality));
+            }
+        }
+    }
+
+    best.map(|(encoding, _)| encoding)
+}
+
+// Decompress `input` into `output`
+pub fn decode<R: Read, W: Write>(encoding: Encoding, input: R, output: &mut W) -> io::Result<()> {
+    match encoding {
+        Encoding::Gzip => gzip::decode(input, output),
+        Encoding::Zstd => {
+            let mut decoder = zstd::stream::read::Decoder::new(input)?;
+            io::copy(&mut decoder, output).map(|_| ())
+        }
+        Encoding::Snappy => snappy::decode(input, output),
+    }
+}
+
+// Compresses a response body one chunk at a time, so it never has to be held whole
+enum Compressor {
+    Gzip(gzip::Encoder),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl Compressor {
+    fn new(encoding: Encoding) -> io::Result<Self> {
+        Ok(match encoding {
+            Encoding::Gzip => Compressor::Gzip(gzip::Encoder::new()),
+            Encoding::Zstd => Compressor::Zstd(zstd::stream::write::Encoder::new(Vec::new(), ZSTD_LEVEL)?),
+            Encoding::Snappy => return Err(io::Error::new(io::ErrorKind::Unsupported, "responses aren't sent as snappy")),
+        })
+    }
+
+    // Compress `chunk`, returning everything that can be sent so far. Each chunk is flushed, so a streamed response
+    // reaches the client as it's produced rather than when it ends.
+    fn compress(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compressor::Gzip(encoder) => Ok(encoder.compress(chunk)),
+            Compressor::Zstd(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+        }
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Compressor::Gzip(encoder) => Ok(encoder.finish()),
+            Compressor::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+// Compress responses with the best encoding the client's `Accept-Encoding` allows. Small responses and ones that are
+// already encoded are sent as they are.
+pub async fn compress_responses(request: Request, next: Next) -> Response {
+    let encoding = response_encoding(request.headers());
+    let response = next.run(request).await;
+    let Some(encoding) = encoding else {
+        return response;
+    };
+    if response.headers().contains_key(header::CONTENT_ENCODING)
+        || response.body().size_hint().exact().is_some_and(|len| len < MIN_COMPRESSED_BYTES)
+    {
+        return response;
+    }
+    let compressor = match Compressor::new(encoding) {
+        Ok(compressor) => compressor,
+        Err(e) => {
+            error!("Can't start compressing a response with {}: {}", encoding.name(), e);
+            return response;
+        }
+    };
+
+    let (mut parts, body) = response.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+    parts.headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+
+    // Compression is CPU-bound, so each chunk is compressed off the async workers
+    let chunks = stream::unfold(Some((body.into_data_stream(), compressor)), |state| async move {
+        let (mut body, mut compressor) = state?;
+        match body.next().await {
+            Some(Ok(chunk)) => {
+                let compressed = tokio::task::spawn_blocking(move || {
+                    compressor.compress(&chunk).map(|compressed| (compressed, compressor))
+                })
+                .await
+                .map_err(io::Error::other)
+                .and_then(|result| result);
+                match compressed {
+                    Ok((compressed, compressor)) => Some((Ok(compressed), Some((body, compressor)))),
+                    Err(e) => Some((Err(e), None)),
+                }
+            }
+            Some(Err(e)) => Some((Err(io::Error::other(e)), None)),
+            None => Some((compressor.finish(), None)),
+        }
+    })
+    .filter(|chunk| std::future::ready(!chunk.as_ref().is_ok_and(Vec::is_empty)));
+
+    Response::from_parts(parts, Body::from_stream(chunks))
+}
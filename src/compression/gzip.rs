@@ -0,0 +1,716 @@
+// A small gzip (RFC 1952) codec covering what HTTP bodies need. Decoding handles every kind of DEFLATE block (RFC
+// 1951) and multi-member files. Encoding uses LZ77 with the fixed Huffman codes only, which gets most of the way on
+// repetitive text like CSV without having to build code tables per block.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+// How many earlier positions with the same hash the encoder tries before settling for its best match
+const MAX_CHAIN: usize = 32;
+const HASH_BITS: u32 = 15;
+
+// Decoded output is written out whenever this much is waiting beyond the window
+const FLUSH_AT: usize = 256 * 1024;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+// Order code length code lengths are stored in, in dynamic block headers
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { 0xedb8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+// Running CRC-32 of everything passed in
+#[derive(Debug, Clone, Copy, Default)]
+struct Crc(u32);
+
+impl Crc {
+    fn update(&mut self, bytes: &[u8]) {
+        let mut crc = !self.0;
+        for byte in bytes {
+            crc = CRC_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+        }
+        self.0 = !crc;
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid gzip data: {}", message))
+}
+
+// Decompress a gzip stream (one member or several, one after another) into `output`
+pub fn decode<R: Read, W: Write>(input: R, output: &mut W) -> io::Result<()> {
+    let mut bits = BitReader {
+        input: BufReader::new(input),
+        buffer: 0,
+        count: 0,
+    };
+
+    loop {
+        bits.header()?;
+        let (crc, size) = Inflater::new(output).inflate(&mut bits)?;
+
+        // The trailer starts at the next byte boundary
+        bits.align();
+        if bits.u32_le()? != crc.0 {
+            return Err(invalid("checksum mismatch"));
+        }
+        if bits.u32_le()? != size {
+            return Err(invalid("length mismatch"));
+        }
+        if bits.at_end()? {
+            return Ok(());
+        }
+    }
+}
+
+struct BitReader<R> {
+    input: BufReader<R>,
+    // Bits read but not consumed yet, least significant first
+    buffer: u64,
+    count: u32,
+}
+
+impl<R: Read> BitReader<R> {
+    // Top the buffer up to at least `n` bits. Past the end of the input it's padded with zeros, so a code can always
+    // be peeked at; `consume` catches anything that actually reads the padding.
+    fn fill(&mut self, n: u32) -> io::Result<()> {
+        while self.count < n {
+            let byte = match self.input.fill_buf()?.first() {
+                Some(byte) => {
+                    let byte = *byte;
+                    self.input.consume(1);
+                    byte
+                }
+                None => return Ok(()),
+            };
+            self.buffer |= (byte as u64) << self.count;
+            self.count += 8;
+        }
+        Ok(())
+    }
+
+    fn peek(&mut self, n: u32) -> io::Result<u32> {
+        self.fill(n)?;
+        Ok((self.buffer & ((1u64 << n) - 1)) as u32)
+    }
+
+    fn consume(&mut self, n: u32) -> io::Result<()> {
+        if n > self.count {
+            return Err(invalid("unexpected end of data"));
+        }
+        self.buffer >>= n;
+        self.count -= n;
+        Ok(())
+    }
+
+    fn bits(&mut self, n: u32) -> io::Result<u32> {
+        if n == 0 {
+            return Ok(0);
+        }
+        let value = self.peek(n)?;
+        self.consume(n)?;
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        let partial = self.count % 8;
+        self.buffer >>= partial;
+        self.count -= partial;
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bits(8)? as u8)
+    }
+
+    fn u16_le(&mut self) -> io::Result<u16> {
+        Ok(self.bits(16)? as u16)
+    }
+
+    fn u32_le(&mut self) -> io::Result<u32> {
+        Ok(self.u16_le()? as u32 | (self.u16_le()? as u32) << 16)
+    }
+
+    fn at_end(&mut self) -> io::Result<bool> {
+        self.fill(8)?;
+        Ok(self.count == 0)
+    }
+
+    // Skip a member header, checking it's gzip holding DEFLATE data
+    fn header(&mut self) -> io::Result<()> {
+        if self.u8()? != 0x1f || self.u8()? != 0x8b {
+            return Err(invalid("not a gzip stream"));
+        }
+        if self.u8()? != 8 {
+            return Err(invalid("unsupported compression method"));
+        }
+        let flags = self.u8()?;
+        // Modification time, extra flags, and operating system
+        for _ in 0..6 {
+            self.u8()?;
+        }
+        if flags & 0x04 != 0 {
+            let len = self.u16_le()?;
+            for _ in 0..len {
+                self.u8()?;
+            }
+        }
+        // File name and comment, both zero-terminated
+        for flag in [0x08, 0x10] {
+            if flags & flag != 0 {
+                while self.u8()? != 0 {}
+            }
+        }
+        if flags & 0x02 != 0 {
+            self.u16_le()?;
+        }
+        Ok(())
+    }
+}
+
+// Canonical Huffman code, decoded with a single table indexed by the next `bits` bits of input
+struct Huffman {
+    // (symbol, code length) per bit pattern; length 0 marks patterns no code starts with
+    table: Vec<(u16, u8)>,
+    bits: u32,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> io::Result<Self> {
+        let bits = lengths.iter().copied().max().unwrap_or(0) as u32;
+        let mut table = vec![(0, 0); 1 << bits];
+
+        let mut counts = [0u32; 16];
+        for length in lengths.iter().filter(|length| **length > 0) {
+            counts[*length as usize] += 1;
+        }
+        let mut next = [0u32; 16];
+        let mut code = 0;
+        for length in 1..16 {
+            code = (code + counts[length - 1]) << 1;
+            next[length] = code;
+        }
+
+        for (symbol, length) in lengths.iter().enumerate().filter(|(_, length)| **length > 0) {
+            let length = *length as u32;
+            let code = next[length as usize];
+            next[length as usize] += 1;
+            if code >= 1 << length {
+                return Err(invalid("over-subscribed Huffman code"));
+            }
+            // Codes are packed most significant bit first, while the table is indexed by bits in reading order
+            let reversed = code.reverse_bits() >> (32 - length);
+            let mut index = reversed as usize;
+            while index < table.len() {
+                table[index] = (symbol as u16, length as u8);
+                index += 1 << length;
+            }
+        }
+
+        Ok(Huffman { table, bits })
+    }
+
+    fn decode<R: Read>(&self, input: &mut BitReader<R>) -> io::Result<u16> {
+        let (symbol, length) = self.table[input.peek(self.bits)? as usize];
+        if length == 0 {
+            return Err(invalid("bad Huffman code"));
+        }
+        input.consume(length as u32)?;
+        Ok(symbol)
+    }
+}
+
+struct Inflater<'a, W> {
+    output: &'a mut W,
+    // Everything decoded since the last flush, plus the window before it
+    decoded: Vec<u8>,
+    crc: Crc,
+    size: u32,
+}
+
+impl<'a, W: Write> Inflater<'a, W> {
+    fn new(output: &'a mut W) -> Self {
+        Inflater {
+            output,
+            decoded: Vec::with_capacity(WINDOW + FLUSH_AT),
+            crc: Crc::default(),
+            size: 0,
+        }
+    }
+
+    // Decode one member's DEFLATE blocks, returning the CRC and length (mod 2^32) of what they held
+    fn inflate<R: Read>(mut self, input: &mut BitReader<R>) -> io::Result<(Crc, u32)> {
+        loop {
+            let last = input.bits(1)? == 1;
+            match input.bits(2)? {
+                0 => self.stored(input)?,
+                1 => {
+                    let (literals, distances) = fixed_codes()?;
+                    self.codes(input, &literals, &distances)?;
+                }
+                2 => {
+                    let (literals, distances) = dynamic_codes(input)?;
+                    self.codes(input, &literals, &distances)?;
+                }
+                _ => return Err(invalid("reserved block type")),
+            }
+            if self.decoded.len() >= WINDOW + FLUSH_AT {
+                self.flush(WINDOW)?;
+            }
+            if last {
+                self.flush(0)?;
+                return Ok((self.crc, self.size));
+            }
+        }
+    }
+
+    // Write out all but the last `keep` bytes
+    fn flush(&mut self, keep: usize) -> io::Result<()> {
+        let end = self.decoded.len().saturating_sub(keep);
+        let ready = &self.decoded[..end];
+        self.output.write_all(ready)?;
+        self.crc.update(ready);
+        self.size = self.size.wrapping_add(ready.len() as u32);
+        self.decoded.drain(..end);
+        Ok(())
+    }
+
+    fn stored<R: Read>(&mut self, input: &mut BitReader<R>) -> io::Result<()> {
+        input.align();
+        let len = input.u16_le()?;
+        if input.u16_le()? != !len {
+            return Err(invalid("stored block length mismatch"));
+        }
+        for _ in 0..len {
+            self.decoded.push(input.u8()?);
+        }
+        Ok(())
+    }
+
+    fn codes<R: Read>(&mut self, input: &mut BitReader<R>, literals: &Huffman, distances: &Huffman) -> io::Result<()> {
+        loop {
+            let symbol = literals.decode(input)? as usize;
+            match symbol {
+                0..=255 => self.decoded.push(symbol as u8),
+                256 => return Ok(()),
+                257..=285 => {
+                    let i = symbol - 257;
+                    let len = LENGTH_BASE[i] as usize + input.bits(LENGTH_EXTRA[i] as u32)? as usize;
+                    let d = distances.decode(input)? as usize;
+                    if d >= DISTANCE_BASE.len() {
+                        return Err(invalid("bad distance code"));
+                    }
+                    let distance = DISTANCE_BASE[d] as usize + input.bits(DISTANCE_EXTRA[d] as u32)? as usize;
+                    // Flushes always keep a full window, so only the very start of a stream can be out of reach
+                    if distance > self.decoded.len() {
+                        return Err(invalid("distance too far back"));
+                    }
+                    // Byte by byte, since a match can overlap what it copies
+                    let start = self.decoded.len() - distance;
+                    for i in 0..len {
+                        let byte = self.decoded[start + i];
+                        self.decoded.push(byte);
+                    }
+                }
+                _ => return Err(invalid("bad length code")),
+            }
+            if self.decoded.len() >= WINDOW + FLUSH_AT * 2 {
+                self.flush(WINDOW)?;
+            }
+        }
+    }
+}
+
+fn fixed_lengths() -> ([u8; 288], [u8; 30]) {
+    let mut literals = [0; 288];
+    literals[..144].fill(8);
+    literals[144..256].fill(9);
+    literals[256..280].fill(7);
+    literals[280..].fill(8);
+    (literals, [5; 30])
+}
+
+fn fixed_codes() -> io::Result<(Huffman, Huffman)> {
+    let (literals, distances) = fixed_lengths();
+    Ok((Huffman::new(&literals)?, Huffman::new(&distances)?))
+}
+
+fn dynamic_codes<R: Read>(input: &mut BitReader<R>) -> io::Result<(Huffman, Huffman)> {
+    let literal_count = input.bits(5)? as usize + 257;
+    let distance_count = input.bits(5)? as usize + 1;
+    let code_length_count = input.bits(4)? as usize + 4;
+
+    let mut code_lengths = [0u8; 19];
+    for i in CODE_LENGTH_ORDER.iter().take(code_length_count) {
+        code_lengths[*i] = input.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths)?;
+
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (value, repeat) = match code_lengths.decode(input)? {
+            length @ 0..=15 => (length as u8, 1),
+            16 => {
+                let previous = *lengths.last().ok_or_else(|| invalid("repeat with no previous length"))?;
+                (previous, 3 + input.bits(2)? as usize)
+            }
+            17 => (0, 3 + input.bits(3)? as usize),
+            18 => (0, 11 + input.bits(7)? as usize),
+            _ => return Err(invalid("bad code length code")),
+        };
+        if lengths.len() + repeat > literal_count + distance_count {
+            return Err(invalid("code lengths overflow"));
+        }
+        lengths.extend(std::iter::repeat_n(value, repeat));
+    }
+    if lengths[256] == 0 {
+        return Err(invalid("no end-of-block code"));
+    }
+
+    let (literals, distances) = lengths.split_at(literal_count);
+    Ok((Huffman::new(literals)?, Huffman::new(distances)?))
+}
+
+// Compresses a body one chunk at a time. Each chunk becomes a DEFLATE block of its own (matches don't reach back into
+// earlier chunks), so nothing has to be held back between them.
+pub struct Encoder {
+    crc: Crc,
+    size: u32,
+    // Bits written but not yet a whole byte, least significant first
+    buffer: u64,
+    count: u32,
+    header_written: bool,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Encoder {
+            crc: Crc::default(),
+            size: 0,
+            buffer: 0,
+            count: 0,
+            header_written: false,
+        }
+    }
+
+    // The compressed bytes for `chunk` that are ready so far
+    pub fn compress(&mut self, chunk: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(chunk.len() / 4 + 16);
+        self.header(&mut out);
+        if chunk.is_empty() {
+            return out;
+        }
+        self.crc.update(chunk);
+        self.size = self.size.wrapping_add(chunk.len() as u32);
+
+        // A fixed Huffman block that isn't the last one
+        self.put(&mut out, 0b010, 3);
+        let mut head = vec![u32::MAX; 1 << HASH_BITS];
+        let mut prev = vec![u32::MAX; chunk.len()];
+        let mut i = 0;
+        while i < chunk.len() {
+            let (len, distance) = longest_match(chunk, i, &mut head, &mut prev);
+            if len >= MIN_MATCH {
+                self.length(&mut out, len);
+                self.distance(&mut out, distance);
+                // Index the positions the match covers too, so later matches can start in them
+                for j in i + 1..i + len {
+                    insert(chunk, j, &mut head, &mut prev);
+                }
+                i += len;
+            } else {
+                self.literal(&mut out, chunk[i] as u16);
+                i += 1;
+            }
+        }
+        self.literal(&mut out, 256);
+
+        out
+    }
+
+    // The rest of the stream: an empty last block, and the trailer
+    pub fn finish(mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.header(&mut out);
+        self.put(&mut out, 0b011, 3);
+        self.literal(&mut out, 256);
+        if self.count > 0 {
+            out.push(self.buffer as u8);
+            self.buffer = 0;
+            self.count = 0;
+        }
+        out.extend_from_slice(&self.crc.0.to_le_bytes());
+        out.extend_from_slice(&self.size.to_le_bytes());
+        out
+    }
+
+    fn header(&mut self, out: &mut Vec<u8>) {
+        if !self.header_written {
+            // No name or timestamp, "unknown" operating system
+            out.extend_from_slice(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255]);
+            self.header_written = true;
+        }
+    }
+
+    fn put(&mut self, out: &mut Vec<u8>, value: u32, bits: u32) {
+        self.buffer |= (value as u64) << self.count;
+        self.count += bits;
+        while self.count >= 8 {
+            out.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // A Huffman code, which goes out most significant bit first
+    fn code(&mut self, out: &mut Vec<u8>, code: u32, bits: u32) {
+        self.put(out, code.reverse_bits() >> (32 - bits), bits);
+    }
+
+    fn literal(&mut self, out: &mut Vec<u8>, symbol: u16) {
+        let symbol = symbol as u32;
+        match symbol {
+            0..=143 => self.code(out, 0x30 + symbol, 8),
+            144..=255 => self.code(out, 0x190 + symbol - 144, 9),
+            256..=279 => self.code(out, symbol - 256, 7),
+            _ => self.code(out, 0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn length(&mut self, out: &mut Vec<u8>, len: usize) {
+        let i = LENGTH_BASE.iter().rposition(|base| *base as usize <= len).unwrap_or(0);
+        self.literal(out, 257 + i as u16);
+        self.put(out, (len - LENGTH_BASE[i] as usize) as u32, LENGTH_EXTRA[i] as u32);
+    }
+
+    fn distance(&mut self, out: &mut Vec<u8>, distance: usize) {
+        let i = DISTANCE_BASE.iter().rposition(|base| *base as usize <= distance).unwrap_or(0);
+        self.code(out, i as u32, 5);
+        self.put(out, (distance - DISTANCE_BASE[i] as usize) as u32, DISTANCE_EXTRA[i] as u32);
+    }
+}
+
+fn hash(data: &[u8], i: usize) -> usize {
+    let value = (data[i] as u32) << 16 | (data[i + 1] as u32) << 8 | data[i + 2] as u32;
+    (value.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+fn insert(data: &[u8], i: usize, head: &mut [u32], prev: &mut [u32]) {
+    if i + MIN_MATCH <= data.len() {
+        let h = hash(data, i);
+        prev[i] = head[h];
+        head[h] = i as u32;
+    }
+}
+
+// The longest earlier match for the bytes at `i` within the window, as (length, distance), indexing `i` on the way
+fn longest_match(data: &[u8], i: usize, head: &mut [u32], prev: &mut [u32]) -> (usize, usize) {
+    if i + MIN_MATCH > data.len() {
+        return (0, 0);
+    }
+    let h = hash(data, i);
+    let mut candidate = head[h];
+    prev[i] = candidate;
+    head[h] = i as u32;
+
+    let max = (data.len() - i).min(MAX_MATCH);
+    let (mut best_len, mut best_distance) = (0, 0);
+    for _ in 0..MAX_CHAIN {
+        if candidate == u32::MAX || i - candidate as usize > WINDOW {
+            break;
+        }
+        let start = candidate as usize;
+        let len = data[start..].iter().zip(&data[i..i + max]).take_while(|(a, b)| a == b).count();
+        if len > best_len {
+            best_len = len;
+            best_distance = i - start;
+            if len == max {
+                break;
+            }
+        }
+        candidate = prev[start];
+    }
+
+    (best_len, best_distance)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "hello, hello, hello, hello, world" in a stored block (zlib level 0)
+    const STORED: &str = "1f8b0800000000000403012100deff68656c6c6f2c2068656c6c6f2c2068656c6c6f2c2068656c6c6f\
+                          2c20776f726c6479b123bd21000000";
+    // The same text with the fixed Huffman codes (zlib level 9)
+    const FIXED: &str = "1f8b0800000000000203cb48cdc9c9d751c8c04695e717e5a4000079b123bd21000000";
+    // A small CSV with dynamic Huffman codes (zlib level 9)
+    const DYNAMIC: &str = "1f8b080000000000020305c1b10180300c04b1fe67b9c61fc7789d14747490cc8ff4bd9cf5ec5b8165528356\
+                           12a5c9b08a4a5d845b8d67e907944edf5530000000";
+    const HELLO: &[u8] = b"hello, hello, hello, hello, world";
+    const CSV: &[u8] = b"ts,value\n1,2\n2,4\n3,8\n4,16\n5,32\n6,64\n7,128\n8,256\n";
+
+    fn bytes(hex: &str) -> Vec<u8> {
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn decoded(data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        decode(data, &mut output).map(|_| output)
+    }
+
+    fn encoded(chunks: &[&[u8]]) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        let mut out: Vec<u8> = chunks.iter().flat_map(|chunk| encoder.compress(chunk)).collect();
+        out.extend(encoder.finish());
+        out
+    }
+
+    // Takes up to `max` bytes, and fails the write that goes past them, the way request bodies are decompressed
+    struct Capped {
+        len: usize,
+        max: usize,
+    }
+
+    impl Write for Capped {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.len += bytes.len();
+            if self.len > self.max {
+                return Err(io::Error::other("too large"));
+            }
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stored_block() {
+        assert_eq!(decoded(&bytes(STORED)).unwrap(), HELLO);
+    }
+
+    #[test]
+    fn fixed_block() {
+        assert_eq!(decoded(&bytes(FIXED)).unwrap(), HELLO);
+    }
+
+    #[test]
+    fn dynamic_block() {
+        assert_eq!(decoded(&bytes(DYNAMIC)).unwrap(), CSV);
+    }
+
+    #[test]
+    fn multiple_members() {
+        let data = [bytes(STORED), bytes(DYNAMIC), bytes(FIXED)].concat();
+        assert_eq!(decoded(&data).unwrap(), [HELLO, CSV, HELLO].concat());
+    }
+
+    #[test]
+    fn header_fields_are_skipped() {
+        // FEXTRA, FNAME, FCOMMENT, and FHCRC, in front of the fixed block
+        let data = bytes(FIXED);
+        let mut with_fields = data[..10].to_vec();
+        with_fields[3] = 0x04 | 0x08 | 0x10 | 0x02;
+        with_fields.extend_from_slice(&[2, 0, b'x', b'y']);
+        with_fields.extend_from_slice(b"hello.txt\0a comment\0");
+        with_fields.extend_from_slice(&[0, 0]);
+        with_fields.extend_from_slice(&data[10..]);
+        assert_eq!(decoded(&with_fields).unwrap(), HELLO);
+    }
+
+    #[test]
+    fn round_trip() {
+        let csv = CSV.repeat(500);
+        assert_eq!(decoded(&encoded(&[&csv])).unwrap(), csv);
+        // Chunk by chunk, including empty ones, and a run that only overlapping copies can encode
+        let run = vec![b'a'; 10_000];
+        assert_eq!(decoded(&encoded(&[b"", HELLO, b"", &run, CSV])).unwrap(), [HELLO, &run, CSV].concat());
+        assert_eq!(decoded(&encoded(&[])).unwrap(), b"");
+    }
+
+    #[test]
+    fn round_trip_past_the_window() {
+        // Longer than the window and the flush threshold, so the decoder has to flush midway through a member
+        let data: Vec<u8> = (0..400_000u32).flat_map(|i| format!("{},{}\n", i % 977, i % 13).into_bytes()).collect();
+        assert_eq!(decoded(&encoded(&[&data])).unwrap(), data);
+    }
+
+    #[test]
+    fn truncated_input() {
+        for data in [bytes(STORED), bytes(FIXED), bytes(DYNAMIC)] {
+            for len in [0, 3, 10, 12, data.len() - 8, data.len() - 1] {
+                assert!(decoded(&data[..len]).is_err(), "{} of {} bytes decoded", len, data.len());
+            }
+        }
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let mut data = bytes(DYNAMIC);
+        let crc = data.len() - 8;
+        data[crc] ^= 1;
+        assert!(decoded(&data).unwrap_err().to_string().contains("checksum mismatch"));
+    }
+
+    #[test]
+    fn bad_length() {
+        let mut data = bytes(DYNAMIC);
+        let size = data.len() - 4;
+        data[size] ^= 1;
+        assert!(decoded(&data).unwrap_err().to_string().contains("length mismatch"));
+    }
+
+    #[test]
+    fn not_gzip() {
+        assert!(decoded(b"ts,value\n1,2\n").unwrap_err().to_string().contains("not a gzip stream"));
+        let mut data = bytes(FIXED);
+        data[2] = 7;
+        assert!(decoded(&data).unwrap_err().to_string().contains("unsupported compression method"));
+    }
+
+    #[test]
+    fn stops_at_the_output_limit() {
+        // 16 MiB of zeros comes to well under 1 MiB compressed. Decoding stops at the first write past the limit,
+        // instead of inflating the rest first.
+        let zeros = vec![0; 1 << 20];
+        let mut encoder = Encoder::new();
+        let mut bomb = Vec::new();
+        for _ in 0..16 {
+            bomb.extend(encoder.compress(&zeros));
+        }
+        bomb.extend(encoder.finish());
+        assert!(bomb.len() < 1 << 20);
+
+        let max = 1 << 20;
+        let mut output = Capped { len: 0, max };
+        assert!(decode(bomb.as_slice(), &mut output).is_err());
+        assert!(output.len <= max + WINDOW + 2 * FLUSH_AT + MAX_MATCH, "{} bytes written", output.len);
+    }
+}
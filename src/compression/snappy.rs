@@ -0,0 +1,252 @@
+// Decoding of raw Snappy blocks, the format Prometheus remote write compresses its requests with. There's no framing
+// format (or encoding); remote write doesn't use either.
+
+use std::io::{self, Read, Write};
+
+// Decoded output is written out whenever this much is waiting, so a body that decompresses past the size limit is
+// stopped early rather than decoded whole first
+const FLUSH_AT: usize = 256 * 1024;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+pub fn decode<R: Read, W: Write>(mut input: R, output: &mut W) -> io::Result<()> {
+    // Copies can reach back anywhere in the block, so it's decoded as a whole
+    let mut block = Vec::new();
+    input.read_to_end(&mut block)?;
+
+    let mut at = 0;
+    let expected = varint(&block, &mut at)?;
+    let mut decoded: Vec<u8> = Vec::new();
+    let mut written = 0;
+
+    while at < block.len() {
+        let tag = block[at];
+        at += 1;
+        match tag & 0x03 {
+            // A literal. Lengths up to 60 are in the tag; longer ones in the 1-4 bytes after it.
+            0 => {
+                let len = match tag >> 2 {
+                    len @ 0..60 => usize::from(len) + 1,
+                    extra => {
+                        let bytes = usize::from(extra - 59);
+                        let len = little_endian(&block, &mut at, bytes)?;
+                        len + 1
+                    }
+                };
+                let literal = block.get(at..at + len).ok_or_else(|| invalid("a literal runs past the end"))?;
+                decoded.extend_from_slice(literal);
+                at += len;
+            }
+            kind => {
+                let (len, offset) = match kind {
+                    1 => {
+                        let low = usize::from(*block.get(at).ok_or_else(|| invalid("a copy runs past the end"))?);
+                        at += 1;
+                        (usize::from((tag >> 2) & 0x07) + 4, usize::from(tag >> 5) << 8 | low)
+                    }
+                    2 => (usize::from(tag >> 2) + 1, little_endian(&block, &mut at, 2)?),
+                    _ => (usize::from(tag >> 2) + 1, little_endian(&block, &mut at, 4)?),
+                };
+                if offset == 0 || offset > decoded.len() {
+                    return Err(invalid("a copy reaches back before the start"));
+                }
+                // The source can overlap what's being copied, so it goes a byte at a time
+                let start = decoded.len() - offset;
+                for i in 0..len {
+                    decoded.push(decoded[start + i]);
+                }
+            }
+        }
+
+        if decoded.len() as u64 > expected {
+            return Err(invalid("the block decodes to more than it says"));
+        }
+        if decoded.len() - written >= FLUSH_AT {
+            output.write_all(&decoded[written..])?;
+            written = decoded.len();
+        }
+    }
+    if decoded.len() as u64 != expected {
+        return Err(invalid("the block decodes to less than it says"));
+    }
+
+    output.write_all(&decoded[written..])
+}
+
+// A little-endian base-128 number, as the block's length is stored
+fn varint(block: &[u8], at: &mut usize) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *block.get(*at).ok_or_else(|| invalid("the block is cut short"))?;
+        *at += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("the block's length is malformed"))
+}
+
+fn little_endian(block: &[u8], at: &mut usize, bytes: usize) -> io::Result<usize> {
+    let raw = block.get(*at..*at + bytes).ok_or_else(|| invalid("the block is cut short"))?;
+    *at += bytes;
+    Ok(raw.iter().rev().fold(0, |value, byte| value << 8 | usize::from(*byte)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn decoded(block: &[u8]) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        decode(block, &mut output).map(|_| output)
+    }
+
+    fn length(mut len: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        while len >= 0x80 {
+            out.push(len as u8 | 0x80);
+            len >>= 7;
+        }
+        out.push(len as u8);
+        out
+    }
+
+    fn literal(out: &mut Vec<u8>, bytes: &[u8]) {
+        match bytes.len() - 1 {
+            len @ 0..60 => out.push((len as u8) << 2),
+            len => {
+                out.push(61 << 2);
+                out.extend_from_slice(&(len as u16).to_le_bytes());
+            }
+        }
+        out.extend_from_slice(bytes);
+    }
+
+    fn copy(out: &mut Vec<u8>, len: usize, offset: usize) {
+        out.push(((len - 1) as u8) << 2 | 2);
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+    }
+
+    // A simple greedy compressor, with literals and 2-byte offset copies, for the round trips
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut out = length(data.len());
+        let mut last = HashMap::new();
+        let (mut i, mut pending) = (0, 0);
+        while i < data.len() {
+            let candidate = data.get(i..i + 4).and_then(|key| last.insert(key, i));
+            match candidate.filter(|start| i - start < 1 << 16) {
+                Some(start) => {
+                    let len = (0..64.min(data.len() - i)).take_while(|k| data[start + k] == data[i + k]).count();
+                    for chunk in data[pending..i].chunks(1 << 16) {
+                        literal(&mut out, chunk);
+                    }
+                    copy(&mut out, len, i - start);
+                    i += len;
+                    pending = i;
+                }
+                None => i += 1,
+            }
+        }
+        for chunk in data[pending..].chunks(1 << 16) {
+            literal(&mut out, chunk);
+        }
+        out
+    }
+
+    #[test]
+    fn literals() {
+        let mut block = length(5);
+        literal(&mut block, b"hello");
+        assert_eq!(decoded(&block).unwrap(), b"hello");
+
+        // Past 60 bytes, the length follows the tag
+        let text = b"0123456789".repeat(10);
+        let mut block = length(text.len());
+        block.push(60 << 2);
+        block.push(text.len() as u8 - 1);
+        block.extend_from_slice(&text);
+        assert_eq!(decoded(&block).unwrap(), text);
+
+        assert_eq!(decoded(&[0]).unwrap(), b"");
+    }
+
+    #[test]
+    fn overlapping_copies() {
+        // A 1-byte offset copy repeating the two bytes before it
+        let mut block = length(8);
+        literal(&mut block, b"ab");
+        block.extend_from_slice(&[(6 - 4) << 2 | 1, 2]);
+        assert_eq!(decoded(&block).unwrap(), b"abababab");
+
+        // A 2-byte offset copy of a single byte, and a 4-byte offset one reaching back over it
+        let mut block = length(1 + 64 + 3);
+        literal(&mut block, b"x");
+        copy(&mut block, 64, 1);
+        block.extend_from_slice(&[(3 - 1) << 2 | 3, 65, 0, 0, 0]);
+        assert_eq!(decoded(&block).unwrap(), vec![b'x'; 68]);
+    }
+
+    #[test]
+    fn round_trip() {
+        let csv: Vec<u8> = (0..100_000u32).flat_map(|i| format!("{},{}\n", i % 977, i % 13).into_bytes()).collect();
+        let block = compress(&csv);
+        assert!(block.len() < csv.len());
+        assert_eq!(decoded(&block).unwrap(), csv);
+
+        let runs = [vec![b'a'; 1000], b"abcabcabc".repeat(100), vec![0; 300_000]].concat();
+        assert_eq!(decoded(&compress(&runs)).unwrap(), runs);
+    }
+
+    #[test]
+    fn malformed_lengths() {
+        // Cut off, and longer than a u64 can hold
+        assert!(decoded(&[]).unwrap_err().to_string().contains("cut short"));
+        assert!(decoded(&[0x80]).unwrap_err().to_string().contains("cut short"));
+        assert!(decoded(&[0xff; 11]).unwrap_err().to_string().contains("malformed"));
+
+        // A length that doesn't match what the block holds
+        let mut block = length(6);
+        literal(&mut block, b"hello");
+        assert!(decoded(&block).unwrap_err().to_string().contains("less than it says"));
+        let mut block = length(4);
+        literal(&mut block, b"hello");
+        assert!(decoded(&block).unwrap_err().to_string().contains("more than it says"));
+    }
+
+    #[test]
+    fn out_of_range_copies() {
+        // Before the start, with nothing decoded yet, and with an offset of 0
+        let mut block = length(8);
+        literal(&mut block, b"ab");
+        copy(&mut block, 6, 3);
+        assert!(decoded(&block).unwrap_err().to_string().contains("before the start"));
+        let mut block = length(4);
+        copy(&mut block, 4, 1);
+        assert!(decoded(&block).unwrap_err().to_string().contains("before the start"));
+        let mut block = length(8);
+        literal(&mut block, b"ab");
+        copy(&mut block, 6, 0);
+        assert!(decoded(&block).unwrap_err().to_string().contains("before the start"));
+    }
+
+    #[test]
+    fn truncated_elements() {
+        let mut block = length(5);
+        literal(&mut block, b"hello");
+        block.truncate(block.len() - 1);
+        assert!(decoded(&block).unwrap_err().to_string().contains("runs past the end"));
+
+        // Copies missing their offset bytes
+        for tag in [1, 2, 3] {
+            let mut block = length(8);
+            literal(&mut block, b"ab");
+            block.push(tag);
+            assert!(decoded(&block).is_err(), "tag {}", tag);
+        }
+    }
+}
@@ -0,0 +1,68 @@
+// The compute pool (`[compute]`): Polars work (parsing payloads, grouping aggregates, and the queries behind reads)
+// runs on Tokio's blocking threads rather than on the async runtime, so a large payload doesn't stall every other
+// request. At most `workers` jobs run at once and up to `queue` more wait for a turn; requests past that are turned
+// away with a `429` until the pool catches up. Work that's already under way (merging a payload that was accepted, or
+// a background source's rows) waits for a worker instead, so nothing is dropped halfway.
+
+use std::sync::Arc;
+
+use tokio::sync::Semaphore;
+
+use crate::{config::ComputeConfig, error::AppError};
+
+#[derive(Debug)]
+pub struct Compute {
+    // A permit per job running
+    workers: Arc<Semaphore>,
+    // A permit per job running or waiting, taken by the requests `run` admits
+    admitted: Arc<Semaphore>,
+    config: ComputeConfig,
+}
+
+impl Compute {
+    pub fn new(config: &ComputeConfig) -> Self {
+        let workers = config.workers();
+        Compute {
+            workers: Arc::new(Semaphore::new(workers)),
+            admitted: Arc::new(Semaphore::new(workers + config.queue)),
+            config: config.clone(),
+        }
+    }
+
+    // Run a request's job on the pool, or turn the request away if the queue is full
+    pub async fn run<T, F>(&self, job: F) -> Result<T, AppError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let Ok(admitted) = self.admitted.clone().try_acquire_owned() else {
+            return Err(AppError::TooManyRequests(format!(
+                "The service is too busy to take this request (compute.workers = {} and compute.queue = {} are full). Retry \
+                 shortly.",
+                self.config.workers(),
+                self.config.queue
+            )));
+        };
+        let result = self.wait(job).await;
+        drop(admitted);
+        result
+    }
+
+    // Run a job on the pool once a worker is free, however long the queue is
+    pub async fn wait<T, F>(&self, job: F) -> Result<T, AppError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let worker = self.workers.clone().acquire_owned().await.expect("the compute pool is never closed");
+        // The job is logged (and traced) as part of the request it's for
+        let span = tracing::Span::current();
+        tokio::task::spawn_blocking(move || {
+            // Held until the job is done, even if the request it's for is dropped meanwhile
+            let _worker = worker;
+            span.in_scope(job)
+        })
+        .await
+        .map_err(|e| AppError::Internal(format!("A compute job failed: {}", e)))
+    }
+}
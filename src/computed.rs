@@ -0,0 +1,282 @@
+use std::{fmt, iter::Peekable, str::CharIndices, str::FromStr};
+
+use indexmap::IndexMap;
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Column(String),
+    Integer(i64),
+    Float(f64),
+    Neg(Box<Node>),
+    Binary(Box<Node>, BinaryOp, Box<Node>),
+}
+
+impl Node {
+    fn to_expr(&self) -> Expr {
+        match self {
+            Node::Column(name) => col(name.as_str()),
+            Node::Integer(value) => lit(*value),
+            Node::Float(value) => lit(*value),
+            Node::Neg(node) => lit(0) - node.to_expr(),
+            Node::Binary(left, op, right) => {
+                let (left, right) = (left.to_expr(), right.to_expr());
+                match op {
+                    BinaryOp::Add => left + right,
+                    BinaryOp::Sub => left - right,
+                    BinaryOp::Mul => left * right,
+                    // Always a float, so `bytes / seconds` doesn't round down when both are integers
+                    BinaryOp::Div => binary_expr(left, Operator::TrueDivide, right),
+                    BinaryOp::Rem => left % right,
+                }
+            }
+        }
+    }
+
+    fn collect_columns<'a>(&'a self, columns: &mut Vec<&'a str>) {
+        match self {
+            Node::Column(name) if !columns.contains(&name.as_str()) => columns.push(name),
+            Node::Neg(node) => node.collect_columns(columns),
+            Node::Binary(left, _, right) => {
+                left.collect_columns(columns);
+                right.collect_columns(columns);
+            }
+            _ => {}
+        }
+    }
+}
+
+// Arithmetic on a row's other columns, e.g. `bytes / seconds` or `(end_ms - start_ms) / 1000`. Supports `+`, `-`,
+// `*`, `/` (always a float), `%`, parentheses, and numbers. Column names are letters, digits, `_` and `.` (not
+// starting with a digit), or anything in double quotes (`"latency ms" * 2`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Expression {
+    source: String,
+    node: Node,
+}
+
+impl FromStr for Expression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            source: s,
+            chars: s.char_indices().peekable(),
+        };
+        let node = parser.expression()?;
+        parser.skip_spaces();
+        if let Some((pos, c)) = parser.chars.peek() {
+            return Err(format!("Unexpected {:?} at position {} of {:?}", c, pos, s));
+        }
+
+        Ok(Expression {
+            source: s.to_string(),
+            node,
+        })
+    }
+}
+
+impl TryFrom<String> for Expression {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Expression> for String {
+    fn from(expression: Expression) -> Self {
+        expression.source
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Expression {
+    // Columns the expression refers to, in the order they first appear
+    pub fn columns(&self) -> Vec<&str> {
+        let mut columns = Vec::new();
+        self.node.collect_columns(&mut columns);
+        columns
+    }
+
+    pub fn to_expr(&self) -> Expr {
+        self.node.to_expr()
+    }
+}
+
+// Recursive descent over `expression := term (('+' | '-') term)*`, `term := unary (('*' | '/' | '%') unary)*`, and
+// `unary := '-' unary | number | column | '(' expression ')'`
+struct Parser<'a> {
+    source: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+}
+
+impl Parser<'_> {
+    fn skip_spaces(&mut self) {
+        while self.chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+    }
+
+    // The next character that isn't a space, without consuming it
+    fn peek(&mut self) -> Option<char> {
+        self.skip_spaces();
+        self.chars.peek().map(|(_, c)| *c)
+    }
+
+    fn expression(&mut self) -> Result<Node, String> {
+        let mut node = self.term()?;
+        loop {
+            let op = match self.peek() {
+                Some('+') => BinaryOp::Add,
+                Some('-') => BinaryOp::Sub,
+                _ => return Ok(node),
+            };
+            self.chars.next();
+            node = Node::Binary(Box::new(node), op, Box::new(self.term()?));
+        }
+    }
+
+    fn term(&mut self) -> Result<Node, String> {
+        let mut node = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Some('*') => BinaryOp::Mul,
+                Some('/') => BinaryOp::Div,
+                Some('%') => BinaryOp::Rem,
+                _ => return Ok(node),
+            };
+            self.chars.next();
+            node = Node::Binary(Box::new(node), op, Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        match self.peek() {
+            Some('-') => {
+                self.chars.next();
+                Ok(Node::Neg(Box::new(self.unary()?)))
+            }
+            Some('(') => {
+                self.chars.next();
+                let node = self.expression()?;
+                match self.chars.next() {
+                    Some((_, ')')) => Ok(node),
+                    _ => Err(format!("Missing ')' in {:?}", self.source)),
+                }
+            }
+            Some('"') => {
+                self.chars.next();
+                let mut name = String::new();
+                loop {
+                    match self.chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, c)) => name.push(c),
+                        None => return Err(format!("Unterminated column name in {:?}", self.source)),
+                    }
+                }
+                Ok(Node::Column(name))
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => self.number(),
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                let mut name = String::new();
+                while let Some((_, c)) = self.chars.next_if(|(_, c)| c.is_alphanumeric() || matches!(c, '_' | '.')) {
+                    name.push(c);
+                }
+                Ok(Node::Column(name))
+            }
+            Some(c) => {
+                let pos = self.chars.peek().map_or(0, |(pos, _)| *pos);
+                Err(format!("Expected a column, number, or '(' at position {} of {:?}, found {:?}", pos, self.source, c))
+            }
+            None => Err(format!("{:?} ends where a column, number, or '(' was expected", self.source)),
+        }
+    }
+
+    fn number(&mut self) -> Result<Node, String> {
+        let mut number = String::new();
+        while let Some((_, c)) = self.chars.next_if(|(_, c)| c.is_ascii_alphanumeric() || *c == '.') {
+            number.push(c);
+        }
+        if let Ok(value) = number.parse::<i64>() {
+            return Ok(Node::Integer(value));
+        }
+        number
+            .parse::<f64>()
+            .map(Node::Float)
+            .map_err(|_| format!("{:?} in {:?} is not a number", number, self.source))
+    }
+}
+
+// A dataset's computed columns, in the order they're evaluated (`[computed.<dataset>]`, or `PUT /columns/computed`).
+// Each can refer to the payload's columns and to the computed columns before it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "IndexMap<String, Expression>", into = "IndexMap<String, Expression>")]
+pub struct ComputedColumns {
+    columns: IndexMap<String, Expression>,
+}
+
+impl TryFrom<IndexMap<String, Expression>> for ComputedColumns {
+    type Error = String;
+
+    fn try_from(columns: IndexMap<String, Expression>) -> Result<Self, Self::Error> {
+        if columns.contains_key("") {
+            return Err(String::from("Computed columns need a name"));
+        }
+        if let Some((name, _)) = columns.iter().find(|(name, expression)| expression.columns().contains(&name.as_str())) {
+            return Err(format!("Computed column {:?} can't refer to itself", name));
+        }
+
+        Ok(ComputedColumns { columns })
+    }
+}
+
+impl From<ComputedColumns> for IndexMap<String, Expression> {
+    fn from(computed: ComputedColumns) -> Self {
+        computed.columns
+    }
+}
+
+impl ComputedColumns {
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+
+    // The payload with every computed column added (or, if it sent one itself, replaced). Every column an expression
+    // refers to has to be in the payload.
+    pub fn apply(&self, df: &DataFrame) -> Result<DataFrame, String> {
+        if self.is_empty() {
+            return Ok(df.clone());
+        }
+        let mut available: Vec<&str> = df.get_column_names_str();
+        let mut rows = df.clone().lazy();
+
+        for (name, expression) in &self.columns {
+            if let Some(missing) = expression.columns().into_iter().find(|column| !available.contains(column)) {
+                return Err(format!(
+                    "Computed column {:?} ({}) needs column {:?}, which the payload doesn't have",
+                    name, expression, missing
+                ));
+            }
+            rows = rows.with_column(expression.to_expr().alias(name.as_str()));
+            available.push(name);
+        }
+
+        rows.collect()
+            .map_err(|e| format!("Can't compute {:?}: {}", self.columns.keys().collect::<Vec<_>>(), e))
+    }
+}
@@ -0,0 +1,401 @@
+// A small TOML reader covering what collator config files need: tables, dotted keys, strings, integers, floats,
+// booleans, arrays, and inline tables. Documents are turned into JSON values so they can be deserialized with serde.
+// Not supported: arrays of tables (`[[...]]`), multi-line strings, and dates.
+
+use std::collections::HashSet;
+
+use serde_json::{Map, Number, Value};
+
+pub fn parse(source: &str) -> Result<Value, String> {
+    let mut parser = Parser {
+        chars: source.chars().collect(),
+        pos: 0,
+        line: 1,
+    };
+    parser.document().map(Value::Object)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn error(&self, message: impl AsRef<str>) -> String {
+        format!("line {}: {}", self.line, message.as_ref())
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.bump() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(self.error(format!("expected {:?}, found {:?}", expected, c))),
+            None => Err(self.error(format!("expected {:?}, found end of file", expected))),
+        }
+    }
+
+    // Skip spaces and tabs (but not newlines)
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.bump();
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.bump();
+            }
+        }
+    }
+
+    // Skip whitespace, newlines, and comments
+    fn skip_blank(&mut self) {
+        loop {
+            self.skip_spaces();
+            self.skip_comment();
+            match self.peek() {
+                Some('\n' | '\r') => {
+                    self.bump();
+                }
+                _ => return,
+            }
+        }
+    }
+
+    // After a key/value pair or table header, only a comment may follow on the same line
+    fn end_of_line(&mut self) -> Result<(), String> {
+        self.skip_spaces();
+        self.skip_comment();
+        match self.peek() {
+            None => Ok(()),
+            Some('\r' | '\n') => {
+                self.bump();
+                Ok(())
+            }
+            Some(c) => Err(self.error(format!("unexpected {:?} after value", c))),
+        }
+    }
+
+    fn document(&mut self) -> Result<Map<String, Value>, String> {
+        let mut root = Map::new();
+        let mut current: Vec<String> = Vec::new();
+        // The tables with headers so far, which can't have another
+        let mut headers: HashSet<Vec<String>> = HashSet::new();
+
+        loop {
+            self.skip_blank();
+            // Where the statement starts, since it ends after the newline
+            let line = self.line;
+            match self.peek() {
+                None => return Ok(root),
+                Some('[') => {
+                    self.bump();
+                    if self.peek() == Some('[') {
+                        return Err(self.error("arrays of tables are not supported"));
+                    }
+                    self.skip_spaces();
+                    current = self.key()?;
+                    self.skip_spaces();
+                    self.expect(']')?;
+                    self.end_of_line()?;
+
+                    if !headers.insert(current.clone()) {
+                        let table = current.join(".");
+                        return Err(format!("line {}: the table {:?} is defined more than once", line, table));
+                    }
+                    // Make sure the table exists even if it ends up empty
+                    table_at(&mut root, &current).map_err(|e| format!("line {}: {}", line, e))?;
+                }
+                Some(_) => {
+                    let key = self.key()?;
+                    self.skip_spaces();
+                    self.expect('=')?;
+                    self.skip_spaces();
+                    let value = self.value()?;
+                    self.end_of_line()?;
+
+                    let path: Vec<String> = current.iter().chain(key.iter()).cloned().collect();
+                    insert(&mut root, &path, value).map_err(|e| format!("line {}: {}", line, e))?;
+                }
+            }
+        }
+    }
+
+    // A possibly dotted key, e.g. `server.port` or `"quoted key"`
+    fn key(&mut self) -> Result<Vec<String>, String> {
+        let mut parts = Vec::new();
+
+        loop {
+            self.skip_spaces();
+            let part = match self.peek() {
+                Some('"') => self.basic_string()?,
+                Some('\'') => self.literal_string()?,
+                _ => {
+                    let start = self.pos;
+                    while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                        self.bump();
+                    }
+                    if start == self.pos {
+                        return Err(self.error("expected a key"));
+                    }
+                    self.chars[start..self.pos].iter().collect()
+                }
+            };
+            parts.push(part);
+
+            self.skip_spaces();
+            if self.peek() == Some('.') {
+                self.bump();
+            } else {
+                return Ok(parts);
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.peek() {
+            Some('"') => self.basic_string().map(Value::String),
+            Some('\'') => self.literal_string().map(Value::String),
+            Some('[') => self.array(),
+            Some('{') => self.inline_table(),
+            Some(_) => self.scalar(),
+            None => Err(self.error("expected a value")),
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut out = String::new();
+
+        loop {
+            let c = match self.peek() {
+                None | Some('\n') => return Err(self.error("unterminated string")),
+                Some(c) => c,
+            };
+            self.bump();
+
+            match c {
+                '"' => return Ok(out),
+                '\\' => match self.bump() {
+                    Some('n') => out.push('\n'),
+                    Some('t') => out.push('\t'),
+                    Some('r') => out.push('\r'),
+                    Some('"') => out.push('"'),
+                    Some('\\') => out.push('\\'),
+                    Some('u') => {
+                        let hex: String = (0..4).filter_map(|_| self.bump()).collect();
+                        let c = u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| self.error(format!("invalid unicode escape \\u{}", hex)))?;
+                        out.push(c);
+                    }
+                    other => return Err(self.error(format!("invalid escape {:?}", other))),
+                },
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<String, String> {
+        self.expect('\'')?;
+        let mut out = String::new();
+
+        loop {
+            match self.peek() {
+                None | Some('\n') => return Err(self.error("unterminated string")),
+                Some('\'') => {
+                    self.bump();
+                    return Ok(out);
+                }
+                Some(c) => {
+                    self.bump();
+                    out.push(c);
+                }
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.expect('[')?;
+        let mut values = Vec::new();
+
+        loop {
+            self.skip_blank();
+            if self.peek() == Some(']') {
+                self.bump();
+                return Ok(Value::Array(values));
+            }
+
+            values.push(self.value()?);
+
+            self.skip_blank();
+            match self.bump() {
+                Some(',') => continue,
+                Some(']') => return Ok(Value::Array(values)),
+                _ => return Err(self.error("expected ',' or ']' in array")),
+            }
+        }
+    }
+
+    fn inline_table(&mut self) -> Result<Value, String> {
+        self.expect('{')?;
+        let mut table = Map::new();
+
+        self.skip_spaces();
+        if self.peek() == Some('}') {
+            self.bump();
+            return Ok(Value::Object(table));
+        }
+
+        loop {
+            let key = self.key()?;
+            self.skip_spaces();
+            self.expect('=')?;
+            self.skip_spaces();
+            let value = self.value()?;
+            insert(&mut table, &key, value).map_err(|e| self.error(e))?;
+
+            self.skip_spaces();
+            match self.bump() {
+                Some(',') => self.skip_spaces(),
+                Some('}') => return Ok(Value::Object(table)),
+                _ => return Err(self.error("expected ',' or '}' in inline table")),
+            }
+        }
+    }
+
+    // Booleans, integers, and floats
+    fn scalar(&mut self) -> Result<Value, String> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.' | '_')) {
+            self.bump();
+        }
+        let raw: String = self.chars[start..self.pos].iter().collect();
+
+        match raw.as_str() {
+            "true" => return Ok(Value::Bool(true)),
+            "false" => return Ok(Value::Bool(false)),
+            _ => (),
+        }
+
+        let digits = raw.replace('_', "");
+        if let Ok(int) = digits.parse::<i64>() {
+            return Ok(Value::Number(int.into()));
+        }
+        if let Some(float) = digits.parse::<f64>().ok().and_then(Number::from_f64) {
+            return Ok(Value::Number(float));
+        }
+
+        Err(self.error(format!("invalid value {:?} (strings must be quoted)", raw)))
+    }
+}
+
+// Get (creating if needed) the table at `path`
+fn table_at<'a>(root: &'a mut Map<String, Value>, path: &[String]) -> Result<&'a mut Map<String, Value>, String> {
+    let mut table = root;
+
+    for part in path {
+        let entry = table
+            .entry(part.clone())
+            .or_insert_with(|| Value::Object(Map::new()));
+        table = match entry {
+            Value::Object(inner) => inner,
+            _ => return Err(format!("{:?} is already defined as a value", part)),
+        };
+    }
+
+    Ok(table)
+}
+
+fn insert(root: &mut Map<String, Value>, path: &[String], value: Value) -> Result<(), String> {
+    let (last, parents) = path.split_last().expect("keys always have at least one part");
+    let table = table_at(root, parents)?;
+
+    if table.contains_key(last) {
+        return Err(format!("{:?} is defined more than once", path.join(".")));
+    }
+    table.insert(last.clone(), value);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn documents() {
+        let source = r#"
+# A comment
+name = "collator" # and another
+[server]
+port = 8080
+bind = '0.0.0.0'
+tags = ["a", "b",
+        "c"]
+
+[storage.files]
+max = 1_000
+ratio = 0.5
+enabled = true
+limits = { rows = 10, "bytes" = 2 }
+"#;
+        assert_eq!(
+            parse(source).unwrap(),
+            json!({
+                "name": "collator",
+                "server": {"port": 8080, "bind": "0.0.0.0", "tags": ["a", "b", "c"]},
+                "storage": {"files": {"max": 1000, "ratio": 0.5, "enabled": true, "limits": {"rows": 10, "bytes": 2}}},
+            })
+        );
+        assert_eq!(parse("a.b = 1\na.c = \"\\u00e9\\t\"").unwrap(), json!({"a": {"b": 1, "c": "é\t"}}));
+        assert_eq!(parse("[empty]").unwrap(), json!({"empty": {}}));
+    }
+
+    #[test]
+    fn errors_name_the_line_they_are_on() {
+        assert_eq!(
+            parse("[server]\nport = 1\nport = 2\n").unwrap_err(),
+            "line 3: \"server.port\" is defined more than once"
+        );
+        assert_eq!(parse("a = 1\n[a.b]\nc = 2").unwrap_err(), "line 2: \"a\" is already defined as a value");
+        assert_eq!(parse("a = 1\nb = x\n").unwrap_err(), "line 2: invalid value \"x\" (strings must be quoted)");
+        assert_eq!(parse("\n\na = 1 2").unwrap_err(), "line 3: unexpected '2' after value");
+        assert_eq!(parse("a = \"open\nb = 1").unwrap_err(), "line 1: unterminated string");
+    }
+
+    #[test]
+    fn tables_are_defined_once() {
+        assert_eq!(
+            parse("[server]\nport = 1\n\n[server]\nbind = \"x\"\n").unwrap_err(),
+            "line 4: the table \"server\" is defined more than once"
+        );
+        assert!(parse("[a.b]\nx = 1\n[ a . b ]\n").is_err());
+        // A table a header created implicitly can still get one of its own
+        assert_eq!(parse("[a.b]\nx = 1\n[a]\ny = 2\n").unwrap(), json!({"a": {"b": {"x": 1}, "y": 2}}));
+    }
+
+    #[test]
+    fn unsupported() {
+        assert!(parse("[[points]]").unwrap_err().contains("arrays of tables"));
+        assert!(parse("t = 1979-05-27").is_err());
+        assert!(parse("a = {b = 1, b = 2}").unwrap_err().contains("more than once"));
+    }
+}
@@ -0,0 +1,161 @@
+// Parsing CSV request bodies as they arrive. Bodies too large to keep in memory (`server.stream_body_bytes`) are cut
+// into batches of whole records as the bytes come in, and each batch is parsed on the compute pool while the next one
+// is received, so an upload is mostly parsed by the time it's done and the raw body is never held all at once. The
+// first batch's dtypes (inferred from its first rows, as for any CSV payload) are used for the rest, so the result is
+// the same as parsing the whole body in one go.
+
+use std::{io::Cursor, sync::Arc};
+
+use polars::prelude::*;
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+
+use crate::{dataset::AppState, error::AppError};
+
+type Parse = JoinHandle<Result<DataFrame, AppError>>;
+
+pub struct CsvStream {
+    state: Arc<AppState>,
+    // Roughly how many bytes of records go into a batch
+    batch_bytes: usize,
+    // The header line, once it's been received
+    header: Option<Arc<[u8]>>,
+    // Bytes received since the last batch
+    pending: Vec<u8>,
+    // How far `pending` has been scanned for the ends of records, whether that's inside a quoted field, and where the
+    // first and last whole records ended
+    scanned: usize,
+    quoted: bool,
+    first_end: Option<usize>,
+    records_end: usize,
+    // Bytes of the body that went into batches, for the errors of the batch after
+    offset: usize,
+    // The batch being parsed, and the ones that were
+    parsing: Option<Parse>,
+    batches: Vec<DataFrame>,
+}
+
+impl CsvStream {
+    pub fn new(state: Arc<AppState>, batch_bytes: usize) -> Self {
+        CsvStream {
+            state,
+            batch_bytes: batch_bytes.max(1),
+            header: None,
+            pending: Vec::new(),
+            scanned: 0,
+            quoted: false,
+            first_end: None,
+            records_end: 0,
+            offset: 0,
+            parsing: None,
+            batches: Vec::new(),
+        }
+    }
+
+    // Take in more of the body, starting to parse the records received so far once there's a batch of them
+    pub async fn push(&mut self, bytes: &[u8]) -> Result<(), AppError> {
+        self.pending.extend_from_slice(bytes);
+        // A quote toggles whether newlines end records; an escaped quote (`""`) toggles it twice
+        for (i, byte) in self.pending[self.scanned..].iter().enumerate() {
+            match byte {
+                b'"' => self.quoted = !self.quoted,
+                b'\n' if !self.quoted => {
+                    self.records_end = self.scanned + i + 1;
+                    self.first_end.get_or_insert(self.records_end);
+                }
+                _ => {}
+            }
+        }
+        self.scanned = self.pending.len();
+
+        // The first record is the header
+        if self.header.is_none()
+            && let Some(end) = self.first_end
+        {
+            let rest = self.pending.split_off(end);
+            self.header = Some(Arc::from(std::mem::replace(&mut self.pending, rest)));
+            self.offset = end;
+            self.scanned -= end;
+            self.records_end -= end;
+        }
+        if self.header.is_some() && self.records_end >= self.batch_bytes {
+            let rest = self.pending.split_off(self.records_end);
+            let records = std::mem::replace(&mut self.pending, rest);
+            self.scanned -= self.records_end;
+            self.records_end = 0;
+            self.parse(records).await?;
+        }
+
+        Ok(())
+    }
+
+    // Parse what's left, and return all of the body's rows
+    pub async fn finish(mut self) -> Result<DataFrame, AppError> {
+        let mut rest = std::mem::take(&mut self.pending);
+        // A body without a single line break is all header
+        if self.header.is_none() {
+            self.header = Some(Arc::from(std::mem::take(&mut rest)));
+        }
+        // The last record may not end with a line break. A header with no records still has its columns.
+        let started = self.parsing.is_some() || !self.batches.is_empty();
+        if !rest.iter().all(u8::is_ascii_whitespace) || !started {
+            self.parse(rest).await?;
+        }
+        self.settle().await?;
+
+        let mut batches = std::mem::take(&mut self.batches).into_iter();
+        let mut df = batches.next().unwrap_or_default();
+        for batch in batches {
+            df = df.vstack(&batch).map_err(|e| AppError::BadRequest(format!("Error parsing CSV: {}", e)))?;
+        }
+        Ok(df)
+    }
+
+    // Start parsing a batch of records, once the one before is done (its dtypes are this one's)
+    async fn parse(&mut self, records: Vec<u8>) -> Result<(), AppError> {
+        self.settle().await?;
+        let header = self.header.clone().unwrap_or_else(|| Arc::from(Vec::new()));
+        let schema = self.batches.first().map(|first| first.schema().clone());
+        let offset = self.offset;
+        self.offset += records.len();
+
+        let state = self.state.clone();
+        self.parsing = Some(tokio::spawn(async move {
+            let job = move || {
+                tracing::info_span!("parse").in_scope(|| {
+                    let mut csv = Vec::with_capacity(header.len() + records.len());
+                    csv.extend_from_slice(&header);
+                    csv.extend_from_slice(&records);
+                    drop(records);
+                    CsvReadOptions::default()
+                        .with_schema(schema)
+                        .into_reader_with_file_handle(Cursor::new(csv))
+                        .finish()
+                        .map_err(|e| format!("Error parsing CSV (in the records from byte {} on): {}", offset, e))
+                })
+            };
+            state.compute.run(job).await?.map_err(AppError::BadRequest)
+        }
+        .instrument(tracing::Span::current())));
+
+        Ok(())
+    }
+
+    // Wait for the batch being parsed, if there is one
+    async fn settle(&mut self) -> Result<(), AppError> {
+        if let Some(parsing) = self.parsing.take() {
+            let batch = parsing.await.map_err(|e| AppError::Internal(format!("Parsing the body failed: {}", e)))??;
+            self.batches.push(batch);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CsvStream {
+    // A body that's turned away halfway (too large, or cut off) doesn't need its last batch
+    fn drop(&mut self) {
+        if let Some(parsing) = self.parsing.take() {
+            parsing.abort();
+        }
+    }
+}
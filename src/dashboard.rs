@@ -0,0 +1,337 @@
+// A dashboard for operators at `/ui`: every dataset's row count, the selected dataset's columns and per-key
+// aggregates, and the changes the datasets go through, live. It's a single page with no dependencies, reading the API
+// it's served alongside with relative URLs, so it works under `/v1` (or any prefix) too.
+
+pub const DASHBOARD: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <title>data_collator</title>
+  <style>
+    body { font: 14px system-ui, sans-serif; margin: 0; color: #222; background: #f6f7f9; }
+    header { display: flex; align-items: center; gap: 1em; padding: 0.7em 1.2em; background: #223; color: #fff; }
+    header h1 { font-size: 1.1em; margin: 0; flex: 1; }
+    header input { width: 16em; }
+    main { display: grid; grid-template-columns: 1fr 1fr; gap: 1em; padding: 1em 1.2em; }
+    section { background: #fff; border: 1px solid #dde; border-radius: 6px; padding: 0.8em 1em; overflow: auto; }
+    section.wide { grid-column: 1 / -1; }
+    h2 { font-size: 1em; margin: 0 0 0.6em; }
+    table { border-collapse: collapse; width: 100%; }
+    th, td { text-align: left; padding: 0.25em 0.6em; border-bottom: 1px solid #eee; white-space: nowrap; }
+    td.n { text-align: right; font-variant-numeric: tabular-nums; }
+    tr.selected td { background: #eef3ff; }
+    tbody tr[data-name] { cursor: pointer; }
+    .muted { color: #888; }
+    .error { color: #b00; }
+    #chart { width: 100%; height: 220px; }
+    #legend span { margin-right: 1.2em; }
+    #legend i { display: inline-block; width: 0.8em; height: 0.8em; margin-right: 0.3em; border-radius: 2px; }
+    form { display: flex; gap: 0.5em; margin-bottom: 0.6em; flex-wrap: wrap; }
+  </style>
+</head>
+<body>
+  <header>
+    <h1>data_collator</h1>
+    <span id="status" class="muted">Connecting...</span>
+    <input id="key" type="password" placeholder="API key (if the service needs one)">
+  </header>
+  <main>
+    <section>
+      <h2>Datasets</h2>
+      <table>
+        <thead><tr><th>Name</th><th>Rows</th><th>Last change</th></tr></thead>
+        <tbody id="datasets"></tbody>
+      </table>
+    </section>
+    <section>
+      <h2>Schema of <span id="schema-name" class="muted">(select a dataset)</span></h2>
+      <table>
+        <thead><tr><th>Column</th><th>Dtype</th><th>Nulls</th><th>Declared</th></tr></thead>
+        <tbody id="schema"></tbody>
+      </table>
+    </section>
+    <section class="wide">
+      <h2>Rows over time</h2>
+      <svg id="chart" preserveAspectRatio="none"></svg>
+      <div id="legend" class="muted">Row counts show up here as they change.</div>
+    </section>
+    <section>
+      <h2>Per-key aggregates</h2>
+      <form id="aggregates">
+        <label>Key <select id="group"></select></label>
+        <label>Metric <select id="by"></select></label>
+        <label>Operation
+          <select id="op">
+            <option>sum</option><option>mean</option><option>min</option><option>max</option>
+            <option>count</option><option>median</option><option>std</option><option>p95</option><option>p99</option>
+            <option>distinct</option>
+          </select>
+        </label>
+      </form>
+      <table>
+        <thead id="aggregate-head"></thead>
+        <tbody id="aggregate-rows"></tbody>
+      </table>
+    </section>
+    <section>
+      <h2>Recent ingests <span class="muted">(since this page was opened)</span></h2>
+      <table>
+        <thead><tr><th>Time</th><th>Dataset</th><th>Operation</th><th>Rows</th><th>Total</th></tr></thead>
+        <tbody id="ingests"></tbody>
+      </table>
+    </section>
+  </main>
+  <script>
+    // How many changes the recent ingests list and the chart keep, and how often row counts are re-read
+    const MAX_INGESTS = 50;
+    const MAX_POINTS = 500;
+    const REFRESH_MS = 10000;
+    const COLORS = ["#3366cc", "#dc3912", "#ff9900", "#109618", "#990099", "#0099c6", "#dd4477", "#66aa00"];
+    const NUMERIC = /^(i|u)(8|16|32|64)$|^f(32|64)$/;
+
+    const datasets = new Map();
+    const series = new Map();
+    let selected = null;
+    let stream = null;
+
+    const keyInput = document.getElementById("key");
+    keyInput.value = localStorage.getItem("data_collator.key") || "";
+    keyInput.addEventListener("change", () => {
+      localStorage.setItem("data_collator.key", keyInput.value);
+      if (stream) stream.abort();
+      refresh();
+      follow();
+    });
+
+    function headers() {
+      const headers = { Accept: "application/json" };
+      if (keyInput.value) headers.Authorization = "Bearer " + keyInput.value;
+      return headers;
+    }
+
+    // Routes on the default dataset are unprefixed, the others are under `datasets/{name}/`
+    function route(name, path) {
+      return name === "default" ? path : "datasets/" + encodeURIComponent(name) + "/" + path;
+    }
+
+    async function get(path) {
+      const response = await fetch(path, { headers: headers() });
+      const body = await response.json();
+      if (!response.ok) throw new Error(body.message || response.statusText);
+      return body;
+    }
+
+    function setStatus(text, error) {
+      const status = document.getElementById("status");
+      status.textContent = text;
+      status.className = error ? "error" : "muted";
+    }
+
+    function cell(row, text, numeric) {
+      const td = row.insertCell();
+      td.textContent = text === null || text === undefined ? "" : text;
+      if (numeric) td.className = "n";
+      return td;
+    }
+
+    async function refresh() {
+      try {
+        const { datasets: names } = await get("datasets");
+        for (const name of names) {
+          const { total_rows } = await get(route(name, "data?limit=0"));
+          const dataset = datasets.get(name) || { changed: null };
+          dataset.rows = total_rows;
+          datasets.set(name, dataset);
+          record(name, total_rows);
+        }
+        for (const name of [...datasets.keys()]) {
+          if (!names.includes(name)) datasets.delete(name);
+        }
+        if (selected === null && names.length > 0) selected = names[0];
+        renderDatasets();
+        if (selected !== null) await showDataset(selected);
+      } catch (e) {
+        setStatus(e.message, true);
+      }
+    }
+
+    function renderDatasets() {
+      const body = document.getElementById("datasets");
+      body.replaceChildren();
+      for (const [name, dataset] of datasets) {
+        const row = body.insertRow();
+        row.dataset.name = name;
+        if (name === selected) row.className = "selected";
+        cell(row, name);
+        cell(row, dataset.rows.toLocaleString(), true);
+        cell(row, dataset.changed ? dataset.changed.toLocaleTimeString() : "-");
+        row.addEventListener("click", () => {
+          selected = name;
+          renderDatasets();
+          showDataset(name);
+        });
+      }
+    }
+
+    async function showDataset(name) {
+      const schema = await get(route(name, "schema"));
+      const declared = schema.declared ? schema.declared.columns : {};
+      document.getElementById("schema-name").textContent = name;
+      const body = document.getElementById("schema");
+      body.replaceChildren();
+      for (const column of schema.columns) {
+        const row = body.insertRow();
+        cell(row, column.name);
+        cell(row, column.dtype);
+        cell(row, column.null_count.toLocaleString(), true);
+        cell(row, declared[column.name] || "");
+      }
+
+      // Keep the key and metric picked before, as long as the dataset still has them
+      const group = document.getElementById("group");
+      const by = document.getElementById("by");
+      const keep = (select, options) => {
+        const previous = select.value;
+        select.replaceChildren(...options.map((name) => new Option(name, name)));
+        if (options.includes(previous)) select.value = previous;
+      };
+      keep(group, schema.columns.filter((c) => !NUMERIC.test(c.dtype)).map((c) => c.name));
+      keep(by, schema.columns.filter((c) => NUMERIC.test(c.dtype)).map((c) => c.name));
+      await showAggregates();
+    }
+
+    async function showAggregates() {
+      const head = document.getElementById("aggregate-head");
+      const body = document.getElementById("aggregate-rows");
+      const group = document.getElementById("group").value;
+      const by = document.getElementById("by").value;
+      const op = document.getElementById("op").value;
+      body.replaceChildren();
+      if (!group || !by) {
+        head.replaceChildren();
+        body.insertRow().insertCell().textContent = "Needs a text key column and a numeric metric column";
+        return;
+      }
+
+      const query = new URLSearchParams({ group, by, op, k: "20" });
+      const { rows } = await get(route(selected, "top?" + query));
+      head.replaceChildren();
+      const titles = head.insertRow();
+      for (const name of rows.length > 0 ? Object.keys(rows[0]) : [group, by]) {
+        titles.appendChild(document.createElement("th")).textContent = name;
+      }
+      for (const record of rows) {
+        const row = body.insertRow();
+        for (const value of Object.values(record)) {
+          cell(row, typeof value === "number" ? value.toLocaleString() : value, typeof value === "number");
+        }
+      }
+    }
+
+    for (const id of ["group", "by", "op"]) {
+      document.getElementById(id).addEventListener("change", () => {
+        showAggregates().catch((e) => setStatus(e.message, true));
+      });
+    }
+
+    function changed(change) {
+      const now = new Date();
+      const dataset = datasets.get(change.dataset) || {};
+      dataset.rows = change.total_rows;
+      dataset.changed = now;
+      datasets.set(change.dataset, dataset);
+      renderDatasets();
+
+      const body = document.getElementById("ingests");
+      const row = body.insertRow(0);
+      cell(row, now.toLocaleTimeString());
+      cell(row, change.dataset);
+      cell(row, change.operation);
+      cell(row, change.changed_rows.toLocaleString(), true);
+      cell(row, change.total_rows.toLocaleString(), true);
+      while (body.rows.length > MAX_INGESTS) body.deleteRow(-1);
+
+      record(change.dataset, change.total_rows);
+
+      if (change.dataset === selected) showDataset(selected).catch((e) => setStatus(e.message, true));
+    }
+
+    // Add a point to a dataset's line, unless its row count hasn't changed since the last one
+    function record(name, rows) {
+      const points = series.get(name) || [];
+      if (points.length > 0 && points[points.length - 1][1] === rows) return;
+      points.push([Date.now(), rows]);
+      if (points.length > MAX_POINTS) points.shift();
+      series.set(name, points);
+      drawChart();
+    }
+
+    function drawChart() {
+      const svg = document.getElementById("chart");
+      const width = svg.clientWidth;
+      const height = svg.clientHeight;
+      const all = [...series.values()].flat();
+      if (all.length === 0) return;
+      const [t0, t1] = [Math.min(...all.map((p) => p[0])), Math.max(...all.map((p) => p[0]))];
+      const top = Math.max(1, ...all.map((p) => p[1]));
+      const x = (t) => (t1 === t0 ? width / 2 : ((t - t0) / (t1 - t0)) * (width - 20) + 10);
+      const y = (rows) => height - 10 - (rows / top) * (height - 20);
+
+      svg.setAttribute("viewBox", `0 0 ${width} ${height}`);
+      svg.replaceChildren();
+      const legend = document.getElementById("legend");
+      legend.replaceChildren();
+      [...series.entries()].forEach(([name, points], i) => {
+        const color = COLORS[i % COLORS.length];
+        const line = document.createElementNS("http://www.w3.org/2000/svg", "polyline");
+        line.setAttribute("points", points.map(([t, rows]) => `${x(t)},${y(rows)}`).join(" "));
+        line.setAttribute("fill", "none");
+        line.setAttribute("stroke", color);
+        line.setAttribute("stroke-width", "2");
+        svg.appendChild(line);
+
+        const entry = legend.appendChild(document.createElement("span"));
+        entry.appendChild(document.createElement("i")).style.background = color;
+        entry.append(`${name} (${points[points.length - 1][1].toLocaleString()} rows)`);
+      });
+    }
+
+    // `/events` is read with fetch rather than EventSource, which can't send an API key
+    async function follow() {
+      stream = new AbortController();
+      try {
+        const response = await fetch("events?rows=false", { headers: headers(), signal: stream.signal });
+        if (!response.ok) throw new Error((await response.json()).message);
+        setStatus("Live");
+        const reader = response.body.pipeThrough(new TextDecoderStream()).getReader();
+        let buffer = "";
+        for (;;) {
+          const { value, done } = await reader.read();
+          if (done) break;
+          buffer += value;
+          let end;
+          while ((end = buffer.indexOf("\n\n")) >= 0) {
+            const message = buffer.slice(0, end);
+            buffer = buffer.slice(end + 2);
+            const event = (message.match(/^event: (.*)$/m) || [])[1];
+            const data = message.split("\n").filter((l) => l.startsWith("data: ")).map((l) => l.slice(6)).join("\n");
+            if (event === "change") changed(JSON.parse(data));
+            if (event === "lagged") refresh();
+          }
+        }
+        throw new Error("The event stream closed");
+      } catch (e) {
+        if (e.name === "AbortError") return;
+        setStatus(e.message + "; reconnecting...", true);
+        setTimeout(follow, 5000);
+      }
+    }
+
+    window.addEventListener("resize", drawChart);
+    refresh();
+    follow();
+    setInterval(refresh, REFRESH_MS);
+  </script>
+</body>
+</html>
+"##;
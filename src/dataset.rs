@@ -0,0 +1,860 @@
+use std::{collections::{HashMap, HashSet, VecDeque}, path::PathBuf, str::FromStr, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::{
+    accounting::Accounting,
+    aggregate::{cast_like, group_by_spec, AggregateMode, AggregateSpec, AggregateState, RunningAggregate},
+    audit::AuditLog,
+    clickhouse::ClickHouseSink,
+    completeness::Completeness,
+    compute::Compute,
+    computed::ComputedColumns,
+    config::{Config, StorageConfig},
+    events::Events,
+    filter::Filter,
+    forward::Forwarder,
+    idempotency::IdempotencyCache,
+    lookup::Lookups,
+    metrics::Metrics,
+    nulls::{self, FillSpec},
+    outliers::OutlierSpec,
+    partition::{Compaction, PartitionedOutput},
+    persist,
+    postgres::PostgresSink,
+    provenance::{self, BatchCounter},
+    rate_limit::RateLimiter,
+    replication::Replication,
+    retention::Eviction,
+    s3::S3Exporter,
+    schema::DatasetSchema,
+    spill::{Rows, Spilled},
+    wal::Wal,
+    writer::Writer,
+};
+
+// Name of the dataset used by the top-level `/collate` and `/aggregate` routes
+pub const DEFAULT_DATASET: &str = "default";
+
+// Added to the payload's copy of a column while `/collate_wide` merges it with the dataset's
+const WIDE_SUFFIX: &str = "_collate_wide";
+
+// How `/collate` combines a payload with the data a dataset already has
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", rename_all = "lowercase")]
+pub enum ConcatMode {
+    // Columns must match exactly (names, order, and dtypes)
+    #[default]
+    Strict,
+    // Align columns by name: missing ones are filled with nulls, new ones are added to the dataset, and dtypes are
+    // widened to a common supertype where they differ
+    Union,
+}
+
+impl FromStr for ConcatMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "strict" | "vertical" => Ok(ConcatMode::Strict),
+            "union" | "diagonal" => Ok(ConcatMode::Union),
+            other => Err(format!("Unsupported concat mode {:?} (expected strict or union)", other)),
+        }
+    }
+}
+
+impl TryFrom<String> for ConcatMode {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+// Which of a set of duplicate rows `/dedup` keeps
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", rename_all = "lowercase")]
+pub enum KeepDuplicate {
+    #[default]
+    First,
+    Last,
+    // Drop every row that has a duplicate
+    None,
+}
+
+impl FromStr for KeepDuplicate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "first" => Ok(KeepDuplicate::First),
+            "last" => Ok(KeepDuplicate::Last),
+            "none" => Ok(KeepDuplicate::None),
+            other => Err(format!("Unsupported keep {:?} (expected first, last, or none)", other)),
+        }
+    }
+}
+
+impl TryFrom<String> for KeepDuplicate {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<KeepDuplicate> for UniqueKeepStrategy {
+    fn from(keep: KeepDuplicate) -> Self {
+        match keep {
+            KeepDuplicate::First => UniqueKeepStrategy::First,
+            KeepDuplicate::Last => UniqueKeepStrategy::Last,
+            KeepDuplicate::None => UniqueKeepStrategy::None,
+        }
+    }
+}
+
+// A single named collection of data, with its own lock and output file
+#[derive(Debug, Default)]
+pub struct Dataset {
+    // The "source of truth" dataframe for this dataset
+    pub df: Option<DataFrame>,
+    // What `/aggregate` recomputes `df` from: the raw rows received so far, or running totals per key
+    pub aggregate_state: Option<AggregateState>,
+    pub output_file: Option<PathBuf>,
+    // Where the dataset is also written as hive-style partitions, if it's partitioned
+    pub partitions: Option<PartitionedOutput>,
+    // Columns and dtypes `/collate` payloads must have, if declared
+    pub schema: Option<DatasetSchema>,
+    // Columns added to every payload, worked out from its other columns
+    pub computed: Option<ComputedColumns>,
+    // Rows that failed the dataset's validation rules, with the reason why
+    pub quarantine: Option<DataFrame>,
+    // The latest ingests, for `POST /admin/rollback` to undo and `as_of` reads to look back through
+    pub ledger: Ledger,
+    // The oldest rows, when they were spilled to disk to keep within `storage.memory_budget_bytes`. They come
+    // before `df`.
+    pub spilled: Spilled,
+}
+
+// A dataset's latest ingests, oldest first
+#[derive(Debug, Default)]
+pub struct Ledger {
+    batches: VecDeque<Batch>,
+    // Since when the batches (and the rows the dataset has now) tell what its rows were, if that's known
+    since: Option<DateTime<Utc>>,
+}
+
+impl Ledger {
+    // Forget every batch, now that the rows were changed some other way
+    pub fn clear(&mut self) {
+        self.clear_at(Some(Utc::now()));
+    }
+
+    // The same, for a change made at `at` (`None` when it isn't known when)
+    pub fn clear_at(&mut self, at: Option<DateTime<Utc>>) {
+        self.batches.clear();
+        self.since = at;
+    }
+}
+
+// One of a dataset's latest ingests, with the rows (and aggregate state) the dataset had before it. Frames share
+// their columns with the ones they were cloned from, so keeping appends costs little, but an upsert or wide collate
+// keeps a whole earlier copy of the dataset.
+#[derive(Debug, Clone)]
+pub struct Batch {
+    // `collate`, `aggregate`, `upsert`, or `collate_wide`
+    pub operation: &'static str,
+    // Rows the payload had
+    pub rows: usize,
+    // When it was applied (`None` for ones replayed from write-ahead log records older than timestamps)
+    at: Option<DateTime<Utc>>,
+    df: Option<DataFrame>,
+    aggregate_state: Option<AggregateState>,
+}
+
+impl Dataset {
+    // The dataset's frame with a payload concatenated onto it. Nothing is changed until the caller stores it.
+    pub fn collated(&self, df: &DataFrame, mode: ConcatMode) -> PolarsResult<DataFrame> {
+        let Some(state_df) = self.df.as_ref() else {
+            return Ok(df.clone());
+        };
+
+        match mode {
+            ConcatMode::Strict => state_df.vstack(df),
+            ConcatMode::Union => {
+                let args = UnionArgs {
+                    rechunk: false,
+                    to_supertypes: true,
+                    ..Default::default()
+                };
+                concat_lf_diagonal([state_df.clone().lazy(), df.clone().lazy()], args)?.collect()
+            }
+        }
+    }
+
+    // The payload without the rows that are exact copies of a row the dataset already has, or of an earlier row in the
+    // payload. Rows are compared as they'd be stored (so in union mode, a payload row that leaves out a column matches
+    // an existing row that's null there), ignoring the `ignore` columns. Every existing row is hashed each time, so
+    // this costs a pass over the dataset per payload.
+    pub fn without_duplicates(&self, df: &DataFrame, mode: ConcatMode, ignore: &[&str]) -> PolarsResult<DataFrame> {
+        let combined = self.collated(df, mode)?;
+        let mut compared = combined.drop_many(ignore.iter().copied());
+        // Every column is ignored, so there's nothing to compare
+        if compared.width() == 0 {
+            return Ok(df.clone());
+        }
+
+        let hashes = compared.hash_rows(None)?;
+        let existing = combined.height() - df.height();
+        let mut seen: HashSet<u64> = hashes.slice(0, existing).into_no_null_iter().collect();
+        let new: BooleanChunked = hashes
+            .slice(existing as i64, df.height())
+            .into_no_null_iter()
+            .map(|hash| seen.insert(hash))
+            .collect();
+
+        df.filter(&new)
+    }
+
+    // Check a payload can be upserted (or joined, for `/collate_wide`) into the dataset by `keys`: the dataset isn't
+    // aggregated, and both have every key column
+    pub fn check_upsert(&self, df: &DataFrame, keys: &[String]) -> Result<(), String> {
+        if self.aggregate_state.is_some() {
+            return Err(String::from("This dataset is aggregated, so its rows can't be updated by key (use /aggregate)"));
+        }
+        for key in keys {
+            if df.column(key).is_err() {
+                return Err(format!("Key column {:?} is missing from the payload", key));
+            }
+            if let Some(state_df) = self.df.as_ref().filter(|state_df| state_df.column(key).is_err()) {
+                return Err(format!(
+                    "Key column {:?} is not in the dataset (its columns are {:?})",
+                    key,
+                    state_df.get_column_names()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    // The dataset's frame with every row whose `keys` match a payload row replaced by the payload, plus how many rows
+    // were replaced. The remaining rows keep their order and the payload's rows go at the end. Within the payload the
+    // last row for a key wins, and null keys never match. Nothing is changed until the caller stores it.
+    pub fn upserted(&self, df: &DataFrame, keys: &[String], mode: ConcatMode) -> PolarsResult<(DataFrame, usize)> {
+        let payload = df.unique_stable(Some(keys), UniqueKeepStrategy::Last, None)?;
+        let Some(state_df) = self.df.as_ref() else {
+            return Ok((payload, 0));
+        };
+
+        let key_exprs: Vec<Expr> = keys.iter().map(|key| col(key.as_str())).collect();
+        let kept = state_df
+            .clone()
+            .lazy()
+            .join(
+                payload.clone().lazy().select(key_exprs.clone()),
+                key_exprs.clone(),
+                key_exprs,
+                JoinArgs::new(JoinType::Anti),
+            )
+            .collect()?;
+        let replaced = state_df.height() - kept.height();
+
+        let kept = Dataset {
+            df: Some(kept),
+            ..Default::default()
+        };
+
+        Ok((kept.collated(&payload, mode)?, replaced))
+    }
+
+    // The dataset's frame with the payload's columns joined onto the rows with the same `keys`, plus how many payload
+    // rows matched an existing row. Columns the dataset doesn't have yet are added (null for the other rows); for ones
+    // it has, the payload's value wins unless it's null. Payload rows with new keys go at the end, and every other row
+    // keeps its order. Within the payload the last row for a key wins, and null keys never match. Nothing is changed
+    // until the caller stores it.
+    pub fn widened(&self, df: &DataFrame, keys: &[String]) -> PolarsResult<(DataFrame, usize)> {
+        let payload = df.unique_stable(Some(keys), UniqueKeepStrategy::Last, None)?;
+        let Some(state_df) = self.df.as_ref() else {
+            return Ok((payload, 0));
+        };
+
+        // Join on the dataset's key types, so e.g. integer keys still match float ones
+        let state_schema = state_df.schema();
+        let casts: Vec<Expr> = keys
+            .iter()
+            .filter_map(|key| Some(col(key.as_str()).cast(state_schema.get(key)?.clone())))
+            .collect();
+        let key_exprs: Vec<Expr> = keys.iter().map(|key| col(key.as_str())).collect();
+
+        let args = JoinArgs::new(JoinType::Full)
+            .with_coalesce(JoinCoalesce::CoalesceColumns)
+            .with_suffix(Some(WIDE_SUFFIX.into()));
+
+        // The dataset's columns in order (preferring the payload's values), then the payload's new ones
+        let mut columns: Vec<Expr> = state_df
+            .get_column_names_str()
+            .into_iter()
+            .map(|name| match payload.column(name) {
+                Ok(_) if !keys.iter().any(|key| key == name) => {
+                    coalesce(&[col(format!("{}{}", name, WIDE_SUFFIX)), col(name)]).alias(name)
+                }
+                _ => col(name),
+            })
+            .collect();
+        columns.extend(
+            payload
+                .get_column_names_str()
+                .into_iter()
+                .filter(|name| !state_schema.contains(name))
+                .map(col),
+        );
+
+        // Joins don't keep row order, so number the rows (the payload's after the dataset's) and sort them back
+        let state_row = format!("{}_row", WIDE_SUFFIX);
+        let payload_row = format!("{}_payload_row", WIDE_SUFFIX);
+        let order = col(state_row.as_str())
+            .cast(DataType::UInt64)
+            .fill_null(col(payload_row.as_str()).cast(DataType::UInt64) + lit(state_df.height() as u64));
+
+        let widened = state_df
+            .clone()
+            .lazy()
+            .with_row_index(state_row.as_str(), None)
+            .join(
+                payload.clone().lazy().with_columns(casts).with_row_index(payload_row.as_str(), None),
+                key_exprs.clone(),
+                key_exprs,
+                args,
+            )
+            .sort_by_exprs([order], SortMultipleOptions::default())
+            .select(columns)
+            .collect()?;
+        let added = widened.height() - state_df.height();
+
+        Ok((widened, payload.height() - added))
+    }
+
+    // The quarantine with more rows added. Rows from different payloads may have different columns, so they're
+    // aligned by name.
+    pub fn quarantined(&self, rows: &DataFrame) -> PolarsResult<DataFrame> {
+        let Some(quarantine) = self.quarantine.as_ref() else {
+            return Ok(rows.clone());
+        };
+
+        let args = UnionArgs {
+            rechunk: false,
+            to_supertypes: true,
+            ..Default::default()
+        };
+        concat_lf_diagonal([quarantine.clone().lazy(), rows.clone().lazy()], args)?.collect()
+    }
+
+    // The aggregate state with a payload added, and the aggregate recomputed from it. In full mode every aggregate is
+    // recomputed over the full history, since operations like median can't be computed from previous results. In
+    // incremental (and approximate) mode only the payload is grouped and merged into the running totals. A dataset
+    // that was loaded at startup (from `--input` or recovered from its output file) starts out with those rows.
+    pub fn aggregated(
+        &self,
+        df: &DataFrame,
+        spec: &AggregateSpec,
+        mode: AggregateMode,
+    ) -> PolarsResult<(AggregateState, DataFrame)> {
+        match (mode, &self.aggregate_state) {
+            (AggregateMode::Full, Some(AggregateState::Running(_))) => {
+                polars_bail!(
+                    ComputeError: "this dataset was aggregated incrementally (or approximately), so its raw rows \
+                    weren't kept"
+                )
+            }
+            (AggregateMode::Full, history) => {
+                let history = match history {
+                    Some(AggregateState::History(history)) => Some(history),
+                    _ => self.df.as_ref(),
+                };
+                let history = match history {
+                    Some(history) => with_payload(history, df)?,
+                    None => df.clone(),
+                };
+                let updated_df = group_by_spec(&history, spec)?;
+
+                Ok((AggregateState::History(history), updated_df))
+            }
+            (AggregateMode::Incremental | AggregateMode::Approximate, state) => {
+                let running = match state {
+                    Some(AggregateState::Running(running)) => running.updated(df, spec)?,
+                    // Switching from full mode (or starting from loaded rows) folds the existing rows in once
+                    Some(AggregateState::History(history)) => {
+                        RunningAggregate::new(&with_payload(history, df)?, spec, mode)?
+                    }
+                    None => match self.df.as_ref() {
+                        Some(history) => RunningAggregate::new(&with_payload(history, df)?, spec, mode)?,
+                        None => RunningAggregate::new(df, spec, mode)?,
+                    },
+                };
+                let updated_df = running.finish()?;
+
+                Ok((AggregateState::Running(running), updated_df))
+            }
+        }
+    }
+
+    // Forget every row, along with any aggregate state and quarantined rows. The schema and computed columns (if
+    // declared) stay.
+    pub fn reset(&mut self) {
+        self.df = None;
+        self.aggregate_state = None;
+        self.quarantine = None;
+        self.spilled.clear();
+    }
+
+    // Take on a snapshot's rows, aggregate state, schema, and computed columns. The output file stays the same.
+    pub fn restore(&mut self, snapshot: Dataset) {
+        self.df = snapshot.df;
+        self.aggregate_state = snapshot.aggregate_state;
+        self.schema = snapshot.schema;
+        self.computed = snapshot.computed;
+        self.spilled.clear();
+    }
+
+    // Every row the dataset has, spilled ones included, for reading once the lock is released. Cheap: spilled rows
+    // are only read when it's collected.
+    pub fn rows(&self) -> Rows {
+        self.spilled.rows_with(self.df.clone().unwrap_or_default())
+    }
+
+    // Read the spilled rows back into memory, for changes that need every row. Does nothing when none were spilled.
+    pub fn unspill(&mut self) -> Result<(), String> {
+        if self.spilled.is_empty() {
+            return Ok(());
+        }
+        self.df = Some(self.rows().collect()?);
+        self.spilled.clear();
+        Ok(())
+    }
+
+    // What the dataset is before an ingest, for `record_batch` once it's gone in. Cheap to clone.
+    pub fn before_batch(&self) -> (Option<DataFrame>, Option<AggregateState>) {
+        (self.df.clone(), self.aggregate_state.clone())
+    }
+
+    // Note an ingest the dataset took at `at`, keeping the latest `max` (0 keeps none)
+    pub fn record_batch(
+        &mut self,
+        operation: &'static str,
+        rows: usize,
+        (df, aggregate_state): (Option<DataFrame>, Option<AggregateState>),
+        at: Option<DateTime<Utc>>,
+        max: usize,
+    ) {
+        let ledger = &mut self.ledger;
+        if max == 0 {
+            ledger.clear_at(at);
+            return;
+        }
+        while ledger.batches.len() >= max {
+            // What the rows were before the oldest batch isn't known once it's dropped
+            if let Some(dropped) = ledger.batches.pop_front() {
+                ledger.since = ledger.since.max(dropped.at);
+            }
+        }
+        ledger.batches.push_back(Batch {
+            operation,
+            rows,
+            at,
+            df,
+            aggregate_state,
+        });
+        if ledger.since.is_none() {
+            ledger.since = at;
+        }
+    }
+
+    // Whether the latest `count` ingests can be rolled back. Only ingests since the rows were last changed some other
+    // way (a delete, a dedup, a reset, ...) are kept.
+    pub fn check_rollback(&self, count: usize) -> Result<(), String> {
+        if count == 0 {
+            return Err(String::from("`batches` must be at least 1"));
+        }
+        let kept = self.ledger.batches.len();
+        if count > kept {
+            return Err(format!(
+                "Only {} ingests can be rolled back (the latest `storage.rollback_batches` since the rows were last \
+                 changed some other way)",
+                kept
+            ));
+        }
+        Ok(())
+    }
+
+    // Put the rows (and aggregate state) back as they were before the latest `count` ingests, at `at`, returning
+    // those, latest first. `check_rollback` has to pass first.
+    pub fn roll_back(&mut self, count: usize, at: Option<DateTime<Utc>>) -> Vec<Batch> {
+        let batches: Vec<Batch> = (0..count).filter_map(|_| self.ledger.batches.pop_back()).collect();
+        if let Some(oldest) = batches.last() {
+            self.df = oldest.df.clone();
+            self.aggregate_state = oldest.aggregate_state.clone();
+        }
+        // The rows the undone batches added were there until now
+        self.ledger.since = at;
+        batches
+    }
+
+    // The rows the dataset had at `at`, if its ledger goes back that far
+    pub fn rows_as_of(&self, at: DateTime<Utc>) -> Option<DataFrame> {
+        // The ledger's frames don't have the spilled rows, so those reads go to the write-ahead log
+        if !self.spilled.is_empty() || self.ledger.since.is_none_or(|since| since > at) {
+            return None;
+        }
+        let rows = match self.ledger.batches.iter().find(|batch| batch.at.is_some_and(|applied| applied > at)) {
+            Some(batch) => batch.df.clone(),
+            None => self.df.clone(),
+        };
+        Some(rows.unwrap_or_default())
+    }
+
+    // The dataset's frame with duplicate rows removed, plus how many rows that was. Rows are duplicates when they have
+    // the same values in every `subset` column (or every column, without a subset). The rows kept stay in order.
+    // Nothing is changed until the caller stores it.
+    pub fn deduplicated(
+        &self,
+        subset: Option<&[String]>,
+        keep: KeepDuplicate,
+    ) -> Result<(Option<DataFrame>, usize), String> {
+        if self.aggregate_state.is_some() {
+            return Err(String::from("This dataset is aggregated, so it has one row per key already"));
+        }
+        let Some(df) = self.df.as_ref() else {
+            return Ok((None, 0));
+        };
+        if let Some(column) = subset.into_iter().flatten().find(|column| df.column(column).is_err()) {
+            return Err(format!("Column {:?} in the subset doesn't exist", column));
+        }
+
+        let deduplicated = df.unique_stable(subset, keep.into(), None).map_err(|e| e.to_string())?;
+        let removed = df.height() - deduplicated.height();
+
+        Ok((Some(deduplicated), removed))
+    }
+
+    // The dataset's frame with its nulls filled in, plus how many values were filled. Nothing is changed until the
+    // caller stores it.
+    pub fn nulls_filled(&self, spec: &FillSpec) -> Result<(Option<DataFrame>, usize), String> {
+        if self.aggregate_state.is_some() {
+            return Err(String::from("This dataset is aggregated, so its rows are recomputed by every `/aggregate`"));
+        }
+        let Some(df) = self.df.as_ref() else {
+            return Ok((None, 0));
+        };
+
+        let (filled, count) = nulls::fill_nulls(df, spec)?;
+        Ok((Some(filled), count))
+    }
+
+    // The dataset's frame without the rows that have a null in any `subset` column (or any column, without a subset),
+    // plus how many rows that was. Nothing is changed until the caller stores it.
+    pub fn without_nulls(&self, subset: Option<&[String]>) -> Result<(Option<DataFrame>, usize), String> {
+        if self.aggregate_state.is_some() {
+            return Err(String::from("This dataset is aggregated, so its rows are recomputed by every `/aggregate`"));
+        }
+        let Some(df) = self.df.as_ref() else {
+            return Ok((None, 0));
+        };
+
+        let (kept, removed) = nulls::drop_nulls(df, subset)?;
+        Ok((Some(kept), removed))
+    }
+
+    // The dataset's frame with every row flagged as an outlier or not (see `OutlierSpec::flagged`). Nothing is changed
+    // until the caller stores it.
+    pub fn outliers_flagged(&self, spec: &OutlierSpec) -> Result<Option<DataFrame>, String> {
+        if self.aggregate_state.is_some() {
+            return Err(String::from("This dataset is aggregated, so its rows are recomputed by every `/aggregate`"));
+        }
+
+        self.df.as_ref().map(|df| spec.flagged(df)).transpose()
+    }
+
+    // The dataset with every row matching `filter` removed, plus how many rows that was. For aggregated datasets the
+    // rows `/aggregate` recomputes from are filtered too, so deleted keys don't come back with the next payload.
+    // Nothing is changed until the caller stores the result.
+    pub fn without_rows(&self, filter: &Filter) -> Result<(Option<AggregateState>, Option<DataFrame>, usize), String> {
+        let Some(df) = self.df.as_ref() else {
+            return Ok((self.aggregate_state.clone(), None, 0));
+        };
+
+        let remove = |df: &DataFrame| -> Result<DataFrame, String> {
+            let expr = filter.to_expr(df.schema())?;
+            df.clone().lazy().filter(expr.not()).collect().map_err(|e| e.to_string())
+        };
+
+        let aggregate_state = match &self.aggregate_state {
+            Some(AggregateState::History(history)) => Some(AggregateState::History(remove(history)?)),
+            Some(AggregateState::Running(running)) => Some(AggregateState::Running(running.retain(filter)?)),
+            None => None,
+        };
+        let remaining = remove(df)?;
+        let removed = df.height() - remaining.height();
+
+        Ok((aggregate_state, Some(remaining), removed))
+    }
+
+    // The dataset with the rows a retention policy evicts removed, plus how many rows that was. For aggregated
+    // datasets, the raw rows `/aggregate` recomputes from are trimmed by the same rules, and the running totals of
+    // evicted groups are dropped. Nothing is changed until the caller stores the result.
+    pub fn evicted(&self, eviction: &Eviction) -> Result<(Option<AggregateState>, Option<DataFrame>, usize), String> {
+        let Some(df) = self.df.as_ref() else {
+            return Ok((self.aggregate_state.clone(), None, 0));
+        };
+
+        let kept = eviction.kept(df)?;
+        let remaining = df.filter(&kept).map_err(|e| e.to_string())?;
+        let removed = df.height() - remaining.height();
+        if removed == 0 {
+            return Ok((self.aggregate_state.clone(), Some(remaining), 0));
+        }
+
+        let aggregate_state = match &self.aggregate_state {
+            Some(AggregateState::History(history)) => {
+                let kept = eviction.kept(history)?;
+                Some(AggregateState::History(history.filter(&kept).map_err(|e| e.to_string())?))
+            }
+            Some(AggregateState::Running(running)) => {
+                let evicted = df.filter(&!&kept).map_err(|e| e.to_string())?;
+                Some(AggregateState::Running(running.without_groups(&evicted)?))
+            }
+            None => None,
+        };
+
+        Ok((aggregate_state, Some(remaining), removed))
+    }
+}
+
+// The raw rows aggregated so far with a payload's added, cast to their dtypes
+fn with_payload(history: &DataFrame, df: &DataFrame) -> PolarsResult<DataFrame> {
+    let schema = history.schema();
+    history.vstack(&cast_like(df, schema.iter().map(|(name, dtype)| (name.as_str(), dtype)))?)
+}
+
+// A dataset behind its own lock. Reads (`/data`, `/export`, ...) share it; `/collate` and `/aggregate` take it
+// exclusively, but only for that one dataset.
+pub type SharedDataset = Arc<RwLock<Dataset>>;
+
+// Registry of every dataset the service knows about. The registry lock is only held long enough to look a dataset up
+// (or add/remove one), so requests to different datasets never wait on each other.
+#[derive(Debug)]
+pub struct AppState {
+    datasets: RwLock<HashMap<String, SharedDataset>>,
+    pub config: Config,
+    // Background task all output file writes go through
+    pub writer: Writer,
+    // Log every payload is written to before it's applied, when enabled
+    pub wal: Option<Wal>,
+    // Counters and histograms reported by `/metrics`
+    pub metrics: Arc<Metrics>,
+    pub rate_limiter: RateLimiter,
+    // What each client has sent, for `GET /sources` and quotas
+    pub accounting: Accounting,
+    // Runs Polars work off the async runtime
+    pub compute: Compute,
+    // Responses to recent requests with an `Idempotency-Key`, replayed to retries
+    pub idempotency: IdempotencyCache,
+    // Batch numbers for provenance columns, when enabled
+    pub batches: BatchCounter,
+    // Reference tables reads can be joined against
+    pub lookups: Lookups,
+    // Mirrors accepted rows to Postgres, when configured
+    pub postgres: Option<PostgresSink>,
+    // Streams accepted rows to ClickHouse, when configured
+    pub clickhouse: Option<ClickHouseSink>,
+    // Pushes accepted rows (and aggregates) to a parent collator, when configured
+    pub forward: Option<Forwarder>,
+    // Streams every change to the replicas (when configured), or, on a replica, what it has of its primary's
+    pub replication: Replication,
+    // Copies datasets to an object store, when configured
+    pub s3: Option<S3Exporter>,
+    // State changes, for the clients following them (`/ws`, `/events`)
+    pub events: Events,
+    // Record of the requests that changed datasets, when enabled
+    pub audit: Option<AuditLog>,
+    // Reporters datasets expect rows from, and which have sent them
+    pub completeness: Completeness,
+}
+
+impl AppState {
+    // `initial` holds the datasets to start out with (from `--input` and crash recovery). Must be called from within
+    // the Tokio runtime, since it starts the background writer.
+    pub fn new(config: Config, mut initial: HashMap<String, Dataset>, wal: Option<Wal>, audit: Option<AuditLog>) -> Self {
+        let metrics = Metrics::new();
+        let events = Events::default();
+        let writer = Writer::spawn(
+            Duration::from_millis(config.storage.flush_interval_ms),
+            config.storage.flush_rows,
+            persist::backend(config.storage.output_format).expect("the output format is checked by Config::validate"),
+            compaction(&config),
+            metrics.clone(),
+            events.clone(),
+        );
+
+        if config.provenance.enabled {
+            for (name, dataset) in initial.iter_mut() {
+                provenance::restore_dtypes(&config.provenance, name, dataset);
+            }
+        }
+        let batches = BatchCounter::resume(&config.provenance, &initial);
+        let postgres = PostgresSink::spawn(&config.postgres, metrics.clone());
+        let clickhouse = ClickHouseSink::spawn(&config.clickhouse, metrics.clone());
+        let forward = Forwarder::spawn(&config.forward, metrics.clone());
+        let replication = Replication::spawn(&config, metrics.clone());
+        let s3 = S3Exporter::new(&config.s3, metrics.clone());
+        let compute = Compute::new(&config.compute);
+        let completeness = Completeness::new(&config.expected);
+
+        let state = AppState {
+            datasets: RwLock::new(HashMap::new()),
+            config,
+            writer,
+            wal,
+            metrics,
+            rate_limiter: RateLimiter::default(),
+            accounting: Accounting::default(),
+            compute,
+            idempotency: IdempotencyCache::default(),
+            batches,
+            lookups: Lookups::default(),
+            postgres,
+            clickhouse,
+            forward,
+            replication,
+            s3,
+            events,
+            audit,
+            completeness,
+        };
+
+        // Seed the default dataset so it shows up in the registry from the start, along with any recovered ones
+        let mut datasets = state.datasets.try_write().expect("app state is not shared yet");
+        initial.entry(DEFAULT_DATASET.to_string()).or_default();
+        for (name, mut dataset) in initial {
+            dataset.output_file = state.output_file_for(&name);
+            dataset.partitions = state.partitions_for(&name);
+            dataset.schema = state.config.schema.get(&name).cloned();
+            // Computed columns set (or removed) at runtime come back from the write-ahead log, and win over the config
+            if dataset.computed.is_none() {
+                dataset.computed = state.config.computed.get(&name).cloned();
+            }
+            datasets.insert(name, Arc::new(RwLock::new(dataset)));
+        }
+        drop(datasets);
+
+        state
+    }
+
+    // Where a dataset is persisted to (if anywhere)
+    pub fn output_file_for(&self, name: &str) -> Option<PathBuf> {
+        output_file_for(&self.config.storage, name)
+    }
+
+    // Where a dataset is written as partitions (if it is)
+    pub fn partitions_for(&self, name: &str) -> Option<PartitionedOutput> {
+        let dir = self.config.storage.partitions_dir.as_ref()?;
+        let spec = self.config.partition.get(name)?;
+
+        Some(PartitionedOutput {
+            dir: dir.join(name),
+            spec: spec.clone(),
+        })
+    }
+
+    // Get a dataset by name, creating an empty one if it doesn't exist yet
+    pub async fn dataset(&self, name: &str) -> SharedDataset {
+        if let Some(dataset) = self.existing_dataset(name).await {
+            return dataset;
+        }
+
+        // Another request may have created it between the two locks, in which case `entry` keeps theirs
+        self.datasets
+            .write()
+            .await
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                Arc::new(RwLock::new(Dataset {
+                    output_file: self.output_file_for(name),
+                    partitions: self.partitions_for(name),
+                    schema: self.config.schema.get(name).cloned(),
+                    computed: self.config.computed.get(name).cloned(),
+                    ..Default::default()
+                }))
+            })
+            .clone()
+    }
+
+    // Get a dataset by name, without creating it
+    pub async fn existing_dataset(&self, name: &str) -> Option<SharedDataset> {
+        self.datasets.read().await.get(name).cloned()
+    }
+
+    // Remove a dataset from the registry. Its output file (if any) is left on disk.
+    pub async fn remove_dataset(&self, name: &str) -> Option<SharedDataset> {
+        self.datasets.write().await.remove(name)
+    }
+
+    // Names of every dataset, sorted
+    pub async fn dataset_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.datasets.read().await.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    // Every dataset with its name, sorted by name. The registry lock is released before this returns.
+    pub async fn all_datasets(&self) -> Vec<(String, SharedDataset)> {
+        let mut datasets: Vec<(String, SharedDataset)> =
+            self.datasets.read().await.iter().map(|(name, dataset)| (name.clone(), dataset.clone())).collect();
+        datasets.sort_by(|a, b| a.0.cmp(&b.0));
+        datasets
+    }
+}
+
+// Where a dataset is persisted to: the default one goes to `output`, named ones to `<datasets_dir>/<name>.<ext>` (with
+// the output format's extension, e.g. `<name>.csv`)
+pub fn output_file_for(storage: &StorageConfig, name: &str) -> Option<PathBuf> {
+    if name == DEFAULT_DATASET {
+        return storage.output.clone();
+    }
+
+    let extension = storage.output_format.extension();
+    storage.datasets_dir.as_ref().map(|dir| dir.join(format!("{}.{}", name, extension)))
+}
+
+// When the writer compacts partitions, if there are any
+fn compaction(config: &Config) -> Option<Compaction> {
+    let dir = config.storage.partitions_dir.as_ref()?;
+    if config.partition.is_empty() || config.storage.compact_interval_ms == 0 {
+        return None;
+    }
+
+    Some(Compaction {
+        dir: dir.clone(),
+        interval: Duration::from_millis(config.storage.compact_interval_ms),
+    })
+}
+
+// Dataset names end up in file names, so keep them to a safe character set
+pub fn validate_dataset_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && name.len() <= 128
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "Invalid dataset name {:?} (use letters, digits, '_', '-' and '.', not starting with '.')",
+            name
+        ))
+    }
+}
@@ -0,0 +1,103 @@
+use polars::prelude::*;
+
+// Name of the column saying which statistic each row of a description holds
+pub const STATISTIC_COLUMN: &str = "statistic";
+
+// Statistics every description starts with, before the quantiles
+const STATISTICS: &[Statistic] = &[
+    // Values that aren't null
+    Statistic::Basic("count", Expr::count),
+    Statistic::Basic("null_count", Expr::null_count),
+    Statistic::Basic("mean", Expr::mean),
+    // Sample standard deviation, as for `/aggregate`
+    Statistic::Basic("std", |expr| expr.std(1)),
+    Statistic::Basic("min", Expr::min),
+    Statistic::Basic("max", Expr::max),
+];
+
+// Parse a comma-separated list of quantiles, e.g. `0.25,0.5,0.75`
+pub fn parse_quantiles(s: &str) -> Result<Vec<f64>, String> {
+    let quantiles = s
+        .split(',')
+        .map(str::trim)
+        .filter(|quantile| !quantile.is_empty())
+        .map(|quantile| quantile.parse::<f64>().map_err(|_| format!("Quantile {:?} is not a number", quantile)))
+        .collect::<Result<Vec<_>, _>>()?;
+    check_quantiles(&quantiles)?;
+
+    Ok(quantiles)
+}
+
+pub fn check_quantiles(quantiles: &[f64]) -> Result<(), String> {
+    match quantiles.iter().find(|quantile| !(0.0..=1.0).contains(*quantile)) {
+        Some(quantile) => Err(format!("Quantile {} is not between 0 and 1", quantile)),
+        None => Ok(()),
+    }
+}
+
+// One row of a description
+#[derive(Debug, Clone, Copy)]
+enum Statistic {
+    Basic(&'static str, fn(Expr) -> Expr),
+    Quantile(f64),
+}
+
+impl Statistic {
+    fn label(&self) -> String {
+        match self {
+            Statistic::Basic(name, _) => name.to_string(),
+            // `0.25` as `25%`, without float noise like `7.000000000000001%`
+            Statistic::Quantile(quantile) => format!("{}%", (quantile * 100.0 * 1e6).round() / 1e6),
+        }
+    }
+
+    fn apply(&self, expr: Expr) -> Expr {
+        match self {
+            Statistic::Basic(_, statistic) => statistic(expr),
+            Statistic::Quantile(quantile) => expr.quantile(lit(*quantile), QuantileMethod::Linear),
+        }
+    }
+}
+
+// Summary statistics of every numeric column: one row per statistic (named in the `statistic` column), then one
+// `f64` column per numeric column of `rows`. Quantiles are labelled as percentages (`25%`) and interpolated linearly.
+// Only the numeric columns are read.
+pub fn describe(mut rows: LazyFrame, quantiles: &[f64]) -> PolarsResult<DataFrame> {
+    let schema = rows.collect_schema()?;
+    let columns: Vec<&str> = schema
+        .iter()
+        .filter(|(_, dtype)| dtype.is_primitive_numeric())
+        .map(|(name, _)| name.as_str())
+        .collect();
+
+    let statistics: Vec<Statistic> = STATISTICS
+        .iter()
+        .copied()
+        .chain(quantiles.iter().copied().map(Statistic::Quantile))
+        .collect();
+
+    let labels: Vec<String> = statistics.iter().map(Statistic::label).collect();
+    let mut description = DataFrame::new(vec![Column::new(STATISTIC_COLUMN.into(), labels)])?;
+    if columns.is_empty() {
+        return Ok(description);
+    }
+
+    // One single-row frame per statistic, stacked in order
+    let rows: Vec<LazyFrame> = statistics
+        .iter()
+        .map(|statistic| {
+            let exprs: Vec<Expr> = columns
+                .iter()
+                .map(|column| {
+                    let values = col(*column).cast(DataType::Float64);
+                    statistic.apply(values).cast(DataType::Float64).alias(*column)
+                })
+                .collect();
+            rows.clone().select(exprs)
+        })
+        .collect();
+    let values = concat(rows, UnionArgs::default())?.collect()?;
+
+    description.hstack_mut(values.get_columns())?;
+    Ok(description)
+}
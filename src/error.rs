@@ -0,0 +1,158 @@
+use std::fmt;
+
+use axum::{
+    extract::rejection::{PathRejection, QueryRejection},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_macros::FromRequestParts;
+use log::{debug, error};
+
+use crate::types::{ErrorResponse, Status};
+
+// Why a request couldn't be completed, which decides its HTTP status
+#[derive(Debug)]
+pub enum AppError {
+    // The request is malformed: a payload that doesn't parse, an unknown parameter value, ... (400)
+    BadRequest(String),
+    // The request has no API key, or one that isn't known (401)
+    Unauthorized(String),
+    // The API key is valid, but doesn't allow this request (403)
+    Forbidden(String),
+    // The request refers to a dataset that doesn't exist (404)
+    NotFound(String),
+    // None of the response formats the client accepts are supported (406)
+    NotAcceptable(String),
+    // A barrier (`POST /await`) wasn't reached within its timeout (408)
+    RequestTimeout(String),
+    // The request body is bigger than `max_body_bytes` (413)
+    PayloadTooLarge(String),
+    // The request body is compressed with an encoding that isn't supported (415)
+    UnsupportedMediaType(String),
+    // The client has sent more requests than its rate limit allows (429)
+    TooManyRequests(String),
+    // The payload parsed, but its columns or dtypes don't fit the dataset (422)
+    SchemaMismatch(String),
+    // Something failed on the service's side, e.g. writing to disk (500)
+    Internal(String),
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::NotAcceptable(_) => StatusCode::NOT_ACCEPTABLE,
+            AppError::RequestTimeout(_) => StatusCode::REQUEST_TIMEOUT,
+            AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::SchemaMismatch(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // Machine-readable name of the error kind, returned as `error` in the body
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::NotFound(_) => "not_found",
+            AppError::NotAcceptable(_) => "not_acceptable",
+            AppError::RequestTimeout(_) => "request_timeout",
+            AppError::PayloadTooLarge(_) => "payload_too_large",
+            AppError::UnsupportedMediaType(_) => "unsupported_media_type",
+            AppError::SchemaMismatch(_) => "schema_mismatch",
+            AppError::TooManyRequests(_) => "too_many_requests",
+            AppError::Internal(_) => "internal",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            AppError::BadRequest(message)
+            | AppError::Unauthorized(message)
+            | AppError::Forbidden(message)
+            | AppError::NotFound(message)
+            | AppError::NotAcceptable(message)
+            | AppError::RequestTimeout(message)
+            | AppError::PayloadTooLarge(message)
+            | AppError::UnsupportedMediaType(message)
+            | AppError::SchemaMismatch(message)
+            | AppError::TooManyRequests(message)
+            | AppError::Internal(message) => message,
+        }
+    }
+
+    pub fn dataset_not_found(name: &str) -> Self {
+        AppError::NotFound(format!("Dataset {:?} does not exist", name))
+    }
+
+    pub fn lookup_not_found(name: &str) -> Self {
+        AppError::NotFound(format!("Lookup table {:?} does not exist", name))
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+// So services embedding a `Collator` can pass it on with `?`
+impl std::error::Error for AppError {}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        if status.is_server_error() {
+            error!("{}", self);
+        } else {
+            debug!("Rejected request ({}): {}", status, self);
+        }
+
+        let body = Json(ErrorResponse {
+            status: Status::Error,
+            error: self.kind().to_string(),
+            message: self.message().to_string(),
+        });
+
+        // Tell clients how to authenticate, as RFC 6750 asks
+        if matches!(self, AppError::Unauthorized(_)) {
+            return (status, [(header::WWW_AUTHENTICATE, "Bearer")], body).into_response();
+        }
+        // A rough wait for a busy service; the rate limiter replaces it with the time its bucket takes to refill, and
+        // quotas with the time until the next period
+        if matches!(self, AppError::TooManyRequests(_)) {
+            return (status, [(header::RETRY_AFTER, "1")], body).into_response();
+        }
+
+        (status, body).into_response()
+    }
+}
+
+impl From<QueryRejection> for AppError {
+    fn from(rejection: QueryRejection) -> Self {
+        AppError::BadRequest(rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for AppError {
+    fn from(rejection: PathRejection) -> Self {
+        AppError::BadRequest(rejection.body_text())
+    }
+}
+
+// Same as axum's `Query`, but rejections use the JSON error body
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(AppError))]
+pub struct Query<T>(pub T);
+
+// Same as axum's `Path`, but rejections use the JSON error body
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Path), rejection(AppError))]
+pub struct Path<T>(pub T);
@@ -0,0 +1,90 @@
+// Changes to the service's state, published as they happen for the clients following them live: WebSocket
+// connections (`/ws`) and Server-Sent Events streams (`/events`)
+
+use std::sync::Arc;
+
+use polars::prelude::DataFrame;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+// How many events a follower can fall behind before the oldest are skipped
+const CAPACITY: usize = 1024;
+
+#[derive(Debug)]
+pub enum Event {
+    Changed(Change),
+    Flushed(Flush),
+}
+
+// A change to a dataset's rows
+#[derive(Debug)]
+pub struct Change {
+    pub dataset: String,
+    // What made the change, e.g. `collate`, `aggregate`, or `reset`
+    pub operation: &'static str,
+    // The rows that were added or replaced, or for an aggregate, the groups whose results changed. Empty for
+    // operations that remove or rewrite rows in place (`/reset`, `/dedup`, `/fill_nulls`, ...).
+    pub rows: DataFrame,
+    // How many rows the dataset has now
+    pub total_rows: usize,
+}
+
+// A flush of the background writer, as `/flush` reports them
+#[derive(Debug, Serialize)]
+pub struct Flush {
+    // The output files (or partition directories) written to
+    pub files: Vec<String>,
+    pub rows: usize,
+    pub duration_ms: u128,
+    // Why the flush failed, if it did
+    pub error: Option<String>,
+}
+
+// Where events are published, to be handed to every follower
+#[derive(Debug, Clone)]
+pub struct Events {
+    tx: broadcast::Sender<Arc<Event>>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Events {
+            tx: broadcast::channel(CAPACITY).0,
+        }
+    }
+}
+
+impl Events {
+    // Whether anything is following events, so working them out is worth it
+    pub fn followed(&self) -> bool {
+        self.tx.receiver_count() > 0
+    }
+
+    pub fn changed(&self, dataset: &str, operation: &'static str, rows: &DataFrame, total_rows: usize) {
+        if !self.followed() {
+            return;
+        }
+
+        self.publish(Event::Changed(Change {
+            dataset: dataset.to_string(),
+            operation,
+            rows: rows.clone(),
+            total_rows,
+        }));
+    }
+
+    pub fn flushed(&self, flush: Flush) {
+        if self.followed() {
+            self.publish(Event::Flushed(flush));
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Event>> {
+        self.tx.subscribe()
+    }
+
+    fn publish(&self, event: Event) {
+        // Only fails when no one's listening
+        let _ = self.tx.send(Arc::new(event));
+    }
+}
@@ -0,0 +1,303 @@
+use std::{fmt, str::FromStr};
+
+use polars::prelude::*;
+
+// Comparison operators a filter condition can use, longest first so `<=` isn't read as `<`
+const OPERATORS: &[(&str, Op)] = &[
+    ("==", Op::Eq),
+    ("!=", Op::Ne),
+    ("<=", Op::Le),
+    (">=", Op::Ge),
+    ("=", Op::Eq),
+    ("<", Op::Lt),
+    (">", Op::Gt),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Condition {
+    column: String,
+    op: Op,
+    // `None` for a bare `null`
+    value: Option<String>,
+}
+
+// Conditions that must all hold for a row to match, e.g. `host=node3,latency_ms>250` (or `host=node3&latency_ms>250`).
+// Values can be quoted with `'` to include commas (`name='a,b'`), and `null` matches missing values (`host=null`,
+// `host!=null`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    source: String,
+    conditions: Vec<Condition>,
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let conditions = split_conditions(s)?
+            .into_iter()
+            .map(parse_condition)
+            .collect::<Result<Vec<_>, _>>()?;
+        if conditions.is_empty() {
+            return Err(String::from("The filter is empty"));
+        }
+
+        Ok(Filter {
+            source: s.to_string(),
+            conditions,
+        })
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Filter {
+    // Columns the filter refers to
+    pub fn columns(&self) -> Vec<&str> {
+        self.conditions.iter().map(|condition| condition.column.as_str()).collect()
+    }
+
+    // The filter as a Polars expression, with each value parsed as its column's dtype. Rows where a compared value is
+    // null don't match (except for explicit `null` comparisons).
+    pub fn to_expr(&self, schema: &Schema) -> Result<Expr, String> {
+        let mut expr: Option<Expr> = None;
+
+        for condition in &self.conditions {
+            let dtype = schema
+                .get(condition.column.as_str())
+                .ok_or_else(|| format!("Column {:?} in the filter doesn't exist", condition.column))?;
+            let column = col(condition.column.as_str());
+
+            let matched = match &condition.value {
+                None => match condition.op {
+                    Op::Eq => column.is_null(),
+                    Op::Ne => column.is_not_null(),
+                    _ => return Err(format!("Column {:?} can only be compared to null with = or !=", condition.column)),
+                },
+                Some(value) => {
+                    let value = literal(value, dtype)
+                        .map_err(|e| format!("Filter value for column {:?}: {}", condition.column, e))?;
+                    match condition.op {
+                        Op::Eq => column.eq(value),
+                        Op::Ne => column.neq(value),
+                        Op::Lt => column.lt(value),
+                        Op::Le => column.lt_eq(value),
+                        Op::Gt => column.gt(value),
+                        Op::Ge => column.gt_eq(value),
+                    }
+                    .fill_null(lit(false))
+                }
+            };
+
+            expr = Some(match expr {
+                Some(expr) => expr.and(matched),
+                None => matched,
+            });
+        }
+
+        expr.ok_or_else(|| String::from("The filter is empty"))
+    }
+
+    // The rows of a frame that match the filter
+    pub fn apply(&self, df: &DataFrame) -> Result<DataFrame, String> {
+        let expr = self.to_expr(df.schema())?;
+        df.clone().lazy().filter(expr).collect().map_err(|e| e.to_string())
+    }
+}
+
+// A value parsed as the dtype of the column it's compared with
+fn literal(value: &str, dtype: &DataType) -> Result<Expr, String> {
+    if dtype == &DataType::Boolean {
+        return match value.to_ascii_lowercase().as_str() {
+            "true" => Ok(lit(true)),
+            "false" => Ok(lit(false)),
+            _ => Err(format!("{:?} is not true or false", value)),
+        };
+    }
+    // Check the value casts up front, so a bad one is reported as such rather than as a failed query
+    if dtype != &DataType::String {
+        let _ = Series::new(PlSmallStr::EMPTY, [value])
+            .strict_cast(dtype)
+            .map_err(|_| format!("{:?} is not a valid {}", value, dtype))?;
+    }
+
+    Ok(lit(value.to_string()).strict_cast(dtype.clone()))
+}
+
+// Split on commas (or `&`) that aren't inside quotes
+fn split_conditions(s: &str) -> Result<Vec<&str>, String> {
+    let mut conditions = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+
+    for (i, c) in s.char_indices() {
+        match c {
+            '\'' => quoted = !quoted,
+            ',' | '&' if !quoted => {
+                conditions.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if quoted {
+        return Err(String::from("The filter has an unterminated quote"));
+    }
+    conditions.push(&s[start..]);
+
+    Ok(conditions.into_iter().map(str::trim).filter(|condition| !condition.is_empty()).collect())
+}
+
+fn parse_condition(condition: &str) -> Result<Condition, String> {
+    let (index, operator, op) = OPERATORS
+        .iter()
+        .filter_map(|(operator, op)| condition.find(operator).map(|index| (index, *operator, *op)))
+        // The leftmost operator wins; at the same spot, the longer one (they're listed longest first)
+        .min_by_key(|(index, _, _)| *index)
+        .ok_or_else(|| format!("Filter condition {:?} has no operator (use =, !=, <, <=, >, or >=)", condition))?;
+
+    let column = condition[..index].trim();
+    if column.is_empty() {
+        return Err(format!("Filter condition {:?} has no column", condition));
+    }
+
+    let value = condition[index + operator.len()..].trim();
+    let value = match value.strip_prefix('\'').and_then(|value| value.strip_suffix('\'')) {
+        Some(quoted) => Some(quoted.to_string()),
+        None if value.eq_ignore_ascii_case("null") => None,
+        None => Some(value.to_string()),
+    };
+
+    Ok(Condition {
+        column: column.to_string(),
+        op,
+        value,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn condition(source: &str) -> (String, Op, Option<String>) {
+        let filter: Filter = source.parse().unwrap();
+        let condition = filter.conditions[0].clone();
+        (condition.column, condition.op, condition.value)
+    }
+
+    fn rows() -> DataFrame {
+        df!(
+            "host" => [Some("a"), Some("b,c"), None, Some("null")],
+            "latency" => [Some(5i64), Some(250), Some(40), None],
+            "up" => [true, false, true, true],
+        )
+        .unwrap()
+    }
+
+    // The hosts of the rows a filter matches
+    fn matching(source: &str) -> Vec<Option<String>> {
+        let df = source.parse::<Filter>().unwrap().apply(&rows()).unwrap();
+        df.column("host").unwrap().str().unwrap().into_iter().map(|host| host.map(String::from)).collect()
+    }
+
+    #[test]
+    fn operators() {
+        for (source, op, value) in [
+            ("a<=5", Op::Le, "5"),
+            ("a<5", Op::Lt, "5"),
+            ("a>=5", Op::Ge, "5"),
+            ("a>5", Op::Gt, "5"),
+            ("a==5", Op::Eq, "5"),
+            ("a=5", Op::Eq, "5"),
+            ("a!=5", Op::Ne, "5"),
+            (" a <= 5 ", Op::Le, "5"),
+            // The leftmost operator wins, so the rest is the value
+            ("a=<5", Op::Eq, "<5"),
+            ("a=x>y", Op::Eq, "x>y"),
+            ("a<=>5", Op::Le, ">5"),
+        ] {
+            assert_eq!(condition(source), (String::from("a"), op, Some(String::from(value))), "{}", source);
+        }
+    }
+
+    #[test]
+    fn quoting() {
+        let filter: Filter = "host='b,c'&name=' x & y ',latency>1".parse().unwrap();
+        assert_eq!(filter.columns(), ["host", "name", "latency"]);
+        assert_eq!(filter.conditions[0].value.as_deref(), Some("b,c"));
+        // Quotes keep the spaces inside them
+        assert_eq!(filter.conditions[1].value.as_deref(), Some(" x & y "));
+        // There's no escaping a quote: a doubled one is taken as it is
+        assert_eq!(condition("name='it''s'").2.as_deref(), Some("it''s"));
+        assert_eq!(filter.to_string(), "host='b,c'&name=' x & y ',latency>1");
+
+        assert_eq!(matching("host='b,c'"), [Some(String::from("b,c"))]);
+        // Unquoted, the comma starts another condition
+        assert!("host=b,c".parse::<Filter>().unwrap_err().contains("\"c\" has no operator"));
+    }
+
+    #[test]
+    fn nulls() {
+        assert_eq!(condition("host=null").2, None);
+        assert_eq!(condition("host = NULL").2, None);
+        // A quoted null is the string
+        assert_eq!(condition("host='null'").2.as_deref(), Some("null"));
+
+        assert_eq!(matching("host=null"), [None]);
+        assert_eq!(matching("host!=null").len(), 3);
+        assert_eq!(matching("host='null'"), [Some(String::from("null"))]);
+        // Other comparisons never match a null
+        assert_eq!(matching("latency<100"), [Some(String::from("a")), None]);
+        assert_eq!(matching("latency!=5"), [Some(String::from("b,c")), None]);
+
+        let error = "host<null".parse::<Filter>().unwrap().apply(&rows()).unwrap_err();
+        assert_eq!(error, "Column \"host\" can only be compared to null with = or !=");
+    }
+
+    #[test]
+    fn values_take_the_columns_dtypes() {
+        assert_eq!(matching("latency>=40,up=TRUE"), [None]);
+        assert_eq!(matching("latency>=40&up=false"), [Some(String::from("b,c"))]);
+        // Strings compare as strings
+        assert_eq!(matching("host>a"), [Some(String::from("b,c")), Some(String::from("null"))]);
+
+        for (source, error) in [
+            ("latency>fast", "Filter value for column \"latency\": \"fast\" is not a valid i64"),
+            ("up=yes", "Filter value for column \"up\": \"yes\" is not true or false"),
+            ("missing=1", "Column \"missing\" in the filter doesn't exist"),
+        ] {
+            assert_eq!(source.parse::<Filter>().unwrap().apply(&rows()).unwrap_err(), error, "{}", source);
+        }
+    }
+
+    #[test]
+    fn malformed_filters() {
+        for (source, error) in [
+            ("", "The filter is empty"),
+            (" , & ", "The filter is empty"),
+            ("host", "Filter condition \"host\" has no operator (use =, !=, <, <=, >, or >=)"),
+            ("=5", "Filter condition \"=5\" has no column"),
+            ("host=a,latency", "Filter condition \"latency\" has no operator"),
+            ("host='a,b", "The filter has an unterminated quote"),
+        ] {
+            let e = source.parse::<Filter>().unwrap_err();
+            assert!(e.starts_with(error), "{:?}: {}", source, e);
+        }
+        // Empty conditions are skipped
+        assert_eq!("host=a,,latency>1,".parse::<Filter>().unwrap().columns(), ["host", "latency"]);
+    }
+}
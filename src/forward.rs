@@ -0,0 +1,414 @@
+// Forwarding (`[forward]`, or `--forward <URL>`): a collator that pushes what it collates to a parent collator, so
+// per-rack collators can roll up into a cluster-level one (and that one into another). Every `interval_ms`, the rows
+// the datasets accepted since the last push are sent to the parent's `/datasets/{name}/collate` (or its `/upsert` and
+// `/collate_wide`, for the payloads that were merged by key, keyed by this collator's name as well), and an aggregated
+// dataset's groups (its pre-aggregated state, which replaces the last one sent) to its `/upsert`, keyed by the groups
+// and this collator's name. Each batch carries an `Idempotency-Key`, the same on every retry, so a parent that applied
+// a batch whose response was lost replays it instead of collating it twice; that needs the parent's `[idempotency]`
+// on, with a `ttl_secs` longer than the retries take.
+
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use chrono::Utc;
+use log::{error, warn};
+use polars::prelude::*;
+use tokio::{sync::mpsc, time::MissedTickBehavior};
+
+use crate::{
+    config::ForwardConfig,
+    http::{self, percent_encode, Endpoint},
+    metrics::Metrics,
+    payload::{write_df, FileFormat, ARROW_STREAM_CONTENT_TYPE},
+    sink::{backoff, QUEUE_CAPACITY},
+    wal::Operation,
+};
+
+// What's sent to the parent: a dataset's new rows, or an aggregated dataset's every group
+#[derive(Debug)]
+enum Forward {
+    Rows { dataset: String, df: DataFrame, merge: Merge },
+    Groups { dataset: String, df: DataFrame, keys: Vec<String> },
+}
+
+// How rows go into the parent's dataset
+#[derive(Debug, Clone, PartialEq)]
+enum Merge {
+    // After its rows (`/collate`)
+    Append,
+    // In place of its rows with the same keys (`/upsert`), which is also how groups are sent
+    Upsert(Vec<String>),
+    // Joined onto its rows with the same keys (`/collate_wide`)
+    Wide(Vec<String>),
+}
+
+// Handle to the background task that forwards the datasets' rows to the parent collator
+#[derive(Debug, Clone)]
+pub struct Forwarder {
+    tx: mpsc::Sender<Forward>,
+    // Where the parent is, so `/readyz` can check it's reachable
+    server: (String, u16),
+    // Datasets that are forwarded (every one, if empty)
+    datasets: Vec<String>,
+    metrics: Arc<Metrics>,
+}
+
+impl Forwarder {
+    // Start the forwarding task, if a parent is configured. Must be called from within the Tokio runtime.
+    pub fn spawn(config: &ForwardConfig, metrics: Arc<Metrics>) -> Option<Self> {
+        let endpoint = Endpoint::parse(config.url.as_deref()?).expect("the URL is checked by Config::validate");
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        let server = (endpoint.host.clone(), endpoint.port);
+
+        tokio::spawn(run(rx, endpoint, config.clone(), metrics.clone()));
+
+        Some(Forwarder {
+            tx,
+            server,
+            datasets: config.datasets.clone(),
+            metrics,
+        })
+    }
+
+    // The parent's host and port
+    pub fn server(&self) -> (&str, u16) {
+        (&self.server.0, self.server.1)
+    }
+
+    // Queue rows a dataset accepted to be merged into the parent's the same way: appended, upserted, or joined on
+    pub fn submit(&self, dataset: &str, operation: &Operation, df: &DataFrame) {
+        let merge = match operation {
+            Operation::Collate { .. } => Merge::Append,
+            Operation::Upsert { keys, .. } => Merge::Upsert(keys.clone()),
+            Operation::CollateWide { keys } => Merge::Wide(keys.clone()),
+            _ => return,
+        };
+        if df.height() > 0 && self.forwards(dataset) {
+            self.queue(Forward::Rows {
+                dataset: dataset.to_string(),
+                df: df.clone(),
+                merge,
+            });
+        }
+    }
+
+    // Check a change can be made to a dataset. The parent's copy of a forwarded dataset can't follow a change to rows
+    // it was already sent (a delete, a dedup, a rollback, ...), so those are turned away rather than leave the two
+    // out of step. Resets and retention evictions still go through: a child keeping fewer rows than its parent is
+    // what they're for.
+    pub fn check(&self, dataset: &str, operation: &Operation) -> Result<(), String> {
+        let passed_on = matches!(
+            operation,
+            Operation::Collate { .. }
+                | Operation::Aggregate { .. }
+                | Operation::Upsert { .. }
+                | Operation::CollateWide { .. }
+                | Operation::Reset
+                | Operation::Evict { .. }
+        );
+        if passed_on || !operation.changes_rows() || !self.forwards(dataset) {
+            return Ok(());
+        }
+        Err(format!(
+            "Dataset {:?} is forwarded to a parent collator, which a {} can't be passed on to; make the change on the \
+             parent, or stop forwarding the dataset",
+            dataset,
+            operation.name()
+        ))
+    }
+
+    // Queue an aggregated dataset's groups (all of them) to replace the ones last sent. `keys` are the columns that
+    // tell the groups apart.
+    pub fn submit_groups(&self, dataset: &str, df: &DataFrame, keys: Vec<String>) {
+        if self.forwards(dataset) {
+            self.queue(Forward::Groups {
+                dataset: dataset.to_string(),
+                df: df.clone(),
+                keys,
+            });
+        }
+    }
+
+    fn forwards(&self, dataset: &str) -> bool {
+        self.datasets.is_empty() || self.datasets.iter().any(|name| name == dataset)
+    }
+
+    // If the queue is full (the parent has been unreachable for a while), what's queued is dropped rather than holding
+    // up the request
+    fn queue(&self, forward: Forward) {
+        if let Err(e) = self.tx.try_send(forward) {
+            let (mpsc::error::TrySendError::Full(forward) | mpsc::error::TrySendError::Closed(forward)) = e;
+            let (dataset, rows) = match &forward {
+                Forward::Rows { dataset, df, .. } | Forward::Groups { dataset, df, .. } => (dataset, df.height()),
+            };
+            error!("Dropping {} rows of {:?}: forwarding is {} batches behind", rows, dataset, QUEUE_CAPACITY);
+            self.metrics.record_sink("forward", 0, rows);
+        }
+    }
+}
+
+#[derive(Debug)]
+enum ForwardError {
+    // Worth trying again: the connection failed, or the parent had a problem of its own (a `5xx`, or too many requests)
+    Transient(String),
+    // The parent won't take the batch (it doesn't fit the dataset, or the API key isn't accepted), and would turn it
+    // away the same way every time
+    Rejected(String),
+}
+
+async fn run(mut rx: mpsc::Receiver<Forward>, endpoint: Endpoint, config: ForwardConfig, metrics: Arc<Metrics>) {
+    let mut ticks = tokio::time::interval(Duration::from_millis(config.interval_ms));
+    // A push that took longer than the interval is followed by the next one after a full interval, not right away
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // Rows waiting to be sent by dataset (in order), and the latest groups of each aggregated dataset
+    let mut rows: BTreeMap<String, Vec<Batch>> = BTreeMap::new();
+    let mut groups: BTreeMap<String, (DataFrame, Vec<String>)> = BTreeMap::new();
+    let node = config.node();
+    // Makes each batch's ID unique to this run
+    let run_id = Utc::now().format("%Y%m%dT%H%M%S%.3fZ").to_string();
+    let mut batches = 0u64;
+
+    loop {
+        // Buffer what's submitted until the next tick. Once the service is shutting down, what's buffered is sent one
+        // last time.
+        let closed = tokio::select! {
+            received = rx.recv() => match received {
+                Some(Forward::Rows { dataset, df, merge }) => {
+                    // Appends in a row go in one request. Rows merged by key keep their place after them, so the
+                    // parent merges them into the same rows this collator did.
+                    let batches = rows.entry(dataset.clone()).or_default();
+                    match batches.last_mut() {
+                        Some(last) if merge == Merge::Append && last.merge == Merge::Append => last.frames.push(df),
+                        _ => batches.push(Batch::new(dataset, vec![df], merge)),
+                    }
+                    continue;
+                }
+                Some(Forward::Groups { dataset, df, keys }) => {
+                    groups.insert(dataset, (df, keys));
+                    continue;
+                }
+                None => true,
+            },
+            _ = ticks.tick() => false,
+        };
+
+        let buffered = std::mem::take(&mut rows).into_values().flatten().chain(
+            std::mem::take(&mut groups)
+                .into_iter()
+                .map(|(dataset, (df, keys))| Batch::new(dataset, vec![df], Merge::Upsert(keys))),
+        );
+        for batch in buffered.collect::<Vec<_>>() {
+            batches += 1;
+            let id = format!("{}-{}-{}", node, run_id, batches);
+            send_with_retries(&endpoint, &config, &metrics, &node, batch, &id).await;
+        }
+        if closed {
+            return;
+        }
+    }
+}
+
+// One request's worth of a dataset's rows
+#[derive(Debug)]
+struct Batch {
+    dataset: String,
+    frames: Vec<DataFrame>,
+    merge: Merge,
+}
+
+impl Batch {
+    fn new(dataset: String, frames: Vec<DataFrame>, merge: Merge) -> Self {
+        Batch { dataset, frames, merge }
+    }
+
+    fn rows_count(&self) -> usize {
+        self.frames.iter().map(DataFrame::height).sum()
+    }
+
+    // The route on the parent, and the rows as an Arrow stream (so they keep their dtypes), with the node's column
+    // added. Rows that already have the column (forwarded from further down the tree) keep theirs.
+    fn request(self, config: &ForwardConfig, node: &str) -> Result<(String, Vec<u8>), String> {
+        let args = UnionArgs {
+            rechunk: true,
+            to_supertypes: true,
+            ..Default::default()
+        };
+        let mut df = concat_lf_diagonal(self.frames.into_iter().map(DataFrame::lazy).collect::<Vec<_>>(), args)
+            .and_then(|lf| lf.collect())
+            .map_err(|e| e.to_string())?;
+
+        let column = config.node_column.as_str();
+        if !column.is_empty() && df.column(column).is_err() {
+            let nodes = Column::new_scalar(column.into(), Scalar::from(PlSmallStr::from(node)), df.height());
+            df.with_column(nodes).map_err(|e| e.to_string())?;
+        }
+
+        let dataset = percent_encode(&self.dataset);
+        let target = match self.merge {
+            Merge::Append => format!("/datasets/{}/collate?concat=union", dataset),
+            Merge::Upsert(keys) => format!("/datasets/{}/upsert?concat=union&keys={}", dataset, keyed(keys, column)?),
+            Merge::Wide(keys) => format!("/datasets/{}/collate_wide?keys={}", dataset, keyed(keys, column)?),
+        };
+
+        Ok((target, write_df(&mut df, FileFormat::Arrow)?))
+    }
+}
+
+// The `keys` parameter for rows merged by key. They only replace (or join onto) the rows this collator sent, so its
+// name is a key too.
+fn keyed(mut keys: Vec<String>, column: &str) -> Result<String, String> {
+    if !column.is_empty() && !keys.iter().any(|key| key == column) {
+        keys.push(column.to_string());
+    }
+    if keys.is_empty() {
+        return Err(String::from(
+            "an aggregate without keys can only be forwarded with a forward.node_column to tell it apart",
+        ));
+    }
+    Ok(percent_encode(&keys.join(",")))
+}
+
+// Send a batch to the parent, retrying (up to `max_retries` times) while the failure could pass. Every attempt carries
+// the same `Idempotency-Key`, so the parent applies it once even if an attempt that looked failed went through.
+async fn send_with_retries(
+    endpoint: &Endpoint,
+    config: &ForwardConfig,
+    metrics: &Metrics,
+    node: &str,
+    batch: Batch,
+    id: &str,
+) {
+    let (dataset, rows) = (batch.dataset.clone(), batch.rows_count());
+    let (target, body) = match batch.request(config, node) {
+        Ok(request) => request,
+        Err(e) => {
+            error!("Dropping {} rows of {:?} that can't be forwarded: {}", rows, dataset, e);
+            metrics.record_sink("forward", 0, rows);
+            return;
+        }
+    };
+
+    let mut attempt = 0;
+    loop {
+        match send(endpoint, config, &target, &body, id).await {
+            Ok(()) => {
+                metrics.record_sink("forward", rows, 0);
+                return;
+            }
+            Err(ForwardError::Transient(e)) if attempt < config.max_retries => {
+                let delay = backoff(config.retry_backoff_ms, attempt);
+                attempt += 1;
+                warn!(
+                    "Can't forward {} rows of {:?} ({}), retrying in {:?} ({} of {})",
+                    rows, dataset, e, delay, attempt, config.max_retries
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(ForwardError::Transient(e)) => {
+                error!("Dropping {} rows of {:?}, still not forwarded after {} retries: {}", rows, dataset, attempt, e);
+                metrics.record_sink("forward", 0, rows);
+                return;
+            }
+            Err(ForwardError::Rejected(e)) => {
+                error!("Dropping {} rows of {:?} that the parent collator rejected: {}", rows, dataset, e);
+                metrics.record_sink("forward", 0, rows);
+                return;
+            }
+        }
+    }
+}
+
+async fn send(
+    endpoint: &Endpoint,
+    config: &ForwardConfig,
+    target: &str,
+    body: &[u8],
+    id: &str,
+) -> Result<(), ForwardError> {
+    let mut headers = vec![
+        ("Content-Type", String::from(ARROW_STREAM_CONTENT_TYPE)),
+        ("Idempotency-Key", id.to_string()),
+    ];
+    if let Some(api_key) = &config.api_key {
+        headers.push(("Authorization", format!("Bearer {}", api_key)));
+    }
+
+    let target = format!("{}{}", endpoint.path, target);
+    let response = http::send(endpoint, "POST", &target, &headers, body).await.map_err(ForwardError::Transient)?;
+    if response.is_success() {
+        return Ok(());
+    }
+
+    let message = format!("{} {}", response.status, String::from_utf8_lossy(&response.body).trim());
+    if response.status >= 500 || matches!(response.status, 408 | 429) {
+        Err(ForwardError::Transient(message))
+    } else {
+        Err(ForwardError::Rejected(message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dataset::{ConcatMode, KeepDuplicate},
+        retention::Eviction,
+    };
+
+    #[test]
+    fn batches_go_to_the_route_their_rows_were_merged_by() {
+        let config = ForwardConfig::default();
+        let df = df!("host" => ["a"], "latency" => [1.5]).unwrap();
+        let target = |merge| Batch::new(String::from("rack metrics"), vec![df.clone()], merge).request(&config, "r7");
+        let keys = || vec![String::from("host")];
+
+        assert_eq!(target(Merge::Append).unwrap().0, "/datasets/rack%20metrics/collate?concat=union");
+        assert_eq!(
+            target(Merge::Upsert(keys())).unwrap().0,
+            "/datasets/rack%20metrics/upsert?concat=union&keys=host%2Ccollator"
+        );
+        let wide = target(Merge::Wide(keys())).unwrap().0;
+        assert_eq!(wide, "/datasets/rack%20metrics/collate_wide?keys=host%2Ccollator");
+
+        let config = ForwardConfig {
+            node_column: String::new(),
+            ..Default::default()
+        };
+        let groups = Batch::new(String::from("rollup"), vec![df.clone()], Merge::Upsert(Vec::new()));
+        assert!(groups.request(&config, "r7").unwrap_err().contains("forward.node_column"));
+    }
+
+    #[test]
+    fn changes_the_parent_cant_follow_are_refused() {
+        let (tx, _rx) = mpsc::channel(1);
+        let forwarder = Forwarder {
+            tx,
+            server: (String::from("parent"), 8000),
+            datasets: vec![String::from("metrics")],
+            metrics: Metrics::new(),
+        };
+
+        let upsert = Operation::Upsert {
+            keys: vec![String::from("host")],
+            concat: ConcatMode::default(),
+        };
+        let dedup = Operation::Dedup {
+            subset: None,
+            keep: KeepDuplicate::First,
+        };
+        let evict = Operation::Evict {
+            spec: Eviction {
+                column: None,
+                before: None,
+                max_rows: Some(10),
+            },
+        };
+        assert!(forwarder.check("metrics", &upsert).is_ok());
+        assert!(forwarder.check("metrics", &Operation::Reset).is_ok());
+        assert!(forwarder.check("metrics", &evict).is_ok());
+        assert!(forwarder.check("metrics", &Operation::ClearQuarantine).is_ok());
+        let e = forwarder.check("metrics", &dedup).unwrap_err();
+        assert!(e.contains("a dedup can't be passed on"), "{}", e);
+        // Datasets that aren't forwarded can change however they like
+        assert!(forwarder.check("local", &dedup).is_ok());
+    }
+}
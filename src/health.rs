@@ -0,0 +1,143 @@
+// The checks behind the probe endpoints. `/livez` fails when the service can't make progress any more (a restart is
+// the fix), and `/readyz` when it can't do its job right now: a flush failed, an output directory doesn't take writes,
+// or a sink's (or a shard's) server can't be reached (traffic should go elsewhere until it recovers).
+
+use std::{
+    fs::{self, OpenOptions},
+    path::Path,
+    time::Duration,
+};
+
+use tokio::{net::TcpStream, time::timeout};
+
+use crate::{
+    dataset::{AppState, DEFAULT_DATASET},
+    http::Endpoint,
+    types::{ProbeCheck, ProbeResponse, Status},
+};
+
+// How long a check waits (for a lock, or a connection) before it fails
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Whether the service can still make progress: the dataset registry isn't stuck behind a lock, and the background
+// writer is running
+pub async fn liveness(state: &AppState) -> ProbeResponse {
+    probe(vec![registry(state).await, writer_running(state)])
+}
+
+// Whether the service can take traffic: everything `liveness` checks, plus the last flush having succeeded, every
+// directory output is written to taking writes, and every configured sink's server accepting connections
+pub async fn readiness(state: &AppState) -> ProbeResponse {
+    let mut checks = vec![registry(state).await, writer_running(state)];
+
+    if let Some(error) = state.writer.status().last_error {
+        checks.push(check("flush", Err(format!("The last flush failed: {}", error))));
+    }
+
+    let storage = &state.config.storage;
+    if let Some(output) = state.output_file_for(DEFAULT_DATASET) {
+        checks.push(check("output", output_writable(&output)));
+    }
+    for (name, dir) in [("datasets_dir", &storage.datasets_dir), ("partitions_dir", &storage.partitions_dir)] {
+        if let Some(dir) = dir {
+            checks.push(check(name, dir_writable(dir)));
+        }
+    }
+
+    if let Some(postgres) = &state.postgres {
+        checks.push(check("postgres", reachable(postgres.server()).await));
+    }
+    if let Some(clickhouse) = &state.clickhouse {
+        checks.push(check("clickhouse", reachable(clickhouse.server()).await));
+    }
+    if let Some(forward) = &state.forward {
+        checks.push(check("forward", reachable(forward.server()).await));
+    }
+    if let Some(s3) = &state.s3 {
+        checks.push(check("s3", reachable(s3.server()).await));
+    }
+    // A sharding front-end sends every request on to its shards
+    for (index, url) in state.config.sharding.shards.iter().enumerate() {
+        let endpoint = Endpoint::parse(url).expect("the URLs are checked by Config::validate");
+        checks.push(check(&format!("shard {}", index), reachable((&endpoint.host, endpoint.port)).await));
+    }
+    // A replica turns changes away, so writes should go to the primary until it's promoted
+    if state.replication.is_replica() {
+        checks.push(check("replication", Err(String::from("This collator is a replica (promote it to take writes)"))));
+    }
+
+    probe(checks)
+}
+
+fn probe(checks: Vec<ProbeCheck>) -> ProbeResponse {
+    let status = if checks.iter().all(|check| check.ok) { Status::Operational } else { Status::Failing };
+    ProbeResponse { status, checks }
+}
+
+fn check(name: &str, result: Result<String, String>) -> ProbeCheck {
+    let ok = result.is_ok();
+    ProbeCheck {
+        name: name.to_string(),
+        ok,
+        message: result.unwrap_or_else(|message| message),
+    }
+}
+
+// The registry lock is only ever held briefly, so waiting long for it means something is stuck holding it
+async fn registry(state: &AppState) -> ProbeCheck {
+    let result = match timeout(CHECK_TIMEOUT, state.dataset_names()).await {
+        Ok(names) => Ok(format!("{} datasets loaded", names.len())),
+        Err(_) => Err(format!("The dataset registry has been locked for over {:?}", CHECK_TIMEOUT)),
+    };
+    check("datasets", result)
+}
+
+fn writer_running(state: &AppState) -> ProbeCheck {
+    let result = if state.writer.is_running() {
+        Ok(format!("{} rows waiting to be written", state.writer.status().pending_rows))
+    } else {
+        Err(String::from("The output writer has stopped"))
+    };
+    check("writer", result)
+}
+
+// An output file that exists must open for appending (which doesn't change it); one that doesn't yet needs a directory
+// it can be created in
+fn output_writable(path: &Path) -> Result<String, String> {
+    if path.exists() {
+        return match OpenOptions::new().append(true).open(path) {
+            Ok(_) => Ok(format!("{:?} is writable", path)),
+            Err(e) => Err(format!("Can't write to {:?}: {}", path, e)),
+        };
+    }
+
+    dir_writable(path.parent().unwrap_or(Path::new(".")))
+}
+
+// Create (and remove) a file in the directory, or in the nearest one above it that exists, since that's where the
+// missing ones would be created
+fn dir_writable(dir: &Path) -> Result<String, String> {
+    let existing = dir
+        .ancestors()
+        // A relative path's last ancestor is the empty path, meaning the working directory
+        .map(|dir| if dir.as_os_str().is_empty() { Path::new(".") } else { dir })
+        .find(|dir| dir.is_dir())
+        .unwrap_or(dir);
+    let probe = existing.join(format!(".data_collator-probe-{:016x}", rand::random::<u64>()));
+
+    match OpenOptions::new().write(true).create_new(true).open(&probe) {
+        Ok(_) => {
+            let _ = fs::remove_file(&probe);
+            Ok(format!("{:?} is writable", dir))
+        }
+        Err(e) => Err(format!("Can't write to {:?}: {}", existing, e)),
+    }
+}
+
+async fn reachable((host, port): (&str, u16)) -> Result<String, String> {
+    match timeout(CHECK_TIMEOUT, TcpStream::connect((host, port))).await {
+        Ok(Ok(_)) => Ok(format!("{}:{} is reachable", host, port)),
+        Ok(Err(e)) => Err(format!("Can't connect to {}:{}: {}", host, port, e)),
+        Err(_) => Err(format!("Connecting to {}:{} took over {:?}", host, port, CHECK_TIMEOUT)),
+    }
+}
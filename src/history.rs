@@ -0,0 +1,317 @@
+// `as_of` reads: a dataset's rows as they were at some point in the past, e.g. just before a run's results went in.
+// Recent points come from the ingests its ledger keeps for rollback (`storage.rollback_batches`); older ones are
+// rebuilt by replaying the write-ahead log up to then, on top of what the dataset started out with, the way startup
+// rebuilds the datasets. Restores along the way read their snapshots back from `snapshots_dir`. The log also numbers
+// a dataset's versions (`GET /versions`), one per record that changed its rows, which `GET /diff` compares.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use polars::prelude::*;
+use serde_json::{Map, Value};
+
+use crate::{
+    aggregate::AggregateSpec,
+    config::StorageConfig,
+    dataset::{AppState, Dataset, SharedDataset, DEFAULT_DATASET},
+    error::AppError,
+    load::load_initial_state,
+    serialize::df_to_json_records,
+    types::{ChangedRow, DiffResponse, Status, VersionInfo, VersionsResponse},
+    wal::{self, Operation, Record},
+};
+
+// Parse an `as_of` parameter: an RFC 3339 time, e.g. `2024-06-01T12:00:00Z`
+pub(crate) fn parse_as_of(as_of: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(as_of.trim())
+        .map(|at| at.with_timezone(&Utc))
+        .map_err(|e| format!("Invalid as_of {:?}: {} (expected an RFC 3339 time, e.g. 2024-06-01T12:00:00Z)", as_of, e))
+}
+
+// The rows a dataset had at `at`
+pub(crate) async fn rows_as_of(
+    state: &AppState,
+    name: &str,
+    dataset: &SharedDataset,
+    at: DateTime<Utc>,
+) -> Result<DataFrame, AppError> {
+    if let Some(rows) = dataset.read().await.rows_as_of(at) {
+        return Ok(rows);
+    }
+
+    let storage = state.config.storage.clone();
+    let Some(wal) = storage.wal.clone() else {
+        return Err(AppError::BadRequest(format!(
+            "The rows {:?} had at {} aren't known: that's before the ingests it keeps for rollback, and there's no \
+             write-ahead log to rebuild them from",
+            name,
+            at.to_rfc3339_opts(SecondsFormat::Secs, true)
+        )));
+    };
+    // The whole log is read, so it's done on the blocking thread pool
+    let name = name.to_string();
+    tokio::task::spawn_blocking(move || replayed(&storage, &name, &wal, at))
+        .await
+        .map_err(|e| AppError::Internal(format!("Rebuilding the dataset failed: {}", e)))?
+        .map_err(AppError::Internal)
+}
+
+// Replay a dataset's records logged up to `at`
+fn replayed(storage: &StorageConfig, name: &str, wal: &Path, at: DateTime<Utc>) -> Result<DataFrame, String> {
+    let mut datasets = starting_state(storage, name)?;
+    let records = logged(wal, name)?
+        .into_iter()
+        .take_while(|record| record.at.is_none_or(|logged| logged <= at))
+        .collect();
+    wal::replay(records, &mut datasets, storage.snapshots_dir.as_deref(), storage.rollback_batches);
+
+    Ok(datasets.remove(name).and_then(|dataset| dataset.df).unwrap_or_default())
+}
+
+// What a dataset started out with, before its first record: the input file for the default dataset, nothing for
+// the others
+fn starting_state(storage: &StorageConfig, name: &str) -> Result<HashMap<String, Dataset>, String> {
+    let mut datasets = HashMap::new();
+    if name == DEFAULT_DATASET
+        && let Some(input) = &storage.input
+    {
+        let dataset = Dataset {
+            df: Some(load_initial_state(input)?),
+            ..Default::default()
+        };
+        datasets.insert(name.to_string(), dataset);
+    }
+    Ok(datasets)
+}
+
+// A dataset's records, in the order they were applied. Ones logged before records had timestamps come first.
+fn logged(wal: &Path, name: &str) -> Result<Vec<Record>, String> {
+    Ok(wal::read_log(wal)?.into_iter().filter(|record| record.dataset == name).collect())
+}
+
+// Versions are rebuilt from the write-ahead log, so there has to be one
+fn wal_path(state: &AppState) -> Result<PathBuf, AppError> {
+    state.config.storage.wal.clone().ok_or_else(|| {
+        AppError::BadRequest(String::from(
+            "Versions are rebuilt from the write-ahead log, which is disabled; set `storage.wal` (or --wal) to keep one",
+        ))
+    })
+}
+
+// Parse a version, as `v3` or `3`
+pub(crate) fn parse_version(version: &str) -> Result<u64, String> {
+    let trimmed = version.trim();
+    trimmed
+        .strip_prefix('v')
+        .unwrap_or(trimmed)
+        .parse()
+        .map_err(|_| format!("Invalid version {:?} (expected e.g. v3)", version))
+}
+
+// `GET /versions`. The whole log is read and the dataset's records replayed, so it's done on the blocking thread pool.
+pub(crate) async fn versions(state: &AppState, name: &str) -> Result<VersionsResponse, AppError> {
+    let wal = wal_path(state)?;
+    let storage = state.config.storage.clone();
+    let name = name.to_string();
+    tokio::task::spawn_blocking(move || list_versions(&storage, name, &wal))
+        .await
+        .map_err(|e| AppError::Internal(format!("Listing the versions failed: {}", e)))?
+        .map_err(AppError::Internal)
+}
+
+fn list_versions(storage: &StorageConfig, name: String, wal: &Path) -> Result<VersionsResponse, String> {
+    let mut datasets = starting_state(storage, &name)?;
+    let rows = |datasets: &HashMap<String, Dataset>| {
+        datasets.get(&name).and_then(|dataset| dataset.df.as_ref()).map_or(0, DataFrame::height)
+    };
+
+    let mut versions = vec![VersionInfo {
+        version: 0,
+        at: None,
+        operation: String::from("initial"),
+        rows: rows(&datasets),
+    }];
+    for record in logged(wal, &name)? {
+        let (at, operation, changes_rows) = (record.at, record.operation.name(), record.operation.changes_rows());
+        wal::replay(vec![record], &mut datasets, storage.snapshots_dir.as_deref(), storage.rollback_batches);
+        if changes_rows {
+            versions.push(VersionInfo {
+                version: versions.len() as u64,
+                at: at.map(|at| at.to_rfc3339_opts(SecondsFormat::Millis, true)),
+                operation: operation.to_string(),
+                rows: rows(&datasets),
+            });
+        }
+    }
+
+    Ok(VersionsResponse {
+        status: Status::Success,
+        dataset: name,
+        versions,
+    })
+}
+
+// `GET /diff`: `to` defaults to the latest version, and `from` to the one before `to`
+pub(crate) async fn diff(
+    state: &AppState,
+    name: &str,
+    from: Option<u64>,
+    to: Option<u64>,
+) -> Result<DiffResponse, AppError> {
+    let wal = wal_path(state)?;
+    let storage = state.config.storage.clone();
+    let name = name.to_string();
+    tokio::task::spawn_blocking(move || diff_versions(&storage, name, &wal, from, to))
+        .await
+        .map_err(|e| AppError::Internal(format!("Comparing the versions failed: {}", e)))?
+}
+
+// A dataset's rows at some version, and its group columns if it was an aggregate then
+struct Version {
+    df: DataFrame,
+    keys: Option<Vec<String>>,
+}
+
+fn diff_versions(
+    storage: &StorageConfig,
+    name: String,
+    wal: &Path,
+    from: Option<u64>,
+    to: Option<u64>,
+) -> Result<DiffResponse, AppError> {
+    let records = logged(wal, &name).map_err(AppError::Internal)?;
+    let latest = records.iter().filter(|record| record.operation.changes_rows()).count() as u64;
+    let to = to.unwrap_or(latest);
+    let from = from.unwrap_or(to.saturating_sub(1));
+    if let Some(missing) = [to, from].into_iter().find(|version| *version > latest) {
+        return Err(AppError::NotFound(format!(
+            "Dataset {:?} has no version {} (its latest is v{})",
+            name, missing, latest
+        )));
+    }
+
+    // One replay, stopping by the earlier version on the way to the later one
+    let (earlier, later) = (from.min(to), from.max(to));
+    let mut datasets = starting_state(storage, &name).map_err(AppError::Internal)?;
+    let mut spec: Option<AggregateSpec> = None;
+    let mut version = 0;
+    let mut found = Vec::new();
+    let mut records = records.into_iter();
+    loop {
+        while found.len() < 2 && [earlier, later][found.len()] == version {
+            found.push(version_of(&datasets, &name, spec.as_ref()));
+        }
+        let Some(record) = records.next().filter(|_| found.len() < 2) else {
+            break;
+        };
+        if record.operation.changes_rows() {
+            version += 1;
+        }
+        if let Operation::Aggregate { spec: aggregated, .. } = &record.operation {
+            spec = Some(aggregated.clone());
+        }
+        wal::replay(vec![record], &mut datasets, storage.snapshots_dir.as_deref(), storage.rollback_batches);
+    }
+    let (Some(mut after), Some(mut before)) = (found.pop(), found.pop()) else {
+        return Err(AppError::Internal(format!("Versions {} and {} weren't reached replaying the log", from, to)));
+    };
+    if from > to {
+        std::mem::swap(&mut before, &mut after);
+    }
+
+    let (added, removed, changed) = compare(&before.df, &after.df, after.keys.as_deref());
+    Ok(DiffResponse {
+        status: Status::Success,
+        dataset: name,
+        from,
+        to,
+        added,
+        removed,
+        keys: after.keys,
+        changed,
+    })
+}
+
+fn version_of(datasets: &HashMap<String, Dataset>, name: &str, spec: Option<&AggregateSpec>) -> Version {
+    let dataset = datasets.get(name);
+    Version {
+        df: dataset.and_then(|dataset| dataset.df.clone()).unwrap_or_default(),
+        keys: dataset
+            .filter(|dataset| dataset.aggregate_state.is_some())
+            .and(spec)
+            .map(AggregateSpec::group_columns),
+    }
+}
+
+// The rows `after` has that `before` doesn't, the ones it lacks, and (with keys) the groups in both whose rows differ
+fn compare(
+    before: &DataFrame,
+    after: &DataFrame,
+    keys: Option<&[String]>,
+) -> (Vec<Value>, Vec<Value>, Vec<ChangedRow>) {
+    let before = df_to_json_records(before);
+    let after = df_to_json_records(after);
+
+    // Rows are matched by their values, with nulls (and so columns one of the versions doesn't have) left out
+    let mut unmatched: HashMap<String, Vec<usize>> = HashMap::new();
+    for (i, row) in before.iter().enumerate().rev() {
+        unmatched.entry(row_key(row, None)).or_default().push(i);
+    }
+    let mut matched = vec![false; before.len()];
+    let mut added = Vec::new();
+    for row in after {
+        match unmatched.get_mut(&row_key(&row, None)).and_then(Vec::pop) {
+            Some(i) => matched[i] = true,
+            None => added.push(row),
+        }
+    }
+    let mut removed: Vec<Value> =
+        before.into_iter().zip(matched).filter(|(_, matched)| !matched).map(|(row, _)| row).collect();
+
+    let Some(keys) = keys else {
+        return (added, removed, Vec::new());
+    };
+    // An aggregate has one row per group, so a group that's removed and added again is one whose results changed
+    let mut groups: HashMap<String, usize> = HashMap::new();
+    for (i, row) in removed.iter().enumerate() {
+        groups.entry(row_key(row, Some(keys))).or_insert(i);
+    }
+    let mut changed = Vec::new();
+    let mut paired = vec![false; removed.len()];
+    added.retain(|row| match groups.remove(&row_key(row, Some(keys))) {
+        Some(i) => {
+            let key: Map<String, Value> = keys
+                .iter()
+                .map(|column| (column.clone(), row.get(column).cloned().unwrap_or(Value::Null)))
+                .collect();
+            changed.push(ChangedRow {
+                key: Value::Object(key),
+                from: removed[i].clone(),
+                to: row.clone(),
+            });
+            paired[i] = true;
+            false
+        }
+        None => true,
+    });
+    let mut paired = paired.into_iter();
+    removed.retain(|_| !paired.next().unwrap_or(false));
+
+    (added, removed, changed)
+}
+
+// What a row (or, with `columns`, some of its columns) is matched on
+fn row_key(row: &Value, columns: Option<&[String]>) -> String {
+    let Value::Object(row) = row else {
+        return row.to_string();
+    };
+    let values: BTreeMap<&str, &Value> = row
+        .iter()
+        .filter(|(column, value)| !value.is_null() && columns.is_none_or(|columns| columns.contains(column)))
+        .map(|(column, value)| (column.as_str(), value))
+        .collect();
+    serde_json::to_string(&values).unwrap_or_default()
+}
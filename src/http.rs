@@ -0,0 +1,184 @@
+// A minimal HTTP/1.1 client for the services rows are exported to (S3-compatible object stores, ClickHouse), and for
+// `client::Client`. Every request gets its own connection; there's no support for TLS, redirects, or keep-alive.
+
+use std::time::Duration;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+// How long connecting may take before the request counts as failed
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long the rest of the exchange may take, sending the body included
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+// Where a service is, from an `http://host:port/path` URL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+    // Path the service is mounted at, without a trailing `/` (empty at the root)
+    pub path: String,
+}
+
+impl Endpoint {
+    pub fn parse(url: &str) -> Result<Self, String> {
+        if url.starts_with("https://") {
+            return Err(String::from(
+                "https:// URLs aren't supported; TLS isn't part of this build (use http://, or a TLS-terminating proxy)",
+            ));
+        }
+        let rest = url.strip_prefix("http://").ok_or_else(|| String::from("expected an http://host:port URL"))?;
+        if rest.contains(['?', '#', '@']) {
+            return Err(String::from("the URL can't have credentials, parameters, or a fragment"));
+        }
+
+        let (address, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], rest[i..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        // An IPv6 address is in brackets, since it has colons of its own
+        let (host, port) = match address.strip_prefix('[') {
+            Some(rest) => {
+                let (host, port) = rest.split_once(']').ok_or_else(|| format!("invalid address {:?}", address))?;
+                (host, port.strip_prefix(':'))
+            }
+            None => match address.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (address, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| format!("invalid port {:?}", port))?,
+            None => 80,
+        };
+        if host.is_empty() {
+            return Err(String::from("the URL has no host"));
+        }
+
+        Ok(Endpoint {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    // What goes in the `Host` header, which leaves out the default port
+    pub fn authority(&self) -> String {
+        let host = if self.host.contains(':') { format!("[{}]", self.host) } else { self.host.clone() };
+        if self.port == 80 { host } else { format!("{}:{}", host, self.port) }
+    }
+}
+
+// Percent-encode a query parameter value, leaving only unreserved characters as they are
+pub fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    // The value of a header (the first, if it's repeated)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+}
+
+// Send a request for `target` (the path and query, from the root of the host) and read the whole response. `Host`,
+// `Content-Length`, and `Connection` are added to `headers` here.
+pub async fn send(
+    endpoint: &Endpoint,
+    method: &str,
+    target: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+) -> Result<Response, String> {
+    let address = format!("{}:{}", endpoint.host, endpoint.port);
+    let mut stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect((endpoint.host.as_str(), endpoint.port)))
+        .await
+        .map_err(|_| format!("Timed out connecting to {}", address))?
+        .map_err(|e| format!("Can't connect to {}: {}", address, e))?;
+
+    let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", method, target, endpoint.authority());
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n\r\n", body.len()));
+
+    let exchange = async {
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(body).await?;
+        stream.flush().await?;
+
+        // The server closes the connection once it's sent the response, since we asked it to
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok::<_, std::io::Error>(response)
+    };
+    let response = tokio::time::timeout(REQUEST_TIMEOUT, exchange)
+        .await
+        .map_err(|_| format!("Timed out waiting for {} to respond", address))?
+        .map_err(|e| format!("Request to {} failed: {}", address, e))?;
+
+    parse_response(&response).ok_or_else(|| format!("{} sent a malformed response", address))
+}
+
+fn parse_response(response: &[u8]) -> Option<Response> {
+    let end = response.windows(4).position(|window| window == b"\r\n\r\n")?;
+    let head = std::str::from_utf8(&response[..end]).ok()?;
+    let mut body = &response[end + 4..];
+
+    let mut lines = head.split("\r\n");
+    let status = lines.next()?.split(' ').nth(1)?.parse().ok()?;
+    let mut chunked = false;
+    let mut headers = Vec::new();
+    for line in lines {
+        let (name, value) = line.split_once(':')?;
+        let value = value.trim();
+        headers.push((name.to_string(), value.to_string()));
+        if name.eq_ignore_ascii_case("content-length") {
+            body = body.get(..value.parse().ok()?)?;
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        }
+    }
+
+    let body = if chunked { dechunk(body)? } else { body.to_vec() };
+    Some(Response { status, headers, body })
+}
+
+// Undo `Transfer-Encoding: chunked`
+fn dechunk(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    loop {
+        let end = body.windows(2).position(|window| window == b"\r\n")?;
+        let size = std::str::from_utf8(&body[..end]).ok()?;
+        // Chunk extensions (`;name=value`) don't matter here
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        body = &body[end + 2..];
+        if size == 0 {
+            return Some(data);
+        }
+        data.extend_from_slice(body.get(..size)?);
+        body = body.get(size + 2..)?;
+    }
+}
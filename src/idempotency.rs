@@ -0,0 +1,151 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    extract::{MatchedPath, RawPathParams, Request, State},
+    http::{HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    RequestExt,
+};
+use log::trace;
+
+use crate::{api::unversioned, auth::request_key, config::IdempotencyConfig, dataset::AppState, error::AppError};
+
+// Longest `Idempotency-Key` accepted
+const MAX_KEY_LEN: usize = 255;
+
+// Routes (or ends of routes, for named datasets) that ingest payloads
+const INGEST_ROUTES: &[&str] = &["/collate", "/collate/batch", "/collate_wide", "/upsert", "/aggregate"];
+
+// Header added to responses replayed from the cache
+const REPLAYED_HEADER: &str = "idempotent-replayed";
+
+// A successful response, kept to be sent again
+#[derive(Debug)]
+struct StoredResponse {
+    at: Instant,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl StoredResponse {
+    fn to_response(&self) -> Response {
+        let mut response = (self.status, Body::from(self.body.clone())).into_response();
+        *response.headers_mut() = self.headers.clone();
+        response.headers_mut().insert(REPLAYED_HEADER, HeaderValue::from_static("true"));
+        response
+    }
+}
+
+// One key's response, or `None` while the first request with it is still running (or after it failed). The lock is
+// held for the whole first request, so a retry that arrives in the meantime waits for it rather than ingesting twice.
+type Slot = Arc<tokio::sync::Mutex<Option<StoredResponse>>>;
+
+// Responses to recent requests that carried an `Idempotency-Key`
+#[derive(Debug, Default)]
+pub struct IdempotencyCache {
+    slots: Mutex<HashMap<String, Slot>>,
+}
+
+impl IdempotencyCache {
+    // The slot for a key, making room for it if the cache is full
+    fn slot(&self, config: &IdempotencyConfig, key: &str) -> Slot {
+        let mut slots = self.slots.lock().unwrap();
+        if let Some(slot) = slots.get(key) {
+            return slot.clone();
+        }
+
+        if slots.len() >= config.max_keys {
+            let ttl = Duration::from_secs(config.ttl_secs);
+            // Slots that are locked belong to requests still running, so they're kept
+            slots.retain(|_, slot| {
+                slot.try_lock().map_or(true, |stored| stored.as_ref().is_some_and(|stored| stored.at.elapsed() < ttl))
+            });
+
+            while slots.len() >= config.max_keys {
+                let oldest = slots
+                    .iter()
+                    .filter_map(|(key, slot)| slot.try_lock().ok()?.as_ref().map(|stored| (key.clone(), stored.at)))
+                    .min_by_key(|(_, at)| *at)
+                    .map(|(key, _)| key);
+                match oldest {
+                    Some(oldest) => slots.remove(&oldest),
+                    // Everything left is in flight; let the cache grow rather than turn the request away
+                    None => break,
+                };
+            }
+        }
+
+        slots.entry(key.to_string()).or_default().clone()
+    }
+}
+
+// Middleware making `POST /collate`, `POST /upsert`, and `POST /aggregate` (and their named dataset versions) safe to
+// retry. A request with an `Idempotency-Key` header gets the response of the first successful request with the same
+// key, route, and API key, for `ttl_secs`, instead of being applied again. Failed requests aren't remembered, so they
+// can be retried.
+pub async fn deduplicate(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let config = &state.config.idempotency;
+    let route = request.extensions().get::<MatchedPath>().map(|path| path.as_str().to_string());
+    let ingest = request.method() == Method::POST
+        && route.as_deref().is_some_and(|route| INGEST_ROUTES.iter().any(|suffix| route.ends_with(suffix)));
+    let key = request.headers().get("idempotency-key");
+    if !config.enabled() || !ingest || key.is_none() {
+        return Ok(next.run(request).await);
+    }
+
+    let key = key
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|key| !key.is_empty() && key.len() <= MAX_KEY_LEN)
+        .ok_or_else(|| {
+            AppError::BadRequest(format!("The Idempotency-Key header must be 1 to {} visible ASCII characters", MAX_KEY_LEN))
+        })?;
+
+    // The same key from two clients (or for two datasets) means two different requests, but a retry sent to `/v1/...`
+    // after `/...` (or with `Token` after `Bearer`) is the same one. So the slot is the unversioned route, the dataset
+    // it names, and the API key, rather than the raw path and header.
+    let key = key.to_string();
+    let route = route.as_deref().map(unversioned).unwrap_or_default().to_string();
+    let params = request.extract_parts::<RawPathParams>().await.ok();
+    let params = params.iter().flat_map(|params| params.iter()).map(|(name, value)| format!("{}={}", name, value));
+    let params = params.collect::<Vec<_>>().join("&");
+    let api_key = request_key(request.headers()).unwrap_or("");
+    let cache_key = format!("{}\n{}\n{}\n{}", route, params, api_key, key);
+
+    let slot = state.idempotency.slot(config, &cache_key);
+    let mut stored = slot.lock().await;
+    if let Some(previous) = stored.as_ref().filter(|previous| previous.at.elapsed() < Duration::from_secs(config.ttl_secs)) {
+        trace!("Replaying the response for Idempotency-Key {:?}", key);
+        return Ok(previous.to_response());
+    }
+
+    let response = next.run(request).await;
+    if !response.status().is_success() {
+        *stored = None;
+        return Ok(response);
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = to_bytes(body, usize::MAX)
+        .await
+        .map_err(|e| AppError::Internal(format!("The data was accepted, but the response couldn't be sent: {}", e)))?;
+    *stored = Some(StoredResponse {
+        at: Instant::now(),
+        status: parts.status,
+        headers: parts.headers.clone(),
+        body: body.clone(),
+    });
+
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
@@ -0,0 +1,84 @@
+// The collation engine behind `data_collator serve`, for services that embed it instead of running the daemon:
+// `Collator` holds the datasets and does everything the service does to what's added to them, and `api::router` serves
+// the HTTP API on top of one, whose bodies are `types`. `client::Client` is for services that talk to a running
+// collator over that API instead.
+
+mod accounting;
+mod aggregate;
+pub mod api;
+mod audit;
+mod auth;
+mod barrier;
+mod batch;
+pub mod cli;
+mod clickhouse;
+pub mod client;
+mod collator;
+mod completeness;
+mod compression;
+mod compute;
+mod computed;
+mod csv_stream;
+pub mod config;
+mod dashboard;
+mod dataset;
+mod describe;
+mod digest;
+mod error;
+mod events;
+mod filter;
+mod forward;
+mod health;
+mod history;
+mod http;
+mod idempotency;
+mod line_protocol;
+mod load;
+pub mod logging;
+mod lookup;
+mod metrics;
+mod nulls;
+mod openapi;
+mod otlp;
+mod outliers;
+mod partition;
+pub mod payload;
+mod persist;
+mod postgres;
+mod provenance;
+mod pubsub;
+mod rank;
+mod rate_limit;
+mod remote_write;
+mod replication;
+mod resample;
+mod reshape;
+mod retention;
+mod rolling;
+mod s3;
+mod schema;
+mod serialize;
+mod sharding;
+mod sink;
+mod sketch;
+mod snapshot;
+mod spill;
+mod sources;
+mod stats;
+mod statsd;
+mod stream;
+mod tail;
+pub mod types;
+mod upload;
+mod validation;
+mod wal;
+mod watch;
+mod webhooks;
+mod writer;
+mod ws;
+
+pub use aggregate::{AggregateOperation, AggregateSpec, TimeWindow};
+pub use collator::{Collator, ReadOptions};
+pub use config::Config;
+pub use error::AppError;
+pub use polars;
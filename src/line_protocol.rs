@@ -0,0 +1,494 @@
+// InfluxDB line protocol, as telemetry agents like Telegraf write it to `/write`. Each line is one point:
+//
+//     <measurement>[,<tag>=<value>...] <field>=<value>[,<field>=<value>...] [<timestamp>]
+//
+// and becomes one row, with the measurement, every tag and field as columns of their own, and the timestamp.
+
+use std::str::FromStr;
+
+use indexmap::IndexMap;
+use log::warn;
+use polars::prelude::*;
+
+use crate::dataset::Dataset;
+
+// Columns every row has, so tags and fields can't be named after them. Remote write rows have the same `time`.
+const MEASUREMENT_COLUMN: &str = "measurement";
+pub const TIME_COLUMN: &str = "time";
+pub const TIME_DTYPE: DataType = DataType::Datetime(TimeUnit::Nanoseconds, None);
+
+// What a line's timestamp counts in (`precision`). Nanoseconds unless the client says otherwise.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Precision {
+    #[default]
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+    Minutes,
+    Hours,
+}
+
+impl FromStr for Precision {
+    type Err = String;
+
+    // InfluxDB 1.x spells them `n`, `u`, `ms`, `s`, `m`, and `h`; 2.x `ns`, `us`, `ms`, and `s`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "n" | "ns" => Ok(Precision::Nanoseconds),
+            "u" | "us" | "µ" | "µs" => Ok(Precision::Microseconds),
+            "ms" => Ok(Precision::Milliseconds),
+            "s" => Ok(Precision::Seconds),
+            "m" => Ok(Precision::Minutes),
+            "h" => Ok(Precision::Hours),
+            other => Err(format!("Unsupported precision {:?} (expected ns, us, ms, s, m, or h)", other)),
+        }
+    }
+}
+
+impl Precision {
+    fn nanoseconds(self) -> i64 {
+        match self {
+            Precision::Nanoseconds => 1,
+            Precision::Microseconds => 1_000,
+            Precision::Milliseconds => 1_000_000,
+            Precision::Seconds => 1_000_000_000,
+            Precision::Minutes => 60 * 1_000_000_000,
+            Precision::Hours => 60 * 60 * 1_000_000_000,
+        }
+    }
+}
+
+// A tag's or field's value. Numbers are floats unless they end in `i` (signed) or `u` (unsigned).
+#[derive(Debug)]
+enum Value {
+    Tag(String),
+    Float(f64),
+    Int(i64),
+    UInt(u64),
+    Bool(bool),
+    Str(String),
+}
+
+impl Value {
+    fn kind(&self) -> &'static str {
+        match self {
+            Value::Tag(_) => "a tag",
+            Value::Float(_) => "a float field",
+            Value::Int(_) => "an integer field",
+            Value::UInt(_) => "an unsigned integer field",
+            Value::Bool(_) => "a boolean field",
+            Value::Str(_) => "a string field",
+        }
+    }
+}
+
+// A column's values so far, one per row (null where a point didn't have it)
+enum Values {
+    Tag(Vec<Option<String>>),
+    Float(Vec<Option<f64>>),
+    Int(Vec<Option<i64>>),
+    UInt(Vec<Option<u64>>),
+    Bool(Vec<Option<bool>>),
+    Str(Vec<Option<String>>),
+}
+
+impl Values {
+    // An empty column for values like this one
+    fn like(value: &Value) -> Self {
+        match value {
+            Value::Tag(_) => Values::Tag(Vec::new()),
+            Value::Float(_) => Values::Float(Vec::new()),
+            Value::Int(_) => Values::Int(Vec::new()),
+            Value::UInt(_) => Values::UInt(Vec::new()),
+            Value::Bool(_) => Values::Bool(Vec::new()),
+            Value::Str(_) => Values::Str(Vec::new()),
+        }
+    }
+
+    // Add a value to the column, or hand it back if it's of another kind
+    fn push(&mut self, value: Value) -> Result<(), Value> {
+        match (self, value) {
+            (Values::Tag(values), Value::Tag(value)) | (Values::Str(values), Value::Str(value)) => values.push(Some(value)),
+            (Values::Float(values), Value::Float(value)) => values.push(Some(value)),
+            (Values::Int(values), Value::Int(value)) => values.push(Some(value)),
+            (Values::UInt(values), Value::UInt(value)) => values.push(Some(value)),
+            (Values::Bool(values), Value::Bool(value)) => values.push(Some(value)),
+            (_, value) => return Err(value),
+        }
+        Ok(())
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Values::Tag(_) => "a tag",
+            Values::Float(_) => "a float field",
+            Values::Int(_) => "an integer field",
+            Values::UInt(_) => "an unsigned integer field",
+            Values::Bool(_) => "a boolean field",
+            Values::Str(_) => "a string field",
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Values::Tag(values) | Values::Str(values) => values.len(),
+            Values::Float(values) => values.len(),
+            Values::Int(values) => values.len(),
+            Values::UInt(values) => values.len(),
+            Values::Bool(values) => values.len(),
+        }
+    }
+
+    // Nulls up to `rows`, for the points before this one that didn't have the column
+    fn pad(&mut self, rows: usize) {
+        match self {
+            Values::Tag(values) | Values::Str(values) => values.resize(rows, None),
+            Values::Float(values) => values.resize(rows, None),
+            Values::Int(values) => values.resize(rows, None),
+            Values::UInt(values) => values.resize(rows, None),
+            Values::Bool(values) => values.resize(rows, None),
+        }
+    }
+
+    fn into_column(self, name: &str) -> Column {
+        let name = PlSmallStr::from(name);
+        match self {
+            Values::Tag(values) | Values::Str(values) => Column::new(name, values),
+            Values::Float(values) => Column::new(name, values),
+            Values::Int(values) => Column::new(name, values),
+            Values::UInt(values) => Column::new(name, values),
+            Values::Bool(values) => Column::new(name, values),
+        }
+    }
+}
+
+// The rows of a line protocol body. Points without a timestamp get `now` (nanoseconds since the epoch), so they all
+// share the time the request arrived, as InfluxDB does.
+pub fn parse(body: &str, precision: Precision, now: i64) -> Result<DataFrame, String> {
+    let mut measurements = Vec::new();
+    let mut times = Vec::new();
+    let mut columns: IndexMap<String, Values> = IndexMap::new();
+
+    let lines = body.lines().enumerate().map(|(i, line)| (i + 1, line.trim()));
+    for (number, line) in lines.filter(|(_, line)| !line.is_empty() && !line.starts_with('#')) {
+        let row = measurements.len();
+        let point = parse_line(line).map_err(|e| format!("Line {}: {}", number, e))?;
+
+        for (key, value) in point.values {
+            if key == MEASUREMENT_COLUMN || key == TIME_COLUMN {
+                return Err(format!("Line {}: {:?} can't be used as a tag or field name", number, key));
+            }
+            let values = columns.entry(key.clone()).or_insert_with(|| Values::like(&value));
+            if values.len() > row {
+                return Err(format!("Line {}: {:?} is given more than once", number, key));
+            }
+            values.pad(row);
+            if let Err(value) = values.push(value) {
+                return Err(format!(
+                    "Line {}: {:?} is {} here, but {} in an earlier line",
+                    number,
+                    key,
+                    value.kind(),
+                    values.kind()
+                ));
+            }
+        }
+
+        let time = match point.timestamp {
+            Some(timestamp) => timestamp
+                .checked_mul(precision.nanoseconds())
+                .ok_or_else(|| format!("Line {}: the timestamp is out of range", number))?,
+            None => now,
+        };
+        measurements.push(point.measurement);
+        times.push(time);
+    }
+    if measurements.is_empty() {
+        return Err(String::from("The body has no points"));
+    }
+
+    let rows = measurements.len();
+    let mut df = vec![Column::new(PlSmallStr::from(MEASUREMENT_COLUMN), measurements)];
+    for (name, mut values) in columns {
+        values.pad(rows);
+        df.push(values.into_column(&name));
+    }
+    let time = Column::new(PlSmallStr::from(TIME_COLUMN), times).cast(&TIME_DTYPE).map_err(|e| e.to_string())?;
+    df.push(time);
+
+    DataFrame::new(df).map_err(|e| e.to_string())
+}
+
+// Output files are CSV, so a dataset read back from one has its times as strings. Parse them again before more points
+// are added, so they still line up.
+pub fn restore_time(name: &str, dataset: &mut Dataset) {
+    let Some(df) = dataset.df.as_mut() else {
+        return;
+    };
+
+    let (times, nulls) = match df.column(TIME_COLUMN) {
+        Ok(existing) if existing.dtype() == &DataType::String => (existing.cast(&TIME_DTYPE), existing.null_count()),
+        _ => return,
+    };
+    // Values that aren't times come back null rather than failing the cast, so they're counted instead
+    let result = times.and_then(|times| {
+        let unparsed = times.null_count() - nulls;
+        if unparsed > 0 {
+            polars_bail!(ComputeError: "{} values aren't times", unparsed);
+        }
+        df.with_column(times).map(|_| ())
+    });
+    if let Err(e) = result {
+        warn!("Can't parse the {:?} column of dataset {:?} as times: {}", TIME_COLUMN, name, e);
+    }
+}
+
+#[derive(Debug)]
+struct Point {
+    measurement: String,
+    // The tags, then the fields
+    values: Vec<(String, Value)>,
+    timestamp: Option<i64>,
+}
+
+fn parse_line(line: &str) -> Result<Point, String> {
+    let mut rest = line;
+
+    // Commas, spaces, and equals signs in names and tag values are escaped with a backslash
+    let measurement = take_escaped(&mut rest, &[',', ' ']);
+    if measurement.is_empty() {
+        return Err(String::from("the measurement is missing"));
+    }
+
+    let mut values = Vec::new();
+    while let Some(after) = rest.strip_prefix(',') {
+        rest = after;
+        let key = take_escaped(&mut rest, &[',', '=', ' ']);
+        rest = rest.strip_prefix('=').ok_or_else(|| format!("the tag {:?} has no value", key))?;
+        let value = take_escaped(&mut rest, &[',', ' ']);
+        if key.is_empty() || value.is_empty() {
+            return Err(String::from("tags need a name and a value"));
+        }
+        values.push((key, Value::Tag(value)));
+    }
+
+    rest = rest.trim_start_matches(' ');
+    loop {
+        let key = take_escaped(&mut rest, &[',', '=', ' ']);
+        if key.is_empty() {
+            return Err(String::from("a point needs at least one field"));
+        }
+        rest = rest.strip_prefix('=').ok_or_else(|| format!("the field {:?} has no value", key))?;
+        let value = take_value(&mut rest).map_err(|e| format!("the field {:?} {}", key, e))?;
+        values.push((key, value));
+
+        match rest.strip_prefix(',') {
+            Some(after) => rest = after,
+            None => break,
+        }
+    }
+
+    let timestamp = match rest.trim() {
+        "" => None,
+        timestamp if rest.starts_with(' ') => {
+            Some(timestamp.parse().map_err(|_| format!("invalid timestamp {:?}", timestamp))?)
+        }
+        _ => return Err(format!("unexpected {:?} after the fields", rest)),
+    };
+
+    Ok(Point {
+        measurement,
+        values,
+        timestamp,
+    })
+}
+
+// Take everything up to the first unescaped `stops` character off the front of `rest`, with the escapes removed. A
+// backslash before anything but a comma, equals sign, space, or backslash is kept as it is.
+fn take_escaped(rest: &mut &str, stops: &[char]) -> String {
+    let mut taken = String::new();
+    let mut chars = rest.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => match chars.peek() {
+                Some(&(_, next @ (',' | '=' | ' ' | '\\'))) => {
+                    taken.push(next);
+                    chars.next();
+                }
+                _ => taken.push(c),
+            },
+            c if stops.contains(&c) => {
+                *rest = &rest[i..];
+                return taken;
+            }
+            c => taken.push(c),
+        }
+    }
+    *rest = "";
+    taken
+}
+
+// Take a field value off the front of `rest`: a double-quoted string (with `\"` and `\\` escapes), or a number or
+// boolean running up to the next comma or space
+fn take_value(rest: &mut &str) -> Result<Value, String> {
+    if let Some(quoted) = rest.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = quoted.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => match chars.clone().next() {
+                    Some((_, next @ ('"' | '\\'))) => {
+                        value.push(next);
+                        chars.next();
+                    }
+                    _ => value.push(c),
+                },
+                '"' => {
+                    *rest = &quoted[i + 1..];
+                    return Ok(Value::Str(value));
+                }
+                c => value.push(c),
+            }
+        }
+        return Err(String::from("has a string that's never closed"));
+    }
+
+    let end = rest.find([',', ' ']).unwrap_or(rest.len());
+    let (raw, after) = rest.split_at(end);
+    *rest = after;
+    let invalid = || format!("has an invalid value {:?}", raw);
+
+    let value = match raw {
+        "t" | "T" | "true" | "True" | "TRUE" => Value::Bool(true),
+        "f" | "F" | "false" | "False" | "FALSE" => Value::Bool(false),
+        "" => return Err(String::from("has no value")),
+        raw => match raw.as_bytes()[raw.len() - 1] {
+            b'i' => Value::Int(raw[..raw.len() - 1].parse().map_err(|_| invalid())?),
+            b'u' => Value::UInt(raw[..raw.len() - 1].parse().map_err(|_| invalid())?),
+            _ => {
+                let value: f64 = raw.parse().map_err(|_| invalid())?;
+                // `inf` and `NaN` parse as floats, but aren't numbers InfluxDB takes
+                if !value.is_finite() {
+                    return Err(invalid());
+                }
+                Value::Float(value)
+            }
+        },
+    };
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(point: &Point, i: usize) -> (&str, &str) {
+        match &point.values[i] {
+            (key, Value::Tag(value)) => (key, value),
+            (key, value) => panic!("{:?} is {}", key, value.kind()),
+        }
+    }
+
+    #[test]
+    fn escaped_measurements_and_tags() {
+        let point = parse_line(r"cpu\ load\,avg,host\ name=web\ 01,path=a\,b\=c,odd=x\y value=1").unwrap();
+        assert_eq!(point.measurement, "cpu load,avg");
+        assert_eq!(tag(&point, 0), ("host name", "web 01"));
+        assert_eq!(tag(&point, 1), ("path", "a,b=c"));
+        // A backslash before anything else is kept
+        assert_eq!(tag(&point, 2), ("odd", r"x\y"));
+
+        let point = parse_line(r"m field\=name\ x=1").unwrap();
+        assert_eq!(point.values[0].0, "field=name x");
+    }
+
+    #[test]
+    fn numbers() {
+        let point = parse_line("m a=5i,b=7u,c=1.5,d=-3i,e=1e3").unwrap();
+        assert!(matches!(point.values[0].1, Value::Int(5)));
+        assert!(matches!(point.values[1].1, Value::UInt(7)));
+        assert!(matches!(point.values[2].1, Value::Float(value) if value == 1.5));
+        assert!(matches!(point.values[3].1, Value::Int(-3)));
+        assert!(matches!(point.values[4].1, Value::Float(value) if value == 1000.0));
+
+        for line in ["m a=5.5i", "m a=-1u", "m a=12x", "m a=inf", "m a=NaN", "m a=i"] {
+            assert!(parse_line(line).unwrap_err().contains("invalid value"), "{}", line);
+        }
+    }
+
+    #[test]
+    fn booleans() {
+        for (raw, expected) in [("t", true), ("T", true), ("true", true), ("True", true), ("TRUE", true)] {
+            let point = parse_line(&format!("m a={}", raw)).unwrap();
+            assert!(matches!(point.values[0].1, Value::Bool(value) if value == expected));
+        }
+        for raw in ["f", "F", "false", "False", "FALSE"] {
+            assert!(matches!(parse_line(&format!("m a={}", raw)).unwrap().values[0].1, Value::Bool(false)));
+        }
+        assert!(parse_line("m a=yes").is_err());
+    }
+
+    #[test]
+    fn strings() {
+        let point = parse_line(r#"m s="a \"quoted\" value, with spaces\\",n=1i 1700000000"#).unwrap();
+        assert!(matches!(&point.values[0].1, Value::Str(value) if value == r#"a "quoted" value, with spaces\"#));
+        assert!(matches!(point.values[1].1, Value::Int(1)));
+        assert_eq!(point.timestamp, Some(1_700_000_000));
+
+        assert!(parse_line(r#"m s="never closed"#).unwrap_err().contains("never closed"));
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(parse_line("m a=1").unwrap().timestamp, None);
+        assert_eq!(parse_line("m a=1 ").unwrap().timestamp, None);
+        assert_eq!(parse_line("m a=1 -5").unwrap().timestamp, Some(-5));
+        assert!(parse_line("m a=1 soon").unwrap_err().contains("invalid timestamp"));
+        assert!(parse_line("m a=1 1 2").is_err());
+    }
+
+    #[test]
+    fn malformed_lines() {
+        for (line, error) in [
+            (",a=1 b=1", "measurement is missing"),
+            ("m", "at least one field"),
+            ("m,a=1", "at least one field"),
+            ("m,a b=1", "has no value"),
+            ("m,=x b=1", "a name and a value"),
+            ("m,a= b=1", "a name and a value"),
+            ("m b", "has no value"),
+            ("m b=", "has no value"),
+        ] {
+            assert!(parse_line(line).unwrap_err().contains(error), "{}", line);
+        }
+    }
+
+    #[test]
+    fn rows() {
+        let body = "# a comment\n\ncpu,host=a usage=0.5 10\nmem,host=b free=3i\ncpu usage=0.25,up=true 20\n";
+        let df = parse(body, Precision::Seconds, 99).unwrap();
+        assert_eq!(df.get_column_names(), ["measurement", "host", "usage", "free", "up", "time"]);
+        assert_eq!(df.height(), 3);
+
+        let host: Vec<Option<&str>> = df.column("host").unwrap().str().unwrap().into_iter().collect();
+        assert_eq!(host, [Some("a"), Some("b"), None]);
+        let free: Vec<Option<i64>> = df.column("free").unwrap().i64().unwrap().into_iter().collect();
+        assert_eq!(free, [None, Some(3), None]);
+        // Seconds, and `now` for the line without one
+        let time = df.column("time").unwrap().cast(&DataType::Int64).unwrap();
+        let time: Vec<Option<i64>> = time.i64().unwrap().into_iter().collect();
+        assert_eq!(time, [Some(10_000_000_000), Some(99), Some(20_000_000_000)]);
+    }
+
+    #[test]
+    fn conflicting_lines() {
+        let error = parse("m a=1\nm a=1i", Precision::Nanoseconds, 0).unwrap_err();
+        assert_eq!(error, "Line 2: \"a\" is an integer field here, but a float field in an earlier line");
+        assert!(parse("m a=1,a=2", Precision::Nanoseconds, 0).unwrap_err().contains("more than once"));
+        assert!(parse("m,time=x a=1", Precision::Nanoseconds, 0).unwrap_err().contains("can't be used"));
+        assert!(parse("m a=1 9223372036854775807", Precision::Seconds, 0).unwrap_err().contains("out of range"));
+        assert_eq!(parse("# nothing\n", Precision::Nanoseconds, 0).unwrap_err(), "The body has no points");
+    }
+}
@@ -0,0 +1,197 @@
+use std::{collections::HashMap, io::ErrorKind, path::Path};
+
+use log::{error, info, warn};
+use polars::prelude::*;
+
+use crate::{
+    config::StorageConfig,
+    dataset::{output_file_for, validate_dataset_name, Dataset, DEFAULT_DATASET},
+    payload::read_file,
+    persist::{self, StorageBackend, WriteMode},
+};
+
+// Read the file the default dataset starts out with. The format is picked from the extension (see `FileFormat`). A
+// path with a glob pattern in it (`results/**/*.csv`) reads every file that matches instead.
+pub fn load_initial_state(path: &Path) -> Result<DataFrame, String> {
+    if is_glob(path) {
+        return load_glob(path);
+    }
+
+    let metadata = std::fs::metadata(path).map_err(|e| format!("Can't load initial state from {:?}: {}", path, e))?;
+    if !metadata.is_file() {
+        return Err(format!("Can't load initial state from {:?}: not a file", path));
+    }
+
+    let df = read_file(path).map_err(|e| format!("Can't load initial state: {}", e))?;
+
+    info!("Loaded {} rows ({} columns) from {:?}", df.height(), df.width(), path);
+
+    Ok(df)
+}
+
+fn is_glob(path: &Path) -> bool {
+    path.to_string_lossy().contains(['*', '?', '['])
+}
+
+// Concatenate every file matching a glob pattern, in path order. Columns are aligned by name, as in `/collate`'s union
+// mode. Each file that can't be read is reported, and then startup fails rather than going ahead without it.
+fn load_glob(pattern: &Path) -> Result<DataFrame, String> {
+    let pattern = pattern.to_string_lossy();
+    let paths = glob::glob(&pattern).map_err(|e| format!("Invalid input pattern {:?}: {}", pattern, e))?;
+
+    let mut frames = Vec::new();
+    let mut errors = Vec::new();
+    for path in paths {
+        let path = match path {
+            Ok(path) if path.is_file() => path,
+            Ok(_) => continue,
+            Err(e) => {
+                errors.push(format!("Can't read {:?}: {}", e.path(), e.error()));
+                continue;
+            }
+        };
+        match read_file(&path) {
+            Ok(df) => {
+                info!("Loaded {} rows ({} columns) from {:?}", df.height(), df.width(), path);
+                frames.push(df.lazy());
+            }
+            Err(e) => errors.push(e),
+        }
+    }
+
+    for error in &errors {
+        error!("{}", error);
+    }
+    if !errors.is_empty() {
+        let count = errors.len();
+        return Err(format!("Can't load initial state: {} of the files matching {:?} can't be read", count, pattern));
+    }
+    if frames.is_empty() {
+        return Err(format!("Can't load initial state: no files match {:?}", pattern));
+    }
+
+    let count = frames.len();
+    let args = UnionArgs {
+        rechunk: true,
+        to_supertypes: true,
+        ..Default::default()
+    };
+    let df = concat_lf_diagonal(frames, args)
+        .and_then(LazyFrame::collect)
+        .map_err(|e| format!("Can't load initial state: the files matching {:?} can't be combined: {}", pattern, e))?;
+
+    info!("Loaded {} rows ({} columns) from {} files matching {:?}", df.height(), df.width(), count, pattern);
+
+    Ok(df)
+}
+
+// Work out what every dataset's data starts out as: the `--input` file for the default dataset, plus whatever was
+// persisted to the output files before the last shutdown (or crash).
+pub fn initial_datasets(
+    storage: &StorageConfig,
+    initial_df: Option<DataFrame>,
+) -> Result<HashMap<String, Dataset>, String> {
+    let datasets = initial_frames(storage, initial_df)?;
+
+    Ok(datasets
+        .into_iter()
+        .map(|(name, df)| {
+            let dataset = Dataset {
+                df: Some(df),
+                ..Default::default()
+            };
+            (name, dataset)
+        })
+        .collect())
+}
+
+fn initial_frames(
+    storage: &StorageConfig,
+    initial_df: Option<DataFrame>,
+) -> Result<HashMap<String, DataFrame>, String> {
+    let mut datasets = HashMap::new();
+    if let Some(df) = initial_df {
+        datasets.insert(DEFAULT_DATASET.to_string(), df);
+    }
+
+    // With a write-ahead log, the log is replayed instead: it has everything the output files do, and more
+    if !storage.recover || storage.wal.is_some() {
+        return Ok(datasets);
+    }
+
+    let backend = persist::backend(storage.output_format)?;
+    let mut names = vec![DEFAULT_DATASET.to_string()];
+    if let Some(dir) = &storage.datasets_dir {
+        names.extend(persisted_dataset_names(dir, backend.extension())?);
+    }
+
+    for name in names {
+        let Some(path) = output_file_for(storage, &name) else {
+            continue;
+        };
+        let Some(recovered) = recover_output(backend.as_ref(), &path, storage.write_mode)? else {
+            continue;
+        };
+
+        // An appended output file only holds what was received after startup, so it goes on top of the input.
+        // A snapshot already includes everything.
+        let df = match datasets.remove(&name) {
+            Some(initial) if storage.write_mode == WriteMode::Append => initial
+                .vstack(&recovered)
+                .map_err(|e| format!("Can't recover {:?} on top of the input file: {}", path, e))?,
+            _ => recovered,
+        };
+        datasets.insert(name, df);
+    }
+
+    Ok(datasets)
+}
+
+// Read a dataset back from its output file. Returns `None` when there's nothing to recover.
+fn recover_output(backend: &dyn StorageBackend, path: &Path, mode: WriteMode) -> Result<Option<DataFrame>, String> {
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.is_file() && metadata.len() > 0 => (),
+        Ok(_) => return Ok(None),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Can't recover from {:?}: {}", path, e)),
+    }
+
+    // Overwrite mode only keeps the latest batch, which isn't the dataset's state
+    if mode == WriteMode::Overwrite {
+        warn!("Not recovering from {:?}: files written in overwrite mode only hold the latest batch", path);
+        return Ok(None);
+    }
+
+    let df = backend.load(path).map_err(|e| format!("Can't recover from {:?}: {}", path, e))?;
+
+    info!("Recovered {} rows ({} columns) from {:?}", df.height(), df.width(), path);
+
+    Ok(Some(df))
+}
+
+// Names of the datasets with an output file (ending in `extension`) in `dir`
+fn persisted_dataset_names(dir: &Path, extension: &str) -> Result<Vec<String>, String> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Can't read datasets directory {:?}: {}", dir, e)),
+    };
+
+    let mut names = Vec::new();
+    for entry in entries {
+        let path = entry.map_err(|e| format!("Can't read datasets directory {:?}: {}", dir, e))?.path();
+        if path.extension().is_none_or(|ext| ext != extension) {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+
+        // The default dataset is persisted to `output`, not the datasets directory
+        if name != DEFAULT_DATASET && validate_dataset_name(name).is_ok() {
+            names.push(name.to_string());
+        }
+    }
+
+    Ok(names)
+}
@@ -0,0 +1,299 @@
+// Logs: every request gets an id (its `X-Request-Id`, or a new one), which is sent back in the response and tagged on
+// everything logged while handling it, and one line is logged when it finishes, with its route, status, latency,
+// client, and the rows it ingested. Lines are plain text by default, or one JSON object each (`server.log_format`).
+// Which lines are logged (`server.log_level`, or `RUST_LOG`) can be changed while the service runs, by
+// `PUT /admin/log_level` or a `SIGHUP`.
+
+use std::{
+    cell::Cell,
+    fmt,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, OnceLock},
+    time::Instant,
+};
+
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    http::HeaderValue,
+    middleware::Next,
+    response::Response,
+};
+use chrono::{SecondsFormat, Utc};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use tracing::{
+    field::{self, Field, Visit},
+    span, Event, Instrument, Level, Subscriber,
+};
+use tracing_subscriber::{
+    field::RecordFields,
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields, FormattedFields},
+    filter::Targets,
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    reload,
+    util::SubscriberInitExt,
+    EnvFilter, Layer, Registry,
+};
+
+use crate::{dataset::AppState, otlp::SpanRecorder, rate_limit::client_ip};
+
+// The header a request's id is read from (when a proxy in front already gave it one) and sent back in
+pub const REQUEST_ID: &str = "x-request-id";
+
+// Longest id taken from a request; longer ones are replaced with a new one
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+// The filter used when neither `server.log_level` nor `RUST_LOG` sets one: errors only
+const DEFAULT_LOG_LEVEL: &str = "error";
+
+// Swaps the filter of the lines being logged, once `init` has run
+static LOG_LEVEL: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+tokio::task_local! {
+    // Rows the request being handled has ingested so far
+    static INGESTED: Cell<usize>;
+}
+
+// How log lines are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum LogFormat {
+    // Human-readable lines, the request's fields in front of each message
+    #[default]
+    Text,
+    // One JSON object per line, for log shippers (Loki, Elasticsearch, CloudWatch, ...)
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("Unsupported log format {:?} (expected one of: text, json)", other)),
+        }
+    }
+}
+
+impl TryFrom<String> for LogFormat {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+// Send log lines (from `log` and `tracing` alike) to standard output, filtered by `log_level` (`RUST_LOG` when it's
+// `None`). With `record_spans`, the collator's spans are also recorded for `[telemetry]` to export, whatever the filter
+// says.
+pub fn init(format: LogFormat, log_level: Option<&str>, record_spans: bool) {
+    let lines = match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer().fmt_fields(JsonFields).event_format(JsonFormat).boxed(),
+    };
+    let (filter, handle) = reload::Layer::new(EnvFilter::builder().parse_lossy(resolved_log_level(log_level)));
+    let _ = LOG_LEVEL.set(handle);
+    let spans = record_spans.then(|| SpanRecorder.with_filter(Targets::new().with_target("data_collator", Level::INFO)));
+
+    tracing_subscriber::registry().with(lines.with_filter(filter)).with(spans).init();
+}
+
+// The filter a configured `log_level` amounts to: itself, or else `RUST_LOG`, or else errors only
+pub fn resolved_log_level(log_level: Option<&str>) -> String {
+    match log_level {
+        Some(log_level) => log_level.to_string(),
+        None => std::env::var(EnvFilter::DEFAULT_ENV).unwrap_or_else(|_| String::from(DEFAULT_LOG_LEVEL)),
+    }
+}
+
+// Check a filter, e.g. `info,data_collator::aggregate=debug` (the syntax `RUST_LOG` takes)
+pub fn parse_log_level(log_level: &str) -> Result<EnvFilter, String> {
+    if log_level.trim().is_empty() {
+        return Err(String::from("The log level is empty (expected e.g. \"info\", or \"warn,data_collator=debug\")"));
+    }
+    EnvFilter::builder()
+        .parse(log_level)
+        .map_err(|e| format!("Invalid log level {:?}: {}", log_level, e))
+}
+
+// The filter lines are logged with right now
+pub fn current_log_level() -> String {
+    LOG_LEVEL
+        .get()
+        .and_then(|handle| handle.with_current(ToString::to_string).ok())
+        .unwrap_or_default()
+}
+
+// Log lines by another filter from now on, returning the one it replaced
+pub fn set_log_level(log_level: &str) -> Result<String, String> {
+    let filter = parse_log_level(log_level)?;
+    let handle = LOG_LEVEL.get().ok_or_else(|| String::from("Logging hasn't been set up"))?;
+    let previous = current_log_level();
+    handle.reload(filter).map_err(|e| format!("Can't change the log level: {}", e))?;
+
+    Ok(previous)
+}
+
+// A request's id, in its extensions for the layers inside `trace_requests`
+#[derive(Debug, Clone)]
+pub(crate) struct RequestId(pub(crate) String);
+
+// Count rows ingested while handling a request, for its log line. Does nothing outside of a request.
+pub(crate) fn count_ingested(rows: usize) {
+    let _ = INGESTED.try_with(|count| count.set(count.get() + rows));
+}
+
+// Rows ingested so far by the request being handled
+pub(crate) fn ingested() -> usize {
+    INGESTED.try_with(Cell::get).unwrap_or(0)
+}
+
+// Middleware giving every request an id, and logging a line once it's handled. Everything logged while it's handled
+// is in its span, so it carries the id too.
+pub(crate) async fn trace_requests(State(state): State<Arc<AppState>>, mut request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic()))
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:032x}", rand::random::<u128>()));
+    // The route rather than the path, so no values from the path (or the query string) end up in the logs
+    let route = request.extensions().get::<MatchedPath>().map_or("unmatched", |path| path.as_str()).to_string();
+    let peer = request.extensions().get::<ConnectInfo<SocketAddr>>().map(|info| info.0);
+    let client = client_ip(&state, request.headers(), peer).map_or(String::from("unknown"), |ip| ip.to_string());
+
+    request.extensions_mut().insert(RequestId(id.clone()));
+
+    let method = request.method().clone();
+    // `status` and `rows` are filled in once the request is handled, so they're on the span (for `otlp`) too
+    let span = tracing::info_span!(
+        "request",
+        request_id = %id,
+        method = %method,
+        route = %route,
+        client = %client,
+        status = field::Empty,
+        rows = field::Empty,
+    );
+    let started = Instant::now();
+    let handled = async {
+        let response = next.run(request).await;
+        (response, INGESTED.with(Cell::get))
+    };
+    let (mut response, rows) = INGESTED.scope(Cell::new(0), handled).instrument(span.clone()).await;
+
+    let latency_ms = (started.elapsed().as_secs_f64() * 1e6).round() / 1e3;
+    span.record("status", response.status().as_u16());
+    span.record("rows", rows);
+    span.in_scope(|| tracing::info!(latency_ms, "Request finished"));
+
+    let id = HeaderValue::from_str(&id).expect("request ids are visible ASCII");
+    response.headers_mut().insert(REQUEST_ID, id);
+    response
+}
+
+// Writes each event as a JSON object: its time, level, and target, the fields of the spans it's in (outermost first),
+// and then its own fields (`message` among them)
+struct JsonFormat;
+
+impl<S> FormatEvent<S, JsonFields> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let metadata = event.metadata();
+        let mut line = Map::new();
+        line.insert(String::from("timestamp"), Value::from(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)));
+        line.insert(String::from("level"), Value::from(metadata.level().to_string()));
+        line.insert(String::from("target"), Value::from(metadata.target()));
+
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                let extensions = span.extensions();
+                let fields = extensions.get::<FormattedFields<JsonFields>>();
+                if let Some(Ok(Value::Object(fields))) = fields.map(|fields| serde_json::from_str(&fields.fields)) {
+                    line.extend(fields);
+                }
+            }
+        }
+        event.record(&mut JsonVisitor(&mut line));
+
+        writeln!(writer, "{}", Value::Object(line))
+    }
+}
+
+// Keeps each span's fields as a JSON object, which `JsonFormat` merges into the lines logged in it
+struct JsonFields;
+
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: RecordFields>(&self, mut writer: Writer<'writer>, fields: R) -> fmt::Result {
+        let mut map = Map::new();
+        fields.record(&mut JsonVisitor(&mut map));
+        write!(writer, "{}", Value::Object(map))
+    }
+
+    // Fields recorded after the span was created go in the same object
+    fn add_fields(&self, current: &'writer mut FormattedFields<Self>, fields: &span::Record<'_>) -> fmt::Result {
+        let mut map = match serde_json::from_str(&current.fields) {
+            Ok(Value::Object(map)) => map,
+            _ => Map::new(),
+        };
+        fields.record(&mut JsonVisitor(&mut map));
+        current.fields = Value::Object(map).to_string();
+        Ok(())
+    }
+}
+
+// Collects fields into a JSON object
+pub(crate) struct JsonVisitor<'a>(pub(crate) &'a mut Map<String, Value>);
+
+impl JsonVisitor<'_> {
+    fn insert(&mut self, field: &Field, value: Value) {
+        match field.name() {
+            // Lines from the `log` crate come with where they were logged from; only the target is kept, as the line's
+            "log.target" => {
+                self.0.insert(String::from("target"), value);
+            }
+            name if name.starts_with("log.") => {}
+            name => {
+                self.0.insert(name.to_string(), value);
+            }
+        }
+    }
+}
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, Value::from(format!("{:?}", value)));
+    }
+}
@@ -0,0 +1,106 @@
+use std::{collections::HashMap, sync::Arc};
+
+use polars::prelude::*;
+use tokio::sync::RwLock;
+
+// Added to a lookup column's name when the data being enriched already has a column with that name
+const CLASH_SUFFIX: &str = "_lookup";
+
+// A reference table loaded with `PUT /lookup/{name}`, e.g. hostname → rack and CPU model, that reads can left-join
+// onto a dataset's rows by its key columns
+#[derive(Debug, Clone)]
+pub struct LookupTable {
+    pub df: DataFrame,
+    pub keys: Vec<String>,
+}
+
+impl LookupTable {
+    // Keys must exist and be unique, so enriching never adds or drops rows
+    pub fn new(df: DataFrame, keys: Vec<String>) -> Result<Self, String> {
+        if keys.is_empty() {
+            return Err(String::from("At least one key column is required"));
+        }
+        if let Some(key) = keys.iter().find(|key| df.column(key).is_err()) {
+            return Err(format!("Key column {:?} doesn't exist", key));
+        }
+        if keys.len() == df.width() {
+            return Err(String::from("The lookup table has no columns besides its keys"));
+        }
+        let unique = df
+            .select(&keys)
+            .and_then(|keys| keys.unique_stable(None, UniqueKeepStrategy::Any, None))
+            .map_err(|e| e.to_string())?
+            .height();
+        if unique != df.height() {
+            return Err(format!(
+                "Keys {:?} aren't unique ({} rows, {} distinct keys)",
+                keys,
+                df.height(),
+                unique
+            ));
+        }
+
+        Ok(LookupTable { df, keys })
+    }
+
+    // Make sure rows with `schema` have every key column, so they can be enriched
+    pub fn check_keys(&self, schema: &Schema) -> Result<(), String> {
+        match self.keys.iter().find(|key| !schema.contains(key)) {
+            Some(key) => Err(format!("Lookup key column {:?} doesn't exist in the data", key)),
+            None => Ok(()),
+        }
+    }
+
+    // Left-join the table onto `df` by its keys: every row of `df` is kept, in order, with the table's other columns
+    // added (null where a row's key isn't in the table). Key columns are cast to `df`'s types first, so e.g. an
+    // integer `rank` key still matches a float one. A dataset with no columns yet is returned as it is.
+    pub fn enrich(&self, df: &DataFrame) -> Result<DataFrame, String> {
+        self.enriched(df.clone().lazy(), df.schema())?.collect().map_err(|e| e.to_string())
+    }
+
+    // The same, as a step of a lazy query over rows with `schema`
+    pub fn enriched(&self, rows: LazyFrame, schema: &Schema) -> Result<LazyFrame, String> {
+        if schema.is_empty() {
+            return Ok(rows);
+        }
+        self.check_keys(schema)?;
+        let casts: Vec<Expr> = self
+            .keys
+            .iter()
+            .filter_map(|key| Some(col(key.as_str()).cast(schema.get(key)?.clone())))
+            .collect();
+        let keys: Vec<Expr> = self.keys.iter().map(|key| col(key.as_str())).collect();
+
+        let mut args = JoinArgs::new(JoinType::Left).with_suffix(Some(CLASH_SUFFIX.into()));
+        args.maintain_order = MaintainOrderJoin::Left;
+        Ok(rows.join(self.df.clone().lazy().with_columns(casts), keys.clone(), keys, args))
+    }
+}
+
+// Every loaded lookup table, by name. They're only kept in memory, so they have to be loaded again after a restart.
+#[derive(Debug, Default)]
+pub struct Lookups {
+    tables: RwLock<HashMap<String, Arc<LookupTable>>>,
+}
+
+impl Lookups {
+    pub async fn get(&self, name: &str) -> Option<Arc<LookupTable>> {
+        self.tables.read().await.get(name).cloned()
+    }
+
+    // Returns whether it replaced a table with the same name
+    pub async fn insert(&self, name: &str, table: LookupTable) -> bool {
+        self.tables.write().await.insert(name.to_string(), Arc::new(table)).is_some()
+    }
+
+    pub async fn remove(&self, name: &str) -> Option<Arc<LookupTable>> {
+        self.tables.write().await.remove(name)
+    }
+
+    // Names of every table, sorted
+    pub async fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.tables.read().await.keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
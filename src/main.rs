@@ -1,321 +1,302 @@
-use std::{env, error::Error, io::Cursor, path::PathBuf};
+use std::{env, io::Write, net::SocketAddr, process::ExitCode};
 
-use axum::{
-    extract::State, response::IntoResponse, routing::{get, post}, Json, Router
+use log::{error, info, warn};
+
+use data_collator::{
+    api,
+    logging::{self, LogFormat},
+    cli::{self, AggregateArgs, Command, ExportArgs, PullArgs, PushArgs, RemoteArgs, ServeArgs, ValidateArgs},
+    client::{Client, Ingested},
+    payload::{read_file, write_df, FileFormat},
+    Collator, Config,
 };
-use serde_json::json;
-use log::{error, trace};
-use polars::prelude::*;
-use tokio::sync::Mutex;
-use tracing_subscriber::fmt::format;
-
-#[derive(Clone, Debug)]
-struct AppState {
-    // A "global source of truth" dataframe
-    df: Option<DataFrame>,
-    output_file: Option<PathBuf>,
-}
+
+// Where the client commands send requests when neither --to/--from nor DATA_COLLATOR_URL says
+const DEFAULT_URL: &str = "http://localhost:3000";
 
 #[tokio::main]
-async fn main() {
-    // initialize tracing
-    tracing_subscriber::fmt::init();
-
-    // Initialize the app state
-    let mut app_state = AppState {
-        df: None,
-        output_file: None,
+async fn main() -> ExitCode {
+    let command = match cli::parse(env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e.message, e.usage);
+            return ExitCode::from(2);
+        }
     };
 
-    // Check if the user has provided a CSV file
-    let args: Vec<String> = env::args().collect();
-    for arg in args {
-        if arg.ends_with(".csv") {
-            let csv_file = arg;
-
-            // Use Polars to read the CSV
-            let df = CsvReader::new(Cursor::new(csv_file.clone())).finish().unwrap();
+    // `serve` sets up logging once its config is resolved, since that says how log lines are written
+    if !matches!(command, Command::Serve(_)) {
+        logging::init(LogFormat::Text, None, false);
+    }
 
-            // Update the app state
-            app_state.df = Some(df);
-            app_state.output_file = Some(PathBuf::from(csv_file.clone()));
-            
-            break;
+    match command {
+        Command::Help(usage) => {
+            print!("{}", usage);
+            ExitCode::SUCCESS
         }
+        Command::Serve(args) => serve(*args).await,
+        Command::Export(args) => run_export(args),
+        Command::Validate(args) => run_validate(args),
+        Command::Push(args) => run_push(args).await,
+        Command::Pull(args) => run_pull(args).await,
+        Command::Aggregate(args) => run_aggregate(args).await,
     }
+}
 
-    // Check for IP-related arguments
-    let mut expose_ip = String::from("0.0.0.0");
-    let mut port = 3000;
-    let args: Vec<String> = env::args().collect();
-    for (i, arg) in args.iter().enumerate() {
-        if arg == "--local" {
-            expose_ip = String::from("127.0.0.1");
+// `data_collator export`: convert a data file to another format
+fn run_export(args: ExportArgs) -> ExitCode {
+    let format = match args.format.map(Ok).unwrap_or_else(|| FileFormat::from_path(&args.output)) {
+        Ok(format) => format,
+        Err(message) => {
+            eprintln!("error: {}", message);
+            return ExitCode::FAILURE;
         }
+    };
 
-        if arg == "--port" {
-            port = args[i + 1].parse::<u16>().unwrap();
+    let result = read_file(&args.input)
+        .and_then(|mut df| write_df(&mut df, format))
+        .and_then(|bytes| std::fs::write(&args.output, bytes).map_err(|e| format!("Can't write {:?}: {}", args.output, e)));
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("error: {}", message);
+            ExitCode::FAILURE
         }
     }
+}
 
-    // Create a reference to the app state (will be shared across threads/tokio tasks, so needs to be thread safe)
-    let state_ref = Arc::new(Mutex::new(app_state));
+// `data_collator validate`: make sure a data file can be read and print its schema
+fn run_validate(args: ValidateArgs) -> ExitCode {
+    let df = match read_file(&args.input) {
+        Ok(df) => df,
+        Err(message) => {
+            eprintln!("error: {}", message);
+            return ExitCode::FAILURE;
+        }
+    };
 
-    // Build router
-    let app = Router::new()
-        // `GET /` goes to `root`
-        .route("/", get(root))
-        // `POST /collate` goes to `collate`
-        .route("/collate", post(collate))
-        // `POST /aggregate` goes to `aggregate`
-        .route("/aggregate", post(aggregate))
-        // Add the app state to the router
-        .with_state(state_ref);
+    println!("{}: {} rows, {} columns", args.input.display(), df.height(), df.width());
+    for column in df.get_columns() {
+        println!("  {}: {} ({} nulls)", column.name(), column.dtype(), column.null_count());
+    }
 
-    // Create a listener
-    let listener = tokio::net::TcpListener::bind(format!("{}:{}", expose_ip, port))
-        .await
-        .unwrap();
+    ExitCode::SUCCESS
+}
 
-    tracing::debug!("listening on {}", listener.local_addr().unwrap());
+// The client the push, pull and aggregate commands send their requests with. The URL and API key fall back to
+// DATA_COLLATOR_URL and DATA_COLLATOR_API_KEY, so they needn't be repeated (or show up in shell history).
+fn connect(remote: &RemoteArgs) -> Result<Client, String> {
+    let url = remote.url.clone().or_else(|| env::var("DATA_COLLATOR_URL").ok());
+    let mut client = Client::new(url.as_deref().unwrap_or(DEFAULT_URL)).map_err(|e| e.to_string())?;
+    if let Some(key) = remote.api_key.clone().or_else(|| env::var("DATA_COLLATOR_API_KEY").ok()) {
+        client = client.with_api_key(&key);
+    }
+    if let Some(retries) = remote.retries {
+        client = client.with_retries(retries);
+    }
+    Ok(client)
+}
 
-    // Serve app with hyper
-    axum::serve(listener, app).await.unwrap();
+// Describe what became of pushed or aggregated rows
+fn report(dataset: &str, sent: usize, ingested: &Ingested) {
+    let replayed = if ingested.replayed { " (already accepted, not added again)" } else { "" };
+    println!("Sent {} rows to {:?}{}: it now has {} rows", sent, dataset, replayed, ingested.dataset.height());
+    if let Some(rows) = ingested.quarantined.filter(|rows| *rows > 0) {
+        println!("  {} rows were quarantined", rows);
+    }
+    if let Some(rows) = ingested.skipped.filter(|rows| *rows > 0) {
+        println!("  {} rows were skipped as duplicates", rows);
+    }
 }
 
-// Health check, essentially
-async fn root() -> impl IntoResponse {
-    trace!("Root endpoint (GET /) called. Returning operational status.");
-    
-    Json(json!({
-        "status": "operational"
-    }))
+// `data_collator push`: collate a data file into a running service
+async fn run_push(args: PushArgs) -> ExitCode {
+    let result = async {
+        let client = connect(&args.remote)?;
+        let mut df = read_file(&args.input)?;
+        let ingested = client.collate(&args.remote.dataset, &mut df).await.map_err(|e| e.to_string())?;
+        report(&args.remote.dataset, df.height(), &ingested);
+        Ok::<_, String>(())
+    };
+
+    match result.await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("error: {}", message);
+            ExitCode::FAILURE
+        }
+    }
 }
 
-// handler that accepts a POST request with a CSV payload and returns a JSON response
-#[axum_macros::debug_handler]
-async fn collate(State(state): State<Arc<Mutex<AppState>>>, body: String) -> impl IntoResponse {
-    trace!("Collating message: {:?}", body);
-
-    // Convert the body into a vector of bytes
-    let body_bytes = body.as_bytes();
-
-    // Use Polars to read the CSV
-    let mut df = CsvReader::new(Cursor::new(body_bytes)).finish().unwrap();
-
-    // Acquire a lock on the app state within a scope
-    let output_csv_text;
-    let output_file;
-    {
-        let mut state = state.lock().await;
-
-        // Set the output file
-        output_file = state.output_file.clone();
-
-        // Get the current state
-        match state.df.as_ref() {
-            Some(df) => {
-                // Concatenate the current state with the new DataFrame
-                let new_df = match df.vstack(df) {
-                    Ok(df) => df,
-                    Err(e) => {
-                        error!("Error concatenating DataFrames: {:?}", e);
-                        return Json(json!({
-                            "status": "error",
-                            "message": e.to_string()
-                        }));
-                    }
-                };
-
-                // Update the app state
-                state.df = Some(new_df);
-
-                output_csv_text = get_df_as_csv(state.df.as_mut().unwrap(), true);
-
-                // Print the DataFrame
-                trace!("Concatted. New state:\n{:?}", state.df.as_ref().unwrap());
-            },
-            None => {
-                // If the current state is None, set it to the new DataFrame (don't need to concat!)
-                state.df = Some(df.clone());
-                
-                output_csv_text = get_df_as_csv(state.df.as_mut().unwrap(), true);
-
-                trace!("Brand new, no concat was needed. New state:\n{:?}", state.df.as_ref().unwrap());
-            }
+// `data_collator pull`: download a dataset from a running service, to a file or standard output
+async fn run_pull(args: PullArgs) -> ExitCode {
+    let result = async {
+        let format = match (args.format, &args.output) {
+            (Some(format), _) => format,
+            (None, Some(output)) => FileFormat::from_path(output)?,
+            (None, None) => FileFormat::Csv,
         };
-    }
+        let client = connect(&args.remote)?;
+        let bytes = client.export(&args.remote.dataset, format).await.map_err(|e| e.to_string())?;
 
-    // Directly append the new DataFrame to the output file (if it has been set)
-    let mut wrote_to_file = String::from("no");
-    if let Some(output_file) = &output_file {
-        append_df_to_csv(&mut df, output_file).await.unwrap();
-        wrote_to_file = format!("yes: {:?}", output_file);
-    }
+        match &args.output {
+            Some(output) => std::fs::write(output, bytes).map_err(|e| format!("Can't write {:?}: {}", output, e)),
+            None => std::io::stdout().write_all(&bytes).map_err(|e| format!("Can't write to standard output: {}", e)),
+        }
+    };
 
-    Json(json!({
-        "status": "success",
-        "wrote_to_file": wrote_to_file,
-        "csv_string": output_csv_text
-    }))
+    match result.await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("error: {}", message);
+            ExitCode::FAILURE
+        }
+    }
 }
 
+// `data_collator aggregate`: aggregate a data file into a dataset of a running service
+async fn run_aggregate(mut args: AggregateArgs) -> ExitCode {
+    let result = async {
+        let client = connect(&args.remote)?;
+        let mut df = read_file(&args.input)?;
+        // As with /aggregate, rows are grouped by their first column unless told otherwise (or windowed)
+        if args.spec.keys.is_empty() && args.spec.window.is_none() {
+            let first = df.get_columns().first().ok_or_else(|| format!("{:?} has no columns", args.input))?;
+            args.spec.keys.push(first.name().to_string());
+        }
+        let ingested = client.aggregate(&args.remote.dataset, &mut df, &args.spec).await.map_err(|e| e.to_string())?;
+        report(&args.remote.dataset, df.height(), &ingested);
+        Ok::<_, String>(())
+    };
 
-#[derive(Debug, Clone)]
-enum AggregateOperation {
-    Sum,
-    Mean,
+    match result.await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("error: {}", message);
+            ExitCode::FAILURE
+        }
+    }
 }
 
-#[inline(always)]
-fn group_by_sum(df: &DataFrame, key: &str) -> PolarsResult<DataFrame> {
-    let mut summed = df.group_by([key])?
-    .sum()?;
-
-    let mut out = summed.clone();
+// `data_collator serve`: run the HTTP service
+async fn serve(args: ServeArgs) -> ExitCode {
+    let config = match Config::resolve(&args) {
+        Ok(config) => config,
+        Err(message) => {
+            eprintln!("error: {}", message);
+            return ExitCode::FAILURE;
+        }
+    };
 
-    for col in summed.get_column_names() {
-        if col.to_string().ends_with("_sum") {
-            let new_name = col.to_string().replace("_sum", "");
+    let server = &config.server;
+    logging::init(server.log_format, server.log_level.as_deref(), config.telemetry.otlp_endpoint.is_some());
+    #[cfg(unix)]
+    tokio::spawn(reload_log_level_on_hangup(args.clone()));
 
-            let proper_name = PlSmallStr::from(new_name.clone());
+    let bind = (config.server.bind.clone(), config.server.port);
 
-            // Rename the column
-            out.rename(col, proper_name)?;
+    let collator = match Collator::open(config) {
+        Ok(collator) => collator,
+        Err(message) => {
+            eprintln!("error: {}", message);
+            return ExitCode::FAILURE;
         }
+    };
+    if let Err(message) = collator.start_sources().await {
+        eprintln!("error: {}", message);
+        return ExitCode::FAILURE;
     }
 
-    Ok(out)
-}
+    let app = api::router(&collator);
 
-#[inline(always)]
-fn group_by_mean(df: &DataFrame, key: &str) -> PolarsResult<DataFrame> {
-    Err(PolarsError::ComputeError("Mean is not supported yet".into()))
-}
+    // Create a listener
+    let listener = match tokio::net::TcpListener::bind((bind.0.as_str(), bind.1)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("error: Can't listen on {}:{}: {}", bind.0, bind.1, e);
+            return ExitCode::FAILURE;
+        }
+    };
 
-// handler that accepts a POST request with a CSV payload, updates the state according to keys, and returns the updated DataFrame as a CSV string
-#[axum_macros::debug_handler]
-async fn aggregate(State(state): State<Arc<Mutex<AppState>>>, body: String) -> impl IntoResponse {
-    trace!("Aggregating message: {:?}", body);
-
-    // Convert the body into a vector of bytes
-    let body_bytes = body.as_bytes();
-
-    // Use Polars to read the CSV
-    let mut df = CsvReader::new(Cursor::new(body_bytes)).finish().unwrap();
-
-    // HACK: Only support sum for now
-    let operation = AggregateOperation::Sum;
-
-    // Acquire a lock on the app state within a scope
-    let output_csv_text;
-    let output_file;
-    {
-        let mut state = state.lock().await;
-
-        // Set the output file
-        output_file = state.output_file.clone();
-
-        // Get the current state
-        match state.df.as_ref() {
-            Some(state_df) => {
-                // Get the first column header
-                let key = df.get_columns()[0].name().to_string();
-
-                // Concatenate the current state with the new DataFrame
-                let cat_df = match state_df.vstack(&df) {
-                    Ok(df) => df,
-                    Err(e) => {
-                        error!("Error concatenating DataFrames: {:?}", e);
-                        return Json(json!({
-                            "status": "error",
-                            "message": e.to_string()
-                        }));
-                    }
-                };
-
-                // Print the DataFrame
-                trace!("Aggregated. New state:\n{:?}", cat_df);
-
-                // Update the DataFrame according to the aggregate operation joining on the first column value 
-                let updated_df = match operation {
-                    AggregateOperation::Sum => match group_by_sum(&cat_df, key.as_str()) {
-                        Ok(df) => df,
-                        Err(e) => {
-                            error!("Error aggregating DataFrame: {:?}", e);
-                            return Json(json!({
-                                "status": "error",
-                                "message": e.to_string()
-                            }));
-                        }
-                    },
-                    AggregateOperation::Mean => match group_by_mean(&cat_df, key.as_str()) {
-                        Ok(df) => df,
-                        Err(e) => {
-                            error!("Error aggregating DataFrame: {:?}", e);
-                            return Json(json!({
-                                "status": "error",
-                                "message": e.to_string()
-                            }));
-                        }
-                    }
-                };
-                
-                // Update the app state
-                state.df = Some(updated_df);
-
-                output_csv_text = get_df_as_csv(state.df.as_mut().unwrap(), true);
-
-                // Print the DataFrame
-                trace!("Concatted. New state:\n{:?}", state.df.as_ref().unwrap());
-            },
-            None => {
-                // If the current state is None, set it to the new DataFrame (don't need to concat or do any aggregation!)
-                state.df = Some(df.clone());
-                
-                output_csv_text = get_df_as_csv(state.df.as_mut().unwrap(), true);
-
-                trace!("Brand new, no concat was needed. New state:\n{:?}", state.df.as_ref().unwrap());
-            }
-        };
+    if let Ok(addr) = listener.local_addr() {
+        tracing::debug!("listening on {}", addr);
     }
 
-    // Directly append the new DataFrame to the output file (if it has been set)
-    let mut wrote_to_file = String::from("no");
-    if let Some(output_file) = &output_file {
-        append_df_to_csv(&mut df, output_file).await.unwrap();
-        wrote_to_file = format!("yes: {:?}", output_file);
+    // Serve app with hyper until asked to stop. In-flight requests are allowed to finish first.
+    if let Err(e) = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).with_graceful_shutdown(shutdown_signal()).await {
+        error!("Server error: {}", e);
+        return ExitCode::FAILURE;
     }
 
-    Json(json!({
-        "status": "success",
-        "wrote_to_file": wrote_to_file,
-        "csv_string": output_csv_text
-    }))
+    // Make sure everything accepted so far is on disk before exiting
+    info!("Shutting down, flushing pending writes");
+    match collator.persist().await {
+        Ok(status) => {
+            info!("Flushed {} rows in total, exiting", status.rows_flushed);
+            ExitCode::SUCCESS
+        }
+        Err(message) => {
+            error!("Final flush failed: {}", message);
+            ExitCode::FAILURE
+        }
+    }
 }
 
+// Set the log level back to the configured one on every SIGHUP, re-reading the config file (so an edited `log_level`
+// takes effect) and undoing any `PUT /admin/log_level`. Nothing else in the file is applied; that takes a restart.
+#[cfg(unix)]
+async fn reload_log_level_on_hangup(args: ServeArgs) {
+    let mut hangups = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!("Can't listen for SIGHUP: {}", e);
+            return;
+        }
+    };
 
-    // Append a DataFrame to a CSV file. If it doesn't exist, create it.
-async fn append_df_to_csv(df: &mut DataFrame, output_file: &PathBuf) -> Result<(), Box<dyn Error>> {
-    let mut file = std::fs::File::create(output_file)?;
-
-    CsvWriter::new(&mut file).include_header(false).finish(df)?;
-
-    Ok(())
+    while hangups.recv().await.is_some() {
+        let log_level = match Config::resolve(&args) {
+            Ok(config) => logging::resolved_log_level(config.server.log_level.as_deref()),
+            Err(message) => {
+                error!("Received SIGHUP, but the config can't be read, so the log level is unchanged: {}", message);
+                continue;
+            }
+        };
+        match logging::set_log_level(&log_level) {
+            Ok(previous) => warn!("Received SIGHUP, the log level is now {:?} (was {:?})", log_level, previous),
+            Err(message) => error!("Received SIGHUP, but {}", message),
+        }
+    }
 }
 
+// Resolves when the process receives Ctrl+C (SIGINT) or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Can't listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
 
-// Get a DataFrame as a CSV string
-fn get_df_as_csv(df: &mut DataFrame, include_header: bool) -> String {
-    let mut csv_bytes = Vec::new();
-
-    match CsvWriter::new(&mut csv_bytes).include_header(include_header).finish(df) {
-        Ok(_) => (),
-        Err(e) => {
-            error!("Error writing DataFrame to CSV: {:?}", e);
-            return String::new();
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!("Can't listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
         }
-    }
+    };
 
-    String::from_utf8(csv_bytes).unwrap()
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("Received Ctrl+C"),
+        _ = terminate => info!("Received SIGTERM"),
+    }
 }
@@ -0,0 +1,567 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+
+use serde_json::Value;
+
+use crate::{
+    accounting,
+    dataset::AppState,
+    logging,
+    otlp::{self, Labels, Timestamps},
+};
+
+// Upper bounds of the payload size histogram, in bytes (1 KiB to 64 MiB)
+const PAYLOAD_BUCKETS: &[f64] = &[
+    1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0, 16777216.0, 67108864.0,
+];
+
+// Upper bounds of the latency histograms, in seconds
+const LATENCY_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+// How far back the recent ingest rate (`/admin/stats`) looks, in one-second slots
+const RECENT_SECS: u64 = 60;
+
+// Cumulative-bucket histogram in the shape Prometheus expects
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    values: Mutex<HistogramValues>,
+}
+
+#[derive(Debug)]
+struct HistogramValues {
+    // One count per bound, plus one for `+Inf`. Not cumulative; that happens when rendering.
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            values: Mutex::new(HistogramValues {
+                buckets: vec![0; bounds.len() + 1],
+                sum: 0.0,
+                count: 0,
+            }),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let bucket = self.bounds.iter().position(|bound| value <= *bound).unwrap_or(self.bounds.len());
+
+        let mut values = self.values.lock().unwrap();
+        values.buckets[bucket] += 1;
+        values.sum += value;
+        values.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str) {
+        let values = self.values.lock().unwrap();
+
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&values.buckets) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        cumulative += values.buckets[self.bounds.len()];
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative);
+        let _ = writeln!(out, "{}_sum {}", name, values.sum);
+        let _ = writeln!(out, "{}_count {}", name, values.count);
+    }
+
+    fn otlp(&self, name: &str, description: &str, times: &Timestamps) -> Value {
+        let values = self.values.lock().unwrap();
+        otlp::histogram(name, description, self.bounds, (&values.buckets, values.sum, values.count), times)
+    }
+}
+
+// Labels of an HTTP request counter
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct RequestLabels {
+    method: String,
+    route: String,
+    status: u16,
+}
+
+// Labels of an ingest counter: which dataset, and whether the rows came through `/collate`, `/collate_wide`,
+// `/upsert`, or `/aggregate`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct IngestLabels {
+    dataset: String,
+    endpoint: &'static str,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct IngestCounts {
+    payloads: u64,
+    rows: u64,
+}
+
+// What was ingested during one second of the recent window
+#[derive(Debug, Default, Clone, Copy)]
+struct RecentSlot {
+    // Seconds since the service started
+    second: u64,
+    payloads: u64,
+    rows: u64,
+    bytes: u64,
+}
+
+// Ingests over the last `RECENT_SECS`, all datasets together
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RecentIngests {
+    // Less than `RECENT_SECS` just after the service started
+    pub window_seconds: f64,
+    pub payloads: u64,
+    pub rows: u64,
+    pub bytes: u64,
+}
+
+// Time spent waiting for a dataset's write lock
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LockWaits {
+    pub count: u64,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct SinkCounts {
+    rows: u64,
+    dropped_rows: u64,
+}
+
+// Everything `/metrics` reports that isn't read from the datasets or the writer at scrape time
+#[derive(Debug)]
+pub struct Metrics {
+    started: Instant,
+    requests: Mutex<BTreeMap<RequestLabels, u64>>,
+    request_seconds: Histogram,
+    ingested: Mutex<BTreeMap<IngestLabels, IngestCounts>>,
+    payload_bytes: Histogram,
+    // Ingests of the last minute, a slot per second (indexed by second, modulo `RECENT_SECS`)
+    recent: Mutex<Vec<RecentSlot>>,
+    lock_wait_seconds: Histogram,
+    lock_waits: Mutex<BTreeMap<String, LockWaits>>,
+    // Kept here rather than in the writer's status so the latency distribution is available, not just the last one
+    pub flush_seconds: Histogram,
+    pub flush_errors: AtomicU64,
+    // Rows mirrored to (or dropped by) each external sink, e.g. `postgres`
+    sinks: Mutex<BTreeMap<&'static str, SinkCounts>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            started: Instant::now(),
+            requests: Mutex::new(BTreeMap::new()),
+            request_seconds: Histogram::new(LATENCY_BUCKETS),
+            ingested: Mutex::new(BTreeMap::new()),
+            payload_bytes: Histogram::new(PAYLOAD_BUCKETS),
+            recent: Mutex::new(vec![RecentSlot::default(); RECENT_SECS as usize]),
+            lock_wait_seconds: Histogram::new(LATENCY_BUCKETS),
+            lock_waits: Mutex::new(BTreeMap::new()),
+            flush_seconds: Histogram::new(LATENCY_BUCKETS),
+            flush_errors: AtomicU64::new(0),
+            sinks: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+// Per-dataset gauges, read at scrape time
+#[derive(Debug)]
+pub struct DatasetGauges {
+    pub name: String,
+    pub rows: usize,
+    pub columns: usize,
+    pub estimated_bytes: usize,
+    // Rows spilled to disk to keep within `storage.memory_budget_bytes`, and the size of their files
+    pub spilled_rows: usize,
+    pub spilled_bytes: usize,
+}
+
+impl Metrics {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Metrics::default())
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    fn record_request(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        let labels = RequestLabels {
+            method: method.to_string(),
+            route: route.to_string(),
+            status,
+        };
+        *self.requests.lock().unwrap().entry(labels).or_default() += 1;
+        self.request_seconds.observe(elapsed.as_secs_f64());
+    }
+
+    // Count an accepted payload. `bytes` is the size of the request body as received.
+    pub fn record_ingest(&self, dataset: &str, endpoint: &'static str, rows: usize, bytes: usize) {
+        let labels = IngestLabels {
+            dataset: dataset.to_string(),
+            endpoint,
+        };
+        let mut ingested = self.ingested.lock().unwrap();
+        let counts = ingested.entry(labels).or_default();
+        counts.payloads += 1;
+        counts.rows += rows as u64;
+        drop(ingested);
+
+        self.payload_bytes.observe(bytes as f64);
+
+        let second = self.started.elapsed().as_secs();
+        let mut recent = self.recent.lock().unwrap();
+        let slot = &mut recent[(second % RECENT_SECS) as usize];
+        if slot.second != second {
+            *slot = RecentSlot {
+                second,
+                ..Default::default()
+            };
+        }
+        slot.payloads += 1;
+        slot.rows += rows as u64;
+        slot.bytes += bytes as u64;
+        drop(recent);
+
+        // And in the log line and the client's account of the request that sent it, if any
+        logging::count_ingested(rows);
+        accounting::count(rows, bytes);
+    }
+
+    // How many payloads a dataset has accepted since the service started, through any endpoint
+    pub fn payloads(&self, dataset: &str) -> u64 {
+        let ingested = self.ingested.lock().unwrap();
+        ingested.iter().filter(|(labels, _)| labels.dataset == dataset).map(|(_, counts)| counts.payloads).sum()
+    }
+
+    // What was ingested over the last minute (or since the service started, if that was more recent)
+    pub fn recent_ingests(&self) -> RecentIngests {
+        let elapsed = self.started.elapsed();
+        let second = elapsed.as_secs();
+        let recent = self.recent.lock().unwrap();
+        let mut ingests = RecentIngests {
+            window_seconds: elapsed.as_secs_f64().min(RECENT_SECS as f64),
+            ..Default::default()
+        };
+        for slot in recent.iter().filter(|slot| second.saturating_sub(slot.second) < RECENT_SECS) {
+            ingests.payloads += slot.payloads;
+            ingests.rows += slot.rows;
+            ingests.bytes += slot.bytes;
+        }
+        ingests
+    }
+
+    // Count a wait for a dataset's write lock
+    pub fn record_lock_wait(&self, dataset: &str, waited: Duration) {
+        self.lock_wait_seconds.observe(waited.as_secs_f64());
+
+        let mut lock_waits = self.lock_waits.lock().unwrap();
+        let waits = lock_waits.entry(dataset.to_string()).or_default();
+        waits.count += 1;
+        waits.total += waited;
+        waits.max = waits.max.max(waited);
+    }
+
+    // Every dataset's lock waits so far
+    pub fn lock_waits(&self) -> BTreeMap<String, LockWaits> {
+        self.lock_waits.lock().unwrap().clone()
+    }
+
+    // Count rows a sink has delivered, or given up on
+    pub fn record_sink(&self, sink: &'static str, rows: usize, dropped_rows: usize) {
+        let mut sinks = self.sinks.lock().unwrap();
+        let counts = sinks.entry(sink).or_default();
+        counts.rows += rows as u64;
+        counts.dropped_rows += dropped_rows as u64;
+    }
+
+    // Render every metric in the Prometheus text exposition format
+    pub fn render(&self, datasets: &[DatasetGauges], pending_rows: usize) -> String {
+        let mut out = String::new();
+
+        header(&mut out, "data_collator_uptime_seconds", "gauge", "Seconds since the service started");
+        let _ = writeln!(out, "data_collator_uptime_seconds {}", self.started.elapsed().as_secs_f64());
+
+        header(&mut out, "data_collator_http_requests_total", "counter", "HTTP requests by method, route and status");
+        let requests = self.requests.lock().unwrap().clone();
+        for (labels, count) in &requests {
+            let _ = writeln!(
+                out,
+                "data_collator_http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                escape(&labels.method),
+                escape(&labels.route),
+                labels.status,
+                count
+            );
+        }
+
+        header(&mut out, "data_collator_http_errors_total", "counter", "HTTP responses with a 4xx or 5xx status");
+        let mut errors: BTreeMap<u16, u64> = BTreeMap::new();
+        for (labels, count) in &requests {
+            if labels.status >= 400 {
+                *errors.entry(labels.status).or_default() += count;
+            }
+        }
+        for (status, count) in errors {
+            let _ = writeln!(out, "data_collator_http_errors_total{{status=\"{}\"}} {}", status, count);
+        }
+
+        header(&mut out, "data_collator_http_request_duration_seconds", "histogram", "HTTP request latency");
+        self.request_seconds.render(&mut out, "data_collator_http_request_duration_seconds");
+
+        let ingested = self.ingested.lock().unwrap().clone();
+        header(&mut out, "data_collator_ingested_payloads_total", "counter", "Payloads accepted, by dataset and endpoint");
+        for (labels, counts) in &ingested {
+            let _ = writeln!(
+                out,
+                "data_collator_ingested_payloads_total{{{}}} {}",
+                ingest_labels(labels),
+                counts.payloads
+            );
+        }
+        header(
+            &mut out,
+            "data_collator_ingested_rows_total",
+            "counter",
+            "Rows accepted, by dataset and endpoint (use rate() for rows per second)",
+        );
+        for (labels, counts) in &ingested {
+            let _ = writeln!(out, "data_collator_ingested_rows_total{{{}}} {}", ingest_labels(labels), counts.rows);
+        }
+
+        header(&mut out, "data_collator_payload_bytes", "histogram", "Size of accepted request bodies");
+        self.payload_bytes.render(&mut out, "data_collator_payload_bytes");
+
+        header(&mut out, "data_collator_dataset_rows", "gauge", "Rows currently held by each dataset");
+        for dataset in datasets {
+            let _ = writeln!(out, "data_collator_dataset_rows{{dataset=\"{}\"}} {}", escape(&dataset.name), dataset.rows);
+        }
+        header(
+            &mut out,
+            "data_collator_dataset_estimated_bytes",
+            "gauge",
+            "Estimated in-memory size of each dataset's DataFrame",
+        );
+        for dataset in datasets {
+            let _ = writeln!(
+                out,
+                "data_collator_dataset_estimated_bytes{{dataset=\"{}\"}} {}",
+                escape(&dataset.name),
+                dataset.estimated_bytes
+            );
+        }
+        header(
+            &mut out,
+            "data_collator_dataset_spilled_bytes",
+            "gauge",
+            "Size of the files each dataset's spilled rows are in",
+        );
+        for dataset in datasets {
+            let _ = writeln!(
+                out,
+                "data_collator_dataset_spilled_bytes{{dataset=\"{}\"}} {}",
+                escape(&dataset.name),
+                dataset.spilled_bytes
+            );
+        }
+
+        header(&mut out, "data_collator_lock_wait_seconds", "histogram", "Time ingests waited for a dataset's lock");
+        self.lock_wait_seconds.render(&mut out, "data_collator_lock_wait_seconds");
+
+        header(&mut out, "data_collator_flush_duration_seconds", "histogram", "Time taken to write pending data to disk");
+        self.flush_seconds.render(&mut out, "data_collator_flush_duration_seconds");
+        header(&mut out, "data_collator_flush_errors_total", "counter", "Flushes that failed to write an output file");
+        let _ = writeln!(out, "data_collator_flush_errors_total {}", self.flush_errors.load(Ordering::Relaxed));
+        header(&mut out, "data_collator_pending_rows", "gauge", "Rows waiting to be written to output files");
+        let _ = writeln!(out, "data_collator_pending_rows {}", pending_rows);
+
+        let sinks = self.sinks.lock().unwrap().clone();
+        header(&mut out, "data_collator_sink_rows_total", "counter", "Rows mirrored to each external sink");
+        for (sink, counts) in &sinks {
+            let _ = writeln!(out, "data_collator_sink_rows_total{{sink=\"{}\"}} {}", sink, counts.rows);
+        }
+        header(
+            &mut out,
+            "data_collator_sink_dropped_rows_total",
+            "counter",
+            "Rows a sink gave up on, after retrying or because its queue was full",
+        );
+        for (sink, counts) in &sinks {
+            let _ = writeln!(out, "data_collator_sink_dropped_rows_total{{sink=\"{}\"}} {}", sink, counts.dropped_rows);
+        }
+
+        out
+    }
+
+    // The same metrics as `render`, as OTLP metrics (see `otlp`)
+    pub fn otlp(&self, datasets: &[DatasetGauges], pending_rows: usize) -> Vec<Value> {
+        let times = Timestamps::since(self.started.elapsed());
+        let requests = self.requests.lock().unwrap().clone();
+        let ingested = self.ingested.lock().unwrap().clone();
+        let sinks = self.sinks.lock().unwrap().clone();
+        let ingest_labels = |labels: &IngestLabels| -> Labels {
+            vec![("dataset", labels.dataset.clone()), ("endpoint", labels.endpoint.to_string())]
+        };
+        let dataset_gauge = |value: fn(&DatasetGauges) -> usize| -> Vec<(Labels, f64)> {
+            datasets.iter().map(|dataset| (vec![("dataset", dataset.name.clone())], value(dataset) as f64)).collect()
+        };
+
+        vec![
+            otlp::gauge(
+                "data_collator_uptime_seconds",
+                "Seconds since the service started",
+                vec![(Vec::new(), self.started.elapsed().as_secs_f64())],
+                &times,
+            ),
+            otlp::counter(
+                "data_collator_http_requests_total",
+                "HTTP requests by method, route and status",
+                requests
+                    .iter()
+                    .map(|(labels, count)| {
+                        let labels = vec![
+                            ("method", labels.method.clone()),
+                            ("route", labels.route.clone()),
+                            ("status", labels.status.to_string()),
+                        ];
+                        (labels, *count)
+                    })
+                    .collect(),
+                &times,
+            ),
+            self.request_seconds.otlp("data_collator_http_request_duration_seconds", "HTTP request latency", &times),
+            otlp::counter(
+                "data_collator_ingested_payloads_total",
+                "Payloads accepted, by dataset and endpoint",
+                ingested.iter().map(|(labels, counts)| (ingest_labels(labels), counts.payloads)).collect(),
+                &times,
+            ),
+            otlp::counter(
+                "data_collator_ingested_rows_total",
+                "Rows accepted, by dataset and endpoint",
+                ingested.iter().map(|(labels, counts)| (ingest_labels(labels), counts.rows)).collect(),
+                &times,
+            ),
+            self.payload_bytes.otlp("data_collator_payload_bytes", "Size of accepted request bodies", &times),
+            otlp::gauge(
+                "data_collator_dataset_rows",
+                "Rows currently held by each dataset",
+                dataset_gauge(|dataset| dataset.rows),
+                &times,
+            ),
+            otlp::gauge(
+                "data_collator_dataset_estimated_bytes",
+                "Estimated in-memory size of each dataset's DataFrame",
+                dataset_gauge(|dataset| dataset.estimated_bytes),
+                &times,
+            ),
+            otlp::gauge(
+                "data_collator_dataset_spilled_bytes",
+                "Size of the files each dataset's spilled rows are in",
+                dataset_gauge(|dataset| dataset.spilled_bytes),
+                &times,
+            ),
+            self.lock_wait_seconds.otlp(
+                "data_collator_lock_wait_seconds",
+                "Time ingests waited for a dataset's lock",
+                &times,
+            ),
+            self.flush_seconds.otlp(
+                "data_collator_flush_duration_seconds",
+                "Time taken to write pending data to disk",
+                &times,
+            ),
+            otlp::counter(
+                "data_collator_flush_errors_total",
+                "Flushes that failed to write an output file",
+                vec![(Vec::new(), self.flush_errors.load(Ordering::Relaxed))],
+                &times,
+            ),
+            otlp::gauge(
+                "data_collator_pending_rows",
+                "Rows waiting to be written to output files",
+                vec![(Vec::new(), pending_rows as f64)],
+                &times,
+            ),
+            otlp::counter(
+                "data_collator_sink_rows_total",
+                "Rows mirrored to each external sink",
+                sinks.iter().map(|(sink, counts)| (vec![("sink", sink.to_string())], counts.rows)).collect(),
+                &times,
+            ),
+            otlp::counter(
+                "data_collator_sink_dropped_rows_total",
+                "Rows a sink gave up on, after retrying or because its queue was full",
+                sinks.iter().map(|(sink, counts)| (vec![("sink", sink.to_string())], counts.dropped_rows)).collect(),
+                &times,
+            ),
+        ]
+    }
+}
+
+// Every dataset's gauges, read now
+pub async fn dataset_gauges(state: &AppState) -> Vec<DatasetGauges> {
+    let mut datasets = Vec::new();
+    for (name, dataset) in state.all_datasets().await {
+        let dataset = dataset.read().await;
+        let (rows, columns, estimated_bytes) =
+            dataset.df.as_ref().map_or((0, 0, 0), |df| (df.height(), df.width(), df.estimated_size()));
+        datasets.push(DatasetGauges {
+            name,
+            rows,
+            columns,
+            estimated_bytes,
+            spilled_rows: dataset.spilled.rows(),
+            spilled_bytes: dataset.spilled.bytes() as usize,
+        });
+    }
+    datasets
+}
+
+// Middleware counting every request by method, matched route (so `/datasets/{name}` stays one series) and status
+pub async fn track_requests(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| String::from("unmatched"), |path| path.as_str().to_string());
+
+    let started = Instant::now();
+    let response = next.run(request).await;
+    state.metrics.record_request(&method, &route, response.status().as_u16(), started.elapsed());
+
+    response
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn ingest_labels(labels: &IngestLabels) -> String {
+    format!("dataset=\"{}\",endpoint=\"{}\"", escape(&labels.dataset), labels.endpoint)
+}
+
+// Escape a label value (backslashes, quotes and newlines)
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,114 @@
+use std::fmt;
+
+use indexmap::IndexMap;
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::aggregate::one_or_many;
+
+// How `/fill_nulls` fills a column's missing values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NullFill {
+    // 0 (numeric columns only)
+    Zero,
+    // The mean of the column's other values (numeric columns only; truncated for integer columns)
+    Mean,
+    // The last value before it
+    Forward,
+}
+
+impl fmt::Display for NullFill {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            NullFill::Zero => "zero",
+            NullFill::Mean => "mean",
+            NullFill::Forward => "forward",
+        })
+    }
+}
+
+// Body of a `/fill_nulls` request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FillSpec {
+    // Used for every column not in `columns`. Columns it doesn't apply to (`zero` and `mean` for text columns) are
+    // left alone.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strategy: Option<NullFill>,
+    // Strategy per column
+    #[serde(default)]
+    pub columns: IndexMap<String, NullFill>,
+    // Fill each value of these columns on its own, e.g. with the mean of that host's rows
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub keys: Vec<String>,
+}
+
+// `df` with its nulls filled in, plus how many values that was. Every column keeps its dtype, and key columns are
+// never filled.
+pub fn fill_nulls(df: &DataFrame, spec: &FillSpec) -> Result<(DataFrame, usize), String> {
+    if spec.strategy.is_none() && spec.columns.is_empty() {
+        return Err(String::from("Pick a `strategy` for every column, or one per column in `columns`"));
+    }
+    let schema = df.schema();
+    if let Some(column) = spec.columns.keys().chain(&spec.keys).find(|column| !schema.contains(column)) {
+        return Err(format!("Column {:?} doesn't exist", column));
+    }
+    if let Some(column) = spec.columns.keys().find(|column| spec.keys.contains(column)) {
+        return Err(format!("Column {:?} can't be both filled and a key", column));
+    }
+
+    let keys: Vec<Expr> = spec.keys.iter().map(|key| col(key.as_str())).collect();
+    let mut filled = Vec::new();
+    let mut fills = Vec::new();
+    for (name, dtype) in schema.iter() {
+        if spec.keys.iter().any(|key| key == name) {
+            continue;
+        }
+        let (strategy, explicit) = match (spec.columns.get(name.as_str()), spec.strategy) {
+            (Some(strategy), _) => (*strategy, true),
+            (None, Some(strategy)) => (strategy, false),
+            (None, None) => continue,
+        };
+        if strategy != NullFill::Forward && !dtype.is_primitive_numeric() {
+            if explicit {
+                return Err(format!("Column {:?} is {}, so it can't be filled with {}", name, dtype, strategy));
+            }
+            continue;
+        }
+
+        let column = col(name.clone());
+        let fill = match strategy {
+            NullFill::Zero => column.fill_null(lit(0)),
+            NullFill::Mean => column.clone().fill_null(column.mean()),
+            NullFill::Forward => column.forward_fill(None),
+        };
+        // Never fill across keys
+        let fill = if keys.is_empty() || strategy == NullFill::Zero { fill } else { fill.over(keys.clone()) };
+        fills.push(fill.cast(dtype.clone()).alias(name.clone()));
+        filled.push(name.clone());
+    }
+
+    let before = null_count(df, &filled);
+    let df = df.clone().lazy().with_columns(fills).collect().map_err(|e| e.to_string())?;
+    let after = null_count(&df, &filled);
+
+    Ok((df, before - after))
+}
+
+// `df` without the rows that have a null in any `subset` column (any column at all, without a subset), plus how many
+// rows that was. The rows kept stay in order.
+pub fn drop_nulls(df: &DataFrame, subset: Option<&[String]>) -> Result<(DataFrame, usize), String> {
+    if let Some(column) = subset.into_iter().flatten().find(|column| df.column(column).is_err()) {
+        return Err(format!("Column {:?} in the subset doesn't exist", column));
+    }
+
+    let kept = df.drop_nulls(subset).map_err(|e| e.to_string())?;
+    let removed = df.height() - kept.height();
+
+    Ok((kept, removed))
+}
+
+fn null_count(df: &DataFrame, columns: &[PlSmallStr]) -> usize {
+    columns.iter().filter_map(|name| df.column(name).ok()).map(Column::null_count).sum()
+}
@@ -0,0 +1,1269 @@
+// The OpenAPI description of the HTTP API, served at `/openapi.json` (and browsable at `/docs`). There's no utoipa in
+// this build to derive it from the handlers, so it's generated from the table of endpoints below, which has to be kept
+// in step with `api::router` by hand: a route added there needs an entry here.
+
+use serde_json::{json, Map, Value};
+
+use crate::auth::PUBLIC_PATHS;
+
+// The Swagger UI page for `/docs`. Its scripts come from a CDN, so the service doesn't have to bundle them; the spec is
+// fetched next to the page, so it also works when the API is nested under a prefix.
+pub const SWAGGER_UI: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <title>data_collator API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>
+    SwaggerUIBundle({ url: new URL("openapi.json", window.location.href).href, dom_id: "#swagger-ui" });
+  </script>
+</body>
+</html>
+"##;
+
+// What an endpoint takes as its body
+#[derive(Clone, Copy)]
+enum Body {
+    None,
+    // A payload in any format `/collate` reads: CSV, JSON records, NDJSON, or Arrow IPC
+    Payload,
+    // CSV, or a JSON `AggregateRequest`
+    Aggregate,
+    // NDJSON, one `BatchPayload` per line
+    Batch,
+    LineProtocol,
+    // A Snappy-compressed protobuf `WriteRequest`
+    RemoteWrite,
+    // Write-ahead log records, as a primary sends them to its replicas
+    Replication,
+    // A JSON body, by the name of its schema
+    Json(&'static str),
+}
+
+// What an endpoint responds with when it succeeds
+#[derive(Clone, Copy)]
+enum Reply {
+    // A JSON body, by the name of its schema
+    Json(&'static str),
+    // A JSON body by default, or the rows alone in another format (see Response Formats in the README)
+    Negotiated(&'static str),
+    NoContent,
+    // A whole dataset as a file
+    File,
+    // A WebSocket upgrade
+    Socket,
+    // Server-sent events
+    Events,
+    // Prometheus text exposition
+    Metrics,
+    // An HTML page
+    Page,
+    // A probe's checks, with a `503` when any failed
+    Probe,
+}
+
+struct Endpoint {
+    method: &'static str,
+    path: &'static str,
+    // Whether it's also served for named datasets, under `/datasets/{name}`
+    per_dataset: bool,
+    summary: &'static str,
+    // Query (and header) parameters, by their key in `PARAMETERS`
+    params: &'static [&'static str],
+    body: Body,
+    reply: Reply,
+}
+
+const fn endpoint(
+    method: &'static str,
+    path: &'static str,
+    per_dataset: bool,
+    summary: &'static str,
+    params: &'static [&'static str],
+    body: Body,
+    reply: Reply,
+) -> Endpoint {
+    Endpoint {
+        method,
+        path,
+        per_dataset,
+        summary,
+        params,
+        body,
+        reply,
+    }
+}
+
+const INGEST: &[&str] = &["concat", "X-Concat-Mode", "Idempotency-Key"];
+const COLLATE: &[&str] = &["concat", "X-Concat-Mode", "Idempotency-Key", "Prefer"];
+const UPSERT: &[&str] = &["keys", "concat", "X-Concat-Mode", "Idempotency-Key"];
+const AGGREGATE: &[&str] =
+    &["op", "X-Aggregate-Op", "keys", "window", "slide", "time", "filter", "lookup", "Idempotency-Key", "Prefer"];
+
+// Every route of `api::router`, in the same order
+const ENDPOINTS: &[Endpoint] = &[
+    endpoint("get", "/", false, "Check that the service is running", &[], Body::None, Reply::Json("Operational")),
+    endpoint("get", "/healthz", false, "Check that the process is up", &[], Body::None, Reply::Json("Operational")),
+    endpoint("get", "/livez", false, "Check that the service can still make progress", &[], Body::None, Reply::Probe),
+    endpoint("get", "/readyz", false, "Check that the service can take traffic", &[], Body::None, Reply::Probe),
+    endpoint(
+        "post",
+        "/collate",
+        true,
+        "Add rows to the dataset",
+        COLLATE,
+        Body::Payload,
+        Reply::Negotiated("CollateResponse"),
+    ),
+    endpoint(
+        "post",
+        "/collate/batch",
+        true,
+        "Collate many payloads in one request, all or nothing",
+        INGEST,
+        Body::Batch,
+        Reply::Json("BatchResponse"),
+    ),
+    endpoint(
+        "post",
+        "/write",
+        false,
+        "Collate InfluxDB line protocol into the dataset named by `db` or `bucket`",
+        &["db", "bucket", "precision"],
+        Body::LineProtocol,
+        Reply::NoContent,
+    ),
+    endpoint(
+        "post",
+        "/api/v2/write",
+        false,
+        "Collate InfluxDB line protocol, where InfluxDB 2.x clients write it",
+        &["db", "bucket", "precision"],
+        Body::LineProtocol,
+        Reply::NoContent,
+    ),
+    endpoint(
+        "post",
+        "/api/v1/write",
+        true,
+        "Collate a Prometheus remote write request",
+        &[],
+        Body::RemoteWrite,
+        Reply::NoContent,
+    ),
+    endpoint(
+        "post",
+        "/aggregate",
+        true,
+        "Aggregate rows into the dataset",
+        AGGREGATE,
+        Body::Aggregate,
+        Reply::Negotiated("CollateResponse"),
+    ),
+    endpoint(
+        "post",
+        "/upsert",
+        true,
+        "Add rows, replacing existing rows with the same keys",
+        UPSERT,
+        Body::Payload,
+        Reply::Negotiated("CollateResponse"),
+    ),
+    endpoint(
+        "post",
+        "/collate_wide",
+        true,
+        "Join the payload's columns onto existing rows with the same keys",
+        UPSERT,
+        Body::Payload,
+        Reply::Negotiated("CollateResponse"),
+    ),
+    endpoint(
+        "get",
+        "/data",
+        true,
+        "Read (part of) the dataset",
+        &["columns", "offset", "limit", "filter", "sort", "lookup", "format", "as_of"],
+        Body::None,
+        Reply::Negotiated("DataResponse"),
+    ),
+    endpoint(
+        "delete",
+        "/data",
+        true,
+        "Delete the rows matching a filter",
+        &["filter"],
+        Body::None,
+        Reply::Json("DeleteResponse"),
+    ),
+    endpoint(
+        "post",
+        "/reset",
+        true,
+        "Delete every row of the dataset",
+        &["rotate"],
+        Body::None,
+        Reply::Json("ResetResponse"),
+    ),
+    endpoint(
+        "post",
+        "/dedup",
+        true,
+        "Drop duplicate rows",
+        &["subset", "keep"],
+        Body::None,
+        Reply::Json("RemovedResponse"),
+    ),
+    endpoint(
+        "post",
+        "/fill_nulls",
+        true,
+        "Fill in missing values",
+        &[],
+        Body::Json("FillSpec"),
+        Reply::Json("FilledResponse"),
+    ),
+    endpoint(
+        "post",
+        "/drop_nulls",
+        true,
+        "Drop rows with missing values",
+        &["subset"],
+        Body::None,
+        Reply::Json("RemovedResponse"),
+    ),
+    endpoint(
+        "get",
+        "/nulls",
+        true,
+        "Count the missing values of each column",
+        &[],
+        Body::None,
+        Reply::Json("NullsResponse"),
+    ),
+    endpoint(
+        "get",
+        "/outliers",
+        true,
+        "Return the rows that are outliers",
+        &["columns", "keys", "method", "threshold"],
+        Body::None,
+        Reply::Negotiated("RowsResponse"),
+    ),
+    endpoint(
+        "post",
+        "/outliers",
+        true,
+        "Flag every row with whether it's an outlier",
+        &["columns", "keys", "method", "threshold"],
+        Body::None,
+        Reply::Json("OutliersResponse"),
+    ),
+    endpoint(
+        "get",
+        "/describe",
+        true,
+        "Summarize every numeric column",
+        &["quantiles"],
+        Body::None,
+        Reply::Negotiated("DescribeResponse"),
+    ),
+    endpoint(
+        "get",
+        "/value_counts",
+        true,
+        "Find the most frequent values of a column",
+        &["column", "k"],
+        Body::None,
+        Reply::Negotiated("ValueCountsResponse"),
+    ),
+    endpoint(
+        "get",
+        "/top",
+        true,
+        "Find the rows (or groups) with the highest values of a column",
+        &["by", "k", "group", "op", "order"],
+        Body::None,
+        Reply::Negotiated("TopResponse"),
+    ),
+    endpoint(
+        "get",
+        "/distinct",
+        true,
+        "Count the distinct values of columns (or of combinations of them)",
+        &["distinct_columns", "combined", "values", "values_limit"],
+        Body::None,
+        Reply::Json("DistinctResponse"),
+    ),
+    endpoint(
+        "post",
+        "/pivot",
+        true,
+        "Return the dataset pivoted wide",
+        &[],
+        Body::Json("PivotSpec"),
+        Reply::Negotiated("RowsResponse"),
+    ),
+    endpoint(
+        "post",
+        "/melt",
+        true,
+        "Return the dataset melted long",
+        &[],
+        Body::Json("MeltSpec"),
+        Reply::Negotiated("RowsResponse"),
+    ),
+    endpoint(
+        "post",
+        "/resample",
+        true,
+        "Return the dataset on a uniform time grid",
+        &[],
+        Body::Json("ResampleSpec"),
+        Reply::Negotiated("RowsResponse"),
+    ),
+    endpoint(
+        "post",
+        "/rolling",
+        true,
+        "Return the dataset with rolling statistics added",
+        &[],
+        Body::Json("RollingSpec"),
+        Reply::Negotiated("RowsResponse"),
+    ),
+    endpoint("get", "/export", true, "Download the dataset as a file", &["export_format"], Body::None, Reply::File),
+    endpoint("post", "/export/s3", true, "Export the dataset to S3", &[], Body::None, Reply::Json("S3ExportResponse")),
+    endpoint(
+        "get",
+        "/ws",
+        true,
+        "Collate (or aggregate) messages sent over a WebSocket, and receive the dataset's updates",
+        &["ingest", "updates", "op", "keys", "window", "slide", "time", "filter"],
+        Body::None,
+        Reply::Socket,
+    ),
+    endpoint(
+        "get",
+        "/events",
+        false,
+        "Receive every dataset's changes as server-sent events",
+        &["dataset", "rows"],
+        Body::None,
+        Reply::Events,
+    ),
+    endpoint(
+        "get",
+        "/lookup/{name}",
+        false,
+        "Read a lookup table",
+        &[],
+        Body::None,
+        Reply::Negotiated("LookupResponse"),
+    ),
+    endpoint(
+        "put",
+        "/lookup/{name}",
+        false,
+        "Load (or replace) a lookup table",
+        &["key"],
+        Body::Payload,
+        Reply::Json("LookupLoadedResponse"),
+    ),
+    endpoint(
+        "delete",
+        "/lookup/{name}",
+        false,
+        "Remove a lookup table",
+        &[],
+        Body::None,
+        Reply::Json("LookupDeletedResponse"),
+    ),
+    endpoint("get", "/lookups", false, "List the lookup tables", &[], Body::None, Reply::Json("LookupsResponse")),
+    endpoint("get", "/datasets", false, "List the datasets", &[], Body::None, Reply::Json("DatasetsResponse")),
+    endpoint(
+        "get",
+        "/datasets/{name}",
+        false,
+        "Read a named dataset as CSV",
+        &[],
+        Body::None,
+        Reply::Json("DatasetResponse"),
+    ),
+    endpoint(
+        "delete",
+        "/datasets/{name}",
+        false,
+        "Delete a named dataset",
+        &[],
+        Body::None,
+        Reply::Json("DatasetDeletedResponse"),
+    ),
+    endpoint(
+        "get",
+        "/quarantine",
+        true,
+        "Read the rows that broke the validation rules",
+        &[],
+        Body::None,
+        Reply::Negotiated("QuarantineResponse"),
+    ),
+    endpoint(
+        "delete",
+        "/quarantine",
+        true,
+        "Delete the quarantined rows",
+        &[],
+        Body::None,
+        Reply::Json("QuarantineClearedResponse"),
+    ),
+    endpoint(
+        "get",
+        "/snapshots",
+        true,
+        "List the dataset's snapshots",
+        &[],
+        Body::None,
+        Reply::Json("SnapshotsResponse"),
+    ),
+    endpoint(
+        "post",
+        "/snapshots",
+        true,
+        "Save the dataset's current state",
+        &["snapshot_name"],
+        Body::None,
+        Reply::Json("SnapshotResponse"),
+    ),
+    endpoint(
+        "post",
+        "/snapshots/{id}/restore",
+        true,
+        "Restore the dataset from a snapshot",
+        &[],
+        Body::None,
+        Reply::Json("RestoreResponse"),
+    ),
+    endpoint(
+        "get",
+        "/versions",
+        true,
+        "List the dataset's versions, rebuilt from the write-ahead log",
+        &[],
+        Body::None,
+        Reply::Json("VersionsResponse"),
+    ),
+    endpoint(
+        "get",
+        "/diff",
+        true,
+        "Compare two versions of the dataset",
+        &["from_version", "to_version"],
+        Body::None,
+        Reply::Json("DiffResponse"),
+    ),
+    endpoint(
+        "get",
+        "/schema",
+        true,
+        "Describe the dataset's columns and declared schema",
+        &[],
+        Body::None,
+        Reply::Json("SchemaResponse"),
+    ),
+    endpoint(
+        "put",
+        "/schema",
+        true,
+        "Declare the dataset's schema",
+        &[],
+        Body::Json("DatasetSchema"),
+        Reply::Json("SchemaSetResponse"),
+    ),
+    endpoint("delete", "/schema", true, "Remove the declared schema", &[], Body::None, Reply::Json("StatusResponse")),
+    endpoint(
+        "get",
+        "/columns/computed",
+        true,
+        "Read the dataset's computed columns",
+        &[],
+        Body::None,
+        Reply::Json("ComputedResponse"),
+    ),
+    endpoint(
+        "put",
+        "/columns/computed",
+        true,
+        "Set the dataset's computed columns",
+        &[],
+        Body::Json("ComputedColumns"),
+        Reply::Json("ComputedResponse"),
+    ),
+    endpoint(
+        "delete",
+        "/columns/computed",
+        true,
+        "Remove the computed columns",
+        &[],
+        Body::None,
+        Reply::Json("ComputedResponse"),
+    ),
+    endpoint(
+        "get",
+        "/expected",
+        true,
+        "Report which of the dataset's expected reporters haven't sent rows yet",
+        &[],
+        Body::None,
+        Reply::Json("CompletenessResponse"),
+    ),
+    endpoint(
+        "put",
+        "/expected",
+        true,
+        "Declare the reporters the dataset expects rows from",
+        &[],
+        Body::Json("Expectation"),
+        Reply::Json("CompletenessResponse"),
+    ),
+    endpoint("delete", "/expected", true, "Stop expecting reporters", &[], Body::None, Reply::Json("StatusResponse")),
+    endpoint(
+        "post",
+        "/await",
+        true,
+        "Wait until the dataset has collated a number of batches (or distinct values), then read it",
+        &["expect", "await_key", "timeout", "format"],
+        Body::None,
+        Reply::Negotiated("AwaitResponse"),
+    ),
+    endpoint(
+        "get",
+        "/flush",
+        false,
+        "Report how far writing to the output files has got",
+        &[],
+        Body::None,
+        Reply::Json("FlushResponse"),
+    ),
+    endpoint(
+        "post",
+        "/flush",
+        false,
+        "Write everything pending to the output files now",
+        &[],
+        Body::None,
+        Reply::Json("FlushResponse"),
+    ),
+    endpoint("get", "/metrics", false, "Prometheus metrics", &[], Body::None, Reply::Metrics),
+    endpoint(
+        "get",
+        "/sources",
+        false,
+        "How much each client has sent, and how much of its quota it has used",
+        &[],
+        Body::None,
+        Reply::Json("SourcesResponse"),
+    ),
+    endpoint("get", "/openapi.json", false, "This description of the API", &[], Body::None, Reply::Json("OpenApi")),
+    endpoint("get", "/docs", false, "Browse this description of the API with Swagger UI", &[], Body::None, Reply::Page),
+    endpoint("get", "/ui", false, "A dashboard of the datasets", &[], Body::None, Reply::Page),
+    endpoint(
+        "get",
+        "/audit",
+        false,
+        "Read back the audit log: every request that changed a dataset, oldest first",
+        &["audit_dataset", "operation", "offset", "limit"],
+        Body::None,
+        Reply::Json("AuditResponse"),
+    ),
+    endpoint(
+        "get",
+        "/admin/stats",
+        false,
+        "Report memory use, the recent ingest rate, lock waits, and the last flush",
+        &[],
+        Body::None,
+        Reply::Json("StatsResponse"),
+    ),
+    endpoint(
+        "get",
+        "/admin/log_level",
+        false,
+        "Report which log lines are written",
+        &[],
+        Body::None,
+        Reply::Json("LogLevelResponse"),
+    ),
+    endpoint(
+        "put",
+        "/admin/log_level",
+        false,
+        "Change which log lines are written, until the next restart or SIGHUP",
+        &[],
+        Body::Json("LogLevelRequest"),
+        Reply::Json("LogLevelResponse"),
+    ),
+    endpoint(
+        "post",
+        "/admin/rollback",
+        false,
+        "Undo a dataset's latest ingests, rewriting its output file",
+        &["batches", "rollback_dataset"],
+        Body::None,
+        Reply::Json("RollbackResponse"),
+    ),
+    endpoint(
+        "get",
+        "/replication/status",
+        false,
+        "This collator's role, and how far behind its replicas (or it, as a replica) are",
+        &[],
+        Body::None,
+        Reply::Json("ReplicationStatusResponse"),
+    ),
+    endpoint(
+        "post",
+        "/replication/apply",
+        false,
+        "Apply changes a primary streams to this replica",
+        &["X-Replication-Stream", "X-Replication-Latest"],
+        Body::Replication,
+        Reply::Json("ReplicationAck"),
+    ),
+    endpoint(
+        "post",
+        "/replication/promote",
+        false,
+        "Make this replica a primary, taking writes",
+        &[],
+        Body::None,
+        Reply::Json("ReplicationStatusResponse"),
+    ),
+];
+
+// Query and header parameters: the key endpoints list them by, where they go, their name, type, and description
+const PARAMETERS: &[(&str, &str, &str, &str, &str)] = &[
+    ("concat", "query", "concat", "string", "`strict` or `union`: whether columns must match the dataset's"),
+    ("X-Concat-Mode", "header", "X-Concat-Mode", "string", "The same as `concat`, which wins if both are given"),
+    ("Idempotency-Key", "header", "Idempotency-Key", "string", "Replays the first response to a retried payload"),
+    ("Prefer", "header", "Prefer", "string", "`return=minimal` leaves the rows out of the response"),
+    ("X-Replication-Stream", "header", "X-Replication-Stream", "string", "The stream the changes are from"),
+    ("X-Replication-Latest", "header", "X-Replication-Latest", "integer", "The primary's latest change"),
+    ("keys", "query", "keys", "string", "Comma-separated key columns, e.g. `run_id,rank`"),
+    ("key", "query", "key", "string", "Comma-separated columns the table is keyed by (its first by default)"),
+    ("op", "query", "op", "string", "sum, mean, min, max, count, median, std, p90, p95, p99, or distinct"),
+    ("X-Aggregate-Op", "header", "X-Aggregate-Op", "string", "The same as `op`, which wins if both are given"),
+    ("window", "query", "window", "string", "Group rows into time windows this long, e.g. `5m` (or `5m,slide=1m`)"),
+    ("slide", "query", "slide", "string", "How often a window starts, for sliding windows"),
+    ("time", "query", "time", "string", "The timestamp column windows are taken from (`timestamp` by default)"),
+    ("filter", "query", "filter", "string", "Conditions rows must meet, e.g. `host=node3,latency_ms>250`"),
+    ("lookup", "query", "lookup", "string", "A lookup table to left-join the returned rows against"),
+    ("columns", "query", "columns", "string", "Comma-separated columns (every one by default)"),
+    ("offset", "query", "offset", "integer", "Index of the first row to return"),
+    ("limit", "query", "limit", "integer", "Maximum number of rows to return"),
+    ("sort", "query", "sort", "string", "Comma-separated columns to sort by, each optionally `:asc` or `:desc`"),
+    ("format", "query", "format", "string", "csv, json, ndjson, or arrow (taken from `Accept` by default)"),
+    ("as_of", "query", "as_of", "string", "Read the rows as they were at this time, e.g. `2024-06-01T12:00:00Z`"),
+    ("export_format", "query", "format", "string", "csv, arrow, or feather (the output format by default)"),
+    ("db", "query", "db", "string", "The dataset to collate the points into (InfluxDB 1.x)"),
+    ("bucket", "query", "bucket", "string", "The dataset to collate the points into (InfluxDB 2.x)"),
+    ("precision", "query", "precision", "string", "ns (the default), us, ms, s, m, or h"),
+    ("rotate", "query", "rotate", "boolean", "Rename the output file rather than remove it"),
+    ("subset", "query", "subset", "string", "Comma-separated columns to compare (every one by default)"),
+    ("keep", "query", "keep", "string", "first (the default), last, or none"),
+    ("method", "query", "method", "string", "mad (the default) or std"),
+    ("threshold", "query", "threshold", "number", "How many deviations from the median a value can be (3 by default)"),
+    ("quantiles", "query", "quantiles", "string", "Comma-separated quantiles, e.g. `0.1,0.5,0.9`"),
+    ("column", "query", "column", "string", "The column to count the values of"),
+    ("k", "query", "k", "integer", "How many to return (10 by default)"),
+    ("by", "query", "by", "string", "The column to rank by"),
+    ("group", "query", "group", "string", "Rank this column's values instead of single rows"),
+    ("order", "query", "order", "string", "desc (the default) or asc"),
+    ("distinct_columns", "query", "columns", "string", "Comma-separated columns to count the distinct values of"),
+    ("combined", "query", "combined", "boolean", "Count combinations of the columns' values, not each column's"),
+    ("values", "query", "values", "boolean", "Also return the distinct values, in order"),
+    ("values_limit", "query", "limit", "integer", "How many values to return per column (1000 by default)"),
+    ("expect", "query", "expect", "integer", "How many batches to wait for (or distinct values, with `key`)"),
+    ("await_key", "query", "key", "string", "Wait for distinct values of this column instead of batches"),
+    ("timeout", "query", "timeout", "string", "How long to wait, e.g. `300s` or `5m` (a minute by default)"),
+    ("ingest", "query", "ingest", "string", "collate (the default) or aggregate"),
+    ("updates", "query", "updates", "boolean", "Whether to send the dataset's updates (true by default)"),
+    ("dataset", "query", "dataset", "string", "Only send this dataset's changes"),
+    ("rows", "query", "rows", "boolean", "Include the changed rows in each event"),
+    ("snapshot_name", "query", "name", "string", "A label for the snapshot, added to its id"),
+    ("from_version", "query", "from", "string", "The version to compare from, e.g. `v3` (by default the one before `to`)"),
+    ("to_version", "query", "to", "string", "The version to compare to, e.g. `v5` (the latest by default)"),
+    ("audit_dataset", "query", "dataset", "string", "Only return the entries for this dataset"),
+    ("operation", "query", "operation", "string", "Only return the entries for this operation, e.g. `collate`"),
+    ("batches", "query", "batches", "integer", "How many of the latest ingests to undo (1 by default)"),
+    ("rollback_dataset", "query", "dataset", "string", "The dataset to undo them in (`default` by default)"),
+];
+
+// Content types a dataset's rows can be returned as, besides JSON
+const ROW_FORMATS: &[(&str, &str)] =
+    &[("text/csv", "string"), ("application/x-ndjson", "string"), ("application/vnd.apache.arrow.stream", "binary")];
+
+// The OpenAPI document
+pub fn spec() -> Value {
+    let mut paths = Map::new();
+    for endpoint in ENDPOINTS {
+        add_operation(&mut paths, endpoint.path, endpoint);
+        if endpoint.per_dataset {
+            add_operation(&mut paths, &format!("/datasets/{{name}}{}", endpoint.path), endpoint);
+        }
+    }
+
+    let parameters: Map<String, Value> = PARAMETERS
+        .iter()
+        .map(|(key, location, name, kind, description)| {
+            let parameter = json!({
+                "name": name,
+                "in": location,
+                "description": description,
+                "schema": {"type": kind},
+            });
+            (key.to_string(), parameter)
+        })
+        .collect();
+
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "data_collator",
+            "description": "Collect and aggregate CSV data over HTTP. Routes that work on the default dataset \
+                            also work on named ones under `/datasets/{name}`.",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        // Relative to where the document is served, so it's right when the API is nested under a prefix too
+        "servers": [{"url": "."}],
+        // Keys are only needed once some are configured
+        "security": [{}, {"bearer": []}],
+        "paths": paths,
+        "components": {
+            "securitySchemes": {"bearer": {"type": "http", "scheme": "bearer"}},
+            "parameters": parameters,
+            "responses": {
+                "Error": {
+                    "description": "The request failed",
+                    "content": {"application/json": {"schema": schema_ref("ErrorResponse")}},
+                },
+            },
+            "schemas": schemas(),
+        },
+    })
+}
+
+fn add_operation(paths: &mut Map<String, Value>, path: &str, endpoint: &Endpoint) {
+    let mut parameters: Vec<Value> = path
+        .split('/')
+        .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+        .map(|name| {
+            let description = match name {
+                "id" => "The snapshot's id",
+                _ if path.starts_with("/lookup/") => "The lookup table's name",
+                _ => "The dataset's name",
+            };
+            json!({"name": name, "in": "path", "required": true, "description": description, "schema": text_schema()})
+        })
+        .collect();
+    parameters.extend(endpoint.params.iter().map(|key| json!({"$ref": format!("#/components/parameters/{}", key)})));
+
+    let mut operation = json!({
+        "summary": endpoint.summary,
+        "parameters": parameters,
+        "responses": responses(endpoint.reply),
+    });
+    if let Some(body) = request_body(endpoint.body) {
+        operation["requestBody"] = body;
+    }
+    if PUBLIC_PATHS.contains(&endpoint.path) {
+        operation["security"] = json!([]);
+    }
+
+    let item = paths.entry(path.to_string()).or_insert_with(|| json!({}));
+    item[endpoint.method] = operation;
+}
+
+fn request_body(body: Body) -> Option<Value> {
+    let text = json!({"schema": text_schema()});
+    let binary = json!({"schema": binary_schema()});
+    let content = match body {
+        Body::None => return None,
+        Body::Payload => json!({
+            "text/csv": text,
+            "application/json": {"schema": {"oneOf": [schema_ref("Record"), schema_ref("Records")]}},
+            "application/x-ndjson": text,
+            "application/vnd.apache.arrow.stream": binary,
+            "application/vnd.apache.arrow.file": binary,
+        }),
+        Body::Aggregate => json!({
+            "text/csv": text,
+            "application/json": {"schema": schema_ref("AggregateRequest")},
+        }),
+        Body::Batch => {
+            let example = r#"{"id": "rank-0", "payload": "run_id,latency\n1,12.5\n"}"#;
+            json!({"application/x-ndjson": {"schema": text_schema(), "example": example}})
+        }
+        Body::LineProtocol => json!({"text/plain": text}),
+        Body::RemoteWrite => json!({"application/x-protobuf": binary}),
+        Body::Replication => json!({"application/vnd.data-collator.wal": binary}),
+        Body::Json(schema) => json!({"application/json": {"schema": schema_ref(schema)}}),
+    };
+    Some(json!({"required": true, "content": content}))
+}
+
+fn responses(reply: Reply) -> Value {
+    let text = json!({"schema": text_schema()});
+    let binary = json!({"schema": binary_schema()});
+    let mut responses = match reply {
+        Reply::Json(schema) => json!({
+            "200": {"description": "Success", "content": {"application/json": {"schema": schema_ref(schema)}}},
+        }),
+        Reply::Negotiated(schema) => {
+            let mut content = Map::new();
+            content.insert(String::from("application/json"), json!({"schema": schema_ref(schema)}));
+            for (content_type, format) in ROW_FORMATS {
+                let schema = if *format == "binary" { binary_schema() } else { text_schema() };
+                content.insert(content_type.to_string(), json!({"schema": schema}));
+            }
+            let description = "Success: JSON, or the rows alone in the format `Accept` asks for";
+            json!({"200": {"description": description, "content": content}})
+        }
+        Reply::NoContent => json!({"204": {"description": "Collated"}}),
+        Reply::File => json!({
+            "200": {
+                "description": "The dataset as a file",
+                "content": {
+                    "text/csv": text,
+                    "application/vnd.apache.arrow.stream": binary,
+                    "application/vnd.apache.arrow.file": binary,
+                },
+            },
+        }),
+        Reply::Socket => json!({"101": {"description": "Switching to the WebSocket protocol"}}),
+        Reply::Events => json!({"200": {"description": "A stream of events", "content": {"text/event-stream": text}}}),
+        Reply::Metrics => json!({"200": {"description": "Metrics", "content": {"text/plain": text}}}),
+        Reply::Page => json!({"200": {"description": "The page", "content": {"text/html": text}}}),
+        Reply::Probe => {
+            let content = json!({"application/json": {"schema": schema_ref("ProbeResponse")}});
+            json!({
+                "200": {"description": "Every check passed", "content": content},
+                "503": {"description": "A check failed", "content": content},
+            })
+        }
+    };
+
+    responses["default"] = json!({"$ref": "#/components/responses/Error"});
+    responses
+}
+
+fn schema_ref(name: &str) -> Value {
+    json!({"$ref": format!("#/components/schemas/{}", name)})
+}
+
+fn text_schema() -> Value {
+    json!({"type": "string"})
+}
+
+fn binary_schema() -> Value {
+    json!({"type": "string", "format": "binary"})
+}
+
+fn array_of(items: Value) -> Value {
+    json!({"type": "array", "items": items})
+}
+
+// An object with any keys, all with values of the given schema
+fn map_of(values: Value) -> Value {
+    json!({"type": "object", "additionalProperties": values})
+}
+
+// An object whose fields aren't spelled out here
+fn free_form(description: &str) -> Value {
+    json!({"type": "object", "description": description, "additionalProperties": true})
+}
+
+// An object schema with the given properties, all but the last `optional` ones required
+fn object(properties: Value, optional: &[&str]) -> Value {
+    let required: Vec<&String> = properties
+        .as_object()
+        .expect("properties are an object")
+        .keys()
+        .filter(|name| !optional.contains(&name.as_str()))
+        .collect();
+    json!({"type": "object", "properties": properties, "required": required})
+}
+
+// A success response with the given fields besides `status`
+fn success(mut properties: Value, optional: &[&str]) -> Value {
+    properties
+        .as_object_mut()
+        .expect("properties are an object")
+        .insert(String::from("status"), json!({"type": "string", "const": "success"}));
+    object(properties, optional)
+}
+
+fn schemas() -> Map<String, Value> {
+    let string = json!({"type": "string"});
+    let count = json!({"type": "integer", "minimum": 0});
+    let nullable_string = json!({"type": ["string", "null"]});
+    let boolean = json!({"type": "boolean"});
+    let number = json!({"type": "number"});
+    let records = schema_ref("Records");
+    let strings = array_of(text_schema());
+    let one_or_many = json!({"oneOf": [text_schema(), array_of(text_schema())]});
+    let counts = ["quarantined", "replaced", "joined", "skipped", "filtered"];
+
+    let schemas = [
+        ("ErrorResponse", object(json!({
+            "status": {"type": "string", "const": "error"},
+            "error": {"type": "string", "description": "bad_request, unauthorized, forbidden, not_found, ..."},
+            "message": string,
+        }), &[])),
+        ("Record", free_form("A row, by column name")),
+        ("Records", array_of(schema_ref("Record"))),
+        ("OpenApi", json!({"type": "object", "description": "An OpenAPI 3.1 document"})),
+        ("Operational", object(json!({"status": {"type": "string", "const": "operational"}}), &[])),
+        ("StatusResponse", success(json!({}), &[])),
+        ("ProbeResponse", object(json!({
+            "status": {"type": "string", "enum": ["operational", "failing"]},
+            "checks": array_of(schema_ref("ProbeCheck")),
+        }), &[])),
+        ("ProbeCheck", object(json!({
+            "name": {"type": "string", "description": "datasets, writer, flush, output, postgres, ..."},
+            "ok": boolean,
+            "message": string,
+        }), &[])),
+        ("CollateResponse", success(json!({
+            "wrote_to_file": {"type": "string", "description": "Where the rows are being persisted, or `no`"},
+            "csv_string": {"type": "string", "description": "The dataset's rows as CSV"},
+            "quarantined": count,
+            "replaced": count,
+            "joined": count,
+            "skipped": count,
+            "filtered": count,
+        }), &counts)),
+        ("BatchResult", object(json!({
+            "index": count,
+            "id": string,
+            "status": {"type": "string", "enum": ["success", "error", "skipped"]},
+            "rows": count,
+            "quarantined": count,
+            "error": string,
+        }), &["id", "rows", "quarantined", "error"])),
+        // A failed batch has `error` and `message` instead of `wrote_to_file` and `rows`
+        ("BatchResponse", object(json!({
+            "status": {"type": "string", "enum": ["success", "error"]},
+            "batches": array_of(schema_ref("BatchResult")),
+            "wrote_to_file": string,
+            "rows": count,
+            "error": string,
+            "message": string,
+            "quarantined": count,
+            "skipped": count,
+        }), &["wrote_to_file", "rows", "error", "message", "quarantined", "skipped"])),
+        ("AggregateRequest", object(json!({
+            "csv": string,
+            "key": string,
+            "keys": strings,
+            "op": string,
+            "ops": {"type": "object", "additionalProperties": {"type": "string"}},
+        }), &["key", "keys", "op", "ops"])),
+        ("DataResponse", success(json!({"total_rows": count, "offset": count, "rows": records}), &[])),
+        ("RowsResponse", success(json!({"columns": strings, "rows": records}), &[])),
+        ("DeleteResponse", success(json!({"deleted": count, "rows": count}), &[])),
+        ("RemovedResponse", success(json!({"removed": count, "rows": count}), &[])),
+        ("FilledResponse", success(json!({"filled": count, "rows": count}), &[])),
+        ("OutliersResponse", success(json!({"outliers": count, "rows": count}), &[])),
+        ("ResetResponse", success(json!({
+            "dataset": string,
+            "rotated_to": nullable_string,
+            "partitions_rotated_to": nullable_string,
+        }), &[])),
+        ("NullsResponse", success(json!({
+            "rows": count,
+            "rows_with_nulls": count,
+            "columns": {"type": "array", "items": object(json!({
+                "name": string,
+                "null_count": count,
+                "null_fraction": {"type": "number"},
+            }), &[])},
+        }), &[])),
+        ("DescribeResponse", success(json!({
+            "rows": count,
+            "columns": {"type": "object", "additionalProperties": map_of(json!({"type": ["number", "null"]}))},
+        }), &[])),
+        ("ValueCountsResponse", success(json!({"column": string, "values": records}), &[])),
+        ("TopResponse", success(json!({"by": string, "rows": records}), &[])),
+        ("DistinctResponse", success(json!({
+            "rows": count,
+            "distinct": {"type": "array", "items": object(json!({
+                "columns": {"type": "array", "items": string},
+                "count": count,
+                "values": records,
+            }), &["values"])},
+        }), &[])),
+        ("FillSpec", object(json!({
+            "strategy": {"type": "string", "enum": ["zero", "mean", "forward"]},
+            "columns": map_of(json!({"type": "string", "enum": ["zero", "mean", "forward"]})),
+            "keys": one_or_many,
+        }), &["strategy", "columns", "keys"])),
+        ("PivotSpec", object(json!({
+            "index": one_or_many,
+            "columns": one_or_many,
+            "on": one_or_many,
+            "values": one_or_many,
+            "agg": string,
+        }), &["columns", "on", "values", "agg"])),
+        ("MeltSpec", object(json!({
+            "index": one_or_many,
+            "values": one_or_many,
+            "on": one_or_many,
+            "variable_name": string,
+            "value_name": string,
+        }), &["index", "values", "on", "variable_name", "value_name"])),
+        ("ResampleSpec", object(json!({
+            "every": string,
+            "time": string,
+            "keys": one_or_many,
+            "agg": string,
+            "fill": {"type": "string", "enum": ["null", "forward", "interpolate"]},
+        }), &["time", "keys", "agg", "fill"])),
+        ("RollingSpec", object(json!({
+            "window": string,
+            "ops": one_or_many,
+            "op": string,
+            "columns": one_or_many,
+            "keys": one_or_many,
+            "time": string,
+        }), &["ops", "op", "columns", "keys", "time"])),
+        ("ExportedObject", object(json!({"key": string, "rows": count, "bytes": count}), &[])),
+        ("S3ExportResponse", success(json!({
+            "dataset": string,
+            "rows": count,
+            "objects": array_of(schema_ref("ExportedObject")),
+        }), &[])),
+        ("LookupResponse", success(json!({"name": string, "keys": strings, "rows": records}), &[])),
+        ("LookupLoadedResponse", success(json!({
+            "name": string,
+            "keys": strings,
+            "rows": count,
+            "replaced": boolean,
+        }), &[])),
+        ("LookupDeletedResponse", success(json!({"name": string}), &[])),
+        ("LookupsResponse", success(json!({"lookups": strings}), &[])),
+        ("DatasetsResponse", success(json!({"datasets": strings}), &[])),
+        ("DatasetResponse", success(json!({
+            "name": string,
+            "rows": count,
+            "output_file": nullable_string,
+            "csv_string": string,
+        }), &[])),
+        ("DatasetDeletedResponse", success(json!({"deleted": string}), &[])),
+        ("QuarantineResponse", success(json!({"rows": count, "quarantine": records}), &[])),
+        ("QuarantineClearedResponse", success(json!({"deleted": count}), &[])),
+        ("SnapshotInfo", object(json!({
+            "id": string,
+            "dataset": string,
+            "name": nullable_string,
+            "created_at": {"type": "string", "format": "date-time"},
+            "rows": count,
+            "schema": {"type": ["object", "null"]},
+            "computed": {"type": ["object", "null"]},
+            "aggregate": {"type": ["object", "null"]},
+        }), &[])),
+        ("SnapshotsResponse", success(json!({"snapshots": array_of(schema_ref("SnapshotInfo"))}), &[])),
+        ("SnapshotResponse", success(json!({"snapshot": schema_ref("SnapshotInfo")}), &[])),
+        ("RestoreResponse", success(json!({"restored": schema_ref("SnapshotInfo")}), &[])),
+        ("VersionInfo", object(json!({
+            "version": count,
+            "at": {"type": "string", "format": "date-time"},
+            "operation": string,
+            "rows": count,
+        }), &["at"])),
+        ("VersionsResponse", success(json!({"dataset": string, "versions": array_of(schema_ref("VersionInfo"))}), &[])),
+        ("DiffResponse", success(json!({
+            "dataset": string,
+            "from": count,
+            "to": count,
+            "added": records,
+            "removed": records,
+            "keys": strings,
+            "changed": array_of(object(json!({
+                "key": {"type": "object"},
+                "from": {"type": "object"},
+                "to": {"type": "object"},
+            }), &[])),
+        }), &["keys"])),
+        ("DatasetSchema", free_form("A declared schema (see PUT /schema in the README)")),
+        ("SchemaResponse", success(json!({
+            "dataset": string,
+            "rows": count,
+            "columns": array_of(object(json!({"name": string, "dtype": string, "null_count": count}), &[])),
+            "declared": {"oneOf": [schema_ref("DatasetSchema"), {"type": "null"}]},
+        }), &[])),
+        ("SchemaSetResponse", success(json!({"schema": schema_ref("DatasetSchema")}), &[])),
+        ("ComputedColumns", free_form("Computed columns (see PUT /columns/computed in the README)")),
+        ("ComputedResponse", success(json!({"computed": schema_ref("ComputedColumns")}), &[])),
+        ("Expectation", object(json!({
+            "column": string,
+            "values": {"type": "array", "items": {"type": ["string", "number"]}},
+            "range": {"type": "array", "items": {"type": "integer"}, "minItems": 2, "maxItems": 2},
+            "webhook": string,
+        }), &["values", "range", "webhook"])),
+        ("CompletenessResponse", success(json!({
+            "dataset": string,
+            "column": string,
+            "expected": count,
+            "reported": count,
+            "unexpected": count,
+            "complete": {"type": "boolean"},
+            "completed_at": string,
+            "missing": {"type": "array", "items": {"type": ["string", "number"]}},
+            "webhook": string,
+        }), &["completed_at", "webhook"])),
+        ("AwaitResponse", success(json!({
+            "dataset": string,
+            "reached": count,
+            "waited_ms": count,
+            "total_rows": count,
+            "rows": records,
+        }), &[])),
+        ("FlushResponse", success(json!({"flush": schema_ref("FlushStatus")}), &[])),
+        ("FlushStatus", object(json!({
+            "pending_batches": count,
+            "pending_rows": count,
+            "flushes": count,
+            "rows_flushed": count,
+            "last_flush_at": nullable_string,
+            "last_flush_ms": {"type": ["integer", "null"]},
+            "last_error": nullable_string,
+        }), &[])),
+        ("AuditResponse", success(json!({
+            "total": {"type": "integer", "description": "Entries matching the query, before `offset` and `limit`"},
+            "entries": array_of(schema_ref("AuditEntry")),
+        }), &[])),
+        ("AuditEntry", object(json!({
+            "timestamp": string,
+            "request_id": string,
+            "operation": {"type": "string", "description": "collate, aggregate, delete, reset, ..."},
+            "dataset": string,
+            "method": string,
+            "route": string,
+            "status": count,
+            "client": {"type": "string", "description": "The client's IP address"},
+            "key": {"type": "string", "description": "A fingerprint of the API key the request was sent with"},
+            "rows_ingested": count,
+            "rows_removed": count,
+            "payload_bytes": {"type": "integer", "description": "The size of the body as it was received"},
+            "payload_sha256": {"type": "string", "description": "The SHA-256 of the body as it was received"},
+        }), &["request_id", "key", "rows_ingested", "rows_removed", "payload_bytes", "payload_sha256"])),
+        ("StatsResponse", success(json!({
+            "uptime_seconds": number,
+            "resident_bytes": {"type": "integer", "description": "The process's resident memory (Linux only)"},
+            "estimated_bytes": {"type": "integer", "description": "Every dataset's estimated DataFrame size, together"},
+            "datasets": array_of(schema_ref("DatasetStats")),
+            "ingest": schema_ref("IngestRate"),
+            "flush": schema_ref("FlushStatus"),
+        }), &["resident_bytes"])),
+        ("DatasetStats", object(json!({
+            "name": string,
+            "rows": count,
+            "columns": count,
+            "estimated_bytes": count,
+            "spilled_rows": {"type": "integer", "description": "Rows spilled to disk, not counted in `rows`"},
+            "spilled_bytes": {"type": "integer", "description": "Size of the spilled rows' files"},
+            "lock_waits": {"type": "integer", "description": "Waits for the dataset's write lock by ingests"},
+            "lock_wait_mean_ms": number,
+            "lock_wait_max_ms": number,
+        }), &[])),
+        ("IngestRate", object(json!({
+            "window_seconds": {"type": "number", "description": "60, or less just after the service started"},
+            "payloads": count,
+            "rows": count,
+            "bytes": count,
+            "rows_per_second": number,
+            "bytes_per_second": number,
+        }), &[])),
+        ("LogLevelRequest", object(json!({
+            "filter": {"type": "string", "description": "A filter as RUST_LOG takes it, e.g. `info,data_collator=debug`"},
+        }), &[])),
+        ("LogLevelResponse", success(json!({
+            "filter": string,
+            "previous": {"type": "string", "description": "The filter a PUT replaced"},
+        }), &["previous"])),
+        ("RollbackResponse", success(json!({
+            "dataset": string,
+            "rolled_back": array_of(schema_ref("RolledBackBatch")),
+            "rows": count,
+        }), &[])),
+        ("RolledBackBatch", object(json!({
+            "operation": {"type": "string", "description": "collate, aggregate, upsert, or collate_wide"},
+            "rows": {"type": "integer", "description": "Rows the payload had"},
+        }), &[])),
+        ("ReplicationStatusResponse", success(json!({
+            "role": {"type": "string", "description": "primary or replica"},
+            "stream": {"type": "string", "description": "The stream of changes this collator's replicas follow"},
+            "latest_seq": {"type": "integer", "description": "Its latest change, when it has replicas"},
+            "replicas": array_of(schema_ref("ReplicaStatus")),
+            "primary": schema_ref("FollowedStream"),
+        }), &["latest_seq", "primary"])),
+        ("ReplicaStatus", object(json!({
+            "url": string,
+            "state": {
+                "type": "string",
+                "description": "connecting, streaming, catching_up, retrying, rejected, or out_of_sync",
+            },
+            "acked_seq": {"type": "integer", "description": "The last change the replica has applied"},
+            "lag_changes": {"type": "integer", "description": "Changes the replica doesn't have yet"},
+            "lag_seconds": {"type": "number", "description": "How long the oldest of them has waited"},
+            "last_ack_at": string,
+            "last_error": string,
+        }), &["acked_seq", "lag_changes", "lag_seconds", "last_ack_at", "last_error"])),
+        ("FollowedStream", object(json!({
+            "stream": string,
+            "applied_seq": count,
+            "latest_seq": {"type": "integer", "description": "The primary's latest change, as of its last batch"},
+            "lag_changes": count,
+            "lag_seconds": {"type": "number", "description": "How long ago the last change applied was made"},
+            "last_applied_at": string,
+        }), &["lag_seconds", "last_applied_at"])),
+        ("SourcesResponse", success(json!({
+            "since": {"type": "string", "description": "When counting started (the service's start)"},
+            "period_secs": {"type": "integer", "description": "How long a quota lasts, when quotas are configured"},
+            "period_started": string,
+            "sources": array_of(schema_ref("SourceAccount")),
+        }), &["period_secs", "period_started"])),
+        ("SourceAccount", object(json!({
+            "source": {"type": "string", "description": "`key:<id>`, an `X-Source-Id`, or an IP address"},
+            "rows": count,
+            "bytes": count,
+            "batches": count,
+            "share": {"type": "number", "description": "Its part of every client's bytes, from 0 to 1"},
+            "rejected": {"type": "integer", "description": "Batches turned away because its quota was used up"},
+            "first_seen": string,
+            "last_seen": string,
+            "used": schema_ref("SourceUsage"),
+            "quota": schema_ref("Quota"),
+        }), &["used", "quota"])),
+        ("SourceUsage", object(json!({
+            "rows": count,
+            "bytes": count,
+            "batches": count,
+        }), &[])),
+        ("Quota", object(json!({
+            "rows": {"type": "integer", "description": "null for no limit"},
+            "bytes": {"type": "integer", "description": "null for no limit"},
+            "batches": {"type": "integer", "description": "null for no limit"},
+        }), &["rows", "bytes", "batches"])),
+        ("ReplicationAck", success(json!({
+            "stream": string,
+            "applied_seq": {"type": "integer", "description": "The last change of the stream the replica has"},
+        }), &[])),
+    ];
+
+    schemas.into_iter().map(|(name, schema)| (name.to_string(), schema)).collect()
+}
@@ -0,0 +1,299 @@
+// OpenTelemetry export over OTLP/HTTP (`[telemetry]`), as JSON, so traces and metrics end up in the Jaeger, Tempo, or
+// Prometheus an OpenTelemetry collector feeds. Spans are recorded by a `tracing` layer: a request's span, and inside
+// it the steps of the pipeline (`parse`, `prepare`, `lock`, `merge`, `group_by`, `wal`, `persist`), plus the
+// background writer's `flush`es. Finished spans are kept until the next export, as are the metrics `/metrics` reports,
+// which are sent with every export too. Exports that fail are dropped, not retried.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+use serde_json::{json, Map, Value};
+use tracing::{
+    span::{Attributes, Id, Record},
+    Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+use crate::{
+    dataset::AppState,
+    http::{self, Endpoint},
+    logging::JsonVisitor,
+    metrics::dataset_gauges,
+};
+
+// Spans kept between exports; ones that finish once this many are waiting are dropped
+const MAX_FINISHED_SPANS: usize = 50_000;
+
+// Span kinds, as OTLP numbers them
+const SPAN_KIND_INTERNAL: u8 = 1;
+const SPAN_KIND_SERVER: u8 = 2;
+
+// The OTLP status of a span that failed
+const STATUS_CODE_ERROR: u8 = 2;
+
+// Fields of the request span, by the names OpenTelemetry's semantic conventions give them
+const SEMANTIC_NAMES: &[(&str, &str)] = &[
+    ("method", "http.request.method"),
+    ("route", "http.route"),
+    ("status", "http.response.status_code"),
+    ("client", "client.address"),
+];
+
+static FINISHED: Mutex<Vec<FinishedSpan>> = Mutex::new(Vec::new());
+static DROPPED_SPANS: AtomicU64 = AtomicU64::new(0);
+
+// A span that hasn't closed yet, kept in its extensions
+struct OpenSpan {
+    trace_id: u128,
+    span_id: u64,
+    parent_span_id: Option<u64>,
+    start: SystemTime,
+    attributes: Map<String, Value>,
+}
+
+struct FinishedSpan {
+    name: &'static str,
+    span: OpenSpan,
+    end: SystemTime,
+}
+
+// The `tracing` layer that records spans for export. Spans inherit the trace of the span they're in, and start a new one
+// when they aren't in any.
+pub struct SpanRecorder;
+
+impl<S> Layer<S> for SpanRecorder
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let parent = span.parent().and_then(|parent| {
+            parent.extensions().get::<OpenSpan>().map(|open| (open.trace_id, open.span_id))
+        });
+
+        let mut attributes = Map::new();
+        attrs.record(&mut JsonVisitor(&mut attributes));
+        span.extensions_mut().insert(OpenSpan {
+            trace_id: parent.map_or_else(|| rand::random::<u128>().max(1), |(trace_id, _)| trace_id),
+            span_id: rand::random::<u64>().max(1),
+            parent_span_id: parent.map(|(_, span_id)| span_id),
+            start: SystemTime::now(),
+            attributes,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id)
+            && let Some(open) = span.extensions_mut().get_mut::<OpenSpan>()
+        {
+            values.record(&mut JsonVisitor(&mut open.attributes));
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(open) = span.extensions_mut().remove::<OpenSpan>() else {
+            return;
+        };
+
+        let mut finished = FINISHED.lock().unwrap();
+        if finished.len() >= MAX_FINISHED_SPANS {
+            DROPPED_SPANS.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        finished.push(FinishedSpan {
+            name: span.name(),
+            span: open,
+            end: SystemTime::now(),
+        });
+    }
+}
+
+// Send the spans that have finished, and the metrics, every `telemetry.export_interval_ms`
+pub(crate) async fn export(state: Arc<AppState>) {
+    let config = &state.config.telemetry;
+    let Some(endpoint) = config.otlp_endpoint.as_deref() else {
+        return;
+    };
+    let endpoint = Endpoint::parse(endpoint).expect("the endpoint is checked by Config::validate");
+    let resource = json!({
+        "attributes": [
+            attribute("service.name", &Value::from(config.service_name.as_str())),
+            attribute("service.version", &Value::from(env!("CARGO_PKG_VERSION"))),
+        ],
+    });
+    let scope = json!({"name": "data_collator", "version": env!("CARGO_PKG_VERSION")});
+
+    let interval = Duration::from_millis(config.export_interval_ms);
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+
+        let spans = std::mem::take(&mut *FINISHED.lock().unwrap());
+        let dropped = DROPPED_SPANS.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!("Dropped {} spans: more than {} finished between two exports", dropped, MAX_FINISHED_SPANS);
+        }
+        if !spans.is_empty() {
+            let spans: Vec<Value> = spans.iter().map(otlp_span).collect();
+            let body = json!({"resourceSpans": [{"resource": resource, "scopeSpans": [{"scope": scope, "spans": spans}]}]});
+            send(&endpoint, "/v1/traces", &body).await;
+        }
+
+        let datasets = dataset_gauges(&state).await;
+        let metrics = state.metrics.otlp(&datasets, state.writer.status().pending_rows);
+        let body = json!({"resourceMetrics": [{"resource": resource, "scopeMetrics": [{"scope": scope, "metrics": metrics}]}]});
+        send(&endpoint, "/v1/metrics", &body).await;
+    }
+}
+
+async fn send(endpoint: &Endpoint, signal: &str, body: &Value) {
+    let target = format!("{}{}", endpoint.path, signal);
+    let headers = [("Content-Type", String::from("application/json"))];
+    match http::send(endpoint, "POST", &target, &headers, body.to_string().as_bytes()).await {
+        Ok(response) if response.is_success() => {}
+        Ok(response) => warn!(
+            "The OTLP collector at {} turned down {} ({}): {}",
+            endpoint.authority(),
+            signal,
+            response.status,
+            String::from_utf8_lossy(&response.body).trim()
+        ),
+        Err(e) => warn!("Can't send {} to the OTLP collector: {}", signal, e),
+    }
+}
+
+fn otlp_span(finished: &FinishedSpan) -> Value {
+    let span = &finished.span;
+    let attribute_str = |name: &str| span.attributes.get(name).and_then(Value::as_str);
+
+    // Requests are named by their method and route, as OpenTelemetry's HTTP server spans are
+    let (name, kind) = match (finished.name, attribute_str("method"), attribute_str("route")) {
+        ("request", Some(method), Some(route)) => (format!("{} {}", method, route), SPAN_KIND_SERVER),
+        (name, _, _) => (name.to_string(), SPAN_KIND_INTERNAL),
+    };
+    let attributes: Vec<Value> = span.attributes.iter().map(|(key, value)| attribute(key, value)).collect();
+
+    let mut otlp = json!({
+        "traceId": format!("{:032x}", span.trace_id),
+        "spanId": format!("{:016x}", span.span_id),
+        "name": name,
+        "kind": kind,
+        "startTimeUnixNano": unix_nanos(span.start),
+        "endTimeUnixNano": unix_nanos(finished.end),
+        "attributes": attributes,
+    });
+    if let Some(parent) = span.parent_span_id {
+        otlp["parentSpanId"] = Value::from(format!("{:016x}", parent));
+    }
+    // Requests the service failed, and steps that record an error
+    let status = span.attributes.get("status").and_then(Value::as_u64);
+    if status.is_some_and(|status| status >= 500) || span.attributes.contains_key("error") {
+        otlp["status"] = json!({"code": STATUS_CODE_ERROR});
+    }
+    otlp
+}
+
+// A key and value, as OTLP attributes are
+fn attribute(key: &str, value: &Value) -> Value {
+    let key = SEMANTIC_NAMES.iter().find(|(name, _)| *name == key).map_or(key, |(_, semantic)| semantic);
+    let value = match value {
+        Value::Bool(value) => json!({"boolValue": value}),
+        // 64-bit integers are strings in OTLP's JSON
+        Value::Number(number) if number.is_i64() || number.is_u64() => json!({"intValue": number.to_string()}),
+        Value::Number(number) => json!({"doubleValue": number.as_f64()}),
+        Value::String(value) => json!({"stringValue": value}),
+        other => json!({"stringValue": other.to_string()}),
+    };
+    json!({"key": key, "value": value})
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
+
+// When metrics are reported: since the service started (they're cumulative), as of now
+pub(crate) struct Timestamps {
+    start: String,
+    now: String,
+}
+
+impl Timestamps {
+    pub(crate) fn since(uptime: Duration) -> Self {
+        let now = SystemTime::now();
+        Timestamps {
+            start: unix_nanos(now - uptime),
+            now: unix_nanos(now),
+        }
+    }
+}
+
+// Attributes of a data point, all strings
+pub(crate) type Labels = Vec<(&'static str, String)>;
+
+fn labels(labels: &Labels) -> Vec<Value> {
+    labels.iter().map(|(key, value)| attribute(key, &Value::from(value.as_str()))).collect()
+}
+
+// A cumulative counter, one data point per set of labels
+pub(crate) fn counter(name: &str, description: &str, points: Vec<(Labels, u64)>, times: &Timestamps) -> Value {
+    let points: Vec<Value> = points
+        .iter()
+        .map(|(attributes, value)| {
+            json!({
+                "attributes": labels(attributes),
+                "startTimeUnixNano": times.start,
+                "timeUnixNano": times.now,
+                "asInt": value.to_string(),
+            })
+        })
+        .collect();
+    json!({
+        "name": name,
+        "description": description,
+        "sum": {"dataPoints": points, "aggregationTemporality": 2, "isMonotonic": true},
+    })
+}
+
+pub(crate) fn gauge(name: &str, description: &str, points: Vec<(Labels, f64)>, times: &Timestamps) -> Value {
+    let points: Vec<Value> = points
+        .iter()
+        .map(|(attributes, value)| json!({"attributes": labels(attributes), "timeUnixNano": times.now, "asDouble": value}))
+        .collect();
+    json!({"name": name, "description": description, "gauge": {"dataPoints": points}})
+}
+
+// A cumulative histogram. `buckets` has a count per bound (not cumulative, as OTLP wants), then one for `+Inf`.
+pub(crate) fn histogram(
+    name: &str,
+    description: &str,
+    bounds: &[f64],
+    (buckets, sum, count): (&[u64], f64, u64),
+    times: &Timestamps,
+) -> Value {
+    let buckets: Vec<String> = buckets.iter().map(u64::to_string).collect();
+    let point = json!({
+        "startTimeUnixNano": times.start,
+        "timeUnixNano": times.now,
+        "count": count.to_string(),
+        "sum": sum,
+        "bucketCounts": buckets,
+        "explicitBounds": bounds,
+    });
+    json!({
+        "name": name,
+        "description": description,
+        "histogram": {"dataPoints": [point], "aggregationTemporality": 2},
+    })
+}
@@ -0,0 +1,185 @@
+use std::{fmt, str::FromStr};
+
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::aggregate::one_or_many;
+
+// Column flagged rows are marked in
+pub const OUTLIER_COLUMN: &str = "is_outlier";
+
+// Scales the median absolute deviation so that, for normally distributed values, it estimates the standard deviation
+// and thresholds mean the same with either method
+const MAD_SCALE: f64 = 1.4826;
+
+// What a value's distance from its group's median is measured in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutlierMethod {
+    // Standard deviations
+    Std,
+    // (Scaled) median absolute deviations, which a few wild values can't inflate the way they do the standard
+    // deviation
+    #[default]
+    Mad,
+}
+
+impl FromStr for OutlierMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "std" | "stddev" => Ok(OutlierMethod::Std),
+            "mad" => Ok(OutlierMethod::Mad),
+            other => Err(format!("Unsupported outlier method {:?} (expected std or mad)", other)),
+        }
+    }
+}
+
+impl fmt::Display for OutlierMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OutlierMethod::Std => "std",
+            OutlierMethod::Mad => "mad",
+        })
+    }
+}
+
+// Which rows count as outliers (`[outliers.<dataset>]`, or the `/outliers` parameters)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutlierSpec {
+    // The columns to check (every numeric column other than the keys by default)
+    #[serde(deserialize_with = "one_or_many")]
+    pub columns: Vec<String>,
+    // Compare each row only with the rows that have the same values in these columns, e.g. the same benchmark
+    #[serde(deserialize_with = "one_or_many")]
+    pub keys: Vec<String>,
+    pub method: OutlierMethod,
+    // How many deviations from the median a value can be before its row is flagged
+    pub threshold: f64,
+}
+
+impl Default for OutlierSpec {
+    fn default() -> Self {
+        OutlierSpec {
+            columns: Vec::new(),
+            keys: Vec::new(),
+            method: OutlierMethod::default(),
+            threshold: 3.0,
+        }
+    }
+}
+
+impl OutlierSpec {
+    // `df` with an `is_outlier` column (replacing any it had): true for the rows where a checked column is more than
+    // `threshold` deviations from the median of its group. Nulls are never outliers, and neither are rows whose group
+    // is too small to measure (a single row, with `std`). When most of a group's values are the same, the MAD is 0, so
+    // any other value is an outlier.
+    pub fn flagged(&self, df: &DataFrame) -> Result<DataFrame, String> {
+        let columns = self.checked_columns(df)?;
+        self.flag(df.clone().lazy(), &columns).collect().map_err(|e| e.to_string())
+    }
+
+    // The payload with its rows flagged, measured against the dataset's existing rows as well as its own
+    pub fn flagged_payload(&self, existing: Option<&DataFrame>, payload: &DataFrame) -> Result<DataFrame, String> {
+        let columns = self.checked_columns(payload)?;
+        let Some(existing) = existing.filter(|existing| existing.height() > 0) else {
+            return self.flagged(payload);
+        };
+
+        // Only the keys and checked columns are needed. Existing rows may not have every column yet; those count as
+        // nulls.
+        let needed: Vec<Expr> = self.keys.iter().chain(&columns).map(|column| col(column.as_str())).collect();
+        let present: Vec<Expr> = self
+            .keys
+            .iter()
+            .chain(&columns)
+            .filter(|column| existing.schema().contains(column))
+            .map(|column| col(column.as_str()))
+            .collect();
+        let args = UnionArgs {
+            rechunk: false,
+            to_supertypes: true,
+            ..Default::default()
+        };
+        let combined =
+            concat_lf_diagonal([existing.clone().lazy().select(present), payload.clone().lazy().select(needed)], args)
+                .map_err(|e| e.to_string())?;
+        let flags = self
+            .flag(combined, &columns)
+            .select([col(OUTLIER_COLUMN)])
+            .collect()
+            .map_err(|e| e.to_string())?;
+
+        let flags = flags.slice(existing.height() as i64, payload.height());
+        let mut payload = payload.clone();
+        payload
+            .with_column(flags.column(OUTLIER_COLUMN).map_err(|e| e.to_string())?.clone())
+            .map_err(|e| e.to_string())?;
+
+        Ok(payload)
+    }
+
+    fn checked_columns(&self, df: &DataFrame) -> Result<Vec<String>, String> {
+        if self.threshold.is_nan() || self.threshold <= 0.0 {
+            return Err(format!("The threshold must be above 0, not {}", self.threshold));
+        }
+        let schema = df.schema();
+        if let Some(column) = self.keys.iter().chain(&self.columns).find(|column| !schema.contains(column)) {
+            return Err(format!("Column {:?} doesn't exist", column));
+        }
+        if let Some(column) = self.columns.iter().find(|column| self.keys.contains(column)) {
+            return Err(format!("Column {:?} can't be both checked and a key", column));
+        }
+
+        let columns: Vec<String> = if self.columns.is_empty() {
+            schema
+                .iter()
+                .filter(|(name, dtype)| dtype.is_primitive_numeric() && !self.keys.iter().any(|key| key == *name))
+                .map(|(name, _)| name.to_string())
+                .collect()
+        } else {
+            self.columns.clone()
+        };
+        let numeric = |column: &String| schema.get(column).is_some_and(DataType::is_primitive_numeric);
+        if let Some(column) = columns.iter().find(|column| !numeric(column)) {
+            return Err(format!("Column {:?} isn't numeric", column));
+        }
+        if columns.is_empty() {
+            return Err(String::from("There are no numeric columns to check"));
+        }
+
+        Ok(columns)
+    }
+
+    // `rows` with the `is_outlier` column added. Deviations go in temporary columns first, since the median of them
+    // is itself taken per group.
+    fn flag(&self, rows: LazyFrame, columns: &[String]) -> LazyFrame {
+        let keys: Vec<Expr> = self.keys.iter().map(|key| col(key.as_str())).collect();
+        let per_group = |expr: Expr| if keys.is_empty() { expr } else { expr.over(keys.clone()) };
+        let deviation_columns: Vec<String> =
+            (0..columns.len()).map(|i| format!("__{}_deviation_{}", OUTLIER_COLUMN, i)).collect();
+
+        let deviations: Vec<Expr> = columns
+            .iter()
+            .zip(&deviation_columns)
+            .map(|(column, name)| {
+                let value = col(column.as_str()).cast(DataType::Float64);
+                (value.clone() - per_group(value.median())).abs().alias(name.as_str())
+            })
+            .collect();
+
+        let mut flag = lit(false);
+        for (column, name) in columns.iter().zip(&deviation_columns) {
+            let deviation = col(name.as_str());
+            let scale = match self.method {
+                OutlierMethod::Std => per_group(col(column.as_str()).cast(DataType::Float64).std(1)),
+                OutlierMethod::Mad => per_group(deviation.clone().median()) * lit(MAD_SCALE),
+            };
+            flag = flag.or(deviation.gt(scale * lit(self.threshold)).fill_null(lit(false)));
+        }
+
+        rows.with_columns(deviations).with_column(flag.alias(OUTLIER_COLUMN)).drop(deviation_columns)
+    }
+}
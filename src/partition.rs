@@ -0,0 +1,249 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::Utc;
+use polars::prelude::*;
+use serde::Deserialize;
+
+use crate::payload::{read_file, write_df, FileFormat};
+
+// Directory name Hive (and Spark and DuckDB after it) use for rows whose partition column is null
+const NULL_PARTITION: &str = "__HIVE_DEFAULT_PARTITION__";
+
+// Part files are named `part-<timestamp>-<n>.<ext>`, so they sort by when they were written
+const PART_PREFIX: &str = "part-";
+
+// How a dataset's rows are laid out as hive-style partitions (`[partition.<name>]`): a directory level per column,
+// e.g. `date=2024-05-01/job_id=17/part-....csv`, so readers like Spark and DuckDB can skip partitions a query rules out
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PartitionSpec {
+    // Columns to partition by, outermost first. Their values are in the directory names, so they're left out of the
+    // part files.
+    pub by: Vec<String>,
+    // Format of the part files: csv, arrow, or feather (Parquet isn't available in this build)
+    #[serde(default = "default_format")]
+    pub format: FileFormat,
+}
+
+fn default_format() -> FileFormat {
+    FileFormat::Csv
+}
+
+impl PartitionSpec {
+    pub fn new(by: Vec<String>) -> Self {
+        PartitionSpec {
+            by,
+            format: default_format(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.by.is_empty() {
+            return Err(String::from("No columns to partition by"));
+        }
+        let mut seen = HashSet::new();
+        if let Some(column) = self.by.iter().find(|column| !seen.insert(column.as_str())) {
+            return Err(format!("Column {:?} is listed twice", column));
+        }
+        if self.format == FileFormat::Parquet {
+            return Err(String::from("Parquet part files aren't supported by this build (use csv, arrow, or feather)"));
+        }
+
+        Ok(())
+    }
+}
+
+// Where a dataset's partitions are written (`<partitions_dir>/<dataset>`), and how
+#[derive(Debug, Clone)]
+pub struct PartitionedOutput {
+    pub dir: PathBuf,
+    pub spec: PartitionSpec,
+}
+
+// How often the writer merges each partition's part files (see `compact`)
+#[derive(Debug, Clone)]
+pub struct Compaction {
+    pub dir: PathBuf,
+    pub interval: Duration,
+}
+
+// Write batches as new part files, one per partition they have rows for. Each call adds files rather than appending
+// to existing ones, which is what keeps it cheap; `compact` merges them later.
+pub fn append(dir: &Path, spec: &PartitionSpec, batches: &[DataFrame]) -> PolarsResult<()> {
+    let df = match batches {
+        [] => return Ok(()),
+        [df] => df.clone(),
+        batches => {
+            let args = UnionArgs {
+                rechunk: true,
+                to_supertypes: true,
+                ..Default::default()
+            };
+            concat_lf_diagonal(batches.iter().map(|df| df.clone().lazy()).collect::<Vec<_>>(), args)?.collect()?
+        }
+    };
+
+    write_parts(dir, spec, &df)
+}
+
+// Replace every partition with the rows of `df`. The new tree is written next to the old one and swapped into place,
+// so readers see either the old rows or the new ones.
+pub fn replace(dir: &Path, spec: &PartitionSpec, df: &DataFrame) -> PolarsResult<()> {
+    let staging = sibling(dir, "new");
+    remove(&staging)?;
+    fs::create_dir_all(&staging)?;
+    write_parts(&staging, spec, df)?;
+
+    let old = sibling(dir, "old");
+    remove(&old)?;
+    if dir.exists() {
+        fs::rename(dir, &old)?;
+    }
+    fs::rename(&staging, dir)?;
+    remove(&old)?;
+
+    Ok(())
+}
+
+// Remove a dataset's partitions, if it has any
+pub fn remove(dir: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(dir) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// A hidden directory next to `dir` (`.<name>.<suffix>`), where readers and `compact` won't look
+fn sibling(dir: &Path, suffix: &str) -> PathBuf {
+    let name = dir.file_name().map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+    dir.with_file_name(format!(".{}.{}", name, suffix))
+}
+
+fn write_parts(dir: &Path, spec: &PartitionSpec, df: &DataFrame) -> PolarsResult<()> {
+    if df.height() == 0 {
+        return Ok(());
+    }
+
+    // A column a payload didn't have is null for all of its rows, like any other missing value
+    let mut df = df.clone();
+    for column in &spec.by {
+        if df.column(column).is_err() {
+            df.with_column(Series::full_null(column.as_str().into(), df.height(), &DataType::String))?;
+        }
+    }
+
+    for part in df.partition_by_stable(spec.by.iter().map(String::as_str), true)? {
+        let mut partition = dir.to_path_buf();
+        for column in &spec.by {
+            let value = part.column(column)?.get(0)?;
+            let value = if value.is_null() { String::from(NULL_PARTITION) } else { escape(&value.str_value()) };
+            partition.push(format!("{}={}", escape(column), value));
+        }
+
+        let mut part = part.drop_many(spec.by.iter().map(String::as_str));
+        let bytes = write_df(&mut part, spec.format).map_err(|e| polars_err!(ComputeError: "{}", e))?;
+        write_part(&partition, spec.format.extension(), &bytes)?;
+    }
+
+    Ok(())
+}
+
+// Write a new part file into a partition. It's written under a hidden name first, so a reader listing the partition
+// never sees half of it.
+fn write_part(partition: &Path, extension: &str, bytes: &[u8]) -> io::Result<PathBuf> {
+    fs::create_dir_all(partition)?;
+    let stamp = Utc::now().format("%Y%m%dT%H%M%S%.3fZ");
+    let mut n = 0;
+    let mut path = partition.join(format!("{}{}-{}.{}", PART_PREFIX, stamp, n, extension));
+    while path.exists() {
+        n += 1;
+        path = partition.join(format!("{}{}-{}.{}", PART_PREFIX, stamp, n, extension));
+    }
+
+    let tmp = partition.join(format!(".{}.tmp", path.file_name().unwrap_or_default().to_string_lossy()));
+    fs::write(&tmp, bytes)?;
+    fs::rename(&tmp, &path)?;
+
+    Ok(path)
+}
+
+// Escape the characters Hive does in partition directory names, e.g. `/` as `%2F`
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c.is_ascii_control() || "\"#%'*/:=?\\{}[]^".contains(c) {
+            escaped.push_str(&format!("%{:02X}", c as u32));
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+// Merge the part files of every partition under `dir` that has more than one into a single file, oldest rows first,
+// so a partition that's been appended to many times doesn't slow readers down with lots of small files. The merged
+// file is renamed into place before the parts are removed, so a crash in between leaves rows twice rather than not at
+// all. Returns how many partitions were compacted.
+pub fn compact(dir: &Path) -> Result<usize, String> {
+    let mut compacted = 0;
+    compact_dir(dir, &mut compacted)?;
+    Ok(compacted)
+}
+
+fn compact_dir(dir: &Path, compacted: &mut usize) -> Result<(), String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("Can't read {:?}: {}", dir, e)),
+    };
+
+    // Part files by extension, in case the format was changed since some of them were written
+    let mut parts: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Can't read {:?}: {}", dir, e))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        if path.is_dir() {
+            compact_dir(&path, compacted)?;
+        } else if name.starts_with(PART_PREFIX)
+            && let Some(extension) = path.extension()
+        {
+            parts.entry(extension.to_string_lossy().into_owned()).or_default().push(path);
+        }
+    }
+
+    for (extension, mut paths) in parts {
+        if paths.len() < 2 {
+            continue;
+        }
+        paths.sort();
+
+        let format = FileFormat::from_path(&paths[0])?;
+        let frames = paths.iter().map(|path| read_file(path).map(DataFrame::lazy)).collect::<Result<Vec<_>, _>>()?;
+        let args = UnionArgs {
+            rechunk: true,
+            to_supertypes: true,
+            ..Default::default()
+        };
+        let mut df = concat_lf_diagonal(frames, args)
+            .and_then(|lf| lf.collect())
+            .map_err(|e| format!("Can't merge the part files in {:?}: {}", dir, e))?;
+        let bytes = write_df(&mut df, format)?;
+        write_part(dir, &extension, &bytes).map_err(|e| format!("Can't write a part file in {:?}: {}", dir, e))?;
+
+        for path in paths {
+            fs::remove_file(&path).map_err(|e| format!("Can't remove {:?} after compacting it: {}", path, e))?;
+        }
+        *compacted += 1;
+    }
+
+    Ok(())
+}
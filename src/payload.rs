@@ -0,0 +1,291 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+    str::FromStr,
+};
+
+use axum::http::{header, HeaderMap, HeaderValue};
+use indexmap::IndexMap;
+use polars::{io::mmap::MmapBytesReader, prelude::*};
+use serde::Deserialize;
+use serde_json::Value;
+
+// Content type used for Parquet request bodies and exports
+pub const PARQUET_CONTENT_TYPE: &str = "application/vnd.apache.parquet";
+
+// Content types for Arrow IPC streams and Arrow IPC files (Feather v2)
+pub const ARROW_STREAM_CONTENT_TYPE: &str = "application/vnd.apache.arrow.stream";
+pub const ARROW_FILE_CONTENT_TYPE: &str = "application/vnd.apache.arrow.file";
+
+// Content types for JSON request bodies (an array of records, or a single record) and newline-delimited JSON
+const JSON_CONTENT_TYPE: &str = "application/json";
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+// Get the request's `Content-Type` without any parameters (e.g. `; charset=utf-8`)
+pub fn content_type(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(';').next().unwrap_or("").trim())
+}
+
+// Headers that give a payload's content type, for payloads that don't come with headers of their own (broker
+// messages, WebSocket messages)
+pub fn content_type_headers(content_type: &str) -> Result<HeaderMap, String> {
+    let value = HeaderValue::from_str(content_type).map_err(|_| format!("Bad content type {:?}", content_type))?;
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, value);
+    Ok(headers)
+}
+
+// Whether a request body is parsed as CSV: when it doesn't say it's anything else
+pub fn is_csv(headers: &HeaderMap) -> bool {
+    !matches!(
+        content_type(headers),
+        Some(
+            PARQUET_CONTENT_TYPE
+                | ARROW_STREAM_CONTENT_TYPE
+                | ARROW_FILE_CONTENT_TYPE
+                | JSON_CONTENT_TYPE
+                | NDJSON_CONTENT_TYPE
+        )
+    )
+}
+
+// Work out the content type of a payload that doesn't say, from its first bytes. JSON (an object, an array of them,
+// or one per line) and Arrow IPC streams are recognized, and anything else is taken for CSV.
+pub fn sniff_content_type(payload: &[u8]) -> &'static str {
+    if payload.starts_with(&[0xff, 0xff, 0xff, 0xff]) {
+        return ARROW_STREAM_CONTENT_TYPE;
+    }
+
+    let text = payload.trim_ascii();
+    match text.first() {
+        Some(b'[') => JSON_CONTENT_TYPE,
+        // More than one object is newline-delimited JSON
+        Some(b'{') if serde_json::from_slice::<serde::de::IgnoredAny>(text).is_err() => NDJSON_CONTENT_TYPE,
+        Some(b'{') => JSON_CONTENT_TYPE,
+        _ => "text/csv",
+    }
+}
+
+// Parse a request body into a DataFrame according to its `Content-Type` (CSV unless stated otherwise). The body can
+// be in memory (a `Cursor`) or a file a large upload was spooled to, which Polars reads without loading it first.
+pub fn read_payload<R: MmapBytesReader>(headers: &HeaderMap, body: R) -> Result<DataFrame, String> {
+    match content_type(headers) {
+        Some(PARQUET_CONTENT_TYPE) => {
+            // Polars' `parquet` feature pulls in compression crates (brotli, flate2) this build doesn't have
+            Err(String::from("Parquet payloads are not supported by this build"))
+        }
+        Some(ARROW_STREAM_CONTENT_TYPE) => IpcStreamReader::new(body)
+            .finish()
+            .map_err(|e| format!("Error parsing Arrow IPC stream: {}", e)),
+        Some(ARROW_FILE_CONTENT_TYPE) => IpcReader::new(body)
+            .finish()
+            .map_err(|e| format!("Error parsing Arrow IPC file: {}", e)),
+        Some(JSON_CONTENT_TYPE) => {
+            let value: Value =
+                serde_json::from_reader(BufReader::new(body)).map_err(|e| format!("Error parsing JSON: {}", e))?;
+
+            let records = match value {
+                Value::Array(records) => records,
+                record @ Value::Object(_) => vec![record],
+                _ => return Err(String::from("JSON payloads must be an object or an array of objects")),
+            };
+
+            records_to_df(records)
+        }
+        Some(NDJSON_CONTENT_TYPE) => {
+            let mut records = Vec::new();
+            for (i, line) in BufReader::new(body).split(b'\n').enumerate() {
+                let line = line.map_err(|e| format!("Error reading NDJSON line {}: {}", i + 1, e))?;
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+
+                let record =
+                    serde_json::from_slice(&line).map_err(|e| format!("Error parsing NDJSON line {}: {}", i + 1, e))?;
+                records.push(record);
+            }
+
+            records_to_df(records)
+        }
+        // Use Polars to read the CSV
+        _ => CsvReader::new(body)
+            .finish()
+            .map_err(|e| format!("Error parsing CSV: {}", e)),
+    }
+}
+
+// File formats that whole datasets can be read from and written to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum FileFormat {
+    Csv,
+    // Arrow IPC stream
+    Arrow,
+    // Arrow IPC file (Feather v2)
+    Feather,
+    Parquet,
+}
+
+impl FromStr for FileFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "csv" => Ok(FileFormat::Csv),
+            "arrow" | "arrows" => Ok(FileFormat::Arrow),
+            "feather" | "ipc" => Ok(FileFormat::Feather),
+            "parquet" => Ok(FileFormat::Parquet),
+            other => Err(format!(
+                "Unsupported format {:?} (expected csv, arrow, feather, or parquet)",
+                other
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for FileFormat {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl FileFormat {
+    // Guess the format from a file's extension
+    pub fn from_path(path: &Path) -> Result<Self, String> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            // `.arrow` is the conventional extension for Arrow IPC files, `.arrows` for streams
+            Some("arrow") => Ok(FileFormat::Feather),
+            Some(ext) => ext.parse(),
+            None => Err(format!("Can't tell the format of {:?} without a file extension", path)),
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            FileFormat::Csv => "text/csv",
+            FileFormat::Arrow => ARROW_STREAM_CONTENT_TYPE,
+            FileFormat::Feather => ARROW_FILE_CONTENT_TYPE,
+            FileFormat::Parquet => PARQUET_CONTENT_TYPE,
+        }
+    }
+
+    // What the format is called in `format` parameters
+    pub fn name(&self) -> &'static str {
+        match self {
+            FileFormat::Csv => "csv",
+            FileFormat::Arrow => "arrow",
+            FileFormat::Feather => "feather",
+            FileFormat::Parquet => "parquet",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            FileFormat::Csv => "csv",
+            FileFormat::Arrow => "arrows",
+            FileFormat::Feather => "arrow",
+            FileFormat::Parquet => "parquet",
+        }
+    }
+}
+
+// Serialize a whole DataFrame in the given format
+pub fn write_df(df: &mut DataFrame, format: FileFormat) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+
+    match format {
+        FileFormat::Csv => CsvWriter::new(&mut bytes).include_header(true).finish(df),
+        FileFormat::Arrow => IpcStreamWriter::new(&mut bytes).finish(df),
+        FileFormat::Feather => IpcWriter::new(&mut bytes).finish(df),
+        FileFormat::Parquet => return Err(String::from("Parquet export is not supported by this build")),
+    }
+    .map_err(|e| e.to_string())?;
+
+    Ok(bytes)
+}
+
+// Read a whole file into a DataFrame, picking the format from its extension
+pub fn read_file(path: &Path) -> Result<DataFrame, String> {
+    read_file_as(path, FileFormat::from_path(path)?)
+}
+
+// Read a whole file into a DataFrame, whatever its extension
+pub fn read_file_as(path: &Path, format: FileFormat) -> Result<DataFrame, String> {
+    let file = File::open(path).map_err(|e| format!("Can't open {:?}: {}", path, e))?;
+
+    match format {
+        FileFormat::Csv => CsvReader::new(file).finish(),
+        FileFormat::Arrow => IpcStreamReader::new(file).finish(),
+        FileFormat::Feather => IpcReader::new(file).finish(),
+        FileFormat::Parquet => return Err(String::from("Parquet files are not supported by this build")),
+    }
+    .map_err(|e| format!("Error reading {:?}: {}", path, e))
+}
+
+// Build a DataFrame from JSON objects. Nested objects are flattened one level into `parent.child` columns, anything
+// nested deeper (or arrays) is kept as a JSON string. Columns appear in the order their keys are first seen.
+fn records_to_df(records: Vec<Value>) -> Result<DataFrame, String> {
+    let height = records.len();
+    let mut columns: IndexMap<String, Vec<Value>> = IndexMap::new();
+
+    for (row, record) in records.into_iter().enumerate() {
+        let Value::Object(record) = record else {
+            return Err(format!("JSON record {} is not an object", row + 1));
+        };
+
+        for (key, value) in record {
+            match value {
+                Value::Object(nested) => {
+                    for (nested_key, nested_value) in nested {
+                        set_cell(&mut columns, format!("{}.{}", key, nested_key), row, height, nested_value);
+                    }
+                }
+                value => set_cell(&mut columns, key, row, height, value),
+            }
+        }
+    }
+
+    let columns = columns
+        .into_iter()
+        .map(|(name, values)| json_values_to_column(name, values))
+        .collect();
+
+    DataFrame::new(columns).map_err(|e| e.to_string())
+}
+
+// Store one value, creating the (all-null) column the first time its name is seen
+fn set_cell(columns: &mut IndexMap<String, Vec<Value>>, name: String, row: usize, height: usize, value: Value) {
+    columns.entry(name).or_insert_with(|| vec![Value::Null; height])[row] = value;
+}
+
+// Turn a column of JSON values into a Polars column, picking the narrowest type that fits every value
+fn json_values_to_column(name: String, values: Vec<Value>) -> Column {
+    let non_null = || values.iter().filter(|v| !v.is_null());
+
+    if non_null().all(Value::is_boolean) {
+        let values: Vec<Option<bool>> = values.iter().map(Value::as_bool).collect();
+        Column::new(name.into(), values)
+    } else if non_null().all(|v| v.is_i64()) {
+        let values: Vec<Option<i64>> = values.iter().map(Value::as_i64).collect();
+        Column::new(name.into(), values)
+    } else if non_null().all(Value::is_number) {
+        let values: Vec<Option<f64>> = values.iter().map(Value::as_f64).collect();
+        Column::new(name.into(), values)
+    } else {
+        let values: Vec<Option<String>> = values
+            .into_iter()
+            .map(|v| match v {
+                Value::Null => None,
+                Value::String(s) => Some(s),
+                other => Some(other.to_string()),
+            })
+            .collect();
+        Column::new(name.into(), values)
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    fs::{File, OpenOptions},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use polars::prelude::*;
+use serde::Deserialize;
+
+use crate::payload::{read_file_as, FileFormat};
+
+// How a dataset's output file is updated after each accepted request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum WriteMode {
+    // Append each incoming batch, writing the header only when the file is first created
+    #[default]
+    Append,
+    // Replace the file with the latest incoming batch
+    Overwrite,
+    // Replace the file with the full current state of the dataset
+    Snapshot,
+}
+
+impl FromStr for WriteMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "append" => Ok(WriteMode::Append),
+            "overwrite" => Ok(WriteMode::Overwrite),
+            "snapshot" => Ok(WriteMode::Snapshot),
+            other => Err(format!(
+                "Unsupported write mode {:?} (expected one of: append, overwrite, snapshot)",
+                other
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for WriteMode {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+// How output files are stored. The background writer (and recovery at startup) only go through this, so a new
+// format is a new implementation, picked by `storage.output_format` in `backend`.
+pub trait StorageBackend: std::fmt::Debug + Send + Sync {
+    // Extension of the files it writes, which named datasets' files in `datasets_dir` are given
+    fn extension(&self) -> &'static str;
+
+    // Add rows to the end of a file, creating it if it doesn't exist yet
+    fn append(&self, df: &mut DataFrame, path: &Path) -> PolarsResult<()>;
+
+    // Replace a file's contents. Readers never see a half-written file.
+    fn snapshot(&self, df: &mut DataFrame, path: &Path) -> PolarsResult<()>;
+
+    // Read a file back, for recovery at startup
+    fn load(&self, path: &Path) -> PolarsResult<DataFrame>;
+
+    // Make sure what's been written to a file so far is on disk, once the writer has written a batch of writes
+    fn flush(&self, path: &Path) -> PolarsResult<()> {
+        match File::open(path) {
+            Ok(file) => Ok(file.sync_all()?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+// The backend output files are written with
+pub fn backend(format: FileFormat) -> Result<Arc<dyn StorageBackend>, String> {
+    match format {
+        FileFormat::Csv => Ok(Arc::new(CsvBackend)),
+        FileFormat::Arrow | FileFormat::Feather => Ok(Arc::new(ArrowBackend { format })),
+        FileFormat::Parquet => Err(String::from("Parquet output files are not supported by this build")),
+    }
+}
+
+// CSV files, which can be appended to in place: the header is only written when the file is first created
+#[derive(Debug)]
+pub struct CsvBackend;
+
+impl StorageBackend for CsvBackend {
+    fn extension(&self) -> &'static str {
+        FileFormat::Csv.extension()
+    }
+
+    fn append(&self, df: &mut DataFrame, path: &Path) -> PolarsResult<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        let include_header = file.metadata()?.len() == 0;
+
+        CsvWriter::new(&mut file).include_header(include_header).finish(df)
+    }
+
+    fn snapshot(&self, df: &mut DataFrame, path: &Path) -> PolarsResult<()> {
+        replace_with(path, |file| CsvWriter::new(file).include_header(true).finish(df))
+    }
+
+    fn load(&self, path: &Path) -> PolarsResult<DataFrame> {
+        read_file_as(path, FileFormat::Csv).map_err(|e| polars_err!(ComputeError: "{}", e))
+    }
+}
+
+// Arrow IPC files (Feather v2) or streams, which keep dtypes exactly. Neither can be added to in place, so appending
+// rewrites the whole file: cheap for small datasets, but `snapshot` mode is a better fit for big ones.
+#[derive(Debug)]
+pub struct ArrowBackend {
+    format: FileFormat,
+}
+
+impl StorageBackend for ArrowBackend {
+    fn extension(&self) -> &'static str {
+        self.format.extension()
+    }
+
+    fn append(&self, df: &mut DataFrame, path: &Path) -> PolarsResult<()> {
+        let mut combined = match std::fs::metadata(path) {
+            Ok(metadata) if metadata.len() > 0 => self.load(path)?.vstack(df)?,
+            _ => df.clone(),
+        };
+
+        self.snapshot(&mut combined, path)
+    }
+
+    fn snapshot(&self, df: &mut DataFrame, path: &Path) -> PolarsResult<()> {
+        match self.format {
+            FileFormat::Arrow => replace_with(path, |file| IpcStreamWriter::new(file).finish(df)),
+            _ => replace_with(path, |file| IpcWriter::new(file).finish(df)),
+        }
+    }
+
+    fn load(&self, path: &Path) -> PolarsResult<DataFrame> {
+        read_file_as(path, self.format).map_err(|e| polars_err!(ComputeError: "{}", e))
+    }
+}
+
+// Replace a file with what `write` writes. The new contents are written next to it first and then renamed into place,
+// so readers never see a half-written file.
+pub(crate) fn replace_with(path: &Path, write: impl FnOnce(&mut File) -> PolarsResult<()>) -> PolarsResult<()> {
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+
+    let mut file = File::create(&tmp_name)?;
+    write(&mut file)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_name, path)?;
+
+    Ok(())
+}
+
+// Move an output file out of the way, to `<file>.<timestamp>` next to it, so the next write starts a new file. The
+// new name doesn't end in the output format's extension, so it isn't picked up as a dataset of its own from
+// `--datasets-dir`. Returns where it went, or `None` if there was no file yet.
+pub fn rotate_output(output_file: &Path) -> std::io::Result<Option<PathBuf>> {
+    if !output_file.exists() {
+        return Ok(None);
+    }
+
+    let mut rotated = output_file.as_os_str().to_owned();
+    rotated.push(chrono::Utc::now().format(".%Y%m%dT%H%M%S%.3fZ").to_string());
+    let rotated = PathBuf::from(rotated);
+    std::fs::rename(output_file, &rotated)?;
+
+    Ok(Some(rotated))
+}
+
+// Remove an output file, if there is one
+pub fn remove_output(output_file: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(output_file) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,201 @@
+mod wire;
+
+use std::sync::Arc;
+
+use log::{error, warn};
+use polars::prelude::*;
+use tokio::sync::mpsc;
+
+use crate::{
+    config::PostgresConfig,
+    metrics::Metrics,
+    sink::{backoff, QUEUE_CAPACITY},
+};
+
+pub use wire::ConnectParams;
+use wire::{Connection, PgError};
+
+// Rows a dataset just accepted, to be inserted into its table
+#[derive(Debug)]
+struct Mirror {
+    dataset: String,
+    df: DataFrame,
+}
+
+// Handle to the background task that mirrors every batch of rows the datasets accept into a Postgres table, for
+// dashboards that read from there. It's best-effort: ingest never waits on Postgres, and a batch that still can't be
+// inserted after every retry is dropped (and counted in `/metrics`).
+#[derive(Debug, Clone)]
+pub struct PostgresSink {
+    tx: mpsc::Sender<Mirror>,
+    // Where the server is, so `/readyz` can check it's reachable
+    server: (String, u16),
+    // Datasets that are mirrored (every one, if empty)
+    datasets: Vec<String>,
+    metrics: Arc<Metrics>,
+}
+
+impl PostgresSink {
+    // Start the sink task, if a Postgres URL is configured. Must be called from within the Tokio runtime.
+    pub fn spawn(config: &PostgresConfig, metrics: Arc<Metrics>) -> Option<Self> {
+        let params = ConnectParams::parse(config.url.as_deref()?).expect("the URL is checked by Config::validate");
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        let server = (params.host.clone(), params.port);
+
+        tokio::spawn(run(rx, params, config.clone(), metrics.clone()));
+
+        Some(PostgresSink {
+            tx,
+            server,
+            datasets: config.datasets.clone(),
+            metrics,
+        })
+    }
+
+    // The server's host and port
+    pub fn server(&self) -> (&str, u16) {
+        (&self.server.0, self.server.1)
+    }
+
+    // Queue a dataset's new rows to be inserted. If the queue is full (Postgres has been unreachable for a while),
+    // they're dropped rather than holding up the request.
+    pub fn submit(&self, dataset: &str, df: &DataFrame) {
+        if df.height() == 0 || !(self.datasets.is_empty() || self.datasets.iter().any(|name| name == dataset)) {
+            return;
+        }
+
+        let mirror = Mirror {
+            dataset: dataset.to_string(),
+            df: df.clone(),
+        };
+        if let Err(e) = self.tx.try_send(mirror) {
+            let rows = match &e {
+                mpsc::error::TrySendError::Full(mirror) | mpsc::error::TrySendError::Closed(mirror) => mirror.df.height(),
+            };
+            error!("Dropping {} rows of {:?}: the Postgres mirror is {} batches behind", rows, dataset, QUEUE_CAPACITY);
+            self.metrics.record_sink("postgres", 0, rows);
+        }
+    }
+}
+
+async fn run(mut rx: mpsc::Receiver<Mirror>, params: ConnectParams, config: PostgresConfig, metrics: Arc<Metrics>) {
+    let mut connection = None;
+
+    while let Some(mirror) = rx.recv().await {
+        let rows = mirror.df.height();
+        let statements = match insert_statements(&config, &mirror) {
+            Ok(statements) => statements,
+            Err(e) => {
+                error!("Dropping {} rows of {:?} that can't be mirrored to Postgres: {}", rows, mirror.dataset, e);
+                metrics.record_sink("postgres", 0, rows);
+                continue;
+            }
+        };
+
+        let mut attempt = 0;
+        loop {
+            match insert(&mut connection, &params, &statements).await {
+                Ok(()) => {
+                    metrics.record_sink("postgres", rows, 0);
+                    break;
+                }
+                Err(e) if e.is_transient() && attempt < config.max_retries => {
+                    let delay = backoff(config.retry_backoff_ms, attempt);
+                    attempt += 1;
+                    warn!(
+                        "Can't mirror {} rows of {:?} to Postgres ({}), retrying in {:?} ({} of {})",
+                        rows, mirror.dataset, e, delay, attempt, config.max_retries
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => {
+                    error!("Dropping {} rows of {:?} that can't be mirrored to Postgres: {}", rows, mirror.dataset, e);
+                    metrics.record_sink("postgres", 0, rows);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// Insert a batch's rows in one transaction, connecting first if there's no connection yet. The connection is kept for
+// the next batch unless it failed.
+async fn insert(connection: &mut Option<Connection>, params: &ConnectParams, statements: &[String]) -> Result<(), PgError> {
+    let mut conn = match connection.take() {
+        Some(conn) => conn,
+        None => Connection::connect(params).await?,
+    };
+
+    let result = async {
+        conn.execute("BEGIN").await?;
+        for statement in statements {
+            conn.execute(statement).await?;
+        }
+        conn.execute("COMMIT").await
+    }
+    .await;
+
+    match &result {
+        Ok(()) => *connection = Some(conn),
+        Err(PgError::Server { .. }) => {
+            if conn.execute("ROLLBACK").await.is_ok() {
+                *connection = Some(conn);
+            }
+        }
+        Err(PgError::Io(_)) => {}
+    }
+    result
+}
+
+// Multi-row `INSERT`s of up to `batch_rows` rows each. Every value is sent as a quoted literal (or `NULL`), which
+// Postgres converts to the column's type, so the table's types decide how values are stored.
+fn insert_statements(config: &PostgresConfig, mirror: &Mirror) -> Result<Vec<String>, String> {
+    let table = config.table.replace("{dataset}", &mirror.dataset);
+    let table = table.split('.').map(quote_identifier).collect::<Vec<_>>().join(".");
+
+    let df = mirror.df.clone().as_single_chunk_par().clone();
+    let columns = df.get_columns();
+    let names = columns
+        .iter()
+        .map(|column| {
+            let name = column.name().as_str();
+            quote_identifier(config.columns.get(name).map_or(name, String::as_str))
+        })
+        .collect::<Vec<_>>();
+    let head = format!("INSERT INTO {} ({}) VALUES ", table, names.join(", "));
+
+    let mut statements = Vec::new();
+    for start in (0..df.height()).step_by(config.batch_rows) {
+        let mut sql = head.clone();
+        for row in start..(start + config.batch_rows).min(df.height()) {
+            sql.push_str(if row == start { "(" } else { ", (" });
+            for (i, column) in columns.iter().enumerate() {
+                if i > 0 {
+                    sql.push_str(", ");
+                }
+                let value = column.get(row).map_err(|e| e.to_string())?;
+                push_literal(&mut sql, &value);
+            }
+            sql.push(')');
+        }
+        statements.push(sql);
+    }
+
+    Ok(statements)
+}
+
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn push_literal(sql: &mut String, value: &AnyValue) {
+    if value.is_null() {
+        sql.push_str("NULL");
+        return;
+    }
+
+    sql.push('\'');
+    // Postgres doesn't allow NUL characters in text at all
+    sql.push_str(&value.str_value().replace('\'', "''").replace('\0', ""));
+    sql.push('\'');
+}
@@ -0,0 +1,125 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::atomic::{AtomicI64, Ordering},
+};
+
+use axum::http::HeaderMap;
+use chrono::{DateTime, Utc};
+use log::warn;
+use polars::prelude::*;
+
+use crate::{
+    config::ProvenanceConfig,
+    dataset::{AppState, Dataset},
+    rate_limit::client_ip,
+};
+
+const RECEIVED_AT_DTYPE: DataType = DataType::Datetime(TimeUnit::Microseconds, None);
+
+// Where and when a payload came from, noted as soon as the request arrives
+#[derive(Debug, Clone)]
+pub struct Origin {
+    pub received_at: DateTime<Utc>,
+    pub source: String,
+}
+
+impl Origin {
+    pub fn of(state: &AppState, headers: &HeaderMap, peer: SocketAddr) -> Self {
+        Origin {
+            received_at: Utc::now(),
+            source: source(state, headers, Some(peer)),
+        }
+    }
+}
+
+// The `X-Source-Id` header names the source when the client sends one; otherwise it's the client's IP address
+pub fn source(state: &AppState, headers: &HeaderMap, peer: Option<SocketAddr>) -> String {
+    headers
+        .get("x-source-id")
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|source| !source.is_empty())
+        .map(String::from)
+        .or_else(|| client_ip(state, headers, peer).map(|ip| ip.to_string()))
+        .unwrap_or_else(|| String::from("unknown"))
+}
+
+// Hands out batch sequence numbers. They only ever increase, but numbers taken by payloads that were then rejected are
+// skipped.
+#[derive(Debug)]
+pub struct BatchCounter {
+    next: AtomicI64,
+}
+
+impl BatchCounter {
+    // Carry on from the highest batch number the datasets already hold, so numbers keep increasing across restarts
+    pub fn resume(config: &ProvenanceConfig, datasets: &HashMap<String, Dataset>) -> Self {
+        // Spilled rows count too, which only reads their batch column back in
+        let last = datasets
+            .values()
+            .filter_map(|dataset| {
+                let rows = dataset.rows();
+                let last = col(config.batch_column.as_str()).max().cast(DataType::Int64);
+                rows.lazy().ok()?.select([last]).collect().ok()
+            })
+            .filter_map(|df| df.column(&config.batch_column).ok()?.i64().ok()?.get(0))
+            .max();
+
+        BatchCounter {
+            next: AtomicI64::new(last.map_or(1, |last| last + 1)),
+        }
+    }
+
+    fn next(&self) -> i64 {
+        self.next.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+// A payload with the provenance columns added at the end. Called with the dataset locked, so batch numbers increase in
+// the order payloads are applied. A payload that already has one of the columns is rejected rather than overwritten.
+pub fn annotate(
+    config: &ProvenanceConfig,
+    counter: &BatchCounter,
+    origin: &Origin,
+    df: DataFrame,
+) -> Result<DataFrame, String> {
+    let names = [&config.received_at_column, &config.source_column, &config.batch_column];
+    if let Some(name) = names.into_iter().find(|name| df.column(name).is_ok()) {
+        return Err(format!("The payload has a {:?} column, which is reserved for provenance", name));
+    }
+
+    df.lazy()
+        .with_columns([
+            lit(origin.received_at.timestamp_micros())
+                .cast(RECEIVED_AT_DTYPE)
+                .alias(config.received_at_column.as_str()),
+            lit(origin.source.clone()).alias(config.source_column.as_str()),
+            lit(counter.next()).cast(DataType::Int64).alias(config.batch_column.as_str()),
+        ])
+        .collect()
+        .map_err(|e| e.to_string())
+}
+
+// Output files are CSV, so provenance columns read back from one come back as strings (for the timestamp) or
+// whatever integer type was inferred. Cast them back to what `annotate` adds, so new payloads still line up.
+pub fn restore_dtypes(config: &ProvenanceConfig, name: &str, dataset: &mut Dataset) {
+    let Some(df) = dataset.df.as_mut() else {
+        return;
+    };
+
+    let dtypes = [
+        (&config.received_at_column, RECEIVED_AT_DTYPE),
+        (&config.source_column, DataType::String),
+        (&config.batch_column, DataType::Int64),
+    ];
+    for (column, dtype) in dtypes {
+        let cast = match df.column(column) {
+            Ok(existing) if existing.dtype() != &dtype => existing.strict_cast(&dtype),
+            _ => continue,
+        };
+        if let Err(e) = cast.and_then(|cast| df.with_column(cast).map(|_| ())) {
+            warn!("Can't restore provenance column {:?} of dataset {:?}: {}", column, name, e);
+        }
+    }
+}
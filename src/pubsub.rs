@@ -0,0 +1,135 @@
+mod mqtt;
+mod nats;
+
+use polars::prelude::*;
+
+use crate::{
+    config::{Config, MqttConfig, NatsConfig},
+    payload::{content_type_headers, read_payload, sniff_content_type},
+};
+
+// A message received from a broker
+#[derive(Debug)]
+pub struct Message {
+    // The MQTT topic or NATS subject it was published to
+    pub subject: String,
+    pub payload: Vec<u8>,
+    // Id to acknowledge an MQTT QoS 1 message by
+    pub id: Option<u16>,
+}
+
+// A broker whose messages are collated into a dataset, for producers that publish rather than POST (`[mqtt]`,
+// `[nats]`)
+#[derive(Debug, Clone)]
+pub enum Source {
+    Mqtt(MqttConfig),
+    Nats(NatsConfig),
+}
+
+impl Source {
+    // Every configured source
+    pub fn all(config: &Config) -> Vec<Source> {
+        let mqtt = config.mqtt.url.is_some().then(|| Source::Mqtt(config.mqtt.clone()));
+        let nats = config.nats.url.is_some().then(|| Source::Nats(config.nats.clone()));
+        mqtt.into_iter().chain(nats).collect()
+    }
+
+    // Also the `endpoint` label of the rows' ingest metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            Source::Mqtt(_) => "mqtt",
+            Source::Nats(_) => "nats",
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        match self {
+            Source::Mqtt(config) => config.url.as_deref(),
+            Source::Nats(config) => config.url.as_deref(),
+        }
+        .unwrap_or_default()
+    }
+
+    // Dataset the messages are collated into
+    pub fn dataset(&self) -> &str {
+        match self {
+            Source::Mqtt(config) => &config.dataset,
+            Source::Nats(config) => &config.dataset,
+        }
+    }
+
+    fn content_type(&self) -> Option<&str> {
+        match self {
+            Source::Mqtt(config) => config.content_type.as_deref(),
+            Source::Nats(config) => config.content_type.as_deref(),
+        }
+    }
+
+    // Connect and subscribe
+    pub async fn connect(&self) -> Result<Subscription, String> {
+        match self {
+            Source::Mqtt(config) => {
+                let address = parse_address(self.url(), "mqtt", 1883)?;
+                mqtt::Client::connect(&address, config).await.map(Subscription::Mqtt)
+            }
+            Source::Nats(config) => {
+                let address = parse_address(self.url(), "nats", 4222)?;
+                nats::Client::connect(&address, config).await.map(Subscription::Nats)
+            }
+        }
+    }
+
+    // Parse a message's payload the way `/collate` would a request body with the source's `content_type` (worked
+    // out from the payload if there isn't one)
+    pub fn read(&self, message: &Message) -> Result<DataFrame, String> {
+        let content_type = self.content_type().unwrap_or_else(|| sniff_content_type(&message.payload));
+        let headers = content_type_headers(content_type)?;
+
+        read_payload(&headers, std::io::Cursor::new(message.payload.as_slice()))
+    }
+}
+
+// A live subscription to a source's broker
+pub enum Subscription {
+    Mqtt(mqtt::Client),
+    Nats(nats::Client),
+}
+
+impl Subscription {
+    pub async fn next(&mut self) -> Result<Message, String> {
+        match self {
+            Subscription::Mqtt(client) => client.next().await,
+            Subscription::Nats(client) => client.next().await,
+        }
+    }
+
+    // Tell the broker a message has been dealt with, where it wants to know (MQTT QoS 1)
+    pub async fn ack(&mut self, message: &Message) -> Result<(), String> {
+        match self {
+            Subscription::Mqtt(client) => client.ack(message).await,
+            Subscription::Nats(_) => Ok(()),
+        }
+    }
+}
+
+// The host and port of a `<scheme>://host[:port]` URL
+pub fn parse_address(url: &str, scheme: &str, default_port: u16) -> Result<(String, u16), String> {
+    let rest = url
+        .strip_prefix(scheme)
+        .and_then(|rest| rest.strip_prefix("://"))
+        .ok_or_else(|| format!("expected a {}://host:port URL (TLS isn't part of this build)", scheme))?;
+    let address = rest.trim_end_matches('/');
+    if address.contains(['/', '@', '?']) {
+        return Err(String::from("the URL can't have a path, credentials, or parameters"));
+    }
+
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().map_err(|_| format!("invalid port {:?}", port))?),
+        None => (address, default_port),
+    };
+    if host.is_empty() {
+        return Err(String::from("the URL has no host"));
+    }
+
+    Ok((host.trim_start_matches('[').trim_end_matches(']').to_string(), port))
+}
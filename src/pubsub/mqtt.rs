@@ -0,0 +1,249 @@
+use std::{collections::VecDeque, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::Instant,
+};
+
+use super::Message;
+use crate::config::MqttConfig;
+
+// How long connecting (and subscribing) may take before the attempt counts as failed
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Packet types, in the high four bits of a packet's first byte
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+
+struct Packet {
+    kind: u8,
+    flags: u8,
+    body: Vec<u8>,
+}
+
+// A connection to an MQTT 3.1.1 broker, subscribed to the configured topics. Enough to receive QoS 0 and 1 messages;
+// there's no publishing, QoS 2, or TLS.
+pub struct Client {
+    stream: TcpStream,
+    // Bytes read from the broker that don't make up a whole packet yet
+    buffer: Vec<u8>,
+    keep_alive: Duration,
+    // When the last packet was sent, since the broker expects one at least every `keep_alive`
+    last_sent: Instant,
+    // Messages that arrived while subscribing: a persistent session's backlog can come in before the `SUBACK`
+    backlog: VecDeque<Message>,
+}
+
+impl Client {
+    pub async fn connect(address: &(String, u16), config: &MqttConfig) -> Result<Self, String> {
+        tokio::time::timeout(CONNECT_TIMEOUT, Client::subscribe(address, config))
+            .await
+            .map_err(|_| format!("Timed out connecting to {}:{}", address.0, address.1))?
+    }
+
+    async fn subscribe(address: &(String, u16), config: &MqttConfig) -> Result<Self, String> {
+        let stream = TcpStream::connect((address.0.as_str(), address.1))
+            .await
+            .map_err(|e| format!("Can't connect to {}:{}: {}", address.0, address.1, e))?;
+        let mut client = Client {
+            stream,
+            buffer: Vec::new(),
+            keep_alive: Duration::from_secs(config.keep_alive_secs.into()),
+            last_sent: Instant::now(),
+            backlog: VecDeque::new(),
+        };
+
+        let mut flags = if config.persistent_session { 0 } else { 0x02 };
+        let mut body = Vec::new();
+        push_str(&mut body, "MQTT");
+        // Protocol level 4 is MQTT 3.1.1
+        body.push(4);
+        let flags_at = body.len();
+        body.push(0);
+        body.extend_from_slice(&config.keep_alive_secs.to_be_bytes());
+        push_str(&mut body, &config.client_id);
+        if let Some(username) = &config.username {
+            flags |= 0x80;
+            push_str(&mut body, username);
+        }
+        if let Some(password) = &config.password {
+            flags |= 0x40;
+            push_str(&mut body, password);
+        }
+        body[flags_at] = flags;
+        client.send(CONNECT << 4, &body).await?;
+
+        let packet = client.receive().await?;
+        if packet.kind != CONNACK || packet.body.len() < 2 {
+            return Err(String::from("The broker didn't accept the connection"));
+        }
+        match packet.body[1] {
+            0 => {}
+            1 => return Err(String::from("The broker doesn't support MQTT 3.1.1")),
+            2 => return Err(format!("The broker rejected the client id {:?}", config.client_id)),
+            3 => return Err(String::from("The broker is unavailable")),
+            4 | 5 => return Err(String::from("The broker rejected the username or password")),
+            code => return Err(format!("The broker refused the connection (code {})", code)),
+        }
+
+        let mut body = Vec::new();
+        // Packet id
+        body.extend_from_slice(&1u16.to_be_bytes());
+        for topic in &config.topics {
+            push_str(&mut body, topic);
+            body.push(config.qos);
+        }
+        client.send(SUBSCRIBE << 4 | 0x02, &body).await?;
+
+        let packet = loop {
+            let packet = client.receive().await?;
+            match packet.kind {
+                PUBLISH => client.backlog.push_back(publish(packet)?),
+                SUBACK => break packet,
+                _ => return Err(String::from("The broker didn't acknowledge the subscription")),
+            }
+        };
+        if let Some(i) = packet.body.iter().skip(2).position(|code| *code == 0x80) {
+            return Err(format!("The broker refused the subscription to {:?}", config.topics[i]));
+        }
+
+        Ok(client)
+    }
+
+    // Wait for the next message, pinging the broker when nothing's been sent to it for a while, as it expects
+    pub async fn next(&mut self) -> Result<Message, String> {
+        if let Some(message) = self.backlog.pop_front() {
+            return Ok(message);
+        }
+
+        loop {
+            let packet = if self.keep_alive.is_zero() {
+                self.receive().await?
+            } else {
+                match tokio::time::timeout_at(self.last_sent + self.keep_alive / 2, self.receive()).await {
+                    Ok(packet) => packet?,
+                    Err(_) => {
+                        self.send(PINGREQ << 4, &[]).await?;
+                        continue;
+                    }
+                }
+            };
+
+            match packet.kind {
+                PUBLISH => return publish(packet),
+                PINGRESP => {}
+                kind => return Err(format!("Unexpected packet (type {}) from the broker", kind)),
+            }
+        }
+    }
+
+    // Acknowledge a QoS 1 message, so the broker doesn't send it again
+    pub async fn ack(&mut self, message: &Message) -> Result<(), String> {
+        match message.id {
+            Some(id) => self.send(PUBACK << 4, &id.to_be_bytes()).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn send(&mut self, header: u8, body: &[u8]) -> Result<(), String> {
+        let mut packet = vec![header];
+        // The remaining length, seven bits at a time
+        let mut length = body.len();
+        loop {
+            let byte = (length % 128) as u8;
+            length /= 128;
+            packet.push(if length > 0 { byte | 0x80 } else { byte });
+            if length == 0 {
+                break;
+            }
+        }
+        packet.extend_from_slice(body);
+
+        self.last_sent = Instant::now();
+        self.stream.write_all(&packet).await.map_err(|e| format!("Lost the connection to the broker: {}", e))
+    }
+
+    // Read the next whole packet. Only the buffer is added to while waiting, so this can be cancelled (by the
+    // keep-alive timeout) without losing anything.
+    async fn receive(&mut self) -> Result<Packet, String> {
+        loop {
+            if let Some(packet) = self.take_packet()? {
+                return Ok(packet);
+            }
+
+            let mut chunk = [0; 8192];
+            let read = self.stream.read(&mut chunk).await.map_err(|e| format!("Lost the connection to the broker: {}", e))?;
+            if read == 0 {
+                return Err(String::from("The broker closed the connection"));
+            }
+            self.buffer.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    fn take_packet(&mut self) -> Result<Option<Packet>, String> {
+        let mut length = 0;
+        let mut at = 1;
+        loop {
+            let Some(&byte) = self.buffer.get(at) else {
+                return Ok(None);
+            };
+            length += usize::from(byte & 0x7f) << (7 * (at - 1));
+            at += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            if at > 4 {
+                return Err(String::from("The broker sent a malformed packet"));
+            }
+        }
+        if self.buffer.len() < at + length {
+            return Ok(None);
+        }
+
+        let first = self.buffer[0];
+        let body = self.buffer[at..at + length].to_vec();
+        self.buffer.drain(..at + length);
+        Ok(Some(Packet {
+            kind: first >> 4,
+            flags: first & 0x0f,
+            body,
+        }))
+    }
+}
+
+fn publish(packet: Packet) -> Result<Message, String> {
+    let malformed = || String::from("The broker sent a malformed message");
+    let length = packet.body.get(..2).ok_or_else(malformed)?;
+    let length = usize::from(u16::from_be_bytes([length[0], length[1]]));
+    let topic = packet.body.get(2..2 + length).ok_or_else(malformed)?;
+    let topic = String::from_utf8_lossy(topic).into_owned();
+    let mut rest = &packet.body[2 + length..];
+
+    // QoS 1 and 2 messages have an id to acknowledge them by
+    let id = if (packet.flags >> 1) & 0x03 > 0 {
+        let id = rest.get(..2).ok_or_else(malformed)?;
+        let id = u16::from_be_bytes([id[0], id[1]]);
+        rest = &rest[2..];
+        Some(id)
+    } else {
+        None
+    };
+
+    Ok(Message {
+        subject: topic,
+        payload: rest.to_vec(),
+        id,
+    })
+}
+
+fn push_str(buffer: &mut Vec<u8>, value: &str) {
+    buffer.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(value.as_bytes());
+}
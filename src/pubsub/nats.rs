@@ -0,0 +1,166 @@
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use super::Message;
+use crate::config::NatsConfig;
+
+// How long connecting (and subscribing) may take before the attempt counts as failed
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A connection to a NATS server, subscribed to the configured subjects. Core NATS only: messages are delivered at
+// most once, and there's no JetStream, headers, or TLS.
+pub struct Client {
+    stream: TcpStream,
+    // Bytes read from the server that don't make up a whole line (or message) yet
+    buffer: Vec<u8>,
+}
+
+// What the server sent, one protocol line (and payload) at a time
+enum Op {
+    Info(Value),
+    Msg(Message),
+    Ping,
+    Pong,
+    Ok,
+    Err(String),
+}
+
+impl Client {
+    pub async fn connect(address: &(String, u16), config: &NatsConfig) -> Result<Self, String> {
+        tokio::time::timeout(CONNECT_TIMEOUT, Client::subscribe(address, config))
+            .await
+            .map_err(|_| format!("Timed out connecting to {}:{}", address.0, address.1))?
+    }
+
+    async fn subscribe(address: &(String, u16), config: &NatsConfig) -> Result<Self, String> {
+        let stream = TcpStream::connect((address.0.as_str(), address.1))
+            .await
+            .map_err(|e| format!("Can't connect to {}:{}: {}", address.0, address.1, e))?;
+        let mut client = Client {
+            stream,
+            buffer: Vec::new(),
+        };
+
+        let Op::Info(info) = client.receive().await? else {
+            return Err(String::from("The server didn't introduce itself"));
+        };
+        if info["tls_required"].as_bool() == Some(true) {
+            return Err(String::from("The server requires TLS, which isn't part of this build"));
+        }
+
+        let mut options = json!({
+            "verbose": false,
+            "pedantic": false,
+            "name": "data_collator",
+            "lang": "rust",
+            "version": env!("CARGO_PKG_VERSION"),
+            "protocol": 1,
+        });
+        if let Some(user) = &config.user {
+            options["user"] = json!(user);
+        }
+        if let Some(password) = &config.password {
+            options["pass"] = json!(password);
+        }
+        if let Some(token) = &config.token {
+            options["auth_token"] = json!(token);
+        }
+        let mut commands = format!("CONNECT {}\r\n", options);
+        for (sid, subject) in config.subjects.iter().enumerate() {
+            match &config.queue_group {
+                Some(group) => commands.push_str(&format!("SUB {} {} {}\r\n", subject, group, sid + 1)),
+                None => commands.push_str(&format!("SUB {} {}\r\n", subject, sid + 1)),
+            }
+        }
+        // The `PONG` to this says everything before it was accepted (a bad password gets an `-ERR` instead)
+        commands.push_str("PING\r\n");
+        client.send(&commands).await?;
+
+        loop {
+            match client.receive().await? {
+                Op::Pong => return Ok(client),
+                Op::Err(message) => return Err(format!("The server refused the connection: {}", message)),
+                Op::Ping => client.send("PONG\r\n").await?,
+                Op::Info(_) | Op::Ok | Op::Msg(_) => {}
+            }
+        }
+    }
+
+    // Wait for the next message, answering the server's pings meanwhile
+    pub async fn next(&mut self) -> Result<Message, String> {
+        loop {
+            match self.receive().await? {
+                Op::Msg(message) => return Ok(message),
+                Op::Ping => self.send("PONG\r\n").await?,
+                Op::Err(message) => return Err(format!("The server reported an error: {}", message)),
+                Op::Info(_) | Op::Pong | Op::Ok => {}
+            }
+        }
+    }
+
+    async fn send(&mut self, commands: &str) -> Result<(), String> {
+        self.stream
+            .write_all(commands.as_bytes())
+            .await
+            .map_err(|e| format!("Lost the connection to the server: {}", e))
+    }
+
+    async fn receive(&mut self) -> Result<Op, String> {
+        loop {
+            if let Some(op) = self.take_op()? {
+                return Ok(op);
+            }
+
+            let mut chunk = [0; 8192];
+            let read = self.stream.read(&mut chunk).await.map_err(|e| format!("Lost the connection to the server: {}", e))?;
+            if read == 0 {
+                return Err(String::from("The server closed the connection"));
+            }
+            self.buffer.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    fn take_op(&mut self) -> Result<Option<Op>, String> {
+        let Some(end) = self.buffer.windows(2).position(|window| window == b"\r\n") else {
+            return Ok(None);
+        };
+        let line = String::from_utf8_lossy(&self.buffer[..end]).into_owned();
+        let (verb, args) = line.split_once(' ').unwrap_or((&line, ""));
+
+        let op = match verb.to_ascii_uppercase().as_str() {
+            // `MSG <subject> <sid> [reply-to] <#bytes>`, then the payload and another CRLF
+            "MSG" => {
+                let args = args.split_whitespace().collect::<Vec<_>>();
+                let (Some(subject), Some(size)) = (args.first(), args.last().and_then(|size| size.parse::<usize>().ok()))
+                else {
+                    return Err(format!("The server sent a malformed message: {:?}", line));
+                };
+                let start = end + 2;
+                if self.buffer.len() < start + size + 2 {
+                    return Ok(None);
+                }
+                let message = Message {
+                    subject: subject.to_string(),
+                    payload: self.buffer[start..start + size].to_vec(),
+                    id: None,
+                };
+                self.buffer.drain(..start + size + 2);
+                return Ok(Some(Op::Msg(message)));
+            }
+            "INFO" => Op::Info(serde_json::from_str(args).map_err(|e| format!("The server sent malformed INFO: {}", e))?),
+            "PING" => Op::Ping,
+            "PONG" => Op::Pong,
+            "+OK" => Op::Ok,
+            "-ERR" => Op::Err(args.trim().trim_matches('\'').to_string()),
+            _ => return Err(format!("Unexpected line from the server: {:?}", line)),
+        };
+        self.buffer.drain(..end + 2);
+
+        Ok(Some(op))
+    }
+}
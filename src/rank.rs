@@ -0,0 +1,132 @@
+use polars::prelude::*;
+
+use crate::aggregate::AggregateOperation;
+
+// Name of the column `value_counts` puts each value's count in
+const COUNT_COLUMN: &str = "count";
+
+// The `k` most frequent values of a column, most frequent first, with how many rows have each. Nulls are counted as a
+// value of their own. Values with the same count are ordered by value, so the result is the same on every request.
+// Only the counted column is read.
+pub fn value_counts(mut rows: LazyFrame, column: &str, k: usize) -> Result<DataFrame, String> {
+    let schema = schema_of(&mut rows)?;
+    check_column(&schema, column)?;
+    // Don't clash with the column being counted
+    let count_column = match column {
+        COUNT_COLUMN => format!("{}_{}", column, COUNT_COLUMN),
+        _ => String::from(COUNT_COLUMN),
+    };
+
+    rows.group_by([col(column)])
+        .agg([len().cast(DataType::UInt64).alias(count_column.as_str())])
+        .sort_by_exprs(
+            [col(count_column.as_str()), col(column)],
+            SortMultipleOptions::default()
+                .with_order_descending_multi([true, false])
+                .with_nulls_last(true),
+        )
+        .limit(k as IdxSize)
+        .collect()
+        .map_err(|e| e.to_string())
+}
+
+// How many distinct values `columns` have between them (each combination of values counting once), and if `values` is
+// set, the first that many of them in order. Nulls are counted as a value of their own. Only the listed columns are
+// read.
+pub fn distinct(
+    mut rows: LazyFrame,
+    columns: &[String],
+    values: Option<usize>,
+) -> Result<(usize, Option<DataFrame>), String> {
+    let schema = schema_of(&mut rows)?;
+    for column in columns {
+        check_column(&schema, column)?;
+    }
+
+    let exprs: Vec<Expr> = columns.iter().map(|column| col(column.as_str())).collect();
+    let unique = rows.select(exprs.clone()).unique(None, UniqueKeepStrategy::Any);
+    let counted = unique.clone().select([len().cast(DataType::UInt64)]).collect().map_err(|e| e.to_string())?;
+    let count = counted.get_columns()[0].u64().map_err(|e| e.to_string())?.get(0).unwrap_or(0) as usize;
+
+    let values = match values {
+        Some(limit) => Some(
+            unique
+                .sort_by_exprs(exprs, SortMultipleOptions::default().with_nulls_last(true))
+                .limit(limit as IdxSize)
+                .collect()
+                .map_err(|e| e.to_string())?,
+        ),
+        None => None,
+    };
+
+    Ok((count, values))
+}
+
+// The `k` rows with the highest (or with `descending` off, lowest) values in the `by` column. Rows where it's null are
+// left out. Sorting with a limit only keeps `k` rows as it goes.
+pub fn top_rows(mut rows: LazyFrame, by: &str, k: usize, descending: bool) -> Result<DataFrame, String> {
+    let schema = schema_of(&mut rows)?;
+    check_metric(&schema, by)?;
+
+    rows.filter(col(by).is_not_null())
+        .sort_by_exprs(
+            [col(by)],
+            SortMultipleOptions::default().with_order_descending(descending).with_maintain_order(true),
+        )
+        .limit(k as IdxSize)
+        .collect()
+        .map_err(|e| e.to_string())
+}
+
+// The `k` values of the `group` column whose rows have the highest (or lowest) `by` values once reduced with
+// `operation`, e.g. the hosts with the most failures in total. Returns the group column and the reduced `by` column.
+pub fn top_groups(
+    mut rows: LazyFrame,
+    group: &str,
+    by: &str,
+    operation: AggregateOperation,
+    k: usize,
+    descending: bool,
+) -> Result<DataFrame, String> {
+    let schema = schema_of(&mut rows)?;
+    check_column(&schema, group)?;
+    check_metric(&schema, by)?;
+    if group == by {
+        return Err(format!("Column {:?} can't be both the group and the metric", by));
+    }
+
+    rows.group_by([col(group)])
+        .agg([operation.apply(col(by)).alias(by)])
+        .filter(col(by).is_not_null())
+        .sort_by_exprs(
+            [col(by), col(group)],
+            SortMultipleOptions::default()
+                .with_order_descending_multi([descending, false])
+                .with_nulls_last(true),
+        )
+        .limit(k as IdxSize)
+        .collect()
+        .map_err(|e| e.to_string())
+}
+
+fn schema_of(rows: &mut LazyFrame) -> Result<SchemaRef, String> {
+    rows.collect_schema().map_err(|e| e.to_string())
+}
+
+fn check_column(schema: &Schema, column: &str) -> Result<(), String> {
+    match schema.contains(column) {
+        true => Ok(()),
+        false => Err(format!("Column {:?} doesn't exist", column)),
+    }
+}
+
+// Metrics are ranked by value, so they have to be numeric (or at least orderable like one)
+fn check_metric(schema: &Schema, column: &str) -> Result<(), String> {
+    check_column(schema, column)?;
+    let dtype = schema.get(column).cloned().unwrap_or(DataType::Null);
+    if dtype.is_primitive_numeric() || dtype.is_temporal() {
+        Ok(())
+    } else {
+        Err(format!("Column {:?} is {}, not a number", column, dtype))
+    }
+}
@@ -0,0 +1,153 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    http::{header, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{api::unversioned, auth, config::RateLimitConfig, dataset::AppState, error::AppError};
+
+// Once this many clients are tracked, buckets that have refilled completely are dropped
+const MAX_IDLE_BUCKETS: usize = 10_000;
+
+// Routes health checks hit, which are never limited
+const PROBES: &[&str] = &["/", "/healthz", "/livez", "/readyz"];
+
+// Token bucket for one client: `burst` requests at once, refilled at `requests_per_second`
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// Per-client token buckets
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    // Take a token for `client`, or return how many seconds until one is available
+    fn acquire(&self, config: &RateLimitConfig, client: &str) -> Result<(), u64> {
+        let now = Instant::now();
+        let rate = config.requests_per_second;
+        let burst = config.burst.max(1) as f64;
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_IDLE_BUCKETS {
+            buckets.retain(|_, bucket| bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst);
+        }
+
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / rate).ceil().max(1.0) as u64)
+        }
+    }
+}
+
+// Middleware limiting how fast each client can send requests. Clients are told apart by API key when they send one
+// that is configured (so several agents behind one NAT don't share a limit), and by IP address otherwise. `GET /` and
+// the probes (under `/v1` too) aren't limited, so health checks keep working.
+pub async fn limit_rate(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    let config = &state.config.rate_limit;
+    let public = request.extensions().get::<MatchedPath>().is_some_and(|path| PROBES.contains(&unversioned(path.as_str())));
+    if !config.enabled() || public {
+        return next.run(request).await;
+    }
+
+    let client = client_id(&state, &request);
+    if let Err(retry_after) = state.rate_limiter.acquire(config, &client) {
+        let error = AppError::TooManyRequests(format!(
+            "Too many requests; the limit is {} per second (bursts of {}). Retry in {} s.",
+            config.requests_per_second, config.burst, retry_after
+        ));
+        let mut response = error.into_response();
+        response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        return response;
+    }
+
+    next.run(request).await
+}
+
+// Name the bucket a request counts against
+fn client_id(state: &AppState, request: &Request) -> String {
+    let key = auth::request_key(request.headers());
+    // Only keys the service knows count, or a client could dodge its limit by making up a new key for each request
+    if let Some(key) = key.filter(|key| state.config.auth.scope_of(key).is_some()) {
+        return format!("key:{}", key);
+    }
+
+    let peer = request.extensions().get::<ConnectInfo<SocketAddr>>().map(|info| info.0);
+    match client_ip(state, request.headers(), peer) {
+        Some(ip) => format!("ip:{}", ip),
+        None => String::from("unknown"),
+    }
+}
+
+// The address a request came from. Behind a reverse proxy every request comes from the proxy, so when it's trusted the
+// address it saw is used instead.
+pub fn client_ip(state: &AppState, headers: &HeaderMap, peer: Option<SocketAddr>) -> Option<IpAddr> {
+    if state.config.rate_limit.trust_forwarded_for
+        && let Some(forwarded) = forwarded_for(headers)
+    {
+        return Some(forwarded);
+    }
+
+    peer.map(|peer| peer.ip())
+}
+
+// The address the proxy in front of the service saw a request come from: the last `X-Forwarded-For` entry, which
+// it added. The ones before it are whatever the client sent, so they can't be trusted.
+fn forwarded_for(headers: &HeaderMap) -> Option<IpAddr> {
+    let entries = headers.get_all("x-forwarded-for").iter().filter_map(|value| value.to_str().ok());
+    entries.flat_map(|value| value.split(',')).last()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(forwarded: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in forwarded {
+            headers.append("x-forwarded-for", HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn forwarded_for_takes_the_proxys_entry() {
+        // A client can send any X-Forwarded-For it likes, and the proxy adds the address it saw at the end
+        assert_eq!(forwarded_for(&headers(&["203.0.113.7, 198.51.100.2"])), Some("198.51.100.2".parse().unwrap()));
+        assert_eq!(forwarded_for(&headers(&["203.0.113.7", "198.51.100.2"])), Some("198.51.100.2".parse().unwrap()));
+        assert_eq!(forwarded_for(&headers(&[" 2001:db8::1 "])), Some("2001:db8::1".parse().unwrap()));
+
+        assert_eq!(forwarded_for(&headers(&["198.51.100.2, unknown"])), None);
+        assert_eq!(forwarded_for(&headers(&[])), None);
+    }
+
+    #[test]
+    fn keys_are_read_the_way_auth_reads_them() {
+        let mut headers = HeaderMap::new();
+        for (value, key) in [("Bearer abc", Some("abc")), ("Token abc ", Some("abc")), ("Basic abc", None)] {
+            headers.insert(header::AUTHORIZATION, HeaderValue::from_static(value));
+            assert_eq!(auth::request_key(&headers), key, "{}", value);
+        }
+    }
+}
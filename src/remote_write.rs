@@ -0,0 +1,179 @@
+// Prometheus remote write (1.0): a Snappy-compressed protobuf `WriteRequest` of time series, each a set of labels and
+// the samples taken of it. Every sample becomes a row, with the metric's name, every label as a column of its own, the
+// value, and the time it was taken.
+
+use indexmap::IndexMap;
+use polars::prelude::*;
+
+use crate::line_protocol::{TIME_COLUMN, TIME_DTYPE};
+
+// Columns every row has, so labels can't be named after them
+const METRIC_COLUMN: &str = "metric";
+const VALUE_COLUMN: &str = "value";
+
+// The label holding a series' metric name
+const NAME_LABEL: &str = "__name__";
+
+// The NaN Prometheus sends to say a series has stopped being reported, rather than as a value
+const STALE_MARKER: u64 = 0x7ff0_0000_0000_0002;
+
+// A field of a protobuf message, by its wire type
+enum Field<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32,
+}
+
+// Reads a protobuf message's fields in order. Only what remote write uses is covered: no groups, which are deprecated.
+struct Message<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Message<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Message { data }
+    }
+
+    // The next field's number and value
+    fn next(&mut self) -> Result<Option<(u64, Field<'a>)>, String> {
+        if self.data.is_empty() {
+            return Ok(None);
+        }
+
+        let key = self.varint()?;
+        let field = match key & 0x07 {
+            0 => Field::Varint(self.varint()?),
+            1 => Field::Fixed64(u64::from_le_bytes(self.take(8)?.try_into().expect("eight bytes"))),
+            2 => {
+                let len = usize::try_from(self.varint()?).map_err(|_| String::from("a field is too long"))?;
+                Field::Bytes(self.take(len)?)
+            }
+            5 => {
+                self.take(4)?;
+                Field::Fixed32
+            }
+            kind => return Err(format!("unsupported protobuf wire type {}", kind)),
+        };
+
+        Ok(Some((key >> 3, field)))
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self.data.split_first().ok_or_else(|| String::from("the message is cut short"))?;
+            self.data = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(String::from("a number in the message is malformed"))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() < len {
+            return Err(String::from("the message is cut short"));
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+}
+
+fn string(bytes: &[u8]) -> Result<String, String> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| String::from("a label isn't valid UTF-8"))
+}
+
+// The rows of a decompressed `WriteRequest`. Stale markers are left out, as are native histograms, exemplars, and
+// metadata. `None` when there's no sample left.
+pub fn parse(body: &[u8]) -> Result<Option<DataFrame>, String> {
+    let mut metrics: Vec<Option<String>> = Vec::new();
+    let mut values = Vec::new();
+    let mut times = Vec::new();
+    let mut labels: IndexMap<String, Vec<Option<String>>> = IndexMap::new();
+
+    // WriteRequest { repeated TimeSeries timeseries = 1; ... }
+    let mut request = Message::new(body);
+    while let Some((number, field)) = request.next()? {
+        let (1, Field::Bytes(series)) = (number, field) else {
+            continue;
+        };
+
+        // TimeSeries { repeated Label labels = 1; repeated Sample samples = 2; ... }
+        let mut metric = None;
+        let mut series_labels = Vec::new();
+        let mut samples = Vec::new();
+        let mut fields = Message::new(series);
+        while let Some((number, field)) = fields.next()? {
+            match (number, field) {
+                // Label { string name = 1; string value = 2; }
+                (1, Field::Bytes(label)) => {
+                    let (mut name, mut value) = (String::new(), String::new());
+                    let mut fields = Message::new(label);
+                    while let Some((number, field)) = fields.next()? {
+                        match (number, field) {
+                            (1, Field::Bytes(bytes)) => name = string(bytes)?,
+                            (2, Field::Bytes(bytes)) => value = string(bytes)?,
+                            _ => {}
+                        }
+                    }
+                    if name == NAME_LABEL {
+                        metric = Some(value);
+                    } else if [METRIC_COLUMN, VALUE_COLUMN, TIME_COLUMN].contains(&name.as_str()) {
+                        return Err(format!("{:?} can't be used as a label name", name));
+                    } else {
+                        series_labels.push((name, value));
+                    }
+                }
+                // Sample { double value = 1; int64 timestamp = 2; }, the timestamp in milliseconds
+                (2, Field::Bytes(sample)) => {
+                    let (mut value, mut timestamp) = (0, 0);
+                    let mut fields = Message::new(sample);
+                    while let Some((number, field)) = fields.next()? {
+                        match (number, field) {
+                            (1, Field::Fixed64(bits)) => value = bits,
+                            (2, Field::Varint(raw)) => timestamp = raw as i64,
+                            _ => {}
+                        }
+                    }
+                    if value != STALE_MARKER {
+                        samples.push((f64::from_bits(value), timestamp));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for (value, timestamp) in samples {
+            let row = metrics.len();
+            for (name, label) in &series_labels {
+                let column = labels.entry(name.clone()).or_default();
+                if column.len() > row {
+                    return Err(format!("the label {:?} is given more than once", name));
+                }
+                column.resize(row, None);
+                column.push(Some(label.clone()));
+            }
+            let time = timestamp.checked_mul(1_000_000).ok_or_else(|| String::from("a timestamp is out of range"))?;
+            metrics.push(metric.clone());
+            values.push(value);
+            times.push(time);
+        }
+    }
+    if metrics.is_empty() {
+        return Ok(None);
+    }
+
+    let rows = metrics.len();
+    let mut df = vec![Column::new(PlSmallStr::from(METRIC_COLUMN), metrics)];
+    for (name, mut column) in labels {
+        column.resize(rows, None);
+        df.push(Column::new(PlSmallStr::from(name), column));
+    }
+    df.push(Column::new(PlSmallStr::from(VALUE_COLUMN), values));
+    df.push(Column::new(PlSmallStr::from(TIME_COLUMN), times).cast(&TIME_DTYPE).map_err(|e| e.to_string())?);
+
+    DataFrame::new(df).map(Some).map_err(|e| e.to_string())
+}
@@ -0,0 +1,692 @@
+// Replication (`[replication]`): active-passive high availability, so losing a collator mid-campaign doesn't lose what
+// it hadn't flushed. The primary streams every change it logs (what the write-ahead log records: ingests, deletes,
+// resets, ...) to its replicas, in order, and each replica applies them the way the primary did. A replica turns away
+// changes of its own until it's promoted (`POST /replication/promote`). `GET /replication/status` says how far behind
+// each replica is.
+//
+// Changes are numbered in a stream named after the primary. With a write-ahead log, the numbers are the log's, so a
+// replica that falls further behind than the changes kept in memory catches up from the log (across restarts of the
+// primary too); without one, every start of the primary is a new stream, numbered from 1. A replica notes the change
+// each of its own records is a copy of, so it picks up where it left off after a restart.
+
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use polars::prelude::*;
+use serde::Deserialize;
+use tokio::sync::{watch, Mutex as AsyncMutex};
+
+use crate::{
+    collator::{log_record, persist_result},
+    config::Config,
+    dataset::{validate_dataset_name, AppState},
+    error::AppError,
+    http::{self, Endpoint},
+    metrics::Metrics,
+    partition,
+    persist::{self, WriteMode},
+    sink::{backoff, MAX_BACKOFF},
+    types::{FollowedStream, ReplicaStatus, ReplicationAck, ReplicationStatusResponse, Status},
+    wal::{self, Operation, Record, Replicated},
+};
+
+// Headers the primary sends its stream's name and latest change in
+pub const STREAM_HEADER: &str = "x-replication-stream";
+pub const LATEST_HEADER: &str = "x-replication-latest";
+
+// A batch of changes: records as the write-ahead log holds them, one after another
+pub const RECORDS_CONTENT_TYPE: &str = "application/vnd.data-collator.wal";
+
+// The most bytes of changes sent to a replica in one request (a single bigger change is sent on its own)
+const MAX_BATCH_BYTES: usize = 64 * 1024 * 1024;
+
+// Whether a collator takes changes from clients, or only from its primary
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Role {
+    #[default]
+    Primary,
+    Replica,
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "primary" => Ok(Role::Primary),
+            "replica" => Ok(Role::Replica),
+            other => Err(format!("Unsupported replication role {:?} (expected one of: primary, replica)", other)),
+        }
+    }
+}
+
+impl TryFrom<String> for Role {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+// Where the write-ahead log left off, for `Replication::resume`
+#[derive(Debug, Default)]
+pub struct Resumed {
+    seq: Option<u64>,
+    replicated: Option<Replicated>,
+}
+
+impl Resumed {
+    pub fn of(records: &[Record]) -> Self {
+        Resumed {
+            seq: records.last().map(|record| record.seq),
+            replicated: records.iter().rev().find_map(|record| record.replicated.clone()),
+        }
+    }
+}
+
+// A change kept for the replicas that don't have it yet
+#[derive(Debug)]
+struct Change {
+    seq: u64,
+    at: DateTime<Utc>,
+    rows: usize,
+    record: Arc<Vec<u8>>,
+}
+
+// The latest changes, in order
+#[derive(Debug, Default)]
+struct Backlog {
+    changes: VecDeque<Change>,
+    // The number of the latest change
+    latest: u64,
+}
+
+// What's known about a replica, for `GET /replication/status`
+#[derive(Debug, Clone, Default)]
+struct Progress {
+    // The last change it has applied, once it's said
+    acked: Option<u64>,
+    state: &'static str,
+    last_ack_at: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+}
+
+#[derive(Debug)]
+struct Replica {
+    url: String,
+    progress: Arc<Mutex<Progress>>,
+}
+
+// How far a replica has got in its primary's stream
+#[derive(Debug, Default)]
+struct Applied {
+    stream: Option<String>,
+    seq: u64,
+    // The primary's latest change, as of the last batch it sent
+    latest: u64,
+    applied_at: Option<DateTime<Utc>>,
+    // When the last change applied was made on the primary
+    made_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+pub struct Replication {
+    // A replica, until it's promoted
+    passive: AtomicBool,
+    // The name of this collator's own stream of changes
+    stream: String,
+    backlog: Arc<Mutex<Backlog>>,
+    // How many changes the backlog keeps
+    capacity: usize,
+    // Wakes the tasks sending to the replicas when there's a new change
+    latest: watch::Sender<u64>,
+    replicas: Vec<Replica>,
+    applied: AsyncMutex<Applied>,
+}
+
+impl Replication {
+    // Start streaming to the configured replicas (if any). Must be called from within the Tokio runtime.
+    pub fn spawn(config: &Config, metrics: Arc<Metrics>) -> Self {
+        let replication = &config.replication;
+        let stream = match &config.storage.wal {
+            Some(_) => replication.node(),
+            None => format!("{}@{}", replication.node(), Utc::now().format("%Y%m%dT%H%M%S%.3fZ")),
+        };
+        let backlog = Arc::new(Mutex::new(Backlog::default()));
+        let (latest, _) = watch::channel(0);
+
+        let replicas = replication
+            .replicas
+            .iter()
+            .map(|url| {
+                let progress = Arc::new(Mutex::new(Progress {
+                    state: "connecting",
+                    ..Default::default()
+                }));
+                let follower = Follower {
+                    url: url.clone(),
+                    endpoint: Endpoint::parse(url).expect("the URLs are checked by Config::validate"),
+                    api_key: replication.api_key.clone(),
+                    stream: stream.clone(),
+                    wal: config.storage.wal.clone(),
+                    backlog: backlog.clone(),
+                    latest: latest.subscribe(),
+                    capacity: replication.backlog,
+                    batch_records: replication.batch_records,
+                    retry_backoff_ms: replication.retry_backoff_ms,
+                    progress: progress.clone(),
+                    metrics: metrics.clone(),
+                };
+                tokio::spawn(follower.run());
+                Replica {
+                    url: url.clone(),
+                    progress,
+                }
+            })
+            .collect();
+
+        Replication {
+            passive: AtomicBool::new(replication.role == Role::Replica),
+            stream,
+            backlog,
+            capacity: replication.backlog,
+            latest,
+            replicas,
+            applied: AsyncMutex::new(Applied::default()),
+        }
+    }
+
+    // Whether it only takes changes from its primary
+    pub fn is_replica(&self) -> bool {
+        self.passive.load(Ordering::SeqCst)
+    }
+
+    // Pick up where the last run left off: its changes are numbered on from the write-ahead log's, and as a replica,
+    // it's got as far as the last change of its primary's stream the log has a copy of
+    pub fn resume(&self, resumed: Resumed) {
+        if let Some(seq) = resumed.seq {
+            self.backlog.lock().unwrap().latest = seq;
+            self.latest.send_replace(seq);
+        }
+        if let Some(replicated) = resumed.replicated {
+            let mut applied = self.applied.try_lock().expect("replication is not shared yet");
+            applied.latest = replicated.seq;
+            applied.seq = replicated.seq;
+            applied.stream = Some(replicated.stream);
+        }
+    }
+
+    // Queue a change for the replicas: the record the write-ahead log wrote for it (with its number there), or with no
+    // log, the change itself, to be numbered here
+    pub fn submit(&self, logged: Option<(u64, Vec<u8>)>, dataset: &str, operation: &Operation, df: &DataFrame) {
+        if self.replicas.is_empty() {
+            return;
+        }
+        let at = Utc::now();
+        let parts = match logged {
+            Some(_) => None,
+            None => match wal::encode_parts(dataset, operation, at, None, df) {
+                Ok(parts) => Some(parts),
+                Err(e) => return error!("Can't send a change of {:?} to the replicas: {}", dataset, e),
+            },
+        };
+
+        let mut backlog = self.backlog.lock().unwrap();
+        let (seq, record) = match (logged, parts) {
+            (Some(logged), _) => logged,
+            (None, Some((header, body))) => {
+                (backlog.latest + 1, wal::encode_record(backlog.latest + 1, &header, &body))
+            }
+            (None, None) => unreachable!("a change is either logged or encoded"),
+        };
+        // Changes logged at the same time can get here in either order
+        let position = backlog.changes.iter().rposition(|change| change.seq < seq).map_or(0, |index| index + 1);
+        let change = Change {
+            seq,
+            at,
+            rows: df.height(),
+            record: Arc::new(record),
+        };
+        backlog.changes.insert(position, change);
+        backlog.latest = backlog.latest.max(seq);
+        while backlog.changes.len() > self.capacity {
+            backlog.changes.pop_front();
+        }
+        let latest = backlog.latest;
+        drop(backlog);
+        self.latest.send_replace(latest);
+    }
+
+    // Stop being a replica, and take changes from clients. Returns whether it was one.
+    pub fn promote(&self) -> bool {
+        let promoted = self.passive.swap(false, Ordering::SeqCst);
+        if promoted {
+            info!("Promoted to primary: this collator takes changes from clients now");
+        }
+        promoted
+    }
+
+    // What `GET /replication/status` reports
+    pub async fn status(&self) -> ReplicationStatusResponse {
+        let now = Utc::now();
+        let (replicas, latest) = {
+            let backlog = self.backlog.lock().unwrap();
+            let replicas = self
+                .replicas
+                .iter()
+                .map(|replica| {
+                    let progress = replica.progress.lock().unwrap().clone();
+                    let lag = progress.acked.map(|acked| backlog.latest.saturating_sub(acked));
+                    // How long the oldest change the replica doesn't have has been waiting
+                    let waiting =
+                        progress.acked.and_then(|acked| backlog.changes.iter().find(|change| change.seq > acked));
+                    ReplicaStatus {
+                        url: replica.url.clone(),
+                        state: progress.state.to_string(),
+                        acked_seq: progress.acked,
+                        lag_changes: lag,
+                        lag_seconds: match lag {
+                            Some(0) => Some(0.0),
+                            _ => waiting.map(|change| seconds_since(change.at, now)),
+                        },
+                        last_ack_at: progress.last_ack_at.map(|at| at.to_rfc3339()),
+                        last_error: progress.last_error,
+                    }
+                })
+                .collect();
+            (replicas, backlog.latest)
+        };
+
+        let applied = self.applied.lock().await;
+        let primary = applied.stream.as_ref().map(|stream| {
+            let lag = applied.latest.saturating_sub(applied.seq);
+            FollowedStream {
+                stream: stream.clone(),
+                applied_seq: applied.seq,
+                latest_seq: applied.latest,
+                lag_changes: lag,
+                lag_seconds: match lag {
+                    0 => Some(0.0),
+                    _ => applied.made_at.map(|at| seconds_since(at, now)),
+                },
+                last_applied_at: applied.applied_at.map(|at| at.to_rfc3339()),
+            }
+        });
+
+        ReplicationStatusResponse {
+            status: Status::Success,
+            role: String::from(if self.is_replica() { "replica" } else { "primary" }),
+            stream: self.stream.clone(),
+            latest_seq: (!self.replicas.is_empty()).then_some(latest),
+            replicas,
+            primary,
+        }
+    }
+}
+
+fn seconds_since(at: DateTime<Utc>, now: DateTime<Utc>) -> f64 {
+    (now - at).num_milliseconds().max(0) as f64 / 1000.0
+}
+
+// Apply a batch of changes from a primary's stream, in order, returning how far this replica has got in it. Changes
+// it already has are skipped; a batch that doesn't follow on from the last change applied (or, from a stream it hasn't
+// followed before, start at the first) is left alone, and the primary sends what's missing.
+pub async fn apply(state: &AppState, stream: &str, latest: u64, body: &[u8]) -> Result<ReplicationAck, AppError> {
+    if !state.replication.is_replica() {
+        return Err(AppError::Forbidden(String::from(
+            "This collator isn't a replica (or it's been promoted), so it doesn't take changes from a primary",
+        )));
+    }
+    if stream.is_empty() {
+        return Err(AppError::BadRequest(format!("The {} header is required", STREAM_HEADER)));
+    }
+    let records = wal::decode(body).map_err(AppError::BadRequest)?;
+
+    let mut applied = state.replication.applied.lock().await;
+    if applied.stream.as_deref() != Some(stream) {
+        if records.first().is_none_or(|record| record.seq != 1) {
+            return Ok(ack(stream, 0));
+        }
+        match &applied.stream {
+            Some(previous) => warn!("Following the changes of {:?} now, instead of {:?}", stream, previous),
+            None => info!("Following the changes of {:?}", stream),
+        }
+        *applied = Applied {
+            stream: Some(stream.to_string()),
+            ..Default::default()
+        };
+    }
+
+    applied.latest = applied.latest.max(latest);
+    for record in records {
+        if record.seq <= applied.seq {
+            continue;
+        }
+        if record.seq != applied.seq + 1 {
+            break;
+        }
+        let replicated = Replicated {
+            stream: stream.to_string(),
+            seq: record.seq,
+        };
+        apply_record(state, &record, &replicated).await?;
+        applied.seq = record.seq;
+        applied.latest = applied.latest.max(record.seq);
+        applied.applied_at = Some(Utc::now());
+        applied.made_at = record.at;
+    }
+    Ok(ack(stream, applied.seq))
+}
+
+fn ack(stream: &str, applied_seq: u64) -> ReplicationAck {
+    ReplicationAck {
+        status: Status::Success,
+        stream: stream.to_string(),
+        applied_seq,
+    }
+}
+
+// Log and apply a change, and write it to the output file, as the primary did. A change that doesn't apply (the
+// replica's datasets have drifted from the primary's) is skipped, the way replaying the write-ahead log skips it.
+async fn apply_record(state: &AppState, record: &Record, replicated: &Replicated) -> Result<(), AppError> {
+    let name = record.dataset.as_str();
+    if let Err(e) = validate_dataset_name(name) {
+        warn!("Skipping change {} of {:?}: {}", replicated.seq, replicated.stream, e);
+        return Ok(());
+    }
+    let dataset = state.dataset(name).await;
+    let mut dataset = dataset.write().await;
+
+    wal::unspill_for(&mut dataset, record).map_err(AppError::Internal)?;
+    let schema = dataset.df.as_ref().map(|df| df.schema().clone());
+
+    log_record(state, name, &record.operation, &record.df, Some(replicated)).await?;
+    let kept = dataset.df.as_ref().map_or(0, DataFrame::height);
+    let storage = &state.config.storage;
+    if let Err(e) = wal::apply(&mut dataset, record, storage.snapshots_dir.as_deref(), storage.rollback_batches) {
+        warn!("Skipping change {} of {:?} to {:?}: {}", replicated.seq, replicated.stream, name, e);
+        return Ok(());
+    }
+    if !record.operation.changes_rows() {
+        return Ok(());
+    }
+
+    // Followers get the rows that were added, and the output file is brought up to date
+    let rows = dataset.df.clone().unwrap_or_default();
+    let added = match &record.operation {
+        Operation::Collate { .. } => rows.slice(kept as i64, rows.height().saturating_sub(kept)),
+        operation if operation.ingest_name().is_some() => record.df.clone(),
+        _ => DataFrame::empty(),
+    };
+    state.events.changed(name, record.operation.name(), &added, rows.height());
+    let reshaped = schema.as_ref().is_some_and(|schema| schema != rows.schema());
+
+    // The output file (and partitions) start over, as they did on the primary
+    if let Operation::Reset = record.operation {
+        state.writer.flush().await.map_err(AppError::Internal)?;
+        let mut removed = dataset.output_file.as_deref().map_or(Ok(()), persist::remove_output);
+        if let Some(partitions) = &dataset.partitions {
+            removed = removed.and_then(|()| partition::remove(&partitions.dir));
+        }
+        if let Err(e) = removed {
+            let (seq, stream) = (replicated.seq, &replicated.stream);
+            error!("Applied change {} of {:?} to {:?}, but can't start its output over: {}", seq, stream, name, e);
+        }
+        return Ok(());
+    }
+
+    let write = match (&record.operation, storage.write_mode) {
+        (Operation::Collate { .. }, WriteMode::Append) if !reshaped => Some((WriteMode::Append, added)),
+        (operation, WriteMode::Overwrite) if operation.ingest_name().is_some() => {
+            Some((WriteMode::Overwrite, record.df.clone()))
+        }
+        (_, WriteMode::Overwrite) => None,
+        _ => Some((WriteMode::Snapshot, rows)),
+    };
+    if let Some((mode, batch)) = write
+        && let Err(e) = persist_result(&state.writer, &dataset, mode, batch).await
+    {
+        error!("Applied change {} of {:?} to {:?}, but: {}", replicated.seq, replicated.stream, name, e.message());
+    }
+    Ok(())
+}
+
+// Sends a primary's changes to one of its replicas, in order, for as long as the service runs
+struct Follower {
+    url: String,
+    endpoint: Endpoint,
+    api_key: Option<String>,
+    stream: String,
+    // Where changes that have left the backlog are read back from
+    wal: Option<PathBuf>,
+    backlog: Arc<Mutex<Backlog>>,
+    latest: watch::Receiver<u64>,
+    capacity: usize,
+    batch_records: usize,
+    retry_backoff_ms: u64,
+    progress: Arc<Mutex<Progress>>,
+    metrics: Arc<Metrics>,
+}
+
+#[derive(Debug)]
+enum SendError {
+    // The replica couldn't be reached, or had a problem of its own (a `5xx`, or too many requests)
+    Transient(String),
+    // The replica turned the batch away (the API key isn't accepted, it's been promoted, ...), or applied none of it
+    Rejected(String),
+}
+
+// Changes to send in one request
+#[derive(Debug, Default)]
+struct Batch {
+    // The number of the last one, or 0 when asking the replica where it is
+    last: u64,
+    rows: usize,
+    body: Vec<u8>,
+}
+
+impl Follower {
+    async fn run(mut self) {
+        // Changes read back from the write-ahead log for a replica that's behind the backlog
+        let mut catch_up: VecDeque<(u64, usize, Vec<u8>)> = VecDeque::new();
+        let mut acked: Option<u64> = None;
+        let mut attempt = 0;
+
+        loop {
+            // First ask the replica where it is (with an empty batch), then send what it doesn't have
+            let batch = match acked {
+                None => Batch::default(),
+                Some(after) => match self.next_batch(after, &mut catch_up).await {
+                    Ok(Some(batch)) => batch,
+                    Ok(None) => {
+                        self.set_state("streaming", None);
+                        // Wait for a change (the sender, in `AppState`, lives as long as the service)
+                        if self.latest.changed().await.is_err() {
+                            return;
+                        }
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("Can't replicate to {}: {}", self.url, e);
+                        self.set_state("out_of_sync", Some(e));
+                        // It may have been reset (or replaced) in the meantime, so ask again later
+                        tokio::time::sleep(MAX_BACKOFF).await;
+                        acked = None;
+                        catch_up.clear();
+                        continue;
+                    }
+                },
+            };
+
+            let latest = self.backlog.lock().unwrap().latest;
+            let result = self.send(&batch, latest).await.and_then(|applied| match acked {
+                // A batch the replica applied none of would be sent again and again
+                Some(previous) if batch.last > previous && applied <= previous => Err(SendError::Rejected(format!(
+                    "the replica didn't apply any of changes {} to {} (it's at {})",
+                    previous + 1,
+                    batch.last,
+                    applied
+                ))),
+                // Changes numbered after the ones it has would be skipped as ones it has
+                None if applied > latest => Err(SendError::Rejected(format!(
+                    "the replica has applied changes up to {}, past this collator's latest ({}); was the \
+                     write-ahead log replaced?",
+                    applied, latest
+                ))),
+                _ => Ok(applied),
+            });
+            match result {
+                Ok(applied) => {
+                    attempt = 0;
+                    if applied >= batch.last {
+                        self.metrics.record_sink("replication", batch.rows, 0);
+                    }
+                    acked = Some(applied);
+                    let mut progress = self.progress.lock().unwrap();
+                    progress.acked = Some(applied);
+                    progress.last_ack_at = Some(Utc::now());
+                    progress.last_error = None;
+                    progress.state = if applied < latest { "catching_up" } else { "streaming" };
+                }
+                // Tried again either way: a replica that turned a batch away may be fixed (or reset) in the meantime
+                Err(e) => {
+                    let delay = backoff(self.retry_backoff_ms, attempt);
+                    attempt += 1;
+                    let (state, e) = match e {
+                        SendError::Transient(e) => ("retrying", e),
+                        SendError::Rejected(e) => ("rejected", e),
+                    };
+                    warn!("Can't replicate to {} ({}), retrying in {:?} (attempt {})", self.url, e, delay, attempt);
+                    self.set_state(state, Some(e));
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+
+    fn set_state(&self, state: &'static str, error: Option<String>) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.state = state;
+        if error.is_some() {
+            progress.last_error = error;
+        }
+    }
+
+    // The changes after `acked`, from the backlog or else the write-ahead log. `None` when the replica has them all.
+    async fn next_batch(
+        &self,
+        acked: u64,
+        catch_up: &mut VecDeque<(u64, usize, Vec<u8>)>,
+    ) -> Result<Option<Batch>, String> {
+        let mut batch = Batch::default();
+        // Changes go in one after another from the one after `acked`, up to the batch's limits
+        let add = |batch: &mut Batch, seq: u64, rows: usize, record: &[u8]| {
+            let next = if batch.body.is_empty() {
+                acked + 1
+            } else {
+                batch.last + 1
+            };
+            let fits = batch.body.is_empty()
+                || ((seq - acked) as usize <= self.batch_records && batch.body.len() + record.len() <= MAX_BATCH_BYTES);
+            if seq == next && fits {
+                batch.last = seq;
+                batch.rows += rows;
+                batch.body.extend_from_slice(record);
+            }
+            seq == next && fits
+        };
+
+        {
+            let backlog = self.backlog.lock().unwrap();
+            if backlog.latest <= acked {
+                return Ok(None);
+            }
+            if backlog.changes.front().is_some_and(|change| change.seq <= acked + 1) {
+                catch_up.clear();
+                for change in backlog.changes.iter().skip_while(|change| change.seq <= acked) {
+                    if !add(&mut batch, change.seq, change.rows, &change.record) {
+                        break;
+                    }
+                }
+                // Empty when the next change was logged, but hasn't got to the backlog yet
+                return Ok((!batch.body.is_empty()).then_some(batch));
+            }
+        }
+
+        // The replica is further behind than the backlog goes back
+        while catch_up.front().is_some_and(|(seq, ..)| *seq <= acked) {
+            catch_up.pop_front();
+        }
+        if catch_up.front().is_none_or(|(seq, ..)| *seq != acked + 1) {
+            *catch_up = self.read_log(acked).await?;
+        }
+        while let Some((seq, rows, record)) = catch_up.front() {
+            if !add(&mut batch, *seq, *rows, record) {
+                break;
+            }
+            catch_up.pop_front();
+        }
+        Ok(Some(batch))
+    }
+
+    // Up to a backlog's worth of the changes after `acked`, read back from the write-ahead log
+    async fn read_log(&self, acked: u64) -> Result<VecDeque<(u64, usize, Vec<u8>)>, String> {
+        let missing = || format!("it needs the changes from {} on, which are no longer kept", acked + 1);
+        let Some(path) = self.wal.clone() else {
+            return Err(missing());
+        };
+        let capacity = self.capacity;
+        let changes = tokio::task::spawn_blocking(move || {
+            let mut changes = VecDeque::new();
+            for record in wal::read_log(&path)?.into_iter().filter(|record| record.seq > acked).take(capacity) {
+                let at = record.at.unwrap_or_else(Utc::now);
+                let (header, body) = wal::encode_parts(&record.dataset, &record.operation, at, None, &record.df)?;
+                changes.push_back((record.seq, record.df.height(), wal::encode_record(record.seq, &header, &body)));
+            }
+            Ok::<_, String>(changes)
+        })
+        .await
+        .map_err(|e| e.to_string())??;
+
+        match changes.front() {
+            Some((seq, ..)) if *seq == acked + 1 => Ok(changes),
+            _ => Err(missing()),
+        }
+    }
+
+    // Send a batch, returning the last change the replica has applied
+    async fn send(&self, batch: &Batch, latest: u64) -> Result<u64, SendError> {
+        let mut headers = vec![
+            ("Content-Type", String::from(RECORDS_CONTENT_TYPE)),
+            (STREAM_HEADER, self.stream.clone()),
+            (LATEST_HEADER, latest.max(batch.last).to_string()),
+        ];
+        if let Some(api_key) = &self.api_key {
+            headers.push(("Authorization", format!("Bearer {}", api_key)));
+        }
+
+        let target = format!("{}/replication/apply", self.endpoint.path);
+        let response =
+            http::send(&self.endpoint, "POST", &target, &headers, &batch.body).await.map_err(SendError::Transient)?;
+        if !response.is_success() {
+            let message = format!("{} {}", response.status, String::from_utf8_lossy(&response.body).trim());
+            return Err(match response.status >= 500 || matches!(response.status, 408 | 429) {
+                true => SendError::Transient(message),
+                false => SendError::Rejected(message),
+            });
+        }
+        let ack: ReplicationAck = serde_json::from_slice(&response.body)
+            .map_err(|e| SendError::Rejected(format!("the replica's answer isn't an acknowledgement: {}", e)))?;
+        Ok(ack.applied_seq)
+    }
+}
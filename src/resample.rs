@@ -0,0 +1,140 @@
+use std::str::FromStr;
+
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::aggregate::{one_or_many, parse_duration, parse_timestamps, AggregateOperation};
+
+// How `/resample` fills the intervals no row fell in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", rename_all = "lowercase")]
+pub enum FillStrategy {
+    // Leave them empty
+    #[default]
+    Null,
+    // Repeat the last value before them
+    Forward,
+    // Draw a straight line between the values on either side (numeric columns only; others are filled forward)
+    Interpolate,
+}
+
+impl FromStr for FillStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "null" | "none" => Ok(FillStrategy::Null),
+            "forward" | "ffill" => Ok(FillStrategy::Forward),
+            "interpolate" | "linear" => Ok(FillStrategy::Interpolate),
+            other => Err(format!("Unsupported fill {:?} (expected null, forward, or interpolate)", other)),
+        }
+    }
+}
+
+impl TryFrom<String> for FillStrategy {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+// Body of a `/resample` request
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResampleSpec {
+    // The timestamp column
+    #[serde(default = "default_time_column")]
+    pub time: String,
+    // The interval of the grid, e.g. `1m` (see `aggregate::TimeWindow`)
+    pub every: String,
+    // Resample each value of these columns on its own, e.g. one grid per sensor
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub keys: Vec<String>,
+    // How rows falling in the same interval are combined (`mean` by default)
+    pub agg: Option<AggregateOperation>,
+    #[serde(default)]
+    pub fill: FillStrategy,
+}
+
+fn default_time_column() -> String {
+    String::from("timestamp")
+}
+
+// Put `df` on a uniform time grid: one row per key and interval, from each key's first row to its last. Rows in the
+// same interval are reduced to one (downsampling), and intervals no row fell in are added and filled with `fill`
+// (upsampling). Each row is labelled with its interval's start, and rows without a timestamp are left out.
+pub fn resample(df: &DataFrame, spec: &ResampleSpec) -> Result<DataFrame, String> {
+    let schema = df.schema();
+    if let Some(column) = std::iter::once(&spec.time).chain(&spec.keys).find(|column| !schema.contains(column)) {
+        return Err(format!("Column {:?} doesn't exist", column));
+    }
+    if spec.keys.contains(&spec.time) {
+        return Err(format!("Time column {:?} can't also be a key", spec.time));
+    }
+    let every = parse_duration(&spec.every)?;
+    let operation = spec.agg.unwrap_or(AggregateOperation::Mean);
+
+    let df = parse_timestamps(df, &spec.time).map_err(|e| e.to_string())?;
+    let time_dtype = df.schema().get(&spec.time).cloned().unwrap_or(DataType::Null);
+    if !matches!(time_dtype, DataType::Datetime(_, _) | DataType::Date) {
+        return Err(format!("Time column {:?} is {}, not a timestamp", spec.time, time_dtype));
+    }
+    let values: Vec<String> = df
+        .get_column_names_str()
+        .into_iter()
+        .filter(|name| *name != spec.time && !spec.keys.iter().any(|key| key == name))
+        .map(String::from)
+        .collect();
+    let keys: Vec<Expr> = spec.keys.iter().map(|key| col(key.as_str())).collect();
+    let mut order = keys.clone();
+    order.push(col(spec.time.as_str()));
+
+    // Downsample: one row per key and interval that has rows
+    let options = DynamicGroupOptions {
+        index_column: spec.time.as_str().into(),
+        every,
+        period: every,
+        offset: Duration::parse("0ns"),
+        label: Label::Left,
+        include_boundaries: false,
+        closed_window: ClosedWindow::Left,
+        start_by: StartBy::WindowBound,
+    };
+    let binned = df
+        .lazy()
+        .filter(col(spec.time.as_str()).is_not_null())
+        .sort_by_exprs(order, SortMultipleOptions::default().with_maintain_order(true))
+        .group_by_dynamic(col(spec.time.as_str()), keys.clone(), options)
+        .agg(values.iter().map(|name| operation.apply(col(name.as_str())).alias(name.as_str())).collect::<Vec<_>>())
+        .collect()
+        .map_err(|e| e.to_string())?;
+    if binned.height() == 0 {
+        return Ok(binned);
+    }
+
+    // Upsample: add the intervals in between. The added rows only have a timestamp, so their keys are carried over
+    // from the row before (which is always from the same key).
+    let grid = binned.upsample_stable(spec.keys.clone(), &spec.time, every).map_err(|e| e.to_string())?;
+    let schema = grid.schema().clone();
+    let mut fills: Vec<Expr> = keys.iter().map(|key| key.clone().forward_fill(None)).collect();
+    fills.extend(values.iter().filter_map(|name| {
+        let value = col(name.as_str());
+        let value = match spec.fill {
+            FillStrategy::Null => return None,
+            FillStrategy::Interpolate if schema.get(name.as_str()).is_some_and(DataType::is_primitive_numeric) => {
+                value.interpolate(InterpolationMethod::Linear)
+            }
+            FillStrategy::Forward | FillStrategy::Interpolate => value.forward_fill(None),
+        };
+        // Never fill across keys
+        Some(if keys.is_empty() { value } else { value.over(keys.clone()) })
+    }));
+
+    // Keys are filled first, so each value fill can be split up by them
+    grid.lazy()
+        .with_columns(fills.drain(..spec.keys.len()).collect::<Vec<_>>())
+        .with_columns(fills)
+        .collect()
+        .map_err(|e| e.to_string())
+}
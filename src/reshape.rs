@@ -0,0 +1,111 @@
+use polars::{lazy::frame::pivot::pivot_stable, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::aggregate::{one_or_many, AggregateOperation};
+
+// Body of a `/pivot` request: turns a long table wide, with one column per distinct value of `columns`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PivotSpec {
+    // Columns identifying each row of the result
+    #[serde(deserialize_with = "one_or_many")]
+    pub index: Vec<String>,
+    // Columns whose values become the new column names
+    #[serde(alias = "on", deserialize_with = "one_or_many")]
+    pub columns: Vec<String>,
+    // Columns whose values fill the new columns (every other column if left out)
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub values: Vec<String>,
+    // How to combine values landing in the same cell: `first` (the default), `last`, or any `/aggregate` operation
+    pub agg: Option<String>,
+}
+
+// Body of a `/melt` request: turns a wide table long, with one row per value column of each input row
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MeltSpec {
+    // Columns kept as they are on every output row
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub index: Vec<String>,
+    // Columns folded into rows (every column not in `index` if left out)
+    #[serde(default, alias = "on", deserialize_with = "one_or_many")]
+    pub values: Vec<String>,
+    // Names of the output columns holding the folded column's name and its value
+    #[serde(default = "default_variable_name")]
+    pub variable_name: String,
+    #[serde(default = "default_value_name")]
+    pub value_name: String,
+}
+
+fn default_variable_name() -> String {
+    String::from("variable")
+}
+
+fn default_value_name() -> String {
+    String::from("value")
+}
+
+// Pivot `df` long to wide. New columns are named after the values of `columns` (joined with `_` when there are
+// several), prefixed with the value column's name when there's more than one, and appear in the order those values
+// first show up. Index rows keep the order they first show up in too.
+pub fn pivot(df: &DataFrame, spec: &PivotSpec) -> Result<DataFrame, String> {
+    if spec.index.is_empty() {
+        return Err(String::from("At least one `index` column is required"));
+    }
+    if spec.columns.is_empty() {
+        return Err(String::from("At least one `columns` column is required"));
+    }
+    check_columns(df, spec.index.iter().chain(&spec.columns).chain(&spec.values))?;
+    if let Some(column) = spec.columns.iter().find(|column| spec.index.contains(column)) {
+        return Err(format!("Column {:?} can't be both an index and a pivoted column", column));
+    }
+
+    let agg = match spec.agg.as_deref().map(str::to_ascii_lowercase).as_deref() {
+        None | Some("first") => col("").first(),
+        Some("last") => col("").last(),
+        Some(op) => op
+            .parse::<AggregateOperation>()
+            .map_err(|_| {
+                format!(
+                    "Unsupported pivot aggregation {:?} (expected first, last, sum, mean, min, max, count, median, std, \
+                     p90, p95, p99, or distinct)",
+                    op
+                )
+            })?
+            .apply(col("")),
+    };
+    let values = (!spec.values.is_empty()).then_some(&spec.values);
+
+    pivot_stable(df, &spec.columns, Some(&spec.index), values, false, Some(agg), None).map_err(|e| e.to_string())
+}
+
+// Melt (unpivot) `df` wide to long: each row becomes one row per value column, holding the index columns, the value
+// column's name, and its value. Value columns of different types are cast to a common one.
+pub fn melt(df: &DataFrame, spec: &MeltSpec) -> Result<DataFrame, String> {
+    check_columns(df, spec.index.iter().chain(&spec.values))?;
+    if let Some(column) = spec.values.iter().find(|column| spec.index.contains(column)) {
+        return Err(format!("Column {:?} can't be both an index and a value column", column));
+    }
+    if spec.variable_name == spec.value_name {
+        return Err(format!("`variable_name` and `value_name` are both {:?}", spec.value_name));
+    }
+    if let Some(name) = [&spec.variable_name, &spec.value_name].into_iter().find(|name| spec.index.contains(name)) {
+        return Err(format!("Output column {:?} clashes with an index column", name));
+    }
+
+    df.unpivot2(UnpivotArgsIR {
+        on: spec.values.iter().map(PlSmallStr::from).collect(),
+        index: spec.index.iter().map(PlSmallStr::from).collect(),
+        variable_name: Some(spec.variable_name.as_str().into()),
+        value_name: Some(spec.value_name.as_str().into()),
+    })
+    .map_err(|e| e.to_string())
+}
+
+fn check_columns<'a>(df: &DataFrame, columns: impl IntoIterator<Item = &'a String>) -> Result<(), String> {
+    let schema = df.schema();
+    match columns.into_iter().find(|column| !schema.contains(column)) {
+        Some(column) => Err(format!("Column {:?} doesn't exist", column)),
+        None => Ok(()),
+    }
+}
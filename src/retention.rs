@@ -0,0 +1,211 @@
+// Retention (`[retention.<dataset>]`): a background task that keeps long-running datasets from growing without bound,
+// by evicting rows older than `max_age_secs` (by a timestamp column) and, past `max_rows`, the oldest rows first.
+// Evictions are logged like deletes, so a restart (and `GET /versions`) sees the same rows the service had. In
+// `window` mode only memory is trimmed, to a sliding window of the latest rows, while the output file keeps them all.
+
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    aggregate::parse_timestamps,
+    collator::{log_payload, persist_result},
+    dataset::{AppState, SharedDataset},
+    error::AppError,
+    persist::WriteMode,
+    wal::Operation,
+};
+
+// How long a dataset's rows are kept, and how many of them
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionPolicy {
+    // The timestamp column `max_age_secs` goes by: RFC 3339 text, a datetime, or a date
+    pub column: Option<String>,
+    // Evict rows whose timestamp is older than this
+    pub max_age_secs: Option<u64>,
+    // Evict the oldest rows once the dataset has more than this
+    pub max_rows: Option<usize>,
+    pub mode: RetentionMode,
+}
+
+// Where evicted rows are removed from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum RetentionMode {
+    // Memory and the output file, like `DELETE /data`
+    #[default]
+    Delete,
+    // Memory only: the dataset is a sliding window of its latest rows, and its output file keeps the full history
+    Window,
+}
+
+impl FromStr for RetentionMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "delete" => Ok(RetentionMode::Delete),
+            "window" => Ok(RetentionMode::Window),
+            other => Err(format!("Unsupported retention mode {:?} (expected one of: delete, window)", other)),
+        }
+    }
+}
+
+impl TryFrom<String> for RetentionMode {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl RetentionPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        match (&self.column, self.max_age_secs) {
+            (None, Some(_)) => return Err(String::from("max_age_secs needs a timestamp column to go by")),
+            (Some(_), None) => return Err(String::from("A column is set, but no max_age_secs to go with it")),
+            _ => {}
+        }
+        if self.max_age_secs == Some(0) {
+            return Err(String::from("max_age_secs must be above 0"));
+        }
+        if self.max_rows == Some(0) {
+            return Err(String::from("max_rows must be above 0 (use POST /reset to empty a dataset)"));
+        }
+        if self.max_age_secs.is_none() && self.max_rows.is_none() {
+            return Err(String::from("Set max_age_secs (with a column), max_rows, or both"));
+        }
+        Ok(())
+    }
+
+    // A sliding window only keeps the full history if the output file is appended to
+    pub fn check_write_mode(&self, write_mode: WriteMode) -> Result<(), String> {
+        if self.mode == RetentionMode::Window && write_mode == WriteMode::Snapshot {
+            return Err(String::from(
+                "window mode needs storage.write_mode = \"append\": in snapshot mode the output file is rewritten with \
+                 just the window",
+            ));
+        }
+        Ok(())
+    }
+
+    // What the policy evicts as of `now`
+    fn eviction(&self, now: DateTime<Utc>) -> Eviction {
+        let max_age = self.max_age_secs.map(|secs| chrono::Duration::seconds(secs.min(i64::MAX as u64) as i64));
+        Eviction {
+            column: self.column.clone(),
+            before: max_age.and_then(|age| now.checked_sub_signed(age)).map(|cutoff| cutoff.timestamp_micros()),
+            max_rows: self.max_rows,
+        }
+    }
+}
+
+// One pass of a policy, as it's logged: the cutoff is fixed, so replaying it evicts the same rows
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Eviction {
+    pub column: Option<String>,
+    // Rows timestamped before this (in microseconds since the Unix epoch) are evicted
+    pub before: Option<i64>,
+    pub max_rows: Option<usize>,
+}
+
+impl Eviction {
+    // Which rows of `df` are kept. Rows without a timestamp are kept, since how old they are isn't known, and so are
+    // all of them when the frame doesn't have the column (yet).
+    pub fn kept(&self, df: &DataFrame) -> Result<BooleanChunked, String> {
+        let mut keep = vec![true; df.height()];
+        if let (Some(column), Some(before)) = (&self.column, self.before)
+            && df.schema().contains(column)
+        {
+            let parsed = parse_timestamps(df, column).map_err(|e| e.to_string())?;
+            let dtype = parsed.column(column).map_err(|e| e.to_string())?.dtype().clone();
+            if !matches!(dtype, DataType::Datetime(_, _) | DataType::Date) {
+                return Err(format!("Retention column {:?} holds {}, not timestamps", column, dtype));
+            }
+            let recent = col(column.as_str())
+                .cast(DataType::Datetime(TimeUnit::Microseconds, None))
+                .cast(DataType::Int64)
+                .gt_eq(lit(before))
+                .fill_null(lit(true));
+            let recent = parsed.lazy().select([recent]).collect().map_err(|e| e.to_string())?;
+            let recent = recent.get_columns()[0].bool().map_err(|e| e.to_string())?;
+            for (keep, recent) in keep.iter_mut().zip(recent) {
+                *keep = recent.unwrap_or(true);
+            }
+        }
+        // Rows are appended at the end, so the oldest of the rest are the first ones
+        if let Some(max_rows) = self.max_rows {
+            let mut excess = keep.iter().filter(|keep| **keep).count().saturating_sub(max_rows);
+            for keep in keep.iter_mut().filter(|keep| **keep) {
+                if excess == 0 {
+                    break;
+                }
+                *keep = false;
+                excess -= 1;
+            }
+        }
+
+        Ok(BooleanChunked::from_slice(PlSmallStr::from_static("keep"), &keep))
+    }
+}
+
+// Apply every dataset's policy every `storage.retention_interval_ms`
+pub(crate) async fn enforce(state: Arc<AppState>) {
+    let interval = Duration::from_millis(state.config.storage.retention_interval_ms);
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        // A replica's rows go when its primary evicts them
+        if state.replication.is_replica() {
+            continue;
+        }
+        for (name, policy) in &state.config.retention {
+            let Some(dataset) = state.existing_dataset(name).await else {
+                continue;
+            };
+            match evict(&state, name, dataset, policy.eviction(Utc::now()), policy.mode).await {
+                Ok(0) => {}
+                Ok(evicted) => info!("Evicted {} rows from {:?} under its retention policy", evicted, name),
+                Err(e) => error!("Can't apply the retention policy of {:?}: {}", name, e.message()),
+            }
+        }
+    }
+}
+
+// Evict a dataset's rows, returning how many there were. Like deletes, the output file is rewritten with what's left,
+// except in `overwrite` mode, or in `window` mode, where it's left alone. Nothing is logged when there's nothing to
+// evict.
+async fn evict(
+    state: &AppState,
+    name: &str,
+    dataset: SharedDataset,
+    eviction: Eviction,
+    mode: RetentionMode,
+) -> Result<usize, AppError> {
+    let mut dataset = dataset.write().await;
+    // The oldest rows are the ones evicted first, and they may have been spilled
+    dataset.unspill().map_err(AppError::Internal)?;
+    let (aggregate_state, df, evicted) = dataset.evicted(&eviction).map_err(AppError::BadRequest)?;
+    if evicted == 0 {
+        return Ok(0);
+    }
+
+    log_payload(state, name, &Operation::Evict { spec: eviction }, &DataFrame::empty()).await?;
+    dataset.aggregate_state = aggregate_state;
+    dataset.df = df;
+    dataset.ledger.clear();
+    let rows = dataset.df.as_ref().map_or(0, DataFrame::height);
+    state.events.changed(name, "evict", &DataFrame::empty(), rows);
+
+    if mode == RetentionMode::Delete && state.config.storage.write_mode != WriteMode::Overwrite {
+        let state_df = dataset.df.clone().unwrap_or_default();
+        persist_result(&state.writer, &dataset, WriteMode::Snapshot, state_df).await?;
+    }
+
+    Ok(evicted)
+}
@@ -0,0 +1,168 @@
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::aggregate::{one_or_many, parse_duration, parse_timestamps, AggregateOperation};
+
+// Body of a `/rolling` request
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RollingSpec {
+    // How far back each window reaches: a number of rows (`10`) or, with a time column, a duration (`5m`)
+    pub window: String,
+    // The statistics to compute (`mean` by default)
+    #[serde(default = "default_ops", alias = "op", deserialize_with = "ops")]
+    pub ops: Vec<AggregateOperation>,
+    // The columns to smooth (every numeric column other than the keys and time column by default)
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub columns: Vec<String>,
+    // Compute each value of these columns' windows on their own, e.g. one curve per host
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub keys: Vec<String>,
+    // The timestamp column rows are ordered by (and, for a duration window, measured with)
+    pub time: Option<String>,
+}
+
+fn default_ops() -> Vec<AggregateOperation> {
+    vec![AggregateOperation::Mean]
+}
+
+// Accept either a single operation or a list of them
+fn ops<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<AggregateOperation>, D::Error> {
+    one_or_many(deserializer)?
+        .iter()
+        .map(|op| op.parse())
+        .collect::<Result<_, String>>()
+        .map_err(serde::de::Error::custom)
+}
+
+// A rolling window's length
+#[derive(Debug, Clone, Copy)]
+enum Window {
+    Rows(usize),
+    Duration(Duration),
+}
+
+// `df` with a `<column>_rolling_<op>` column added for each column and statistic, computed over the window ending at
+// each row: its last `n` rows, or the rows from the past duration up to and including it. Windows near the start
+// hold fewer rows. With a time column, rows are sorted by it first; otherwise they keep their order.
+pub fn rolling(df: &DataFrame, spec: &RollingSpec) -> Result<DataFrame, String> {
+    let window = match spec.window.trim().parse::<usize>() {
+        Ok(0) => return Err(String::from("The window must hold at least one row")),
+        Ok(rows) => Window::Rows(rows),
+        Err(_) if spec.time.is_none() => {
+            return Err(format!(
+                "Window {:?} isn't a number of rows, and duration windows need a `time` column",
+                spec.window
+            ));
+        }
+        Err(_) => Window::Duration(parse_duration(spec.window.trim())?),
+    };
+    if spec.ops.is_empty() {
+        return Err(String::from("At least one operation is required"));
+    }
+    let not_rolling = |op: &&AggregateOperation| matches!(op, AggregateOperation::Count | AggregateOperation::Distinct);
+    if let Some(op) = spec.ops.iter().find(not_rolling) {
+        return Err(format!(
+            "{} isn't a rolling statistic (use sum, mean, min, max, median, std, p90, p95, or p99)",
+            op.name()
+        ));
+    }
+
+    let schema = df.schema();
+    let referenced = spec.keys.iter().chain(&spec.columns).chain(&spec.time);
+    if let Some(column) = referenced.clone().find(|column| !schema.contains(column)) {
+        return Err(format!("Column {:?} doesn't exist", column));
+    }
+    let skipped = |name: &str| spec.keys.iter().chain(&spec.time).any(|column| column == name);
+    if let Some(column) = spec.columns.iter().find(|column| skipped(column)) {
+        return Err(format!("Column {:?} can't be both smoothed and a key or the time column", column));
+    }
+    let columns: Vec<String> = if spec.columns.is_empty() {
+        schema
+            .iter()
+            .filter(|(name, dtype)| dtype.is_primitive_numeric() && !skipped(name))
+            .map(|(name, _)| name.to_string())
+            .collect()
+    } else {
+        spec.columns.clone()
+    };
+    let numeric = |column: &String| schema.get(column).is_some_and(DataType::is_primitive_numeric);
+    if let Some(column) = columns.iter().find(|column| !numeric(column)) {
+        return Err(format!("Column {:?} isn't numeric", column));
+    }
+
+    // Rows without a timestamp have no place in a window ordered by time
+    let mut rows = df.clone().lazy();
+    if let Some(time) = &spec.time {
+        rows = parse_timestamps(df, time)
+            .map_err(|e| e.to_string())?
+            .lazy()
+            .filter(col(time.as_str()).is_not_null())
+            .sort([time.as_str()], SortMultipleOptions::default().with_maintain_order(true));
+    }
+
+    let keys: Vec<Expr> = spec.keys.iter().map(|key| col(key.as_str())).collect();
+    let mut statistics = Vec::with_capacity(columns.len() * spec.ops.len());
+    for column in &columns {
+        for op in &spec.ops {
+            let value = col(column.as_str()).cast(DataType::Float64);
+            let statistic = match window {
+                Window::Rows(rows) => rolling_rows(value, *op, rows),
+                Window::Duration(duration) => {
+                    rolling_by(value, *op, col(spec.time.as_deref().unwrap_or_default()), duration)
+                }
+            };
+            // Never roll across keys
+            let statistic = if keys.is_empty() { statistic } else { statistic.over(keys.clone()) };
+            statistics.push(statistic.alias(format!("{}_rolling_{}", column, op.name())));
+        }
+    }
+
+    rows.with_columns(statistics).collect().map_err(|e| e.to_string())
+}
+
+fn rolling_rows(value: Expr, op: AggregateOperation, rows: usize) -> Expr {
+    let options = RollingOptionsFixedWindow {
+        window_size: rows,
+        min_periods: 1,
+        ..Default::default()
+    };
+    match op {
+        AggregateOperation::Sum => value.rolling_sum(options),
+        AggregateOperation::Min => value.rolling_min(options),
+        AggregateOperation::Max => value.rolling_max(options),
+        AggregateOperation::Median => value.rolling_median(options),
+        AggregateOperation::Std => value.rolling_std(options),
+        AggregateOperation::P90 | AggregateOperation::P95 | AggregateOperation::P99 => {
+            let quantile = op.quantile().expect("percentiles have a quantile");
+            value.rolling_quantile(QuantileMethod::Linear, quantile, options)
+        }
+        // `Count` and `Distinct` are rejected by `rolling`
+        AggregateOperation::Mean | AggregateOperation::Count | AggregateOperation::Distinct => {
+            value.rolling_mean(options)
+        }
+    }
+}
+
+fn rolling_by(value: Expr, op: AggregateOperation, time: Expr, duration: Duration) -> Expr {
+    let options = RollingOptionsDynamicWindow {
+        window_size: duration,
+        min_periods: 1,
+        closed_window: ClosedWindow::Right,
+        fn_params: None,
+    };
+    match op {
+        AggregateOperation::Sum => value.rolling_sum_by(time, options),
+        AggregateOperation::Min => value.rolling_min_by(time, options),
+        AggregateOperation::Max => value.rolling_max_by(time, options),
+        AggregateOperation::Median => value.rolling_median_by(time, options),
+        AggregateOperation::Std => value.rolling_std_by(time, options),
+        AggregateOperation::P90 | AggregateOperation::P95 | AggregateOperation::P99 => {
+            let quantile = op.quantile().expect("percentiles have a quantile");
+            value.rolling_quantile_by(time, QuantileMethod::Linear, quantile, options)
+        }
+        AggregateOperation::Mean | AggregateOperation::Count | AggregateOperation::Distinct => {
+            value.rolling_mean_by(time, options)
+        }
+    }
+}
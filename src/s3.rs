@@ -0,0 +1,176 @@
+mod sigv4;
+
+use std::{sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::S3Config,
+    http::{self, Endpoint},
+    metrics::Metrics,
+    payload::write_df,
+};
+
+use sigv4::{encode_path, Credentials};
+
+// What an object key can have filled in
+const PLACEHOLDERS: [&str; 5] = ["dataset", "date", "time", "n", "ext"];
+
+// An object an export wrote
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportedObject {
+    pub key: String,
+    pub rows: usize,
+    pub bytes: usize,
+}
+
+// Copies datasets to an S3-compatible object store (`[s3]`), each export being every row the dataset holds at the
+// time, so there's a copy that outlives the node's disks. Exports happen every `s3.interval_ms` and on `POST
+// /export/s3`.
+#[derive(Debug)]
+pub struct S3Exporter {
+    endpoint: Endpoint,
+    config: S3Config,
+    credentials: Credentials,
+    metrics: Arc<Metrics>,
+}
+
+impl S3Exporter {
+    // The exporter, if an endpoint is configured
+    pub fn new(config: &S3Config, metrics: Arc<Metrics>) -> Option<Self> {
+        let mut endpoint = Endpoint::parse(config.endpoint.as_deref()?).expect("the endpoint is checked by Config::validate");
+        // Virtual-hosted buckets are their own host name, e.g. `bucket.s3.example.com`
+        if !config.path_style {
+            endpoint.host = format!("{}.{}", config.bucket, endpoint.host);
+        }
+        let credentials = Credentials {
+            access_key_id: config.access_key_id.clone().expect("credentials are checked by Config::validate"),
+            secret_access_key: config.secret_access_key.clone().expect("credentials are checked by Config::validate"),
+            session_token: config.session_token.clone(),
+        };
+
+        Some(S3Exporter {
+            endpoint,
+            config: config.clone(),
+            credentials,
+            metrics,
+        })
+    }
+
+    // How often every dataset is exported, unless it's only on request
+    pub fn interval(&self) -> Option<Duration> {
+        (self.config.interval_ms > 0).then(|| Duration::from_millis(self.config.interval_ms))
+    }
+
+    // The object store's host and port, so `/readyz` can check it's reachable
+    pub fn server(&self) -> (&str, u16) {
+        (&self.endpoint.host, self.endpoint.port)
+    }
+
+    pub fn exports(&self, dataset: &str) -> bool {
+        self.config.datasets.is_empty() || self.config.datasets.iter().any(|name| name == dataset)
+    }
+
+    // Upload a dataset's rows, split into parts of `part_rows` rows when the key has `{n}`. Parts are uploaded one at
+    // a time, and an export that fails partway leaves the parts before it in place. A dataset with no rows isn't
+    // uploaded at all.
+    pub async fn export(&self, dataset: &str, df: &DataFrame) -> Result<Vec<ExportedObject>, String> {
+        let now = Utc::now();
+        let part_rows = if self.config.key.contains("{n}") { self.config.part_rows } else { df.height() };
+
+        let mut objects = Vec::new();
+        for (n, offset) in (0..df.height()).step_by(part_rows.max(1)).enumerate() {
+            let mut part = df.slice(offset as i64, part_rows);
+            let key = render_key(&self.config.key, dataset, now, n, self.config.format.extension());
+            let result = match write_df(&mut part, self.config.format) {
+                Ok(bytes) => self.put(&key, &bytes).await.map(|()| bytes.len()),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(bytes) => objects.push(ExportedObject {
+                    key,
+                    rows: part.height(),
+                    bytes,
+                }),
+                Err(e) => {
+                    self.metrics.record_sink("s3", 0, df.height() - offset);
+                    return Err(format!("Can't export {:?} to s3://{}/{}: {}", dataset, self.config.bucket, key, e));
+                }
+            }
+            self.metrics.record_sink("s3", part.height(), 0);
+        }
+
+        Ok(objects)
+    }
+
+    async fn put(&self, key: &str, body: &[u8]) -> Result<(), String> {
+        let path = if self.config.path_style {
+            format!("{}/{}/{}", self.endpoint.path, self.config.bucket, key)
+        } else {
+            format!("{}/{}", self.endpoint.path, key)
+        };
+        let path = encode_path(&path);
+
+        let authority = self.endpoint.authority();
+        let mut headers = sigv4::sign(&self.credentials, &self.config.region, "PUT", &authority, &path, body, Utc::now());
+        headers.push(("content-type", String::from(self.config.format.content_type())));
+
+        let response = http::send(&self.endpoint, "PUT", &path, &headers, body).await?;
+        if response.is_success() {
+            Ok(())
+        } else {
+            Err(format!("the object store responded {}: {}", response.status, error_message(&response.body)))
+        }
+    }
+}
+
+// Check an object key template for placeholders that don't exist (or aren't closed)
+pub fn check_key(template: &str) -> Result<(), String> {
+    if template.trim().is_empty() || template.starts_with('/') {
+        return Err(String::from("the key can't be empty or start with '/'"));
+    }
+
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..].find('}').ok_or_else(|| format!("unclosed '{{' in {:?}", template))?;
+        let name = &rest[start + 1..start + end];
+        if !PLACEHOLDERS.contains(&name) {
+            return Err(format!(
+                "unknown placeholder {{{}}} (use {})",
+                name,
+                PLACEHOLDERS.map(|name| format!("{{{}}}", name)).join(", ")
+            ));
+        }
+        rest = &rest[start + end + 1..];
+    }
+
+    Ok(())
+}
+
+fn render_key(template: &str, dataset: &str, now: DateTime<Utc>, n: usize, extension: &str) -> String {
+    template
+        .replace("{dataset}", dataset)
+        .replace("{date}", &now.format("%Y-%m-%d").to_string())
+        .replace("{time}", &now.format("%H%M%S").to_string())
+        .replace("{n}", &n.to_string())
+        .replace("{ext}", extension)
+}
+
+// The `<Code>` and `<Message>` of an S3 error response, or the body itself if it isn't one
+fn error_message(body: &[u8]) -> String {
+    let body = String::from_utf8_lossy(body);
+    let element = |name: &str| {
+        let start = body.find(&format!("<{}>", name))? + name.len() + 2;
+        let end = body[start..].find("</")?;
+        Some(body[start..start + end].to_string())
+    };
+
+    match (element("Code"), element("Message")) {
+        (Some(code), Some(message)) => format!("{} ({})", message, code),
+        (Some(code), None) => code,
+        _ if body.trim().is_empty() => String::from("no details"),
+        _ => body.trim().chars().take(200).collect(),
+    }
+}
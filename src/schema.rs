@@ -0,0 +1,162 @@
+use std::{fmt, str::FromStr};
+
+use indexmap::IndexMap;
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// Column types a schema can declare
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum ColumnType {
+    Bool,
+    Int32,
+    Int64,
+    UInt32,
+    UInt64,
+    Float32,
+    Float64,
+    String,
+    Date,
+    // Microsecond precision, no time zone
+    Datetime,
+}
+
+impl FromStr for ColumnType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "bool" | "boolean" => Ok(ColumnType::Bool),
+            "i32" | "int32" => Ok(ColumnType::Int32),
+            "i64" | "int64" | "int" => Ok(ColumnType::Int64),
+            "u32" | "uint32" => Ok(ColumnType::UInt32),
+            "u64" | "uint64" => Ok(ColumnType::UInt64),
+            "f32" | "float32" => Ok(ColumnType::Float32),
+            "f64" | "float64" | "float" => Ok(ColumnType::Float64),
+            "str" | "string" | "utf8" => Ok(ColumnType::String),
+            "date" => Ok(ColumnType::Date),
+            "datetime" => Ok(ColumnType::Datetime),
+            other => Err(format!(
+                "Unsupported column type {:?} (expected one of: bool, int32, int64, uint32, uint64, float32, float64, \
+                 string, date, datetime)",
+                other
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for ColumnType {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<ColumnType> for String {
+    fn from(column_type: ColumnType) -> Self {
+        column_type.to_string()
+    }
+}
+
+impl fmt::Display for ColumnType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ColumnType::Bool => "bool",
+            ColumnType::Int32 => "int32",
+            ColumnType::Int64 => "int64",
+            ColumnType::UInt32 => "uint32",
+            ColumnType::UInt64 => "uint64",
+            ColumnType::Float32 => "float32",
+            ColumnType::Float64 => "float64",
+            ColumnType::String => "string",
+            ColumnType::Date => "date",
+            ColumnType::Datetime => "datetime",
+        };
+        f.write_str(name)
+    }
+}
+
+impl ColumnType {
+    pub fn dtype(&self) -> DataType {
+        match self {
+            ColumnType::Bool => DataType::Boolean,
+            ColumnType::Int32 => DataType::Int32,
+            ColumnType::Int64 => DataType::Int64,
+            ColumnType::UInt32 => DataType::UInt32,
+            ColumnType::UInt64 => DataType::UInt64,
+            ColumnType::Float32 => DataType::Float32,
+            ColumnType::Float64 => DataType::Float64,
+            ColumnType::String => DataType::String,
+            ColumnType::Date => DataType::Date,
+            ColumnType::Datetime => DataType::Datetime(TimeUnit::Microseconds, None),
+        }
+    }
+}
+
+// What happens to a payload that doesn't match the declared schema
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SchemaMode {
+    // Reject payloads with missing columns or different dtypes
+    #[default]
+    Strict,
+    // Cast columns to the declared dtypes and fill missing columns with nulls. Values that can't be cast are still
+    // rejected, so nothing is silently turned into a null.
+    Coerce,
+}
+
+// The columns (in order) and dtypes a dataset is expected to have
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DatasetSchema {
+    pub columns: IndexMap<String, ColumnType>,
+    #[serde(default)]
+    pub mode: SchemaMode,
+}
+
+impl DatasetSchema {
+    // Check a payload against the schema, returning it with its columns in schema order (and, when coercing, cast to
+    // the declared dtypes). Columns the schema doesn't know about are always rejected.
+    pub fn enforce(&self, df: &DataFrame) -> Result<DataFrame, String> {
+        if self.columns.is_empty() {
+            return Err(String::from("The schema doesn't declare any columns"));
+        }
+
+        let extra: Vec<&str> = df
+            .get_column_names_str()
+            .into_iter()
+            .filter(|name| !self.columns.contains_key(*name))
+            .collect();
+        if !extra.is_empty() {
+            return Err(format!("Columns {:?} are not in the schema", extra));
+        }
+
+        let mut columns = Vec::with_capacity(self.columns.len());
+        for (name, column_type) in &self.columns {
+            let dtype = column_type.dtype();
+
+            let column = match df.column(name) {
+                Ok(column) if column.dtype() == &dtype => column.clone(),
+                Ok(column) if self.mode == SchemaMode::Coerce => column
+                    .strict_cast(&dtype)
+                    .map_err(|e| format!("Column {:?} can't be cast to {}: {}", name, column_type, e))?,
+                Ok(column) => {
+                    return Err(format!(
+                        "Column {:?} has dtype {}, but the schema declares {}",
+                        name,
+                        column.dtype(),
+                        column_type
+                    ));
+                }
+                Err(_) if self.mode == SchemaMode::Coerce => {
+                    Column::full_null(name.as_str().into(), df.height(), &dtype)
+                }
+                Err(_) => return Err(format!("Column {:?} is missing", name)),
+            };
+            columns.push(column);
+        }
+
+        DataFrame::new(columns).map_err(|e| e.to_string())
+    }
+}
@@ -0,0 +1,205 @@
+use std::str::FromStr;
+
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderName, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use log::error;
+use polars::prelude::*;
+use serde_json::{json, Value};
+
+use crate::{
+    error::AppError,
+    payload::{write_df, FileFormat, ARROW_STREAM_CONTENT_TYPE},
+    stream::csv_body,
+};
+
+const CSV_CONTENT_TYPE: &str = "text/csv";
+const JSON_CONTENT_TYPE: &str = "application/json";
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+// How a DataFrame in a response is serialized, picked from the `Accept` header (or a `format` parameter)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseFormat {
+    Csv,
+    // The endpoint's JSON response (which has the data in it as CSV or as records)
+    Json,
+    // One JSON record per line
+    Ndjson,
+    // Arrow IPC stream
+    Arrow,
+}
+
+impl FromStr for ResponseFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "csv" => Ok(ResponseFormat::Csv),
+            "json" => Ok(ResponseFormat::Json),
+            "ndjson" | "jsonl" => Ok(ResponseFormat::Ndjson),
+            "arrow" | "arrows" => Ok(ResponseFormat::Arrow),
+            other => Err(format!("Unsupported format {:?} (expected csv, json, ndjson, or arrow)", other)),
+        }
+    }
+}
+
+impl ResponseFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ResponseFormat::Csv => CSV_CONTENT_TYPE,
+            ResponseFormat::Json => JSON_CONTENT_TYPE,
+            ResponseFormat::Ndjson => NDJSON_CONTENT_TYPE,
+            ResponseFormat::Arrow => ARROW_STREAM_CONTENT_TYPE,
+        }
+    }
+
+    // Match a media range from an `Accept` header. `*/*` means the endpoint's default.
+    fn from_media_range(range: &str, default: ResponseFormat) -> Option<Self> {
+        match range {
+            "*/*" => Some(default),
+            CSV_CONTENT_TYPE | "text/*" => Some(ResponseFormat::Csv),
+            JSON_CONTENT_TYPE => Some(ResponseFormat::Json),
+            NDJSON_CONTENT_TYPE | "application/jsonl" => Some(ResponseFormat::Ndjson),
+            ARROW_STREAM_CONTENT_TYPE => Some(ResponseFormat::Arrow),
+            _ => None,
+        }
+    }
+}
+
+// Pick the response format from the `Accept` header, preferring higher `q` values and then the order the client
+// listed them in. Without an `Accept` header (or with `*/*`) the endpoint's default is used. A request that accepts
+// none of the supported formats gets a 406.
+pub fn negotiate(headers: &HeaderMap, default: ResponseFormat) -> Result<ResponseFormat, AppError> {
+    let accept: Vec<&str> = headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|range| !range.is_empty())
+        .collect();
+    if accept.is_empty() {
+        return Ok(default);
+    }
+
+    let mut ranges: Vec<(&str, f32)> = accept
+        .iter()
+        .map(|range| {
+            let mut parts = range.split(';').map(str::trim);
+            let media = parts.next().unwrap_or("");
+            let q = parts
+                .filter_map(|param| param.strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (media, q)
+        })
+        .filter(|(_, q)| *q > 0.0)
+        .collect();
+    // Stable, so equally preferred ranges keep the client's order
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    ranges
+        .iter()
+        .find_map(|(media, _)| ResponseFormat::from_media_range(&media.to_ascii_lowercase(), default))
+        .ok_or_else(|| {
+            AppError::NotAcceptable(format!(
+                "None of the accepted types ({}) are supported (expected text/csv, application/json, \
+                 application/x-ndjson, or {})",
+                accept.join(", "),
+                ARROW_STREAM_CONTENT_TYPE
+            ))
+        })
+}
+
+// A response with a DataFrame as its whole body, for every format except `Json` (whose shape depends on the
+// endpoint). `headers` are added to the response, e.g. `x-total-rows`.
+pub fn df_response(
+    df: DataFrame,
+    format: ResponseFormat,
+    headers: Vec<(HeaderName, HeaderValue)>,
+) -> Result<Response, AppError> {
+    let body = match format {
+        // CSV is streamed in chunks, so large frames aren't serialized in memory all at once
+        ResponseFormat::Csv => csv_body(df),
+        ResponseFormat::Json => Body::from(Value::Array(df_to_json_records(&df)).to_string()),
+        ResponseFormat::Ndjson => Body::from(df_to_ndjson(&df)),
+        ResponseFormat::Arrow => {
+            let mut df = df;
+            let bytes = write_df(&mut df, FileFormat::Arrow)
+                .map_err(|e| AppError::Internal(format!("Error writing DataFrame as Arrow: {}", e)))?;
+            Body::from(bytes)
+        }
+    };
+
+    let mut response = ([(header::CONTENT_TYPE, format.content_type())], body).into_response();
+    response.headers_mut().extend(headers);
+
+    Ok(response)
+}
+
+// Get a DataFrame as a CSV string
+pub fn df_to_csv(df: &mut DataFrame, include_header: bool) -> String {
+    let mut csv_bytes = Vec::new();
+
+    match CsvWriter::new(&mut csv_bytes).include_header(include_header).finish(df) {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Error writing DataFrame to CSV: {:?}", e);
+            return String::new();
+        }
+    }
+
+    String::from_utf8(csv_bytes).unwrap()
+}
+
+// Convert a single Polars value into JSON
+fn any_value_to_json(value: AnyValue) -> Value {
+    match value {
+        AnyValue::Null => Value::Null,
+        AnyValue::Boolean(v) => json!(v),
+        AnyValue::String(v) => json!(v),
+        AnyValue::StringOwned(v) => json!(v.as_str()),
+        AnyValue::UInt8(v) => json!(v),
+        AnyValue::UInt16(v) => json!(v),
+        AnyValue::UInt32(v) => json!(v),
+        AnyValue::UInt64(v) => json!(v),
+        AnyValue::Int8(v) => json!(v),
+        AnyValue::Int16(v) => json!(v),
+        AnyValue::Int32(v) => json!(v),
+        AnyValue::Int64(v) => json!(v),
+        AnyValue::Float32(v) => json!(v),
+        AnyValue::Float64(v) => json!(v),
+        // Dates, times, etc. use their display representation
+        other => json!(other.to_string()),
+    }
+}
+
+// Get a DataFrame as a list of JSON records (one object per row)
+pub fn df_to_json_records(df: &DataFrame) -> Vec<Value> {
+    let columns = df.get_columns();
+
+    (0..df.height())
+        .map(|row| {
+            let record = columns
+                .iter()
+                .map(|column| {
+                    let value = column.get(row).map(any_value_to_json).unwrap_or(Value::Null);
+                    (column.name().to_string(), value)
+                })
+                .collect();
+            Value::Object(record)
+        })
+        .collect()
+}
+
+// Get a DataFrame as newline-delimited JSON records
+fn df_to_ndjson(df: &DataFrame) -> String {
+    let mut out = String::new();
+    for record in df_to_json_records(df) {
+        out.push_str(&record.to_string());
+        out.push('\n');
+    }
+    out
+}
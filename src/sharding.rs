@@ -0,0 +1,522 @@
+// Sharding (`[sharding]`, or `--shards <LIST>`): a collator that's the front-end to others (its shards), for collection
+// jobs too big for one machine. It holds no rows itself. Each row it's sent goes to the shard that owns the row's key:
+// the values of the dataset's shard key columns, as text, are hashed, and each shard owns an equal range of the
+// hashes, in the order the shards are listed. An `/aggregate` is sharded by its group columns (which the shard key has
+// to be among), so every group lives on one shard, and the front-end's answer is every shard's groups put together.
+// `/data` asks every shard for its part and merges them into one page.
+
+use std::io::Cursor;
+
+use axum::{body::Bytes, http::HeaderMap};
+use futures::future::join_all;
+use polars::prelude::*;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::{
+    aggregate::{is_json_body, parse_aggregate_body, AggregateParams},
+    collator::{parse_sort, ReadOptions},
+    dataset::AppState,
+    error::AppError,
+    http::{self, percent_encode, Endpoint},
+    payload::{read_payload, write_df, FileFormat, ARROW_STREAM_CONTENT_TYPE},
+    provenance::Origin,
+    serialize::df_to_csv,
+    types::{AggregateRequest, ErrorResponse, IngestCounts},
+};
+
+// Request headers passed on to the shards as they are
+const PASSED_HEADERS: &[&str] = &["x-concat-mode", "x-aggregate-op"];
+
+// What an ingest did across the shards: the rows (or, for an aggregate, every shard's groups), how many the payload
+// had, where they went, and the shards' counts added up
+pub(crate) struct Sharded {
+    pub rows: DataFrame,
+    pub ingested: usize,
+    pub wrote_to_file: String,
+    pub counts: IngestCounts,
+}
+
+// One of the shards, by its place in the list
+struct Shard {
+    index: usize,
+    url: String,
+    endpoint: Endpoint,
+}
+
+fn shards(state: &AppState) -> Vec<Shard> {
+    let urls = state.config.sharding.shards.iter().enumerate();
+    urls.map(|(index, url)| Shard {
+        index,
+        url: url.clone(),
+        endpoint: Endpoint::parse(url).expect("the URLs are checked by Config::validate"),
+    })
+    .collect()
+}
+
+// Whose range of hashes `hash` is in, of `shards` equal ranges
+fn owner(hash: u64, shards: usize) -> usize {
+    ((hash as u128 * shards as u128) >> 64) as usize
+}
+
+// A dataset's rows, split by the shard that owns each row's key
+fn split(df: &DataFrame, key: &[String], shards: usize) -> Result<Vec<DataFrame>, AppError> {
+    let mut columns = Vec::with_capacity(key.len());
+    for name in key {
+        let column = df.column(name).map_err(|_| {
+            AppError::BadRequest(format!("The rows need the column {:?}, which picks the shard they go to", name))
+        })?;
+        columns.push(column.cast(&DataType::String).map_err(|e| AppError::BadRequest(e.to_string()))?);
+    }
+    let mut values = Vec::with_capacity(columns.len());
+    for column in &columns {
+        values.push(column.str().map_err(|e| AppError::Internal(e.to_string()))?.into_iter());
+    }
+
+    let mut rows: Vec<Vec<IdxSize>> = vec![Vec::new(); shards];
+    let mut bytes = Vec::new();
+    for row in 0..df.height() {
+        // Each value is length-prefixed, so `("a,b", "c")` and `("a", "b,c")` hash differently, and null is told apart
+        // from ""
+        bytes.clear();
+        for value in values.iter_mut() {
+            match value.next().flatten() {
+                Some(value) => {
+                    bytes.extend_from_slice(&(value.len() as u64).to_le_bytes());
+                    bytes.extend_from_slice(value.as_bytes());
+                }
+                None => bytes.extend_from_slice(&u64::MAX.to_le_bytes()),
+            }
+        }
+        rows[owner(xxh3_64(&bytes), shards)].push(row as IdxSize);
+    }
+
+    rows.into_iter()
+        .map(|rows| df.take(&IdxCa::from_vec(PlSmallStr::EMPTY, rows)).map_err(|e| AppError::Internal(e.to_string())))
+        .collect()
+}
+
+// The columns a dataset's rows are sharded by, or a `400` if there aren't any
+fn shard_key<'a>(state: &'a AppState, name: &str) -> Result<&'a [String], AppError> {
+    let key = state.config.sharding.key(name);
+    if key.is_empty() {
+        return Err(AppError::BadRequest(format!(
+            "Dataset {:?} has no shard key to pick each row's shard by; set sharding.key or sharding.keys.{}",
+            name, name
+        )));
+    }
+    Ok(key)
+}
+
+// Headers every request to a shard carries: the write key, who the rows came from, and the request's own headers that
+// change what the shard does. A request with an `Idempotency-Key` sends each shard a key of its own, the same on every
+// retry, so a shard that already took its part replays its answer instead of collating the rows again.
+fn shard_headers(
+    state: &AppState,
+    headers: &HeaderMap,
+    origin: Option<&Origin>,
+    shard: &Shard,
+) -> Vec<(&'static str, String)> {
+    let mut sent = Vec::new();
+    if let Some(api_key) = &state.config.sharding.api_key {
+        sent.push(("Authorization", format!("Bearer {}", api_key)));
+    }
+    if let Some(origin) = origin {
+        sent.push(("X-Source-Id", origin.source.clone()));
+    }
+    for &name in PASSED_HEADERS {
+        if let Some(value) = headers.get(name).and_then(|value| value.to_str().ok()) {
+            sent.push((name, value.to_string()));
+        }
+    }
+    if let Some(key) = headers.get("idempotency-key").and_then(|value| value.to_str().ok()) {
+        sent.push(("Idempotency-Key", format!("{}-shard{}", key, shard.index)));
+    }
+    sent
+}
+
+// A shard's answer to a request it didn't take, as the front-end's error
+fn shard_error(shard: &Shard, response: &http::Response) -> AppError {
+    let message = match serde_json::from_slice::<ErrorResponse>(&response.body) {
+        Ok(error) => error.message,
+        Err(_) => format!("{} {}", response.status, String::from_utf8_lossy(&response.body).trim()),
+    };
+    let message = format!("Shard {} answered: {}", shard.url, message);
+    match response.status {
+        400 => AppError::BadRequest(message),
+        404 => AppError::NotFound(message),
+        408 => AppError::RequestTimeout(message),
+        413 => AppError::PayloadTooLarge(message),
+        415 => AppError::UnsupportedMediaType(message),
+        422 => AppError::SchemaMismatch(message),
+        429 => AppError::TooManyRequests(message),
+        _ => AppError::Internal(message),
+    }
+}
+
+// Rows an Arrow stream from a shard holds
+fn rows_in(shard: &Shard, response: &http::Response) -> Result<DataFrame, AppError> {
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", ARROW_STREAM_CONTENT_TYPE.parse().expect("the content type is a valid header"));
+    read_payload(&headers, Cursor::new(response.body.as_slice()))
+        .map_err(|e| AppError::Internal(format!("Shard {} sent rows that can't be read: {}", shard.url, e)))
+}
+
+// Add the counts a shard's answer has (in its headers) to the ones so far
+fn add_counts(counts: &mut IngestCounts, response: &http::Response) {
+    let fields = [
+        (&mut counts.quarantined, "x-quarantined-rows"),
+        (&mut counts.replaced, "x-replaced-rows"),
+        (&mut counts.joined, "x-joined-rows"),
+        (&mut counts.skipped, "x-skipped-rows"),
+        (&mut counts.filtered, "x-filtered-rows"),
+    ];
+    for (count, header) in fields {
+        if let Some(added) = response.header(header).and_then(|value| value.parse::<usize>().ok()) {
+            *count = Some(count.unwrap_or(0) + added);
+        }
+    }
+}
+
+// Put the shards' rows together, widening the columns that don't agree (as `/collate`'s union mode does)
+fn concatenated(frames: Vec<DataFrame>) -> Result<DataFrame, AppError> {
+    if frames.is_empty() {
+        return Ok(DataFrame::empty());
+    }
+    let args = UnionArgs {
+        rechunk: true,
+        to_supertypes: true,
+        ..Default::default()
+    };
+    concat_lf_diagonal(frames.into_iter().map(DataFrame::lazy).collect::<Vec<_>>(), args)
+        .and_then(|lf| lf.collect())
+        .map_err(|e| AppError::Internal(format!("Can't put the shards' rows together: {}", e)))
+}
+
+// Fail the request with the first shard's error, saying what the others did with their part
+fn failed(mut errors: Vec<AppError>, took: usize, headers: &HeaderMap) -> AppError {
+    let error = errors.remove(0);
+    if took == 0 {
+        return error;
+    }
+    let retry = match headers.contains_key("idempotency-key") {
+        true => "sending it again with the same Idempotency-Key only collates the rest",
+        false => "sending it again without an Idempotency-Key would collate theirs twice",
+    };
+    let message = format!("{} ({} other shards took their part of the payload; {})", error.message(), took, retry);
+    match error {
+        AppError::BadRequest(_) => AppError::BadRequest(message),
+        AppError::NotFound(_) => AppError::NotFound(message),
+        AppError::RequestTimeout(_) => AppError::RequestTimeout(message),
+        AppError::PayloadTooLarge(_) => AppError::PayloadTooLarge(message),
+        AppError::UnsupportedMediaType(_) => AppError::UnsupportedMediaType(message),
+        AppError::SchemaMismatch(_) => AppError::SchemaMismatch(message),
+        AppError::TooManyRequests(_) => AppError::TooManyRequests(message),
+        _ => AppError::Internal(message),
+    }
+}
+
+fn wrote_to_file(touched: usize, shards: usize) -> String {
+    format!("sharded to {} of {} shards", touched, shards)
+}
+
+// Send each shard its part of a payload, all at once. `build` makes a shard's request (its route, content type, and
+// body) from its rows. With `minimal`, the shards are asked to leave the dataset's rows out of their answers. Returns
+// every shard, with its answer if it had rows.
+async fn scatter<F>(
+    state: &AppState,
+    headers: &HeaderMap,
+    origin: &Origin,
+    parts: Vec<DataFrame>,
+    minimal: bool,
+    mut build: F,
+) -> Result<Vec<(Shard, Option<http::Response>)>, AppError>
+where
+    F: FnMut(DataFrame) -> Result<(String, &'static str, Vec<u8>), AppError>,
+{
+    let mut requests = Vec::new();
+    for (shard, part) in shards(state).into_iter().zip(parts) {
+        if part.height() == 0 {
+            requests.push((shard, None));
+            continue;
+        }
+        let (target, content_type, body) = build(part)?;
+        requests.push((shard, Some((target, content_type, body))));
+    }
+
+    let sent = requests.into_iter().map(|(shard, request)| async move {
+        let Some((target, content_type, body)) = request else {
+            return (shard, Ok(None));
+        };
+        let mut sent = shard_headers(state, headers, Some(origin), &shard);
+        sent.push(("Content-Type", content_type.to_string()));
+        sent.push(("Accept", ARROW_STREAM_CONTENT_TYPE.to_string()));
+        if minimal {
+            sent.push(("Prefer", String::from("return=minimal")));
+        }
+        let target = format!("{}{}", shard.endpoint.path, target);
+        let response = http::send(&shard.endpoint, "POST", &target, &sent, &body).await;
+        (shard, response.map(Some))
+    });
+
+    let mut answers = Vec::new();
+    let mut errors = Vec::new();
+    let mut took = 0;
+    for (shard, response) in join_all(sent).await {
+        match response {
+            Ok(Some(response)) if response.is_success() => {
+                took += 1;
+                answers.push((shard, Some(response)));
+            }
+            Ok(Some(response)) => errors.push(shard_error(&shard, &response)),
+            Ok(None) => answers.push((shard, None)),
+            Err(e) => errors.push(AppError::Internal(format!("Can't reach shard {}: {}", shard.url, e))),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(failed(errors, took, headers));
+    }
+    Ok(answers)
+}
+
+// A route on the shards, with the request's query string (which each shard gets as it is)
+fn route(name: &str, endpoint: &str, query: Option<&str>) -> String {
+    match query {
+        Some(query) => format!("/datasets/{}/{}?{}", percent_encode(name), endpoint, query),
+        None => format!("/datasets/{}/{}", percent_encode(name), endpoint),
+    }
+}
+
+// Collate a payload's rows into a dataset, each into the shard that owns its key. The rows returned are the
+// payload's, since only the shards have the rest.
+pub(crate) async fn collate(
+    state: &AppState,
+    name: &str,
+    query: Option<&str>,
+    headers: &HeaderMap,
+    origin: &Origin,
+    df: DataFrame,
+) -> Result<Sharded, AppError> {
+    let key = shard_key(state, name)?.to_vec();
+    let shard_count = state.config.sharding.shards.len();
+    let (df, parts) = state
+        .compute
+        .run(move || {
+            let parts = split(&df, &key, shard_count)?;
+            Ok::<_, AppError>((df, parts))
+        })
+        .await??;
+
+    let target = route(name, "collate", query);
+    let answers = scatter(state, headers, origin, parts, true, |mut part| {
+        let body = write_df(&mut part, FileFormat::Arrow).map_err(AppError::Internal)?;
+        Ok((target.clone(), ARROW_STREAM_CONTENT_TYPE, body))
+    })
+    .await?;
+
+    let mut counts = IngestCounts::default();
+    let mut touched = 0;
+    for response in answers.iter().filter_map(|(_, response)| response.as_ref()) {
+        touched += 1;
+        add_counts(&mut counts, response);
+    }
+    Ok(Sharded {
+        ingested: df.height(),
+        rows: df,
+        wrote_to_file: wrote_to_file(touched, shard_count),
+        counts,
+    })
+}
+
+// Aggregate a payload into a dataset, each group's rows on the shard that owns the group. The shard key has to be
+// among the group columns (or, without one, the group columns are the shard key), or a group's rows would be split
+// between shards. Returns every shard's groups: the whole aggregate.
+pub(crate) async fn aggregate(
+    state: &AppState,
+    name: &str,
+    params: AggregateParams,
+    query: Option<&str>,
+    headers: &HeaderMap,
+    origin: &Origin,
+    body: Bytes,
+) -> Result<Sharded, AppError> {
+    let configured = state.config.sharding.key(name).to_vec();
+    let shard_count = state.config.sharding.shards.len();
+    let op = state.config.aggregate.op;
+    let lookup = params.lookup.clone();
+    let parse_headers = headers.clone();
+    let (parts, request) = state
+        .compute
+        .run(move || {
+            let text = std::str::from_utf8(&body)
+                .map_err(|e| AppError::BadRequest(format!("The request body is not valid UTF-8: {}", e)))?;
+            let (df, spec) = parse_aggregate_body(&params, &parse_headers, text, op)?;
+            let key = if configured.is_empty() { spec.keys.clone() } else { configured };
+            if key.is_empty() {
+                return Err(AppError::BadRequest(String::from(
+                    "An aggregate without group columns can't be sharded (each shard would have part of its one group)",
+                )));
+            }
+            if let Some(column) = key.iter().find(|column| !spec.keys.contains(column)) {
+                return Err(AppError::BadRequest(format!(
+                    "The shard key column {:?} has to be one of the aggregate's group columns, so that each group's \
+                     rows are on one shard",
+                    column
+                )));
+            }
+            // A JSON body is sent on as JSON, with the rest of what it asks for, and just the shard's part of the CSV
+            let request = match is_json_body(&parse_headers) {
+                true => {
+                    let request = serde_json::from_str::<AggregateRequest>(text);
+                    Some(request.map_err(|e| AppError::BadRequest(e.to_string()))?)
+                }
+                false => None,
+            };
+            Ok((split(&df, &key, shard_count)?, request))
+        })
+        .await??;
+
+    let ingested = parts.iter().map(DataFrame::height).sum();
+    let target = route(name, "aggregate", query);
+    let answers = scatter(state, headers, origin, parts, false, |mut part| {
+        let csv = df_to_csv(&mut part, true);
+        match &request {
+            Some(request) => {
+                let request = AggregateRequest { csv, ..request.clone() };
+                let body = serde_json::to_vec(&request).expect("aggregate requests serialize");
+                Ok((target.clone(), "application/json", body))
+            }
+            None => Ok((target.clone(), "text/csv", csv.into_bytes())),
+        }
+    })
+    .await?;
+
+    // The shards that weren't sent any rows are asked for their groups
+    let mut counts = IngestCounts::default();
+    let mut touched = 0;
+    let mut unsent = Vec::new();
+    let mut groups = Vec::new();
+    for (shard, response) in answers {
+        match response {
+            Some(response) => {
+                touched += 1;
+                add_counts(&mut counts, &response);
+                groups.push((shard.index, rows_in(&shard, &response)?));
+            }
+            None => unsent.push(shard),
+        }
+    }
+    let options = ReadOptions {
+        lookup,
+        ..Default::default()
+    };
+    let gathered = gather(state, name, unsent, &options).await.map_err(|e| {
+        let message = format!("Aggregated, but the other shards' groups can't be read: {}", e.message());
+        AppError::Internal(message)
+    })?;
+    groups.extend(gathered.into_iter().map(|(shard, rows, _)| (shard, rows)));
+    groups.sort_by_key(|(shard, _)| *shard);
+
+    Ok(Sharded {
+        rows: concatenated(groups.into_iter().map(|(_, rows)| rows).collect())?,
+        ingested,
+        wrote_to_file: wrote_to_file(touched, shard_count),
+        counts,
+    })
+}
+
+// Each shard's rows of a dataset for `/data`, in Arrow (so they keep their dtypes), with how many rows it has that
+// match the filter. A shard that doesn't have the dataset is left out.
+async fn gather(
+    state: &AppState,
+    name: &str,
+    shards: Vec<Shard>,
+    options: &ReadOptions,
+) -> Result<Vec<(usize, DataFrame, usize)>, AppError> {
+    let mut query = vec![("format", String::from("arrow"))];
+    for (parameter, value) in [("filter", &options.filter), ("lookup", &options.lookup), ("as_of", &options.as_of)] {
+        if let Some(value) = value {
+            query.push((parameter, value.clone()));
+        }
+    }
+    // Each shard sorts its rows and returns the ones that could make the page. The columns are picked once they're
+    // sorted together, since the sort may be by columns that aren't returned.
+    match &options.sort {
+        Some(sort) => query.push(("sort", sort.clone())),
+        None => {
+            if let Some(columns) = &options.columns {
+                query.push(("columns", columns.join(",")));
+            }
+        }
+    }
+    if let Some(limit) = options.limit {
+        query.push(("limit", options.offset.saturating_add(limit).to_string()));
+    }
+    let query: Vec<String> =
+        query.iter().map(|(parameter, value)| format!("{}={}", parameter, percent_encode(value))).collect();
+    let target = route(name, "data", Some(&query.join("&")));
+
+    let missing = AppError::dataset_not_found(name);
+    let requests = shards.into_iter().map(|shard| {
+        let target = format!("{}{}", shard.endpoint.path, target);
+        let headers = shard_headers(state, &HeaderMap::new(), None, &shard);
+        async move {
+            let response = http::send(&shard.endpoint, "GET", &target, &headers, &[]).await;
+            (shard, response)
+        }
+    });
+
+    let mut gathered = Vec::new();
+    for (shard, response) in join_all(requests).await {
+        let response = response.map_err(|e| AppError::Internal(format!("Can't reach shard {}: {}", shard.url, e)))?;
+        if !response.is_success() {
+            let error = shard_error(&shard, &response);
+            // Rows that haven't been sent to a shard yet haven't made the dataset there
+            if response.status == 404 && error.message().ends_with(missing.message()) {
+                continue;
+            }
+            return Err(error);
+        }
+        let total = response.header("x-total-rows").and_then(|total| total.parse().ok()).unwrap_or(0);
+        gathered.push((shard.index, rows_in(&shard, &response)?, total));
+    }
+    Ok(gathered)
+}
+
+// A page of a dataset's rows, read from every shard and merged: sorted (if asked), then offset and limited across all
+// of them. Returns the page, and how many rows the shards have between them that match the filter.
+pub(crate) async fn read(state: &AppState, name: &str, options: &ReadOptions) -> Result<(DataFrame, usize), AppError> {
+    let gathered = gather(state, name, shards(state), options).await?;
+    if gathered.is_empty() {
+        return Err(AppError::dataset_not_found(name));
+    }
+    let total_rows = gathered.iter().map(|(_, _, total)| total).sum();
+    let rows = concatenated(gathered.into_iter().map(|(_, rows, _)| rows).collect())?;
+
+    let options = options.clone();
+    let page = state
+        .compute
+        .run(move || {
+            let schema = rows.schema().clone();
+            let mut plan = rows.lazy();
+            if let Some(sort) = &options.sort {
+                let (columns, descending) = parse_sort(sort, &schema).map_err(AppError::BadRequest)?;
+                let sort_options = SortMultipleOptions::default()
+                    .with_order_descending_multi(descending)
+                    .with_nulls_last(true)
+                    .with_maintain_order(true);
+                plan = plan.sort_by_exprs(columns, sort_options);
+            }
+            if let Some(columns) = &options.columns {
+                if let Some(missing) = columns.iter().find(|column| schema.get(column).is_none()) {
+                    return Err(AppError::BadRequest(format!("Column {:?} doesn't exist", missing)));
+                }
+                plan = plan.select(columns.iter().map(|column| col(column.as_str())).collect::<Vec<_>>());
+            }
+            let length = options.limit.unwrap_or(usize::MAX).min(IdxSize::MAX as usize) as IdxSize;
+            let plan = plan.slice(options.offset.min(i64::MAX as usize) as i64, length);
+            plan.collect().map_err(|e| AppError::BadRequest(e.to_string()))
+        })
+        .await??;
+
+    Ok((page, total_rows))
+}
@@ -0,0 +1,30 @@
+// What the background tasks that send accepted rows on to another service (Postgres, ClickHouse, a parent collator,
+// replicas) have in common: a bounded queue in front of them, and retries that back off.
+
+use std::time::Duration;
+
+// How many batches can wait for a sink before new ones are dropped
+pub const QUEUE_CAPACITY: usize = 1024;
+
+// The longest a retry waits, however many attempts have failed
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// How long to wait before retrying after `attempt` failures in a row (counting from 0). Doubles with every failed
+// attempt: `base`, `2 * base`, `4 * base`, ..., up to `MAX_BACKOFF`.
+pub fn backoff(base_ms: u64, attempt: u32) -> Duration {
+    Duration::from_millis(base_ms.saturating_mul(1 << attempt.min(16))).min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        let delays: Vec<u64> = (0..8).map(|attempt| backoff(500, attempt).as_millis() as u64).collect();
+        assert_eq!(delays, [500, 1000, 2000, 4000, 8000, 16_000, 32_000, 60_000]);
+        assert_eq!(backoff(500, u32::MAX), MAX_BACKOFF);
+        assert_eq!(backoff(u64::MAX, 3), MAX_BACKOFF);
+        assert_eq!(backoff(0, 5), Duration::ZERO);
+    }
+}
@@ -0,0 +1,310 @@
+// Sketches for approximate aggregation (`aggregate.mode = "approximate"`): small summaries of a group's values that can
+// be merged with each other, so a running aggregate can answer quantiles and distinct counts without keeping the raw
+// rows. Quantiles come from a t-digest (clusters of nearby values, smallest at the tails, where p99 needs them), and
+// distinct counts from a HyperLogLog (the longest run of leading zeros seen among the values' hashes). Both are stored
+// as bytes in the running aggregate's table.
+
+use std::f64::consts::PI;
+
+// How many clusters a digest keeps, roughly: more is more accurate and larger
+const COMPRESSION: f64 = 100.0;
+
+// HyperLogLog counts use 2^12 registers, for a relative error of about 1.6%
+const PRECISION: u32 = 12;
+const REGISTERS: usize = 1 << PRECISION;
+
+// Values that fall into one cluster of a digest
+#[derive(Debug, Clone, Copy)]
+struct Cluster {
+    mean: f64,
+    weight: f64,
+}
+
+// A t-digest of a group's (non-null, non-NaN) values
+#[derive(Debug, Clone, Default)]
+pub struct TDigest {
+    // Sorted by mean
+    clusters: Vec<Cluster>,
+    min: f64,
+    max: f64,
+}
+
+impl TDigest {
+    pub fn of_values(values: impl IntoIterator<Item = f64>) -> Self {
+        let clusters = values
+            .into_iter()
+            .filter(|value| !value.is_nan())
+            .map(|mean| Cluster { mean, weight: 1.0 })
+            .collect();
+        TDigest::compressed(clusters)
+    }
+
+    // One digest of everything the given (encoded) digests summarize
+    pub fn merged<'a>(digests: impl IntoIterator<Item = &'a [u8]>) -> Result<Self, String> {
+        let mut clusters = Vec::new();
+        // The clusters at the tails may be means of several values, so the extremes come from the digests instead
+        let (mut min, mut max) = (f64::INFINITY, f64::NEG_INFINITY);
+        for bytes in digests {
+            let digest = TDigest::from_bytes(bytes)?;
+            if !digest.clusters.is_empty() {
+                min = min.min(digest.min);
+                max = max.max(digest.max);
+            }
+            clusters.extend(digest.clusters);
+        }
+
+        let mut merged = TDigest::compressed(clusters);
+        if !merged.clusters.is_empty() {
+            (merged.min, merged.max) = (min, max);
+        }
+        Ok(merged)
+    }
+
+    // The value at quantile `q` (0 to 1), interpolated linearly between clusters. While every cluster is still a
+    // single value, that's exactly the `linear` quantile of the values themselves. `None` for a digest of no values.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        let first = self.clusters.first()?;
+        if self.clusters.len() == 1 {
+            return Some(first.mean);
+        }
+
+        // A cluster's mean sits at the middle of its weight, and the minimum and maximum at the middle of the first
+        // and last value
+        let total: f64 = self.clusters.iter().map(|cluster| cluster.weight).sum();
+        let target = q.clamp(0.0, 1.0) * (total - 1.0) + 0.5;
+        let mut below = (0.5, self.min);
+        let mut cumulative = 0.0;
+        for cluster in &self.clusters {
+            let middle = cumulative + cluster.weight / 2.0;
+            if target < middle {
+                return Some(interpolate(below, (middle, cluster.mean), target));
+            }
+            below = (middle, cluster.mean);
+            cumulative += cluster.weight;
+        }
+        Some(interpolate(below, (total - 0.5, self.max), target))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(16 + self.clusters.len() * 16);
+        bytes.extend_from_slice(&self.min.to_le_bytes());
+        bytes.extend_from_slice(&self.max.to_le_bytes());
+        for cluster in &self.clusters {
+            bytes.extend_from_slice(&cluster.mean.to_le_bytes());
+            bytes.extend_from_slice(&cluster.weight.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if !bytes.len().is_multiple_of(16) {
+            return Err(format!("A quantile sketch of {} bytes is corrupt", bytes.len()));
+        }
+        let floats: Vec<f64> = bytes
+            .chunks_exact(8)
+            .map(|chunk| f64::from_le_bytes(chunk.try_into().expect("chunks are 8 bytes")))
+            .collect();
+        let Some((ends, clusters)) = floats.split_first_chunk::<2>() else {
+            return Ok(TDigest::default());
+        };
+
+        Ok(TDigest {
+            clusters: clusters.chunks_exact(2).map(|pair| Cluster { mean: pair[0], weight: pair[1] }).collect(),
+            min: ends[0],
+            max: ends[1],
+        })
+    }
+
+    // Sort clusters and merge neighbours, as far as the size limit allows at their place in the distribution
+    fn compressed(mut clusters: Vec<Cluster>) -> Self {
+        clusters.retain(|cluster| cluster.weight > 0.0);
+        clusters.sort_by(|a, b| a.mean.total_cmp(&b.mean));
+        let (Some(first), Some(last)) = (clusters.first(), clusters.last()) else {
+            return TDigest::default();
+        };
+        let (min, max) = (first.mean, last.mean);
+
+        let total: f64 = clusters.iter().map(|cluster| cluster.weight).sum();
+        let mut merged = Vec::new();
+        let mut clusters = clusters.into_iter();
+        let mut current = clusters.next().expect("there's at least one cluster");
+        let mut before = 0.0;
+        let mut limit = total * next_quantile(0.0);
+        for next in clusters {
+            if before + current.weight + next.weight <= limit {
+                let weight = current.weight + next.weight;
+                current.mean += (next.mean - current.mean) * next.weight / weight;
+                current.weight = weight;
+            } else {
+                before += current.weight;
+                merged.push(current);
+                limit = total * next_quantile(before / total);
+                current = next;
+            }
+        }
+        merged.push(current);
+
+        TDigest {
+            clusters: merged,
+            min,
+            max,
+        }
+    }
+}
+
+// How far a cluster starting at quantile `q` may reach. The scale is `COMPRESSION / 2π · asin(2q - 1)`, and each
+// cluster covers one unit of it, so clusters are smallest at the tails.
+fn next_quantile(q: f64) -> f64 {
+    let k = COMPRESSION / (2.0 * PI) * (2.0 * q - 1.0).asin() + 1.0;
+    let k = k.min(COMPRESSION / 4.0);
+    ((k * 2.0 * PI / COMPRESSION).sin() + 1.0) / 2.0
+}
+
+fn interpolate((x0, y0): (f64, f64), (x1, y1): (f64, f64), x: f64) -> f64 {
+    if x1 <= x0 {
+        return y1;
+    }
+    let t = ((x - x0) / (x1 - x0)).clamp(0.0, 1.0);
+    y0 + (y1 - y0) * t
+}
+
+// A HyperLogLog of a group's (non-null) values
+#[derive(Debug, Clone)]
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        HyperLogLog {
+            registers: vec![0; REGISTERS],
+        }
+    }
+}
+
+impl HyperLogLog {
+    // Values are compared by their bytes, so they should all be rendered the same way (e.g. cast to text)
+    pub fn of_values<'a>(values: impl IntoIterator<Item = &'a [u8]>) -> Self {
+        let mut distinct = HyperLogLog::default();
+        for value in values {
+            let hash = hash(value);
+            let register = (hash >> (64 - PRECISION)) as usize;
+            let rank = ((hash << PRECISION).leading_zeros() + 1).min(64 - PRECISION + 1) as u8;
+            distinct.registers[register] = distinct.registers[register].max(rank);
+        }
+        distinct
+    }
+
+    // One sketch of everything the given (encoded) sketches counted
+    pub fn merged<'a>(sketches: impl IntoIterator<Item = &'a [u8]>) -> Result<Self, String> {
+        let mut distinct = HyperLogLog::default();
+        for bytes in sketches {
+            for (register, rank) in distinct.registers.iter_mut().zip(HyperLogLog::from_bytes(bytes)?.registers) {
+                *register = (*register).max(rank);
+            }
+        }
+        Ok(distinct)
+    }
+
+    // The estimated number of distinct values, counting the empty registers instead while that's more accurate
+    pub fn estimate(&self) -> u64 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|rank| 2f64.powi(-(*rank as i32))).sum();
+        let estimate = alpha * m * m / sum;
+
+        let empty = self.registers.iter().filter(|rank| **rank == 0).count();
+        if estimate <= 2.5 * m && empty > 0 {
+            return (m * (m / empty as f64).ln()).round() as u64;
+        }
+        estimate.round() as u64
+    }
+
+    // Sketches of a few values list the registers they set (2 bytes of register and 1 of rank each), the rest are
+    // every register's rank
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let set: Vec<(usize, u8)> = self.registers.iter().copied().enumerate().filter(|(_, rank)| *rank > 0).collect();
+        if set.len() * 3 < REGISTERS {
+            let mut bytes = vec![0];
+            for (register, rank) in set {
+                bytes.extend_from_slice(&(register as u16).to_le_bytes());
+                bytes.push(rank);
+            }
+            bytes
+        } else {
+            let mut bytes = vec![1];
+            bytes.extend_from_slice(&self.registers);
+            bytes
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let corrupt = || format!("A distinct count sketch of {} bytes is corrupt", bytes.len());
+        let mut distinct = HyperLogLog::default();
+        match bytes.split_first() {
+            Some((0, set)) if set.len().is_multiple_of(3) => {
+                for entry in set.chunks_exact(3) {
+                    let register = u16::from_le_bytes([entry[0], entry[1]]) as usize;
+                    *distinct.registers.get_mut(register).ok_or_else(corrupt)? = entry[2];
+                }
+            }
+            Some((1, registers)) if registers.len() == REGISTERS => distinct.registers.copy_from_slice(registers),
+            _ => return Err(corrupt()),
+        }
+        Ok(distinct)
+    }
+}
+
+// A 64-bit hash that stays the same across builds and runs, since sketches outlive the process (in snapshots): FNV-1a,
+// with MurmurHash3's finalizer to spread it over every bit
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merging_keeps_the_extremes() {
+        // Enough values that the clusters at the tails hold more than one, so their means aren't the extremes
+        let low = TDigest::of_values((0..100_000).map(|i| i as f64 / 1000.0)).to_bytes();
+        let high = TDigest::of_values((0..100_000).map(|i| 50.0 + i as f64 / 1000.0)).to_bytes();
+
+        let mut merged = TDigest::merged([low.as_slice(), high.as_slice()]).unwrap();
+        assert_eq!((merged.min, merged.max), (0.0, 149.999));
+        // And again for every merge after it, the way a running aggregate merges each batch into its state
+        for i in 0..10 {
+            let batch = TDigest::of_values([60.0 + i as f64]).to_bytes();
+            merged = TDigest::merged([merged.to_bytes().as_slice(), batch.as_slice()]).unwrap();
+        }
+        assert_eq!(merged.quantile(0.0), Some(0.0));
+        assert_eq!(merged.quantile(1.0), Some(149.999));
+
+        // Digests of no values don't count
+        let empty = TDigest::default().to_bytes();
+        let merged = TDigest::merged([empty.as_slice(), high.as_slice()]).unwrap();
+        assert_eq!((merged.min, merged.max), (50.0, 149.999));
+        assert_eq!(TDigest::merged([empty.as_slice()]).unwrap().quantile(0.5), None);
+    }
+
+    #[test]
+    fn exact_while_small() {
+        let digest = TDigest::of_values([3.0, 1.0, f64::NAN, 2.0, 4.0]);
+        assert_eq!(digest.quantile(0.0), Some(1.0));
+        assert_eq!(digest.quantile(0.5), Some(2.5));
+        assert_eq!(digest.quantile(1.0), Some(4.0));
+        let bytes = digest.to_bytes();
+        assert_eq!(TDigest::from_bytes(&bytes).unwrap().quantile(0.25), Some(1.75));
+        assert!(TDigest::from_bytes(&bytes[..20]).is_err());
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    fs::File,
+    io::Write as _,
+    path::{Path, PathBuf},
+};
+
+use chrono::Utc;
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    aggregate::{AggregateState, RunningAggregate, RunningLayout},
+    computed::ComputedColumns,
+    dataset::Dataset,
+    schema::DatasetSchema,
+};
+
+// What `/aggregate` was recomputing the dataset from when the snapshot was taken
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum SnapshotAggregate {
+    // The raw rows (kept in `<id>.aggregate.arrow`)
+    History,
+    // Running partials per key (kept in `<id>.aggregate.arrow`)
+    Running(RunningLayout),
+}
+
+// A snapshot's description, stored as `<id>.json` next to its data. It's written last, so a snapshot interrupted
+// halfway through is never listed or restored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotInfo {
+    pub id: String,
+    pub dataset: String,
+    // The label it was taken with, if any
+    pub name: Option<String>,
+    // RFC 3339
+    pub created_at: String,
+    pub rows: usize,
+    pub schema: Option<DatasetSchema>,
+    // Missing from snapshots taken before computed columns existed
+    #[serde(default)]
+    pub computed: Option<ComputedColumns>,
+    pub aggregate: Option<SnapshotAggregate>,
+}
+
+// Snapshots are stored as Arrow IPC files (Parquet isn't available in this build)
+const DATA_EXT: &str = "arrow";
+
+// Snapshot ids are used as file names, so they're held to the same rules as dataset names
+pub fn validate_snapshot_id(id: &str) -> Result<(), String> {
+    let valid = !id.is_empty()
+        && id.len() <= 160
+        && !id.starts_with('.')
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "Invalid snapshot id {:?} (use letters, digits, '_', '-' and '.', not starting with '.')",
+            id
+        ))
+    }
+}
+
+// Write a dataset's current state (rows, aggregate state, schema, and computed columns) as a new snapshot. Its id is
+// the time it was taken, plus the label if there is one, so ids sort by age.
+pub fn write_snapshot(dir: &Path, dataset_name: &str, dataset: &Dataset, name: Option<String>) -> Result<SnapshotInfo, String> {
+    let now = Utc::now();
+    let id = match &name {
+        Some(name) => format!("{}-{}", now.format("%Y%m%dT%H%M%S%.3fZ"), name),
+        None => now.format("%Y%m%dT%H%M%S%.3fZ").to_string(),
+    };
+    validate_snapshot_id(&id)?;
+
+    let dir = dir.join(dataset_name);
+    std::fs::create_dir_all(&dir).map_err(|e| format!("Can't create snapshot directory {:?}: {}", dir, e))?;
+    if info_path(&dir, &id).exists() {
+        return Err(format!("Snapshot {:?} already exists", id));
+    }
+
+    // Rows spilled to disk are part of the snapshot too
+    let mut df = dataset.rows().collect()?;
+    write_ipc(&mut df, &data_path(&dir, &id))?;
+
+    let aggregate = match &dataset.aggregate_state {
+        Some(AggregateState::History(history)) => {
+            write_ipc(&mut history.clone(), &aggregate_path(&dir, &id))?;
+            Some(SnapshotAggregate::History)
+        }
+        Some(AggregateState::Running(running)) => {
+            write_ipc(&mut running.table().clone(), &aggregate_path(&dir, &id))?;
+            Some(SnapshotAggregate::Running(running.layout()))
+        }
+        None => None,
+    };
+
+    let info = SnapshotInfo {
+        id,
+        dataset: dataset_name.to_string(),
+        name,
+        created_at: now.to_rfc3339(),
+        rows: df.height(),
+        schema: dataset.schema.clone(),
+        computed: dataset.computed.clone(),
+        aggregate,
+    };
+    let json = serde_json::to_vec_pretty(&info).map_err(|e| e.to_string())?;
+    let path = info_path(&dir, &info.id);
+    File::create(&path)
+        .and_then(|mut file| file.write_all(&json).and_then(|()| file.sync_all()))
+        .map_err(|e| format!("Can't write snapshot {:?}: {}", path, e))?;
+
+    Ok(info)
+}
+
+// Every complete snapshot of a dataset, oldest first
+pub fn list_snapshots(dir: &Path, dataset_name: &str) -> Result<Vec<SnapshotInfo>, String> {
+    let dir = dir.join(dataset_name);
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Can't read snapshot directory {:?}: {}", dir, e)),
+    };
+
+    let mut snapshots = Vec::new();
+    for entry in entries {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            snapshots.push(read_info(&path)?);
+        }
+    }
+    snapshots.sort_by(|a, b| a.id.cmp(&b.id));
+
+    Ok(snapshots)
+}
+
+// Load a snapshot back into a dataset's shape, or `None` if there's no such snapshot. The output file isn't part of a
+// snapshot, so it's left unset for the caller to fill in.
+pub fn read_snapshot(dir: &Path, dataset_name: &str, id: &str) -> Result<Option<(SnapshotInfo, Dataset)>, String> {
+    validate_snapshot_id(id)?;
+
+    let dir = dir.join(dataset_name);
+    let path = info_path(&dir, id);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let info = read_info(&path)?;
+
+    let df = read_ipc(&data_path(&dir, id))?;
+    let aggregate_state = match &info.aggregate {
+        Some(SnapshotAggregate::History) => Some(AggregateState::History(read_ipc(&aggregate_path(&dir, id))?)),
+        Some(SnapshotAggregate::Running(layout)) => Some(AggregateState::Running(RunningAggregate::from_parts(
+            layout.clone(),
+            read_ipc(&aggregate_path(&dir, id))?,
+        ))),
+        None => None,
+    };
+
+    let dataset = Dataset {
+        // A dataset with no rows yet has no frame at all
+        df: (df.width() > 0).then_some(df),
+        aggregate_state,
+        output_file: None,
+        partitions: None,
+        schema: info.schema.clone(),
+        computed: info.computed.clone(),
+        // Quarantined rows aren't part of a snapshot
+        quarantine: None,
+        ledger: Default::default(),
+        spilled: Default::default(),
+    };
+
+    Ok(Some((info, dataset)))
+}
+
+fn read_info(path: &Path) -> Result<SnapshotInfo, String> {
+    let json = std::fs::read(path).map_err(|e| format!("Can't read snapshot {:?}: {}", path, e))?;
+    serde_json::from_slice(&json).map_err(|e| format!("Snapshot {:?} is not valid: {}", path, e))
+}
+
+fn write_ipc(df: &mut DataFrame, path: &Path) -> Result<(), String> {
+    File::create(path)
+        .map_err(PolarsError::from)
+        .and_then(|mut file| {
+            IpcWriter::new(&mut file).finish(df)?;
+            file.sync_all().map_err(PolarsError::from)
+        })
+        .map_err(|e| format!("Can't write snapshot {:?}: {}", path, e))
+}
+
+fn read_ipc(path: &Path) -> Result<DataFrame, String> {
+    File::open(path)
+        .map_err(PolarsError::from)
+        .and_then(|file| IpcReader::new(file).finish())
+        .map_err(|e| format!("Can't read snapshot {:?}: {}", path, e))
+}
+
+fn info_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{}.json", id))
+}
+
+fn data_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{}.{}", id, DATA_EXT))
+}
+
+fn aggregate_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{}.aggregate.{}", id, DATA_EXT))
+}
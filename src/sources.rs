@@ -0,0 +1,327 @@
+// The background sources `serve` collates from besides requests: a watched directory, tailed files (or standard
+// input), MQTT and NATS subscriptions, and statsd, as well as scheduled exports to an object store and an OTLP
+// collector, the retention policies that evict old rows, the memory budget that spills them to disk, the check for
+// expected reporters, and webhooks
+
+use std::sync::Arc;
+
+use axum::http::HeaderMap;
+use log::{error, info, trace};
+
+use crate::{
+    collator::{collate_local, collate_points},
+    completeness,
+    dataset::AppState,
+    error::AppError,
+    otlp,
+    payload::{read_file, read_payload},
+    provenance::Origin,
+    pubsub::{Message, Source},
+    retention,
+    spill,
+    statsd::Aggregator,
+    tail::{LineBatcher, LineSource},
+    watch::DirWatcher,
+    webhooks,
+};
+
+// Start a task for every source the config sets up. The watched directory and the statsd socket are opened first,
+// so one that can't be is reported before anything starts.
+pub(crate) async fn start(state: &Arc<AppState>) -> Result<(), String> {
+    let config = &state.config;
+    let watcher = config.watch.dir.as_deref().map(DirWatcher::new).transpose()?;
+    let statsd_socket = match config.statsd.bind.as_deref() {
+        Some(address) => Some(
+            tokio::net::UdpSocket::bind(address)
+                .await
+                .map_err(|e| format!("Can't listen for statsd metrics on {}: {}", address, e))?,
+        ),
+        None => None,
+    };
+
+    if let Some(watcher) = watcher {
+        info!("Watching {:?} for files to collate into {:?}", watcher.dir(), config.watch.dataset);
+        tokio::spawn(watch_dir(state.clone(), watcher));
+    }
+    let tail = &config.tail;
+    let sources = tail.file.as_deref().map(LineSource::file).into_iter().chain(tail.stdin.then(LineSource::stdin));
+    for source in sources {
+        info!("Collating CSV lines from {} into {:?}", source.name(), tail.dataset);
+        tokio::spawn(tail_lines(state.clone(), source));
+    }
+    for source in Source::all(config) {
+        info!("Collating messages from {} into {:?}", source.url(), source.dataset());
+        tokio::spawn(subscribe(state.clone(), source));
+    }
+    if let Some(socket) = statsd_socket {
+        info!("Collating statsd metrics from {:?} into {:?}", socket.local_addr().ok(), config.statsd.dataset);
+        tokio::spawn(receive_statsd(state.clone(), socket));
+    }
+    if let Some(interval) = state.s3.as_ref().and_then(|exporter| exporter.interval()) {
+        tokio::spawn(export_on_schedule(state.clone(), interval));
+    }
+    if let Some(endpoint) = &config.telemetry.otlp_endpoint {
+        info!("Sending spans and metrics to the OTLP collector at {}", endpoint);
+        tokio::spawn(otlp::export(state.clone()));
+    }
+    if !config.retention.is_empty() {
+        info!("Evicting old rows from {} datasets under their retention policies", config.retention.len());
+        tokio::spawn(retention::enforce(state.clone()));
+    }
+    // Reporters can be expected at runtime as well, so this one always runs (and waits while nothing is expected)
+    if !config.expected.is_empty() {
+        info!("Checking {} datasets for the reporters they expect", config.expected.len());
+    }
+    tokio::spawn(completeness::follow(state.clone()));
+    if !config.webhooks.is_empty() {
+        info!("Posting to {} webhooks", config.webhooks.len());
+        tokio::spawn(webhooks::notify(state.clone()));
+    }
+    if let Some(budget) = config.storage.memory_budget_bytes {
+        info!("Spilling the oldest rows to disk past {} bytes in memory", budget);
+        tokio::spawn(spill::enforce_budget(state.clone()));
+    }
+
+    Ok(())
+}
+
+// Collate the files dropped into the watched directory as they turn up
+async fn watch_dir(state: Arc<AppState>, mut watcher: DirWatcher) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_millis(state.config.watch.interval_ms));
+    loop {
+        interval.tick().await;
+        for path in watcher.ready() {
+            match collate_file(&state, &path).await {
+                Ok(rows) => {
+                    info!("Collated {} rows from {:?}", rows, path);
+                    watcher.processed(&path);
+                }
+                Err(e) => {
+                    error!("Can't collate {:?}: {}", path, e.message());
+                    watcher.failed(&path, e.message());
+                }
+            }
+        }
+    }
+}
+
+// Collate a data file into the watched directory's dataset, as if it had been sent to `/collate`
+async fn collate_file(state: &AppState, path: &std::path::Path) -> Result<usize, AppError> {
+    let name = state.config.watch.dataset.as_str();
+    let size = std::fs::metadata(path).map_or(0, |metadata| metadata.len() as usize);
+    let file = path.to_path_buf();
+    let df = state.compute.wait(move || read_file(&file)).await?.map_err(AppError::BadRequest)?;
+    // Rows are noted as coming from the file, by name
+    let origin = Origin {
+        received_at: chrono::Utc::now(),
+        source: path.file_name().map_or_else(String::new, |name| name.to_string_lossy().into_owned()),
+    };
+
+    collate_local(state, name, "watch", &origin, df, size).await
+}
+
+// Collate CSV lines from standard input or a tailed file, a batch at a time. A batch is collated once it has
+// `batch_rows` rows, or its first row has waited `batch_ms`, so a slow producer's rows still arrive promptly.
+async fn tail_lines(state: Arc<AppState>, mut source: LineSource) {
+    let config = &state.config.tail;
+    let source_name = source.name();
+    let wait = std::time::Duration::from_millis(config.batch_ms);
+    let mut batcher = LineBatcher::new(config.batch_rows);
+    let mut deadline = None;
+
+    loop {
+        let line = tokio::select! {
+            line = source.next_line() => line,
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(tokio::time::Instant::now)), if deadline.is_some() => {
+                collate_lines(&state, &source_name, batcher.take()).await;
+                deadline = None;
+                continue;
+            }
+        };
+
+        match line {
+            Ok(Some(line)) => {
+                if let Some(csv) = batcher.push(line) {
+                    collate_lines(&state, &source_name, Some(csv)).await;
+                }
+                deadline = if batcher.is_empty() {
+                    None
+                } else {
+                    deadline.or_else(|| Some(tokio::time::Instant::now() + wait))
+                };
+            }
+            Ok(None) => {
+                collate_lines(&state, &source_name, batcher.take()).await;
+                info!("Reached the end of {}, still serving requests", source_name);
+                return;
+            }
+            Err(e) => {
+                collate_lines(&state, &source_name, batcher.take()).await;
+                error!("Can't read from {}, no longer collating its lines: {}", source_name, e);
+                return;
+            }
+        }
+    }
+}
+
+// Collate a batch of lines read by `tail_lines`. There's no client to send an error to, so a batch that can't be
+// collated is logged and dropped.
+async fn collate_lines(state: &AppState, source: &str, csv: Option<String>) {
+    let Some(csv) = csv else {
+        return;
+    };
+    let name = state.config.tail.dataset.as_str();
+    let origin = Origin {
+        received_at: chrono::Utc::now(),
+        source: source.to_string(),
+    };
+
+    let (size, lines) = (csv.len(), csv.lines().count() - 1);
+    let parsed = state
+        .compute
+        .wait(move || read_payload(&HeaderMap::new(), std::io::Cursor::new(csv.as_bytes())))
+        .await;
+    let result = match parsed.and_then(|parsed| parsed.map_err(AppError::BadRequest)) {
+        Ok(df) => collate_local(state, name, "tail", &origin, df, size).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(rows) => trace!("Collated {} rows from {}", rows, source),
+        Err(e) => error!("Dropped {} lines from {}: {}", lines, source, e.message()),
+    }
+}
+
+// Collate the messages an MQTT or NATS subscription receives, one at a time. A lost connection is made again after
+// a wait that doubles with every failed attempt (up to a minute).
+async fn subscribe(state: Arc<AppState>, source: Source) {
+    let mut delay = std::time::Duration::from_secs(1);
+    loop {
+        match source.connect().await {
+            Ok(mut subscription) => {
+                info!("Subscribed to {}", source.url());
+                delay = std::time::Duration::from_secs(1);
+                loop {
+                    let result = match subscription.next().await {
+                        Ok(message) => {
+                            collate_message(&state, &source, &message).await;
+                            subscription.ack(&message).await
+                        }
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = result {
+                        error!("Lost the subscription to {}: {}", source.url(), e);
+                        break;
+                    }
+                }
+            }
+            Err(e) => error!("Can't subscribe to {} (trying again in {:?}): {}", source.url(), delay, e),
+        }
+
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(std::time::Duration::from_secs(60));
+    }
+}
+
+// Collate a message's rows, with its topic (or subject) as the provenance source. There's no client to send an error
+// to, so a message that can't be collated is logged and dropped.
+async fn collate_message(state: &AppState, source: &Source, message: &Message) {
+    let origin = Origin {
+        received_at: chrono::Utc::now(),
+        source: message.subject.clone(),
+    };
+
+    let result = match source.read(message) {
+        Ok(df) => collate_local(state, source.dataset(), source.name(), &origin, df, message.payload.len()).await,
+        Err(message) => Err(AppError::BadRequest(message)),
+    };
+    match result {
+        Ok(rows) => trace!("Collated {} rows from {:?}", rows, message.subject),
+        Err(e) => error!("Dropped a message from {:?}: {}", message.subject, e.message()),
+    }
+}
+
+// Sum up the statsd metrics received over UDP, and collate them every flush interval. Packets that can't be
+// received, and lines that can't be parsed, are logged and dropped: statsd senders don't expect an answer.
+async fn receive_statsd(state: Arc<AppState>, socket: tokio::net::UdpSocket) {
+    let config = &state.config.statsd;
+    let source = format!("statsd://{}", config.bind.as_deref().unwrap_or_default());
+    let period = std::time::Duration::from_millis(config.flush_interval_ms);
+    let mut flushes = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    let mut aggregator = Aggregator::new(config.percentiles.clone());
+    // The biggest a UDP datagram can be
+    let mut buffer = vec![0; 65536];
+    // Bytes received since the last flush
+    let mut size = 0;
+
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buffer) => {
+                let (len, peer) = match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        error!("Can't receive statsd packets: {}", e);
+                        continue;
+                    }
+                };
+                size += len;
+                let packet = String::from_utf8_lossy(&buffer[..len]);
+                let mut errors = packet.lines().filter(|line| !line.trim().is_empty()).filter_map(|line| {
+                    aggregator.add(line.trim()).err()
+                });
+                if let Some(first) = errors.next() {
+                    error!("Dropped {} statsd lines from {}: {}", errors.count() + 1, peer, first);
+                }
+            }
+            _ = flushes.tick() => {
+                let origin = Origin {
+                    received_at: chrono::Utc::now(),
+                    source: source.clone(),
+                };
+                let now = origin.received_at.timestamp_nanos_opt().unwrap_or_default();
+                let result = match aggregator.flush(now) {
+                    Ok(Some(df)) => collate_points(&state, &config.dataset, "statsd", &origin, df, size).await,
+                    Ok(None) => Ok(()),
+                    Err(message) => Err(AppError::Internal(message)),
+                };
+                if let Err(e) = result {
+                    error!("Dropped the statsd metrics received in the last {:?}: {}", period, e.message());
+                }
+                size = 0;
+            }
+        }
+    }
+}
+
+// Export every dataset with rows to the object store every `s3.interval_ms`. The first export is one interval after
+// startup, since there's nothing new to copy before then.
+async fn export_on_schedule(state: Arc<AppState>, interval: std::time::Duration) {
+    let Some(exporter) = &state.s3 else {
+        return;
+    };
+
+    let start = tokio::time::Instant::now() + interval;
+    let mut ticker = tokio::time::interval_at(start, interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        for (name, dataset) in state.all_datasets().await {
+            if !exporter.exports(&name) {
+                continue;
+            }
+            // Only the rows are needed, so the lock is released before anything is read from disk or uploaded
+            let rows = dataset.read().await.rows();
+            let df = match rows.collect() {
+                Ok(df) if df.height() > 0 => df,
+                Ok(_) => continue,
+                Err(e) => {
+                    error!("Can't export {:?}: {}", name, e);
+                    continue;
+                }
+            };
+            match exporter.export(&name, &df).await {
+                Ok(objects) => info!("Exported {} rows of {:?} as {} objects", df.height(), name, objects.len()),
+                Err(e) => error!("{}", e),
+            }
+        }
+    }
+}
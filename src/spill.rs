@@ -0,0 +1,527 @@
+// Spilling to disk (`storage.memory_budget_bytes`): a background task that keeps the datasets' rows within a memory
+// budget, by moving the rows of the largest datasets to Arrow files under `storage.spill_dir`. Spilled rows are still
+// the dataset's: reads scan them lazily along with the rows in memory, and anything that has to change every row (an
+// upsert, a delete, a dedup, ...) reads them back into memory first. Each spill checkpoints the dataset, so a restart
+// picks up from the spill files and only replays the write-ahead log records after them. Aggregated datasets are never
+// spilled.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use log::{error, info, warn};
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    computed::ComputedColumns,
+    dataset::{validate_dataset_name, AppState, Dataset},
+    persist::replace_with,
+    wal::Wal,
+};
+
+// How often the datasets' sizes are checked against the budget
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Spilling stops once the datasets are this share of the budget, so they don't spill a sliver with every payload
+const SPILL_TO: f64 = 0.75;
+
+// Numbers spill files, so a file a read is still scanning is never overwritten
+static SPILLS: AtomicU64 = AtomicU64::new(0);
+
+// What each dataset's spill directory lists its spill files in, so a restart can pick up from them
+const CHECKPOINT_FILE: &str = "checkpoint.json";
+
+// One spilled file. It's deleted once it's been discarded and neither the dataset nor any read needs it; until then it
+// outlives the process, for the next run to pick up.
+#[derive(Debug)]
+struct Part {
+    path: PathBuf,
+    rows: usize,
+    bytes: u64,
+    discarded: AtomicBool,
+}
+
+impl Part {
+    fn new(path: PathBuf, rows: usize, bytes: u64, discarded: bool) -> Self {
+        Part {
+            path,
+            rows,
+            bytes,
+            discarded: AtomicBool::new(discarded),
+        }
+    }
+
+    fn discard(&self) {
+        self.discarded.store(true, Ordering::Relaxed);
+    }
+
+    fn listed(&self) -> Listed {
+        Listed {
+            file: self.path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+            rows: self.rows,
+            bytes: self.bytes,
+        }
+    }
+}
+
+impl Drop for Part {
+    fn drop(&mut self) {
+        if !self.discarded.load(Ordering::Relaxed) {
+            return;
+        }
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            error!("Can't remove the spill file {:?}: {}", self.path, e);
+        }
+    }
+}
+
+// A dataset's spilled state as of a write-ahead log record, which is everything a restart needs to rebuild it from
+// there: the spill files (the dataset's every row), its computed columns, and its quarantine
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    seq: u64,
+    parts: Vec<Listed>,
+    #[serde(default)]
+    computed: Option<ComputedColumns>,
+    #[serde(default)]
+    quarantine: Option<Listed>,
+}
+
+// A spill file, as a manifest lists it
+#[derive(Debug, Serialize, Deserialize)]
+struct Listed {
+    file: String,
+    rows: usize,
+    bytes: u64,
+}
+
+// A dataset's rows that were spilled to disk, oldest first. They come before the rows it has in memory.
+#[derive(Debug, Default)]
+pub struct Spilled {
+    parts: Vec<Arc<Part>>,
+    // The dataset's spill directory, once it's spilled
+    dir: Option<PathBuf>,
+    // The copy of the quarantine the checkpoint has
+    quarantine: Option<Part>,
+}
+
+impl Spilled {
+    pub fn is_empty(&self) -> bool {
+        self.parts.is_empty()
+    }
+
+    pub fn rows(&self) -> usize {
+        self.parts.iter().map(|part| part.rows).sum()
+    }
+
+    // Size of the spill files
+    pub fn bytes(&self) -> u64 {
+        self.parts.iter().map(|part| part.bytes).sum()
+    }
+
+    // Forget the spilled rows, along with the checkpoint, so a restart rebuilds the dataset from the whole write-ahead
+    // log. Their files go once the reads scanning them are done.
+    pub fn clear(&mut self) {
+        if let Some(dir) = &self.dir
+            && !self.parts.is_empty()
+        {
+            let path = dir.join(CHECKPOINT_FILE);
+            if let Err(e) = std::fs::remove_file(&path)
+                && e.kind() != ErrorKind::NotFound
+            {
+                error!("Can't remove the spill checkpoint {:?}: {}", path, e);
+            }
+        }
+        for part in self.parts.drain(..) {
+            part.discard();
+        }
+        if let Some(quarantine) = self.quarantine.take() {
+            quarantine.discard();
+        }
+    }
+
+    // The spilled rows followed by `df`, for reading after the dataset's lock is released
+    pub fn rows_with(&self, df: DataFrame) -> Rows {
+        Rows {
+            df,
+            parts: self.parts.clone(),
+        }
+    }
+
+    // Write `df` (every row the dataset has in memory) to a new spill file in `dir`, and checkpoint the dataset as of
+    // write-ahead log record `seq`. Returns what's left in memory: none of the rows, but their columns.
+    async fn spill(
+        &mut self,
+        dir: PathBuf,
+        df: DataFrame,
+        seq: u64,
+        computed: Option<ComputedColumns>,
+        quarantine: Option<DataFrame>,
+    ) -> Result<DataFrame, String> {
+        let rows = df.height();
+        let rest = df.clear();
+        let path = dir.join(next_file());
+        let quarantine_path = quarantine.as_ref().map(|_| dir.join(next_file()));
+        let mut listed: Vec<Listed> = self.parts.iter().map(|part| part.listed()).collect();
+        let checkpoint = dir.join(CHECKPOINT_FILE);
+
+        let written = tokio::task::spawn_blocking(move || {
+            let mut df = df;
+            let bytes = write_part(&mut df, &path)?;
+            // Held right away, so the files are removed if anything below fails
+            let part = Part::new(path, rows, bytes, true);
+            let quarantine = match (quarantine, quarantine_path) {
+                (Some(mut quarantine), Some(path)) => {
+                    let bytes = write_part(&mut quarantine, &path)?;
+                    Some(Part::new(path, quarantine.height(), bytes, true))
+                }
+                _ => None,
+            };
+
+            listed.push(part.listed());
+            let manifest = Manifest {
+                seq,
+                parts: listed,
+                computed,
+                quarantine: quarantine.as_ref().map(Part::listed),
+            };
+            let json = serde_json::to_vec_pretty(&manifest).map_err(|e| e.to_string())?;
+            replace_with(&checkpoint, |file| Ok(file.write_all(&json)?))
+                .map_err(|e| format!("Can't write the spill checkpoint {:?}: {}", checkpoint, e))?;
+            Ok::<_, String>((part, quarantine))
+        })
+        .await
+        .map_err(|e| format!("Spilling failed: {}", e))?;
+        let (part, quarantine) = written?;
+
+        // The checkpoint has them now, so they stay for the next run
+        part.discarded.store(false, Ordering::Relaxed);
+        self.parts.push(Arc::new(part));
+        if let Some(quarantine) = &quarantine {
+            quarantine.discarded.store(false, Ordering::Relaxed);
+        }
+        if let Some(previous) = std::mem::replace(&mut self.quarantine, quarantine) {
+            previous.discard();
+        }
+        self.dir = Some(dir);
+        Ok(rest)
+    }
+}
+
+fn next_file() -> String {
+    format!("spill-{}.arrow", SPILLS.fetch_add(1, Ordering::Relaxed))
+}
+
+// A dataset's rows, in memory and on disk. The spill files it scans stay until it's dropped, even if the dataset reads
+// them back in (or is reset) meanwhile.
+#[derive(Debug)]
+pub struct Rows {
+    df: DataFrame,
+    parts: Vec<Arc<Part>>,
+}
+
+impl Rows {
+    // How many rows there are, without reading any back
+    pub fn height(&self) -> usize {
+        self.df.height() + self.parts.iter().map(|part| part.rows).sum::<usize>()
+    }
+
+    // A lazy query over every row, for the caller to build on: filters and column picks are pushed down into the
+    // spill files' scans, so only what the query needs is read back. The query has to be collected while `self` is
+    // still around.
+    pub fn lazy(&self) -> Result<LazyFrame, String> {
+        if self.parts.is_empty() {
+            return Ok(self.df.clone().lazy());
+        }
+
+        let mut frames = Vec::with_capacity(self.parts.len() + 1);
+        for part in &self.parts {
+            let scan = LazyFrame::scan_ipc(&part.path, ScanArgsIpc::default())
+                .map_err(|e| format!("Can't read the spill file {:?}: {}", part.path, e))?;
+            frames.push(scan);
+        }
+        frames.push(self.df.clone().lazy());
+        concat_lf_diagonal(frames, UnionArgs::default()).map_err(|e| e.to_string())
+    }
+
+    // Every row
+    pub fn collect(self) -> Result<DataFrame, String> {
+        if self.parts.is_empty() {
+            return Ok(self.df);
+        }
+        self.lazy()?.collect().map_err(|e| e.to_string())
+    }
+}
+
+fn write_part(df: &mut DataFrame, path: &Path) -> Result<u64, String> {
+    File::create(path)
+        .map_err(PolarsError::from)
+        .and_then(|mut file| {
+            IpcWriter::new(&mut file).finish(df)?;
+            file.sync_all()?;
+            Ok(file.metadata()?.len())
+        })
+        .map_err(|e| format!("Can't write the spill file {:?}: {}", path, e))
+}
+
+// A dataset a previous run spilled, and the last write-ahead log record it has; only the ones after that have to be
+// replayed on top
+#[derive(Debug)]
+pub(crate) struct Checkpoint {
+    pub seq: u64,
+    pub dataset: Dataset,
+}
+
+// Pick up the datasets a previous run spilled to `dir`. Spill files no checkpoint lists (from rows that were read back
+// in, or a spill that didn't finish) are removed, and so is a checkpoint that can't be read, since the write-ahead
+// log can rebuild its dataset anyway.
+pub(crate) fn checkpoints(dir: &Path) -> HashMap<String, Checkpoint> {
+    let mut checkpoints = HashMap::new();
+    let Ok(datasets) = std::fs::read_dir(dir) else {
+        return checkpoints;
+    };
+    for entry in datasets.flatten() {
+        let dataset_dir = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !dataset_dir.is_dir() || validate_dataset_name(&name).is_err() {
+            continue;
+        }
+
+        let checkpoint = dataset_dir.join(CHECKPOINT_FILE);
+        let mut kept = HashSet::new();
+        match checkpoint.exists().then(|| read_checkpoint(&dataset_dir)) {
+            Some(Ok((seq, dataset))) => {
+                info!("Picking up {} rows of {:?} from {:?}", dataset.spilled.rows(), name, dataset_dir);
+                kept.extend(dataset.spilled.parts.iter().map(|part| part.path.clone()));
+                kept.extend(dataset.spilled.quarantine.iter().map(|part| part.path.clone()));
+                checkpoints.insert(name, Checkpoint { seq, dataset });
+            }
+            Some(Err(e)) => {
+                warn!("Can't pick up the rows spilled to {:?}, so they're rebuilt from the log: {}", dataset_dir, e);
+                let _ = std::fs::remove_file(&checkpoint);
+            }
+            None => {}
+        }
+
+        let Ok(files) = std::fs::read_dir(&dataset_dir) else {
+            continue;
+        };
+        for file in files.flatten() {
+            let name = file.file_name();
+            let name = name.to_string_lossy();
+            // New files are numbered after the ones kept
+            if let Some(n) = name.strip_prefix("spill-").and_then(|name| name.strip_suffix(".arrow")) {
+                SPILLS.fetch_max(n.parse::<u64>().map_or(0, |n| n + 1), Ordering::Relaxed);
+            }
+            let stale = name.starts_with("spill-") && name.ends_with(".arrow") && !kept.contains(&file.path());
+            if stale || name.ends_with(".tmp") {
+                let _ = std::fs::remove_file(file.path());
+            }
+        }
+    }
+    checkpoints
+}
+
+fn read_checkpoint(dir: &Path) -> Result<(u64, Dataset), String> {
+    let path = dir.join(CHECKPOINT_FILE);
+    let manifest = std::fs::read(&path).map_err(|e| e.to_string())?;
+    let manifest: Manifest = serde_json::from_slice(&manifest).map_err(|e| e.to_string())?;
+    let part = |listed: &Listed| {
+        let path = dir.join(&listed.file);
+        match std::fs::metadata(&path) {
+            Ok(metadata) if metadata.len() == listed.bytes => Ok(Part::new(path, listed.rows, listed.bytes, false)),
+            Ok(_) => Err(format!("{:?} isn't the size the checkpoint says", path)),
+            Err(e) => Err(format!("Can't read {:?}: {}", path, e)),
+        }
+    };
+    let parts = manifest.parts.iter().map(part).collect::<Result<Vec<_>, _>>()?;
+    let quarantine = manifest.quarantine.as_ref().map(part).transpose()?;
+
+    // Every part has the dataset's columns, since anything that changes them reads the spilled rows back in first
+    let last = parts.last().ok_or("it lists no spill files")?;
+    let schema = LazyFrame::scan_ipc(&last.path, ScanArgsIpc::default())
+        .and_then(|mut scan| scan.collect_schema())
+        .map_err(|e| format!("Can't read {:?}: {}", last.path, e))?;
+    let quarantined = quarantine
+        .as_ref()
+        .map(|part| File::open(&part.path).map_err(PolarsError::from).and_then(|file| IpcReader::new(file).finish()))
+        .transpose()
+        .map_err(|e| format!("Can't read the quarantine: {}", e))?;
+
+    let dataset = Dataset {
+        df: Some(DataFrame::empty_with_schema(&schema)),
+        computed: manifest.computed,
+        quarantine: quarantined,
+        spilled: Spilled {
+            parts: parts.into_iter().map(Arc::new).collect(),
+            dir: Some(dir.to_path_buf()),
+            quarantine,
+        },
+        ..Default::default()
+    };
+    Ok((manifest.seq, dataset))
+}
+
+// Spill every row a dataset has in memory (it's locked by the caller), returning how many bytes of memory that freed
+async fn spill_dataset(state: &AppState, dir: &Path, name: &str, dataset: &mut Dataset) -> Result<usize, String> {
+    let Some(df) = dataset.df.clone() else {
+        return Ok(0);
+    };
+    let dir = dir.join(name);
+    std::fs::create_dir_all(&dir).map_err(|e| format!("Can't create the spill directory {:?}: {}", dir, e))?;
+
+    // The caller holds the lock every record of the dataset is logged under, so the dataset has them all
+    let seq = state.wal.as_ref().map_or(0, Wal::last_seq);
+    let bytes = df.estimated_size();
+    let rows = df.height();
+    let rest = dataset.spilled.spill(dir, df, seq, dataset.computed.clone(), dataset.quarantine.clone()).await?;
+    dataset.df = Some(rest);
+    // The ingests kept for rollback hold the spilled rows too
+    dataset.ledger.clear();
+    info!("Spilled {} rows ({} bytes in memory) of {:?} to disk", rows, bytes, name);
+    Ok(bytes)
+}
+
+// Keep the datasets within `storage.memory_budget_bytes`, checking every `CHECK_INTERVAL`
+pub(crate) async fn enforce_budget(state: Arc<AppState>) {
+    let (Some(budget), Some(dir)) = (state.config.storage.memory_budget_bytes, state.config.storage.spill_dir.clone())
+    else {
+        return;
+    };
+
+    let mut ticker = tokio::time::interval(CHECK_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // Whether the datasets were over the budget with nothing left to spill, so that's only logged once
+    let mut warned = false;
+    loop {
+        ticker.tick().await;
+
+        let mut sizes = Vec::new();
+        for (name, dataset) in state.all_datasets().await {
+            let dataset = dataset.read().await;
+            let bytes = dataset.df.as_ref().map_or(0, DataFrame::estimated_size);
+            sizes.push((name, bytes, dataset.aggregate_state.is_none()));
+        }
+        let mut total: usize = sizes.iter().map(|(_, bytes, _)| bytes).sum();
+        if total <= budget {
+            warned = false;
+            continue;
+        }
+
+        // The largest datasets go first, so as few as possible are spilled. Each one is spilled whole, which keeps
+        // its checkpoint simple: every row is in the spill files, and nothing in memory still has to be saved.
+        let target = (budget as f64 * SPILL_TO) as usize;
+        sizes.sort_by_key(|(_, bytes, _)| std::cmp::Reverse(*bytes));
+        for (name, _, spillable) in sizes {
+            if total <= target {
+                break;
+            }
+            if !spillable {
+                continue;
+            }
+            let Some(dataset) = state.existing_dataset(&name).await else {
+                continue;
+            };
+            let mut dataset = dataset.write().await;
+            // Checked again, since it may have changed while the lock was awaited
+            if dataset.df.as_ref().is_none_or(|df| df.height() == 0) || dataset.aggregate_state.is_some() {
+                continue;
+            }
+            match spill_dataset(&state, &dir, &name, &mut dataset).await {
+                Ok(freed) => total = total.saturating_sub(freed),
+                Err(e) => error!("Can't spill rows of {:?}: {}", name, e),
+            }
+        }
+
+        if total > budget && !warned {
+            warn!(
+                "The datasets take {} bytes, over storage.memory_budget_bytes ({}), and none of it can be spilled \
+                 (aggregated datasets never are)",
+                total, budget
+            );
+            warned = true;
+        } else if total <= budget {
+            warned = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process};
+
+    use super::*;
+    use crate::{
+        collator::{Collator, ReadOptions},
+        config::Config,
+    };
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("data_collator-spill-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn open(dir: &Path) -> Collator {
+        let mut config = Config::default();
+        config.storage.wal = Some(dir.join("collator.wal"));
+        config.storage.memory_budget_bytes = Some(1 << 30);
+        config.storage.spill_dir = Some(dir.join("spill"));
+        Collator::open(config).unwrap()
+    }
+
+    async fn values(collator: &Collator) -> Vec<i64> {
+        let df = collator.query("metrics", &ReadOptions::default()).await.unwrap();
+        df.column("value").unwrap().i64().unwrap().into_no_null_iter().collect()
+    }
+
+    #[tokio::test]
+    async fn a_restart_picks_up_from_the_spill_files() {
+        let dir = scratch("restart");
+        let collator = open(&dir);
+        collator.ingest("metrics", df!("value" => [1i64, 2]).unwrap()).await.unwrap();
+        collator.ingest("metrics", df!("value" => [3i64]).unwrap()).await.unwrap();
+        {
+            let state = &collator.state;
+            let dataset = state.existing_dataset("metrics").await.unwrap();
+            let mut dataset = dataset.write().await;
+            spill_dataset(state, &dir.join("spill"), "metrics", &mut dataset).await.unwrap();
+        }
+        collator.ingest("metrics", df!("value" => [4i64]).unwrap()).await.unwrap();
+        drop(collator);
+        // Left behind by a spill that didn't finish
+        fs::write(dir.join("spill/metrics/spill-100.arrow"), b"").unwrap();
+
+        // Only the record after the checkpoint is replayed; the rest stay on disk
+        let collator = open(&dir);
+        assert_eq!(values(&collator).await, [1, 2, 3, 4]);
+        {
+            let dataset = collator.state.existing_dataset("metrics").await.unwrap();
+            let mut dataset = dataset.write().await;
+            assert_eq!(dataset.spilled.rows(), 3);
+            assert_eq!(dataset.df.as_ref().map(DataFrame::height), Some(1));
+            assert!(!dir.join("spill/metrics/spill-100.arrow").exists());
+
+            // Reading the rows back in discards the checkpoint, so the next restart replays the whole log
+            dataset.unspill().unwrap();
+        }
+        assert!(!dir.join("spill/metrics").join(CHECKPOINT_FILE).exists());
+        drop(collator);
+        let collator = open(&dir);
+        assert_eq!(values(&collator).await, [1, 2, 3, 4]);
+        assert!(collator.state.existing_dataset("metrics").await.unwrap().read().await.spilled.is_empty());
+
+        drop(collator);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,67 @@
+// `GET /admin/stats`: how much memory the datasets take, how fast rows are arriving, and how long ingests wait for
+// each other, to size the machines collators run on. Everything is read now, except for the lock waits and ingests,
+// which `Metrics` counts as they happen.
+
+use std::time::Duration;
+
+use crate::{
+    dataset::AppState,
+    metrics::dataset_gauges,
+    types::{DatasetStats, IngestRate, StatsResponse, Status},
+};
+
+pub async fn stats(state: &AppState) -> StatsResponse {
+    let lock_waits = state.metrics.lock_waits();
+    let datasets: Vec<DatasetStats> = dataset_gauges(state)
+        .await
+        .into_iter()
+        .map(|dataset| {
+            let waits = lock_waits.get(&dataset.name).copied().unwrap_or_default();
+            let mean = if waits.count == 0 { Duration::ZERO } else { waits.total / waits.count as u32 };
+            DatasetStats {
+                rows: dataset.rows,
+                columns: dataset.columns,
+                estimated_bytes: dataset.estimated_bytes,
+                spilled_rows: dataset.spilled_rows,
+                spilled_bytes: dataset.spilled_bytes,
+                lock_waits: waits.count,
+                lock_wait_mean_ms: millis(mean),
+                lock_wait_max_ms: millis(waits.max),
+                name: dataset.name,
+            }
+        })
+        .collect();
+
+    let recent = state.metrics.recent_ingests();
+    let per_second = |count: u64| if recent.window_seconds > 0.0 { count as f64 / recent.window_seconds } else { 0.0 };
+    let ingest = IngestRate {
+        window_seconds: recent.window_seconds,
+        payloads: recent.payloads,
+        rows: recent.rows,
+        bytes: recent.bytes,
+        rows_per_second: per_second(recent.rows),
+        bytes_per_second: per_second(recent.bytes),
+    };
+
+    StatsResponse {
+        status: Status::Success,
+        uptime_seconds: state.metrics.uptime().as_secs_f64(),
+        resident_bytes: resident_bytes(),
+        estimated_bytes: datasets.iter().map(|dataset| dataset.estimated_bytes).sum(),
+        datasets,
+        ingest,
+        flush: state.writer.status(),
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1e3
+}
+
+// `VmRSS` from `/proc/self/status`, which is in kB
+fn resident_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb: u64 = line.trim_start_matches("VmRSS:").trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kb * 1024)
+}
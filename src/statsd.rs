@@ -0,0 +1,265 @@
+// statsd metrics, as legacy job wrappers and agents send them over UDP: `name:value|type`, optionally followed by a
+// sample rate (`|@0.1`) and DogStatsD tags (`|#host:a,env:prod`), one metric per line. They're summed up (or averaged,
+// ...) as they arrive, and every flush turns what was received since the last into a row per metric and set of tags.
+
+use std::collections::HashSet;
+
+use indexmap::IndexMap;
+use polars::prelude::*;
+
+use crate::line_protocol::{TIME_COLUMN, TIME_DTYPE};
+
+// Columns every row has, so tags can't be named after them
+const METRIC_COLUMN: &str = "metric";
+const TYPE_COLUMN: &str = "type";
+const VALUE_COLUMN: &str = "value";
+const COUNT_COLUMN: &str = "count";
+const MIN_COLUMN: &str = "min";
+const MAX_COLUMN: &str = "max";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    Counter,
+    Gauge,
+    // Timers (`ms`), histograms (`h`), and distributions (`d`) are all summed up the same way
+    Timer,
+    Set,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Timer => "timer",
+            Kind::Set => "set",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    name: String,
+    kind: Kind,
+    // Sorted, so the same tags in another order are the same metric
+    tags: Vec<(String, String)>,
+}
+
+#[derive(Debug)]
+enum Metric {
+    Counter(f64),
+    // Kept between flushes, so `+`/`-` changes have something to apply to. Only reported once it's been updated.
+    Gauge { value: f64, updated: bool },
+    // The values received, and how many there were once sample rates are taken into account
+    Timer { values: Vec<f64>, count: f64 },
+    Set(HashSet<String>),
+}
+
+// A metric, as one line of a packet puts it
+struct Sample<'a> {
+    key: Key,
+    value: &'a str,
+    rate: f64,
+}
+
+// The metrics received since the last flush
+#[derive(Debug)]
+pub struct Aggregator {
+    percentiles: Vec<f64>,
+    metrics: IndexMap<Key, Metric>,
+}
+
+impl Aggregator {
+    pub fn new(percentiles: Vec<f64>) -> Self {
+        Aggregator {
+            percentiles,
+            metrics: IndexMap::new(),
+        }
+    }
+
+    // Add one line of a packet
+    pub fn add(&mut self, line: &str) -> Result<(), String> {
+        let Sample { key, value, rate } = parse_line(line)?;
+        let number = || value.parse::<f64>().ok().filter(|n| n.is_finite());
+        let invalid = || format!("{:?} isn't a valid {} value", value, key.kind.name());
+
+        match key.kind {
+            Kind::Counter => {
+                let n = number().ok_or_else(invalid)?;
+                match self.metrics.entry(key).or_insert(Metric::Counter(0.0)) {
+                    Metric::Counter(total) => *total += n / rate,
+                    _ => unreachable!("keys include the kind"),
+                }
+            }
+            Kind::Gauge => {
+                let n = number().ok_or_else(invalid)?;
+                // A leading sign changes the gauge rather than setting it
+                let relative = value.starts_with(['+', '-']);
+                match self.metrics.entry(key).or_insert(Metric::Gauge { value: 0.0, updated: false }) {
+                    Metric::Gauge { value, updated } => {
+                        *value = if relative { *value + n } else { n };
+                        *updated = true;
+                    }
+                    _ => unreachable!("keys include the kind"),
+                }
+            }
+            Kind::Timer => {
+                let n = number().ok_or_else(invalid)?;
+                let metric = self.metrics.entry(key).or_insert(Metric::Timer { values: Vec::new(), count: 0.0 });
+                match metric {
+                    Metric::Timer { values, count } => {
+                        values.push(n);
+                        *count += 1.0 / rate;
+                    }
+                    _ => unreachable!("keys include the kind"),
+                }
+            }
+            Kind::Set => match self.metrics.entry(key).or_insert_with(|| Metric::Set(HashSet::new())) {
+                Metric::Set(values) => {
+                    values.insert(value.to_string());
+                }
+                _ => unreachable!("keys include the kind"),
+            },
+        }
+        Ok(())
+    }
+
+    // A row for every metric received since the last flush, all with the time given (in nanoseconds since the
+    // epoch). `None` when nothing was.
+    pub fn flush(&mut self, now: i64) -> Result<Option<DataFrame>, String> {
+        let percentiles = self.percentiles.clone();
+        let mut metrics = Vec::new();
+        let mut kinds = Vec::new();
+        let mut tags: IndexMap<String, Vec<Option<String>>> = IndexMap::new();
+        let mut values = Vec::new();
+        let mut counts = Vec::new();
+        let mut mins = Vec::new();
+        let mut maxes = Vec::new();
+        let mut ranks: Vec<Vec<Option<f64>>> = vec![Vec::new(); percentiles.len()];
+
+        for (key, metric) in self.metrics.iter_mut() {
+            let (value, count, min, max) = match metric {
+                Metric::Counter(total) => (*total, None, None, None),
+                Metric::Gauge { updated: false, .. } => continue,
+                Metric::Gauge { value, updated } => {
+                    *updated = false;
+                    (*value, None, None, None)
+                }
+                Metric::Timer { values, count } => {
+                    values.sort_by(f64::total_cmp);
+                    for (percentile, column) in percentiles.iter().zip(ranks.iter_mut()) {
+                        // The nearest rank: the smallest value at least `percentile`% of the values are up to
+                        let rank = (percentile / 100.0 * values.len() as f64).ceil() as usize;
+                        column.resize(metrics.len(), None);
+                        column.push(Some(values[rank.clamp(1, values.len()) - 1]));
+                    }
+                    let mean = values.iter().sum::<f64>() / values.len() as f64;
+                    (mean, Some(count.round() as u64), values.first().copied(), values.last().copied())
+                }
+                Metric::Set(values) => (values.len() as f64, None, None, None),
+            };
+
+            let row = metrics.len();
+            for (name, tag) in &key.tags {
+                let column = tags.entry(name.clone()).or_default();
+                column.resize(row, None);
+                column.push(Some(tag.clone()));
+            }
+            metrics.push(key.name.clone());
+            kinds.push(key.kind.name());
+            values.push(value);
+            counts.push(count);
+            mins.push(min);
+            maxes.push(max);
+        }
+        // Gauges are all that's kept, to be changed by later packets
+        self.metrics.retain(|_, metric| matches!(metric, Metric::Gauge { .. }));
+        if metrics.is_empty() {
+            return Ok(None);
+        }
+
+        let rows = metrics.len();
+        let mut df = vec![
+            Column::new(PlSmallStr::from(TIME_COLUMN), vec![now; rows]).cast(&TIME_DTYPE).map_err(|e| e.to_string())?,
+            Column::new(PlSmallStr::from(METRIC_COLUMN), metrics),
+            Column::new(PlSmallStr::from(TYPE_COLUMN), kinds),
+        ];
+        for (name, mut column) in tags {
+            column.resize(rows, None);
+            df.push(Column::new(PlSmallStr::from(name), column));
+        }
+        df.push(Column::new(PlSmallStr::from(VALUE_COLUMN), values));
+        df.push(Column::new(PlSmallStr::from(COUNT_COLUMN), counts));
+        df.push(Column::new(PlSmallStr::from(MIN_COLUMN), mins));
+        df.push(Column::new(PlSmallStr::from(MAX_COLUMN), maxes));
+        for (percentile, mut column) in percentiles.iter().zip(ranks) {
+            column.resize(rows, None);
+            df.push(Column::new(PlSmallStr::from(percentile_column(*percentile)), column));
+        }
+
+        DataFrame::new(df).map(Some).map_err(|e| e.to_string())
+    }
+}
+
+// `p90` for the 90th percentile, `p99_9` for the 99.9th
+fn percentile_column(percentile: f64) -> String {
+    format!("p{}", percentile).replace('.', "_")
+}
+
+// `name:value|type[|@rate][|#tag:value,...]`
+fn parse_line(line: &str) -> Result<Sample<'_>, String> {
+    let (name, rest) = line.split_once(':').ok_or_else(|| String::from("a metric has no value (`name:value|type`)"))?;
+    if name.is_empty() {
+        return Err(String::from("a metric has no name"));
+    }
+    let mut parts = rest.split('|');
+    let value = parts.next().unwrap_or_default();
+    let kind = match parts.next() {
+        Some("c") => Kind::Counter,
+        Some("g") => Kind::Gauge,
+        Some("ms" | "h" | "d") => Kind::Timer,
+        Some("s") => Kind::Set,
+        Some(other) => return Err(format!("{:?} isn't a metric type (c, g, ms, h, d, or s)", other)),
+        None => return Err(format!("the metric {:?} has no type", name)),
+    };
+
+    let mut rate = 1.0;
+    let mut tags = Vec::new();
+    for part in parts {
+        if let Some(given) = part.strip_prefix('@') {
+            rate = given.parse().ok().filter(|rate| *rate > 0.0 && *rate <= 1.0).ok_or_else(|| {
+                format!("{:?} isn't a sample rate (above 0, up to 1)", given)
+            })?;
+        } else if let Some(given) = part.strip_prefix('#') {
+            for tag in given.split(',').filter(|tag| !tag.is_empty()) {
+                // A tag with no value is kept, with an empty one
+                let (tag, value) = tag.split_once(':').unwrap_or((tag, ""));
+                let fixed = [TIME_COLUMN, METRIC_COLUMN, TYPE_COLUMN, VALUE_COLUMN, COUNT_COLUMN, MIN_COLUMN, MAX_COLUMN];
+                if fixed.contains(&tag) || is_percentile_column(tag) {
+                    return Err(format!("{:?} can't be used as a tag name", tag));
+                }
+                if tags.iter().any(|(name, _)| name == tag) {
+                    return Err(format!("the tag {:?} is given more than once", tag));
+                }
+                tags.push((tag.to_string(), value.to_string()));
+            }
+        }
+        // Anything else (e.g. a DogStatsD container ID, `|c:...`) is left out
+    }
+    tags.sort();
+
+    Ok(Sample {
+        key: Key {
+            name: name.to_string(),
+            kind,
+            tags,
+        },
+        value,
+        rate,
+    })
+}
+
+// Whether a tag would clash with a percentile column, whichever percentiles are configured
+fn is_percentile_column(tag: &str) -> bool {
+    tag.strip_prefix('p').is_some_and(|rest| !rest.is_empty() && rest.chars().all(|c| c.is_ascii_digit() || c == '_'))
+}
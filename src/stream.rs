@@ -0,0 +1,40 @@
+use std::io;
+
+use axum::body::{Body, Bytes};
+use futures::stream;
+use polars::prelude::*;
+
+// Rows serialized per chunk of a streamed CSV response
+const CSV_CHUNK_ROWS: usize = 16_384;
+
+// Stream a DataFrame as CSV, one chunk of rows at a time, so only a single chunk is ever held as text. The frame
+// itself is a cheap clone of the dataset's columns, so the dataset's lock doesn't need to be held while streaming.
+pub fn csv_body(df: DataFrame) -> Body {
+    // (frame, offset of the next chunk, whether the header still has to be written)
+    let chunks = stream::unfold((df, 0, true), |(df, offset, header)| async move {
+        // The header goes out even if there are no rows
+        if offset >= df.height() && !header {
+            return None;
+        }
+
+        let chunk = df.slice(offset as i64, CSV_CHUNK_ROWS);
+        let rows = chunk.height();
+        let bytes = tokio::task::spawn_blocking(move || write_chunk(chunk, header))
+            .await
+            .unwrap_or_else(|e| Err(io::Error::other(e)));
+
+        Some((bytes, (df, offset + rows, false)))
+    });
+
+    Body::from_stream(chunks)
+}
+
+fn write_chunk(mut chunk: DataFrame, include_header: bool) -> io::Result<Bytes> {
+    let mut bytes = Vec::new();
+    CsvWriter::new(&mut bytes)
+        .include_header(include_header)
+        .finish(&mut chunk)
+        .map_err(io::Error::other)?;
+
+    Ok(Bytes::from(bytes))
+}
@@ -0,0 +1,239 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use log::info;
+use tokio::io::{AsyncBufReadExt, BufReader, Lines, Stdin};
+
+// How often a tailed file is checked for new lines
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// A line of CSV read from a `LineSource`
+#[derive(Debug)]
+pub enum Line {
+    // Column names for the rows that follow
+    Header(String),
+    Row(String),
+}
+
+// Where `serve --tail` and `serve --stdin` read CSV records from, one line at a time. The first line is the header.
+pub enum LineSource {
+    Stdin {
+        lines: Lines<BufReader<Stdin>>,
+        header_read: bool,
+    },
+    File(FileTail),
+}
+
+impl LineSource {
+    pub fn stdin() -> Self {
+        LineSource::Stdin {
+            lines: BufReader::new(tokio::io::stdin()).lines(),
+            header_read: false,
+        }
+    }
+
+    pub fn file(path: &Path) -> Self {
+        LineSource::File(FileTail {
+            path: path.to_path_buf(),
+            skip_to: std::fs::metadata(path).ok().map(|metadata| metadata.len()),
+            offset: None,
+            partial: Vec::new(),
+            partial_skipped: false,
+            lines: VecDeque::new(),
+        })
+    }
+
+    // Name rows read from this source are noted with in the provenance columns
+    pub fn name(&self) -> String {
+        match self {
+            LineSource::Stdin { .. } => String::from("stdin"),
+            LineSource::File(tail) => {
+                tail.path.file_name().map_or_else(String::new, |name| name.to_string_lossy().into_owned())
+            }
+        }
+    }
+
+    // The next line, without its line ending, or `None` once stdin is closed (a tailed file never ends). Safe to
+    // cancel: no line is lost if the future is dropped while it waits.
+    pub async fn next_line(&mut self) -> io::Result<Option<Line>> {
+        match self {
+            LineSource::Stdin { lines, header_read } => {
+                let Some(line) = lines.next_line().await?.map(trim_line_ending) else {
+                    return Ok(None);
+                };
+                if std::mem::replace(header_read, true) {
+                    Ok(Some(Line::Row(line)))
+                } else {
+                    Ok(Some(Line::Header(line)))
+                }
+            }
+            LineSource::File(tail) => tail.next_line().await.map(Some),
+        }
+    }
+}
+
+// Follows a file as lines are appended to it, like `tail -F`: it may not exist yet, and if it's truncated or replaced
+// by a smaller file, it's read again from the top. Lines that were already in it at startup are skipped, apart from
+// the header.
+pub struct FileTail {
+    path: PathBuf,
+    // Length of the file at startup
+    skip_to: Option<u64>,
+    // Where in the file reading left off, once it's been opened
+    offset: Option<u64>,
+    // The start of a line that hasn't been finished yet
+    partial: Vec<u8>,
+    // Whether `partial` is the end of a line that was cut off by skipping to `skip_to`, so it's not a whole row
+    partial_skipped: bool,
+    lines: VecDeque<Line>,
+}
+
+impl FileTail {
+    async fn next_line(&mut self) -> io::Result<Line> {
+        loop {
+            if let Some(line) = self.lines.pop_front() {
+                return Ok(line);
+            }
+            self.read_more()?;
+            if self.lines.is_empty() {
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+
+    fn read_more(&mut self) -> io::Result<()> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let len = file.metadata()?.len();
+
+        let offset = match self.offset {
+            Some(offset) if offset <= len => offset,
+            previous => {
+                if previous.is_some() {
+                    info!("{:?} was truncated, reading it again from the top", self.path);
+                }
+                self.partial.clear();
+                self.partial_skipped = false;
+                let Some((header, end)) = self.header(&mut file)? else {
+                    return Ok(());
+                };
+                self.lines.push_back(Line::Header(header));
+                match self.skip_to.take() {
+                    Some(skip_to) if previous.is_none() && skip_to <= len && skip_to > end => {
+                        let mut last = [0];
+                        file.seek(SeekFrom::Start(skip_to - 1))?;
+                        file.read_exact(&mut last)?;
+                        self.partial_skipped = last[0] != b'\n';
+                        skip_to
+                    }
+                    _ => end,
+                }
+            }
+        };
+
+        file.seek(SeekFrom::Start(offset))?;
+        let mut bytes = Vec::new();
+        file.take(len - offset).read_to_end(&mut bytes)?;
+        self.offset = Some(offset + bytes.len() as u64);
+
+        self.partial.extend_from_slice(&bytes);
+        if let Some(end) = self.partial.iter().rposition(|byte| *byte == b'\n') {
+            let complete: Vec<u8> = self.partial.drain(..=end).collect();
+            let skip = usize::from(std::mem::take(&mut self.partial_skipped));
+            for line in complete.split(|byte| *byte == b'\n').skip(skip).filter(|line| !line.is_empty()) {
+                self.lines.push_back(Line::Row(trim_line_ending(String::from_utf8_lossy(line).into_owned())));
+            }
+        }
+
+        Ok(())
+    }
+
+    // The file's first line, and where the line after it starts (`None` until a whole line has been written)
+    fn header(&self, file: &mut File) -> io::Result<Option<(String, u64)>> {
+        let mut start = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        let mut buffer = [0; 4096];
+        loop {
+            let n = file.read(&mut buffer)?;
+            if n == 0 {
+                return Ok(None);
+            }
+            if let Some(end) = buffer[..n].iter().position(|byte| *byte == b'\n') {
+                start.extend_from_slice(&buffer[..end]);
+                let header = trim_line_ending(String::from_utf8_lossy(&start).into_owned());
+                return Ok(Some((header, (start.len() + 1) as u64)));
+            }
+            start.extend_from_slice(&buffer[..n]);
+        }
+    }
+}
+
+fn trim_line_ending(mut line: String) -> String {
+    if line.ends_with('\r') {
+        line.pop();
+    }
+    line
+}
+
+// Collects lines into CSV payloads of up to `max_rows` rows
+pub struct LineBatcher {
+    header: Option<String>,
+    rows: Vec<String>,
+    max_rows: usize,
+}
+
+impl LineBatcher {
+    pub fn new(max_rows: usize) -> Self {
+        LineBatcher {
+            header: None,
+            rows: Vec::new(),
+            max_rows,
+        }
+    }
+
+    // Add a line, returning a payload if that filled the batch. A new header sends the rows waiting under the old one
+    // first. Rows before any header are dropped, since there's no telling what their columns are.
+    pub fn push(&mut self, line: Line) -> Option<String> {
+        match line {
+            Line::Header(header) => {
+                let pending = self.take();
+                self.header = Some(header);
+                pending
+            }
+            Line::Row(row) if row.trim().is_empty() || self.header.is_none() => None,
+            Line::Row(row) => {
+                self.rows.push(row);
+                (self.rows.len() >= self.max_rows).then(|| self.take()).flatten()
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    // The rows waiting so far as a CSV payload, header first
+    pub fn take(&mut self) -> Option<String> {
+        if self.rows.is_empty() {
+            return None;
+        }
+        let header = self.header.as_deref().unwrap_or_default();
+        let len = header.len() + self.rows.iter().map(|row| row.len() + 1).sum::<usize>() + 1;
+        let mut csv = String::with_capacity(len);
+        csv.push_str(header);
+        csv.push('\n');
+        for row in self.rows.drain(..) {
+            csv.push_str(&row);
+            csv.push('\n');
+        }
+        Some(csv)
+    }
+}
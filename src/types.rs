@@ -0,0 +1,775 @@
+// The bodies the HTTP API takes and responds with, for tooling built against it. Under `/v1` they only change by
+// gaining fields: none is renamed, retyped, or removed, and a change that would need to is left to a new version. JSON
+// rows are records (an object per row, by column name), since their columns are each dataset's own.
+
+use std::{collections::HashMap, path::PathBuf};
+
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub use crate::accounting::Quota;
+pub use crate::batch::{BatchEntry, BatchResult};
+pub use crate::computed::ComputedColumns;
+pub use crate::nulls::FillSpec;
+pub use crate::reshape::{MeltSpec, PivotSpec};
+pub use crate::resample::ResampleSpec;
+pub use crate::rolling::RollingSpec;
+pub use crate::s3::ExportedObject;
+pub use crate::schema::DatasetSchema;
+pub use crate::snapshot::SnapshotInfo;
+pub use crate::writer::FlushStatus;
+
+// The `status` of every response, and of each payload of a batch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Success,
+    Error,
+    // `GET /`, and the probes (`/healthz`, `/livez`, `/readyz`) when every check passed
+    Operational,
+    // A probe with a check that didn't pass
+    Failing,
+    // A batch payload that wasn't checked, because an earlier one failed
+    Skipped,
+}
+
+// Any request that failed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub status: Status,
+    // `bad_request`, `unauthorized`, `not_found`, ... (see `AppError::kind`)
+    pub error: String,
+    pub message: String,
+}
+
+// `GET /`, `GET /healthz`, and requests with nothing else to say
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatusResponse {
+    pub status: Status,
+}
+
+// What else happened to a payload's rows. Each count is `None` (and left out) where it doesn't apply.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IngestCounts {
+    // Rows that failed the dataset's validation rules
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quarantined: Option<usize>,
+    // Existing rows an upsert replaced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replaced: Option<usize>,
+    // Payload rows a wide collate joined onto existing rows
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub joined: Option<usize>,
+    // Rows dropped as duplicates (with `collate.skip_duplicates`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<usize>,
+    // Rows that didn't match the request's filter
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filtered: Option<usize>,
+}
+
+impl IngestCounts {
+    // Each count that applies, with its JSON field and response header
+    pub fn fields(&self) -> impl Iterator<Item = (&'static str, &'static str, usize)> {
+        [
+            ("quarantined", "x-quarantined-rows", self.quarantined),
+            ("replaced", "x-replaced-rows", self.replaced),
+            ("joined", "x-joined-rows", self.joined),
+            ("skipped", "x-skipped-rows", self.skipped),
+            ("filtered", "x-filtered-rows", self.filtered),
+        ]
+        .into_iter()
+        .filter_map(|(field, header, count)| count.map(|count| (field, header, count)))
+    }
+}
+
+// `/collate`, `/upsert`, `/collate_wide`, and `/aggregate`, when the response is JSON
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CollateResponse {
+    pub status: Status,
+    // Where the rows are being persisted (`queued: "<file>"`), or `no`
+    pub wrote_to_file: String,
+    // The dataset's rows, as CSV
+    pub csv_string: String,
+    #[serde(flatten)]
+    pub counts: IngestCounts,
+}
+
+// The JSON form of an `/aggregate` body
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AggregateRequest {
+    // Group-by column (defaults to the `keys` query parameter, then the first column of the CSV)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    // Several group-by columns, instead of `key`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keys: Option<Vec<String>>,
+    // Operation for columns not listed in `ops` (defaults to the query parameter/header, then the configured default)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub op: Option<String>,
+    // Per-column operations, e.g. `{"latency_ms": "mean", "bytes": "sum"}`
+    #[serde(default)]
+    pub ops: HashMap<String, String>,
+    // The CSV payload itself
+    pub csv: String,
+}
+
+// `/collate/batch`. A batch that failed gets the failed payload's status code, and `message` says why.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchResponse {
+    pub status: Status,
+    // Set when the batch succeeded: where it's being persisted, and how many rows it added
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wrote_to_file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rows: Option<usize>,
+    // Set when it failed, as in `ErrorResponse`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    pub batches: Vec<BatchResult>,
+    #[serde(flatten)]
+    pub counts: IngestCounts,
+}
+
+// `GET /data`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DataResponse {
+    pub status: Status,
+    // Rows matching the filter, before `offset` and `limit`
+    pub total_rows: usize,
+    pub offset: usize,
+    pub rows: Vec<Value>,
+}
+
+// `GET /describe`: each numeric column's statistics, by name (`count`, `mean`, `25%`, ...)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DescribeResponse {
+    pub status: Status,
+    pub rows: usize,
+    pub columns: IndexMap<String, IndexMap<String, Option<f64>>>,
+}
+
+// `GET /value_counts`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ValueCountsResponse {
+    pub status: Status,
+    pub column: String,
+    pub values: Vec<Value>,
+}
+
+// `GET /top`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopResponse {
+    pub status: Status,
+    pub by: String,
+    pub rows: Vec<Value>,
+}
+
+// A column (or combination of columns) of `GET /distinct`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DistinctCount {
+    pub columns: Vec<String>,
+    pub count: usize,
+    // Only with `values=true`, and at most `limit` of them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<Value>>,
+}
+
+// `GET /distinct`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DistinctResponse {
+    pub status: Status,
+    pub rows: usize,
+    pub distinct: Vec<DistinctCount>,
+}
+
+// Rows computed from a dataset without changing it: `/pivot`, `/melt`, `/resample`, `/rolling`, and `GET /outliers`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RowsResponse {
+    pub status: Status,
+    pub columns: Vec<String>,
+    pub rows: Vec<Value>,
+}
+
+// `POST /export/s3`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct S3ExportResponse {
+    pub status: Status,
+    pub dataset: String,
+    pub rows: usize,
+    pub objects: Vec<ExportedObject>,
+}
+
+// A column of `GET /schema`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ColumnSummary {
+    pub name: String,
+    pub dtype: String,
+    pub null_count: usize,
+}
+
+// `GET /schema`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SchemaResponse {
+    pub status: Status,
+    pub dataset: String,
+    pub rows: usize,
+    pub columns: Vec<ColumnSummary>,
+    // The schema declared with `PUT /schema` (or the config file), if any
+    pub declared: Option<DatasetSchema>,
+}
+
+// `PUT /schema`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SchemaSetResponse {
+    pub status: Status,
+    pub schema: DatasetSchema,
+}
+
+// `/columns/computed`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ComputedResponse {
+    pub status: Status,
+    pub computed: ComputedColumns,
+}
+
+// `/expected`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompletenessResponse {
+    pub status: Status,
+    pub dataset: String,
+    // The column that names each row's reporter
+    pub column: String,
+    // How many reporters are expected, and how many of them have sent rows
+    pub expected: usize,
+    pub reported: usize,
+    // Reporters that sent rows without being expected
+    pub unexpected: usize,
+    pub complete: bool,
+    // When every expected reporter had sent rows (RFC 3339), while they all have
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completed_at: Option<String>,
+    // The expected reporters that haven't sent rows yet
+    pub missing: Vec<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<String>,
+}
+
+// What `[webhooks.<name>]` post
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookNotification {
+    // The webhook's name in the config
+    pub webhook: String,
+    // `ingest`, `rows` (a row threshold was reached), or `value` (rows past a limit arrived)
+    pub trigger: String,
+    pub dataset: String,
+    // What made the change, e.g. `collate` or `aggregate`
+    pub operation: String,
+    // A one-line summary, which Slack-style incoming webhooks show as the message
+    pub text: String,
+    // When it happened (RFC 3339)
+    pub at: String,
+    // The rows the change brought (for an aggregate, the groups it changed), and how many the dataset has now
+    pub rows: usize,
+    pub total_rows: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rows_above: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub above: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub below: Option<f64>,
+    // How many of the rows were past the limit, and the first few of them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matched: Option<usize>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sample: Vec<Value>,
+}
+
+// `POST /await`: the dataset's rows (or groups, for an aggregate) once the barrier was reached
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AwaitResponse {
+    pub status: Status,
+    pub dataset: String,
+    // The batches collated while the request waited, or with `key`, the distinct values of that column
+    pub reached: usize,
+    pub waited_ms: u64,
+    pub total_rows: usize,
+    pub rows: Vec<Value>,
+}
+
+// `GET /replication/status` (and `POST /replication/promote`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicationStatusResponse {
+    pub status: Status,
+    // `primary` or `replica`
+    pub role: String,
+    // The name of the stream of changes this collator's replicas follow
+    pub stream: String,
+    // The number of its latest change, when it has replicas
+    pub latest_seq: Option<u64>,
+    pub replicas: Vec<ReplicaStatus>,
+    // What it has of the stream it follows as a replica (or followed, before it was promoted)
+    pub primary: Option<FollowedStream>,
+}
+
+// One of a primary's replicas
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicaStatus {
+    pub url: String,
+    // `connecting`, `streaming`, `catching_up`, `retrying`, `rejected`, or `out_of_sync`
+    pub state: String,
+    // The last change it's applied, once it's said
+    pub acked_seq: Option<u64>,
+    // How many changes it doesn't have yet, and how long the oldest of them has waited
+    pub lag_changes: Option<u64>,
+    pub lag_seconds: Option<f64>,
+    pub last_ack_at: Option<String>,
+    pub last_error: Option<String>,
+}
+
+// How far a replica has got in its primary's stream
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FollowedStream {
+    pub stream: String,
+    pub applied_seq: u64,
+    // The primary's latest change, as of the last batch it sent
+    pub latest_seq: u64,
+    // How many changes it doesn't have yet, and (while there are any) how long ago the last one it applied was made
+    pub lag_changes: u64,
+    pub lag_seconds: Option<f64>,
+    pub last_applied_at: Option<String>,
+}
+
+// `POST /replication/apply`: the last change of the stream the replica has applied
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicationAck {
+    pub status: Status,
+    pub stream: String,
+    pub applied_seq: u64,
+}
+
+// `PUT /lookup/{name}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LookupLoadedResponse {
+    pub status: Status,
+    pub name: String,
+    pub keys: Vec<String>,
+    pub rows: usize,
+    // Whether a table of that name was replaced
+    pub replaced: bool,
+}
+
+// `GET /lookup/{name}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LookupResponse {
+    pub status: Status,
+    pub name: String,
+    pub keys: Vec<String>,
+    pub rows: Vec<Value>,
+}
+
+// `DELETE /lookup/{name}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LookupDeletedResponse {
+    pub status: Status,
+    pub name: String,
+}
+
+// `GET /lookups`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LookupsResponse {
+    pub status: Status,
+    pub lookups: Vec<String>,
+}
+
+// `GET /datasets`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DatasetsResponse {
+    pub status: Status,
+    pub datasets: Vec<String>,
+}
+
+// `GET /datasets/{name}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DatasetResponse {
+    pub status: Status,
+    pub name: String,
+    pub rows: usize,
+    pub output_file: Option<PathBuf>,
+    pub csv_string: String,
+}
+
+// `DELETE /datasets/{name}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DatasetDeletedResponse {
+    pub status: Status,
+    pub deleted: String,
+}
+
+// `POST /reset`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResetResponse {
+    pub status: Status,
+    pub dataset: String,
+    // Where the output file (and the partitions) were moved, with `?rotate=true`
+    pub rotated_to: Option<PathBuf>,
+    pub partitions_rotated_to: Option<PathBuf>,
+}
+
+// `DELETE /data`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeleteResponse {
+    pub status: Status,
+    pub deleted: usize,
+    // Rows left
+    pub rows: usize,
+}
+
+// `/dedup` and `/drop_nulls`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemovedResponse {
+    pub status: Status,
+    pub removed: usize,
+    // Rows left
+    pub rows: usize,
+}
+
+// `/fill_nulls`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FilledResponse {
+    pub status: Status,
+    pub filled: usize,
+    pub rows: usize,
+}
+
+// A column of `GET /nulls`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NullCount {
+    pub name: String,
+    pub null_count: usize,
+    pub null_fraction: f64,
+}
+
+// `GET /nulls`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NullsResponse {
+    pub status: Status,
+    pub rows: usize,
+    pub rows_with_nulls: usize,
+    pub columns: Vec<NullCount>,
+}
+
+// `POST /outliers`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutliersResponse {
+    pub status: Status,
+    pub outliers: usize,
+    pub rows: usize,
+}
+
+// `GET /quarantine`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuarantineResponse {
+    pub status: Status,
+    pub rows: usize,
+    pub quarantine: Vec<Value>,
+}
+
+// `DELETE /quarantine`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuarantineClearedResponse {
+    pub status: Status,
+    pub deleted: usize,
+}
+
+// `POST /snapshots`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotResponse {
+    pub status: Status,
+    pub snapshot: SnapshotInfo,
+}
+
+// `GET /snapshots`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotsResponse {
+    pub status: Status,
+    pub snapshots: Vec<SnapshotInfo>,
+}
+
+// `POST /snapshots/{id}/restore`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RestoreResponse {
+    pub status: Status,
+    pub restored: SnapshotInfo,
+}
+
+// `GET /versions`: a dataset's versions, oldest first. Version 0 is what it started out with, and every change to its
+// rows logged since (an ingest, a delete, a reset, a rollback, ...) made the next one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VersionsResponse {
+    pub status: Status,
+    pub dataset: String,
+    pub versions: Vec<VersionInfo>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VersionInfo {
+    pub version: u64,
+    // When it was made (missing for version 0, and for records logged before they had timestamps)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub at: Option<String>,
+    // What made it (`initial` for version 0)
+    pub operation: String,
+    // Rows the dataset had then
+    pub rows: usize,
+}
+
+// `GET /diff`: how a dataset's rows changed from one version to another. A row that's in both as many times is
+// neither added nor removed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiffResponse {
+    pub status: Status,
+    pub dataset: String,
+    pub from: u64,
+    pub to: u64,
+    pub added: Vec<Value>,
+    pub removed: Vec<Value>,
+    // When `to` is an aggregate, its key (and window) columns: a group whose results differ is in `changed`, not in
+    // `added` and `removed`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keys: Option<Vec<String>>,
+    #[serde(default)]
+    pub changed: Vec<ChangedRow>,
+}
+
+// A group of an aggregate, before and after
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangedRow {
+    pub key: Value,
+    pub from: Value,
+    pub to: Value,
+}
+
+// `/flush`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlushResponse {
+    pub status: Status,
+    pub flush: FlushStatus,
+}
+
+// A line of the audit file, and an entry of `GET /audit`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    // When the request was handled (RFC 3339)
+    pub timestamp: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    // `collate`, `aggregate`, `delete`, `reset`, ...
+    pub operation: String,
+    pub dataset: String,
+    pub method: String,
+    pub route: String,
+    pub status: u16,
+    // The client's IP address
+    pub client: String,
+    // Which API key it was sent with (see `auth::KeyId`), when keys are configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rows_ingested: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rows_removed: Option<usize>,
+    // The size and SHA-256 of the request body as it was received (before decompressing it), when it had one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_bytes: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_sha256: Option<String>,
+}
+
+// `GET /audit`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditResponse {
+    pub status: Status,
+    // Entries matching the query, before `offset` and `limit`
+    pub total: usize,
+    pub entries: Vec<AuditEntry>,
+}
+
+// `GET /admin/stats`: what a collator holds and how busy it is, for sizing the machine it runs on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatsResponse {
+    pub status: Status,
+    pub uptime_seconds: f64,
+    // The process's resident memory, where the platform reports it (Linux)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resident_bytes: Option<u64>,
+    // Every dataset's estimated DataFrame size, together
+    pub estimated_bytes: usize,
+    pub datasets: Vec<DatasetStats>,
+    pub ingest: IngestRate,
+    pub flush: FlushStatus,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DatasetStats {
+    pub name: String,
+    pub rows: usize,
+    pub columns: usize,
+    pub estimated_bytes: usize,
+    // Rows spilled to disk (not counted in `rows` or `estimated_bytes`), and the size of their files
+    pub spilled_rows: usize,
+    pub spilled_bytes: usize,
+    // Waits for the dataset's write lock by ingests since the service started
+    pub lock_waits: u64,
+    pub lock_wait_mean_ms: f64,
+    pub lock_wait_max_ms: f64,
+}
+
+// What was ingested over the last minute (less, just after starting), across every dataset
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IngestRate {
+    pub window_seconds: f64,
+    pub payloads: u64,
+    pub rows: u64,
+    pub bytes: u64,
+    pub rows_per_second: f64,
+    pub bytes_per_second: f64,
+}
+
+// The body of `PUT /admin/log_level`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogLevelRequest {
+    // A filter as `RUST_LOG` takes it, e.g. `info,data_collator::aggregate=debug`
+    pub filter: String,
+}
+
+// `/admin/log_level`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogLevelResponse {
+    pub status: Status,
+    pub filter: String,
+    // The filter a `PUT` replaced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<String>,
+}
+
+// `POST /admin/rollback`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RollbackResponse {
+    pub status: Status,
+    pub dataset: String,
+    // The ingests undone, latest first
+    pub rolled_back: Vec<RolledBackBatch>,
+    // Rows left
+    pub rows: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RolledBackBatch {
+    // `collate`, `aggregate`, `upsert`, or `collate_wide`
+    pub operation: String,
+    // Rows the payload had
+    pub rows: usize,
+}
+
+// `GET /livez` and `GET /readyz`, with a `503` when any check failed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProbeResponse {
+    pub status: Status,
+    pub checks: Vec<ProbeCheck>,
+}
+
+// One thing a probe looked at: `datasets`, `writer`, `output`, `postgres`, ...
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProbeCheck {
+    pub name: String,
+    pub ok: bool,
+    // What it found, e.g. why it failed
+    pub message: String,
+}
+
+// A change to a dataset, as `/ws` and `/events` send them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangeEvent {
+    pub dataset: String,
+    // What made the change, e.g. `collate`, `aggregate`, or `reset`
+    pub operation: String,
+    // The rows added or replaced (left out of `/events` with `rows=false`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rows: Option<Vec<Value>>,
+    pub changed_rows: usize,
+    pub total_rows: usize,
+}
+
+// `GET /sources`: what each client has sent, the ones that sent the most bytes first
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourcesResponse {
+    pub status: Status,
+    // When counting started (the service's start)
+    pub since: String,
+    // How long a quota lasts, and when the current period started, when quotas are configured
+    pub period_secs: Option<u64>,
+    pub period_started: Option<String>,
+    pub sources: Vec<SourceAccount>,
+}
+
+// What a client (`key:<id>`, an `X-Source-Id`, or an IP address) has sent since the service started
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceAccount {
+    pub source: String,
+    pub rows: u64,
+    pub bytes: u64,
+    pub batches: u64,
+    // Its part of every client's bytes, from 0 to 1
+    pub share: f64,
+    // Batches turned away because its quota was used up
+    pub rejected: u64,
+    pub first_seen: String,
+    pub last_seen: String,
+    // What it has sent this period, and its quota, when quotas are configured
+    pub used: Option<SourceUsage>,
+    pub quota: Option<Quota>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceUsage {
+    pub rows: u64,
+    pub bytes: u64,
+    pub batches: u64,
+}
+
+// A message `/ws` sends
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SocketMessage {
+    // A message was collated (or aggregated). `seq` counts the messages received, from 1.
+    Ack {
+        seq: u64,
+        status: Status,
+        rows: usize,
+        wrote_to_file: String,
+        #[serde(flatten)]
+        counts: IngestCounts,
+    },
+    // A message was turned down
+    Error {
+        seq: u64,
+        status: Status,
+        error: String,
+        message: String,
+    },
+    Update(ChangeEvent),
+    Lagged(Lagged),
+}
+
+// Changes that were skipped, because the connection wasn't reading them fast enough (a `/ws` message, or the data of
+// an `/events` `lagged` event)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lagged {
+    pub missed: u64,
+}
@@ -0,0 +1,353 @@
+use std::{
+    fs::File,
+    io::{self, Cursor, Write},
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use axum::{
+    body::Bytes,
+    extract::{FromRequest, Request},
+    http::{header, HeaderMap},
+};
+use futures::StreamExt;
+use log::{trace, warn};
+use polars::prelude::DataFrame;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    audit,
+    compression::{self, request_encoding, Encoding},
+    csv_stream::CsvStream,
+    dataset::AppState,
+    digest::{hex, Sha256},
+    error::AppError,
+    payload::{self, read_payload},
+};
+
+// Used to give every spool file in this process its own name
+static SPOOL_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// A request body, read with the configured size limits. Bodies up to `stream_body_bytes` are kept in memory; bigger
+// ones (or ones sent without a `Content-Length` that grow past it) are streamed to a temporary file as they arrive,
+// so the raw upload never has to fit in memory next to the DataFrame parsed from it. Bodies sent with a
+// `Content-Encoding` are decompressed once received, and the limits apply to both the compressed and decompressed
+// sizes.
+#[derive(Debug)]
+pub enum Upload {
+    Memory(Bytes),
+    Spooled(SpoolFile),
+}
+
+// A temporary file holding a request body, removed when dropped
+#[derive(Debug)]
+pub struct SpoolFile {
+    pub path: PathBuf,
+    pub len: usize,
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("Can't remove spooled request body {:?}: {}", self.path, e);
+        }
+    }
+}
+
+impl Upload {
+    // Parse the body into a DataFrame (see `read_payload`)
+    pub fn read(&self, headers: &HeaderMap) -> Result<DataFrame, String> {
+        match self {
+            Upload::Memory(bytes) => read_payload(headers, Cursor::new(bytes.as_ref())),
+            Upload::Spooled(file) => {
+                let body = File::open(&file.path).map_err(|e| format!("Can't read spooled request body: {}", e))?;
+                read_payload(headers, body)
+            }
+        }
+    }
+
+    // Size of the body in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            Upload::Memory(bytes) => bytes.len(),
+            Upload::Spooled(file) => file.len,
+        }
+    }
+
+    // The whole body in memory, for handlers that can't parse from a file
+    pub async fn into_bytes(self) -> Result<Bytes, AppError> {
+        match self {
+            Upload::Memory(bytes) => Ok(bytes),
+            Upload::Spooled(file) => tokio::fs::read(&file.path)
+                .await
+                .map(Bytes::from)
+                .map_err(|e| AppError::Internal(format!("Can't read spooled request body: {}", e))),
+        }
+    }
+}
+
+// A request body of rows, for `/collate` and the routes like it. Unlike an `Upload`, a CSV body (sent without a
+// `Content-Encoding`) larger than `stream_body_bytes` is parsed as it arrives, `stream_body_bytes` at a time, instead
+// of being spooled to a file and parsed once it's all there (see `CsvStream`).
+#[derive(Debug)]
+pub enum Payload {
+    Upload(Upload),
+    // The rows of a CSV body parsed as it arrived, and the body's size
+    Parsed(DataFrame, usize),
+}
+
+impl Payload {
+    // Size of the body in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            Payload::Upload(upload) => upload.size(),
+            Payload::Parsed(_, len) => *len,
+        }
+    }
+
+    // The body's rows, parsed on the compute pool unless they already were
+    pub async fn rows(self, state: &AppState, headers: &HeaderMap) -> Result<DataFrame, AppError> {
+        match self {
+            Payload::Upload(upload) => {
+                let headers = headers.clone();
+                state
+                    .compute
+                    .run(move || tracing::info_span!("parse").in_scope(|| upload.read(&headers)))
+                    .await?
+                    .map_err(AppError::BadRequest)
+            }
+            Payload::Parsed(df, _) => Ok(df),
+        }
+    }
+}
+
+impl FromRequest<Arc<AppState>> for Upload {
+    type Rejection = AppError;
+
+    async fn from_request(request: Request, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        match receive(request, state, false).await? {
+            Payload::Upload(upload) => Ok(upload),
+            Payload::Parsed(..) => unreachable!("only payloads are parsed as they arrive"),
+        }
+    }
+}
+
+impl FromRequest<Arc<AppState>> for Payload {
+    type Rejection = AppError;
+
+    async fn from_request(request: Request, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        let parse_csv = request_encoding(request.headers())?.is_none() && payload::is_csv(request.headers());
+        receive(request, state, parse_csv).await
+    }
+}
+
+// Read a request body, parsing it as it arrives if `parse_csv` is set and it turns out to be bigger than what's kept in
+// memory
+async fn receive(request: Request, state: &Arc<AppState>, parse_csv: bool) -> Result<Payload, AppError> {
+    let max = state.config.server.max_body_bytes;
+    let soft = state.config.server.stream_body_bytes.min(max);
+    let encoding = request_encoding(request.headers())?;
+
+    // Turn oversized uploads away before reading any of them, when the client says how big they are
+    let declared = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if declared.is_some_and(|len| len > max) {
+        return Err(too_large(max));
+    }
+
+    let mut stream = request.into_body().into_data_stream();
+    let mut buffer = Vec::with_capacity(declared.unwrap_or(0).min(soft));
+    let mut spool: Option<(tokio::fs::File, SpoolFile)> = None;
+    let mut csv: Option<CsvStream> = None;
+    let mut len = 0;
+    // The audit log records what was sent, as it was sent
+    let mut digest = audit::wants_payload().then(Sha256::new);
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| AppError::BadRequest(format!("Error reading the request body: {}", e)))?;
+        len += chunk.len();
+        if let Some(digest) = digest.as_mut() {
+            digest.update(&chunk);
+        }
+        if len > max {
+            return Err(too_large(max));
+        }
+
+        if let Some(csv) = csv.as_mut() {
+            csv.push(&chunk).await?;
+            continue;
+        }
+        match spool.as_mut() {
+            Some((file, _)) => write_chunk(file, &chunk).await?,
+            None if len > soft && parse_csv => {
+                let mut stream = CsvStream::new(state.clone(), soft);
+                stream.push(&std::mem::take(&mut buffer)).await?;
+                stream.push(&chunk).await?;
+                csv = Some(stream);
+            }
+            None if len > soft => {
+                let (mut file, spooled) = create_spool_file().await?;
+                write_chunk(&mut file, &buffer).await?;
+                write_chunk(&mut file, &chunk).await?;
+                buffer = Vec::new();
+                spool = Some((file, spooled));
+            }
+            None => buffer.extend_from_slice(&chunk),
+        }
+    }
+
+    if let Some(csv) = csv {
+        if let Some(digest) = digest {
+            audit::note_payload(len, hex(&digest.finish()));
+        }
+        let df = csv.finish().await?;
+        trace!("Parsed a {} byte CSV body into {} rows as it arrived", len, df.height());
+        return Ok(Payload::Parsed(df, len));
+    }
+
+    let upload = match spool {
+        Some((mut file, mut spooled)) => {
+            file.flush()
+                .await
+                .map_err(|e| AppError::Internal(format!("Can't spool the request body: {}", e)))?;
+            spooled.len = len;
+            trace!("Spooled a {} byte request body to {:?}", len, spooled.path);
+            Upload::Spooled(spooled)
+        }
+        None => Upload::Memory(Bytes::from(buffer)),
+    };
+    if let Some(digest) = digest {
+        audit::note_payload(len, hex(&digest.finish()));
+    }
+
+    match encoding {
+        Some(encoding) => tokio::task::spawn_blocking(move || upload.decompressed(encoding, max, soft))
+            .await
+            .map_err(|e| AppError::Internal(format!("Decompressing the request body failed: {}", e)))?
+            .map(Payload::Upload),
+        None => Ok(Payload::Upload(upload)),
+    }
+}
+
+impl Upload {
+    // The body decompressed, kept in memory or spooled by the same rules as an uncompressed one
+    fn decompressed(self, encoding: Encoding, max: usize, soft: usize) -> Result<Upload, AppError> {
+        let mut output = Decompressed {
+            buffer: Vec::new(),
+            spool: None,
+            len: 0,
+            max,
+            soft,
+            error: None,
+        };
+        let result = match &self {
+            Upload::Memory(bytes) => compression::decode(encoding, bytes.as_ref(), &mut output),
+            Upload::Spooled(file) => {
+                File::open(&file.path).and_then(|body| compression::decode(encoding, body, &mut output))
+            }
+        };
+        if let Some(e) = output.error.take() {
+            return Err(e);
+        }
+        if let Err(e) = result {
+            return Err(AppError::BadRequest(format!("Can't decompress the {} request body: {}", encoding.name(), e)));
+        }
+
+        let compressed = self.size();
+        match output.spool {
+            Some((mut file, mut spooled)) => {
+                file.flush().map_err(|e| AppError::Internal(format!("Can't spool the request body: {}", e)))?;
+                spooled.len = output.len;
+                trace!("Spooled a {} byte request body ({} compressed) to {:?}", output.len, compressed, spooled.path);
+                Ok(Upload::Spooled(spooled))
+            }
+            None => Ok(Upload::Memory(Bytes::from(output.buffer))),
+        }
+    }
+}
+
+// Where a request body is decompressed to: memory up to `soft` bytes, then a spool file. Writes past `max` fail, so
+// a small body that decompresses into something huge is turned away instead of filling the disk.
+struct Decompressed {
+    buffer: Vec<u8>,
+    spool: Option<(File, SpoolFile)>,
+    len: usize,
+    max: usize,
+    soft: usize,
+    // Why a write failed, when it wasn't the compressed data's fault
+    error: Option<AppError>,
+}
+
+impl Write for Decompressed {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.len += bytes.len();
+        if self.len > self.max {
+            self.error = Some(too_large(self.max));
+            return Err(io::Error::other("the decompressed body is too large"));
+        }
+
+        let spooled = match self.spool.as_mut() {
+            Some((file, _)) => file.write_all(bytes),
+            None if self.len > self.soft => {
+                let path = spool_path();
+                File::create(&path).and_then(|mut file| {
+                    let spooled = SpoolFile { path, len: 0 };
+                    file.write_all(&self.buffer)?;
+                    file.write_all(bytes)?;
+                    self.buffer = Vec::new();
+                    self.spool = Some((file, spooled));
+                    Ok(())
+                })
+            }
+            None => {
+                self.buffer.extend_from_slice(bytes);
+                Ok(())
+            }
+        };
+        if let Err(e) = spooled {
+            self.error = Some(AppError::Internal(format!("Can't spool the request body: {}", e)));
+            return Err(e);
+        }
+
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.spool.as_mut() {
+            Some((file, _)) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+fn spool_path() -> PathBuf {
+    let id = SPOOL_COUNTER.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("data_collator-{}-{}.upload", process::id(), id))
+}
+
+async fn create_spool_file() -> Result<(tokio::fs::File, SpoolFile), AppError> {
+    let path = spool_path();
+
+    let file = tokio::fs::File::create(&path)
+        .await
+        .map_err(|e| AppError::Internal(format!("Can't spool the request body to {:?}: {}", path, e)))?;
+
+    Ok((file, SpoolFile { path, len: 0 }))
+}
+
+async fn write_chunk(file: &mut tokio::fs::File, chunk: &[u8]) -> Result<(), AppError> {
+    file.write_all(chunk)
+        .await
+        .map_err(|e| AppError::Internal(format!("Can't spool the request body: {}", e)))
+}
+
+fn too_large(max: usize) -> AppError {
+    AppError::PayloadTooLarge(format!("The request body is larger than the {} byte limit", max))
+}
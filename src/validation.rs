@@ -0,0 +1,147 @@
+use indexmap::IndexMap;
+use polars::prelude::*;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+
+// Column added to quarantined rows, saying which rules they broke
+pub const REASON_COLUMN: &str = "_quarantine_reason";
+
+// What the config file says about a column, before the regex is compiled
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawColumnRule {
+    min: Option<f64>,
+    max: Option<f64>,
+    #[serde(default)]
+    not_null: bool,
+    regex: Option<String>,
+    allowed: Option<Vec<Value>>,
+}
+
+// Checks every value of one column must pass. Nulls only fail `not_null`; a column missing from a payload counts as
+// all nulls.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "RawColumnRule")]
+pub struct ColumnRule {
+    // Inclusive bounds, for numeric columns
+    min: Option<f64>,
+    max: Option<f64>,
+    not_null: bool,
+    // Must match somewhere in the value (anchor it with `^...$` to match the whole value)
+    regex: Option<Regex>,
+    // Values are compared as text, so `allowed = [200, 404]` works for integer columns too
+    allowed: Option<Vec<String>>,
+}
+
+impl TryFrom<RawColumnRule> for ColumnRule {
+    type Error = String;
+
+    fn try_from(raw: RawColumnRule) -> Result<Self, Self::Error> {
+        let regex = raw
+            .regex
+            .map(|regex| Regex::new(&regex).map_err(|e| format!("Invalid regex {:?}: {}", regex, e)))
+            .transpose()?;
+        let allowed = raw.allowed.map(|values| {
+            values
+                .into_iter()
+                .map(|value| match value {
+                    Value::String(value) => value,
+                    other => other.to_string(),
+                })
+                .collect()
+        });
+
+        Ok(ColumnRule {
+            min: raw.min,
+            max: raw.max,
+            not_null: raw.not_null,
+            regex,
+            allowed,
+        })
+    }
+}
+
+impl ColumnRule {
+    // Why a value breaks this rule, if it does
+    fn check(&self, value: &AnyValue) -> Option<String> {
+        if value.is_null() {
+            return self.not_null.then(|| String::from("is null"));
+        }
+
+        if self.min.is_some() || self.max.is_some() {
+            let Some(number) = value.extract::<f64>() else {
+                return Some(format!("{} is not a number", value));
+            };
+            if let Some(min) = self.min.filter(|min| number < *min) {
+                return Some(format!("{} is below the minimum {}", value, min));
+            }
+            if let Some(max) = self.max.filter(|max| number > *max) {
+                return Some(format!("{} is above the maximum {}", value, max));
+            }
+        }
+
+        let text = match value {
+            AnyValue::String(text) => text.to_string(),
+            AnyValue::StringOwned(text) => text.to_string(),
+            other => other.to_string(),
+        };
+        if let Some(regex) = self.regex.as_ref().filter(|regex| !regex.is_match(&text)) {
+            return Some(format!("{:?} doesn't match {:?}", text, regex.as_str()));
+        }
+        if let Some(allowed) = self.allowed.as_ref().filter(|allowed| !allowed.contains(&text)) {
+            return Some(format!("{:?} is not one of {:?}", text, allowed));
+        }
+
+        None
+    }
+}
+
+// Per-column rules for one dataset (`[validation.<dataset>.<column>]`)
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct ValidationRules {
+    columns: IndexMap<String, ColumnRule>,
+}
+
+impl ValidationRules {
+    // Split a payload into the rows that pass every rule and the ones that don't. Failing rows get a
+    // `_quarantine_reason` column listing each broken rule; `None` means every row passed.
+    pub fn split(&self, df: &DataFrame) -> PolarsResult<(DataFrame, Option<DataFrame>)> {
+        let mut reasons: Vec<Option<String>> = vec![None; df.height()];
+
+        for (name, rule) in &self.columns {
+            let column = df.column(name).ok();
+            for (row, reason) in reasons.iter_mut().enumerate() {
+                let value = match column {
+                    Some(column) => column.get(row)?,
+                    None => AnyValue::Null,
+                };
+                let Some(broken) = rule.check(&value) else {
+                    continue;
+                };
+
+                let broken = format!("{}: {}", name, broken);
+                match reason {
+                    Some(reason) => {
+                        reason.push_str("; ");
+                        reason.push_str(&broken);
+                    }
+                    None => *reason = Some(broken),
+                }
+            }
+        }
+
+        if reasons.iter().all(Option::is_none) {
+            return Ok((df.clone(), None));
+        }
+
+        let failed: BooleanChunked = reasons.iter().map(|reason| Some(reason.is_some())).collect();
+        let passed = df.filter(&!&failed)?;
+        let mut quarantined = df.filter(&failed)?;
+        let reasons: Vec<&str> = reasons.iter().filter_map(|reason| reason.as_deref()).collect();
+        quarantined.with_column(Column::new(REASON_COLUMN.into(), reasons))?;
+
+        Ok((passed, Some(quarantined)))
+    }
+}
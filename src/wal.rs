@@ -0,0 +1,649 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufReader, Cursor, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::{
+    aggregate::{AggregateMode, AggregateSpec},
+    computed::ComputedColumns,
+    dataset::{validate_dataset_name, ConcatMode, Dataset, KeepDuplicate},
+    filter::Filter,
+    nulls::FillSpec,
+    outliers::OutlierSpec,
+    payload::{write_df, FileFormat},
+    retention::Eviction,
+    snapshot::read_snapshot,
+};
+
+// Start of every WAL file, so a file in some other format is never mistaken for (or truncated as) a log
+const MAGIC: &[u8; 8] = b"DCWAL\0\0\x01";
+
+// Sequence number, header length, body length, checksum
+const RECORD_HEADER_LEN: usize = 8 + 4 + 4 + 8;
+
+// What a logged payload did to its dataset
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Operation {
+    Collate {
+        // Missing from records written before union concatenation existed
+        #[serde(default)]
+        concat: ConcatMode,
+    },
+    Aggregate {
+        spec: AggregateSpec,
+        // Missing from records written before incremental aggregation existed
+        #[serde(default)]
+        mode: AggregateMode,
+    },
+    // `POST /upsert`
+    Upsert {
+        keys: Vec<String>,
+        #[serde(default)]
+        concat: ConcatMode,
+    },
+    // `POST /collate_wide`
+    CollateWide { keys: Vec<String> },
+    // `POST /reset` (the record's payload is empty)
+    Reset,
+    // `DELETE /data` (the record's payload is empty)
+    Delete { filter: String },
+    // `POST /dedup` (the record's payload is empty)
+    Dedup {
+        subset: Option<Vec<String>>,
+        keep: KeepDuplicate,
+    },
+    // `POST /fill_nulls` (the record's payload is empty)
+    FillNulls { spec: FillSpec },
+    // `POST /drop_nulls` (the record's payload is empty)
+    DropNulls { subset: Option<Vec<String>> },
+    // `POST /outliers` (the record's payload is empty)
+    FlagOutliers { spec: OutlierSpec },
+    // Rows of a `/collate` or `/aggregate` payload that failed validation (the record's payload is those rows)
+    Quarantine,
+    // `DELETE /quarantine` (the record's payload is empty)
+    ClearQuarantine,
+    // `POST /snapshots/{id}/restore` (the record's payload is empty; the snapshot is read back from disk)
+    Restore { snapshot: String },
+    // `PUT /columns/computed`, or with no columns `DELETE /columns/computed` (the record's payload is empty)
+    Computed { columns: ComputedColumns },
+    // `POST /admin/rollback` (the record's payload is empty; the ledger replayed so far says what's undone)
+    Rollback { batches: usize },
+    // Rows a retention policy evicted (the record's payload is empty)
+    Evict { spec: Eviction },
+}
+
+impl Operation {
+    // What it's called in `GET /versions` (the same as the operation the audit log records it as)
+    pub fn name(&self) -> &'static str {
+        match self {
+            Operation::Collate { .. } => "collate",
+            Operation::Aggregate { .. } => "aggregate",
+            Operation::Upsert { .. } => "upsert",
+            Operation::CollateWide { .. } => "collate_wide",
+            Operation::Reset => "reset",
+            Operation::Delete { .. } => "delete",
+            Operation::Dedup { .. } => "dedup",
+            Operation::FillNulls { .. } => "fill_nulls",
+            Operation::DropNulls { .. } => "drop_nulls",
+            Operation::FlagOutliers { .. } => "flag_outliers",
+            Operation::Quarantine => "quarantine",
+            Operation::ClearQuarantine => "clear_quarantine",
+            Operation::Restore { .. } => "restore",
+            Operation::Computed { .. } => "set_computed",
+            Operation::Rollback { .. } => "rollback",
+            Operation::Evict { .. } => "evict",
+        }
+    }
+
+    // What an ingest `POST /admin/rollback` can undo is called, or `None` for anything else
+    pub fn ingest_name(&self) -> Option<&'static str> {
+        match self {
+            Operation::Collate { .. }
+            | Operation::Aggregate { .. }
+            | Operation::Upsert { .. }
+            | Operation::CollateWide { .. } => Some(self.name()),
+            _ => None,
+        }
+    }
+
+    // Whether it changes a dataset's rows, making a new version of it
+    pub fn changes_rows(&self) -> bool {
+        !matches!(self, Operation::Quarantine | Operation::ClearQuarantine | Operation::Computed { .. })
+    }
+
+    // Whether it changes a dataset's rows some other way than an ingest, so earlier ingests can't be rolled back
+    pub fn clears_ledger(&self) -> bool {
+        self.changes_rows() && self.ingest_name().is_none() && !matches!(self, Operation::Rollback { .. })
+    }
+}
+
+// The JSON part of a record; the payload itself follows as an Arrow IPC stream
+#[derive(Debug, Serialize, Deserialize)]
+struct RecordHeader {
+    dataset: String,
+    #[serde(flatten)]
+    operation: Operation,
+    // When it was logged, in microseconds since the Unix epoch (missing from records written before `as_of` reads)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    at: Option<i64>,
+    // On a replica, the primary's record it's a copy of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    replicated: Option<Replicated>,
+}
+
+#[derive(Debug)]
+pub struct Record {
+    pub seq: u64,
+    pub dataset: String,
+    pub operation: Operation,
+    pub at: Option<DateTime<Utc>>,
+    pub replicated: Option<Replicated>,
+    pub df: DataFrame,
+}
+
+// Where a replica's record came from: the primary's stream of changes (see `replication`), and its number in it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Replicated {
+    pub stream: String,
+    pub seq: u64,
+}
+
+// Append-only log of every accepted payload. Records are written (and synced) before the payload is applied, so
+// replaying the log on startup reproduces the in-memory state exactly.
+#[derive(Debug, Clone)]
+pub struct Wal {
+    file: Arc<Mutex<WalFile>>,
+}
+
+#[derive(Debug)]
+struct WalFile {
+    file: File,
+    next_seq: u64,
+    // Why a failed write couldn't be cut back off the end of the log, after which nothing more can be appended to it
+    torn: Option<String>,
+}
+
+impl Wal {
+    // Open (or create) a WAL file and read back every intact record. A torn record at the end, left behind by a
+    // crash in the middle of a write, is cut off so new records start from the last good one. A corrupt record with
+    // intact ones after it isn't cut off, since that would throw the later ones away too: the log has to be repaired
+    // (or moved aside) first. Records `skip` picks (by dataset and sequence number) come back without their payloads,
+    // which aren't decoded at all; records are read one at a time, so only the payloads kept have to fit in memory.
+    pub fn open(path: &Path, skip: &dyn Fn(&str, u64) -> bool) -> Result<(Wal, Vec<Record>), String> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|e| format!("Can't open write-ahead log {:?}: {}", path, e))?;
+
+        let mut len = file.metadata().map_err(|e| format!("Can't read write-ahead log {:?}: {}", path, e))?.len();
+        if len == 0 {
+            file.write_all(MAGIC)
+                .and_then(|()| file.sync_data())
+                .map_err(|e| format!("Can't write to write-ahead log {:?}: {}", path, e))?;
+            len = MAGIC.len() as u64;
+        } else {
+            let mut magic = [0; MAGIC.len()];
+            if file.read_exact(&mut magic).is_err() || &magic != MAGIC {
+                return Err(format!("{:?} is not a write-ahead log", path));
+            }
+        }
+
+        let mut records: Vec<Record> = Vec::new();
+        let mut good_len = MAGIC.len() as u64;
+        let mut reader = BufReader::new(&file);
+        while let Some((record, record_len)) = next_record(&mut reader, len - good_len, skip) {
+            if records.last().is_some_and(|last| record.seq <= last.seq) {
+                break;
+            }
+            records.push(record);
+            good_len += record_len as u64;
+        }
+        drop(reader);
+
+        if good_len < len {
+            // Only what's after the last good record is read into memory, to see whether it's just a torn write
+            let mut rest = Vec::new();
+            file.seek(SeekFrom::Start(good_len))
+                .and_then(|_| file.read_to_end(&mut rest))
+                .map_err(|e| format!("Can't read write-ahead log {:?}: {}", path, e))?;
+            let last_seq = records.last().map_or(0, |record| record.seq);
+            if let Some((offset, seq)) = next_intact(&rest, last_seq) {
+                return Err(format!(
+                    "Write-ahead log {:?} is corrupt at byte {}, but has intact records after it (from record {} at \
+                     byte {}). Repair it, or move it aside, before starting.",
+                    path,
+                    good_len,
+                    seq,
+                    good_len + offset as u64
+                ));
+            }
+            warn!(
+                "Write-ahead log {:?} ends with {} bytes of incomplete or corrupt data, discarding them",
+                path,
+                len - good_len
+            );
+            file.set_len(good_len)
+                .map_err(|e| format!("Can't truncate write-ahead log {:?}: {}", path, e))?;
+        }
+        file.seek(SeekFrom::Start(good_len))
+            .map_err(|e| format!("Can't seek in write-ahead log {:?}: {}", path, e))?;
+
+        info!("Read {} records from write-ahead log {:?}", records.len(), path);
+
+        let next_seq = records.last().map_or(1, |record| record.seq + 1);
+        let wal = Wal {
+            file: Arc::new(Mutex::new(WalFile {
+                file,
+                next_seq,
+                torn: None,
+            })),
+        };
+
+        Ok((wal, records))
+    }
+
+    // The number of the last record written, or 0 before the first one
+    pub fn last_seq(&self) -> u64 {
+        self.file.lock().unwrap().next_seq - 1
+    }
+
+    // Durably log a payload, returning its sequence number and the record as it was written (which is what replicas
+    // are sent). Once this returns, the payload survives a crash.
+    pub async fn append(
+        &self,
+        dataset: &str,
+        operation: &Operation,
+        df: &DataFrame,
+        replicated: Option<&Replicated>,
+    ) -> Result<(u64, Vec<u8>), String> {
+        let (header, body) = encode_parts(dataset, operation, Utc::now(), replicated, df)?;
+
+        let file = self.file.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut wal = file.lock().unwrap();
+            if let Some(e) = &wal.torn {
+                return Err(format!("an earlier write failed and couldn't be undone ({})", e));
+            }
+            let seq = wal.next_seq;
+
+            let record = encode_record(seq, &header, &body);
+            let start = wal.file.stream_position().map_err(|e| e.to_string())?;
+            if let Err(e) = wal.file.write_all(&record).and_then(|()| wal.file.sync_data()) {
+                // Cut off what was written of the record, so the next one doesn't go after a torn record (which
+                // reading the log stops at)
+                let undone = wal.file.set_len(start).and_then(|()| wal.file.seek(SeekFrom::Start(start)));
+                if let Err(undo) = undone {
+                    wal.torn = Some(undo.to_string());
+                }
+                return Err(e.to_string());
+            }
+            wal.next_seq += 1;
+
+            Ok((seq, record))
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()))
+        .map_err(|e| format!("Can't write to the write-ahead log: {}", e))
+    }
+}
+
+// Read back every intact record of a log without opening it for writing, for `as_of` reads while the service appends
+// to it. A record that's still being written is left out.
+pub fn read_log(path: &Path) -> Result<Vec<Record>, String> {
+    let contents = std::fs::read(path).map_err(|e| format!("Can't read write-ahead log {:?}: {}", path, e))?;
+    let records = contents.strip_prefix(MAGIC).ok_or_else(|| format!("{:?} is not a write-ahead log", path))?;
+    Ok(read_records(records).0)
+}
+
+// Decode the records a replica is sent, which have to be intact, every one of them
+pub fn decode(data: &[u8]) -> Result<Vec<Record>, String> {
+    let (records, consumed) = read_records(data);
+    if consumed < data.len() {
+        return Err(format!("The records after the first {} are incomplete or corrupt", records.len()));
+    }
+    Ok(records)
+}
+
+// A record's header and payload, for `encode_record` to put together once it's numbered
+pub fn encode_parts(
+    dataset: &str,
+    operation: &Operation,
+    at: DateTime<Utc>,
+    replicated: Option<&Replicated>,
+    df: &DataFrame,
+) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let header = RecordHeader {
+        dataset: dataset.to_string(),
+        operation: operation.clone(),
+        at: Some(at.timestamp_micros()),
+        replicated: replicated.cloned(),
+    };
+    let header = serde_json::to_vec(&header).map_err(|e| e.to_string())?;
+    let body = write_df(&mut df.clone(), FileFormat::Arrow)?;
+    Ok((header, body))
+}
+
+// A record the way the log holds it, which is also how replicas are sent it
+pub fn encode_record(seq: u64, header: &[u8], body: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN + header.len() + body.len());
+    record.extend_from_slice(&seq.to_le_bytes());
+    record.extend_from_slice(&(header.len() as u32).to_le_bytes());
+    record.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    record.extend_from_slice(&checksum(seq, header, body).to_le_bytes());
+    record.extend_from_slice(header);
+    record.extend_from_slice(body);
+    record
+}
+
+fn checksum(seq: u64, header: &[u8], body: &[u8]) -> u64 {
+    let mut bytes = Vec::with_capacity(8 + header.len() + body.len());
+    bytes.extend_from_slice(&seq.to_le_bytes());
+    bytes.extend_from_slice(header);
+    bytes.extend_from_slice(body);
+    xxh3_64(&bytes)
+}
+
+// Decode records until the data runs out or stops making sense, returning them along with how many bytes they took
+fn read_records(mut data: &[u8]) -> (Vec<Record>, usize) {
+    let mut records = Vec::new();
+    let mut consumed = 0;
+
+    while let Some((record, len)) = read_record(data) {
+        // Sequence numbers only go up; anything else means the rest of the file can't be trusted
+        if records.last().is_some_and(|last: &Record| record.seq <= last.seq) {
+            break;
+        }
+        records.push(record);
+        consumed += len;
+        data = &data[len..];
+    }
+
+    (records, consumed)
+}
+
+// Where the first intact record after a corrupt one starts, and its sequence number, if there is one. Only records
+// numbered after `last_seq` count, so finding one means the corruption isn't just a torn write at the end.
+fn next_intact(data: &[u8], last_seq: u64) -> Option<(usize, u64)> {
+    (1..data.len()).find_map(|offset| {
+        let rest = &data[offset..];
+        let seq = u64::from_le_bytes(rest.get(..8)?.try_into().ok()?);
+        // Cheap checks first, so most offsets never get as far as the checksum
+        if seq <= last_seq || seq - last_seq > u32::MAX as u64 {
+            return None;
+        }
+        read_record(rest).map(|_| (offset, seq))
+    })
+}
+
+fn read_record(data: &[u8]) -> Option<(Record, usize)> {
+    let (seq, header_len, body_len, expected) = fixed_header(data.get(..RECORD_HEADER_LEN)?)?;
+    let header_end = RECORD_HEADER_LEN.checked_add(header_len)?;
+    let end = header_end.checked_add(body_len)?;
+    let header = data.get(RECORD_HEADER_LEN..header_end)?;
+    let body = data.get(header_end..end)?;
+    let record = decoded(seq, header, body, expected, &|_, _| false)?;
+
+    Some((record, end))
+}
+
+// The same, reading the record from `reader` (with at most `available` bytes left in it)
+fn next_record(reader: &mut impl Read, available: u64, skip: &dyn Fn(&str, u64) -> bool) -> Option<(Record, usize)> {
+    let mut fixed = [0; RECORD_HEADER_LEN];
+    reader.read_exact(&mut fixed).ok()?;
+    let (seq, header_len, body_len, expected) = fixed_header(&fixed)?;
+    let len = RECORD_HEADER_LEN.checked_add(header_len)?.checked_add(body_len)?;
+    // A corrupt length isn't allocated for
+    if len as u64 > available {
+        return None;
+    }
+
+    let mut rest = vec![0; header_len + body_len];
+    reader.read_exact(&mut rest).ok()?;
+    let (header, body) = rest.split_at(header_len);
+    let record = decoded(seq, header, body, expected, skip)?;
+
+    Some((record, len))
+}
+
+// A record's sequence number, header and body lengths, and checksum
+fn fixed_header(fixed: &[u8]) -> Option<(u64, usize, usize, u64)> {
+    let seq = u64::from_le_bytes(fixed.get(0..8)?.try_into().ok()?);
+    let header_len = u32::from_le_bytes(fixed.get(8..12)?.try_into().ok()?) as usize;
+    let body_len = u32::from_le_bytes(fixed.get(12..16)?.try_into().ok()?) as usize;
+    let expected = u64::from_le_bytes(fixed.get(16..24)?.try_into().ok()?);
+    Some((seq, header_len, body_len, expected))
+}
+
+// A record from its header and body, if they're intact. The payload of a record `skip` picks is left empty.
+fn decoded(seq: u64, header: &[u8], body: &[u8], expected: u64, skip: &dyn Fn(&str, u64) -> bool) -> Option<Record> {
+    if checksum(seq, header, body) != expected {
+        return None;
+    }
+
+    let header: RecordHeader = serde_json::from_slice(header).ok()?;
+    let df = if skip(&header.dataset, seq) {
+        DataFrame::empty()
+    } else {
+        IpcStreamReader::new(Cursor::new(body)).finish().ok()?
+    };
+
+    Some(Record {
+        seq,
+        dataset: header.dataset,
+        operation: header.operation,
+        at: header.at.and_then(DateTime::from_timestamp_micros),
+        replicated: header.replicated,
+        df,
+    })
+}
+
+// Rebuild datasets by applying logged payloads in order, on top of what they started out with. Restores need the
+// snapshots they restored from to still be in `snapshots_dir`.
+pub fn replay(
+    records: Vec<Record>,
+    datasets: &mut HashMap<String, Dataset>,
+    snapshots_dir: Option<&Path>,
+    rollback_batches: usize,
+) {
+    for record in records {
+        if let Err(message) = validate_dataset_name(&record.dataset) {
+            warn!("Skipping write-ahead log record {}: {}", record.seq, message);
+            continue;
+        }
+
+        let dataset = datasets.entry(record.dataset.clone()).or_default();
+        let applied = unspill_for(dataset, &record);
+        if let Err(e) = applied.and_then(|()| apply(dataset, &record, snapshots_dir, rollback_batches)) {
+            warn!("Skipping write-ahead log record {}: {}", record.seq, e);
+        }
+    }
+}
+
+// Read a dataset's spilled rows back into memory if a record needs them. Only appends work on top of spilled rows, as
+// long as they don't reshape the dataset.
+pub fn unspill_for(dataset: &mut Dataset, record: &Record) -> Result<(), String> {
+    let appends = match &record.operation {
+        Operation::Collate { .. } => dataset.df.as_ref().is_none_or(|df| df.schema() == record.df.schema()),
+        operation => !operation.changes_rows(),
+    };
+    if appends { Ok(()) } else { dataset.unspill() }
+}
+
+// Apply a logged payload to its dataset, the way it was applied when it was logged (or on the primary it was
+// replicated from)
+pub fn apply(
+    dataset: &mut Dataset,
+    record: &Record,
+    snapshots_dir: Option<&Path>,
+    rollback_batches: usize,
+) -> Result<(), String> {
+    let before = dataset.before_batch();
+    let result = match &record.operation {
+        Operation::Collate { concat } => dataset
+            .collated(&record.df, *concat)
+            .map(|df| {
+                dataset.df = Some(df);
+            })
+            .map_err(|e| e.to_string()),
+        Operation::Aggregate { spec, mode } => dataset
+            .aggregated(&record.df, spec, *mode)
+            .map(|(state, df)| {
+                dataset.aggregate_state = Some(state);
+                dataset.df = Some(df);
+            })
+            .map_err(|e| e.to_string()),
+        Operation::Upsert { keys, concat } => dataset
+            .upserted(&record.df, keys, *concat)
+            .map(|(df, _)| {
+                dataset.df = Some(df);
+            })
+            .map_err(|e| e.to_string()),
+        Operation::CollateWide { keys } => dataset
+            .widened(&record.df, keys)
+            .map(|(df, _)| {
+                dataset.df = Some(df);
+            })
+            .map_err(|e| e.to_string()),
+        Operation::Reset => {
+            dataset.reset();
+            Ok(())
+        }
+        Operation::Delete { filter } => filter
+            .parse::<Filter>()
+            .and_then(|filter| dataset.without_rows(&filter))
+            .map(|(state, df, _)| {
+                dataset.aggregate_state = state;
+                dataset.df = df;
+            }),
+        Operation::Dedup { subset, keep } => dataset
+            .deduplicated(subset.as_deref(), *keep)
+            .map(|(df, _)| {
+                dataset.df = df;
+            }),
+        Operation::FillNulls { spec } => dataset.nulls_filled(spec).map(|(df, _)| {
+            dataset.df = df;
+        }),
+        Operation::DropNulls { subset } => dataset.without_nulls(subset.as_deref()).map(|(df, _)| {
+            dataset.df = df;
+        }),
+        Operation::FlagOutliers { spec } => dataset.outliers_flagged(spec).map(|df| {
+            dataset.df = df;
+        }),
+        Operation::Quarantine => dataset
+            .quarantined(&record.df)
+            .map(|quarantine| {
+                dataset.quarantine = Some(quarantine);
+            })
+            .map_err(|e| e.to_string()),
+        Operation::ClearQuarantine => {
+            dataset.quarantine = None;
+            Ok(())
+        }
+        Operation::Restore { snapshot } => snapshots_dir
+            .ok_or_else(|| String::from("no snapshots directory is configured"))
+            .and_then(|dir| read_snapshot(dir, &record.dataset, snapshot))
+            .and_then(|restored| restored.ok_or_else(|| format!("snapshot {:?} no longer exists", snapshot)))
+            .map(|(_, restored)| dataset.restore(restored)),
+        Operation::Computed { columns } => {
+            dataset.computed = Some(columns.clone());
+            Ok(())
+        }
+        Operation::Rollback { batches } => dataset.check_rollback(*batches).map(|_| {
+            dataset.roll_back(*batches, record.at);
+        }),
+        Operation::Evict { spec } => dataset.evicted(spec).map(|(state, df, _)| {
+            dataset.aggregate_state = state;
+            dataset.df = df;
+        }),
+    };
+
+    // Only payloads that applied cleanly are logged, so an error means the log and the startup data disagree
+    result?;
+    match record.operation.ingest_name() {
+        Some(operation) => dataset.record_batch(operation, record.df.height(), before, record.at, rollback_batches),
+        None if record.operation.clears_ledger() => dataset.ledger.clear_at(record.at),
+        None => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, process};
+
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("data_collator-wal-{}-{}.wal", name, process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn record(seq: u64, value: i64) -> Vec<u8> {
+        let df = df!("value" => [value]).unwrap();
+        let operation = Operation::Collate {
+            concat: ConcatMode::default(),
+        };
+        let (header, body) = encode_parts("default", &operation, Utc::now(), None, &df).unwrap();
+        encode_record(seq, &header, &body)
+    }
+
+    fn values(records: &[Record]) -> Vec<i64> {
+        records.iter().map(|record| record.df.column("value").unwrap().i64().unwrap().get(0).unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn a_torn_record_at_the_end_is_cut_off() {
+        let path = scratch("torn");
+        let third = record(3, 30);
+        let mut contents = [MAGIC.as_slice(), &record(1, 10), &record(2, 20)].concat();
+        let good_len = contents.len();
+        contents.extend_from_slice(&third[..third.len() / 2]);
+        fs::write(&path, &contents).unwrap();
+
+        let (wal, records) = Wal::open(&path, &|_, _| false).unwrap();
+        assert_eq!(values(&records), [10, 20]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), good_len as u64);
+
+        // The next record takes the torn one's place
+        let df = df!("value" => [40i64]).unwrap();
+        let (seq, _) = wal.append("default", &records[0].operation, &df, None).await.unwrap();
+        assert_eq!(seq, 3);
+        drop(wal);
+        let (_, records) = Wal::open(&path, &|_, _| false).unwrap();
+        assert_eq!(values(&records), [10, 20, 40]);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corruption_in_the_middle_isnt_cut_off() {
+        let path = scratch("corrupt");
+        let mut second = record(2, 20);
+        let last = second.len() - 1;
+        second[last] ^= 0xff;
+        let contents = [MAGIC.as_slice(), &record(1, 10), &second, &record(3, 30)].concat();
+        fs::write(&path, &contents).unwrap();
+
+        let e = Wal::open(&path, &|_, _| false).unwrap_err();
+        assert!(e.contains("has intact records after it (from record 3"), "{}", e);
+        // The records after the corrupt one are still there to be recovered
+        assert_eq!(fs::read(&path).unwrap(), contents);
+        assert_eq!(values(&read_log(&path).unwrap()), [10]);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
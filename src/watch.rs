@@ -0,0 +1,116 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use log::warn;
+
+use crate::payload::FileFormat;
+
+// Subfolders of the watched directory that files are moved to once they've been ingested, or failed to be
+pub const PROCESSED_DIR: &str = "processed";
+pub const FAILED_DIR: &str = "failed";
+
+// Finds data files dropped into a directory. It's polled rather than watched for events, which also works on the
+// network filesystems HPC jobs tend to write to. A file is only picked up once its size and modification time are
+// the same on two scans in a row, so one that's still being written isn't read half-way.
+#[derive(Debug)]
+pub struct DirWatcher {
+    dir: PathBuf,
+    // Size and modification time of each candidate file on the last scan
+    seen: HashMap<PathBuf, (u64, Option<SystemTime>)>,
+    // Files that were handled but couldn't be moved away, so they aren't ingested again
+    stuck: HashSet<PathBuf>,
+}
+
+impl DirWatcher {
+    pub fn new(dir: &Path) -> Result<Self, String> {
+        for sub in [PROCESSED_DIR, FAILED_DIR] {
+            fs::create_dir_all(dir.join(sub)).map_err(|e| format!("Can't create {:?}: {}", dir.join(sub), e))?;
+        }
+
+        Ok(DirWatcher {
+            dir: dir.to_path_buf(),
+            seen: HashMap::new(),
+            stuck: HashSet::new(),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // Files that are ready to ingest, oldest first. Hidden files (e.g. `.results.csv.tmp`, the usual way to write a
+    // file before renaming it into place) and files without a known data extension are left alone.
+    pub fn ready(&mut self) -> Vec<PathBuf> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Can't scan the watched directory {:?}: {}", self.dir, e);
+                return Vec::new();
+            }
+        };
+
+        let mut seen = HashMap::new();
+        let mut ready = Vec::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let hidden = entry.file_name().to_string_lossy().starts_with('.');
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if hidden || !metadata.is_file() || FileFormat::from_path(&path).is_err() || self.stuck.contains(&path) {
+                continue;
+            }
+
+            let state = (metadata.len(), metadata.modified().ok());
+            if self.seen.get(&path) == Some(&state) {
+                ready.push((state.1, path));
+            } else {
+                seen.insert(path, state);
+            }
+        }
+        self.seen = seen;
+
+        ready.sort();
+        ready.into_iter().map(|(_, path)| path).collect()
+    }
+
+    // Move an ingested file to `processed/`
+    pub fn processed(&mut self, path: &Path) {
+        self.move_to(path, PROCESSED_DIR);
+    }
+
+    // Move a file that couldn't be ingested to `failed/`, with the reason next to it in `<file>.error`
+    pub fn failed(&mut self, path: &Path, message: &str) {
+        if let Some(moved) = self.move_to(path, FAILED_DIR) {
+            let mut error = moved.into_os_string();
+            error.push(".error");
+            if let Err(e) = fs::write(&error, format!("{}\n", message)) {
+                warn!("Can't write {:?}: {}", error, e);
+            }
+        }
+    }
+
+    // Move a file into a subfolder, without replacing a file of the same name that's already there
+    fn move_to(&mut self, path: &Path, sub: &str) -> Option<PathBuf> {
+        let name = path.file_name()?.to_string_lossy().into_owned();
+        let mut target = self.dir.join(sub).join(&name);
+        let mut n = 1;
+        while target.exists() {
+            target = self.dir.join(sub).join(format!("{}.{}", n, name));
+            n += 1;
+        }
+
+        match fs::rename(path, &target) {
+            Ok(()) => Some(target),
+            Err(e) => {
+                warn!("Can't move {:?} to {:?}, so it won't be picked up again until a restart: {}", path, target, e);
+                self.stuck.insert(path.to_path_buf());
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,233 @@
+// Webhooks (`[webhooks.<name>]`): a JSON summary posted to a URL when something happens to a dataset: every batch it
+// ingests, its row count reaching a threshold, or a value in the rows it gets (an aggregate's groups, for an aggregated
+// dataset) going past a limit. The summary has a `text` line, so it can go straight to a Slack-style incoming webhook.
+// Posts are retried in the background, so a slow or failing receiver never holds up an ingest.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use chrono::Utc;
+use log::{error, info, warn};
+use polars::prelude::*;
+use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    dataset::{validate_dataset_name, AppState},
+    events::{Change, Event},
+    http::{self, Endpoint},
+    serialize::df_to_json_records,
+    types::WebhookNotification,
+};
+
+// How many times a webhook is tried, waiting a second and then twice as long between tries
+const ATTEMPTS: u32 = 4;
+
+// How many of the rows past a limit a notification includes
+const SAMPLE_ROWS: usize = 10;
+
+// The operations that add rows to a dataset, as opposed to removing or rewriting them
+const INGESTS: &[&str] = &["collate", "aggregate", "upsert", "collate_wide"];
+
+// When a webhook is posted to. Every trigger that's set applies.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookSpec {
+    // `http://` URL to post to
+    pub url: String,
+    // Only this dataset's changes (every dataset's, if not set)
+    pub dataset: Option<String>,
+    // Post after every batch the dataset ingests
+    #[serde(default)]
+    pub ingest: bool,
+    // Post when the dataset's row count reaches this many (again after it's gone back below)
+    pub rows_above: Option<usize>,
+    // Post when an ingest brings rows whose `column` is above `above` or below `below`
+    pub column: Option<String>,
+    pub above: Option<f64>,
+    pub below: Option<f64>,
+}
+
+impl WebhookSpec {
+    pub fn validate(&self) -> Result<(), String> {
+        Endpoint::parse(&self.url).map_err(|e| format!("Invalid url {:?}: {}", self.url, e))?;
+        if let Some(dataset) = &self.dataset {
+            validate_dataset_name(dataset)?;
+        }
+        if !self.ingest && self.rows_above.is_none() && self.column.is_none() {
+            return Err(String::from("Nothing would post to it (set ingest, rows_above, or a column with a limit)"));
+        }
+        match (&self.column, self.above.is_some() || self.below.is_some()) {
+            (Some(_), false) => Err(String::from("A column needs a limit to check it against (above or below)")),
+            (None, true) => Err(String::from("A limit (above or below) needs the column it applies to")),
+            _ => Ok(()),
+        }
+    }
+
+    fn applies_to(&self, dataset: &str) -> bool {
+        self.dataset.as_deref().is_none_or(|only| only == dataset)
+    }
+}
+
+// Follow every dataset's changes, posting the notifications the configured webhooks ask for. Only started when there
+// are any, since following changes makes every ingest work out what it changed.
+pub(crate) async fn notify(state: Arc<AppState>) {
+    let mut events = state.events.subscribe();
+    let mut webhooks: Vec<(&String, &WebhookSpec)> = state.config.webhooks.iter().collect();
+    webhooks.sort_by_key(|(name, _)| *name);
+
+    // Which datasets are at or above each row threshold already, so a restart doesn't post them again
+    let mut reached: HashMap<(String, String), bool> = HashMap::new();
+    for (name, dataset) in state.all_datasets().await {
+        let rows = dataset.read().await.rows().height();
+        for (webhook, spec) in &webhooks {
+            if let Some(threshold) = spec.rows_above
+                && spec.applies_to(&name)
+            {
+                reached.insert((webhook.to_string(), name.clone()), rows >= threshold);
+            }
+        }
+    }
+
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                warn!("Webhooks fell behind, and missed {} events (whose notifications weren't sent)", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let Event::Changed(change) = &*event else {
+            continue;
+        };
+        // The primary posts them
+        if state.replication.is_replica() {
+            continue;
+        }
+
+        for (webhook, spec) in &webhooks {
+            if !spec.applies_to(&change.dataset) {
+                continue;
+            }
+            let notifications = [
+                ingested(webhook, spec, change),
+                crossed(webhook, spec, change, &mut reached),
+                exceeded(webhook, spec, change),
+            ];
+            for notification in notifications.into_iter().flatten() {
+                let body = serde_json::to_vec(&notification).expect("notifications serialize");
+                let what = format!("the {} notification of {:?}", notification.trigger, change.dataset);
+                tokio::spawn(post(spec.url.clone(), body, what));
+            }
+        }
+    }
+}
+
+fn notification(webhook: &str, trigger: &str, change: &Change, text: String) -> WebhookNotification {
+    WebhookNotification {
+        webhook: webhook.to_string(),
+        trigger: trigger.to_string(),
+        dataset: change.dataset.clone(),
+        operation: change.operation.to_string(),
+        text,
+        at: Utc::now().to_rfc3339(),
+        rows: change.rows.height(),
+        total_rows: change.total_rows,
+        rows_above: None,
+        column: None,
+        above: None,
+        below: None,
+        matched: None,
+        sample: Vec::new(),
+    }
+}
+
+fn ingested(webhook: &str, spec: &WebhookSpec, change: &Change) -> Option<WebhookNotification> {
+    if !spec.ingest || !INGESTS.contains(&change.operation) {
+        return None;
+    }
+    let text = format!(
+        "Dataset {:?} ingested a batch of {} rows ({}), and has {} rows",
+        change.dataset,
+        change.rows.height(),
+        change.operation,
+        change.total_rows
+    );
+    Some(notification(webhook, "ingest", change, text))
+}
+
+// A change that takes the dataset's row count from below the threshold to at or above it. Changes that remove rows
+// can take it back below.
+fn crossed(
+    webhook: &str,
+    spec: &WebhookSpec,
+    change: &Change,
+    reached: &mut HashMap<(String, String), bool>,
+) -> Option<WebhookNotification> {
+    let threshold = spec.rows_above?;
+    let now = change.total_rows >= threshold;
+    let before = reached.insert((webhook.to_string(), change.dataset.clone()), now).unwrap_or(false);
+    if before || !now {
+        return None;
+    }
+
+    let text =
+        format!("Dataset {:?} reached {} rows (the threshold is {})", change.dataset, change.total_rows, threshold);
+    let mut notification = notification(webhook, "rows", change, text);
+    notification.rows_above = Some(threshold);
+    Some(notification)
+}
+
+// The rows of a change whose column is past a limit. Nulls (and text that isn't a number) are never past one.
+fn exceeded(webhook: &str, spec: &WebhookSpec, change: &Change) -> Option<WebhookNotification> {
+    let column = spec.column.as_deref()?;
+    let values = change.rows.column(column).ok()?.cast(&DataType::Float64).ok()?;
+    let values = values.f64().ok()?;
+    let is_past =
+        |value: f64| spec.above.is_some_and(|above| value > above) || spec.below.is_some_and(|below| value < below);
+    let past: BooleanChunked = values.into_iter().map(|value| value.map(is_past)).collect();
+    let matched = past.sum().unwrap_or(0) as usize;
+    if matched == 0 {
+        return None;
+    }
+    let rows = change.rows.filter(&past).ok()?;
+
+    let limit = match (spec.above, spec.below) {
+        (Some(above), Some(below)) => format!("above {} or below {}", above, below),
+        (Some(above), None) => format!("above {}", above),
+        (None, Some(below)) => format!("below {}", below),
+        (None, None) => return None,
+    };
+    let text = format!("Dataset {:?} got {} rows with {} {}", change.dataset, matched, column, limit);
+    let mut notification = notification(webhook, "value", change, text);
+    notification.column = Some(column.to_string());
+    notification.above = spec.above;
+    notification.below = spec.below;
+    notification.matched = Some(matched);
+    notification.sample = df_to_json_records(&rows.head(Some(SAMPLE_ROWS)));
+    Some(notification)
+}
+
+// Post a JSON body to a webhook, retrying failures. `what` says what it is, for the log.
+pub(crate) async fn post(url: String, body: Vec<u8>, what: String) {
+    let endpoint = match Endpoint::parse(&url) {
+        Ok(endpoint) => endpoint,
+        Err(e) => return error!("Invalid webhook {:?}: {}", url, e),
+    };
+    let target = if endpoint.path.is_empty() { "/" } else { endpoint.path.as_str() };
+    let headers = [("Content-Type", String::from("application/json"))];
+
+    let mut wait = Duration::from_secs(1);
+    for attempt in 1..=ATTEMPTS {
+        match http::send(&endpoint, "POST", target, &headers, &body).await {
+            Ok(response) if response.is_success() => return info!("Sent {} to {}", what, url),
+            Ok(response) => warn!("The webhook {} answered {} (attempt {})", url, response.status, attempt),
+            Err(e) => warn!("Can't send the webhook {} (attempt {}): {}", url, attempt, e),
+        }
+        if attempt < ATTEMPTS {
+            tokio::time::sleep(wait).await;
+            wait *= 2;
+        }
+    }
+    error!("Gave up sending {} to {}", what, url);
+}
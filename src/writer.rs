@@ -0,0 +1,438 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use log::{error, info, trace};
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{field, Instrument};
+
+use crate::{
+    events::{Events, Flush},
+    metrics::Metrics,
+    partition::{self, Compaction, PartitionSpec},
+    persist::{StorageBackend, WriteMode},
+};
+
+// How many writes can be queued before request handlers wait for the writer to catch up
+const QUEUE_CAPACITY: usize = 1024;
+
+// A request's result that needs to end up in an output file
+#[derive(Debug)]
+pub struct Write {
+    pub output_file: PathBuf,
+    // When set, `output_file` is the directory of a dataset's hive-style partitions rather than a CSV file
+    pub partitions: Option<PartitionSpec>,
+    pub mode: WriteMode,
+    // The payload that was just accepted
+    pub batch: DataFrame,
+    // The dataset after applying the payload
+    pub state: DataFrame,
+}
+
+enum Message {
+    Write(Write),
+    // Write out everything that is pending, then report back
+    Flush(oneshot::Sender<FlushStatus>),
+}
+
+// What the writer has done so far, reported by `/flush`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FlushStatus {
+    // Batches and rows received but not written to disk yet
+    pub pending_batches: usize,
+    pub pending_rows: usize,
+    pub flushes: u64,
+    pub rows_flushed: u64,
+    // When the last flush finished (RFC 3339) and how long it took
+    pub last_flush_at: Option<String>,
+    pub last_flush_ms: Option<u128>,
+    pub last_error: Option<String>,
+}
+
+// Everything waiting to be written to one output file
+#[derive(Debug)]
+struct Pending {
+    // A frame that replaces the file's contents (the latest batch for overwrite mode, the full state for snapshot
+    // mode), written before `batches`
+    base: Option<DataFrame>,
+    // Batches to append, in order
+    batches: Vec<DataFrame>,
+    rows: usize,
+    partitions: Option<PartitionSpec>,
+}
+
+// Handle to the background task that owns all output file I/O, so request handlers never block on disk writes
+#[derive(Debug, Clone)]
+pub struct Writer {
+    tx: mpsc::Sender<Message>,
+    status: Arc<Mutex<FlushStatus>>,
+}
+
+impl Writer {
+    // Start the writer task. Pending writes are flushed every `interval` or as soon as a file has `max_rows` rows
+    // waiting, whichever comes first. A zero interval writes every batch as soon as it arrives. Flush timings and
+    // failures are recorded in `metrics`, and each flush is published to `events`. Output files are written with
+    // `backend`. With `compaction`, partitions are compacted on its schedule too.
+    pub fn spawn(
+        interval: Duration,
+        max_rows: usize,
+        backend: Arc<dyn StorageBackend>,
+        compaction: Option<Compaction>,
+        metrics: Arc<Metrics>,
+        events: Events,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        let status = Arc::new(Mutex::new(FlushStatus::default()));
+        let flusher = Flusher {
+            status: status.clone(),
+            metrics,
+            backend,
+            events,
+        };
+
+        tokio::spawn(run(rx, flusher, interval, max_rows, compaction));
+
+        Writer { tx, status }
+    }
+
+    // Queue a write. Writes are applied in the order they are submitted.
+    pub async fn submit(&self, write: Write) -> Result<(), String> {
+        self.tx
+            .send(Message::Write(write))
+            .await
+            .map_err(|_| String::from("The output writer has stopped"))
+    }
+
+    // Write out everything queued so far and wait for it to finish
+    pub async fn flush(&self) -> Result<FlushStatus, String> {
+        let (reply, response) = oneshot::channel();
+        self.tx
+            .send(Message::Flush(reply))
+            .await
+            .map_err(|_| String::from("The output writer has stopped"))?;
+
+        response.await.map_err(|_| String::from("The output writer has stopped"))
+    }
+
+    // Whether the writer task is still taking writes
+    pub fn is_running(&self) -> bool {
+        !self.tx.is_closed()
+    }
+
+    pub fn status(&self) -> FlushStatus {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+// Where the writer task reports the outcome of each flush
+struct Flusher {
+    status: Arc<Mutex<FlushStatus>>,
+    metrics: Arc<Metrics>,
+    backend: Arc<dyn StorageBackend>,
+    events: Events,
+}
+
+async fn run(
+    mut rx: mpsc::Receiver<Message>,
+    flusher: Flusher,
+    interval: Duration,
+    max_rows: usize,
+    compaction: Option<Compaction>,
+) {
+    let status = &flusher.status;
+    let mut pending: HashMap<PathBuf, Pending> = HashMap::new();
+
+    // With a zero interval there is nothing to debounce, so the ticker is never polled
+    let period = interval.max(Duration::from_millis(1));
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // Compaction runs here rather than in a task of its own, so it never races a flush to the same partitions
+    let compact_period = compaction.as_ref().map_or(period, |compaction| compaction.interval);
+    let mut compact_ticker = tokio::time::interval_at(tokio::time::Instant::now() + compact_period, compact_period);
+    compact_ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            message = rx.recv() => match message {
+                Some(Message::Write(write)) => {
+                    let path = write.output_file.clone();
+                    let rows = queue(&mut pending, write);
+
+                    if (interval.is_zero() || rows >= max_rows)
+                        && let Some(file) = pending.remove(&path)
+                    {
+                        pending.extend(flush_files(&flusher, vec![(path, file)]).await);
+                    }
+                    update_pending(status, &pending);
+                }
+                Some(Message::Flush(reply)) => {
+                    let failed = flush_files(&flusher, pending.drain().collect()).await;
+                    pending.extend(failed);
+                    update_pending(status, &pending);
+                    let _ = reply.send(status.lock().unwrap().clone());
+                }
+                None => {
+                    // Every handle is gone (the server is shutting down), write out what's left
+                    for (path, file) in flush_files(&flusher, pending.drain().collect()).await {
+                        error!("{} rows were never written to {:?}", file.rows, path);
+                    }
+                    return;
+                }
+            },
+            _ = ticker.tick(), if !interval.is_zero() && !pending.is_empty() => {
+                let failed = flush_files(&flusher, pending.drain().collect()).await;
+                pending.extend(failed);
+                update_pending(status, &pending);
+            }
+            _ = compact_ticker.tick(), if compaction.is_some() => {
+                if let Some(compaction) = &compaction {
+                    compact_partitions(compaction.dir.clone()).await;
+                }
+            }
+        }
+    }
+}
+
+// Add a write to the pending set, returning how many rows are now waiting for its file
+fn queue(pending: &mut HashMap<PathBuf, Pending>, write: Write) -> usize {
+    let file = pending.entry(write.output_file).or_insert_with(|| Pending {
+        base: None,
+        batches: Vec::new(),
+        rows: 0,
+        partitions: None,
+    });
+    file.partitions = write.partitions;
+
+    match write.mode {
+        WriteMode::Append => {
+            file.rows += write.batch.height();
+            file.batches.push(write.batch);
+        }
+        // Replacing the file makes anything queued before it irrelevant
+        WriteMode::Overwrite => {
+            file.rows = write.batch.height();
+            file.base = Some(write.batch);
+            file.batches.clear();
+        }
+        WriteMode::Snapshot => {
+            file.rows = write.state.height();
+            file.base = Some(write.state);
+            file.batches.clear();
+        }
+    }
+
+    file.rows
+}
+
+fn update_pending(status: &Mutex<FlushStatus>, pending: &HashMap<PathBuf, Pending>) {
+    let mut status = status.lock().unwrap();
+    status.pending_batches = pending.values().map(|file| file.batches.len() + file.base.is_some() as usize).sum();
+    status.pending_rows = pending.values().map(|file| file.rows).sum();
+}
+
+// Write pending data to disk on the blocking thread pool and record the outcome. Every file is written on its own,
+// so one that fails doesn't keep the others from being written; what's left of the ones that failed is returned, to be
+// put back and retried with the next flush.
+async fn flush_files(flusher: &Flusher, files: Vec<(PathBuf, Pending)>) -> Vec<(PathBuf, Pending)> {
+    if files.is_empty() {
+        return Vec::new();
+    }
+
+    let started = Instant::now();
+    let paths = files.iter().map(|(path, _)| path.display().to_string()).collect();
+    let backend = flusher.backend.clone();
+    let span = tracing::info_span!("flush", files = files.len(), rows = field::Empty, error = field::Empty);
+    let written = tokio::task::spawn_blocking(move || write_files(backend.as_ref(), files))
+        .instrument(span.clone())
+        .await
+        .unwrap_or_else(|e| Written {
+            errors: vec![format!("Output writer task failed: {}", e)],
+            ..Default::default()
+        });
+
+    flusher.metrics.flush_seconds.observe(started.elapsed().as_secs_f64());
+
+    let mut status = flusher.status.lock().unwrap();
+    status.flushes += 1;
+    status.last_flush_at = Some(Utc::now().to_rfc3339());
+    status.last_flush_ms = Some(started.elapsed().as_millis());
+
+    let mut flush = Flush {
+        files: paths,
+        rows: 0,
+        duration_ms: started.elapsed().as_millis(),
+        error: None,
+    };
+    trace!("Flushed {} rows in {:?}", written.rows, started.elapsed());
+    span.record("rows", written.rows);
+    status.rows_flushed += written.rows as u64;
+    flush.rows = written.rows;
+    if written.errors.is_empty() {
+        status.last_error = None;
+    } else {
+        for message in &written.errors {
+            error!("{}", message);
+        }
+        let message = written.errors.join("; ");
+        span.record("error", message.as_str());
+        flusher.metrics.flush_errors.fetch_add(written.errors.len() as u64, Ordering::Relaxed);
+        status.last_error = Some(message.clone());
+        flush.error = Some(message);
+    }
+    drop(status);
+    flusher.events.flushed(flush);
+
+    written.failed
+}
+
+// What a flush wrote: how many rows, why files failed, and what's left to write to them
+#[derive(Default)]
+struct Written {
+    rows: usize,
+    errors: Vec<String>,
+    failed: Vec<(PathBuf, Pending)>,
+}
+
+fn write_files(backend: &dyn StorageBackend, files: Vec<(PathBuf, Pending)>) -> Written {
+    let mut written = Written::default();
+    for (path, mut file) in files {
+        let rows = file.rows;
+        let result = write_pending(backend, &path, &mut file);
+        written.rows += rows - file.rows;
+        if let Err(e) = result {
+            written.errors.push(format!("Error writing to {:?}: {}", path, e));
+            if file.base.is_some() || !file.batches.is_empty() {
+                written.failed.push((path, file));
+            }
+        }
+    }
+    written
+}
+
+// Merge the part files of every partition on the blocking thread pool
+async fn compact_partitions(dir: PathBuf) {
+    let started = Instant::now();
+    let result = tokio::task::spawn_blocking(move || partition::compact(&dir))
+        .await
+        .unwrap_or_else(|e| Err(format!("Compaction task failed: {}", e)));
+
+    match result {
+        Ok(0) => {}
+        Ok(compacted) => info!("Compacted {} partitions in {:?}", compacted, started.elapsed()),
+        Err(message) => error!("Error compacting partitions: {}", message),
+    }
+}
+
+// Write what's pending for a file, taking each part out of `file` once it's written, so a failed write leaves only
+// what still has to be retried
+fn write_pending(backend: &dyn StorageBackend, path: &std::path::Path, file: &mut Pending) -> PolarsResult<()> {
+    if let Some(spec) = &file.partitions {
+        if let Some(base) = &file.base {
+            partition::replace(path, spec, base)?;
+            file.rows -= base.height();
+            file.base = None;
+        }
+        partition::append(path, spec, &file.batches)?;
+        file.batches.clear();
+        file.rows = 0;
+        return Ok(());
+    }
+
+    if let Some(base) = file.base.as_mut() {
+        backend.snapshot(base, path)?;
+        file.rows -= base.height();
+        file.base = None;
+    }
+    // Appended as one frame, since some backends rewrite the whole file to append. Batches can have different
+    // dtypes, or different columns with `concat = "union"`, so they're combined the way the dataset's rows were.
+    if !file.batches.is_empty() {
+        let args = UnionArgs {
+            rechunk: true,
+            to_supertypes: true,
+            ..Default::default()
+        };
+        let batches = file.batches.iter().map(|batch| batch.clone().lazy()).collect::<Vec<_>>();
+        let mut batch = concat_lf_diagonal(batches, args)?.collect()?;
+        backend.append(&mut batch, path)?;
+        file.batches.clear();
+        file.rows = 0;
+    }
+    backend.flush(path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process};
+
+    use super::*;
+    use crate::persist::CsvBackend;
+
+    fn pending(batches: Vec<DataFrame>) -> Pending {
+        Pending {
+            base: None,
+            rows: batches.iter().map(DataFrame::height).sum(),
+            batches,
+            partitions: None,
+        }
+    }
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("data_collator-writer-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn a_failed_file_doesnt_stop_the_others() {
+        let dir = scratch("failed");
+        let missing = dir.join("missing").join("count.csv");
+        let files = vec![
+            (missing.clone(), pending(vec![df!("count" => [1i64, 2]).unwrap()])),
+            (dir.join("max.csv"), pending(vec![df!("max" => [3i64]).unwrap()])),
+            (dir.join("std.csv"), pending(vec![df!("std" => [4.0f64]).unwrap(), df!("std" => [5.0f64]).unwrap()])),
+        ];
+
+        let written = write_files(&CsvBackend, files);
+        assert_eq!(written.rows, 3);
+        assert_eq!(written.errors.len(), 1);
+        assert!(written.errors[0].contains("missing"), "{}", written.errors[0]);
+        assert_eq!(fs::read_to_string(dir.join("max.csv")).unwrap(), "max\n3\n");
+        assert_eq!(fs::read_to_string(dir.join("std.csv")).unwrap(), "std\n4.0\n5.0\n");
+
+        // What failed is kept, and written once it can be
+        assert_eq!(written.failed.len(), 1);
+        assert_eq!((&written.failed[0].0, written.failed[0].1.rows), (&missing, 2));
+        fs::create_dir_all(missing.parent().unwrap()).unwrap();
+        let retried = write_files(&CsvBackend, written.failed);
+        assert_eq!((retried.rows, retried.errors.len(), retried.failed.len()), (2, 0, 0));
+        assert_eq!(fs::read_to_string(&missing).unwrap(), "count\n1\n2\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn batches_with_different_dtypes_and_columns() {
+        let dir = scratch("mixed");
+        let path = dir.join("mixed.csv");
+        let batches = vec![
+            df!("host" => ["a"], "latency" => [1i64]).unwrap(),
+            df!("host" => ["b"], "latency" => [2.5f64]).unwrap(),
+            df!("host" => ["c"], "latency" => [3i64], "region" => ["eu"]).unwrap(),
+        ];
+
+        let written = write_files(&CsvBackend, vec![(path.clone(), pending(batches))]);
+        assert_eq!((written.rows, written.errors.len()), (3, 0), "{:?}", written.errors);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "host,latency,region\na,1.0,\nb,2.5,\nc,3.0,eu\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,233 @@
+// The server side of WebSocket connections (RFC 6455), for producers that stream batches over one connection instead
+// of sending a request per batch, and for clients following a dataset's changes. Enough of the protocol to exchange
+// whole messages: no extensions (so no compression), and fragmented messages are put back together before they're
+// handed on.
+
+use axum::http::{header, HeaderMap, HeaderValue};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::digest::sha1;
+
+// Appended to the client's key to prove the server speaks WebSocket (RFC 6455, section 1.3)
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// Frame opcodes
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+// Close codes
+pub const NORMAL_CLOSURE: u16 = 1000;
+const PROTOCOL_ERROR: u16 = 1002;
+const INVALID_DATA: u16 = 1007;
+const MESSAGE_TOO_BIG: u16 = 1009;
+
+// Check a request asks to be upgraded to a WebSocket, and work out the `Sec-WebSocket-Accept` value that agrees to it
+pub fn accept_key(headers: &HeaderMap) -> Result<HeaderValue, String> {
+    let has_token = |name: header::HeaderName, token: &str| {
+        headers.get_all(name).iter().filter_map(|value| value.to_str().ok()).any(|value| {
+            value.split(',').any(|part| part.trim().eq_ignore_ascii_case(token))
+        })
+    };
+    if !has_token(header::UPGRADE, "websocket") || !has_token(header::CONNECTION, "upgrade") {
+        return Err(String::from("This endpoint only takes WebSocket connections (`Upgrade: websocket`)"));
+    }
+    if headers.get(header::SEC_WEBSOCKET_VERSION).and_then(|value| value.to_str().ok()) != Some("13") {
+        return Err(String::from("Only version 13 of the WebSocket protocol is supported"));
+    }
+    let key = headers
+        .get(header::SEC_WEBSOCKET_KEY)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| String::from("The Sec-WebSocket-Key header is missing"))?;
+
+    let digest = sha1(format!("{}{}", key.trim(), ACCEPT_GUID).as_bytes());
+    HeaderValue::from_str(&STANDARD.encode(digest)).map_err(|e| e.to_string())
+}
+
+// What the client sent
+#[derive(Debug)]
+pub enum Incoming {
+    // A whole text or binary message
+    Message(Vec<u8>),
+    // A ping, to be answered with a pong carrying the same bytes
+    Ping(Vec<u8>),
+    // The client is closing the connection
+    Close,
+}
+
+// Why a connection can't go on
+#[derive(Debug)]
+pub enum SocketError {
+    // The connection is gone, so there's no one to tell
+    Lost(String),
+    // The client broke the protocol (or sent too big a message), and is told so with a close code
+    Violation(u16, String),
+}
+
+// One side of an upgraded connection
+pub struct Socket<S> {
+    stream: S,
+    // Bytes read from the client that don't make up a whole frame yet
+    buffer: Vec<u8>,
+    // The fragments of a message that's still arriving
+    fragments: Option<(u8, Vec<u8>)>,
+    // The biggest message accepted, in bytes
+    max_message_bytes: usize,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Socket<S> {
+    pub fn new(stream: S, max_message_bytes: usize) -> Self {
+        Socket {
+            stream,
+            buffer: Vec::new(),
+            fragments: None,
+            max_message_bytes,
+        }
+    }
+
+    // Wait for the next message (or ping, or close). Only the buffer is added to while waiting, so this can be
+    // cancelled without losing anything.
+    pub async fn receive(&mut self) -> Result<Incoming, SocketError> {
+        loop {
+            while let Some((fin, opcode, payload)) = self.take_frame()? {
+                if let Some(incoming) = self.assemble(fin, opcode, payload)? {
+                    return Ok(incoming);
+                }
+            }
+
+            let mut chunk = [0; 8192];
+            let read = self.stream.read(&mut chunk).await.map_err(|e| SocketError::Lost(e.to_string()))?;
+            if read == 0 {
+                return Err(SocketError::Lost(String::from("the client disconnected")));
+            }
+            self.buffer.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    pub async fn send_text(&mut self, text: &str) -> Result<(), SocketError> {
+        self.send(TEXT, text.as_bytes()).await
+    }
+
+    pub async fn send_pong(&mut self, payload: &[u8]) -> Result<(), SocketError> {
+        self.send(PONG, payload).await
+    }
+
+    // Say the connection is being closed, and why. Control frames carry at most 125 bytes, so a long reason is cut
+    // short.
+    pub async fn close(&mut self, code: u16, reason: &str) -> Result<(), SocketError> {
+        let mut payload = code.to_be_bytes().to_vec();
+        let mut end = reason.len().min(123);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        payload.extend_from_slice(&reason.as_bytes()[..end]);
+        self.send(CLOSE, &payload).await
+    }
+
+    // Server frames are sent whole and unmasked
+    async fn send(&mut self, opcode: u8, payload: &[u8]) -> Result<(), SocketError> {
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            len @ 0..=125 => frame.push(len as u8),
+            len @ 126..=0xffff => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+
+        self.stream.write_all(&frame).await.map_err(|e| SocketError::Lost(e.to_string()))?;
+        self.stream.flush().await.map_err(|e| SocketError::Lost(e.to_string()))
+    }
+
+    // The next whole frame in the buffer, unmasked: whether it's the last of its message, its opcode, and its payload
+    fn take_frame(&mut self) -> Result<Option<(bool, u8, Vec<u8>)>, SocketError> {
+        let violation = |reason: &str| SocketError::Violation(PROTOCOL_ERROR, reason.to_string());
+        let [first, second, ..] = self.buffer[..] else {
+            return Ok(None);
+        };
+        if first & 0x70 != 0 {
+            return Err(violation("no extensions were agreed on"));
+        }
+        if second & 0x80 == 0 {
+            return Err(violation("client frames must be masked"));
+        }
+
+        let (len, mut at) = match second & 0x7f {
+            126 => match self.buffer.get(2..4) {
+                Some(len) => (u64::from(u16::from_be_bytes([len[0], len[1]])), 4),
+                None => return Ok(None),
+            },
+            127 => match self.buffer.get(2..10) {
+                Some(len) => (u64::from_be_bytes(len.try_into().expect("eight bytes")), 10),
+                None => return Ok(None),
+            },
+            len => (u64::from(len), 2),
+        };
+        // Checked before waiting for the rest, so a huge frame is turned away without being buffered
+        let buffered = self.fragments.as_ref().map_or(0, |(_, data)| data.len());
+        if len.saturating_add(buffered as u64) > self.max_message_bytes as u64 {
+            return Err(SocketError::Violation(
+                MESSAGE_TOO_BIG,
+                format!("messages can be at most {} bytes", self.max_message_bytes),
+            ));
+        }
+        let len = len as usize;
+        if self.buffer.len() < at + 4 + len {
+            return Ok(None);
+        }
+
+        let mask = [self.buffer[at], self.buffer[at + 1], self.buffer[at + 2], self.buffer[at + 3]];
+        at += 4;
+        let mut payload = self.buffer[at..at + len].to_vec();
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        self.buffer.drain(..at + len);
+
+        Ok(Some((first & 0x80 != 0, first & 0x0f, payload)))
+    }
+
+    // Put a frame together with the fragments before it. Returns what the client sent once there's a whole thing.
+    fn assemble(&mut self, fin: bool, opcode: u8, payload: Vec<u8>) -> Result<Option<Incoming>, SocketError> {
+        let violation = |reason: &str| SocketError::Violation(PROTOCOL_ERROR, reason.to_string());
+        if opcode >= CLOSE && (!fin || payload.len() > 125) {
+            return Err(violation("control frames can't be fragmented or longer than 125 bytes"));
+        }
+
+        let (opcode, data) = match opcode {
+            CLOSE => return Ok(Some(Incoming::Close)),
+            PING => return Ok(Some(Incoming::Ping(payload))),
+            PONG => return Ok(None),
+            TEXT | BINARY if self.fragments.is_some() => {
+                return Err(violation("a new message started before the last one was finished"));
+            }
+            TEXT | BINARY => (opcode, payload),
+            CONTINUATION => match self.fragments.take() {
+                Some((opcode, mut data)) => {
+                    data.extend_from_slice(&payload);
+                    (opcode, data)
+                }
+                None => return Err(violation("a continuation frame arrived with no message to continue")),
+            },
+            _ => return Err(violation("unknown opcode")),
+        };
+        if !fin {
+            self.fragments = Some((opcode, data));
+            return Ok(None);
+        }
+
+        if opcode == TEXT && std::str::from_utf8(&data).is_err() {
+            return Err(SocketError::Violation(INVALID_DATA, String::from("text messages must be UTF-8")));
+        }
+        Ok(Some(Incoming::Message(data)))
+    }
+}